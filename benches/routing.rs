@@ -0,0 +1,64 @@
+//! Per-packet routing cost as the number of routes grows.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use intermodal::{Manifest, Router, Selector};
+
+fn manifest(kind: &str) -> Manifest {
+    serde_json::from_value(serde_json::json!({
+        "domain": "example.org",
+        "scope": "metrics/host",
+        "kind": kind,
+        "version": 1,
+        "ctime": "2020-06-01T12:00:00Z",
+        "origin": "host01.example.org",
+        "labels": { "environment": "production" }
+    }))
+    .unwrap()
+}
+
+/// Builds a router resembling a high fan-out deployment: most routes pick
+/// out a single kind, a few match on labels alone.
+fn router(routes: usize) -> Router<usize> {
+    let mut router = Router::new();
+    for i in 0..routes {
+        let selector: Selector = if i % 100 == 99 {
+            format!("environment=env-{}", i).parse().unwrap()
+        } else {
+            format!(
+                "domain=example.org, scope=metrics/app-{}, kind=kind-{}",
+                i % 10,
+                i
+            )
+            .parse()
+            .unwrap()
+        };
+        router.add(selector, i);
+    }
+    router.add("kind=cpu".parse().unwrap(), routes);
+    router
+}
+
+fn routing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route");
+    let cpu = manifest("cpu");
+    for routes in [10, 1_000, 10_000] {
+        let router = router(routes);
+        group.bench_with_input(BenchmarkId::new("indexed", routes), &cpu, |b, cpu| {
+            b.iter(|| router.route(black_box(cpu)).map(|route| route.target))
+        });
+        // The cost of testing every selector in turn, for comparison.
+        group.bench_with_input(BenchmarkId::new("linear", routes), &cpu, |b, cpu| {
+            b.iter(|| {
+                router
+                    .routes()
+                    .iter()
+                    .find(|route| route.selector.matches(black_box(cpu)))
+                    .map(|route| route.target)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, routing);
+criterion_main!(benches);
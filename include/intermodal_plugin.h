@@ -0,0 +1,48 @@
+/*
+ * Native handler plugin interface for intermodal.
+ *
+ * A plugin is a shared library exporting intermodal_plugin(), which returns a
+ * pointer to a statically allocated IntermodalPlugin. Packets are passed to
+ * handle() as JSON-encoded envelopes. See the `native` module documentation
+ * for details.
+ */
+
+#ifndef INTERMODAL_PLUGIN_H
+#define INTERMODAL_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define INTERMODAL_ABI_VERSION 1
+
+/* Coordinates of a handled kind. NULL strings and version 0 match anything. */
+typedef struct IntermodalKind {
+	const char *domain;
+	const char *scope;
+	const char *kind;
+	uint32_t version;
+} IntermodalKind;
+
+/* A plugin-owned buffer, released by the plugin's free_buffer(). */
+typedef struct IntermodalBuffer {
+	uint8_t *ptr;
+	size_t len;
+} IntermodalBuffer;
+
+typedef struct IntermodalPlugin {
+	uint32_t abi_version;
+	const char *name;
+	const IntermodalKind *kinds;
+	size_t kinds_len;
+	/*
+	 * Returns 0 on success, optionally filling out with an encoded packet to
+	 * pass on. Leave out->ptr NULL to pass nothing on. Any other value is an
+	 * error code.
+	 */
+	int32_t (*handle)(const uint8_t *packet, size_t len, IntermodalBuffer *out);
+	void (*free_buffer)(IntermodalBuffer *buffer);
+} IntermodalPlugin;
+
+const IntermodalPlugin *intermodal_plugin(void);
+
+#endif
@@ -0,0 +1,655 @@
+//! The `intermodal` command, for operators working with envelopes by hand.
+//!
+//! Each subcommand reads from a file, or from standard input when none is
+//! given or it is `-`, and writes to standard output:
+//!
+//! - `inspect` pretty-prints the manifest of each envelope;
+//! - `convert` re-encodes an envelope in another format;
+//! - `filter` passes the envelopes of an NDJSON stream that a selector
+//!   matches;
+//! - `validate` checks manifests, and content against a JSON Schema;
+//! - `relabel` rewrites labels by rules, as `intermodal::relabel` reads
+//!   them;
+//! - `infer` drafts a JSON Schema for the content of each kind of envelope,
+//!   as `intermodal::infer` samples them;
+//! - `rewrite` streams an archive, of NDJSON or frames, through migrations
+//!   and relabeling into a new one, keeping each envelope's `ctime`;
+//! - `loadgen` sends synthetic packets, as `intermodal::loadgen` makes
+//!   them.
+//!
+//! Input to `inspect`, `validate` and `infer` is either one envelope, in any format,
+//! or a stream of JSON envelopes, one a line.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use intermodal::infer::Inferrer;
+use intermodal::loadgen::LoadGen;
+use intermodal::mapping::Mapping;
+use intermodal::migrate::Migrator;
+use intermodal::relabel::{Relabeler, Rule};
+use intermodal::rewrite::Rewriter;
+use intermodal::schema::{MemorySchemaRegistry, SchemaRegistry};
+use intermodal::spool::{FileSpool, Spool};
+use intermodal::{Coordinates, Format, Header, RawPacket, Selector};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "intermodal",
+    version,
+    about = "Inspect, convert and filter envelopes"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Pretty-print the manifest of each envelope
+    Inspect {
+        /// Print the whole envelope, content and all
+        #[arg(long)]
+        content: bool,
+        input: Option<PathBuf>,
+    },
+    /// Re-encode an envelope in another format
+    Convert {
+        /// The format to write: json, yaml or cbor
+        #[arg(long, short)]
+        to: Format,
+        /// The format to read, if not the one the input looks like
+        #[arg(long, short)]
+        from: Option<Format>,
+        input: Option<PathBuf>,
+    },
+    /// Pass the envelopes of an NDJSON stream that a selector matches
+    Filter {
+        /// A selector, such as `kind=cpu, environment=production`
+        selector: Selector,
+        /// Pass the envelopes the selector does not match instead
+        #[arg(long, short = 'v')]
+        invert: bool,
+        input: Option<PathBuf>,
+    },
+    /// Check manifests, and content against a JSON Schema
+    Validate {
+        /// A JSON Schema the content of every envelope must match
+        #[arg(long)]
+        schema: Option<PathBuf>,
+        input: Option<PathBuf>,
+    },
+    /// Rewrite the labels of an NDJSON stream by rules
+    Relabel {
+        /// A JSON file holding a list of rename, map and drop rules
+        #[arg(long)]
+        rules: PathBuf,
+        input: Option<PathBuf>,
+    },
+    /// Draft a JSON Schema for the content of each kind of envelope
+    Infer {
+        /// How many envelopes of each kind to sample
+        #[arg(long, default_value_t = 100)]
+        samples: u64,
+        /// Print the draft for these coordinates alone, such as
+        /// `example.org/metrics/host/cpu@1`
+        #[arg(long)]
+        coordinates: Option<Coordinates>,
+        input: Option<PathBuf>,
+    },
+    /// Rewrite an archive through migrations and relabeling, keeping each
+    /// envelope's ctime
+    Rewrite {
+        /// A JSON file mapping coordinates, such as
+        /// `example.org/metrics/host/cpu@1`, to the content mapping that
+        /// upgrades them to the next version
+        #[arg(long)]
+        migrations: Option<PathBuf>,
+        /// A JSON file holding a list of relabel rules, applied after the
+        /// migrations
+        #[arg(long)]
+        relabel: Option<PathBuf>,
+        /// Read and write an archive of frames rather than NDJSON
+        #[arg(long)]
+        framed: bool,
+        input: Option<PathBuf>,
+    },
+    /// Send synthetic packets, as NDJSON
+    Loadgen(LoadgenArgs),
+}
+
+#[derive(Debug, Args)]
+struct LoadgenArgs {
+    /// A kind to mix in, as coordinates with an optional weight, such as
+    /// `example.org/metrics/host/cpu@1=3`
+    #[arg(long = "kind", value_parser = parse_kind)]
+    kinds: Vec<(Coordinates, u32)>,
+    /// Payload bytes, as a size or a range such as `64-1024`
+    #[arg(long, value_parser = parse_sizes, default_value = "256")]
+    payload_size: RangeInclusive<usize>,
+    /// A label to set, with how many values it takes, such as `tenant=50`
+    #[arg(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, usize)>,
+    /// How many origins to spread packets across
+    #[arg(long, default_value_t = 1)]
+    origins: usize,
+    /// Packets a second, or as many as the output takes if not given
+    #[arg(long)]
+    rate: Option<f64>,
+    /// How many packets to send
+    #[arg(
+        long,
+        required_unless_present = "duration",
+        conflicts_with = "duration"
+    )]
+    count: Option<u64>,
+    /// How many seconds to send for
+    #[arg(long)]
+    duration: Option<f64>,
+    /// A seed, for runs that send the same packets
+    #[arg(long)]
+    seed: Option<u64>,
+    /// A file to append packets to, rather than standard output
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli.command) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("intermodal: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<ExitCode> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let code = match command {
+        Command::Inspect { content, input } => {
+            inspect(&read(&input)?, content, &mut out)?;
+            ExitCode::SUCCESS
+        }
+        Command::Convert { to, from, input } => {
+            convert(&read(&input)?, from, to, &mut out)?;
+            ExitCode::SUCCESS
+        }
+        Command::Filter {
+            selector,
+            invert,
+            input,
+        } => {
+            filter(open(&input)?, &selector, invert, &mut out)?;
+            ExitCode::SUCCESS
+        }
+        Command::Validate { schema, input } => {
+            let schema = match schema {
+                Some(path) => Some(serde_json::from_slice(&fs::read(path)?)?),
+                None => None,
+            };
+            let failures = validate(&read(&input)?, schema, &mut out)?;
+            if failures == 0 {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Command::Relabel { rules, input } => {
+            let rules: Vec<Rule> = serde_json::from_slice(&fs::read(rules)?)?;
+            let summary = Rewriter::new()
+                .stage(Relabeler::from_rules(rules))
+                .run(open(&input)?, &mut out)?;
+            eprintln!(
+                "read {}, wrote {}, dropped {}",
+                summary.read, summary.written, summary.dropped
+            );
+            ExitCode::SUCCESS
+        }
+        Command::Infer {
+            samples,
+            coordinates,
+            input,
+        } => {
+            infer(&read(&input)?, samples, coordinates.as_ref(), &mut out)?;
+            ExitCode::SUCCESS
+        }
+        Command::Rewrite {
+            migrations,
+            relabel,
+            framed,
+            input,
+        } => {
+            let mut rewriter = Rewriter::new();
+            if let Some(path) = migrations {
+                rewriter = rewriter.stage(migrator(&fs::read(path)?)?);
+            }
+            if let Some(path) = relabel {
+                let rules: Vec<Rule> = serde_json::from_slice(&fs::read(path)?)?;
+                rewriter = rewriter.stage(Relabeler::from_rules(rules));
+            }
+            let summary = if framed {
+                rewriter.run_framed(open(&input)?, &mut out)?
+            } else {
+                rewriter.run(open(&input)?, &mut out)?
+            };
+            eprintln!(
+                "read {}, wrote {}, dropped {}",
+                summary.read, summary.written, summary.dropped
+            );
+            ExitCode::SUCCESS
+        }
+        Command::Loadgen(args) => {
+            drop(out);
+            loadgen(args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+    };
+    out.flush()?;
+    Ok(code)
+}
+
+fn read(input: &Option<PathBuf>) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    open(input)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn open(input: &Option<PathBuf>) -> Result<Box<dyn BufRead>> {
+    Ok(match input.as_deref() {
+        None => Box::new(BufReader::new(io::stdin())),
+        Some(path) if path == Path::new("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+    })
+}
+
+/// The envelopes of an input, each with its position: the line a stream's
+/// envelopes are on, or 1 for a single envelope.
+fn envelopes(bytes: &[u8]) -> Vec<(usize, Result<RawPacket>)> {
+    let error = match RawPacket::from_bytes_auto(bytes) {
+        Ok((packet, _)) => return vec![(1, Ok(packet))],
+        Err(e) => e,
+    };
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return vec![(1, Err(error.into()))],
+    };
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let packet = serde_json::from_str(line).map_err(Into::into);
+            (i + 1, packet)
+        })
+        .collect()
+}
+
+fn inspect(bytes: &[u8], content: bool, out: &mut dyn Write) -> Result<()> {
+    for (line, packet) in envelopes(bytes) {
+        let packet = packet.map_err(|e| format!("line {}: {}", line, e))?;
+        if content {
+            serde_json::to_writer_pretty(&mut *out, &packet)?;
+        } else {
+            serde_json::to_writer_pretty(&mut *out, &Header::from(packet.manifest))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn convert(bytes: &[u8], from: Option<Format>, to: Format, out: &mut dyn Write) -> Result<()> {
+    let packet = match from {
+        Some(format) => RawPacket::from_bytes(bytes, format)?,
+        None => RawPacket::from_bytes_auto(bytes)?.0,
+    };
+    out.write_all(&packet.to_bytes(to)?)?;
+    Ok(())
+}
+
+fn filter<R: BufRead>(
+    input: R,
+    selector: &Selector,
+    invert: bool,
+    out: &mut dyn Write,
+) -> Result<()> {
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let header: Header =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        if selector.matches(&header.manifest) != invert {
+            writeln!(out, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reports each invalid envelope, returning how many there were.
+fn validate(bytes: &[u8], schema: Option<serde_json::Value>, out: &mut dyn Write) -> Result<usize> {
+    let registry = MemorySchemaRegistry::new();
+    let mut failures = 0;
+    for (line, packet) in envelopes(bytes) {
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(e) => {
+                writeln!(out, "line {}: {}", line, e)?;
+                failures += 1;
+                continue;
+            }
+        };
+        let coordinates = packet.manifest.coordinates();
+        let mut problems = Vec::new();
+        if let Err(e) = packet.manifest.validate() {
+            problems.push(e.to_string());
+        }
+        if let Some(schema) = &schema {
+            registry.register_schema(coordinates.clone(), schema.clone())?;
+            if let Err(e) = packet.validate_against(&registry) {
+                problems.push(e.to_string());
+            }
+        }
+        if !problems.is_empty() {
+            writeln!(
+                out,
+                "line {}: {}: {}",
+                line,
+                coordinates,
+                problems.join("; ")
+            )?;
+            failures += 1;
+        }
+    }
+    Ok(failures)
+}
+
+/// Prints the drafts inferred from the input's envelopes: an object from
+/// coordinates to draft, or the draft for `coordinates` alone.
+fn infer(
+    bytes: &[u8],
+    samples: u64,
+    coordinates: Option<&Coordinates>,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let inferrer = Inferrer::new(samples);
+    for (line, packet) in envelopes(bytes) {
+        let packet = packet.map_err(|e| format!("line {}: {}", line, e))?;
+        inferrer.observe(&packet);
+    }
+    let drafts = match coordinates {
+        Some(coordinates) => inferrer
+            .draft(coordinates)
+            .ok_or_else(|| format!("no envelopes of {}", coordinates))?,
+        None => inferrer
+            .drafts(&MemorySchemaRegistry::new())?
+            .into_iter()
+            .map(|(coordinates, draft)| (coordinates.to_string(), draft))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+    };
+    serde_json::to_writer_pretty(&mut *out, &drafts)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// A migrator with the steps of a migrations file, a JSON object from the
+/// coordinates each step upgrades to its mapping.
+fn migrator(bytes: &[u8]) -> Result<Migrator> {
+    let steps: BTreeMap<String, Mapping> = serde_json::from_slice(bytes)?;
+    let mut migrator = Migrator::new();
+    for (from, mapping) in steps {
+        migrator.register_mapping(from.parse()?, mapping);
+    }
+    Ok(migrator)
+}
+
+/// Writes packets to standard output, one JSON envelope a line.
+struct Stdout(Mutex<BufWriter<io::Stdout>>);
+
+impl Spool for Stdout {
+    fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+        let mut line = serde_json::to_vec(packet)?;
+        line.push(b'\n');
+        let mut out = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(&line)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut out = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        out.flush()
+    }
+}
+
+fn loadgen(args: LoadgenArgs) -> Result<()> {
+    let mut load = LoadGen::new()
+        .payload_size(args.payload_size)
+        .origins(args.origins);
+    for (coordinates, weight) in args.kinds {
+        load = load.kind(coordinates, weight);
+    }
+    for (key, cardinality) in args.labels {
+        load = load.label(key, cardinality);
+    }
+    if let Some(rate) = args.rate {
+        load = load.rate(rate);
+    }
+    if let Some(seed) = args.seed {
+        load = load.seed(seed);
+    }
+    let sink: Box<dyn Spool> = match &args.output {
+        Some(path) => Box::new(FileSpool::open(path)?),
+        None => Box::new(Stdout(Mutex::new(BufWriter::new(io::stdout())))),
+    };
+    let report = match (args.count, args.duration) {
+        (Some(count), _) => load.run(&*sink, count)?,
+        (None, Some(seconds)) => load.run_for(&*sink, Duration::try_from_secs_f64(seconds)?)?,
+        (None, None) => unreachable!("clap requires a count or a duration"),
+    };
+    eprintln!("{}", report);
+    Ok(())
+}
+
+fn parse_kind(s: &str) -> std::result::Result<(Coordinates, u32), String> {
+    let (coordinates, weight) = match s.rsplit_once('=') {
+        Some((coordinates, weight)) => {
+            let weight = weight.parse().map_err(|_| "invalid weight".to_string())?;
+            if weight == 0 {
+                return Err("weights must be above zero".to_string());
+            }
+            (coordinates, weight)
+        }
+        None => (s, 1),
+    };
+    Ok((coordinates.parse().map_err(|e| format!("{}", e))?, weight))
+}
+
+fn parse_sizes(s: &str) -> std::result::Result<RangeInclusive<usize>, String> {
+    let size = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .map_err(|_| "invalid size".to_string())
+    };
+    let sizes = match s.split_once('-') {
+        Some((min, max)) => size(min)?..=size(max)?,
+        None => size(s)?..=size(s)?,
+    };
+    if sizes.is_empty() {
+        return Err("the range is empty".to_string());
+    }
+    Ok(sizes)
+}
+
+fn parse_label(s: &str) -> std::result::Result<(String, usize), String> {
+    let (key, cardinality) = s
+        .split_once('=')
+        .ok_or_else(|| "expected `key=cardinality`".to_string())?;
+    match cardinality.parse() {
+        Ok(cardinality) if cardinality > 0 => Ok((key.to_string(), cardinality)),
+        _ => Err("cardinalities must be whole numbers above zero".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    const CPU: &str = include_str!("../../fixtures/cpu.json");
+    const NETSTAT: &str = include_str!("../../fixtures/netstat.json");
+
+    fn stream() -> String {
+        [CPU, NETSTAT]
+            .iter()
+            .map(|json| {
+                let packet: RawPacket = serde_json::from_str(json).unwrap();
+                serde_json::to_string(&packet).unwrap() + "\n"
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_arguments() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from([
+            "intermodal",
+            "loadgen",
+            "--kind",
+            "example.org/metrics/host/cpu@1=3",
+            "--payload-size",
+            "64-1024",
+            "--label",
+            "tenant=50",
+            "--count",
+            "10",
+        ])
+        .unwrap();
+        match cli.command {
+            Command::Loadgen(args) => {
+                assert_eq!(args.kinds[0].1, 3);
+                assert_eq!(args.payload_size, 64..=1024);
+                assert_eq!(args.labels, [("tenant".to_string(), 50)]);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(Cli::try_parse_from(["intermodal", "loadgen"]).is_err());
+        assert!(Cli::try_parse_from(["intermodal", "convert", "--to", "nope"]).is_err());
+    }
+
+    #[test]
+    fn inspects_and_filters() {
+        let mut out = Vec::new();
+        inspect(CPU.as_bytes(), false, &mut out).unwrap();
+        let printed: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(printed["manifest"]["kind"], "cpu");
+        assert!(printed.get("content").is_none());
+
+        let mut out = Vec::new();
+        inspect(stream().as_bytes(), false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().matches("\"kind\"").count(),
+            2
+        );
+
+        let selector = "kind=netstat".parse().unwrap();
+        let mut out = Vec::new();
+        filter(stream().as_bytes(), &selector, false, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("\"kind\":\"netstat\""));
+        let mut out = Vec::new();
+        filter(stream().as_bytes(), &selector, true, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("\"kind\":\"cpu\""));
+    }
+
+    #[test]
+    fn converts_between_formats() {
+        let mut cbor = Vec::new();
+        convert(CPU.as_bytes(), None, Format::Cbor, &mut cbor).unwrap();
+        let mut yaml = Vec::new();
+        convert(&cbor, Some(Format::Cbor), Format::Yaml, &mut yaml).unwrap();
+        assert!(String::from_utf8(yaml.clone())
+            .unwrap()
+            .contains("kind: cpu"));
+        let mut json = Vec::new();
+        convert(&yaml, None, Format::Json, &mut json).unwrap();
+        let expected: RawPacket = serde_json::from_str(CPU).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<RawPacket>(&json).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn validates_envelopes() {
+        let mut out = Vec::new();
+        assert_eq!(validate(stream().as_bytes(), None, &mut out).unwrap(), 0);
+
+        let schema = serde_json::json!({ "type": "object", "required": ["idle"] });
+        let input = stream() + "not an envelope\n";
+        let mut out = Vec::new();
+        assert_eq!(
+            validate(input.as_bytes(), Some(schema), &mut out).unwrap(),
+            2
+        );
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("line 2: example.org/metrics/host/netstat@1: "),
+            "{}",
+            out
+        );
+        assert!(out.contains("line 3: "));
+    }
+
+    #[test]
+    fn infers_drafts() {
+        let mut out = Vec::new();
+        infer(stream().as_bytes(), 10, None, &mut out).unwrap();
+        let drafts: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let cpu = &drafts["example.org/metrics/host/cpu@1"];
+        assert_eq!(cpu["properties"]["idle"]["type"], "number");
+        assert!(drafts["example.org/metrics/host/netstat@1"].is_object());
+
+        let netstat = "example.org/metrics/host/netstat@1".parse().unwrap();
+        let mut out = Vec::new();
+        infer(stream().as_bytes(), 10, Some(&netstat), &mut out).unwrap();
+        let draft: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(draft["title"], "example.org/metrics/host/netstat@1");
+        let uptime = "example.org/metrics/host/uptime@1".parse().unwrap();
+        assert!(infer(stream().as_bytes(), 10, Some(&uptime), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn migrates_archives() {
+        let migrations = br#"{
+            "example.org/metrics/host/cpu@1": {
+                "rules": [{ "from": "/user", "to": "/busy/user" }]
+            }
+        }"#;
+        let mut out = Vec::new();
+        let summary = Rewriter::new()
+            .stage(migrator(migrations).unwrap())
+            .run(stream().as_bytes(), &mut out)
+            .unwrap();
+        assert_eq!(summary.written, 2);
+        let cpu: RawPacket =
+            serde_json::from_str(std::str::from_utf8(&out).unwrap().lines().next().unwrap())
+                .unwrap();
+        assert_eq!(cpu.manifest.version, 2);
+        assert_eq!(cpu.content["busy"]["user"], 12.5);
+
+        assert!(migrator(br#"{ "cpu": { "rules": [] } }"#).is_err());
+    }
+}
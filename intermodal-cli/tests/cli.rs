@@ -0,0 +1,224 @@
+//! Runs the `intermodal` binary, one subcommand at a time.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use intermodal::framing::{FramedReader, FramedWriter};
+use intermodal::{Format, RawPacket};
+use serde_json::Value;
+
+const CPU: &str = include_str!("../../fixtures/cpu.json");
+const NETSTAT: &str = include_str!("../../fixtures/netstat.json");
+
+/// Runs the binary with `stdin` as its standard input.
+fn intermodal(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_intermodal"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Runs the binary, expecting it to succeed, and returns its output.
+fn succeeds(args: &[&str], stdin: &[u8]) -> Vec<u8> {
+    let output = intermodal(args, stdin);
+    assert!(
+        output.status.success(),
+        "{:?}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+fn stream() -> String {
+    [CPU, NETSTAT]
+        .iter()
+        .map(|json| {
+            let packet: RawPacket = serde_json::from_str(json).unwrap();
+            serde_json::to_string(&packet).unwrap() + "\n"
+        })
+        .collect()
+}
+
+fn lines(output: &[u8]) -> Vec<RawPacket> {
+    std::str::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn temp(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "intermodal-cli-{}-{}.json",
+        name,
+        std::process::id()
+    ));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn inspect() {
+    let out = succeeds(&["inspect"], CPU.as_bytes());
+    let header: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(header["manifest"]["origin"], "host01.example.org");
+    assert!(header.get("content").is_none());
+
+    let out = succeeds(&["inspect", "--content", "-"], CPU.as_bytes());
+    let packet: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(packet["content"]["idle"], 83.25);
+
+    let output = intermodal(&["inspect"], b"not an envelope");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("intermodal: "));
+}
+
+#[test]
+fn convert() {
+    let yaml = succeeds(&["convert", "--to", "yaml"], CPU.as_bytes());
+    assert!(String::from_utf8_lossy(&yaml).contains("kind: cpu"));
+    let cbor = succeeds(&["convert", "--to", "cbor", "--from", "yaml"], &yaml);
+    let json = succeeds(&["convert", "--to", "json"], &cbor);
+    assert_eq!(
+        serde_json::from_slice::<RawPacket>(&json).unwrap(),
+        serde_json::from_str::<RawPacket>(CPU).unwrap()
+    );
+}
+
+#[test]
+fn filter() {
+    let out = succeeds(&["filter", "kind=netstat"], stream().as_bytes());
+    let kinds: Vec<_> = lines(&out).into_iter().map(|p| p.manifest.kind).collect();
+    assert_eq!(kinds, ["netstat"]);
+    let out = succeeds(&["filter", "-v", "kind=netstat"], stream().as_bytes());
+    assert_eq!(lines(&out)[0].manifest.kind, "cpu");
+    assert!(!intermodal(&["filter", "kind=("], b"").status.success());
+}
+
+#[test]
+fn validate() {
+    succeeds(&["validate"], stream().as_bytes());
+
+    let schema = temp("schema", r#"{ "type": "object", "required": ["idle"] }"#);
+    let output = intermodal(
+        &["validate", "--schema", schema.to_str().unwrap()],
+        stream().as_bytes(),
+    );
+    fs::remove_file(&schema).unwrap();
+    assert!(!output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.starts_with("line 2: example.org/metrics/host/netstat@1: "));
+}
+
+#[test]
+fn relabel() {
+    let rules = temp(
+        "relabel",
+        r#"[{ "rename": { "from": "datacenter", "to": "region" } }]"#,
+    );
+    let output = intermodal(
+        &["relabel", "--rules", rules.to_str().unwrap()],
+        stream().as_bytes(),
+    );
+    fs::remove_file(&rules).unwrap();
+    assert!(output.status.success());
+    let cpu = &lines(&output.stdout)[0];
+    assert_eq!(cpu.manifest.labels["region"], "us-east");
+    assert!(!cpu.manifest.labels.contains_key("datacenter"));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr).trim(),
+        "read 2, wrote 2, dropped 0"
+    );
+}
+
+#[test]
+fn rewrite() {
+    let migrations = temp(
+        "migrations",
+        r#"{ "example.org/metrics/host/cpu@1": { "rules": [{ "from": "/user", "to": "/busy" }] } }"#,
+    );
+    let migrations = migrations.to_str().unwrap();
+    let out = succeeds(
+        &["rewrite", "--migrations", migrations],
+        stream().as_bytes(),
+    );
+    let rewritten = lines(&out);
+    assert_eq!(rewritten[0].manifest.version, 2);
+    assert_eq!(rewritten[0].content["busy"], 12.5);
+    assert_eq!(
+        rewritten[0].manifest.ctime.to_rfc3339(),
+        "2020-06-01T12:00:00+00:00"
+    );
+    assert_eq!(rewritten[1].manifest.version, 1);
+
+    let mut archive = FramedWriter::new(Vec::new());
+    for json in [CPU, NETSTAT] {
+        let packet: RawPacket = serde_json::from_str(json).unwrap();
+        archive.write_packet(&packet, Format::Cbor).unwrap();
+    }
+    let out = succeeds(
+        &["rewrite", "--framed", "--migrations", migrations],
+        &archive.into_inner(),
+    );
+    fs::remove_file(migrations).unwrap();
+    let frames: Vec<_> = FramedReader::new(&out[..])
+        .map(|frame| frame.unwrap())
+        .collect();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].format, Format::Cbor);
+    assert_eq!(frames[0].manifest.version, 2);
+}
+
+#[test]
+fn infer() {
+    let out = succeeds(&["infer", "--samples", "5"], stream().as_bytes());
+    let drafts: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(
+        drafts["example.org/metrics/host/cpu@1"]["properties"]["user"]["type"],
+        "number"
+    );
+
+    let out = succeeds(
+        &[
+            "infer",
+            "--coordinates",
+            "example.org/metrics/host/netstat@1",
+        ],
+        stream().as_bytes(),
+    );
+    let draft: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(draft["title"], "example.org/metrics/host/netstat@1");
+}
+
+#[test]
+fn loadgen() {
+    let out = succeeds(
+        &[
+            "loadgen",
+            "--kind",
+            "example.org/metrics/host/cpu@1",
+            "--label",
+            "tenant=3",
+            "--count",
+            "5",
+            "--seed",
+            "7",
+        ],
+        b"",
+    );
+    let packets = lines(&out);
+    assert_eq!(packets.len(), 5);
+    assert!(packets.iter().all(|p| p.manifest.kind == "cpu"));
+    assert!(packets
+        .iter()
+        .all(|p| p.manifest.labels.contains_key("tenant")));
+    assert!(!intermodal(&["loadgen"], b"").status.success());
+}
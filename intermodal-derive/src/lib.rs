@@ -0,0 +1,191 @@
+//! The `#[derive(Intermodal)]` macro, re-exported by `intermodal` with the
+//! `derive` feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitInt, LitStr};
+
+/// Implements `intermodal::ContentType` for a content type, from its
+/// `#[intermodal(domain = "...", scope = "...", kind = "...", version = N)]`
+/// attribute. Coordinates that `intermodal::validation` would reject fail
+/// to compile.
+#[proc_macro_derive(Intermodal, attributes(intermodal))]
+pub fn derive_intermodal(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut domain = None;
+    let mut scope = None;
+    let mut kind = None;
+    let mut version = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("intermodal"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("domain") {
+                domain = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("scope") {
+                scope = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("kind") {
+                kind = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("version") {
+                let lit = meta.value()?.parse::<LitInt>()?;
+                if lit.base10_parse::<u32>()? < 1 {
+                    return Err(syn::Error::new_spanned(&lit, "`version` is less than 1"));
+                }
+                version = Some(lit);
+            } else {
+                return Err(meta.error("expected `domain`, `scope`, `kind` or `version`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let missing = |field: &str| {
+        syn::Error::new_spanned(
+            &input.ident,
+            format!("missing `{}` in #[intermodal(...)]", field),
+        )
+    };
+    let domain = domain.ok_or_else(|| missing("domain"))?;
+    let scope = scope.ok_or_else(|| missing("scope"))?;
+    let kind = kind.ok_or_else(|| missing("kind"))?;
+    let version = version.ok_or_else(|| missing("version"))?;
+    let checks: [(&str, &LitStr, Check); 3] = [
+        ("domain", &domain, check_domain),
+        ("scope", &scope, check_scope),
+        ("kind", &kind, check_kind),
+    ];
+    for (field, lit, check) in checks.iter() {
+        if let Err(reason) = check(&lit.value()) {
+            return Err(syn::Error::new_spanned(
+                lit,
+                format!("`{}` {}", field, reason),
+            ));
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::intermodal::ContentType for #name #ty_generics #where_clause {
+            const DOMAIN: &'static str = #domain;
+            const SCOPE: &'static str = #scope;
+            const KIND: &'static str = #kind;
+            const VERSION: u32 = #version;
+        }
+    })
+}
+
+// The rules below are those of `intermodal::validation`, which this crate
+// cannot depend on, since `intermodal` depends on it.
+
+type Check = fn(&str) -> Result<(), &'static str>;
+
+fn check_domain(domain: &str) -> Result<(), &'static str> {
+    if domain.is_empty() {
+        return Err("is empty");
+    }
+    if domain.len() > 253 {
+        return Err("is longer than 253 characters");
+    }
+    for label in domain.split('.') {
+        if label.is_empty() {
+            return Err("has an empty label");
+        }
+        if label.len() > 63 {
+            return Err("has a label longer than 63 characters");
+        }
+        if !label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        {
+            return Err("may only contain ASCII letters, digits, hyphens and dots");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err("has a label starting or ending with a hyphen");
+        }
+    }
+    Ok(())
+}
+
+fn check_scope(scope: &str) -> Result<(), &'static str> {
+    if scope.is_empty() {
+        return Err("is empty");
+    }
+    for segment in scope.split('/') {
+        if segment.is_empty() {
+            return Err("has an empty segment");
+        }
+        if segment == "." || segment == ".." {
+            return Err("has a `.` or `..` segment");
+        }
+        if !segment
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+        {
+            return Err(
+                "has a segment with characters other than ASCII letters, digits, `-`, `_` and `.`",
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_kind(kind: &str) -> Result<(), &'static str> {
+    if kind.is_empty() {
+        return Err("is empty");
+    }
+    if kind.chars().any(char::is_uppercase) {
+        return Err("is not lowercase");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(attr: &str) -> Option<String> {
+        let input = syn::parse_str(&format!("{} struct Cpu;", attr)).unwrap();
+        expand(&input).err().map(|e| e.to_string())
+    }
+
+    #[test]
+    fn checks_coordinates() {
+        let attr = |domain: &str, scope: &str, kind: &str, version: u32| {
+            format!(
+                r#"#[intermodal(domain = "{}", scope = "{}", kind = "{}", version = {})]"#,
+                domain, scope, kind, version
+            )
+        };
+        assert_eq!(error(&attr("example.org", "metrics/host", "cpu", 1)), None);
+        assert_eq!(
+            error(&attr("", "metrics/host", "cpu", 1)).unwrap(),
+            "`domain` is empty"
+        );
+        assert_eq!(
+            error(&attr("example.org", "metrics//host", "cpu", 1)).unwrap(),
+            "`scope` has an empty segment"
+        );
+        assert_eq!(
+            error(&attr("example.org", "metrics/host", "CPU", 1)).unwrap(),
+            "`kind` is not lowercase"
+        );
+        assert_eq!(
+            error(&attr("example.org", "metrics/host", "cpu", 0)).unwrap(),
+            "`version` is less than 1"
+        );
+        assert_eq!(
+            error(r#"#[intermodal(domain = "example.org")]"#).unwrap(),
+            "missing `scope` in #[intermodal(...)]"
+        );
+    }
+}
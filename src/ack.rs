@@ -0,0 +1,362 @@
+//! Tracking whether emitted packets were delivered.
+//!
+//! A consumer that has processed a packet answers it with an [`Ack`], or
+//! with a [`Nack`] when it could not, carrying the packet's correlation id
+//! back; see [`ack`] and [`nack`]. On the producer's side, a [`Tracker`]
+//! assigns each packet it tracks a correlation id and hands out a
+//! [`Pending`] future, which resolves once the reply arrives or the
+//! packet times out:
+//!
+//! ```no_run
+//! # async fn run(
+//! #     send: impl Fn(&intermodal::RawPacket),
+//! #     replies: Vec<intermodal::RawPacket>,
+//! #     mut packet: intermodal::RawPacket,
+//! # ) {
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use intermodal::ack::{Delivery, Tracker};
+//! use intermodal::spool::FileSpool;
+//!
+//! let retries = Arc::new(FileSpool::open("retries.ndjson").unwrap());
+//! let tracker = Tracker::new(Duration::from_secs(30)).retry(retries);
+//!
+//! let pending = tracker.track(&mut packet);
+//! send(&packet);
+//! // ... as replies come back over the transport:
+//! for reply in &replies {
+//!     tracker.receive(reply);
+//! }
+//! match pending.await {
+//!     Delivery::Acked => {}
+//!     Delivery::Nacked(reason) => eprintln!("rejected: {}", reason),
+//!     Delivery::TimedOut => eprintln!("spooled for retry"),
+//!     Delivery::Lost(e) => eprintln!("lost: {}", e),
+//! }
+//! # }
+//! ```
+//!
+//! A packet not answered within the tracker's timeout is set aside in its
+//! retry [`Spool`], if it has one, to be replayed later. Producers may drop
+//! a [`Pending`] future they have no use for; timing out does not depend on
+//! it being awaited.
+//!
+//! Trackers run their timers on the Tokio runtime they are used from.
+//!
+//! Available with the `async` feature.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::spool::Spool;
+use crate::{ContentType, Manifest, Packet, RawPacket};
+
+/// The content of a reply saying a packet was processed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ack {}
+
+impl ContentType for Ack {
+    const DOMAIN: &'static str = "intermodal";
+    const SCOPE: &'static str = "control";
+    const KIND: &'static str = "ack";
+    const VERSION: u32 = 1;
+}
+
+/// The content of a reply saying a packet could not be processed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Nack {
+    pub reason: String,
+}
+
+impl ContentType for Nack {
+    const DOMAIN: &'static str = "intermodal";
+    const SCOPE: &'static str = "control";
+    const KIND: &'static str = "nack";
+    const VERSION: u32 = 1;
+}
+
+/// Builds an ack of the packet, sent by `origin`.
+pub fn ack<T>(packet: &Packet<T>, origin: &str) -> Packet<Ack> {
+    reply(packet, Ack {}, origin)
+}
+
+/// Builds a nack of the packet, sent by `origin`.
+pub fn nack<T, R: Into<String>>(packet: &Packet<T>, reason: R, origin: &str) -> Packet<Nack> {
+    let reason = reason.into();
+    reply(packet, Nack { reason }, origin)
+}
+
+fn reply<T, U: ContentType>(packet: &Packet<T>, content: U, origin: &str) -> Packet<U> {
+    let mut reply = packet.reply_with(content);
+    set_coordinates::<U>(&mut reply.manifest);
+    reply.manifest.origin = origin.to_string();
+    reply.manifest.expires = None;
+    reply
+}
+
+fn set_coordinates<U: ContentType>(manifest: &mut Manifest) {
+    manifest.domain = U::DOMAIN.to_string();
+    manifest.scope = U::SCOPE.to_string();
+    manifest.kind = U::KIND.to_string();
+    manifest.version = U::VERSION;
+}
+
+fn is<U: ContentType>(manifest: &Manifest) -> bool {
+    manifest.domain == U::DOMAIN
+        && manifest.scope == U::SCOPE
+        && manifest.kind == U::KIND
+        && manifest.version == U::VERSION
+}
+
+/// What became of a tracked packet.
+#[derive(Debug)]
+pub enum Delivery {
+    Acked,
+    /// The packet was rejected, for the given reason.
+    Nacked(String),
+    /// No reply arrived in time. The packet was spooled for retry, if the
+    /// tracker has a retry spool.
+    TimedOut,
+    /// No reply arrived in time, and spooling the packet for retry failed.
+    Lost(io::Error),
+}
+
+/// Correlates emitted packets with the acks and nacks returned for them.
+pub struct Tracker {
+    shared: Arc<Shared>,
+    timeout: Duration,
+    retry: Option<Arc<dyn Spool>>,
+}
+
+struct Shared {
+    prefix: String,
+    next: AtomicU64,
+    pending: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    resolve: oneshot::Sender<Delivery>,
+    timer: JoinHandle<()>,
+}
+
+impl fmt::Debug for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracker")
+            .field("timeout", &self.timeout)
+            .field("pending", &self.pending())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracker {
+    /// A tracker timing out packets not answered within `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        Tracker {
+            shared: Arc::new(Shared {
+                prefix: format!("{:x}-{:x}", std::process::id(), started),
+                next: AtomicU64::new(0),
+                pending: Mutex::new(HashMap::new()),
+            }),
+            timeout,
+            retry: None,
+        }
+    }
+
+    /// Sets packets that time out aside in `spool`.
+    pub fn retry(mut self, spool: Arc<dyn Spool>) -> Self {
+        self.retry = Some(spool);
+        self
+    }
+
+    /// Starts tracking the packet, which is about to be emitted.
+    ///
+    /// A packet without a correlation id is given one; a packet with one
+    /// keeps it, and should not share it with another tracked packet.
+    pub fn track(&self, packet: &mut RawPacket) -> Pending {
+        let id = packet
+            .manifest
+            .correlation_id
+            .get_or_insert_with(|| {
+                let n = self.shared.next.fetch_add(1, Ordering::Relaxed);
+                format!("{}-{}", self.shared.prefix, n)
+            })
+            .clone();
+        let (resolve, receiver) = oneshot::channel();
+        // The timer takes the lock before looking for its entry, so holding
+        // it until the entry is in keeps even a zero timeout from missing it.
+        let mut pending = self
+            .shared
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let timer = tokio::spawn({
+            let shared = Arc::clone(&self.shared);
+            let retry = self.retry.clone();
+            let packet = packet.clone();
+            let timeout = self.timeout;
+            let id = id.clone();
+            async move {
+                tokio::time::sleep(timeout).await;
+                let entry = shared.take(&id);
+                if let Some(entry) = entry {
+                    let delivery = match retry.map(|spool| spool.spool(&packet)) {
+                        Some(Err(e)) => Delivery::Lost(e),
+                        _ => Delivery::TimedOut,
+                    };
+                    let _ = entry.resolve.send(delivery);
+                }
+            }
+        });
+        if let Some(previous) = pending.insert(id, Entry { resolve, timer }) {
+            previous.timer.abort();
+        }
+        Pending { receiver }
+    }
+
+    /// Takes in a packet returned over the transport, resolving the tracked
+    /// packet it acks or nacks.
+    ///
+    /// Returns whether the packet was a reply to a tracked packet; other
+    /// packets are left for the caller to pass on.
+    pub fn receive(&self, packet: &RawPacket) -> bool {
+        let delivery = if is::<Ack>(&packet.manifest) {
+            Delivery::Acked
+        } else if is::<Nack>(&packet.manifest) {
+            let reason = packet
+                .content
+                .get("reason")
+                .and_then(|reason| reason.as_str())
+                .unwrap_or_default();
+            Delivery::Nacked(reason.to_string())
+        } else {
+            return false;
+        };
+        let entry = match &packet.manifest.correlation_id {
+            Some(id) => self.shared.take(id),
+            None => None,
+        };
+        match entry {
+            Some(entry) => {
+                entry.timer.abort();
+                let _ = entry.resolve.send(delivery);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many tracked packets are awaiting a reply.
+    pub fn pending(&self) -> usize {
+        self.shared
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+}
+
+impl Shared {
+    fn take(&self, id: &str) -> Option<Entry> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(id)
+    }
+}
+
+/// The delivery status of a tracked packet, resolving once it is known.
+#[derive(Debug)]
+pub struct Pending {
+    receiver: oneshot::Receiver<Delivery>,
+}
+
+impl Future for Pending {
+    type Output = Delivery;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Delivery> {
+        // The sender goes away unresolved only when the packet's timer is
+        // cancelled by tracking another packet under the same id, or by the
+        // runtime shutting down; either way, no reply will resolve it.
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|delivery| delivery.unwrap_or(Delivery::TimedOut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::cpu_raw;
+
+    #[derive(Default)]
+    struct MemorySpool(Mutex<Vec<RawPacket>>);
+
+    impl Spool for MemorySpool {
+        fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+            self.0.lock().unwrap().push(packet.clone());
+            Ok(())
+        }
+    }
+
+    fn raw<T: Serialize>(packet: Packet<T>) -> RawPacket {
+        Packet::new(
+            packet.manifest,
+            serde_json::to_value(packet.content).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn resolves_on_replies() {
+        let tracker = Tracker::new(Duration::from_secs(60));
+        let mut first = cpu_raw();
+        let mut second = cpu_raw();
+        let acked = tracker.track(&mut first);
+        let nacked = tracker.track(&mut second);
+        assert_ne!(
+            first.manifest.correlation_id,
+            second.manifest.correlation_id
+        );
+        assert_eq!(tracker.pending(), 2);
+
+        assert!(!tracker.receive(&cpu_raw()));
+        let reply = ack(&first, "collector01");
+        assert_eq!(reply.manifest.coordinates(), Ack::coordinates());
+        assert!(tracker.receive(&raw(reply)));
+        assert!(tracker.receive(&raw(nack(&second, "schema mismatch", "collector01"))));
+        assert!(!tracker.receive(&raw(ack(&first, "collector01"))));
+        assert_eq!(tracker.pending(), 0);
+
+        assert!(matches!(acked.await, Delivery::Acked));
+        assert!(matches!(nacked.await, Delivery::Nacked(reason) if reason == "schema mismatch"));
+    }
+
+    #[tokio::test]
+    async fn times_out_into_retries() {
+        let spool = Arc::new(MemorySpool::default());
+        let tracker = Tracker::new(Duration::from_millis(20)).retry(spool.clone());
+        let mut packet = cpu_raw();
+        packet.manifest.correlation_id = Some("req-42".to_string());
+        let pending = tracker.track(&mut packet);
+        assert_eq!(packet.manifest.correlation_id.as_deref(), Some("req-42"));
+
+        assert!(matches!(pending.await, Delivery::TimedOut));
+        assert_eq!(tracker.pending(), 0);
+        assert_eq!(*spool.0.lock().unwrap(), vec![packet.clone()]);
+        assert!(!tracker.receive(&raw(ack(&packet, "collector01"))));
+    }
+}
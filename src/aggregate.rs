@@ -0,0 +1,411 @@
+//! Pre-aggregating packets at the edge.
+//!
+//! Sites on constrained links often cannot afford to forward every sample
+//! they collect. An [`Aggregator`] is a [`Transform`] that takes the packets
+//! its [`Selector`] matches out of the stream, groups them by kind and by
+//! the [`Key`]s it is given, and summarizes the numbers at a few JSON
+//! pointers in their content over a window: how many there were, and their
+//! sum, minimum and maximum. Everything else passes through.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use intermodal::aggregate::Aggregator;
+//!
+//! let aggregator = Aggregator::new("kind=cpu".parse()?, Duration::from_secs(60))
+//!     .by_origin()
+//!     .by_label("datacenter")
+//!     .field("/user")
+//!     .field("/system")
+//!     .into_kind("cpu-aggregate", 1);
+//! // In the pipeline, `aggregator` absorbs cpu packets. Meanwhile:
+//! for aggregate in aggregator.flush() {
+//!     // ... send it upstream
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Each group's window opens with the first packet it absorbs. A packet
+//! arriving after the window has closed sends the group's aggregate on in
+//! its place and opens the next window, but a group that falls quiet is only
+//! sent on by [`flush`](Aggregator::flush), which should be called
+//! periodically, and [`drain`](Aggregator::drain) on shutdown.
+//!
+//! The content of an aggregate packet is an [`Aggregate`]. Its manifest is
+//! that of the first packet in the window, apart from the labels, of which
+//! only those grouped by are kept, and the creation time, which is when the
+//! window ended. If the packets came from more than one origin, the
+//! aggregate's origin is that of the aggregator.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::transform::{self, Transform};
+use crate::{Manifest, RawPacket, Selector};
+
+/// What packets are grouped by, besides their kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Key {
+    Origin,
+    /// The value of a label. Packets without the label form a group of
+    /// their own.
+    Label(String),
+}
+
+/// The content of an aggregate packet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// The number of packets aggregated.
+    pub count: u64,
+    /// The summary of each pointer that held a number in any of the
+    /// packets.
+    pub fields: BTreeMap<String, Summary>,
+}
+
+/// The numbers found at one pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    fn new(value: f64) -> Self {
+        Summary {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// The mean of the numbers.
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Summarizes matching packets by group over a window.
+#[derive(Debug)]
+pub struct Aggregator {
+    selector: Selector,
+    window: Duration,
+    keys: Vec<Key>,
+    fields: Vec<String>,
+    kind: Option<(String, u32)>,
+    origin: String,
+    groups: Mutex<HashMap<Group, Window>>,
+}
+
+/// The coordinates of a kind, and the values of its keys.
+type Group = (String, String, String, u32, Vec<Option<String>>);
+
+/// A group's packets so far.
+#[derive(Debug)]
+struct Window {
+    manifest: Manifest,
+    mixed_origins: bool,
+    start: DateTime<Utc>,
+    count: u64,
+    fields: BTreeMap<String, Summary>,
+}
+
+impl Aggregator {
+    /// Aggregates packets matching `selector` over windows of `window`.
+    /// Until keys are added, packets are grouped by kind alone.
+    pub fn new(selector: Selector, window: Duration) -> Self {
+        Aggregator {
+            selector,
+            window,
+            keys: Vec::new(),
+            fields: Vec::new(),
+            kind: None,
+            origin: gethostname::gethostname().to_string_lossy().into_owned(),
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn key(mut self, key: Key) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    pub fn by_origin(self) -> Self {
+        self.key(Key::Origin)
+    }
+
+    pub fn by_label<S: Into<String>>(self, key: S) -> Self {
+        self.key(Key::Label(key.into()))
+    }
+
+    /// Summarizes the numbers at a JSON pointer into the content. Values
+    /// that are absent or not numbers are skipped.
+    pub fn field<S: Into<String>>(mut self, pointer: S) -> Self {
+        self.fields.push(pointer.into());
+        self
+    }
+
+    /// Gives aggregate packets their own kind and version, in place of those
+    /// of the packets aggregated.
+    pub fn into_kind<S: Into<String>>(mut self, kind: S, version: u32) -> Self {
+        self.kind = Some((kind.into(), version));
+        self
+    }
+
+    /// Sets the origin of aggregates of packets from more than one origin.
+    /// Defaults to the local hostname.
+    pub fn origin<S: Into<String>>(mut self, origin: S) -> Self {
+        self.origin = origin.into();
+        self
+    }
+
+    /// The number of groups with a window open.
+    pub fn pending(&self) -> usize {
+        let groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+        groups.len()
+    }
+
+    /// Returns the aggregates of the windows that have closed, oldest
+    /// first.
+    pub fn flush(&self) -> Vec<RawPacket> {
+        self.flush_at(Utc::now())
+    }
+
+    /// Returns the aggregates of every open window, oldest first, closing
+    /// them early.
+    pub fn drain(&self) -> Vec<RawPacket> {
+        let now = Utc::now();
+        let mut groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+        let mut windows: Vec<_> = groups.drain().collect();
+        windows.sort_by(|(a, x), (b, y)| (x.start, a).cmp(&(y.start, b)));
+        windows
+            .into_iter()
+            .map(|(_, window)| self.aggregate(window, now))
+            .collect()
+    }
+
+    fn flush_at(&self, now: DateTime<Utc>) -> Vec<RawPacket> {
+        let mut groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+        let mut closed: Vec<_> = groups
+            .iter()
+            .filter(|(_, window)| self.is_closed(window, now))
+            .map(|(group, window)| (window.start, group.clone()))
+            .collect();
+        closed.sort();
+        closed
+            .into_iter()
+            .filter_map(|(_, group)| groups.remove(&group))
+            .map(|window| self.close(window))
+            .collect()
+    }
+
+    fn is_closed(&self, window: &Window, now: DateTime<Utc>) -> bool {
+        (now - window.start)
+            .to_std()
+            .is_ok_and(|elapsed| elapsed >= self.window)
+    }
+
+    fn group(&self, manifest: &Manifest) -> Group {
+        let values = self
+            .keys
+            .iter()
+            .map(|key| match key {
+                Key::Origin => Some(manifest.origin.clone()),
+                Key::Label(label) => manifest.labels.get(label).cloned(),
+            })
+            .collect();
+        (
+            manifest.domain.clone(),
+            manifest.scope.clone(),
+            manifest.kind.clone(),
+            manifest.version,
+            values,
+        )
+    }
+
+    fn apply_at(&self, packet: RawPacket, now: DateTime<Utc>) -> Option<RawPacket> {
+        if !self.selector.matches(&packet.manifest) {
+            return Some(packet);
+        }
+        let group = self.group(&packet.manifest);
+        let mut groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+        let closed = match groups.get(&group) {
+            Some(window) if self.is_closed(window, now) => groups.remove(&group),
+            _ => None,
+        };
+        let window = groups.entry(group).or_insert_with(|| Window {
+            manifest: packet.manifest.clone(),
+            mixed_origins: false,
+            start: now,
+            count: 0,
+            fields: BTreeMap::new(),
+        });
+        window.mixed_origins |= window.manifest.origin != packet.manifest.origin;
+        window.count += 1;
+        for pointer in &self.fields {
+            if let Some(value) = packet.content.pointer(pointer).and_then(Value::as_f64) {
+                window
+                    .fields
+                    .entry(pointer.clone())
+                    .and_modify(|summary| summary.add(value))
+                    .or_insert_with(|| Summary::new(value));
+            }
+        }
+        closed.map(|window| self.close(window))
+    }
+
+    /// The aggregate of a window that ran its full length.
+    fn close(&self, window: Window) -> RawPacket {
+        let end = window.start + self.window;
+        self.aggregate(window, end)
+    }
+
+    fn aggregate(&self, window: Window, end: DateTime<Utc>) -> RawPacket {
+        let source = window.manifest;
+        let labels = self
+            .keys
+            .iter()
+            .filter_map(|key| match key {
+                Key::Origin => None,
+                Key::Label(label) => source.labels.get_key_value(label),
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let (kind, version) = match &self.kind {
+            Some((kind, version)) => (kind.clone(), *version),
+            None => (source.kind, source.version),
+        };
+        let manifest = Manifest {
+            domain: source.domain,
+            scope: source.scope,
+            kind,
+            version,
+            ctime: end,
+            expires: None,
+            origin: if window.mixed_origins {
+                self.origin.clone()
+            } else {
+                source.origin
+            },
+            labels,
+            trace: None,
+            correlation_id: None,
+            reply_to: None,
+        };
+        let aggregate = Aggregate {
+            start: window.start,
+            end,
+            count: window.count,
+            fields: window.fields,
+        };
+        let content = serde_json::to_value(aggregate).expect("aggregates serialize to JSON");
+        RawPacket::new(manifest, content)
+    }
+}
+
+impl Transform for Aggregator {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        Ok(self.apply_at(packet, Utc::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn cpu(origin: &str, user: f64) -> RawPacket {
+        let mut packet = fixtures::cpu_raw();
+        packet.manifest.origin = origin.to_string();
+        packet.content["user"] = user.into();
+        packet
+    }
+
+    #[test]
+    fn summarizes_groups() {
+        let aggregator = Aggregator::new("kind=cpu".parse().unwrap(), Duration::from_secs(60))
+            .by_label("datacenter")
+            .field("/user")
+            .field("/idle")
+            .field("/missing")
+            .into_kind("cpu-aggregate", 1)
+            .origin("edge01");
+        let start = fixtures::cpu_manifest().ctime;
+        let at = |seconds| start + chrono::Duration::seconds(seconds);
+
+        assert!(aggregator.apply_at(cpu("host01", 10.0), at(0)).is_none());
+        assert!(aggregator.apply_at(cpu("host02", 30.0), at(10)).is_none());
+        let netstat = fixtures::netstat_raw();
+        assert_eq!(aggregator.apply_at(netstat.clone(), at(10)), Some(netstat));
+        assert_eq!(aggregator.pending(), 1);
+        assert!(aggregator.flush_at(at(59)).is_empty());
+
+        let aggregates = aggregator.flush_at(at(60));
+        assert_eq!(aggregator.pending(), 0);
+        assert_eq!(aggregates.len(), 1);
+        let manifest = &aggregates[0].manifest;
+        assert_eq!(manifest.kind, "cpu-aggregate");
+        assert_eq!(manifest.origin, "edge01");
+        assert_eq!(manifest.ctime, at(60));
+        assert_eq!(manifest.labels.len(), 1);
+        assert_eq!(manifest.labels["datacenter"], "us-east");
+
+        let aggregate: Aggregate = serde_json::from_value(aggregates[0].content.clone()).unwrap();
+        assert_eq!((aggregate.start, aggregate.end), (at(0), at(60)));
+        assert_eq!(aggregate.count, 2);
+        let user = Summary {
+            count: 2,
+            sum: 40.0,
+            min: 10.0,
+            max: 30.0,
+        };
+        assert_eq!(aggregate.fields["/user"], user);
+        assert_eq!(user.mean(), 20.0);
+        assert_eq!(aggregate.fields["/idle"].count, 2);
+        assert!(!aggregate.fields.contains_key("/missing"));
+    }
+
+    #[test]
+    fn late_packets_close_their_window() {
+        let aggregator = Aggregator::new(Selector::any(), Duration::from_secs(60))
+            .by_origin()
+            .field("/user");
+        let start = fixtures::cpu_manifest().ctime;
+        let at = |seconds| start + chrono::Duration::seconds(seconds);
+
+        assert!(aggregator.apply_at(cpu("host01", 1.0), at(0)).is_none());
+        assert!(aggregator.apply_at(cpu("host02", 2.0), at(30)).is_none());
+        assert_eq!(aggregator.pending(), 2);
+
+        let closed = aggregator.apply_at(cpu("host01", 3.0), at(70)).unwrap();
+        assert_eq!(closed.manifest.kind, "cpu");
+        assert_eq!(closed.manifest.origin, "host01");
+        assert!(closed.manifest.labels.is_empty());
+        assert_eq!(closed.content["count"], 1);
+        assert_eq!(closed.content["fields"]["/user"]["sum"], 1.0);
+
+        let origins: Vec<_> = aggregator
+            .drain()
+            .into_iter()
+            .map(|aggregate| aggregate.manifest.origin)
+            .collect();
+        assert_eq!(origins, ["host02", "host01"]);
+        assert_eq!(aggregator.pending(), 0);
+    }
+}
@@ -0,0 +1,254 @@
+//! Exchanging framed envelopes over asynchronous byte streams.
+//!
+//! A [`PacketStream`] wraps a Tokio [`AsyncRead`] and [`AsyncWrite`], such
+//! as a TCP stream, in the [framing](crate::framing) format. It is a
+//! [`Stream`] of the packets read from it and a [`Sink`] for packets to
+//! write to it:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use futures_util::{SinkExt, StreamExt};
+//! use intermodal::aio::PacketStream;
+//! # let socket = tokio::io::empty();
+//!
+//! let mut packets = PacketStream::<_, serde_json::Value>::new(socket);
+//! while let Some(packet) = packets.next().await {
+//!     let packet = packet?;
+//!     packets.send(packet).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Available with the `aio` feature.
+
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{Sink, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::framing::{self, Error, FramedReader, FramedWriter};
+use crate::{Format, Packet};
+
+/// The largest frame read unless set otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = framing::DEFAULT_MAX_FRAME_LEN;
+
+/// How much encoded output is buffered before sending waits for it to be
+/// written.
+const WRITE_HIGH_WATER: usize = 64 << 10;
+
+/// Envelopes framed over an asynchronous byte stream.
+///
+/// Reading yields `Packet<T>`, and writing takes them. Packets are written
+/// in the stream's [`Format`], JSON unless set otherwise, while frames in
+/// any enabled format are read.
+#[derive(Debug)]
+pub struct PacketStream<S, T = Value> {
+    io: S,
+    format: Format,
+    max_frame_len: usize,
+    read: Vec<u8>,
+    eof: bool,
+    write: FramedWriter<Vec<u8>>,
+    written: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<S, T> PacketStream<S, T> {
+    pub fn new(io: S) -> Self {
+        PacketStream {
+            io,
+            format: Format::Json,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            read: Vec::new(),
+            eof: false,
+            write: FramedWriter::new(Vec::new()),
+            written: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the format packets are written in.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the largest frame to read. A longer frame fails the stream with
+    /// [`Error::TooLarge`] before it is buffered.
+    pub fn max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.io
+    }
+
+    /// Returns the underlying stream. Input read but not yet decoded, and
+    /// output sent but not yet flushed, are lost.
+    pub fn into_inner(self) -> S {
+        self.io
+    }
+}
+
+impl<S: AsyncRead + Unpin, T: DeserializeOwned> Stream for PacketStream<S, T> {
+    type Item = Result<Packet<T>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match framing::frame_len(&this.read, this.max_frame_len) {
+                Ok(Some(len)) if this.read.len() >= len => {
+                    // The frame's length has been checked already.
+                    let frame = FramedReader::new(&this.read[..len])
+                        .max_frame_len(usize::MAX)
+                        .read_frame();
+                    this.read.drain(..len);
+                    let packet = frame.and_then(|frame| match frame {
+                        Some(frame) => frame.decode(),
+                        None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                    });
+                    return Poll::Ready(Some(packet));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // The rest of the input cannot be framed.
+                    this.read.clear();
+                    this.eof = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+            if this.eof {
+                if this.read.is_empty() {
+                    return Poll::Ready(None);
+                }
+                this.read.clear();
+                let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Poll::Ready(Some(Err(e.into())));
+            }
+
+            let mut chunk = [0; 8 << 10];
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.io).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => this.eof = true,
+                Poll::Ready(Ok(())) => this.read.extend_from_slice(buf.filled()),
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin, T> PacketStream<S, T> {
+    /// Writes out buffered output, without flushing the underlying stream.
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.written < self.write.get_ref().len() {
+            let pending = &self.write.get_ref()[self.written..];
+            match Pin::new(&mut self.io).poll_write(cx, pending) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()))
+                }
+                Poll::Ready(Ok(n)) => self.written += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.write.get_mut().clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin, T: Serialize> Sink<Packet<T>> for PacketStream<S, T> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if this.write.get_ref().len() >= WRITE_HIGH_WATER {
+            this.poll_write_buffered(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, packet: Packet<T>) -> Result<(), Error> {
+        let this = self.get_mut();
+        let format = this.format;
+        this.write.write_packet(&packet, format)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        match this.poll_write_buffered(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_flush(cx).map_err(Error::from),
+            other => other,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.get_mut().io)
+                .poll_shutdown(cx)
+                .map_err(Error::from),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+    use futures_util::{SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn exchanges_packets() {
+        // A small pipe forces frames across many reads and writes.
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = PacketStream::<_, Value>::new(client);
+        let mut server = PacketStream::<_, Cpu>::new(server);
+
+        let sending = tokio::spawn(async move {
+            client.send(fixtures::cpu_raw()).await.unwrap();
+            client.send(fixtures::cpu_raw()).await.unwrap();
+            client.close().await.unwrap();
+        });
+        let cpu: Packet<Cpu> = fixtures::cpu_raw().try_map(serde_json::from_value).unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), cpu);
+        assert_eq!(server.next().await.unwrap().unwrap(), cpu);
+        assert!(server.next().await.is_none());
+        sending.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_input() {
+        let mut packets = PacketStream::<_, Value>::new(&b"GET / HTTP/1.1\r\n"[..]);
+        assert!(matches!(packets.next().await, Some(Err(Error::BadMagic))));
+        assert!(packets.next().await.is_none());
+
+        let mut frame = FramedWriter::new(Vec::new());
+        frame
+            .write_packet(&fixtures::netstat_raw(), Format::Json)
+            .unwrap();
+        let frame = frame.into_inner();
+        let mut packets = PacketStream::<_, Value>::new(&frame[..]).max_frame_len(64);
+        assert!(matches!(
+            packets.next().await,
+            Some(Err(Error::TooLarge(_)))
+        ));
+
+        let mut packets = PacketStream::<_, Value>::new(&frame[..frame.len() - 1]);
+        assert!(matches!(packets.next().await, Some(Err(Error::Io(_)))));
+    }
+}
@@ -0,0 +1,668 @@
+//! Archives of framed envelopes on disk.
+//!
+//! An [`Archive`] is a file of [frames](crate::framing), one per envelope,
+//! as written by a [`FramedWriter`]. [`verify`](Archive::verify) reads it
+//! through and reports where it is damaged: frames that do not decode or,
+//! when written with [checksums](FramedWriter::checksums), do not match
+//! their checksums, and a last frame cut short by a torn write.
+//! [`repair`](Archive::repair) copies the intact frames elsewhere:
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use intermodal::archive::Archive;
+//!
+//! let archive = Archive::open("/var/spool/intermodal/2020-06-01.imf")?;
+//! let integrity = archive.verify()?;
+//! if !integrity.is_intact() {
+//!     for damage in &integrity.damaged {
+//!         eprintln!("bytes {:?}: {}", damage.range, damage.error);
+//!     }
+//!     archive.repair(File::create("/var/spool/intermodal/2020-06-01.repaired.imf")?)?;
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! After damage, reading resumes at the next occurrence of the frame
+//! [magic bytes](crate::framing::MAGIC). Frames written without checksums
+//! are checked only for decoding, so damage to their content goes unseen,
+//! and content that happens to hold the magic bytes can pass for a frame.
+//!
+//! With the `archive-encryption` feature, an [`EncryptedWriter`] encrypts a
+//! whole archive as it is written, for storage at rest, and an
+//! [`EncryptedReader`] decrypts it as it is read, taking the key named in
+//! the archive's header from a [`KeyProvider`](crate::keys::KeyProvider):
+//!
+//! ```no_run
+//! # #[cfg(feature = "archive-encryption")] {
+//! use std::fs::File;
+//! use intermodal::archive::{EncryptedReader, EncryptedWriter};
+//! use intermodal::framing::{FramedReader, FramedWriter};
+//! use intermodal::keys::KeySet;
+//! # let packet: intermodal::RawPacket = unimplemented!();
+//!
+//! let keys = KeySet::from_env("INTERMODAL_ARCHIVE_KEY_")?;
+//! let file = File::create("/media/usb0/buffer.imf")?;
+//! let mut writer = FramedWriter::new(EncryptedWriter::with_provider(file, &keys)?);
+//! writer.write_packet(&packet, intermodal::Format::Json)?;
+//! writer.into_inner().finish()?;
+//!
+//! let file = File::open("/media/usb0/buffer.imf")?;
+//! for frame in FramedReader::new(EncryptedReader::new(file, &keys)?) {
+//!     // ...
+//! }
+//! # }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! An encrypted archive starts with a header: [`ENCRYPTED_MAGIC`], a
+//! version byte of 1, the length of the key id in one byte, the key id,
+//! and a random 7-byte nonce prefix. AES-256-GCM in the STREAM construction
+//! then encrypts the archive in segments of 64 KiB, each authenticated
+//! along with the header, so that damage, reordering and truncation are
+//! all detected. Encrypted archives are read from start to end, so
+//! [`Archive::verify`] does not apply to them; decrypting one checks it.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::framing::{self, Counted, Frame, FramedReader, FramedWriter, MAGIC};
+
+/// A damaged stretch of an archive.
+#[derive(Debug)]
+pub struct Damage {
+    /// The bytes skipped, from the start of the damaged frame to the next
+    /// frame, or to the end of the archive.
+    pub range: Range<u64>,
+    /// Why the frame at the start of the range could not be read.
+    pub error: framing::Error,
+}
+
+/// What [`Archive::verify`] found.
+#[derive(Debug, Default)]
+pub struct Integrity {
+    /// The number of intact frames.
+    pub frames: u64,
+    /// The number of intact frames whose checksums were checked.
+    pub checksummed: u64,
+    pub damaged: Vec<Damage>,
+    /// Where the last frame starts, if it was cut short.
+    pub torn: Option<u64>,
+}
+
+impl Integrity {
+    pub fn is_intact(&self) -> bool {
+        self.damaged.is_empty() && self.torn.is_none()
+    }
+}
+
+/// A file of framed envelopes.
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    file: File,
+}
+
+impl Archive {
+    /// Opens the archive at `path` for reading.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let file = File::open(&path)?;
+        Ok(Archive { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the archive through, checking every frame.
+    pub fn verify(&self) -> Result<Integrity, framing::Error> {
+        self.walk(|_| Ok(()))
+    }
+
+    /// Writes the archive's intact frames to `dest`, in order, with
+    /// checksums, and reports what was left behind.
+    pub fn repair<W: Write>(&self, dest: W) -> Result<Integrity, framing::Error> {
+        let mut writer = FramedWriter::new(dest).checksums(true);
+        let integrity = self.walk(|frame| writer.write_frame(frame))?;
+        writer.flush()?;
+        Ok(integrity)
+    }
+
+    fn walk<F>(&self, mut intact: F) -> Result<Integrity, framing::Error>
+    where
+        F: FnMut(&Frame) -> Result<(), framing::Error>,
+    {
+        let len = self.file.metadata()?.len();
+        let mut integrity = Integrity::default();
+        let mut offset = 0;
+        while offset < len {
+            (&self.file).seek(SeekFrom::Start(offset))?;
+            let mut reader = FramedReader::new(Counted {
+                inner: BufReader::new(&self.file),
+                count: 0,
+            });
+            let (at, error) = loop {
+                let at = offset + reader.get_ref().count;
+                match reader.read_checked_frame() {
+                    Ok(Some((frame, checksummed))) => {
+                        integrity.frames += 1;
+                        integrity.checksummed += u64::from(checksummed);
+                        intact(&frame)?;
+                    }
+                    Ok(None) => break (len, None),
+                    Err(framing::Error::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                        return Err(e.into())
+                    }
+                    Err(error) => break (at, Some(error)),
+                }
+            };
+            let error = match error {
+                Some(error) => error,
+                None => break,
+            };
+            offset = match find_magic(&self.file, at + 1)? {
+                Some(next) => {
+                    integrity.damaged.push(Damage {
+                        range: at..next,
+                        error,
+                    });
+                    next
+                }
+                None => {
+                    match error {
+                        framing::Error::Io(_) => integrity.torn = Some(at),
+                        error => integrity.damaged.push(Damage {
+                            range: at..len,
+                            error,
+                        }),
+                    }
+                    len
+                }
+            };
+        }
+        Ok(integrity)
+    }
+}
+
+#[cfg(feature = "archive-encryption")]
+pub use self::encrypted::{EncryptedReader, EncryptedWriter, EncryptionError, ENCRYPTED_MAGIC};
+
+#[cfg(feature = "archive-encryption")]
+mod encrypted {
+    use std::convert::TryFrom;
+    use std::fmt;
+    use std::io::{self, Read, Write};
+
+    use aes_gcm::aead::rand_core::RngCore;
+    use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+    use aes_gcm::aead::{OsRng, Payload};
+    use aes_gcm::aes::cipher::generic_array::GenericArray;
+    use aes_gcm::Aes256Gcm;
+
+    use crate::keys::{self, KeyEntry, KeyProvider};
+
+    /// The bytes every encrypted archive starts with.
+    pub const ENCRYPTED_MAGIC: [u8; 4] = *b"\x89IME";
+
+    const VERSION: u8 = 1;
+
+    /// The plaintext length of every segment but the last.
+    const SEGMENT_LEN: usize = 64 * 1024;
+
+    const TAG_LEN: usize = 16;
+
+    const NONCE_PREFIX_LEN: usize = 7;
+
+    /// An error starting to read or write an encrypted archive.
+    #[derive(Debug)]
+    pub enum EncryptionError {
+        Io(io::Error),
+        /// The input does not start with [`ENCRYPTED_MAGIC`].
+        NotEncrypted,
+        UnsupportedVersion(u8),
+        /// The header is malformed, or its key id too long.
+        Header(String),
+        /// The key could not be had from its provider.
+        Key(keys::Error),
+    }
+
+    impl fmt::Display for EncryptionError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                EncryptionError::Io(e) => write!(f, "{}", e),
+                EncryptionError::NotEncrypted => f.write_str("not an encrypted archive"),
+                EncryptionError::UnsupportedVersion(version) => {
+                    write!(f, "unsupported encrypted archive version {}", version)
+                }
+                EncryptionError::Header(message) => {
+                    write!(f, "encrypted archive header: {}", message)
+                }
+                EncryptionError::Key(e) => write!(f, "{}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for EncryptionError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                EncryptionError::Io(e) => Some(e),
+                EncryptionError::Key(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for EncryptionError {
+        fn from(e: io::Error) -> Self {
+            EncryptionError::Io(e)
+        }
+    }
+
+    impl From<keys::Error> for EncryptionError {
+        fn from(e: keys::Error) -> Self {
+            EncryptionError::Key(e)
+        }
+    }
+
+    /// Encrypts everything written to it, as an encrypted archive.
+    ///
+    /// [`finish`](EncryptedWriter::finish) must be called once everything
+    /// is written: until then the archive has no end, and reading it fails
+    /// at the last segment, as it would for a file cut short.
+    pub struct EncryptedWriter<W: Write> {
+        inner: W,
+        encryptor: Option<EncryptorBE32<Aes256Gcm>>,
+        header: Vec<u8>,
+        buf: Vec<u8>,
+    }
+
+    impl<W: Write> EncryptedWriter<W> {
+        /// Writes the header of an archive encrypted with `key`.
+        pub fn new(mut inner: W, key: &KeyEntry) -> Result<Self, EncryptionError> {
+            let cipher = key.archive_key()?;
+            let id_len = u8::try_from(key.id.len()).map_err(|_| {
+                EncryptionError::Header(format!("key id of {} bytes is too long", key.id.len()))
+            })?;
+            let mut prefix = [0; NONCE_PREFIX_LEN];
+            OsRng.fill_bytes(&mut prefix);
+
+            let mut header = ENCRYPTED_MAGIC.to_vec();
+            header.push(VERSION);
+            header.push(id_len);
+            header.extend_from_slice(key.id.as_bytes());
+            header.extend_from_slice(&prefix);
+            inner.write_all(&header)?;
+            Ok(EncryptedWriter {
+                inner,
+                encryptor: Some(EncryptorBE32::from_aead(
+                    cipher,
+                    GenericArray::from_slice(&prefix),
+                )),
+                header,
+                buf: Vec::with_capacity(SEGMENT_LEN),
+            })
+        }
+
+        /// Writes the header of an archive encrypted with the provider's
+        /// current key.
+        pub fn with_provider<P: KeyProvider + ?Sized>(
+            inner: W,
+            keys: &P,
+        ) -> Result<Self, EncryptionError> {
+            EncryptedWriter::new(inner, &keys.current()?)
+        }
+
+        /// Writes the last segment and returns the underlying writer.
+        pub fn finish(mut self) -> io::Result<W> {
+            let encryptor = self.encryptor.take().expect("writer is not finished");
+            let segment = encryptor
+                .encrypt_last(Payload {
+                    msg: &self.buf,
+                    aad: &self.header,
+                })
+                .map_err(|_| io::Error::other("archive segment does not encrypt"))?;
+            self.inner.write_all(&segment)?;
+            self.inner.flush()?;
+            Ok(self.inner)
+        }
+    }
+
+    impl<W: Write> Write for EncryptedWriter<W> {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            // A full segment is held back until more follows it, so that
+            // the last segment, which finish writes, is never empty but for
+            // an empty archive.
+            let n = bytes.len().min(SEGMENT_LEN + 1 - self.buf.len());
+            self.buf.extend_from_slice(&bytes[..n]);
+            if self.buf.len() > SEGMENT_LEN {
+                let encryptor = self.encryptor.as_mut().expect("writer is not finished");
+                let segment = encryptor
+                    .encrypt_next(Payload {
+                        msg: &self.buf[..SEGMENT_LEN],
+                        aad: &self.header,
+                    })
+                    .map_err(|_| io::Error::other("archive segment does not encrypt"))?;
+                self.inner.write_all(&segment)?;
+                self.buf.drain(..SEGMENT_LEN);
+            }
+            Ok(n)
+        }
+
+        /// Flushes the underlying writer. Bytes not yet making up a whole
+        /// segment stay buffered.
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Decrypts an encrypted archive as it is read.
+    pub struct EncryptedReader<R> {
+        inner: R,
+        decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+        header: Vec<u8>,
+        key_id: String,
+        raw: Vec<u8>,
+        plain: Vec<u8>,
+        pos: usize,
+    }
+
+    impl<R: Read> EncryptedReader<R> {
+        /// Reads the header of an encrypted archive, and takes the key it
+        /// names from `keys`.
+        pub fn new<P: KeyProvider + ?Sized>(
+            mut inner: R,
+            keys: &P,
+        ) -> Result<Self, EncryptionError> {
+            let mut head = [0; 6];
+            inner.read_exact(&mut head)?;
+            if head[..4] != ENCRYPTED_MAGIC {
+                return Err(EncryptionError::NotEncrypted);
+            }
+            if head[4] != VERSION {
+                return Err(EncryptionError::UnsupportedVersion(head[4]));
+            }
+            let mut rest = vec![0; usize::from(head[5]) + NONCE_PREFIX_LEN];
+            inner.read_exact(&mut rest)?;
+            let (id, prefix) = rest.split_at(usize::from(head[5]));
+            let key_id = String::from_utf8(id.to_vec())
+                .map_err(|_| EncryptionError::Header("key id is not UTF-8".to_string()))?;
+            let key = keys
+                .key(&key_id)?
+                .ok_or_else(|| keys::Error::Unknown(key_id.clone()))?;
+            let decryptor =
+                DecryptorBE32::from_aead(key.archive_key()?, GenericArray::from_slice(prefix));
+            let mut header = head.to_vec();
+            header.extend_from_slice(&rest);
+            Ok(EncryptedReader {
+                inner,
+                decryptor: Some(decryptor),
+                header,
+                key_id,
+                raw: Vec::new(),
+                plain: Vec::new(),
+                pos: 0,
+            })
+        }
+
+        /// The id of the key the archive is encrypted with.
+        pub fn key_id(&self) -> &str {
+            &self.key_id
+        }
+
+        /// Decrypts the next segment into `plain`.
+        fn next_segment(&mut self) -> io::Result<()> {
+            // Read one byte past a whole segment, to tell whether it is the
+            // last.
+            let want = SEGMENT_LEN + TAG_LEN + 1;
+            while self.raw.len() < want {
+                let start = self.raw.len();
+                self.raw.resize(want, 0);
+                match self.inner.read(&mut self.raw[start..]) {
+                    Ok(n) => {
+                        self.raw.truncate(start + n);
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        self.raw.truncate(start);
+                        if e.kind() != io::ErrorKind::Interrupted {
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            let decrypted = if self.raw.len() == want {
+                let segment = &self.raw[..want - 1];
+                let decryptor = self.decryptor.as_mut().expect("reader is not done");
+                let decrypted = decryptor.decrypt_next(Payload {
+                    msg: segment,
+                    aad: &self.header,
+                });
+                self.raw.drain(..want - 1);
+                decrypted
+            } else {
+                let decryptor = self.decryptor.take().expect("reader is not done");
+                decryptor.decrypt_last(Payload {
+                    msg: &self.raw,
+                    aad: &self.header,
+                })
+            };
+            self.plain = decrypted.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "archive segment does not decrypt: damaged, cut short, or another key",
+                )
+            })?;
+            self.pos = 0;
+            Ok(())
+        }
+    }
+
+    impl<R: Read> Read for EncryptedReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.pos == self.plain.len() {
+                if self.decryptor.is_none() {
+                    return Ok(0);
+                }
+                self.next_segment()?;
+            }
+            let n = buf.len().min(self.plain.len() - self.pos);
+            buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::fixtures;
+        use crate::framing::{FramedReader, FramedWriter};
+        use crate::keys::KeySet;
+        use crate::Format;
+
+        fn keys() -> KeySet {
+            KeySet::new().insert(KeyEntry::new("disk-1", vec![3; 32]))
+        }
+
+        fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+            let mut writer = EncryptedWriter::with_provider(Vec::new(), &keys()).unwrap();
+            // Write in uneven pieces, to cross segment boundaries.
+            for piece in plaintext.chunks(10_007) {
+                writer.write_all(piece).unwrap();
+            }
+            writer.finish().unwrap()
+        }
+
+        fn decrypt(archive: &[u8]) -> io::Result<Vec<u8>> {
+            let mut reader = EncryptedReader::new(archive, &keys()).unwrap();
+            assert_eq!(reader.key_id(), "disk-1");
+            let mut plaintext = Vec::new();
+            reader.read_to_end(&mut plaintext)?;
+            Ok(plaintext)
+        }
+
+        #[test]
+        fn round_trips() {
+            for len in [0, 1, SEGMENT_LEN, SEGMENT_LEN + 1, 3 * SEGMENT_LEN + 5] {
+                let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let archive = encrypt(&plaintext);
+                assert_eq!(&archive[..5], b"\x89IME\x01");
+                assert_eq!(decrypt(&archive).unwrap(), plaintext, "{} bytes", len);
+
+                // Cutting the archive short at a segment boundary, or
+                // anywhere else, is noticed.
+                let header = 6 + "disk-1".len() + NONCE_PREFIX_LEN;
+                let segment = SEGMENT_LEN + TAG_LEN;
+                if archive.len() > header + segment {
+                    assert!(decrypt(&archive[..header + segment]).is_err());
+                }
+                assert!(decrypt(&archive[..archive.len() - 1]).is_err());
+            }
+        }
+
+        #[test]
+        fn carries_frames() {
+            let writer = EncryptedWriter::with_provider(Vec::new(), &keys()).unwrap();
+            let mut framed = FramedWriter::new(writer).checksums(true);
+            framed
+                .write_packet(&fixtures::cpu_raw(), Format::Json)
+                .unwrap();
+            let archive = framed.into_inner().finish().unwrap();
+            assert!(!String::from_utf8_lossy(&archive).contains("cpu"));
+
+            let reader = EncryptedReader::new(&archive[..], &keys()).unwrap();
+            let frames: Vec<_> = FramedReader::new(reader).collect::<Result<_, _>>().unwrap();
+            assert_eq!(frames[0].manifest, fixtures::cpu_manifest());
+
+            let mut tampered = archive.clone();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 1;
+            assert!(decrypt(&tampered).is_err());
+            assert!(matches!(
+                EncryptedReader::new(&archive[..], &KeySet::new()),
+                Err(EncryptionError::Key(keys::Error::Unknown(_)))
+            ));
+        }
+    }
+}
+
+/// The offset of the first occurrence of [`MAGIC`] at or after `from`.
+fn find_magic(file: &File, from: u64) -> io::Result<Option<u64>> {
+    let mut file = file;
+    file.seek(SeekFrom::Start(from))?;
+    let mut reader = BufReader::new(file);
+    let mut window = Vec::new();
+    let mut start = from;
+    let mut block = [0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut block) {
+            Ok(0) => return Ok(None),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        window.extend_from_slice(&block[..n]);
+        if let Some(i) = window.windows(MAGIC.len()).position(|w| w == MAGIC) {
+            return Ok(Some(start + i as u64));
+        }
+        // Keep enough of the window to find magic split across blocks.
+        let keep = window.len().min(MAGIC.len() - 1);
+        start += (window.len() - keep) as u64;
+        window.drain(..window.len() - keep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+
+    use super::*;
+    use crate::{fixtures, Format};
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "intermodal-archive-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn write(path: &Path, checksums: bool) -> Vec<u64> {
+        let mut writer = FramedWriter::new(Vec::new()).checksums(checksums);
+        let mut offsets = Vec::new();
+        for i in 0..5 {
+            offsets.push(writer.get_ref().len() as u64);
+            let packet = if i % 2 == 0 {
+                fixtures::cpu_raw()
+            } else {
+                fixtures::netstat_raw()
+            };
+            writer.write_packet(&packet, Format::Json).unwrap();
+        }
+        fs::write(path, writer.into_inner()).unwrap();
+        offsets
+    }
+
+    #[test]
+    fn finds_and_repairs_damage() {
+        let path = temp("damaged");
+        let offsets = write(&path, true);
+        let intact = Archive::open(&path).unwrap().verify().unwrap();
+        assert!(intact.is_intact());
+        assert_eq!((intact.frames, intact.checksummed), (5, 5));
+
+        // Rot a byte of the second frame's content, and tear the last.
+        let mut bytes = fs::read(&path).unwrap();
+        let rotted = offsets[2] as usize - 10;
+        bytes[rotted] ^= 0x20;
+        bytes.truncate(offsets[4] as usize + 20);
+        fs::write(&path, &bytes).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        let integrity = archive.verify().unwrap();
+        assert_eq!(integrity.frames, 3);
+        assert_eq!(integrity.damaged.len(), 1);
+        assert_eq!(integrity.damaged[0].range, offsets[1]..offsets[2]);
+        assert!(matches!(
+            integrity.damaged[0].error,
+            framing::Error::Checksum { .. }
+        ));
+        assert_eq!(integrity.torn, Some(offsets[4]));
+
+        let repaired = temp("repaired");
+        archive.repair(File::create(&repaired).unwrap()).unwrap();
+        let integrity = Archive::open(&repaired).unwrap().verify().unwrap();
+        assert!(integrity.is_intact());
+        assert_eq!(integrity.frames, 3);
+        let kinds: Vec<_> = FramedReader::new(File::open(&repaired).unwrap())
+            .map(|frame| frame.unwrap().manifest.kind)
+            .collect();
+        assert_eq!(kinds, ["cpu", "cpu", "netstat"]);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&repaired).unwrap();
+    }
+
+    #[test]
+    fn checks_unchecksummed_frames_for_decoding() {
+        let path = temp("unchecksummed");
+        let offsets = write(&path, false);
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offsets[3])).unwrap();
+        file.write_all(b"junk").unwrap();
+        drop(file);
+
+        let integrity = Archive::open(&path).unwrap().verify().unwrap();
+        assert_eq!((integrity.frames, integrity.checksummed), (4, 0));
+        assert_eq!(integrity.damaged[0].range, offsets[3]..offsets[4]);
+        assert!(matches!(
+            integrity.damaged[0].error,
+            framing::Error::BadMagic
+        ));
+        assert_eq!(integrity.torn, None);
+        fs::remove_file(&path).unwrap();
+    }
+}
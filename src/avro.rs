@@ -0,0 +1,579 @@
+//! Apache Avro envelopes, for Kafka and other Avro infrastructure.
+//!
+//! An [`Envelope`] pairs the Avro schema of a content type with the
+//! envelope's own, giving the schema of the whole packet: a record with the
+//! manifest, the content and the signature. Packets are written in Avro's
+//! single-object encoding, whose header carries the schema's fingerprint,
+//! so readers know which schema a message was written with without a JSON
+//! detour or a registry lookup:
+//!
+//! ```
+//! use apache_avro::Schema;
+//! use intermodal::avro::{self, Envelope};
+//! use intermodal::{Manifest, Packet};
+//!
+//! let uptime = Schema::parse_str(r#"{
+//!     "type": "record", "name": "Uptime",
+//!     "fields": [{ "name": "seconds", "type": "long" }]
+//! }"#)?;
+//! let envelope = Envelope::new(&uptime)?;
+//!
+//! #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+//! struct Uptime {
+//!     seconds: i64,
+//! }
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .build()
+//!     .unwrap();
+//! let packet = Packet::new(manifest, Uptime { seconds: 86400 });
+//!
+//! let bytes = envelope.encode(&packet)?;
+//! assert_eq!(avro::fingerprint(&bytes)?, envelope.fingerprint());
+//! assert_eq!(envelope.decode::<Uptime>(&bytes)?, packet);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! A reader of several content types gathers their envelopes into
+//! [`Envelopes`], which decode each message with the schema its fingerprint
+//! names. The manifest's times are `timestamp-nanos`, so they survive the
+//! trip exactly, between the years 1677 and 2262. A packet's signature is
+//! carried; its provenance is not.
+//!
+//! Available with the `avro` feature.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+use apache_avro::rabin::Rabin;
+use apache_avro::reader::datum::GenericDatumReader;
+use apache_avro::types::Value;
+use apache_avro::writer::datum::GenericDatumWriter;
+use apache_avro::Schema;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::signing::Signature;
+use crate::trace::TraceContext;
+use crate::{Manifest, Packet, RawPacket};
+
+/// The bytes that begin every single-object encoding.
+pub const MARKER: [u8; 2] = [0xc3, 0x01];
+
+/// The Avro schema of a manifest, the record `intermodal.Manifest`.
+pub const MANIFEST_SCHEMA: &str = r#"{
+  "type": "record",
+  "name": "Manifest",
+  "namespace": "intermodal",
+  "fields": [
+    { "name": "domain", "type": "string" },
+    { "name": "scope", "type": "string" },
+    { "name": "kind", "type": "string" },
+    { "name": "version", "type": "long" },
+    { "name": "ctime", "type": { "type": "long", "logicalType": "timestamp-nanos" } },
+    {
+      "name": "expires",
+      "type": ["null", { "type": "long", "logicalType": "timestamp-nanos" }],
+      "default": null
+    },
+    { "name": "origin", "type": "string" },
+    { "name": "labels", "type": { "type": "map", "values": "string" }, "default": {} },
+    {
+      "name": "trace",
+      "type": [
+        "null",
+        {
+          "type": "record",
+          "name": "TraceContext",
+          "fields": [
+            { "name": "traceparent", "type": "string" },
+            { "name": "tracestate", "type": ["null", "string"], "default": null }
+          ]
+        }
+      ],
+      "default": null
+    },
+    { "name": "correlation_id", "type": ["null", "string"], "default": null },
+    { "name": "reply_to", "type": ["null", "string"], "default": null }
+  ]
+}"#;
+
+const SIGNATURE_SCHEMA: &str = r#"{
+  "type": "record",
+  "name": "Signature",
+  "fields": [
+    { "name": "algorithm", "type": "string" },
+    { "name": "key_id", "type": ["null", "string"], "default": null },
+    { "name": "value", "type": "string" }
+  ]
+}"#;
+
+/// The parsed [`MANIFEST_SCHEMA`].
+pub fn manifest_schema() -> Schema {
+    Schema::parse_str(MANIFEST_SCHEMA).expect("the manifest schema is valid")
+}
+
+/// The fingerprint in the header of a single-object encoding.
+pub fn fingerprint(bytes: &[u8]) -> Result<u64, Error> {
+    match bytes {
+        [0xc3, 0x01, fingerprint @ ..] if fingerprint.len() >= 8 => {
+            let mut le = [0; 8];
+            le.copy_from_slice(&fingerprint[..8]);
+            Ok(u64::from_le_bytes(le))
+        }
+        _ => Err(Error::Header),
+    }
+}
+
+/// The Avro schema of packets with content of one schema.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    schema: Schema,
+    fingerprint: u64,
+}
+
+impl Envelope {
+    /// The envelope of content with the `content` schema: the record
+    /// `intermodal.Envelope`, with fields `manifest`, `content` and
+    /// `signature`.
+    pub fn new(content: &Schema) -> Result<Self, Error> {
+        let parse = |schema| serde_json::from_str::<serde_json::Value>(schema).unwrap();
+        let schema = serde_json::json!({
+            "type": "record",
+            "name": "Envelope",
+            "namespace": "intermodal",
+            "fields": [
+                { "name": "manifest", "type": parse(MANIFEST_SCHEMA) },
+                { "name": "content", "type": content },
+                { "name": "signature", "type": ["null", parse(SIGNATURE_SCHEMA)], "default": null },
+            ],
+        });
+        let schema = Schema::parse(&schema)?;
+        let fingerprint = schema.fingerprint::<Rabin>().bytes;
+        let fingerprint = u64::from_le_bytes(
+            <[u8; 8]>::try_from(&fingerprint[..]).expect("Rabin fingerprints are 8 bytes"),
+        );
+        Ok(Envelope {
+            schema,
+            fingerprint,
+        })
+    }
+
+    /// The schema of the whole envelope, for registering with a schema
+    /// registry.
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The CRC-64-AVRO fingerprint of the schema, which heads each message
+    /// written with it. Its bytes there are little-endian.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Writes a packet in the single-object encoding.
+    pub fn encode<T: Serialize>(&self, packet: &Packet<T>) -> Result<Vec<u8>, Error> {
+        let content = apache_avro::to_value(&packet.content)?;
+        self.write(packet, content)
+    }
+
+    /// Writes a packet of JSON content, converted to the content schema.
+    pub fn encode_raw(&self, packet: &RawPacket) -> Result<Vec<u8>, Error> {
+        let content = Value::try_from(packet.content.clone())?;
+        self.write(packet, content)
+    }
+
+    /// Reads a packet written with this envelope's schema.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Packet<T>, Error> {
+        let (packet, content) = self.read(bytes)?;
+        let content = apache_avro::from_value(&content)?;
+        Ok(with_content(packet, content))
+    }
+
+    /// Reads a packet written with this envelope's schema, converting its
+    /// content to JSON.
+    pub fn decode_raw(&self, bytes: &[u8]) -> Result<RawPacket, Error> {
+        let (packet, content) = self.read(bytes)?;
+        let content = serde_json::Value::try_from(content)?;
+        Ok(with_content(packet, content))
+    }
+
+    fn write<T>(&self, packet: &Packet<T>, content: Value) -> Result<Vec<u8>, Error> {
+        let signature = match &packet.signature {
+            Some(signature) => Value::Record(vec![
+                ("algorithm".into(), signature.algorithm.clone().into()),
+                ("key_id".into(), signature.key_id.clone().into()),
+                ("value".into(), signature.value.clone().into()),
+            ]),
+            None => Value::Null,
+        };
+        let envelope = Value::Record(vec![
+            ("manifest".into(), manifest_value(&packet.manifest)?),
+            ("content".into(), content),
+            ("signature".into(), signature),
+        ])
+        .resolve(&self.schema)?;
+
+        let mut bytes = Vec::with_capacity(64);
+        bytes.extend_from_slice(&MARKER);
+        bytes.extend_from_slice(&self.fingerprint.to_le_bytes());
+        GenericDatumWriter::builder(&self.schema)
+            .build()?
+            .write_value(&mut bytes, envelope)?;
+        Ok(bytes)
+    }
+
+    /// Reads a message, returning its packet with the content taken out.
+    fn read(&self, bytes: &[u8]) -> Result<(Packet<()>, Value), Error> {
+        let found = fingerprint(bytes)?;
+        if found != self.fingerprint {
+            return Err(Error::Unknown(found));
+        }
+        let mut body = &bytes[MARKER.len() + 8..];
+        let value = GenericDatumReader::builder(&self.schema)
+            .build()?
+            .read_value(&mut body)?;
+        let mut fields = Fields::new(value, "envelope")?;
+        let manifest = manifest_from(fields.take("manifest")?)?;
+        let content = fields.take("content")?;
+        let signature = match fields.optional("signature")? {
+            Some(signature) => {
+                let mut fields = Fields::new(signature, "signature")?;
+                Some(Signature {
+                    algorithm: fields.string("algorithm")?,
+                    key_id: fields.optional_string("key_id")?,
+                    value: fields.string("value")?,
+                })
+            }
+            None => None,
+        };
+        let mut packet = Packet::new(manifest, ());
+        packet.signature = signature;
+        Ok((packet, content))
+    }
+}
+
+/// Envelopes by fingerprint, for reading messages of several content
+/// types.
+#[derive(Debug, Clone, Default)]
+pub struct Envelopes {
+    envelopes: BTreeMap<u64, Envelope>,
+}
+
+impl Envelopes {
+    pub fn new() -> Self {
+        Envelopes::default()
+    }
+
+    pub fn insert(&mut self, envelope: Envelope) -> &mut Self {
+        self.envelopes.insert(envelope.fingerprint, envelope);
+        self
+    }
+
+    pub fn get(&self, fingerprint: u64) -> Option<&Envelope> {
+        self.envelopes.get(&fingerprint)
+    }
+
+    /// The envelope a message was written with.
+    pub fn envelope(&self, bytes: &[u8]) -> Result<&Envelope, Error> {
+        let fingerprint = fingerprint(bytes)?;
+        self.get(fingerprint).ok_or(Error::Unknown(fingerprint))
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Packet<T>, Error> {
+        self.envelope(bytes)?.decode(bytes)
+    }
+
+    pub fn decode_raw(&self, bytes: &[u8]) -> Result<RawPacket, Error> {
+        self.envelope(bytes)?.decode_raw(bytes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.envelopes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envelopes.is_empty()
+    }
+}
+
+/// Puts the content back into a packet from [`Envelope::read`], keeping its
+/// signature, which [`Packet::map`] would drop.
+fn with_content<T>(packet: Packet<()>, content: T) -> Packet<T> {
+    let mut with = Packet::new(packet.manifest, content);
+    with.signature = packet.signature;
+    with
+}
+
+fn nanos(field: &'static str, time: DateTime<Utc>) -> Result<Value, Error> {
+    time.timestamp_nanos_opt()
+        .map(Value::TimestampNanos)
+        .ok_or(Error::Timestamp(field))
+}
+
+fn manifest_value(manifest: &Manifest) -> Result<Value, Error> {
+    let labels = manifest
+        .labels
+        .iter()
+        .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+        .collect();
+    let trace = match &manifest.trace {
+        Some(trace) => Value::Record(vec![
+            ("traceparent".into(), trace.traceparent.clone().into()),
+            ("tracestate".into(), trace.tracestate.clone().into()),
+        ]),
+        None => Value::Null,
+    };
+    let expires = match manifest.expires {
+        Some(expires) => nanos("expires", expires)?,
+        None => Value::Null,
+    };
+    Ok(Value::Record(vec![
+        ("domain".into(), manifest.domain.clone().into()),
+        ("scope".into(), manifest.scope.clone().into()),
+        ("kind".into(), manifest.kind.clone().into()),
+        ("version".into(), Value::Long(manifest.version.into())),
+        ("ctime".into(), nanos("ctime", manifest.ctime)?),
+        ("expires".into(), expires),
+        ("origin".into(), manifest.origin.clone().into()),
+        ("labels".into(), Value::Map(labels)),
+        ("trace".into(), trace),
+        (
+            "correlation_id".into(),
+            manifest.correlation_id.clone().into(),
+        ),
+        ("reply_to".into(), manifest.reply_to.clone().into()),
+    ]))
+}
+
+fn manifest_from(value: Value) -> Result<Manifest, Error> {
+    let mut fields = Fields::new(value, "manifest")?;
+    let labels = match fields.take("labels")? {
+        Value::Map(labels) => labels
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => Ok((key, value)),
+                _ => Err(Error::Shape("labels")),
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?,
+        _ => return Err(Error::Shape("labels")),
+    };
+    let trace = match fields.optional("trace")? {
+        Some(trace) => {
+            let mut fields = Fields::new(trace, "trace")?;
+            Some(TraceContext {
+                traceparent: fields.string("traceparent")?,
+                tracestate: fields.optional_string("tracestate")?,
+            })
+        }
+        None => None,
+    };
+    Ok(Manifest {
+        domain: fields.string("domain")?,
+        scope: fields.string("scope")?,
+        kind: fields.string("kind")?,
+        version: match fields.take("version")? {
+            Value::Long(version) => u32::try_from(version).map_err(|_| Error::Shape("version"))?,
+            _ => return Err(Error::Shape("version")),
+        },
+        ctime: time("ctime", fields.take("ctime")?)?,
+        expires: fields
+            .optional("expires")?
+            .map(|expires| time("expires", expires))
+            .transpose()?,
+        origin: fields.string("origin")?,
+        labels,
+        trace,
+        correlation_id: fields.optional_string("correlation_id")?,
+        reply_to: fields.optional_string("reply_to")?,
+    })
+}
+
+fn time(field: &'static str, value: Value) -> Result<DateTime<Utc>, Error> {
+    match value {
+        Value::TimestampNanos(nanos) => Ok(DateTime::from_timestamp_nanos(nanos)),
+        _ => Err(Error::Shape(field)),
+    }
+}
+
+/// The fields of a decoded record, taken out by name.
+struct Fields {
+    record: &'static str,
+    fields: HashMap<String, Value>,
+}
+
+impl Fields {
+    fn new(value: Value, record: &'static str) -> Result<Self, Error> {
+        match value {
+            Value::Record(fields) => Ok(Fields {
+                record,
+                fields: fields.into_iter().collect(),
+            }),
+            _ => Err(Error::Shape(record)),
+        }
+    }
+
+    fn take(&mut self, field: &str) -> Result<Value, Error> {
+        self.fields.remove(field).ok_or(Error::Shape(self.record))
+    }
+
+    fn optional(&mut self, field: &'static str) -> Result<Option<Value>, Error> {
+        match self.take(field)? {
+            Value::Union(_, value) => match *value {
+                Value::Null => Ok(None),
+                value => Ok(Some(value)),
+            },
+            _ => Err(Error::Shape(field)),
+        }
+    }
+
+    fn string(&mut self, field: &'static str) -> Result<String, Error> {
+        match self.take(field)? {
+            Value::String(value) => Ok(value),
+            _ => Err(Error::Shape(field)),
+        }
+    }
+
+    fn optional_string(&mut self, field: &'static str) -> Result<Option<String>, Error> {
+        match self.optional(field)? {
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(_) => Err(Error::Shape(field)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// An error encoding or decoding an Avro envelope.
+#[derive(Debug)]
+pub enum Error {
+    /// The schema is invalid, or a value does not fit it.
+    Avro(apache_avro::Error),
+    /// The input does not begin with a single-object header.
+    Header,
+    /// The input was written with the schema of this fingerprint, which
+    /// is not the one expected or is unknown.
+    Unknown(u64),
+    /// A time field is outside what `timestamp-nanos` holds.
+    Timestamp(&'static str),
+    /// A decoded field does not have the shape of the envelope schema.
+    Shape(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Avro(e) => e.fmt(f),
+            Error::Header => f.write_str("not an Avro single-object encoding"),
+            Error::Unknown(fingerprint) => {
+                write!(f, "unknown Avro schema fingerprint {:016x}", fingerprint)
+            }
+            Error::Timestamp(field) => write!(f, "`{}` is out of range", field),
+            Error::Shape(field) => write!(f, "`{}` does not fit the envelope schema", field),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Avro(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<apache_avro::Error> for Error {
+    fn from(e: apache_avro::Error) -> Self {
+        Error::Avro(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+
+    fn cpu_envelope() -> Envelope {
+        let cpu = Schema::parse_str(
+            r#"{
+                "type": "record", "name": "Cpu",
+                "fields": [
+                    { "name": "user", "type": "double" },
+                    { "name": "system", "type": "double" },
+                    { "name": "idle", "type": "double" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        Envelope::new(&cpu).unwrap()
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        let envelope = cpu_envelope();
+        let mut packet = fixtures::cpu_raw();
+        packet.manifest.ctime = Utc::now();
+        packet.manifest.expires = Some(packet.manifest.ctime + chrono::Duration::minutes(5));
+        packet.manifest.trace = Some(TraceContext {
+            traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string(),
+            tracestate: None,
+        });
+        packet.signature = Some(Signature {
+            algorithm: "ed25519".to_string(),
+            key_id: Some("k1".to_string()),
+            value: "00ff".to_string(),
+        });
+        let bytes = envelope.encode_raw(&packet).unwrap();
+        assert_eq!(bytes[..2], MARKER);
+        assert_eq!(fingerprint(&bytes).unwrap(), envelope.fingerprint());
+        assert_eq!(envelope.decode_raw(&bytes).unwrap(), packet);
+
+        let typed = envelope.decode::<Cpu>(&bytes).unwrap();
+        assert_eq!(typed.content.idle, 83.25);
+        assert_eq!(typed.signature, packet.signature);
+        let bytes = envelope.encode(&typed).unwrap();
+        assert_eq!(envelope.decode::<Cpu>(&bytes).unwrap(), typed);
+    }
+
+    #[test]
+    fn decodes_by_fingerprint() {
+        let cpu = cpu_envelope();
+        let netstat =
+            Envelope::new(&Schema::parse_str(r#"{ "type": "map", "values": "long" }"#).unwrap())
+                .unwrap();
+        assert_ne!(cpu.fingerprint(), netstat.fingerprint());
+
+        let mut envelopes = Envelopes::new();
+        envelopes.insert(cpu.clone());
+        let bytes = cpu.encode_raw(&fixtures::cpu_raw()).unwrap();
+        assert_eq!(envelopes.decode_raw(&bytes).unwrap(), fixtures::cpu_raw());
+        assert!(matches!(
+            netstat.decode_raw(&bytes),
+            Err(Error::Unknown(found)) if found == cpu.fingerprint()
+        ));
+
+        assert!(matches!(envelopes.decode_raw(b"{}"), Err(Error::Header)));
+        assert!(matches!(
+            cpu.encode_raw(&fixtures::netstat_raw()),
+            Err(Error::Avro(_))
+        ));
+    }
+
+    #[test]
+    fn describes_the_manifest() {
+        let schema = manifest_schema();
+        assert_eq!(schema.name().unwrap().fullname(None), "intermodal.Manifest");
+        let envelope = cpu_envelope();
+        assert_eq!(
+            envelope.schema().name().unwrap().fullname(None),
+            "intermodal.Envelope"
+        );
+    }
+}
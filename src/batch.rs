@@ -0,0 +1,221 @@
+//! Many packets carried in one envelope.
+//!
+//! A [`Batch`] holds packets under an outer manifest describing the batch as
+//! a whole, such as the collector that sent it, so that many small packets
+//! cost one message rather than one each:
+//!
+//! ```
+//! use intermodal::batch::Batch;
+//! use intermodal::{Format, Manifest, Packet};
+//! # let manifest = |kind: &str| Manifest::builder()
+//! #     .domain("example.org").scope("metrics/host").kind(kind).version(1)
+//! #     .ctime("2020-06-01T12:00:00Z".parse().unwrap()).origin("host01")
+//! #     .build().unwrap();
+//!
+//! let mut batch = Batch::new(manifest("batch"));
+//! batch.push(Packet::new(manifest("uptime"), 86400));
+//! batch.push(Packet::new(manifest("uptime"), 86460));
+//!
+//! let bytes = batch.to_bytes(Format::Json).unwrap();
+//! let (decoded, errors) = Batch::<u64>::from_bytes(&bytes, Format::Json).unwrap();
+//! assert!(errors.is_empty());
+//! assert_eq!(decoded, batch);
+//! ```
+//!
+//! A batch encodes as `{"manifest": ..., "packets": [...]}`, so that a
+//! [`Header`](crate::Header) decodes from it as from any other envelope.
+//! Decoding checks each packet on its own: one that does not decode is
+//! reported as an [`ItemError`] with its position, and the rest are kept.
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{decode, Format, Manifest, Packet};
+
+/// Packets carried under one manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Batch<T> {
+    /// Describes the batch as a whole.
+    pub manifest: Manifest,
+    pub packets: Vec<Packet<T>>,
+}
+
+/// A batch whose packets' content has not been decoded into a concrete
+/// type.
+pub type RawBatch = Batch<Value>;
+
+/// A packet in a batch that could not be decoded.
+#[derive(Debug)]
+pub struct ItemError {
+    /// The position of the packet in the batch.
+    pub index: usize,
+    pub error: serde_json::Error,
+}
+
+impl fmt::Display for ItemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "packet {}: {}", self.index, self.error)
+    }
+}
+
+impl std::error::Error for ItemError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A batch whose packets are decoded one at a time.
+#[derive(Deserialize)]
+struct Shell {
+    manifest: Manifest,
+    packets: Vec<Value>,
+}
+
+impl<T> Batch<T> {
+    /// An empty batch.
+    pub fn new(manifest: Manifest) -> Self {
+        Batch {
+            manifest,
+            packets: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, packet: Packet<T>) {
+        self.packets.push(packet);
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Moves the packets of `other` to the end of this batch, keeping this
+    /// batch's manifest.
+    pub fn merge(&mut self, other: Batch<T>) {
+        self.packets.extend(other.packets);
+    }
+
+    /// Splits the batch into batches of at most `max_len` packets each, in
+    /// order, each under a copy of the manifest. An empty batch splits into
+    /// none.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_len` is zero.
+    pub fn split(self, max_len: usize) -> Vec<Batch<T>> {
+        assert!(max_len > 0, "batches must hold at least one packet");
+        let Batch { manifest, packets } = self;
+        let mut batches = Vec::with_capacity(packets.len().div_ceil(max_len));
+        let mut packets = packets.into_iter().peekable();
+        while packets.peek().is_some() {
+            batches.push(Batch {
+                manifest: manifest.clone(),
+                packets: packets.by_ref().take(max_len).collect(),
+            });
+        }
+        batches
+    }
+}
+
+impl<T: Serialize> Batch<T> {
+    /// Serializes the batch to the given format.
+    pub fn to_bytes(&self, format: Format) -> Result<Vec<u8>, crate::encode::Error> {
+        crate::encode::to_vec(self, format)
+    }
+}
+
+impl<T: DeserializeOwned> Batch<T> {
+    /// Deserializes a batch from the given format, along with the packets
+    /// that could not be decoded, which are left out of it. Fails only if
+    /// the outer envelope does not decode.
+    pub fn from_bytes(
+        bytes: &[u8],
+        format: Format,
+    ) -> Result<(Self, Vec<ItemError>), decode::Error> {
+        let shell: Shell = decode::from_slice(bytes, format)?;
+        let mut batch = Batch::new(shell.manifest);
+        let mut errors = Vec::new();
+        for (index, packet) in shell.packets.into_iter().enumerate() {
+            match serde_json::from_value(packet) {
+                Ok(packet) => batch.push(packet),
+                Err(error) => errors.push(ItemError { index, error }),
+            }
+        }
+        Ok((batch, errors))
+    }
+}
+
+impl<T> IntoIterator for Batch<T> {
+    type Item = Packet<T>;
+    type IntoIter = std::vec::IntoIter<Packet<T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.packets.into_iter()
+    }
+}
+
+impl<T> Extend<Packet<T>> for Batch<T> {
+    fn extend<I: IntoIterator<Item = Packet<T>>>(&mut self, packets: I) {
+        self.packets.extend(packets);
+    }
+}
+
+impl<T> AsRef<Manifest> for Batch<T> {
+    fn as_ref(&self) -> &Manifest {
+        &self.manifest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+    use crate::Header;
+
+    #[test]
+    fn splits_and_merges() {
+        let mut batch = Batch::new(fixtures::cpu_manifest());
+        batch.extend((0..5).map(|_| fixtures::cpu_raw()));
+        let batches = batch.clone().split(2);
+        assert_eq!(
+            batches.iter().map(Batch::len).collect::<Vec<_>>(),
+            [2, 2, 1]
+        );
+        let mut merged = Batch::new(fixtures::cpu_manifest());
+        for part in batches {
+            merged.merge(part);
+        }
+        assert_eq!(merged, batch);
+        assert!(Batch::<Value>::new(fixtures::cpu_manifest())
+            .split(2)
+            .is_empty());
+    }
+
+    #[test]
+    fn reports_items() {
+        let mut batch = Batch::new(fixtures::netstat_manifest());
+        batch.push(fixtures::cpu_raw());
+        batch.push(fixtures::netstat_raw());
+        batch.push(fixtures::cpu_raw());
+        let bytes = batch.to_bytes(Format::Json).unwrap();
+
+        let header: Header = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(header.manifest, fixtures::netstat_manifest());
+
+        let (decoded, errors) = Batch::<Cpu>::from_bytes(&bytes, Format::Json).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded.packets[1].content.user, 12.5);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index, 1);
+        assert!(errors[0].to_string().starts_with("packet 1: "));
+
+        assert!(Batch::<Cpu>::from_bytes(b"[]", Format::Json).is_err());
+    }
+}
@@ -0,0 +1,442 @@
+//! Content-addressed storage for large content.
+//!
+//! Some content is too large to carry inline through every hop. With a
+//! [`BlobStore`], [`offload`] moves such content out of the envelope and
+//! replaces it with an external content reference:
+//!
+//! ```json
+//! { "$blob": { "digest": "sha256:9f86d0…", "size": 48213 } }
+//! ```
+//!
+//! and [`resolve`] swaps the content back in at the other end. Blobs are
+//! keyed by the SHA-256 digest of their bytes, and every fetch is verified
+//! against that digest, so a corrupted or substituted blob is reported
+//! rather than delivered.
+//!
+//! [`FsBlobStore`] keeps blobs in a local directory. With the `object-store`
+//! feature, [`ObjectStoreBlobStore`] keeps them in any store supported by the
+//! `object_store` crate.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+
+use crate::{hex, RawPacket};
+
+/// The content field naming an external content reference.
+pub const REFERENCE_FIELD: &str = "$blob";
+
+/// The SHA-256 digest of a blob, written `sha256:<hex>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest(String);
+
+impl Digest {
+    /// Computes the digest of `bytes`.
+    pub fn of(bytes: &[u8]) -> Self {
+        Digest(hex::encode(&Sha256::digest(bytes)))
+    }
+
+    /// Returns the hex-encoded digest, without the `sha256:` prefix.
+    pub fn hex(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sha256:{}", self.0)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("sha256:") {
+            Some(hex)
+                if hex.len() == 64
+                    && hex
+                        .bytes()
+                        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) =>
+            {
+                Ok(Digest(hex.to_string()))
+            }
+            _ => Err(Error::InvalidDigest(s.to_string())),
+        }
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Storage for blobs, keyed by the digest of their bytes.
+pub trait BlobStore: Send + Sync {
+    /// Stores `bytes`, returning their digest. Storing the same bytes again
+    /// is harmless.
+    fn put(&self, bytes: &[u8]) -> Result<Digest, Error>;
+
+    /// Fetches the blob with the given digest, without verifying it.
+    ///
+    /// Most callers want [`get`](BlobStore::get) instead.
+    fn fetch(&self, digest: &Digest) -> Result<Vec<u8>, Error>;
+
+    /// Whether a blob with the given digest is stored.
+    fn contains(&self, digest: &Digest) -> Result<bool, Error>;
+
+    /// Fetches the blob with the given digest, verifying that its bytes
+    /// match the digest.
+    fn get(&self, digest: &Digest) -> Result<Vec<u8>, Error> {
+        let bytes = self.fetch(digest)?;
+        let actual = Digest::of(&bytes);
+        if actual != *digest {
+            return Err(Error::Corrupt {
+                expected: digest.clone(),
+                actual,
+            });
+        }
+        Ok(bytes)
+    }
+}
+
+/// Blobs kept as files beneath a directory.
+///
+/// A blob is stored at `<root>/sha256/<first two hex digits>/<hex digest>`.
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Creates a store rooted at `root`, which is created when the first
+    /// blob is stored.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        FsBlobStore { root: root.into() }
+    }
+
+    fn path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join("sha256")
+            .join(&digest.hex()[..2])
+            .join(digest.hex())
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, bytes: &[u8]) -> Result<Digest, Error> {
+        let digest = Digest::of(bytes);
+        let path = self.path(&digest);
+        if path.exists() {
+            return Ok(digest);
+        }
+        let dir = path.parent().expect("blob paths have a parent");
+        fs::create_dir_all(dir)?;
+        // Write beside the final path and rename into place, so that readers
+        // never observe a partially written blob.
+        let partial = dir.join(format!(".{}.{}", digest.hex(), std::process::id()));
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, &path)?;
+        Ok(digest)
+    }
+
+    fn fetch(&self, digest: &Digest) -> Result<Vec<u8>, Error> {
+        fs::read(self.path(digest)).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::NotFound(digest.clone()),
+            _ => Error::Io(e),
+        })
+    }
+
+    fn contains(&self, digest: &Digest) -> Result<bool, Error> {
+        Ok(self.path(digest).exists())
+    }
+}
+
+#[cfg(feature = "object-store")]
+pub use self::object::ObjectStoreBlobStore;
+
+#[cfg(feature = "object-store")]
+mod object {
+    use std::sync::Arc;
+
+    use object_store::path::Path;
+    use object_store::{ObjectStore, ObjectStoreExt};
+    use tokio::runtime::Handle;
+
+    use super::{BlobStore, Digest, Error};
+
+    /// Blobs kept in an [`ObjectStore`], under `<prefix>/sha256/<hex digest>`.
+    ///
+    /// `object_store` is asynchronous, so each call blocks on the given
+    /// runtime. Calls must therefore be made from outside the runtime's
+    /// worker threads, for instance via `tokio::task::spawn_blocking`.
+    #[derive(Debug, Clone)]
+    pub struct ObjectStoreBlobStore {
+        store: Arc<dyn ObjectStore>,
+        prefix: Path,
+        runtime: Handle,
+    }
+
+    impl ObjectStoreBlobStore {
+        pub fn new(store: Arc<dyn ObjectStore>, runtime: Handle) -> Self {
+            ObjectStoreBlobStore {
+                store,
+                prefix: Path::default(),
+                runtime,
+            }
+        }
+
+        /// Keeps blobs beneath `prefix` rather than at the root of the store.
+        pub fn with_prefix<P: Into<Path>>(mut self, prefix: P) -> Self {
+            self.prefix = prefix.into();
+            self
+        }
+
+        fn path(&self, digest: &Digest) -> Path {
+            self.prefix.clone().join("sha256").join(digest.hex())
+        }
+    }
+
+    impl BlobStore for ObjectStoreBlobStore {
+        fn put(&self, bytes: &[u8]) -> Result<Digest, Error> {
+            let digest = Digest::of(bytes);
+            let path = self.path(&digest);
+            self.runtime
+                .block_on(self.store.put(&path, bytes.to_vec().into()))
+                .map_err(store_error)?;
+            Ok(digest)
+        }
+
+        fn fetch(&self, digest: &Digest) -> Result<Vec<u8>, Error> {
+            let path = self.path(digest);
+            self.runtime
+                .block_on(async { self.store.get(&path).await?.bytes().await })
+                .map(|bytes| bytes.to_vec())
+                .map_err(|e| match e {
+                    object_store::Error::NotFound { .. } => Error::NotFound(digest.clone()),
+                    e => store_error(e),
+                })
+        }
+
+        fn contains(&self, digest: &Digest) -> Result<bool, Error> {
+            match self.runtime.block_on(self.store.head(&self.path(digest))) {
+                Ok(_) => Ok(true),
+                Err(object_store::Error::NotFound { .. }) => Ok(false),
+                Err(e) => Err(store_error(e)),
+            }
+        }
+    }
+
+    fn store_error(e: object_store::Error) -> Error {
+        Error::Store(e.to_string())
+    }
+}
+
+/// An external content reference, standing in for content held in a
+/// [`BlobStore`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentRef {
+    /// The digest of the content's JSON encoding.
+    pub digest: Digest,
+    /// The length in bytes of the content's JSON encoding.
+    pub size: u64,
+}
+
+impl ContentRef {
+    /// Returns the reference held by `content`, if it is one.
+    pub fn from_content(content: &Value) -> Option<Self> {
+        match content {
+            Value::Object(fields) if fields.len() == 1 => fields
+                .get(REFERENCE_FIELD)
+                .and_then(|r| serde_json::from_value(r.clone()).ok()),
+            _ => None,
+        }
+    }
+
+    /// Returns content holding this reference.
+    pub fn to_content(&self) -> Value {
+        let mut fields = serde_json::Map::new();
+        fields.insert(
+            REFERENCE_FIELD.to_string(),
+            serde_json::to_value(self).expect("references serialize"),
+        );
+        Value::Object(fields)
+    }
+}
+
+/// Moves the packet's content into `store` if its JSON encoding is larger
+/// than `threshold` bytes, replacing it with a [`ContentRef`].
+///
+/// Smaller content, and content that is already a reference, is left in
+/// place.
+pub fn offload(
+    mut packet: RawPacket,
+    store: &dyn BlobStore,
+    threshold: usize,
+) -> Result<RawPacket, Error> {
+    if ContentRef::from_content(&packet.content).is_some() {
+        return Ok(packet);
+    }
+    let bytes = serde_json::to_vec(&packet.content).map_err(|e| Error::Decode(e.to_string()))?;
+    if bytes.len() <= threshold {
+        return Ok(packet);
+    }
+    let reference = ContentRef {
+        digest: store.put(&bytes)?,
+        size: bytes.len() as u64,
+    };
+    packet.content = reference.to_content();
+    Ok(packet)
+}
+
+/// Replaces a [`ContentRef`] in the packet's content with the content it
+/// refers to, verifying the fetched blob. Content that is not a reference is
+/// left in place.
+pub fn resolve(mut packet: RawPacket, store: &dyn BlobStore) -> Result<RawPacket, Error> {
+    if let Some(reference) = ContentRef::from_content(&packet.content) {
+        let bytes = store.get(&reference.digest)?;
+        packet.content =
+            serde_json::from_slice(&bytes).map_err(|e| Error::Decode(e.to_string()))?;
+    }
+    Ok(packet)
+}
+
+/// An error storing or fetching a blob.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The backing store failed.
+    Store(String),
+    /// No blob with the digest is stored.
+    NotFound(Digest),
+    /// A fetched blob did not match its digest.
+    Corrupt {
+        expected: Digest,
+        actual: Digest,
+    },
+    InvalidDigest(String),
+    /// Content could not be encoded, or a fetched blob could not be decoded.
+    Decode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Store(message) => write!(f, "blob store: {}", message),
+            Error::NotFound(digest) => write!(f, "blob {} not found", digest),
+            Error::Corrupt { expected, actual } => {
+                write!(f, "blob {} is corrupt: its digest is {}", expected, actual)
+            }
+            Error::InvalidDigest(s) => write!(f, "invalid digest `{}`", s),
+            Error::Decode(message) => write!(f, "blob content: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn temp_store(name: &str) -> FsBlobStore {
+        let root =
+            std::env::temp_dir().join(format!("intermodal-blob-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        FsBlobStore::new(root)
+    }
+
+    #[test]
+    fn digests() {
+        let digest = Digest::of(b"abc");
+        assert_eq!(
+            digest.to_string(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(digest.to_string().parse::<Digest>().unwrap(), digest);
+        assert!("sha256:xyz".parse::<Digest>().is_err());
+        assert!("md5:900150983cd24fb0d6963f7d28e17f72"
+            .parse::<Digest>()
+            .is_err());
+    }
+
+    #[test]
+    fn offloads_and_resolves() {
+        let store = temp_store("offload");
+        let packet = fixtures::netstat_raw();
+
+        let small = offload(packet.clone(), &store, 1 << 20).unwrap();
+        assert_eq!(small, packet);
+
+        let offloaded = offload(packet.clone(), &store, 16).unwrap();
+        let reference = ContentRef::from_content(&offloaded.content).unwrap();
+        assert_eq!(offloaded.manifest, packet.manifest);
+        assert!(store.contains(&reference.digest).unwrap());
+        assert_eq!(offload(offloaded.clone(), &store, 16).unwrap(), offloaded);
+
+        assert_eq!(resolve(offloaded, &store).unwrap(), packet);
+        assert_eq!(resolve(packet.clone(), &store).unwrap(), packet);
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let store = temp_store("corrupt");
+        let digest = store.put(b"{\"idle\":83.25}").unwrap();
+        fs::write(store.path(&digest), b"{\"idle\":0}").unwrap();
+        assert!(matches!(store.get(&digest), Err(Error::Corrupt { .. })));
+
+        let missing = Digest::of(b"missing");
+        assert!(matches!(store.get(&missing), Err(Error::NotFound(_))));
+    }
+
+    #[cfg(feature = "object-store")]
+    #[test]
+    fn object_store() {
+        use std::sync::Arc;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let store = ObjectStoreBlobStore::new(
+            Arc::new(object_store::memory::InMemory::new()),
+            runtime.handle().clone(),
+        )
+        .with_prefix("blobs");
+
+        let packet = fixtures::cpu_raw();
+        let offloaded = offload(packet.clone(), &store, 0).unwrap();
+        let reference = ContentRef::from_content(&offloaded.content).unwrap();
+        assert!(store.contains(&reference.digest).unwrap());
+        assert!(!store.contains(&Digest::of(b"missing")).unwrap());
+        assert_eq!(resolve(offloaded, &store).unwrap(), packet);
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::trace::TraceContext;
+use crate::{Manifest, Packet};
+
+/// Builds a [`Manifest`] field by field.
+///
+/// `ctime` defaults to the time the manifest is built, and `labels` to none.
+/// Every other field must be given before [`build`](ManifestBuilder::build):
+///
+/// ```
+/// use intermodal::Manifest;
+///
+/// let manifest = Manifest::builder()
+///     .domain("example.org")
+///     .scope("metrics/host")
+///     .kind("cpu")
+///     .version(1)
+///     .origin_hostname()
+///     .label("environment", "production")
+///     .build()
+///     .unwrap();
+/// assert_eq!(manifest.kind, "cpu");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ManifestBuilder {
+    domain: Option<String>,
+    scope: Option<String>,
+    kind: Option<String>,
+    version: Option<u32>,
+    ctime: Option<DateTime<Utc>>,
+    expires: Option<Expires>,
+    origin: Option<Origin>,
+    labels: HashMap<String, String>,
+    trace: Option<TraceContext>,
+    correlation_id: Option<String>,
+    reply_to: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Expires {
+    At(DateTime<Utc>),
+    After(Duration),
+}
+
+#[derive(Debug, Clone)]
+enum Origin {
+    Given(String),
+    Hostname,
+}
+
+impl Manifest {
+    pub fn builder() -> ManifestBuilder {
+        ManifestBuilder::default()
+    }
+}
+
+impl ManifestBuilder {
+    /// Takes the coordinates, labels, trace context and correlation ID of
+    /// an existing manifest, as for content derived from it. Its `ctime`,
+    /// `expires`, `origin` and `reply_to` belong to the original and are
+    /// not taken.
+    pub fn inherit(mut self, manifest: &Manifest) -> Self {
+        self.domain = Some(manifest.domain.clone());
+        self.scope = Some(manifest.scope.clone());
+        self.kind = Some(manifest.kind.clone());
+        self.version = Some(manifest.version);
+        self.labels = manifest.labels.clone();
+        self.trace = manifest.trace.clone();
+        self.correlation_id = manifest.correlation_id.clone();
+        self
+    }
+
+    pub fn domain<S: Into<String>>(mut self, domain: S) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn scope<S: Into<String>>(mut self, scope: S) -> Self {
+        self.scope = Some(scope.into());
+        self
+    }
+
+    pub fn kind<S: Into<String>>(mut self, kind: S) -> Self {
+        self.kind = Some(kind.into());
+        self
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Sets `ctime`, rather than taking the time of the build.
+    pub fn ctime(mut self, ctime: DateTime<Utc>) -> Self {
+        self.ctime = Some(ctime);
+        self
+    }
+
+    pub fn expires(mut self, expires: DateTime<Utc>) -> Self {
+        self.expires = Some(Expires::At(expires));
+        self
+    }
+
+    /// Sets `expires` to `ttl` after `ctime`.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.expires = Some(Expires::After(ttl));
+        self
+    }
+
+    pub fn origin<S: Into<String>>(mut self, origin: S) -> Self {
+        self.origin = Some(Origin::Given(origin.into()));
+        self
+    }
+
+    /// Sets `origin` to the local hostname, looked up at build time.
+    pub fn origin_hostname(mut self) -> Self {
+        self.origin = Some(Origin::Hostname);
+        self
+    }
+
+    /// Adds a label, replacing any earlier label with the same key.
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    pub fn correlation_id<S: Into<String>>(mut self, correlation_id: S) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    pub fn reply_to<S: Into<String>>(mut self, reply_to: S) -> Self {
+        self.reply_to = Some(reply_to.into());
+        self
+    }
+
+    /// Checks that every required field is present and non-empty, and builds
+    /// the manifest.
+    pub fn build(self) -> Result<Manifest, BuildError> {
+        let origin = match self.origin {
+            Some(Origin::Given(origin)) => Some(origin),
+            Some(Origin::Hostname) => match gethostname::gethostname().into_string() {
+                Ok(hostname) => Some(hostname),
+                Err(_) => return Err(BuildError::new("origin", "hostname is not valid UTF-8")),
+            },
+            None => None,
+        };
+        let ctime = self.ctime.unwrap_or_else(Utc::now);
+        Ok(Manifest {
+            domain: required("domain", self.domain)?,
+            scope: required("scope", self.scope)?,
+            kind: required("kind", self.kind)?,
+            version: self
+                .version
+                .ok_or_else(|| BuildError::new("version", "is required"))?,
+            ctime,
+            expires: self.expires.map(|expires| match expires {
+                Expires::At(expires) => expires,
+                Expires::After(ttl) => ctime + ttl,
+            }),
+            origin: required("origin", origin)?,
+            labels: self.labels,
+            trace: self.trace,
+            correlation_id: self.correlation_id,
+            reply_to: self.reply_to,
+        })
+    }
+}
+
+fn required(field: &'static str, value: Option<String>) -> Result<String, BuildError> {
+    match value {
+        Some(value) if !value.is_empty() => Ok(value),
+        Some(_) => Err(BuildError::new(field, "must not be empty")),
+        None => Err(BuildError::new(field, "is required")),
+    }
+}
+
+/// Builds a [`Packet`] from its manifest's fields and its content.
+///
+/// For a processor re-emitting transformed content, the manifest is best
+/// [inherited](PacketBuilder::inherit) from the one received, which keeps
+/// its coordinates and labels but takes a new `ctime` and the processor's
+/// own origin:
+///
+/// ```
+/// use intermodal::{Packet, RawPacket};
+/// # let received: RawPacket = serde_json::from_value(serde_json::json!({
+/// #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+/// #                   "kind": "uptime", "version": 1,
+/// #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+/// #     "content": 86400
+/// # })).unwrap();
+///
+/// let hours = received.content.as_u64().unwrap() / 3600;
+/// let packet = Packet::builder()
+///     .inherit(&received)
+///     .kind("uptime-hours")
+///     .origin("enricher@core-02")
+///     .content(hours)
+///     .unwrap();
+/// assert_eq!(packet.manifest.scope, "metrics/host");
+/// assert!(packet.manifest.ctime > received.manifest.ctime);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PacketBuilder {
+    manifest: ManifestBuilder,
+}
+
+impl Packet<()> {
+    pub fn builder() -> PacketBuilder {
+        PacketBuilder::default()
+    }
+}
+
+impl PacketBuilder {
+    /// Inherits the manifest of a header or packet, as by
+    /// [`ManifestBuilder::inherit`].
+    pub fn inherit<M: AsRef<Manifest>>(self, source: M) -> Self {
+        self.with(|manifest| manifest.inherit(source.as_ref()))
+    }
+
+    pub fn domain<S: Into<String>>(self, domain: S) -> Self {
+        self.with(|manifest| manifest.domain(domain))
+    }
+
+    pub fn scope<S: Into<String>>(self, scope: S) -> Self {
+        self.with(|manifest| manifest.scope(scope))
+    }
+
+    pub fn kind<S: Into<String>>(self, kind: S) -> Self {
+        self.with(|manifest| manifest.kind(kind))
+    }
+
+    pub fn version(self, version: u32) -> Self {
+        self.with(|manifest| manifest.version(version))
+    }
+
+    /// Sets `ctime`, rather than taking the time of the build.
+    pub fn ctime(self, ctime: DateTime<Utc>) -> Self {
+        self.with(|manifest| manifest.ctime(ctime))
+    }
+
+    /// Sets `expires` to `ttl` after `ctime`.
+    pub fn ttl(self, ttl: Duration) -> Self {
+        self.with(|manifest| manifest.ttl(ttl))
+    }
+
+    pub fn origin<S: Into<String>>(self, origin: S) -> Self {
+        self.with(|manifest| manifest.origin(origin))
+    }
+
+    /// Sets `origin` to the local hostname, looked up at build time.
+    pub fn origin_hostname(self) -> Self {
+        self.with(ManifestBuilder::origin_hostname)
+    }
+
+    /// Adds a label, replacing any earlier label with the same key.
+    pub fn label<K: Into<String>, V: Into<String>>(self, key: K, value: V) -> Self {
+        self.with(|manifest| manifest.label(key, value))
+    }
+
+    /// Builds the manifest, as by [`ManifestBuilder::build`], and pairs it
+    /// with `content`.
+    pub fn content<T>(self, content: T) -> Result<Packet<T>, BuildError> {
+        Ok(Packet::new(self.manifest.build()?, content))
+    }
+
+    fn with<F: FnOnce(ManifestBuilder) -> ManifestBuilder>(mut self, f: F) -> Self {
+        self.manifest = f(self.manifest);
+        self
+    }
+}
+
+/// An error building a [`Manifest`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildError {
+    field: &'static str,
+    message: String,
+}
+
+impl BuildError {
+    fn new<S: Into<String>>(field: &'static str, message: S) -> Self {
+        BuildError {
+            field,
+            message: message.into(),
+        }
+    }
+
+    /// The manifest field at fault.
+    pub fn field(&self) -> &str {
+        self.field
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "manifest `{}` {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn builds_manifests() {
+        let expected = fixtures::cpu_manifest();
+        let manifest = Manifest::builder()
+            .domain("example.org")
+            .scope("metrics/host")
+            .kind("cpu")
+            .version(1)
+            .ctime(expected.ctime)
+            .origin("host01.example.org")
+            .label("environment", "production")
+            .label("datacenter", "us-east")
+            .build()
+            .unwrap();
+        assert_eq!(manifest, expected);
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let ctime = fixtures::cpu_manifest().ctime;
+        let manifest = Manifest::builder()
+            .domain("example.org")
+            .scope("metrics/host")
+            .kind("cpu")
+            .version(1)
+            .ctime(ctime)
+            .ttl(Duration::minutes(5))
+            .origin("host01.example.org")
+            .build()
+            .unwrap();
+        assert_eq!(manifest.expires, Some(ctime + Duration::minutes(5)));
+        assert!(!manifest.is_expired(ctime + Duration::minutes(4)));
+        assert!(manifest.is_expired(ctime + Duration::minutes(5)));
+        assert!(!fixtures::cpu_manifest().is_expired(Utc::now()));
+    }
+
+    #[test]
+    fn defaults_and_validation() {
+        let before = Utc::now();
+        let manifest = Manifest::builder()
+            .domain("example.org")
+            .scope("metrics")
+            .kind("cpu")
+            .version(1)
+            .origin_hostname()
+            .build()
+            .unwrap();
+        assert!(manifest.ctime >= before);
+        assert!(!manifest.origin.is_empty());
+        assert!(manifest.labels.is_empty());
+
+        let err = Manifest::builder()
+            .domain("example.org")
+            .kind("cpu")
+            .version(1)
+            .origin("host01")
+            .build()
+            .unwrap_err();
+        assert_eq!(err.field(), "scope");
+        assert_eq!(err.to_string(), "manifest `scope` is required");
+
+        let err = Manifest::builder().domain("").build().unwrap_err();
+        assert_eq!(err.to_string(), "manifest `domain` must not be empty");
+    }
+
+    #[test]
+    fn inherits_manifests() {
+        let received = fixtures::cpu_raw();
+        let before = Utc::now();
+        let packet = Packet::builder()
+            .inherit(&received)
+            .version(2)
+            .origin("enricher")
+            .label("enriched", "true")
+            .content(received.content["idle"].clone())
+            .unwrap();
+        let manifest = &packet.manifest;
+        assert_eq!(manifest.coordinates().kind, "cpu");
+        assert_eq!(manifest.version, 2);
+        assert!(manifest.ctime >= before);
+        assert_eq!(manifest.origin, "enricher");
+        assert_eq!(manifest.labels["environment"], "production");
+        assert_eq!(manifest.labels["enriched"], "true");
+        assert_eq!(packet.content, 83.25);
+
+        let err = Packet::builder()
+            .inherit(&received)
+            .content(())
+            .unwrap_err();
+        assert_eq!(err.field(), "origin");
+    }
+}
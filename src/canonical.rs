@@ -0,0 +1,149 @@
+//! A deterministic encoding of envelopes, for hashing and signing.
+//!
+//! Encoding the same envelope twice with `serde_json` need not give the
+//! same bytes: labels are kept in a `HashMap`, content may hold maps in any
+//! order, and a timestamp decoded from `+00:00` re-encodes as `Z`. The
+//! canonical encoding is JSON with object keys sorted at every level, no
+//! whitespace, and `ctime` always written in UTC with nanoseconds, so that
+//! equal envelopes encode to equal bytes however they were received:
+//!
+//! ```
+//! # let packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "uptime", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": 86400
+//! # })).unwrap();
+//! let replayed: intermodal::RawPacket = serde_json::from_str(
+//!     r#"{ "content": 86400, "manifest": { "origin": "host01", "version": 1,
+//!          "ctime": "2020-06-01T14:00:00+02:00", "kind": "uptime",
+//!          "scope": "metrics/host", "domain": "example.org" } }"#,
+//! ).unwrap();
+//! assert_eq!(replayed.canonical_bytes()?, packet.canonical_bytes()?);
+//! # Ok::<(), serde_json::Error>(())
+//! ```
+//!
+//! With the `blob` feature, [`Packet::content_digest`] hashes the canonical
+//! encoding, for deduplicating envelopes by what they hold.
+
+use chrono::SecondsFormat;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{Manifest, Packet};
+
+/// Encodes a value as JSON with sorted object keys and no whitespace.
+pub fn to_vec<S: Serialize + ?Sized>(value: &S) -> Result<Vec<u8>, serde_json::Error> {
+    let value = serde_json::to_value(value)?;
+    let mut out = Vec::new();
+    write(&value, &mut out)?;
+    Ok(out)
+}
+
+fn write(value: &Value, out: &mut Vec<u8>) -> Result<(), serde_json::Error> {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            out.push(b'{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut *out, key)?;
+                out.push(b':');
+                write(value, out)?;
+            }
+            out.push(b'}');
+        }
+        Value::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write(item, out)?;
+            }
+            out.push(b']');
+        }
+        scalar => serde_json::to_writer(&mut *out, scalar)?,
+    }
+    Ok(())
+}
+
+/// The manifest as a JSON value, with `ctime` in its canonical form.
+fn manifest_value(manifest: &Manifest) -> Value {
+    let mut value = serde_json::to_value(manifest).expect("manifests encode as JSON");
+    value["ctime"] = manifest
+        .ctime
+        .to_rfc3339_opts(SecondsFormat::Nanos, true)
+        .into();
+    value
+}
+
+impl Manifest {
+    /// Encodes the manifest canonically.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        to_vec(&manifest_value(self)).expect("manifests encode as JSON")
+    }
+}
+
+impl<T: Serialize> Packet<T> {
+    /// Encodes the manifest and content canonically, as an object with
+    /// those two fields. The signature, if any, is left out.
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let mut packet = serde_json::Map::new();
+        packet.insert("manifest".to_string(), manifest_value(&self.manifest));
+        packet.insert("content".to_string(), serde_json::to_value(&self.content)?);
+        to_vec(&packet)
+    }
+
+    /// The SHA-256 digest of the [canonical encoding](Packet::canonical_bytes).
+    /// Envelopes with equal manifests and content have equal digests.
+    #[cfg(feature = "blob")]
+    pub fn content_digest(&self) -> Result<crate::blob::Digest, serde_json::Error> {
+        self.canonical_bytes()
+            .map(|bytes| crate::blob::Digest::of(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::json;
+
+    #[test]
+    fn sorts_keys_without_whitespace() {
+        let value = json!({ "b": [1, { "d": null, "c": "x" }], "a": 2.5 });
+        assert_eq!(
+            to_vec(&value).unwrap(),
+            br#"{"a":2.5,"b":[1,{"c":"x","d":null}]}"#
+        );
+    }
+
+    #[test]
+    fn encodes_envelopes() {
+        let mut packet = fixtures::netstat_raw();
+        packet.content = json!({ "b": 1, "a": 2 });
+        let bytes = String::from_utf8(packet.manifest.canonical_bytes()).unwrap();
+        assert!(bytes.starts_with(r#"{"ctime":"2020-06-01T12:00:05.000000000Z","domain":"#));
+
+        let bytes = String::from_utf8(packet.canonical_bytes().unwrap()).unwrap();
+        assert!(bytes.starts_with(r#"{"content":{"a":2,"b":1},"manifest":{"ctime""#));
+    }
+
+    #[cfg(feature = "blob")]
+    #[test]
+    fn digests_equal_envelopes_equally() {
+        let packet = fixtures::cpu_raw();
+        let mut relabelled = packet.clone();
+        let digest = packet.content_digest().unwrap();
+        assert_eq!(relabelled.content_digest().unwrap(), digest);
+        relabelled
+            .manifest
+            .labels
+            .insert("hop".to_string(), "1".to_string());
+        assert_ne!(relabelled.content_digest().unwrap(), digest);
+    }
+}
@@ -0,0 +1,240 @@
+//! Guarding downstream stores against label explosions.
+//!
+//! Metric stores keep one series per distinct set of label values, so a
+//! producer that puts a request id or a timestamp in a label can overwhelm
+//! them. A [`CardinalityGuard`] counts the distinct values seen for each
+//! label key of each kind (within a domain and scope) over a sliding window.
+//! Once a key has reached its limit, packets bringing yet another value for
+//! it are dropped, or flagged, while values already seen keep passing:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use intermodal::cardinality::{Action, CardinalityGuard};
+//!
+//! let guard = CardinalityGuard::new(100, Duration::from_secs(3600))
+//!     .limit("request_id", 0)
+//!     .action(Action::Flag);
+//! ```
+//!
+//! The guard is a [`Transform`], so it can sit in front of a sink in a
+//! router's transform chain.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::transform::{self, Transform};
+use crate::{labels, Manifest, RawPacket};
+
+/// The label [`Action::Flag`] sets, listing the keys that exceeded their
+/// limits, comma-separated.
+pub const FLAG_LABEL: &str = labels::CARDINALITY_EXCEEDED;
+
+/// What to do with a packet bringing a label value past its key's limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    /// Pass the packet on with [`FLAG_LABEL`] set.
+    Flag,
+    /// Pass the packet on without the offending labels.
+    Strip,
+}
+
+/// Limits the distinct values of each label key per kind.
+#[derive(Debug)]
+pub struct CardinalityGuard {
+    default_limit: usize,
+    limits: HashMap<String, usize>,
+    window: Duration,
+    action: Action,
+    seen: Mutex<HashMap<Series, Values>>,
+}
+
+/// A label key of one kind.
+type Series = (String, String, String, String);
+
+/// The values seen for a label key, with when each was last seen.
+type Values = HashMap<String, Instant>;
+
+impl CardinalityGuard {
+    /// Allows `limit` distinct values per label key within any `window`,
+    /// dropping packets beyond that.
+    pub fn new(limit: usize, window: Duration) -> Self {
+        CardinalityGuard {
+            default_limit: limit,
+            limits: HashMap::new(),
+            window,
+            action: Action::Drop,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the limit for one label key, in place of the default.
+    pub fn limit<S: Into<String>>(mut self, key: S, limit: usize) -> Self {
+        self.limits.insert(key.into(), limit);
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Records the manifest's label values, returning the keys, in order,
+    /// whose values would exceed their limits. Those values are not
+    /// recorded.
+    pub fn observe(&self, manifest: &Manifest) -> Vec<String> {
+        self.observe_at(manifest, Instant::now())
+    }
+
+    fn observe_at(&self, manifest: &Manifest, now: Instant) -> Vec<String> {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        let mut exceeded = Vec::new();
+        for (key, value) in &manifest.labels {
+            let limit = self.limits.get(key).copied().unwrap_or(self.default_limit);
+            let series = (
+                manifest.domain.clone(),
+                manifest.scope.clone(),
+                manifest.kind.clone(),
+                key.clone(),
+            );
+            let values = seen.entry(series).or_default();
+            if let Some(last_seen) = values.get_mut(value) {
+                *last_seen = now;
+                continue;
+            }
+            if values.len() >= limit {
+                // Values that have aged out of the window no longer count.
+                // Pruning only here keeps known values cheap to refresh.
+                let window = self.window;
+                values.retain(|_, last_seen| now.duration_since(*last_seen) < window);
+                if values.len() >= limit {
+                    exceeded.push(key.clone());
+                    continue;
+                }
+            }
+            values.insert(value.clone(), now);
+        }
+        exceeded.sort_unstable();
+        exceeded
+    }
+
+    /// The number of distinct values recorded for a label key of a kind,
+    /// including any that have aged out but not yet been pruned.
+    pub fn distinct(&self, domain: &str, scope: &str, kind: &str, key: &str) -> usize {
+        let seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        let series = (
+            domain.to_string(),
+            scope.to_string(),
+            kind.to_string(),
+            key.to_string(),
+        );
+        seen.get(&series).map_or(0, HashMap::len)
+    }
+
+    fn apply_at(&self, mut packet: RawPacket, now: Instant) -> Option<RawPacket> {
+        let exceeded = self.observe_at(&packet.manifest, now);
+        if exceeded.is_empty() {
+            return Some(packet);
+        }
+        match self.action {
+            Action::Drop => return None,
+            Action::Flag => {
+                packet
+                    .manifest
+                    .labels
+                    .insert(FLAG_LABEL.to_string(), exceeded.join(","));
+            }
+            Action::Strip => {
+                for key in &exceeded {
+                    packet.manifest.labels.remove(key);
+                }
+            }
+        }
+        Some(packet)
+    }
+}
+
+impl Transform for CardinalityGuard {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        Ok(self.apply_at(packet, Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn with_request(id: &str) -> RawPacket {
+        let mut packet = fixtures::cpu_raw();
+        packet.manifest.labels.insert("request".into(), id.into());
+        packet
+    }
+
+    #[test]
+    fn drops_beyond_the_limit() {
+        let guard = CardinalityGuard::new(10, Duration::from_secs(60)).limit("request", 2);
+        let now = Instant::now();
+        assert!(guard.apply_at(with_request("a"), now).is_some());
+        assert!(guard.apply_at(with_request("b"), now).is_some());
+        assert!(guard.apply_at(with_request("c"), now).is_none());
+        assert!(guard.apply_at(with_request("a"), now).is_some());
+        assert_eq!(
+            guard.distinct("example.org", "metrics/host", "cpu", "request"),
+            2
+        );
+        assert_eq!(
+            guard.distinct("example.org", "metrics/host", "cpu", "environment"),
+            1
+        );
+
+        // Other kinds count separately.
+        let mut netstat = fixtures::netstat_raw();
+        netstat.manifest.labels.insert("request".into(), "c".into());
+        assert!(guard.apply_at(netstat, now).is_some());
+    }
+
+    #[test]
+    fn window_slides() {
+        let guard = CardinalityGuard::new(1, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(guard
+            .observe_at(&with_request("a").manifest, start)
+            .is_empty());
+        assert_eq!(
+            guard.observe_at(&with_request("b").manifest, start + Duration::from_secs(30)),
+            vec!["request".to_string()]
+        );
+        // `a` was refreshed at 40s, so still holds the slot at 90s.
+        guard.observe_at(&with_request("a").manifest, start + Duration::from_secs(40));
+        assert_eq!(
+            guard
+                .observe_at(&with_request("b").manifest, start + Duration::from_secs(90))
+                .len(),
+            1
+        );
+        assert!(guard
+            .observe_at(
+                &with_request("b").manifest,
+                start + Duration::from_secs(101)
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn flags_and_strips() {
+        let now = Instant::now();
+        let flag = CardinalityGuard::new(0, Duration::from_secs(60))
+            .limit("environment", 5)
+            .limit("datacenter", 5)
+            .action(Action::Flag);
+        let flagged = flag.apply_at(with_request("a"), now).unwrap();
+        assert_eq!(flagged.manifest.labels[FLAG_LABEL], "request");
+
+        let strip = CardinalityGuard::new(0, Duration::from_secs(60)).action(Action::Strip);
+        let stripped = strip.apply_at(with_request("a"), now).unwrap();
+        assert!(stripped.manifest.labels.is_empty());
+    }
+}
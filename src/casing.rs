@@ -0,0 +1,150 @@
+//! Alternative casing of manifest field names on the wire.
+//!
+//! Manifests are serialized with `snake_case` field names. Some JSON tooling
+//! insists on `camelCase` or `kebab-case` instead, so [`to_vec`] can rename
+//! manifest fields on the way out, and [`from_slice`] accepts any of the
+//! three on the way in.
+//!
+//! Only manifest field names are renamed. Label keys, and everything within
+//! the content, are left exactly as they are.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// A naming convention for manifest fields.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Casing {
+    /// `correlation_id`, the default.
+    #[default]
+    Snake,
+    /// `correlationId`.
+    Camel,
+    /// `correlation-id`.
+    Kebab,
+}
+
+impl Casing {
+    /// Converts a `snake_case` field name to this casing.
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            Casing::Snake => name.to_string(),
+            Casing::Kebab => name.replace('_', "-"),
+            Casing::Camel => {
+                let mut out = String::with_capacity(name.len());
+                let mut upper = false;
+                for c in name.chars() {
+                    if c == '_' {
+                        upper = true;
+                    } else if upper {
+                        out.extend(c.to_uppercase());
+                        upper = false;
+                    } else {
+                        out.push(c);
+                    }
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Converts a field name in any supported casing to `snake_case`.
+pub fn normalize(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c == '-' {
+            out.push('_');
+        } else if c.is_uppercase() {
+            out.push('_');
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Serializes an envelope as JSON, naming manifest fields in the given casing.
+pub fn to_vec<S: Serialize>(envelope: &S, casing: Casing) -> serde_json::Result<Vec<u8>> {
+    let mut value = serde_json::to_value(envelope)?;
+    if casing != Casing::Snake {
+        rename_manifest(&mut value, &|name| casing.apply(name));
+    }
+    serde_json::to_vec(&value)
+}
+
+/// Deserializes a JSON envelope whose manifest fields may use any supported
+/// casing.
+pub fn from_slice<D: DeserializeOwned>(bytes: &[u8]) -> serde_json::Result<D> {
+    let mut value: Value = serde_json::from_slice(bytes)?;
+    rename_manifest(&mut value, &normalize);
+    serde_json::from_value(value)
+}
+
+fn rename_manifest(envelope: &mut Value, rename: &dyn Fn(&str) -> String) {
+    if let Some(manifest) = envelope.get_mut("manifest") {
+        rename_fields(manifest, rename);
+    }
+}
+
+fn rename_fields(value: &mut Value, rename: &dyn Fn(&str) -> String) {
+    match value {
+        Value::Object(fields) => {
+            let old = std::mem::replace(fields, Map::new());
+            for (name, mut field) in old {
+                // Label keys are user data, not field names.
+                if name != "labels" {
+                    rename_fields(&mut field, rename);
+                }
+                fields.insert(rename(&name), field);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rename_fields(item, rename);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::RawPacket;
+
+    #[test]
+    fn converts_names() {
+        assert_eq!(Casing::Camel.apply("correlation_id"), "correlationId");
+        assert_eq!(Casing::Kebab.apply("reply_to"), "reply-to");
+        assert_eq!(Casing::Snake.apply("reply_to"), "reply_to");
+        assert_eq!(normalize("correlationId"), "correlation_id");
+        assert_eq!(normalize("reply-to"), "reply_to");
+        assert_eq!(normalize("ctime"), "ctime");
+    }
+
+    #[test]
+    fn renames_only_manifest_fields() {
+        let mut value = serde_json::json!({
+            "manifest": { "trace_state": { "span_id": 1 }, "labels": { "some_key": "x" } },
+            "content": { "inner_field": 1 }
+        });
+        rename_manifest(&mut value, &|name| Casing::Camel.apply(name));
+        assert_eq!(value["manifest"]["traceState"]["spanId"], 1);
+        assert_eq!(value["manifest"]["labels"]["some_key"], "x");
+        assert_eq!(value["content"]["inner_field"], 1);
+    }
+
+    #[test]
+    fn round_trips() {
+        let packet = fixtures::netstat_raw();
+        for casing in &[Casing::Snake, Casing::Camel, Casing::Kebab] {
+            let bytes = to_vec(&packet, *casing).unwrap();
+            let decoded: RawPacket = from_slice(&bytes).unwrap();
+            assert_eq!(decoded.manifest.labels, packet.manifest.labels);
+            assert_eq!(decoded.content, packet.content);
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! Documenting registered kinds for a data catalog.
+//!
+//! A [`Catalog`] gathers what is known of each kind: its coordinates, the
+//! schema registered for it, and example packets, such as the fixtures a
+//! producer tests with. It renders them as JSON for machines, and as
+//! Markdown or HTML for people, so that a catalog fed from it stays in step
+//! with the registry:
+//!
+//! ```
+//! use intermodal::catalog::Catalog;
+//! use intermodal::schema::{MemorySchemaRegistry, SchemaRegistry};
+//! use intermodal::Coordinates;
+//! use serde_json::json;
+//!
+//! let registry = MemorySchemaRegistry::new();
+//! registry.register_schema(
+//!     Coordinates::new("example.org", "metrics/host", "uptime", 1),
+//!     json!({
+//!         "description": "Seconds since boot.",
+//!         "type": "integer",
+//!         "minimum": 0
+//!     }),
+//! )?;
+//!
+//! let catalog = Catalog::from_registry(&registry);
+//! let json = catalog.to_json();
+//! assert_eq!(json["kinds"][0]["coordinates"], "example.org/metrics/host/uptime@1");
+//! assert!(catalog.to_markdown().contains("Seconds since boot."));
+//! # Ok::<(), intermodal::schema::Error>(())
+//! ```
+//!
+//! [`Catalog::examples_from_dir`] reads examples from a directory of
+//! envelopes: `.json` files holding one each, and `.ndjson` files holding
+//! one a line. An example's content is shown, whatever its manifest holds
+//! besides its coordinates. Kinds with examples but no schema are listed
+//! too, as unregistered. A kind is described by its schema's `title` and
+//! `description`, and the properties of object schemas are tabulated.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::schema::MemorySchemaRegistry;
+use crate::{Coordinates, RawPacket};
+
+/// What is known of one kind.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry {
+    pub schema: Option<Value>,
+    pub examples: Vec<Value>,
+}
+
+/// Documentation of kinds, by their coordinates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Catalog {
+    kinds: BTreeMap<Coordinates, Entry>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Catalog::default()
+    }
+
+    /// A catalog of every schema in `registry`.
+    pub fn from_registry(registry: &MemorySchemaRegistry) -> Self {
+        let mut catalog = Catalog::new();
+        for (coordinates, schema) in registry.schemas() {
+            catalog.schema(coordinates, schema);
+        }
+        catalog
+    }
+
+    /// Documents the schema of a kind.
+    pub fn schema(&mut self, coordinates: Coordinates, schema: Value) -> &mut Self {
+        self.kinds.entry(coordinates).or_default().schema = Some(schema);
+        self
+    }
+
+    /// Adds a packet's content as an example of its kind.
+    pub fn example(&mut self, packet: RawPacket) -> &mut Self {
+        let coordinates = packet.manifest.coordinates();
+        self.kinds
+            .entry(coordinates)
+            .or_default()
+            .examples
+            .push(packet.content);
+        self
+    }
+
+    /// Adds every envelope in the `.json` and `.ndjson` files of `dir` as
+    /// examples, in order of file name, returning how many were added.
+    pub fn examples_from_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<usize, Error> {
+        let dir = dir.as_ref();
+        let io = |path: &Path| {
+            let path = path.to_path_buf();
+            move |e| Error::Io(path, e)
+        };
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).map_err(io(dir))? {
+            paths.push(entry.map_err(io(dir))?.path());
+        }
+        paths.sort();
+
+        let mut added = 0;
+        for path in paths {
+            let ndjson = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => false,
+                Some("ndjson") => true,
+                _ => continue,
+            };
+            let text = fs::read_to_string(&path).map_err(io(&path))?;
+            let decode = |text: &str| {
+                serde_json::from_str::<RawPacket>(text).map_err(|e| Error::Decode(path.clone(), e))
+            };
+            let packets = if ndjson {
+                text.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(decode)
+                    .collect::<Result<Vec<_>, _>>()?
+            } else {
+                vec![decode(&text)?]
+            };
+            added += packets.len();
+            for packet in packets {
+                self.example(packet);
+            }
+        }
+        Ok(added)
+    }
+
+    /// The kinds documented, in order of coordinates.
+    pub fn kinds(&self) -> impl Iterator<Item = (&Coordinates, &Entry)> {
+        self.kinds.iter()
+    }
+
+    /// The catalog as JSON: an object whose `kinds` array holds, for each
+    /// kind, its `coordinates` in canonical form, each part of them, its
+    /// `title` and `description`, its `schema`, or null if it has none, and
+    /// its `examples`.
+    pub fn to_json(&self) -> Value {
+        let kinds: Vec<_> = self
+            .kinds
+            .iter()
+            .map(|(coordinates, entry)| {
+                json!({
+                    "coordinates": coordinates.to_string(),
+                    "domain": coordinates.domain,
+                    "scope": coordinates.scope,
+                    "kind": coordinates.kind,
+                    "version": coordinates.version,
+                    "title": entry.text("title"),
+                    "description": entry.text("description"),
+                    "schema": entry.schema,
+                    "examples": entry.examples,
+                })
+            })
+            .collect();
+        json!({ "kinds": kinds })
+    }
+
+    /// The catalog as a Markdown document, a section to each kind.
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::from("# Kinds\n");
+        for (coordinates, entry) in &self.kinds {
+            let _ = write!(out, "\n## `{}`\n\n", coordinates);
+            if let Some(title) = entry.text("title") {
+                let _ = writeln!(out, "**{}**\n", title);
+            }
+            match (&entry.schema, entry.text("description")) {
+                (None, _) => out.push_str("_No schema is registered._\n"),
+                (Some(_), Some(description)) => {
+                    let _ = writeln!(out, "{}", description);
+                }
+                (Some(_), None) => {}
+            }
+            let properties = entry.properties();
+            if !properties.is_empty() {
+                out.push_str("\n| Property | Type | Required | Description |\n|---|---|---|---|\n");
+                for property in properties {
+                    let _ = writeln!(
+                        out,
+                        "| `{}` | {} | {} | {} |",
+                        property.name,
+                        property.types,
+                        if property.required { "yes" } else { "no" },
+                        property.description.replace('|', "\\|").replace('\n', " ")
+                    );
+                }
+            }
+            if let Some(schema) = &entry.schema {
+                let _ = write!(out, "\n### Schema\n\n```json\n{}\n```\n", pretty(schema));
+            }
+            if !entry.examples.is_empty() {
+                out.push_str("\n### Examples\n");
+                for example in &entry.examples {
+                    let _ = write!(out, "\n```json\n{}\n```\n", pretty(example));
+                }
+            }
+        }
+        out
+    }
+
+    /// The catalog as an HTML fragment, for embedding in a page.
+    pub fn to_html(&self) -> String {
+        let mut out = String::from("<section class=\"intermodal-catalog\">\n<h1>Kinds</h1>\n");
+        for (coordinates, entry) in &self.kinds {
+            let id = coordinates.to_string();
+            let _ = writeln!(
+                out,
+                "<article id=\"{}\">\n<h2><code>{}</code></h2>",
+                escape(&id),
+                escape(&id)
+            );
+            if let Some(title) = entry.text("title") {
+                let _ = writeln!(out, "<p><strong>{}</strong></p>", escape(title));
+            }
+            match (&entry.schema, entry.text("description")) {
+                (None, _) => out.push_str("<p><em>No schema is registered.</em></p>\n"),
+                (Some(_), Some(description)) => {
+                    let _ = writeln!(out, "<p>{}</p>", escape(description));
+                }
+                (Some(_), None) => {}
+            }
+            let properties = entry.properties();
+            if !properties.is_empty() {
+                out.push_str("<table>\n<tr><th>Property</th><th>Type</th><th>Required</th><th>Description</th></tr>\n");
+                for property in properties {
+                    let _ = writeln!(
+                        out,
+                        "<tr><td><code>{}</code></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                        escape(&property.name),
+                        escape(&property.types),
+                        if property.required { "yes" } else { "no" },
+                        escape(&property.description)
+                    );
+                }
+                out.push_str("</table>\n");
+            }
+            if let Some(schema) = &entry.schema {
+                let _ = writeln!(
+                    out,
+                    "<h3>Schema</h3>\n<pre><code>{}</code></pre>",
+                    escape(&pretty(schema))
+                );
+            }
+            if !entry.examples.is_empty() {
+                out.push_str("<h3>Examples</h3>\n");
+                for example in &entry.examples {
+                    let _ = writeln!(out, "<pre><code>{}</code></pre>", escape(&pretty(example)));
+                }
+            }
+            out.push_str("</article>\n");
+        }
+        out.push_str("</section>\n");
+        out
+    }
+}
+
+/// A property of an object schema, as tabulated.
+struct Property {
+    name: String,
+    types: String,
+    required: bool,
+    description: String,
+}
+
+impl Entry {
+    /// A string field of the schema.
+    fn text(&self, field: &str) -> Option<&str> {
+        self.schema.as_ref()?.get(field)?.as_str()
+    }
+
+    fn properties(&self) -> Vec<Property> {
+        let schema = match &self.schema {
+            Some(schema) => schema,
+            None => return Vec::new(),
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let properties = match schema.get("properties").and_then(Value::as_object) {
+            Some(properties) => properties,
+            None => return Vec::new(),
+        };
+        let mut names: Vec<_> = properties.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let property = &properties[name];
+                let types = match property.get("type") {
+                    Some(Value::String(ty)) => ty.clone(),
+                    Some(Value::Array(types)) => types
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join(" or "),
+                    _ => "any".to_string(),
+                };
+                Property {
+                    name: name.clone(),
+                    types,
+                    required: required.contains(&name.as_str()),
+                    description: property
+                        .get("description")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+fn pretty(value: &Value) -> String {
+    serde_json::to_string_pretty(value).expect("JSON values encode")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// An error reading examples.
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    /// A file holds something other than envelopes.
+    Decode(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "cannot read `{}`: {}", path.display(), e),
+            Error::Decode(path, e) => {
+                write!(f, "`{}` does not hold envelopes: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            Error::Decode(_, e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::schema::SchemaRegistry;
+
+    fn catalog() -> Catalog {
+        let registry = MemorySchemaRegistry::new();
+        registry
+            .register_schema(
+                fixtures::cpu_manifest().coordinates(),
+                json!({
+                    "title": "CPU <time>",
+                    "description": "Shares of CPU time, in percent.",
+                    "type": "object",
+                    "required": ["idle"],
+                    "properties": {
+                        "user": { "type": ["number", "null"], "description": "In user | space." },
+                        "idle": { "type": "number" }
+                    }
+                }),
+            )
+            .unwrap();
+        let mut catalog = Catalog::from_registry(&registry);
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        assert_eq!(catalog.examples_from_dir(dir).unwrap(), 2);
+        catalog
+    }
+
+    #[test]
+    fn renders_json() {
+        let json = catalog().to_json();
+        let kinds = json["kinds"].as_array().unwrap();
+        assert_eq!(kinds.len(), 2);
+        assert_eq!(kinds[0]["coordinates"], "example.org/metrics/host/cpu@1");
+        assert_eq!(kinds[0]["title"], "CPU <time>");
+        assert_eq!(kinds[0]["examples"][0]["idle"], 83.25);
+        assert_eq!(kinds[1]["kind"], "netstat");
+        assert_eq!(kinds[1]["schema"], Value::Null);
+        assert_eq!(kinds[1]["examples"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn renders_documents() {
+        let catalog = catalog();
+        let markdown = catalog.to_markdown();
+        assert!(markdown.starts_with("# Kinds\n\n## `example.org/metrics/host/cpu@1`\n"));
+        assert!(markdown.contains("| `idle` | number | yes |  |\n"));
+        assert!(markdown.contains("| `user` | number or null | no | In user \\| space. |\n"));
+        assert!(markdown.contains("\"idle\": 83.25"));
+        assert!(markdown.contains("_No schema is registered._"));
+
+        let html = catalog.to_html();
+        assert!(html.contains("<p><strong>CPU &lt;time&gt;</strong></p>"));
+        assert!(html.contains("<article id=\"example.org/metrics/host/netstat@1\">"));
+        assert!(html.contains("<td><code>idle</code></td><td>number</td><td>yes</td>"));
+        assert!(html.ends_with("</article>\n</section>\n"));
+    }
+
+    #[test]
+    fn reports_bad_examples() {
+        let dir = std::env::temp_dir().join(format!("catalog-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.ndjson"),
+            format!(
+                "{}\n\n{}\n",
+                serde_json::to_string(&fixtures::cpu_raw()).unwrap(),
+                "{}"
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        let result = Catalog::new().examples_from_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(Error::Decode(path, _)) => assert!(path.ends_with("a.ndjson")),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Counting which kinds of content are in use.
+//!
+//! A [`Census`] tallies envelopes by their [`Coordinates`]: how many were
+//! seen, how many bytes they took, and the earliest and latest creation
+//! times among them. It reads archives of newline-delimited JSON with
+//! [`scan`](Census::scan), decoding only each envelope's manifest, or counts
+//! live traffic as a [`Transform`]. Checked against a schema registry, it
+//! shows which kinds still have producers and which have no schema, which
+//! is what deciding to deprecate a version takes:
+//!
+//! ```
+//! use intermodal::census::Census;
+//! use intermodal::schema::MemorySchemaRegistry;
+//!
+//! let archive = br#"{"manifest":{"domain":"example.org","scope":"metrics/host","kind":"uptime","version":1,"ctime":"2020-06-01T12:00:00Z","origin":"host01"},"content":86400}
+//! "#;
+//! let census = Census::new();
+//! census.scan(&archive[..]).unwrap();
+//!
+//! let entries = census.entries();
+//! assert_eq!(entries[0].coordinates.to_string(), "example.org/metrics/host/uptime@1");
+//! assert_eq!(entries[0].packets, 1);
+//! assert_eq!(census.unregistered(&MemorySchemaRegistry::new()).unwrap().len(), 1);
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use crate::schema::{self, SchemaRegistry};
+use crate::transform::{self, Transform};
+use crate::{Coordinates, Format, Header, Manifest, RawPacket};
+
+/// What was seen of one set of coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub coordinates: Coordinates,
+    pub packets: u64,
+    /// The encoded size of the envelopes, as read or as JSON.
+    pub bytes: u64,
+    /// The earliest `ctime` seen.
+    pub first_seen: DateTime<Utc>,
+    /// The latest `ctime` seen.
+    pub last_seen: DateTime<Utc>,
+}
+
+/// What a [`scan`](Census::scan) read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scanned {
+    pub packets: u64,
+    /// Lines that are not envelopes, which are skipped.
+    pub malformed: u64,
+}
+
+/// Tallies envelopes by coordinates.
+#[derive(Debug, Default)]
+pub struct Census {
+    entries: Mutex<BTreeMap<Coordinates, Entry>>,
+}
+
+impl Census {
+    pub fn new() -> Self {
+        Census::default()
+    }
+
+    /// Counts an envelope with the given manifest, encoded in `bytes` bytes.
+    pub fn record(&self, manifest: &Manifest, bytes: u64) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let coordinates = manifest.coordinates();
+        let entry = entries.entry(coordinates.clone()).or_insert(Entry {
+            coordinates,
+            packets: 0,
+            bytes: 0,
+            first_seen: manifest.ctime,
+            last_seen: manifest.ctime,
+        });
+        entry.packets += 1;
+        entry.bytes += bytes;
+        entry.first_seen = entry.first_seen.min(manifest.ctime);
+        entry.last_seen = entry.last_seen.max(manifest.ctime);
+    }
+
+    /// Counts every envelope in an archive of newline-delimited JSON,
+    /// measuring each by its line.
+    pub fn scan<R: Read>(&self, archive: R) -> io::Result<Scanned> {
+        let mut reader = BufReader::new(archive);
+        let mut scanned = Scanned::default();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(scanned);
+            }
+            let text = line.trim_ascii();
+            if text.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<Header>(text) {
+                Ok(header) => {
+                    self.record(&header.manifest, text.len() as u64);
+                    scanned.packets += 1;
+                }
+                Err(_) => scanned.malformed += 1,
+            }
+        }
+    }
+
+    /// Everything seen so far, in order of coordinates.
+    pub fn entries(&self) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.values().cloned().collect()
+    }
+
+    /// The entries whose coordinates have no schema in the registry.
+    pub fn unregistered<R: SchemaRegistry + ?Sized>(
+        &self,
+        registry: &R,
+    ) -> Result<Vec<Entry>, schema::Error> {
+        let mut unregistered = Vec::new();
+        for entry in self.entries() {
+            if registry.get_schema(&entry.coordinates)?.is_none() {
+                unregistered.push(entry);
+            }
+        }
+        Ok(unregistered)
+    }
+}
+
+/// Counts packets in passing, measuring each by its JSON encoding.
+impl Transform for Census {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        let bytes = packet
+            .estimated_encoded_size(Format::Json)
+            .map_err(|e| transform::Error::new(e.to_string()))?;
+        self.record(&packet.manifest, bytes as u64);
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::schema::MemorySchemaRegistry;
+    use chrono::Duration;
+
+    #[test]
+    fn tallies_archives() {
+        let cpu = fixtures::cpu_raw();
+        let mut later = cpu.clone();
+        later.manifest.ctime += Duration::hours(1);
+        let lines: Vec<_> = [&later, &fixtures::netstat_raw(), &cpu]
+            .iter()
+            .map(|packet| serde_json::to_string(packet).unwrap())
+            .collect();
+        let archive = format!("{}\n{}\n\nnot json\n{}", lines[0], lines[1], lines[2]);
+
+        let census = Census::new();
+        let scanned = census.scan(archive.as_bytes()).unwrap();
+        assert_eq!(
+            scanned,
+            Scanned {
+                packets: 3,
+                malformed: 1
+            }
+        );
+
+        let entries = census.entries();
+        assert_eq!(entries.len(), 2);
+        let cpu_entry = entries
+            .iter()
+            .find(|entry| entry.coordinates == cpu.manifest.coordinates())
+            .unwrap();
+        assert_eq!(cpu_entry.packets, 2);
+        assert_eq!(cpu_entry.bytes as usize, lines[0].len() + lines[2].len());
+        assert_eq!(cpu_entry.first_seen, cpu.manifest.ctime);
+        assert_eq!(cpu_entry.last_seen, later.manifest.ctime);
+
+        let registry = MemorySchemaRegistry::new();
+        registry
+            .register_schema(cpu.manifest.coordinates(), serde_json::json!({}))
+            .unwrap();
+        let unregistered = census.unregistered(&registry).unwrap();
+        assert_eq!(unregistered.len(), 1);
+        assert_eq!(unregistered[0].coordinates.kind, "netstat");
+    }
+}
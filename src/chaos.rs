@@ -0,0 +1,360 @@
+//! Injecting faults into a packet flow, to test how consumers cope.
+//!
+//! A [`Chaos`] stage sits downstream of a source and, at rates it is given,
+//! drops packets, delivers them twice, delays them, or corrupts their
+//! manifests, so that a consumer's claims to survive such faults can be
+//! checked in staging before production checks them instead:
+//!
+//! ```
+//! use std::time::Duration;
+//! use intermodal::chaos::Chaos;
+//! # let packets: Vec<intermodal::RawPacket> = Vec::new();
+//!
+//! let chaos = Chaos::new()
+//!     .drop_rate(0.01)
+//!     .duplicate_rate(0.05)
+//!     .delay(0.1, Duration::from_millis(5)..=Duration::from_millis(250))
+//!     .corrupt_rate(0.001);
+//!
+//! for packet in chaos.wrap(packets.into_iter()) {
+//!     // Hand the packet to the consumer under test.
+//! }
+//! ```
+//!
+//! Faults are drawn independently for each packet, and only for packets
+//! matching the stage's [`Selector`]. A dropped packet suffers no other
+//! fault; a duplicated one is delivered twice in a row, the copy exactly as
+//! the packet. Delays are served by blocking the thread that pulls the
+//! packet through. [`Chaos::seed`] makes a run reproducible.
+//!
+//! A stage is also a [`Transform`], for use in a
+//! [`Pipeline`](crate::pipeline::Pipeline); a transform yields at most one
+//! packet, so there it never duplicates.
+//!
+//! Available with the `chaos` feature.
+
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::transform::{self, Transform};
+use crate::{RawPacket, Selector};
+
+/// A way of corrupting a manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Corruption {
+    /// Bump the version, so the content no longer matches it.
+    Version,
+    /// Alter the kind to one no consumer knows.
+    Kind,
+    /// Move the creation time up to a day into the future.
+    Ctime,
+    /// Blank the origin.
+    Origin,
+}
+
+impl Corruption {
+    /// Every corruption.
+    pub const ALL: [Corruption; 4] = [
+        Corruption::Version,
+        Corruption::Kind,
+        Corruption::Ctime,
+        Corruption::Origin,
+    ];
+
+    fn apply<R: Rng>(self, packet: &mut RawPacket, rng: &mut R) {
+        let manifest = &mut packet.manifest;
+        match self {
+            Corruption::Version => manifest.version = manifest.version.wrapping_add(1),
+            Corruption::Kind => manifest.kind.push_str("-corrupted"),
+            Corruption::Ctime => {
+                let seconds = rng.random_range(1..=86_400);
+                manifest.ctime += chrono::Duration::seconds(seconds);
+            }
+            Corruption::Origin => manifest.origin.clear(),
+        }
+    }
+}
+
+/// The faults a [`Chaos`] stage has injected so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChaosStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+    pub corrupted: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    delayed: AtomicU64,
+    corrupted: AtomicU64,
+}
+
+/// Injects faults into the packets passing through it.
+#[derive(Debug)]
+pub struct Chaos {
+    selector: Selector,
+    drop_rate: f64,
+    duplicate_rate: f64,
+    delay_rate: f64,
+    delay: RangeInclusive<Duration>,
+    corrupt_rate: f64,
+    corruptions: Vec<Corruption>,
+    rng: Mutex<StdRng>,
+    counters: Counters,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Chaos::new()
+    }
+}
+
+impl Chaos {
+    /// A stage injecting no faults yet, into every packet, drawing from a
+    /// generator seeded by the operating system.
+    pub fn new() -> Self {
+        Chaos {
+            selector: Selector::any(),
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            delay_rate: 0.0,
+            delay: Duration::ZERO..=Duration::ZERO,
+            corrupt_rate: 0.0,
+            corruptions: Corruption::ALL.to_vec(),
+            rng: Mutex::new(StdRng::from_os_rng()),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Injects faults only into packets matching `selector`, passing the
+    /// rest through untouched.
+    pub fn selector(mut self, selector: Selector) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Drops this fraction of packets. Rates are clamped to between 0 and
+    /// 1.
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        self.drop_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delivers this fraction of packets twice.
+    pub fn duplicate_rate(mut self, rate: f64) -> Self {
+        self.duplicate_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Holds this fraction of packets back for a time drawn evenly from
+    /// `range`.
+    pub fn delay(mut self, rate: f64, range: RangeInclusive<Duration>) -> Self {
+        self.delay_rate = rate.clamp(0.0, 1.0);
+        self.delay = range;
+        self
+    }
+
+    /// Corrupts the manifests of this fraction of packets, each with one of
+    /// the [`corruptions`](Chaos::corruptions) chosen at random.
+    pub fn corrupt_rate(mut self, rate: f64) -> Self {
+        self.corrupt_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Corrupts manifests only in these ways, rather than in
+    /// [all](Corruption::ALL) of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `corruptions` is empty.
+    pub fn corruptions(mut self, corruptions: &[Corruption]) -> Self {
+        assert!(!corruptions.is_empty(), "no corruptions to choose from");
+        self.corruptions = corruptions.to_vec();
+        self
+    }
+
+    /// Draws faults from a generator with the given seed, for reproducible
+    /// runs.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Mutex::new(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    /// Injects faults into one packet, returning what is delivered in its
+    /// place: nothing, the packet, or the packet twice.
+    pub fn inject(&self, packet: RawPacket) -> Vec<RawPacket> {
+        match self.faults(packet) {
+            None => Vec::new(),
+            Some((packet, true)) => vec![packet.clone(), packet],
+            Some((packet, false)) => vec![packet],
+        }
+    }
+
+    /// Applies the faults drawn for the packet but duplication, which it
+    /// reports instead. Returns `None` if the packet is dropped.
+    fn faults(&self, mut packet: RawPacket) -> Option<(RawPacket, bool)> {
+        if !self.selector.matches(&packet.manifest) {
+            return Some((packet, false));
+        }
+        let (delay, duplicate) = {
+            let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+            if rng.random_bool(self.drop_rate) {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            if rng.random_bool(self.corrupt_rate) {
+                let corruption = self.corruptions[rng.random_range(0..self.corruptions.len())];
+                corruption.apply(&mut packet, &mut *rng);
+                self.counters.corrupted.fetch_add(1, Ordering::Relaxed);
+            }
+            let delay = if rng.random_bool(self.delay_rate) {
+                Some(rng.random_range(self.delay.clone()))
+            } else {
+                None
+            };
+            (delay, rng.random_bool(self.duplicate_rate))
+        };
+        if let Some(delay) = delay {
+            self.counters.delayed.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(delay);
+        }
+        if duplicate {
+            self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+        }
+        Some((packet, duplicate))
+    }
+
+    /// Injects faults into the packets `source` yields.
+    pub fn wrap<I: Iterator<Item = RawPacket>>(self, source: I) -> Faulty<I> {
+        Faulty {
+            chaos: self,
+            source,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        ChaosStats {
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            duplicated: self.counters.duplicated.load(Ordering::Relaxed),
+            delayed: self.counters.delayed.load(Ordering::Relaxed),
+            corrupted: self.counters.corrupted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Transform for Chaos {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        Ok(self.faults(packet).map(|(packet, _)| packet))
+    }
+}
+
+/// The packets of a source, with faults injected. Made by [`Chaos::wrap`].
+#[derive(Debug)]
+pub struct Faulty<I> {
+    chaos: Chaos,
+    source: I,
+    pending: VecDeque<RawPacket>,
+}
+
+impl<I> Faulty<I> {
+    pub fn chaos(&self) -> &Chaos {
+        &self.chaos
+    }
+}
+
+impl<I: Iterator<Item = RawPacket>> Iterator for Faulty<I> {
+    type Item = RawPacket;
+
+    fn next(&mut self) -> Option<RawPacket> {
+        while self.pending.is_empty() {
+            let packet = self.source.next()?;
+            self.pending.extend(self.chaos.inject(packet));
+        }
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::time::Instant;
+
+    fn packets(n: usize) -> impl Iterator<Item = RawPacket> {
+        (0..n).map(|_| fixtures::cpu_raw())
+    }
+
+    #[test]
+    fn injects_faults_at_their_rates() {
+        let chaos = || {
+            Chaos::new()
+                .drop_rate(0.2)
+                .duplicate_rate(0.3)
+                .corrupt_rate(0.1)
+                .corruptions(&[Corruption::Kind])
+                .seed(7)
+        };
+        let mut faulty = chaos().wrap(packets(1000));
+        let delivered: Vec<_> = faulty.by_ref().collect();
+        let stats = faulty.chaos().stats();
+        assert_eq!(
+            delivered.len() as u64,
+            1000 - stats.dropped + stats.duplicated
+        );
+        assert!((150..250).contains(&stats.dropped), "{:?}", stats);
+        assert!((200..280).contains(&stats.duplicated), "{:?}", stats);
+        assert!((50..110).contains(&stats.corrupted), "{:?}", stats);
+        let corrupted = delivered
+            .iter()
+            .filter(|packet| packet.manifest.kind == "cpu-corrupted")
+            .count();
+        assert!(corrupted as u64 >= stats.corrupted);
+
+        let again: Vec<_> = chaos().wrap(packets(1000)).collect();
+        assert_eq!(again, delivered);
+    }
+
+    #[test]
+    fn spares_unselected_packets() {
+        let chaos = Chaos::new()
+            .selector("kind=netstat".parse().unwrap())
+            .drop_rate(1.0);
+        assert_eq!(chaos.inject(fixtures::cpu_raw()), [fixtures::cpu_raw()]);
+        assert!(chaos.inject(fixtures::netstat_raw()).is_empty());
+        assert_eq!(chaos.apply(fixtures::netstat_raw()).unwrap(), None);
+        assert_eq!(chaos.stats().dropped, 2);
+    }
+
+    #[test]
+    fn delays_and_corrupts() {
+        let delay = Duration::from_millis(20);
+        let chaos = Chaos::new()
+            .delay(1.0, delay..=delay)
+            .corrupt_rate(1.0)
+            .corruptions(&[Corruption::Version, Corruption::Origin]);
+        let started = Instant::now();
+        let packet = chaos.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert!(started.elapsed() >= delay);
+        assert!(packet.manifest.version == 2 || packet.manifest.origin.is_empty());
+        assert_eq!(
+            chaos.stats(),
+            ChaosStats {
+                dropped: 0,
+                duplicated: 0,
+                delayed: 1,
+                corrupted: 1
+            }
+        );
+    }
+}
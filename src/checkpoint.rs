@@ -0,0 +1,359 @@
+//! Persisting how far a source has read.
+//!
+//! A source that records its [`Position`] with a [`Checkpointer`] after
+//! processing can resume from there after a crash instead of starting over.
+//! Committing after every packet is expensive, so a [`Tracker`] commits
+//! once every so many packets; the number of packets between commits is the
+//! most that will be processed twice after a crash.
+//!
+//! [`FileCheckpointer`] keeps positions in a directory, replacing each one
+//! atomically. With the `redis` feature, [`RedisCheckpointer`] keeps them in
+//! Redis.
+
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// How far a source has read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Position {
+    /// A byte offset into a file.
+    Offset { offset: u64 },
+    /// An offset within a Kafka partition.
+    Kafka {
+        topic: String,
+        partition: i32,
+        offset: i64,
+    },
+    /// An entry id within a stream, such as a Redis stream.
+    Stream { id: String },
+}
+
+/// Durable storage for source positions, keyed by source name.
+pub trait Checkpointer: Send + Sync {
+    /// Returns the last committed position of the source, if any.
+    fn load(&self, source: &str) -> Result<Option<Position>, Error>;
+
+    /// Records the source's position. When this returns, the position
+    /// survives a crash.
+    fn commit(&self, source: &str, position: &Position) -> Result<(), Error>;
+}
+
+/// Keeps positions as JSON files in a directory, one per source.
+///
+/// A source's file is named after it, with every byte of the name other
+/// than an ASCII letter, digit, `-`, `.` or `_` percent-encoded, so that
+/// `kafka/metrics` is kept in `kafka%2Fmetrics.json` and no two sources
+/// share a file.
+#[derive(Debug, Clone)]
+pub struct FileCheckpointer {
+    dir: PathBuf,
+}
+
+impl FileCheckpointer {
+    /// Keeps positions in `dir`, which is created on the first commit.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileCheckpointer { dir: dir.into() }
+    }
+
+    fn path(&self, source: &str) -> PathBuf {
+        let mut name = String::with_capacity(source.len());
+        for byte in source.bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' => {
+                    name.push(byte as char)
+                }
+                _ => {
+                    let _ = write!(name, "%{:02X}", byte);
+                }
+            }
+        }
+        self.dir.join(format!("{}.json", name))
+    }
+}
+
+impl Checkpointer for FileCheckpointer {
+    fn load(&self, source: &str) -> Result<Option<Position>, Error> {
+        match fs::read(self.path(source)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| Error::Decode(e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    fn commit(&self, source: &str, position: &Position) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(source);
+        let partial = path.with_extension("json.partial");
+        let bytes = serde_json::to_vec(position).map_err(|e| Error::Decode(e.to_string()))?;
+        // Write and sync a copy, then rename it over the old position, so
+        // that a crash leaves either the old position or the new one, and
+        // sync the directory so that the rename itself survives.
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&partial, &path)?;
+        sync_dir(&self.dir)?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened, or synced, as files everywhere else.
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_backend::RedisCheckpointer;
+
+#[cfg(feature = "redis")]
+mod redis_backend {
+    use std::sync::Mutex;
+
+    use redis::Commands;
+
+    use super::{Checkpointer, Error, Position};
+
+    /// Keeps positions in Redis, as JSON strings under `<prefix><source>`.
+    pub struct RedisCheckpointer {
+        connection: Mutex<redis::Connection>,
+        prefix: String,
+    }
+
+    impl RedisCheckpointer {
+        pub fn new(connection: redis::Connection) -> Self {
+            RedisCheckpointer {
+                connection: Mutex::new(connection),
+                prefix: "intermodal:checkpoint:".to_string(),
+            }
+        }
+
+        /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1/`.
+        pub fn open(url: &str) -> Result<Self, Error> {
+            let client = redis::Client::open(url).map_err(backend_error)?;
+            let connection = client.get_connection().map_err(backend_error)?;
+            Ok(RedisCheckpointer::new(connection))
+        }
+
+        /// Sets the prefix of the keys positions are stored under.
+        pub fn with_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+            self.prefix = prefix.into();
+            self
+        }
+    }
+
+    impl Checkpointer for RedisCheckpointer {
+        fn load(&self, source: &str) -> Result<Option<Position>, Error> {
+            let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+            let value: Option<String> = connection
+                .get(format!("{}{}", self.prefix, source))
+                .map_err(backend_error)?;
+            match value {
+                Some(value) => serde_json::from_str(&value)
+                    .map(Some)
+                    .map_err(|e| Error::Decode(e.to_string())),
+                None => Ok(None),
+            }
+        }
+
+        fn commit(&self, source: &str, position: &Position) -> Result<(), Error> {
+            let value =
+                serde_json::to_string(position).map_err(|e| Error::Decode(e.to_string()))?;
+            let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+            connection
+                .set::<_, _, ()>(format!("{}{}", self.prefix, source), value)
+                .map_err(backend_error)
+        }
+    }
+
+    fn backend_error(e: redis::RedisError) -> Error {
+        Error::Backend(e.to_string())
+    }
+}
+
+/// Commits a source's position once every so many processed packets.
+///
+/// Call [`processed`](Tracker::processed) after each packet has been fully
+/// handled, and [`flush`](Tracker::flush) before stopping cleanly.
+pub struct Tracker<C> {
+    checkpointer: C,
+    source: String,
+    window: usize,
+    uncommitted: usize,
+    latest: Option<Position>,
+}
+
+impl<C: Checkpointer> Tracker<C> {
+    /// Tracks `source`, committing at least once every `window` packets.
+    pub fn new<S: Into<String>>(checkpointer: C, source: S, window: usize) -> Self {
+        Tracker {
+            checkpointer,
+            source: source.into(),
+            window: window.max(1),
+            uncommitted: 0,
+            latest: None,
+        }
+    }
+
+    /// Returns the position to resume from.
+    pub fn resume(&self) -> Result<Option<Position>, Error> {
+        self.checkpointer.load(&self.source)
+    }
+
+    /// Records that processing has reached `position`, committing it if the
+    /// window is full.
+    pub fn processed(&mut self, position: Position) -> Result<(), Error> {
+        self.latest = Some(position);
+        self.uncommitted += 1;
+        if self.uncommitted >= self.window {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Commits the latest position, if it has not been committed yet.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.uncommitted > 0 {
+            if let Some(position) = &self.latest {
+                self.checkpointer.commit(&self.source, position)?;
+            }
+            self.uncommitted = 0;
+        }
+        Ok(())
+    }
+}
+
+/// An error loading or committing a position.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A stored position could not be decoded, or a position encoded.
+    Decode(String),
+    /// The backing store failed.
+    Backend(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Decode(message) => write!(f, "invalid checkpoint: {}", message),
+            Error::Backend(message) => write!(f, "checkpoint store: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpointer(name: &str) -> FileCheckpointer {
+        let dir = std::env::temp_dir().join(format!(
+            "intermodal-checkpoint-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        FileCheckpointer::new(dir)
+    }
+
+    #[test]
+    fn file_round_trips() {
+        let checkpointer = checkpointer("round-trip");
+        assert_eq!(checkpointer.load("kafka/metrics").unwrap(), None);
+
+        let position = Position::Kafka {
+            topic: "metrics".into(),
+            partition: 3,
+            offset: 1042,
+        };
+        checkpointer.commit("kafka/metrics", &position).unwrap();
+        checkpointer
+            .commit("spool", &Position::Offset { offset: 7 })
+            .unwrap();
+        assert_eq!(checkpointer.load("kafka/metrics").unwrap(), Some(position));
+        assert_eq!(
+            checkpointer.load("spool").unwrap(),
+            Some(Position::Offset { offset: 7 })
+        );
+    }
+
+    #[test]
+    fn sources_keep_files_of_their_own() {
+        let checkpointer = checkpointer("names");
+        let sources = [
+            "kafka/metrics",
+            "kafka_metrics",
+            "kafka%2Fmetrics",
+            "kafka:metrics",
+        ];
+        for (offset, source) in sources.iter().enumerate() {
+            checkpointer
+                .commit(
+                    source,
+                    &Position::Offset {
+                        offset: offset as u64,
+                    },
+                )
+                .unwrap();
+        }
+        for (offset, source) in sources.iter().enumerate() {
+            assert_eq!(
+                checkpointer.load(source).unwrap(),
+                Some(Position::Offset {
+                    offset: offset as u64
+                }),
+                "{}",
+                source
+            );
+        }
+        assert!(checkpointer.dir.join("kafka%2Fmetrics.json").exists());
+        assert!(checkpointer.dir.join("kafka%252Fmetrics.json").exists());
+    }
+
+    #[test]
+    fn tracker_commits_per_window() {
+        let checkpointer = checkpointer("tracker");
+        let mut tracker = Tracker::new(checkpointer.clone(), "file", 3);
+        for offset in 1..=4 {
+            tracker.processed(Position::Offset { offset }).unwrap();
+        }
+        assert_eq!(
+            checkpointer.load("file").unwrap(),
+            Some(Position::Offset { offset: 3 })
+        );
+
+        tracker.flush().unwrap();
+        assert_eq!(
+            tracker.resume().unwrap(),
+            Some(Position::Offset { offset: 4 })
+        );
+    }
+}
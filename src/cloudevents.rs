@@ -0,0 +1,399 @@
+//! Converting packets to and from CloudEvents.
+//!
+//! [`Packet::to_cloudevent`] turns a packet into a [`CloudEvent`], in the
+//! structured JSON form of CloudEvents 1.0, and
+//! [`Packet::from_cloudevent`] turns one back, for systems that speak
+//! CloudEvents and not envelopes:
+//!
+//! ```
+//! use intermodal::cloudevents::CloudEvent;
+//! use intermodal::{Manifest, Packet};
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .label("tenant", "acme")
+//!     .build()
+//!     .unwrap();
+//! let packet = Packet::new(manifest, 86400u64);
+//!
+//! let event = packet.to_cloudevent()?;
+//! assert_eq!(event.r#type, "example.org/metrics/host/uptime@1");
+//! assert_eq!(event.source, "host01");
+//! assert_eq!(event.extensions["tenant"], "acme");
+//!
+//! let json = serde_json::to_string(&event)?;
+//! let event: CloudEvent = serde_json::from_str(&json)?;
+//! assert_eq!(Packet::<u64>::from_cloudevent(event)?, packet);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The attributes are:
+//!
+//! | Attribute | Holds |
+//! |---|---|
+//! | `type` | The coordinates, in their canonical form |
+//! | `source` | The origin |
+//! | `id` | A hash of the canonical encoding, so that a packet sent twice is one event |
+//! | `time` | The ctime |
+//! | `datacontenttype`, `data` | `application/json`, and the content |
+//! | `expires`, `correlationid`, `replyto` | As in the manifest |
+//! | `traceparent`, `tracestate` | The W3C trace context, as the distributed tracing extension has it |
+//! | Any other extension | A label |
+//!
+//! Extension names may hold only lowercase ASCII letters and digits, so
+//! labels with other keys, or with the name of an attribute, are carried
+//! together in a `labels` extension holding them as a JSON object. Events
+//! from elsewhere are read with any extensions not listed above as labels,
+//! their values as strings, and with the time they are read as their ctime
+//! if they have none. Their `type` must be coordinates, and their data JSON.
+//! A packet's signature and provenance are not carried.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::coordinates::{self, Coordinates};
+use crate::trace::TraceContext;
+use crate::{Manifest, Packet};
+
+/// The CloudEvents version events are written in, and the only one read.
+pub const SPEC_VERSION: &str = "1.0";
+
+/// The extension holding labels whose keys are not extension names.
+pub const LABELS: &str = "labels";
+
+const EXPIRES: &str = "expires";
+const CORRELATION_ID: &str = "correlationid";
+const REPLY_TO: &str = "replyto";
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// The names no label may take as an extension.
+const RESERVED: [&str; 16] = [
+    "specversion",
+    "id",
+    "source",
+    "type",
+    "datacontenttype",
+    "dataschema",
+    "subject",
+    "time",
+    "data",
+    "data_base64",
+    EXPIRES,
+    CORRELATION_ID,
+    REPLY_TO,
+    TRACEPARENT,
+    TRACESTATE,
+    LABELS,
+];
+
+/// A CloudEvent in the structured JSON form.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CloudEvent {
+    pub specversion: String,
+    pub id: String,
+    pub source: String,
+    pub r#type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datacontenttype: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dataschema: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// Every other attribute.
+    #[serde(flatten)]
+    pub extensions: BTreeMap<String, Value>,
+}
+
+impl<T: Serialize> Packet<T> {
+    /// The packet as a CloudEvent.
+    pub fn to_cloudevent(&self) -> Result<CloudEvent, Error> {
+        let manifest = &self.manifest;
+        let mut extensions = BTreeMap::new();
+        let mut bagged = BTreeMap::new();
+        for (key, value) in &manifest.labels {
+            if is_extension_name(key) && !RESERVED.contains(&key.as_str()) {
+                extensions.insert(key.clone(), Value::String(value.clone()));
+            } else {
+                bagged.insert(key.as_str(), value.as_str());
+            }
+        }
+        if !bagged.is_empty() {
+            let bag = serde_json::to_string(&bagged).map_err(Error::Data)?;
+            extensions.insert(LABELS.to_string(), Value::String(bag));
+        }
+        let mut extend = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                extensions.insert(name.to_string(), Value::String(value));
+            }
+        };
+        extend(
+            EXPIRES,
+            manifest.expires.map(|expires| expires.to_rfc3339()),
+        );
+        extend(CORRELATION_ID, manifest.correlation_id.clone());
+        extend(REPLY_TO, manifest.reply_to.clone());
+        if let Some(trace) = &manifest.trace {
+            extend(TRACEPARENT, Some(trace.traceparent.clone()));
+            extend(TRACESTATE, trace.tracestate.clone());
+        }
+
+        Ok(CloudEvent {
+            specversion: SPEC_VERSION.to_string(),
+            id: format!(
+                "{:016x}",
+                fnv1a(&self.canonical_bytes().map_err(Error::Data)?)
+            ),
+            source: manifest.origin.clone(),
+            r#type: manifest.coordinates().to_string(),
+            datacontenttype: Some("application/json".to_string()),
+            dataschema: None,
+            subject: None,
+            time: Some(manifest.ctime),
+            data: Some(serde_json::to_value(&self.content).map_err(Error::Data)?),
+            extensions,
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Packet<T> {
+    /// A packet holding a CloudEvent's data.
+    pub fn from_cloudevent(event: CloudEvent) -> Result<Packet<T>, Error> {
+        if event.specversion != SPEC_VERSION {
+            return Err(Error::SpecVersion(event.specversion));
+        }
+        if event.extensions.contains_key("data_base64") {
+            return Err(Error::ContentType("data_base64".to_string()));
+        }
+        if let Some(media_type) = &event.datacontenttype {
+            let essence = media_type.split(';').next().unwrap_or_default().trim();
+            if !essence.eq_ignore_ascii_case("application/json") && !essence.ends_with("+json") {
+                return Err(Error::ContentType(media_type.clone()));
+            }
+        }
+        let coordinates: Coordinates = event.r#type.parse().map_err(Error::Type)?;
+
+        let mut extensions = event.extensions;
+        let mut text = |name: &str| -> Result<Option<String>, Error> {
+            extensions
+                .remove(name)
+                .map(|value| string(name, value))
+                .transpose()
+        };
+        let expires = text(EXPIRES)?
+            .map(|expires| {
+                DateTime::parse_from_rfc3339(&expires)
+                    .map(|expires| expires.with_timezone(&Utc))
+                    .map_err(|_| Error::Extension(EXPIRES.to_string()))
+            })
+            .transpose()?;
+        let correlation_id = text(CORRELATION_ID)?;
+        let reply_to = text(REPLY_TO)?;
+        let trace = match text(TRACEPARENT)? {
+            Some(traceparent) => Some(TraceContext {
+                traceparent,
+                tracestate: text(TRACESTATE)?,
+            }),
+            None => None,
+        };
+        let mut labels = match text(LABELS)? {
+            Some(bag) => serde_json::from_str::<HashMap<String, String>>(&bag)
+                .map_err(|_| Error::Extension(LABELS.to_string()))?,
+            None => HashMap::new(),
+        };
+        for (name, value) in extensions {
+            let value = string(&name, value)?;
+            labels.insert(name, value);
+        }
+
+        let manifest = Manifest {
+            domain: coordinates.domain,
+            scope: coordinates.scope,
+            kind: coordinates.kind,
+            version: coordinates.version,
+            ctime: event.time.unwrap_or_else(Utc::now),
+            expires,
+            origin: event.source,
+            labels,
+            trace,
+            correlation_id,
+            reply_to,
+        };
+        let content =
+            serde_json::from_value(event.data.unwrap_or(Value::Null)).map_err(Error::Data)?;
+        Ok(Packet::new(manifest, content))
+    }
+}
+
+fn is_extension_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+}
+
+/// An extension's value as a string, as labels hold them.
+fn string(name: &str, value: Value) -> Result<String, Error> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        _ => Err(Error::Extension(name.to_string())),
+    }
+}
+
+/// The 64-bit FNV-1a hash, which is stable across builds, as event ids
+/// need to be.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// An error converting between packets and CloudEvents.
+#[derive(Debug)]
+pub enum Error {
+    /// The content could not be converted to or from the event's data.
+    Data(serde_json::Error),
+    /// The event is of a CloudEvents version other than 1.0.
+    SpecVersion(String),
+    /// The event's type is not coordinates.
+    Type(coordinates::ParseError),
+    /// The event's data is not JSON.
+    ContentType(String),
+    /// An extension's value is not one it can hold.
+    Extension(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Data(e) => write!(f, "cannot convert event data: {}", e),
+            Error::SpecVersion(version) => {
+                write!(f, "unsupported CloudEvents version `{}`", version)
+            }
+            Error::Type(e) => write!(f, "event type is not coordinates: {}", e),
+            Error::ContentType(media_type) => {
+                write!(f, "event data is not JSON but `{}`", media_type)
+            }
+            Error::Extension(name) => write!(f, "invalid `{}` extension", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Data(e) => Some(e),
+            Error::Type(e) => Some(e),
+            Error::SpecVersion(_) | Error::ContentType(_) | Error::Extension(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+    use crate::RawPacket;
+    use serde_json::json;
+
+    #[test]
+    fn round_trips_packets() {
+        let mut packet = fixtures::cpu_raw();
+        packet.manifest.expires = Some(packet.manifest.ctime + chrono::Duration::minutes(5));
+        packet.manifest.trace = Some(TraceContext {
+            traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string(),
+            tracestate: Some("vendor=1".to_string()),
+        });
+        packet.manifest.reply_to = Some("replies".to_string());
+        packet
+            .manifest
+            .labels
+            .insert("k8s.io/app".to_string(), "collector".to_string());
+        packet
+            .manifest
+            .labels
+            .insert("type".to_string(), "gauge".to_string());
+
+        let event = packet.to_cloudevent().unwrap();
+        assert_eq!(event.r#type, "example.org/metrics/host/cpu@1");
+        assert_eq!(event.source, "host01.example.org");
+        assert_eq!(event.extensions["datacenter"], "us-east");
+        assert_eq!(
+            event.extensions[LABELS],
+            r#"{"k8s.io/app":"collector","type":"gauge"}"#
+        );
+        assert_eq!(event.id, packet.to_cloudevent().unwrap().id);
+        assert_ne!(
+            event.id,
+            fixtures::netstat_raw().to_cloudevent().unwrap().id
+        );
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["specversion"], "1.0");
+        assert_eq!(json["type"], "example.org/metrics/host/cpu@1");
+        assert_eq!(json["replyto"], "replies");
+        let event: CloudEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(RawPacket::from_cloudevent(event.clone()).unwrap(), packet);
+        let cpu = Packet::<Cpu>::from_cloudevent(event).unwrap();
+        assert_eq!(cpu.content.idle, 83.25);
+    }
+
+    #[test]
+    fn reads_foreign_events() {
+        let event: CloudEvent = serde_json::from_value(json!({
+            "specversion": "1.0",
+            "id": "A234-1234-1234",
+            "source": "https://partner.example.com/orders",
+            "type": "example.com/orders/order-created@1",
+            "datacontenttype": "application/cloudevents+json; charset=utf-8",
+            "priority": 3,
+            "urgent": true,
+            "data": { "order": 17 }
+        }))
+        .unwrap();
+        let packet = RawPacket::from_cloudevent(event.clone()).unwrap();
+        assert_eq!(packet.manifest.kind, "order-created");
+        assert_eq!(packet.manifest.labels["priority"], "3");
+        assert_eq!(packet.manifest.labels["urgent"], "true");
+        assert_eq!(packet.content["order"], 17);
+
+        let mut other = event.clone();
+        other.r#type = "com.example.order.created".to_string();
+        assert!(matches!(
+            RawPacket::from_cloudevent(other),
+            Err(Error::Type(_))
+        ));
+        let mut other = event.clone();
+        other.specversion = "0.3".to_string();
+        assert!(matches!(
+            RawPacket::from_cloudevent(other),
+            Err(Error::SpecVersion(_))
+        ));
+        let mut other = event.clone();
+        other.datacontenttype = Some("text/xml".to_string());
+        assert!(matches!(
+            RawPacket::from_cloudevent(other),
+            Err(Error::ContentType(_))
+        ));
+        let mut other = event;
+        other.extensions.insert("nested".to_string(), json!({}));
+        assert!(matches!(
+            RawPacket::from_cloudevent(other),
+            Err(Error::Extension(name)) if name == "nested"
+        ));
+    }
+}
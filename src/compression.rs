@@ -0,0 +1,329 @@
+//! Compressing content.
+//!
+//! [`Packet::compress`] replaces a packet's content with its JSON encoding,
+//! compressed with a [`Codec`] and written as base64, and records the codec
+//! in the [`LABEL`] label. Decoding an envelope with
+//! [`Packet::from_bytes`] decompresses it again, so receivers need not know
+//! which senders compress:
+//!
+//! ```
+//! use intermodal::compression::Codec;
+//! use intermodal::{Format, Packet, RawPacket};
+//! # let packet: RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "uptime", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": [86400, 86460, 86520]
+//! # })).unwrap();
+//!
+//! # #[cfg(feature = "zstd")] {
+//! let compressed = packet.compress(Codec::Zstd).unwrap();
+//! assert_eq!(compressed.manifest.labels["intermodal.io/compression"], "zstd");
+//!
+//! let bytes = compressed.to_bytes(Format::Json).unwrap();
+//! let decoded: Packet<Vec<u64>> = Packet::from_bytes(&bytes, Format::Json).unwrap();
+//! assert_eq!(decoded.content, [86400, 86460, 86520]);
+//! # }
+//! ```
+//!
+//! Gzip requires the `gzip` feature, and zstd the `zstd` feature. Without
+//! them, compressing and decompressing with those codecs fails.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{base64, labels, Packet, RawPacket};
+
+/// The label recording the codec a packet's content is compressed with.
+pub const LABEL: &str = labels::COMPRESSION;
+
+/// A compression algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Codec::Gzip => gzip::compress(bytes),
+            Codec::Zstd => zstd::compress(bytes),
+        }
+    }
+
+    /// Decompresses `bytes`, failing once the output would be longer than
+    /// `max` bytes.
+    fn decompress(self, bytes: &[u8], max: u64) -> Result<Vec<u8>, Error> {
+        let decompressed = match self {
+            Codec::Gzip => gzip::decompress(bytes, max)?,
+            Codec::Zstd => zstd::decompress(bytes, max)?,
+        };
+        if decompressed.len() as u64 > max {
+            return Err(Error::TooLarge(max));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            other => Err(Error::Codec(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The content is already compressed.
+    AlreadyCompressed,
+    /// The codec is not one of [`Codec`].
+    Codec(String),
+    /// The codec needs a feature this build lacks.
+    Unsupported(&'static str),
+    /// The compressed content is not a base64 string.
+    Malformed,
+    /// The content decompresses to more than the given number of bytes.
+    TooLarge(u64),
+    Json(serde_json::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AlreadyCompressed => f.write_str("content is already compressed"),
+            Error::Codec(codec) => write!(f, "unknown compression codec `{}`", codec),
+            Error::Unsupported(feature) => {
+                write!(f, "compression requires the `{}` feature", feature)
+            }
+            Error::Malformed => f.write_str("compressed content is not a base64 string"),
+            Error::TooLarge(max) => {
+                write!(f, "content decompresses to over the limit of {} bytes", max)
+            }
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl<T: Serialize> Packet<T> {
+    /// Compresses the content with `codec`. The packet's signature, which
+    /// covers the uncompressed content, is dropped.
+    pub fn compress(&self, codec: Codec) -> Result<RawPacket, Error> {
+        if self.manifest.labels.contains_key(LABEL) {
+            return Err(Error::AlreadyCompressed);
+        }
+        let compressed = codec.compress(&serde_json::to_vec(&self.content)?)?;
+        let mut manifest = self.manifest.clone();
+        manifest
+            .labels
+            .insert(LABEL.to_string(), codec.name().to_string());
+        let mut packet = Packet::new(manifest, Value::String(base64::encode(&compressed)));
+        packet.provenance = self.provenance.clone();
+        Ok(packet)
+    }
+}
+
+impl<T> Packet<T> {
+    /// The codec the content is compressed with, if it is.
+    pub fn compression(&self) -> Option<Result<Codec, Error>> {
+        self.manifest.labels.get(LABEL).map(|codec| codec.parse())
+    }
+}
+
+impl RawPacket {
+    /// Decompresses the content, if it is compressed, and removes the
+    /// [`LABEL`] label.
+    pub fn decompress(self) -> Result<RawPacket, Error> {
+        self.decompress_within(u64::MAX)
+    }
+
+    /// Decompresses the content like [`decompress`](RawPacket::decompress),
+    /// failing with [`Error::TooLarge`] rather than decompressing more than
+    /// `max` bytes, so that a small packet cannot expand to exhaust memory.
+    pub fn decompress_within(mut self, max: u64) -> Result<RawPacket, Error> {
+        let codec = match self.compression() {
+            Some(codec) => codec?,
+            None => return Ok(self),
+        };
+        let compressed = self
+            .content
+            .as_str()
+            .and_then(base64::decode)
+            .ok_or(Error::Malformed)?;
+        self.content = serde_json::from_slice(&codec.decompress(&compressed, max)?)?;
+        self.manifest.labels.remove(LABEL);
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "gzip")]
+mod gzip {
+    use std::io::{Read, Write};
+
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+
+    use super::Error;
+
+    pub fn compress(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn decompress(bytes: &[u8], max: u64) -> Result<Vec<u8>, Error> {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(bytes)
+            .take(max.saturating_add(1))
+            .read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+#[cfg(not(feature = "gzip"))]
+mod gzip {
+    use super::Error;
+
+    pub fn compress(_: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("gzip"))
+    }
+
+    pub fn decompress(_: &[u8], _: u64) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("gzip"))
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use std::io::Read;
+
+    use super::Error;
+
+    pub fn compress(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(::zstd::encode_all(bytes, 0)?)
+    }
+
+    pub fn decompress(bytes: &[u8], max: u64) -> Result<Vec<u8>, Error> {
+        let mut decompressed = Vec::new();
+        ::zstd::stream::read::Decoder::new(bytes)?
+            .take(max.saturating_add(1))
+            .read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+mod zstd {
+    use super::Error;
+
+    pub fn compress(_: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("zstd"))
+    }
+
+    pub fn decompress(_: &[u8], _: u64) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("zstd"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn round_trip(codec: Codec) {
+        let packet = fixtures::netstat_raw();
+        let compressed = packet.compress(codec).unwrap();
+        assert!(compressed.content.is_string());
+        assert!(matches!(compressed.compression(), Some(Ok(c)) if c == codec));
+        assert!(matches!(
+            compressed.compress(codec),
+            Err(Error::AlreadyCompressed)
+        ));
+        assert_eq!(compressed.clone().decompress().unwrap(), packet);
+        assert!(matches!(
+            compressed.clone().decompress_within(16),
+            Err(Error::TooLarge(16))
+        ));
+
+        let bytes = compressed.to_bytes(crate::Format::Json).unwrap();
+        let decoded: Packet<fixtures::Netstat> =
+            Packet::from_bytes(&bytes, crate::Format::Json).unwrap();
+        assert_eq!(decoded.content.connections.len(), 2);
+        assert!(decoded.compression().is_none());
+        let raw = RawPacket::from_bytes(&bytes, crate::Format::Json).unwrap();
+        assert_eq!(raw, packet);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compresses_with_gzip() {
+        round_trip(Codec::Gzip);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compresses_with_zstd() {
+        round_trip(Codec::Zstd);
+    }
+
+    #[test]
+    fn leaves_uncompressed_content() {
+        let packet = fixtures::cpu_raw();
+        assert_eq!(packet.clone().decompress().unwrap(), packet);
+
+        let mut bogus = packet;
+        bogus
+            .manifest
+            .labels
+            .insert(LABEL.to_string(), "lz4".to_string());
+        assert!(matches!(bogus.decompress(), Err(Error::Codec(_))));
+    }
+}
@@ -0,0 +1,332 @@
+//! Declarative routing configuration.
+//!
+//! A routing configuration lists routes, each pairing a selector expression
+//! with the name of a sink and, optionally, the names of transforms to apply
+//! before packets reach it. In YAML:
+//!
+//! ```yaml
+//! routes:
+//!   - name: host-metrics
+//!     selector: "domain=example.org, scope=metrics/host"
+//!     sink: metrics-db
+//!     transforms: [strip-labels]
+//!   - selector: "*"
+//!     sink: archive
+//! ```
+//!
+//! Sink and transform names are resolved against [`Bindings`] supplied by the
+//! program when the configuration is turned into a [`Router`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::transform::{self, Transform};
+use crate::{RawPacket, Router, Selector};
+
+/// The top-level routing configuration document.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+/// A single route within a [`RoutingConfig`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Routes with higher priorities are consulted first.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    pub selector: Selector,
+    pub sink: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<String>,
+}
+
+fn is_zero(priority: &i32) -> bool {
+    *priority == 0
+}
+
+/// The named sinks and transforms a configuration may refer to.
+pub struct Bindings<S> {
+    sinks: HashMap<String, S>,
+    transforms: HashMap<String, Arc<dyn Transform>>,
+}
+
+/// Where a configured route sends packets, and what happens on the way.
+#[derive(Clone)]
+pub struct Destination<S> {
+    pub sink: S,
+    pub transforms: Vec<Arc<dyn Transform>>,
+}
+
+impl RoutingConfig {
+    pub fn from_json(s: &str) -> Result<Self, Error> {
+        serde_json::from_str(s).map_err(|e| Error::Parse(e.to_string()))
+    }
+
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(s: &str) -> Result<Self, Error> {
+        serde_yaml::from_str(s).map_err(|e| Error::Parse(e.to_string()))
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::Parse(e.to_string()))
+    }
+
+    /// Reads a configuration file, choosing the syntax from its extension.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(Error::Io)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => RoutingConfig::from_json(&text),
+            #[cfg(feature = "yaml")]
+            Some("yaml") | Some("yml") => RoutingConfig::from_yaml(&text),
+            #[cfg(feature = "toml")]
+            Some("toml") => RoutingConfig::from_toml(&text),
+            ext => Err(Error::UnsupportedFormat(
+                ext.unwrap_or_default().to_string(),
+            )),
+        }
+    }
+
+    /// Builds a router, resolving every sink and transform name against the
+    /// bindings.
+    ///
+    /// Routes keep their configured order within each priority. Unnamed routes are named after
+    /// their position, as with [`Router::add`].
+    pub fn build<S: Clone>(&self, bindings: &Bindings<S>) -> Result<Router<Destination<S>>, Error> {
+        let mut router = Router::new();
+        for (i, route) in self.routes.iter().enumerate() {
+            let name = route.name.clone().unwrap_or_else(|| format!("route-{}", i));
+            let sink = match bindings.sinks.get(&route.sink) {
+                Some(sink) => sink.clone(),
+                None => {
+                    return Err(Error::UnknownSink {
+                        route: name,
+                        sink: route.sink.clone(),
+                    })
+                }
+            };
+            let mut transforms = Vec::with_capacity(route.transforms.len());
+            for transform in &route.transforms {
+                match bindings.transforms.get(transform) {
+                    Some(t) => transforms.push(Arc::clone(t)),
+                    None => {
+                        return Err(Error::UnknownTransform {
+                            route: name,
+                            transform: transform.clone(),
+                        })
+                    }
+                }
+            }
+            router.add_prioritized(
+                name,
+                route.priority,
+                route.selector.clone(),
+                Destination { sink, transforms },
+            );
+        }
+        Ok(router)
+    }
+
+    /// Returns a loader that reads a configuration file and builds a router
+    /// from it, suitable for [`Reloadable::reload_from`] and
+    /// [`reload::watch`](crate::reload).
+    ///
+    /// [`Reloadable::reload_from`]: crate::reload::Reloadable::reload_from
+    pub fn loader<S: Clone>(
+        bindings: Bindings<S>,
+    ) -> impl Fn(&Path) -> Result<Router<Destination<S>>, Error> {
+        move |path| RoutingConfig::from_path(path)?.build(&bindings)
+    }
+}
+
+impl<S> Bindings<S> {
+    pub fn new() -> Self {
+        Bindings {
+            sinks: HashMap::new(),
+            transforms: HashMap::new(),
+        }
+    }
+
+    pub fn sink<N: Into<String>>(mut self, name: N, sink: S) -> Self {
+        self.sinks.insert(name.into(), sink);
+        self
+    }
+
+    pub fn transform<N: Into<String>, T: Transform + 'static>(
+        mut self,
+        name: N,
+        transform: T,
+    ) -> Self {
+        self.transforms.insert(name.into(), Arc::new(transform));
+        self
+    }
+}
+
+impl<S> Default for Bindings<S> {
+    fn default() -> Self {
+        Bindings::new()
+    }
+}
+
+impl<S> Destination<S> {
+    /// Runs the route's transforms over a packet bound for the sink.
+    pub fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        transform::apply_all(&self.transforms, packet)
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for Destination<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Destination")
+            .field("sink", &self.sink)
+            .field("transforms", &self.transforms.len())
+            .finish()
+    }
+}
+
+/// An error encountered while loading a routing configuration.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Parse(String),
+    UnsupportedFormat(String),
+    UnknownSink { route: String, sink: String },
+    UnknownTransform { route: String, transform: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "failed to read configuration: {}", e),
+            Error::Parse(e) => write!(f, "failed to parse configuration: {}", e),
+            Error::UnsupportedFormat(ext) => {
+                write!(f, "unsupported configuration format `{}`", ext)
+            }
+            Error::UnknownSink { route, sink } => {
+                write!(f, "route `{}` refers to unknown sink `{}`", route, sink)
+            }
+            Error::UnknownTransform { route, transform } => {
+                write!(
+                    f,
+                    "route `{}` refers to unknown transform `{}`",
+                    route, transform
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    const JSON: &str = r#"{
+        "routes": [
+            { "name": "cpu", "selector": "kind=cpu", "sink": "metrics", "transforms": ["strip"] },
+            { "selector": "*", "sink": "archive" }
+        ]
+    }"#;
+
+    fn bindings() -> Bindings<&'static str> {
+        Bindings::new()
+            .sink("metrics", "metrics")
+            .sink("archive", "archive")
+            .transform("strip", |mut packet: RawPacket| {
+                packet.manifest.labels.clear();
+                Ok(Some(packet))
+            })
+    }
+
+    #[test]
+    fn builds_router() {
+        let router = RoutingConfig::from_json(JSON)
+            .unwrap()
+            .build(&bindings())
+            .unwrap();
+
+        let route = router.route(&fixtures::cpu_manifest()).unwrap();
+        assert_eq!(route.name, "cpu");
+        assert_eq!(route.target.sink, "metrics");
+        let packet = route.target.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert!(packet.manifest.labels.is_empty());
+
+        let route = router.route(&fixtures::netstat_manifest()).unwrap();
+        assert_eq!(route.name, "route-1");
+        assert_eq!(route.target.sink, "archive");
+    }
+
+    #[test]
+    fn priorities() {
+        let config = RoutingConfig::from_json(
+            r#"{ "routes": [
+                { "selector": "kind=cpu", "sink": "metrics" },
+                { "name": "all", "priority": 5, "selector": "*", "sink": "archive" }
+            ] }"#,
+        )
+        .unwrap();
+        let router = config.build(&bindings()).unwrap();
+        let route = router.route(&fixtures::cpu_manifest()).unwrap();
+        assert_eq!((route.name.as_str(), route.priority), ("all", 5));
+        assert!(!serde_json::to_string(&config.routes[0])
+            .unwrap()
+            .contains("priority"));
+    }
+
+    #[test]
+    fn unknown_names() {
+        let config = RoutingConfig::from_json(JSON).unwrap();
+        let err = config
+            .build(&Bindings::new().sink("metrics", "metrics"))
+            .unwrap_err();
+        assert!(matches!(err, Error::UnknownTransform { .. }));
+
+        let err = config.build(&Bindings::<()>::new()).unwrap_err();
+        assert!(matches!(err, Error::UnknownSink { .. }));
+    }
+
+    #[test]
+    fn invalid_selector() {
+        let err = RoutingConfig::from_json(r#"{"routes": [{"selector": "kind", "sink": "x"}]}"#)
+            .unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml() {
+        let config = RoutingConfig::from_yaml(
+            "routes:\n  - selector: \"kind=netstat\"\n    sink: archive\n",
+        )
+        .unwrap();
+        assert_eq!(config.routes[0].sink, "archive");
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml() {
+        let config = RoutingConfig::from_toml(
+            "[[routes]]\nname = \"cpu\"\nselector = \"kind=cpu\"\nsink = \"metrics\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.routes[0].name.as_deref(), Some("cpu"));
+    }
+}
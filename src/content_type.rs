@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+
+use crate::{Coordinates, Manifest, Packet};
+
+/// A content type that knows its own coordinates.
+///
+/// With the `derive` feature, implement it with `#[derive(Intermodal)]`,
+/// which checks the coordinates against the [`validation`](crate::validation)
+/// rules when it compiles:
+///
+/// ```
+/// # #[cfg(feature = "derive")] {
+/// use intermodal::{ContentType, Intermodal, Packet};
+///
+/// #[derive(Intermodal)]
+/// #[intermodal(domain = "example.org", scope = "metrics/host", kind = "cpu", version = 1)]
+/// struct Cpu {
+///     idle: f64,
+/// }
+///
+/// let packet = Packet::from_content(Cpu { idle: 83.25 });
+/// assert_eq!(packet.manifest.coordinates(), Cpu::coordinates());
+/// # }
+/// ```
+pub trait ContentType {
+    const DOMAIN: &'static str;
+    const SCOPE: &'static str;
+    const KIND: &'static str;
+    const VERSION: u32;
+
+    fn coordinates() -> Coordinates {
+        Coordinates::new(Self::DOMAIN, Self::SCOPE, Self::KIND, Self::VERSION)
+    }
+}
+
+impl<T: ContentType> Packet<T> {
+    /// Wraps content in a packet whose manifest is filled in from the
+    /// content type: its coordinates, the current time as `ctime`, and the
+    /// local hostname as `origin`.
+    ///
+    /// The coordinates are taken as they are, so a hand-written
+    /// implementation should keep them [valid](Manifest::validate).
+    pub fn from_content(content: T) -> Self {
+        let manifest = Manifest {
+            domain: T::DOMAIN.to_string(),
+            scope: T::SCOPE.to_string(),
+            kind: T::KIND.to_string(),
+            version: T::VERSION,
+            ctime: Utc::now(),
+            expires: None,
+            origin: gethostname::gethostname().to_string_lossy().into_owned(),
+            labels: HashMap::new(),
+            trace: None,
+            correlation_id: None,
+            reply_to: None,
+        };
+        Packet::new(manifest, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+
+    impl ContentType for Cpu {
+        const DOMAIN: &'static str = "example.org";
+        const SCOPE: &'static str = "metrics/host";
+        const KIND: &'static str = "cpu";
+        const VERSION: u32 = 1;
+    }
+
+    #[test]
+    fn fills_manifest() {
+        let packet = Packet::from_content(Cpu {
+            user: 1.0,
+            system: 1.0,
+            idle: 98.0,
+        });
+        assert_eq!(Cpu::coordinates(), fixtures::cpu_manifest().coordinates());
+        assert!(Cpu::coordinates().matches(&packet.manifest));
+        assert!(!packet.manifest.origin.is_empty());
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn derived() {
+        use crate::Intermodal;
+
+        #[derive(Intermodal)]
+        #[intermodal(
+            domain = "example.org",
+            scope = "metrics/host",
+            kind = "netstat",
+            version = 1
+        )]
+        struct Netstat;
+
+        assert_eq!(
+            Netstat::coordinates(),
+            fixtures::netstat_manifest().coordinates()
+        );
+    }
+}
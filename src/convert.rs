@@ -0,0 +1,1014 @@
+//! Converting archives between storage formats.
+//!
+//! A [`Converter`] copies every envelope of an archive into another, in
+//! order, keeping manifests as they are:
+//!
+//! ```no_run
+//! use intermodal::convert::{ArchiveFormat, Converter};
+//!
+//! let summary = Converter::new().resume(true).convert(
+//!     "/var/lib/intermodal/2020-06.imf",
+//!     ArchiveFormat::Packfile,
+//!     "/srv/history/2020-06.sqlite",
+//!     ArchiveFormat::Sqlite,
+//! )?;
+//! println!("{} resumed, {} converted", summary.resumed, summary.converted);
+//! # Ok::<(), intermodal::convert::Error>(())
+//! ```
+//!
+//! The formats are:
+//!
+//! - [`Packfile`](ArchiveFormat::Packfile), a file of
+//!   [frames](crate::framing)
+//! - [`Ndjson`](ArchiveFormat::Ndjson), one JSON envelope per line
+//! - [`Parquet`](ArchiveFormat::Parquet), a directory of `part-NNNNNN.parquet`
+//!   files, with columns for the manifest fields, the manifest as JSON and
+//!   the content as JSON; requires the `parquet` feature
+//! - [`Sqlite`](ArchiveFormat::Sqlite), an `envelopes` table with the same
+//!   columns; requires the `sqlite` feature
+//!
+//! Only NDJSON holds whole packets; the other formats, like framing, keep
+//! manifests and content but drop signatures and provenance.
+//!
+//! The converter makes its output durable every
+//! [`batch_size`](Converter::batch_size) envelopes. A conversion that is
+//! interrupted can be resumed: with [`resume`](Converter::resume), the
+//! converter counts the envelopes already in the destination, discarding
+//! any written after the last durable batch, and carries on after as many
+//! envelopes of the source. Without it, a destination that already holds
+//! envelopes is an error, rather than something to overwrite.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::archive::Archive;
+use crate::framing::{self, FramedReader, FramedWriter};
+use crate::{Format, RawPacket};
+
+/// How an archive is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArchiveFormat {
+    Packfile,
+    Ndjson,
+    Parquet,
+    Sqlite,
+}
+
+impl ArchiveFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArchiveFormat::Packfile => "packfile",
+            ArchiveFormat::Ndjson => "ndjson",
+            ArchiveFormat::Parquet => "parquet",
+            ArchiveFormat::Sqlite => "sqlite",
+        }
+    }
+
+    /// Guesses the format from a path's extension: `imf`, `ndjson` or
+    /// `jsonl`, `parquet`, and `sqlite` or `db`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "imf" => Some(ArchiveFormat::Packfile),
+            "ndjson" | "jsonl" => Some(ArchiveFormat::Ndjson),
+            "parquet" => Some(ArchiveFormat::Parquet),
+            "sqlite" | "db" => Some(ArchiveFormat::Sqlite),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "packfile" => Ok(ArchiveFormat::Packfile),
+            "ndjson" => Ok(ArchiveFormat::Ndjson),
+            "parquet" => Ok(ArchiveFormat::Parquet),
+            "sqlite" => Ok(ArchiveFormat::Sqlite),
+            other => Err(Error::Format(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Frame(framing::Error),
+    /// The format is not one of [`ArchiveFormat`].
+    Format(String),
+    /// The format needs a feature this build lacks.
+    Unsupported(&'static str),
+    /// The destination already holds envelopes, and the conversion is not
+    /// resuming.
+    NotEmpty(PathBuf),
+    /// The destination cannot be resumed, as when a packfile is damaged
+    /// before its end.
+    Resume(String),
+    /// An error from the Parquet or SQLite library.
+    Backend(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Frame(e) => write!(f, "{}", e),
+            Error::Format(format) => write!(f, "unknown archive format `{}`", format),
+            Error::Unsupported(feature) => {
+                write!(f, "the archive format requires the `{}` feature", feature)
+            }
+            Error::NotEmpty(path) => write!(
+                f,
+                "{} already holds envelopes; resume the conversion or remove it",
+                path.display()
+            ),
+            Error::Resume(message) => write!(f, "cannot resume: {}", message),
+            Error::Backend(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Frame(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<framing::Error> for Error {
+    fn from(e: framing::Error) -> Self {
+        match e {
+            framing::Error::Io(e) => Error::Io(e),
+            e => Error::Frame(e),
+        }
+    }
+}
+
+/// What a conversion did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// The envelopes already in the destination, and skipped in the source.
+    pub resumed: u64,
+    /// The envelopes converted.
+    pub converted: u64,
+}
+
+/// Converts archives from one format to another.
+#[derive(Debug, Clone)]
+pub struct Converter {
+    resume: bool,
+    batch_size: u64,
+    checksums: bool,
+}
+
+impl Default for Converter {
+    fn default() -> Self {
+        Converter {
+            resume: false,
+            batch_size: 10_000,
+            checksums: false,
+        }
+    }
+}
+
+impl Converter {
+    pub fn new() -> Self {
+        Converter::default()
+    }
+
+    /// Carries on from the envelopes already in the destination.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Sets how many envelopes are written between making the output
+    /// durable, and how many rows each Parquet part holds. Defaults to
+    /// 10,000.
+    ///
+    /// # Panics
+    ///
+    /// If `batch_size` is zero.
+    pub fn batch_size(mut self, batch_size: u64) -> Self {
+        assert!(batch_size > 0, "batch size must not be zero");
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Writes checksummed frames to packfiles; see
+    /// [`framing`](crate::framing).
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Copies the envelopes of the archive at `from` to the archive at
+    /// `to`.
+    pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        from_format: ArchiveFormat,
+        to: Q,
+        to_format: ArchiveFormat,
+    ) -> Result<Summary, Error> {
+        let (mut sink, resumed) = self.sink(to.as_ref(), to_format)?;
+        let source = source(from.as_ref(), from_format, resumed)?;
+        let mut summary = Summary {
+            resumed,
+            converted: 0,
+        };
+        for packet in source {
+            sink.write(&packet?)?;
+            summary.converted += 1;
+            if summary.converted.is_multiple_of(self.batch_size) {
+                sink.commit()?;
+            }
+        }
+        sink.commit()?;
+        Ok(summary)
+    }
+
+    fn sink(&self, path: &Path, format: ArchiveFormat) -> Result<(Box<dyn Sink>, u64), Error> {
+        match format {
+            ArchiveFormat::Packfile => packfile_sink(path, self.resume, self.checksums),
+            ArchiveFormat::Ndjson => ndjson_sink(path, self.resume),
+            ArchiveFormat::Parquet => parquet::sink(path, self.resume, self.batch_size),
+            ArchiveFormat::Sqlite => sqlite::sink(path, self.resume),
+        }
+    }
+}
+
+type Source = Box<dyn Iterator<Item = Result<RawPacket, Error>>>;
+
+/// Where converted envelopes go.
+trait Sink {
+    fn write(&mut self, packet: &RawPacket) -> Result<(), Error>;
+
+    /// Makes everything written so far durable.
+    fn commit(&mut self) -> Result<(), Error>;
+}
+
+/// Reads the archive at `path`, after its first `skip` envelopes.
+fn source(path: &Path, format: ArchiveFormat, skip: u64) -> Result<Source, Error> {
+    let source: Source = match format {
+        ArchiveFormat::Packfile => {
+            let reader = FramedReader::new(BufReader::new(File::open(path)?));
+            Box::new(reader.map(|frame| Ok(frame?.decode()?)))
+        }
+        ArchiveFormat::Ndjson => {
+            let lines = BufReader::new(File::open(path)?).lines();
+            Box::new(lines.filter_map(|line| match line {
+                Ok(line) if line.trim().is_empty() => None,
+                Ok(line) => Some(serde_json::from_str(&line).map_err(Error::from)),
+                Err(e) => Some(Err(e.into())),
+            }))
+        }
+        ArchiveFormat::Parquet => return parquet::source(path, skip),
+        ArchiveFormat::Sqlite => return sqlite::source(path, skip),
+    };
+    let skip = usize::try_from(skip).unwrap_or(usize::MAX);
+    Ok(Box::new(source.skip(skip)))
+}
+
+/// Opens a file to append to, after cutting it to `keep` bytes. Unless
+/// resuming, the file must be empty or absent.
+fn open_file(path: &Path, resume: bool, keep: u64) -> Result<File, Error> {
+    if !resume && path.metadata().is_ok_and(|m| m.len() > 0) {
+        return Err(Error::NotEmpty(path.to_path_buf()));
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.set_len(keep)?;
+    Ok(file)
+}
+
+struct PackfileSink {
+    writer: FramedWriter<BufWriter<File>>,
+}
+
+fn packfile_sink(
+    path: &Path,
+    resume: bool,
+    checksums: bool,
+) -> Result<(Box<dyn Sink>, u64), Error> {
+    let (mut existing, mut keep) = (0, 0);
+    if resume && path.exists() {
+        let integrity = Archive::open(path)?.verify()?;
+        if let Some(damage) = integrity.damaged.first() {
+            return Err(Error::Resume(format!(
+                "{} is damaged at {}..{}: {}",
+                path.display(),
+                damage.range.start,
+                damage.range.end,
+                damage.error
+            )));
+        }
+        existing = integrity.frames;
+        keep = match integrity.torn {
+            Some(torn) => torn,
+            None => path.metadata()?.len(),
+        };
+    }
+    let file = open_file(path, resume, keep)?;
+    let writer = FramedWriter::new(BufWriter::new(file)).checksums(checksums);
+    Ok((Box::new(PackfileSink { writer }), existing))
+}
+
+impl Sink for PackfileSink {
+    fn write(&mut self, packet: &RawPacket) -> Result<(), Error> {
+        Ok(self.writer.write_packet(packet, Format::Json)?)
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        self.writer.get_ref().get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+struct NdjsonSink {
+    file: BufWriter<File>,
+}
+
+fn ndjson_sink(path: &Path, resume: bool) -> Result<(Box<dyn Sink>, u64), Error> {
+    let (mut existing, mut keep) = (0, 0);
+    if resume && path.exists() {
+        // Count the complete lines, and cut off a line left partly written.
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            keep += n as u64;
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                existing += 1;
+            }
+        }
+    }
+    let file = open_file(path, resume, keep)?;
+    Ok((
+        Box::new(NdjsonSink {
+            file: BufWriter::new(file),
+        }),
+        existing,
+    ))
+}
+
+impl Sink for NdjsonSink {
+    fn write(&mut self, packet: &RawPacket) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.file, packet)?;
+        self.file.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// The manifest fields given columns of their own, for querying.
+#[cfg(any(feature = "parquet", feature = "sqlite"))]
+struct Row {
+    domain: String,
+    scope: String,
+    kind: String,
+    version: u32,
+    ctime: chrono::DateTime<chrono::Utc>,
+    origin: String,
+    manifest: String,
+    content: String,
+}
+
+#[cfg(any(feature = "parquet", feature = "sqlite"))]
+impl Row {
+    fn new(packet: &RawPacket) -> Result<Self, Error> {
+        let manifest = &packet.manifest;
+        Ok(Row {
+            domain: manifest.domain.clone(),
+            scope: manifest.scope.clone(),
+            kind: manifest.kind.clone(),
+            version: manifest.version,
+            ctime: manifest.ctime,
+            origin: manifest.origin.clone(),
+            manifest: serde_json::to_string(manifest)?,
+            content: serde_json::to_string(&packet.content)?,
+        })
+    }
+}
+
+#[cfg(any(feature = "parquet", feature = "sqlite"))]
+fn packet(manifest: &str, content: &str) -> Result<RawPacket, Error> {
+    Ok(RawPacket::new(
+        serde_json::from_str(manifest)?,
+        serde_json::from_str(content)?,
+    ))
+}
+
+#[cfg(feature = "parquet")]
+mod parquet {
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use ::parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use ::parquet::file::properties::WriterProperties;
+    use ::parquet::file::reader::{FileReader, SerializedFileReader};
+    use ::parquet::file::writer::SerializedFileWriter;
+    use ::parquet::file::writer::SerializedRowGroupWriter;
+    use ::parquet::record::reader::RowIter;
+    use ::parquet::record::RowAccessor;
+    use ::parquet::schema::parser::parse_message_type;
+
+    use super::{packet, Error, Row, Sink, Source};
+
+    const SCHEMA: &str = "message envelope {
+        required int64 seq;
+        required binary domain (UTF8);
+        required binary scope (UTF8);
+        required binary kind (UTF8);
+        required int64 version;
+        required int64 ctime (TIMESTAMP(MICROS,true));
+        required binary origin (UTF8);
+        required binary manifest (UTF8);
+        required binary content (UTF8);
+    }";
+
+    /// The complete parts of the archive at `dir`, in order, after
+    /// removing any left partly written.
+    fn parts(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut parts = Vec::new();
+        if !dir.exists() {
+            return Ok(parts);
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with(".part-") && name.ends_with(".tmp") {
+                fs::remove_file(&path)?;
+            } else if name.starts_with("part-") && name.ends_with(".parquet") {
+                parts.push(path);
+            }
+        }
+        parts.sort_unstable();
+        Ok(parts)
+    }
+
+    fn rows(path: &Path) -> Result<u64, Error> {
+        let reader = SerializedFileReader::new(File::open(path)?).map_err(backend_error)?;
+        Ok(reader.metadata().file_metadata().num_rows() as u64)
+    }
+
+    pub(super) fn source(dir: &Path, mut skip: u64) -> Result<Source, Error> {
+        // Skip whole parts by their row counts, and open the rest as they
+        // are reached.
+        let mut wanted = Vec::new();
+        for part in parts(dir)? {
+            let rows = rows(&part)?;
+            if skip >= rows {
+                skip -= rows;
+            } else {
+                wanted.push((part, std::mem::take(&mut skip)));
+            }
+        }
+        Ok(Box::new(wanted.into_iter().flat_map(|(part, skip)| {
+            let rows = File::open(&part)
+                .map_err(Error::from)
+                .and_then(|file| SerializedFileReader::new(file).map_err(backend_error));
+            match rows {
+                Ok(reader) => Box::new(
+                    RowIter::from_file_into(Box::new(reader))
+                        .skip(skip as usize)
+                        .map(|row| {
+                            let row = row.map_err(backend_error)?;
+                            let manifest = row.get_string(7).map_err(backend_error)?;
+                            let content = row.get_string(8).map_err(backend_error)?;
+                            packet(manifest, content)
+                        }),
+                ) as Source,
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        })))
+    }
+
+    struct ParquetSink {
+        dir: PathBuf,
+        next_part: usize,
+        seq: i64,
+        rows: Vec<Row>,
+        part_rows: u64,
+    }
+
+    pub(super) fn sink(
+        dir: &Path,
+        resume: bool,
+        part_rows: u64,
+    ) -> Result<(Box<dyn Sink>, u64), Error> {
+        let parts = parts(dir)?;
+        let mut existing = 0;
+        for part in &parts {
+            existing += rows(part)?;
+        }
+        if !resume && !parts.is_empty() {
+            return Err(Error::NotEmpty(dir.to_path_buf()));
+        }
+        fs::create_dir_all(dir)?;
+        Ok((
+            Box::new(ParquetSink {
+                dir: dir.to_path_buf(),
+                next_part: parts.len(),
+                seq: existing as i64,
+                rows: Vec::new(),
+                part_rows,
+            }),
+            existing,
+        ))
+    }
+
+    impl Sink for ParquetSink {
+        fn write(&mut self, packet: &crate::RawPacket) -> Result<(), Error> {
+            self.rows.push(Row::new(packet)?);
+            if self.rows.len() as u64 >= self.part_rows {
+                self.commit()?;
+            }
+            Ok(())
+        }
+
+        /// Writes the rows so far as a part, under a temporary name that
+        /// it loses once it is complete.
+        fn commit(&mut self) -> Result<(), Error> {
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+            let name = format!("part-{:06}.parquet", self.next_part);
+            let partial = self.dir.join(format!(".{}.tmp", name));
+            let schema = Arc::new(parse_message_type(SCHEMA).map_err(backend_error)?);
+            let props = Arc::new(WriterProperties::builder().build());
+            let mut writer = SerializedFileWriter::new(File::create(&partial)?, schema, props)
+                .map_err(backend_error)?;
+            let mut group = writer.next_row_group().map_err(backend_error)?;
+
+            let rows = std::mem::take(&mut self.rows);
+            let strings = |f: fn(&Row) -> &str| -> Vec<ByteArray> {
+                rows.iter().map(|r| ByteArray::from(f(r))).collect()
+            };
+            let seq: Vec<i64> = (self.seq..).take(rows.len()).collect();
+            write_int64s(&mut group, &seq)?;
+            write_strings(&mut group, &strings(|r| r.domain.as_str()))?;
+            write_strings(&mut group, &strings(|r| r.scope.as_str()))?;
+            write_strings(&mut group, &strings(|r| r.kind.as_str()))?;
+            let version: Vec<i64> = rows.iter().map(|r| i64::from(r.version)).collect();
+            write_int64s(&mut group, &version)?;
+            let ctime: Vec<i64> = rows.iter().map(|r| r.ctime.timestamp_micros()).collect();
+            write_int64s(&mut group, &ctime)?;
+            write_strings(&mut group, &strings(|r| r.origin.as_str()))?;
+            write_strings(&mut group, &strings(|r| r.manifest.as_str()))?;
+            write_strings(&mut group, &strings(|r| r.content.as_str()))?;
+            group.close().map_err(backend_error)?;
+            writer.close().map_err(backend_error)?;
+            File::open(&partial)?.sync_all()?;
+            fs::rename(&partial, self.dir.join(name))?;
+
+            self.seq += rows.len() as i64;
+            self.next_part += 1;
+            Ok(())
+        }
+    }
+
+    fn write_int64s(
+        group: &mut SerializedRowGroupWriter<'_, File>,
+        values: &[i64],
+    ) -> Result<(), Error> {
+        let mut column = group
+            .next_column()
+            .map_err(backend_error)?
+            .expect("the schema has another column");
+        column
+            .typed::<Int64Type>()
+            .write_batch(values, None, None)
+            .map_err(backend_error)?;
+        column.close().map_err(backend_error)
+    }
+
+    fn write_strings(
+        group: &mut SerializedRowGroupWriter<'_, File>,
+        values: &[ByteArray],
+    ) -> Result<(), Error> {
+        let mut column = group
+            .next_column()
+            .map_err(backend_error)?
+            .expect("the schema has another column");
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(values, None, None)
+            .map_err(backend_error)?;
+        column.close().map_err(backend_error)
+    }
+
+    fn backend_error(e: ::parquet::errors::ParquetError) -> Error {
+        Error::Backend(e.to_string())
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+mod parquet {
+    use std::path::Path;
+
+    use super::{Error, Sink, Source};
+
+    pub(super) fn source(_: &Path, _: u64) -> Result<Source, Error> {
+        Err(Error::Unsupported("parquet"))
+    }
+
+    pub(super) fn sink(_: &Path, _: bool, _: u64) -> Result<(Box<dyn Sink>, u64), Error> {
+        Err(Error::Unsupported("parquet"))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::collections::VecDeque;
+    use std::path::Path;
+
+    use rusqlite::{params, Connection, OpenFlags};
+
+    use super::{packet, Error, Row, Sink, Source};
+
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS envelopes (
+        seq INTEGER PRIMARY KEY,
+        domain TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        ctime TEXT NOT NULL,
+        origin TEXT NOT NULL,
+        manifest TEXT NOT NULL,
+        content TEXT NOT NULL
+    )";
+
+    /// Rows are read a page at a time, so that no statement outlives a
+    /// call to `next`.
+    const PAGE: i64 = 1000;
+
+    struct SqliteSource {
+        connection: Connection,
+        after: Option<i64>,
+        page: VecDeque<(i64, String, String)>,
+        done: bool,
+    }
+
+    pub(super) fn source(path: &Path, skip: u64) -> Result<Source, Error> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(backend_error)?;
+        // Skip to the sequence number before the first envelope wanted.
+        let after = match skip {
+            0 => None,
+            skip => connection
+                .query_row(
+                    "SELECT seq FROM envelopes ORDER BY seq LIMIT 1 OFFSET ?1",
+                    params![skip as i64 - 1],
+                    |row| row.get(0),
+                )
+                .map(Some)
+                .or_else(|e| match e {
+                    rusqlite::Error::QueryReturnedNoRows => Ok(Some(i64::MAX)),
+                    e => Err(backend_error(e)),
+                })?,
+        };
+        Ok(Box::new(SqliteSource {
+            connection,
+            after,
+            page: VecDeque::new(),
+            done: false,
+        }))
+    }
+
+    impl SqliteSource {
+        fn fill(&mut self) -> Result<(), Error> {
+            let mut statement = self
+                .connection
+                .prepare_cached(
+                    "SELECT seq, manifest, content FROM envelopes
+                     WHERE seq > ?1 ORDER BY seq LIMIT ?2",
+                )
+                .map_err(backend_error)?;
+            let rows = statement
+                .query_map(params![self.after.unwrap_or(i64::MIN), PAGE], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .map_err(backend_error)?;
+            for row in rows {
+                self.page.push_back(row.map_err(backend_error)?);
+            }
+            self.done = (self.page.len() as i64) < PAGE;
+            Ok(())
+        }
+    }
+
+    impl Iterator for SqliteSource {
+        type Item = Result<crate::RawPacket, Error>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.page.is_empty() && !self.done {
+                if let Err(e) = self.fill() {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+            let (seq, manifest, content) = self.page.pop_front()?;
+            self.after = Some(seq);
+            Some(packet(&manifest, &content))
+        }
+    }
+
+    struct SqliteSink {
+        connection: Connection,
+        seq: i64,
+        rows: Vec<Row>,
+    }
+
+    pub(super) fn sink(path: &Path, resume: bool) -> Result<(Box<dyn Sink>, u64), Error> {
+        let connection = Connection::open(path).map_err(backend_error)?;
+        connection.execute(SCHEMA, []).map_err(backend_error)?;
+        let (existing, last): (i64, Option<i64>) = connection
+            .query_row("SELECT COUNT(*), MAX(seq) FROM envelopes", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .map_err(backend_error)?;
+        if !resume && existing > 0 {
+            return Err(Error::NotEmpty(path.to_path_buf()));
+        }
+        Ok((
+            Box::new(SqliteSink {
+                connection,
+                seq: last.map_or(0, |last| last + 1),
+                rows: Vec::new(),
+            }),
+            existing as u64,
+        ))
+    }
+
+    impl Sink for SqliteSink {
+        fn write(&mut self, packet: &crate::RawPacket) -> Result<(), Error> {
+            self.rows.push(Row::new(packet)?);
+            Ok(())
+        }
+
+        /// Inserts the rows so far in one transaction.
+        fn commit(&mut self) -> Result<(), Error> {
+            let transaction = self.connection.transaction().map_err(backend_error)?;
+            {
+                let mut insert = transaction
+                    .prepare_cached(
+                        "INSERT INTO envelopes
+                         (seq, domain, scope, kind, version, ctime, origin, manifest, content)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    )
+                    .map_err(backend_error)?;
+                for row in self.rows.drain(..) {
+                    insert
+                        .execute(params![
+                            self.seq,
+                            row.domain,
+                            row.scope,
+                            row.kind,
+                            row.version,
+                            row.ctime
+                                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+                            row.origin,
+                            row.manifest,
+                            row.content,
+                        ])
+                        .map_err(backend_error)?;
+                    self.seq += 1;
+                }
+            }
+            transaction.commit().map_err(backend_error)
+        }
+    }
+
+    fn backend_error(e: rusqlite::Error) -> Error {
+        Error::Backend(e.to_string())
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod sqlite {
+    use std::path::Path;
+
+    use super::{Error, Sink, Source};
+
+    pub(super) fn source(_: &Path, _: u64) -> Result<Source, Error> {
+        Err(Error::Unsupported("sqlite"))
+    }
+
+    pub(super) fn sink(_: &Path, _: bool) -> Result<(Box<dyn Sink>, u64), Error> {
+        Err(Error::Unsupported("sqlite"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "intermodal-convert-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn packets(n: usize) -> Vec<RawPacket> {
+        (0..n)
+            .map(|i| {
+                let mut packet = if i % 2 == 0 {
+                    fixtures::cpu_raw()
+                } else {
+                    fixtures::netstat_raw()
+                };
+                packet
+                    .manifest
+                    .labels
+                    .insert("seq".to_string(), i.to_string());
+                packet
+            })
+            .collect()
+    }
+
+    fn read(path: &Path, format: ArchiveFormat) -> Vec<RawPacket> {
+        source(path, format, 0)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn write_ndjson(path: &Path, packets: &[RawPacket]) {
+        let mut file = File::create(path).unwrap();
+        for packet in packets {
+            serde_json::to_writer(&mut file, packet).unwrap();
+            file.write_all(b"\n").unwrap();
+        }
+    }
+
+    /// Converts NDJSON to `format` and back, resuming a conversion cut
+    /// short.
+    fn round_trip(format: ArchiveFormat, dest: &str) {
+        let dir = dir(format.name());
+        let input = dir.join("in.ndjson");
+        let output = dir.join(dest);
+        let back = dir.join("back.ndjson");
+        let packets = packets(25);
+        write_ndjson(&input, &packets[..10]);
+
+        let converter = Converter::new().batch_size(4);
+        let summary = converter
+            .convert(&input, ArchiveFormat::Ndjson, &output, format)
+            .unwrap();
+        assert_eq!(summary.converted, 10);
+        assert!(matches!(
+            converter.convert(&input, ArchiveFormat::Ndjson, &output, format),
+            Err(Error::NotEmpty(_))
+        ));
+
+        write_ndjson(&input, &packets);
+        let summary = converter
+            .clone()
+            .resume(true)
+            .convert(&input, ArchiveFormat::Ndjson, &output, format)
+            .unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                resumed: 10,
+                converted: 15
+            }
+        );
+
+        converter
+            .convert(&output, format, &back, ArchiveFormat::Ndjson)
+            .unwrap();
+        assert_eq!(read(&back, ArchiveFormat::Ndjson), packets);
+        // Reading resumes partway too.
+        let rest: Vec<_> = source(&output, format, 23)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rest, packets[23..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn converts_packfiles() {
+        round_trip(ArchiveFormat::Packfile, "out.imf");
+    }
+
+    #[test]
+    fn converts_ndjson() {
+        round_trip(ArchiveFormat::Ndjson, "out.ndjson");
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn converts_parquet() {
+        round_trip(ArchiveFormat::Parquet, "out.parquet");
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn converts_sqlite() {
+        round_trip(ArchiveFormat::Sqlite, "out.sqlite");
+    }
+
+    #[test]
+    fn discards_partial_writes_on_resume() {
+        let dir = dir("torn");
+        let input = dir.join("in.ndjson");
+        let packets = packets(3);
+        write_ndjson(&input, &packets);
+
+        // A packfile and an NDJSON file each holding one envelope and part
+        // of the next.
+        let packfile = dir.join("out.imf");
+        let mut writer = FramedWriter::new(Vec::new());
+        writer.write_packet(&packets[0], Format::Json).unwrap();
+        writer.write_packet(&packets[1], Format::Json).unwrap();
+        let bytes = writer.into_inner();
+        fs::write(&packfile, &bytes[..bytes.len() - 3]).unwrap();
+        let ndjson = dir.join("out.ndjson");
+        let line = serde_json::to_string(&packets[0]).unwrap();
+        fs::write(&ndjson, format!("{}\n{{\"manifest\":", line)).unwrap();
+
+        let converter = Converter::new().resume(true);
+        for (path, format) in [
+            (&packfile, ArchiveFormat::Packfile),
+            (&ndjson, ArchiveFormat::Ndjson),
+        ] {
+            let summary = converter
+                .convert(&input, ArchiveFormat::Ndjson, path, format)
+                .unwrap();
+            assert_eq!(summary.resumed, 1, "{}", format);
+            assert_eq!(read(path, format), packets, "{}", format);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_formats() {
+        assert_eq!(
+            ArchiveFormat::from_path("2020-06.imf"),
+            Some(ArchiveFormat::Packfile)
+        );
+        assert_eq!(
+            ArchiveFormat::from_path("history.db"),
+            Some(ArchiveFormat::Sqlite)
+        );
+        assert_eq!(
+            "parquet".parse::<ArchiveFormat>().unwrap(),
+            ArchiveFormat::Parquet
+        );
+        assert!(matches!(
+            "csv".parse::<ArchiveFormat>(),
+            Err(Error::Format(_))
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Manifest;
+
+/// The fields of a [`Manifest`] that identify the type of its content.
+///
+/// Coordinates have a canonical string form, the domain, scope and kind
+/// joined by slashes, followed by `@` and the version:
+///
+/// ```
+/// # use intermodal::Coordinates;
+/// let coordinates: Coordinates = "example.org/metrics/applications/some-app/useractions@2"
+///     .parse()
+///     .unwrap();
+/// assert_eq!(coordinates.scope, "metrics/applications/some-app");
+/// assert_eq!(coordinates.kind, "useractions");
+/// assert_eq!(coordinates.version, 2);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Coordinates {
+    pub domain: String,
+    pub scope: String,
+    pub kind: String,
+    pub version: u32,
+}
+
+impl Coordinates {
+    pub fn new<D, S, K>(domain: D, scope: S, kind: K, version: u32) -> Self
+    where
+        D: Into<String>,
+        S: Into<String>,
+        K: Into<String>,
+    {
+        Coordinates {
+            domain: domain.into(),
+            scope: scope.into(),
+            kind: kind.into(),
+            version,
+        }
+    }
+
+    /// Returns whether the manifest describes content of this type.
+    pub fn matches(&self, manifest: &Manifest) -> bool {
+        self.domain == manifest.domain
+            && self.scope == manifest.scope
+            && self.kind == manifest.kind
+            && self.version == manifest.version
+    }
+}
+
+impl Manifest {
+    /// Returns the coordinates identifying the type of the content.
+    pub fn coordinates(&self) -> Coordinates {
+        Coordinates {
+            domain: self.domain.clone(),
+            scope: self.scope.clone(),
+            kind: self.kind.clone(),
+            version: self.version,
+        }
+    }
+}
+
+impl From<&Manifest> for Coordinates {
+    fn from(manifest: &Manifest) -> Self {
+        manifest.coordinates()
+    }
+}
+
+impl fmt::Display for Coordinates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}@{}",
+            self.domain, self.scope, self.kind, self.version
+        )
+    }
+}
+
+impl FromStr for Coordinates {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |message: &str| ParseError {
+            input: s.to_string(),
+            message: message.to_string(),
+        };
+
+        let at = s.rfind('@').ok_or_else(|| error("missing `@version`"))?;
+        let version = s[at + 1..].parse().map_err(|_| error("invalid version"))?;
+        let path = &s[..at];
+
+        let slash = path
+            .find('/')
+            .ok_or_else(|| error("missing scope and kind"))?;
+        let last = path.rfind('/').unwrap_or(slash);
+        if last == slash {
+            return Err(error("missing scope"));
+        }
+        let (domain, scope, kind) = (&path[..slash], &path[slash + 1..last], &path[last + 1..]);
+        if domain.is_empty() || kind.is_empty() || scope.split('/').any(str::is_empty) {
+            return Err(error("empty path segment"));
+        }
+        Ok(Coordinates::new(domain, scope, kind, version))
+    }
+}
+
+/// An error parsing the canonical string form of [`Coordinates`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    input: String,
+    message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid coordinates `{}`: {}", self.input, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn display_round_trips() {
+        let coordinates = fixtures::cpu_manifest().coordinates();
+        assert_eq!(coordinates.to_string(), "example.org/metrics/host/cpu@1");
+        assert_eq!(
+            coordinates.to_string().parse::<Coordinates>().unwrap(),
+            coordinates
+        );
+        assert!(coordinates.matches(&fixtures::cpu_manifest()));
+        assert!(!coordinates.matches(&fixtures::netstat_manifest()));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for s in &[
+            "example.org/metrics/cpu",
+            "example.org/metrics/cpu@",
+            "example.org/metrics/cpu@v1",
+            "example.org/cpu@1",
+            "example.org@1",
+            "/metrics/cpu@1",
+            "example.org/metrics/@1",
+            "example.org/metrics//cpu@1",
+        ] {
+            assert!(s.parse::<Coordinates>().is_err(), "{}", s);
+        }
+    }
+}
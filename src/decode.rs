@@ -0,0 +1,816 @@
+//! Deserializing envelopes from each supported format.
+//!
+//! The counterpart to the [`encode`](crate::encode) module. Where envelopes
+//! arrive in a mix of formats, [`sniff`] tells them apart by their first
+//! bytes, and [`Packet::from_bytes_auto`] decodes whatever it finds:
+//!
+//! ```
+//! use intermodal::{Format, RawPacket};
+//!
+//! let bytes = br#"{
+//!     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//!                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//!     "content": 86400
+//! }"#;
+//! let (packet, format) = RawPacket::from_bytes_auto(bytes).unwrap();
+//! assert_eq!(format, Format::Json);
+//! assert_eq!(packet.content, 86400);
+//! ```
+//!
+//! Decoding is lenient by default: fields an envelope or its manifest has
+//! that this build does not know are ignored, so that readers keep working
+//! as producers adopt later versions of the envelope. An ingestion gate
+//! that should hold producers to the envelope as it is decodes with
+//! [`DecodeOptions::strict`] instead, rejecting unknown fields and empty
+//! required ones:
+//!
+//! ```
+//! use intermodal::decode::DecodeOptions;
+//! use intermodal::{Format, RawPacket};
+//!
+//! let bytes = br#"{
+//!     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//!                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "",
+//!                   "priority": "high" },
+//!     "content": 86400
+//! }"#;
+//! assert!(RawPacket::from_bytes(bytes, Format::Json).is_ok());
+//!
+//! let error = DecodeOptions::strict().decode::<serde_json::Value>(bytes, Format::Json).unwrap_err();
+//! assert_eq!(
+//!     error.to_string(),
+//!     "decoding json: unknown field `manifest.priority`; `manifest.origin` is empty"
+//! );
+//! ```
+//!
+//! Consumers of untrusted input can also bound what they decode, in either
+//! mode. [`DecodeOptions::bounded`] sets limits on the size of the manifest
+//! and content and on the number and length of labels; an envelope over any
+//! of them fails with an error whose [`limit`](Error::limit) says which:
+//!
+//! ```
+//! use intermodal::decode::{DecodeOptions, Limit};
+//! use intermodal::{Format, Manifest, RawPacket};
+//!
+//! let options = DecodeOptions {
+//!     max_content_bytes: Some(1024),
+//!     ..DecodeOptions::bounded()
+//! };
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("logs")
+//!     .kind("blob")
+//!     .version(1)
+//!     .origin("host01")
+//!     .build()?;
+//! let packet = RawPacket::new(manifest, serde_json::json!("x".repeat(2048)));
+//! let bytes = packet.to_bytes(Format::Json)?;
+//! let error = options.decode::<serde_json::Value>(&bytes, Format::Json).unwrap_err();
+//! assert_eq!(error.limit(), Some(Limit::ContentBytes));
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::io;
+
+use serde::de::{self, DeserializeOwned, Deserializer, IgnoredAny, MapAccess, SeqAccess};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{compression, Format, Header, Packet, RawPacket};
+
+/// Deserializes an envelope, such as a [`Packet`] or a
+/// [`Header`](crate::Header), from the given format.
+pub fn from_slice<D: DeserializeOwned>(bytes: &[u8], format: Format) -> Result<D, Error> {
+    let error = |e: &dyn fmt::Display| Error {
+        format: Some(format),
+        message: e.to_string(),
+        limit: None,
+    };
+    match format {
+        Format::Json => serde_json::from_slice(bytes).map_err(|e| error(&e)),
+        #[cfg(feature = "yaml")]
+        Format::Yaml => serde_yaml::from_slice(bytes).map_err(|e| error(&e)),
+        #[cfg(feature = "toml")]
+        Format::Toml => {
+            let s = std::str::from_utf8(bytes).map_err(|e| error(&e))?;
+            toml::from_str(s).map_err(|e| error(&e))
+        }
+        #[cfg(feature = "xml")]
+        Format::Xml => {
+            let s = std::str::from_utf8(bytes).map_err(|e| error(&e))?;
+            crate::xml::from_str(s).map_err(|e| error(&e))
+        }
+        #[cfg(feature = "cbor")]
+        Format::Cbor => ciborium::de::from_reader(bytes).map_err(|e| error(&e)),
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| error(&e)),
+    }
+}
+
+/// Guesses the format of an encoded envelope from its leading bytes, among
+/// the formats enabled in this build.
+///
+/// Binary formats are told apart by the type of their top-level value, which
+/// for an envelope is a map: in CBOR that starts with a byte from `0xa0` to
+/// `0xbf` (or with the self-describe tag), and in MessagePack with a byte
+/// from `0x80` to `0x8f`, `0xde` or `0xdf`. Text formats are told apart by
+/// their first significant line, skipping blank lines and `#` comments: JSON
+/// starts with `{`, XML with `<`, TOML with a `[table]` header or a
+/// `key = value` pair, and YAML with `---` or a `key:` pair.
+///
+/// The guess only looks at the start of the input, so it may name a format
+/// the input then fails to decode in.
+pub fn sniff(bytes: &[u8]) -> Option<Format> {
+    if bytes.is_empty() {
+        return None;
+    }
+    #[cfg(feature = "cbor")]
+    {
+        if (0xa0..=0xbf).contains(&bytes[0]) || bytes.starts_with(&[0xd9, 0xd9, 0xf7]) {
+            return Some(Format::Cbor);
+        }
+    }
+    #[cfg(feature = "msgpack")]
+    {
+        if (0x80..=0x8f).contains(&bytes[0]) || bytes[0] == 0xde || bytes[0] == 0xdf {
+            return Some(Format::MessagePack);
+        }
+    }
+
+    let text = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    let line = text
+        .split(|&b| b == b'\n')
+        .map(|line| line.trim_ascii())
+        .find(|line| !line.is_empty() && !line.starts_with(b"#"))?;
+    match line[0] {
+        b'{' => return Some(Format::Json),
+        #[cfg(feature = "xml")]
+        b'<' => return Some(Format::Xml),
+        _ => {}
+    }
+    #[cfg(feature = "toml")]
+    {
+        let key = line
+            .iter()
+            .position(|&b| b == b'=')
+            .map(|end| line[..end].trim_ascii());
+        let is_key = |key: &[u8]| {
+            !key.is_empty()
+                && key
+                    .iter()
+                    .all(|&b| b.is_ascii_alphanumeric() || b"_-.\"' ".contains(&b))
+        };
+        if (line[0] == b'[' && line.ends_with(b"]")) || key.is_some_and(is_key) {
+            return Some(Format::Toml);
+        }
+    }
+    #[cfg(feature = "yaml")]
+    {
+        if line.starts_with(b"---") || line.contains(&b':') {
+            return Some(Format::Yaml);
+        }
+    }
+    None
+}
+
+/// Deserializes an envelope from the format [`sniff`] finds, returning the
+/// envelope and the format.
+pub fn from_slice_auto<D: DeserializeOwned>(bytes: &[u8]) -> Result<(D, Format), Error> {
+    let format = sniff(bytes).ok_or_else(|| Error {
+        format: None,
+        message: "unrecognized format".to_string(),
+        limit: None,
+    })?;
+    Ok((from_slice(bytes, format)?, format))
+}
+
+impl<T: DeserializeOwned> Packet<T> {
+    /// Deserializes a packet from the given format, decompressing
+    /// [compressed](crate::compression) content.
+    pub fn from_bytes(bytes: &[u8], format: Format) -> Result<Self, Error> {
+        decompressed(bytes, format, from_slice(bytes, format), None)
+    }
+
+    /// Deserializes a packet from the format [`sniff`] finds, returning the
+    /// packet and the format.
+    pub fn from_bytes_auto(bytes: &[u8]) -> Result<(Self, Format), Error> {
+        let (packet, format) = match from_slice_auto(bytes) {
+            Ok((packet, format)) => (Ok(packet), format),
+            Err(e) => match e.format {
+                Some(format) => (Err(e), format),
+                None => return Err(e),
+            },
+        };
+        Ok((decompressed(bytes, format, packet, None)?, format))
+    }
+}
+
+/// How strictly an envelope is held to the fields this build knows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Ignore unknown fields, as decoding does by default.
+    #[default]
+    Lenient,
+    /// Reject envelopes and manifests with unknown fields, and manifests
+    /// with empty required fields.
+    Strict,
+}
+
+/// How to decode envelopes.
+///
+/// Limits left as `None` do not apply. The sizes of the manifest and
+/// content are those of their JSON encoding, whatever the format, and the
+/// size of compressed content is that of its decompressed encoding.
+///
+/// An envelope longer than [`max_envelope_bytes`](Self::max_envelope_bytes)
+/// is rejected before it is parsed at all. Otherwise the envelope is measured
+/// in a first pass that holds onto no more than its manifest, and only
+/// decoded if it is within the limits.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeOptions {
+    pub mode: Mode,
+    /// The longest an encoded envelope may be. Defaults, when both the
+    /// manifest and the content are limited, to twice their limits
+    /// together, leaving room for formats more verbose than JSON.
+    pub max_envelope_bytes: Option<usize>,
+    pub max_manifest_bytes: Option<usize>,
+    pub max_content_bytes: Option<usize>,
+    /// The most labels a manifest may have.
+    pub max_labels: Option<usize>,
+    /// The longest a label key or value may be, in bytes.
+    pub max_label_len: Option<usize>,
+}
+
+/// A limit of [`DecodeOptions`] that an envelope exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    EnvelopeBytes,
+    ManifestBytes,
+    ContentBytes,
+    Labels,
+    LabelLen,
+}
+
+/// The fields of an envelope.
+const ENVELOPE_FIELDS: &[&str] = &["manifest", "content", "signature", "provenance"];
+
+/// The fields of a manifest, the first of them required.
+const MANIFEST_FIELDS: &[&str] = &[
+    "domain",
+    "scope",
+    "kind",
+    "version",
+    "ctime",
+    "origin",
+    "expires",
+    "labels",
+    "trace",
+    "correlation_id",
+    "reply_to",
+];
+
+/// The required manifest fields held as strings.
+const REQUIRED_STRINGS: &[&str] = &["domain", "scope", "kind", "origin"];
+
+impl DecodeOptions {
+    /// Options decoding in [`Mode::Strict`].
+    pub fn strict() -> Self {
+        DecodeOptions {
+            mode: Mode::Strict,
+            ..DecodeOptions::default()
+        }
+    }
+
+    /// Options with limits suited to consumers of untrusted input:
+    /// manifests of at most 64 KiB, with at most 64 labels of at most
+    /// [`MAX_LABEL_VALUE_LEN`](crate::validation::MAX_LABEL_VALUE_LEN)
+    /// bytes, and content of at most 16 MiB.
+    pub fn bounded() -> Self {
+        DecodeOptions {
+            max_manifest_bytes: Some(64 << 10),
+            max_content_bytes: Some(16 << 20),
+            max_labels: Some(64),
+            max_label_len: Some(crate::validation::MAX_LABEL_VALUE_LEN),
+            ..DecodeOptions::default()
+        }
+    }
+
+    /// Deserializes a packet from the given format, like
+    /// [`Packet::from_bytes`].
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        format: Format,
+    ) -> Result<Packet<T>, Error> {
+        self.check(bytes, format)?;
+        decompressed(
+            bytes,
+            format,
+            from_slice(bytes, format),
+            self.max_content_bytes,
+        )
+    }
+
+    /// Deserializes a packet from the format [`sniff`] finds, like
+    /// [`Packet::from_bytes_auto`].
+    pub fn decode_auto<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<(Packet<T>, Format), Error> {
+        let format = sniff(bytes).ok_or_else(|| Error {
+            format: None,
+            message: "unrecognized format".to_string(),
+            limit: None,
+        })?;
+        Ok((self.decode(bytes, format)?, format))
+    }
+
+    fn max_envelope_bytes(&self) -> Option<usize> {
+        self.max_envelope_bytes.or_else(|| {
+            let parts = self
+                .max_manifest_bytes?
+                .saturating_add(self.max_content_bytes?);
+            Some(parts.saturating_mul(2))
+        })
+    }
+
+    fn is_limited(&self) -> bool {
+        self.max_envelope_bytes().is_some()
+            || self.max_manifest_bytes.is_some()
+            || self.max_content_bytes.is_some()
+            || self.max_labels.is_some()
+            || self.max_label_len.is_some()
+    }
+
+    /// Checks the envelope against the options before it is decoded.
+    fn check(&self, bytes: &[u8], format: Format) -> Result<(), Error> {
+        if self.mode == Mode::Lenient && !self.is_limited() {
+            return Ok(());
+        }
+        let over = |len: usize, max: Option<usize>| max.filter(|&max| len > max);
+        if let Some(max) = over(bytes.len(), self.max_envelope_bytes()) {
+            let message = format!(
+                "envelope is {} bytes, over the limit of {}",
+                bytes.len(),
+                max
+            );
+            return Err(exceeded(format, Limit::EnvelopeBytes, message));
+        }
+
+        let envelope: Measured = from_slice(bytes, format)?;
+        let manifest_len = json_len(&envelope.manifest);
+        if let Some(max) = over(manifest_len, self.max_manifest_bytes) {
+            let message = format!(
+                "manifest is {} bytes, over the limit of {}",
+                manifest_len, max
+            );
+            return Err(exceeded(format, Limit::ManifestBytes, message));
+        }
+        if let Some(max) = over(envelope.content_len, self.max_content_bytes) {
+            let message = format!(
+                "content is {} bytes, over the limit of {}",
+                envelope.content_len, max
+            );
+            return Err(exceeded(format, Limit::ContentBytes, message));
+        }
+        let labels = envelope.manifest.get("labels").and_then(Value::as_object);
+        if let Some(labels) = labels {
+            if let Some(max) = over(labels.len(), self.max_labels) {
+                let message = format!(
+                    "manifest has {} labels, over the limit of {}",
+                    labels.len(),
+                    max
+                );
+                return Err(exceeded(format, Limit::Labels, message));
+            }
+            for (key, value) in labels {
+                let len = key.len().max(value.as_str().map_or(0, str::len));
+                if let Some(max) = over(len, self.max_label_len) {
+                    let message = format!(
+                        "label `{}` is {} bytes, over the limit of {}",
+                        key, len, max
+                    );
+                    return Err(exceeded(format, Limit::LabelLen, message));
+                }
+            }
+        }
+
+        if self.mode == Mode::Lenient {
+            return Ok(());
+        }
+        let violations = strict_violations(&envelope);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error {
+                format: Some(format),
+                message: violations.join("; "),
+                limit: None,
+            })
+        }
+    }
+}
+
+fn exceeded(format: Format, limit: Limit, message: String) -> Error {
+    Error {
+        format: Some(format),
+        message,
+        limit: Some(limit),
+    }
+}
+
+/// What checking an envelope needs of it: its manifest, the length of its
+/// content, which is measured without being kept, and the names of its
+/// other fields.
+struct Measured {
+    manifest: Value,
+    content_len: usize,
+    fields: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for Measured {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Measured;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an envelope")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Measured, A::Error> {
+                let mut envelope = Measured {
+                    manifest: Value::Null,
+                    content_len: 0,
+                    fields: Vec::new(),
+                };
+                while let Some(field) = map.next_key::<String>()? {
+                    match field.as_str() {
+                        "manifest" => envelope.manifest = map.next_value()?,
+                        "content" => envelope.content_len = map.next_value::<Length>()?.0,
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                    envelope.fields.push(field);
+                }
+                Ok(envelope)
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+/// The length of a value's JSON encoding, taken as it is deserialized.
+struct Length(usize);
+
+impl<'de> Deserialize<'de> for Length {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Length;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("any value")
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Length, E> {
+                Ok(Length(json_len(&v)))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Length, E> {
+                Ok(Length(json_len(&v)))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Length, E> {
+                Ok(Length(json_len(&v)))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Length, E> {
+                Ok(Length(json_len(&v)))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Length, E> {
+                Ok(Length(json_len(v)))
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Length, E> {
+                Ok(Length(json_len(v)))
+            }
+
+            fn visit_none<E>(self) -> Result<Length, E> {
+                Ok(Length(json_len(&())))
+            }
+
+            fn visit_unit<E>(self) -> Result<Length, E> {
+                Ok(Length(json_len(&())))
+            }
+
+            fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Length, D::Error> {
+                Length::deserialize(d)
+            }
+
+            fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> Result<Length, D::Error> {
+                Length::deserialize(d)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Length, A::Error> {
+                // The brackets, and a comma between each pair of elements.
+                let mut len = 1;
+                while let Some(Length(element)) = seq.next_element()? {
+                    len += element + 1;
+                }
+                Ok(Length(len.max(2)))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Length, A::Error> {
+                // The braces, a colon in each entry and a comma between them.
+                let mut len = 1;
+                while let Some(Length(key)) = map.next_key()? {
+                    let Length(value) = map.next_value()?;
+                    len += key + value + 2;
+                }
+                Ok(Length(len.max(2)))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// The length of the value's JSON encoding.
+fn json_len<S: Serialize + ?Sized>(value: &S) -> usize {
+    struct Counter(usize);
+
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, value).map_or(0, |()| counter.0)
+}
+
+/// Everything about the envelope that strict decoding rejects.
+fn strict_violations(envelope: &Measured) -> Vec<String> {
+    let mut violations = Vec::new();
+    let unknown = |fields: &mut dyn Iterator<Item = &String>, known: &[&str], prefix: &str| {
+        let mut unknown: Vec<_> = fields
+            .filter(|key| !known.contains(&key.as_str()))
+            .map(|key| format!("unknown field `{}{}`", prefix, key))
+            .collect();
+        unknown.sort();
+        unknown
+    };
+    violations.extend(unknown(&mut envelope.fields.iter(), ENVELOPE_FIELDS, ""));
+    if let Some(manifest) = envelope.manifest.as_object() {
+        violations.extend(unknown(&mut manifest.keys(), MANIFEST_FIELDS, "manifest."));
+        for field in REQUIRED_STRINGS {
+            if manifest.get(*field).and_then(Value::as_str) == Some("") {
+                violations.push(format!("`manifest.{}` is empty", field));
+            }
+        }
+    }
+    violations
+}
+
+/// The packet decoded from `bytes`, decompressed if it was compressed.
+///
+/// Compressed content is a string, which most content types fail to decode
+/// from, so the manifest is only checked for the compression label when
+/// decoding fails or succeeds with the label, and uncompressed packets
+/// decode once. Content longer than `max_content_bytes` once decompressed
+/// is rejected.
+fn decompressed<T: DeserializeOwned>(
+    bytes: &[u8],
+    format: Format,
+    decoded: Result<Packet<T>, Error>,
+    max_content_bytes: Option<usize>,
+) -> Result<Packet<T>, Error> {
+    let compressed = match &decoded {
+        Ok(packet) => packet.manifest.labels.contains_key(compression::LABEL),
+        Err(_) => from_slice::<Header>(bytes, format)
+            .is_ok_and(|header| header.manifest.labels.contains_key(compression::LABEL)),
+    };
+    if !compressed {
+        return decoded;
+    }
+    let error = |e: &dyn fmt::Display| Error {
+        format: Some(format),
+        message: e.to_string(),
+        limit: None,
+    };
+    let packet: RawPacket = from_slice(bytes, format)?;
+    let max = max_content_bytes.map_or(u64::MAX, |max| max as u64);
+    let packet = packet.decompress_within(max).map_err(|e| match e {
+        compression::Error::TooLarge(_) => exceeded(format, Limit::ContentBytes, e.to_string()),
+        e => error(&e),
+    })?;
+    packet
+        .try_map(serde_json::from_value)
+        .map_err(|e| error(&e))
+}
+
+/// An error deserializing an envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    format: Option<Format>,
+    message: String,
+    limit: Option<Limit>,
+}
+
+impl Error {
+    /// The format the envelope was being deserialized from, or `None` if it
+    /// could not be recognized.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// The limit the envelope exceeded, if that is why it was rejected.
+    pub fn limit(&self) -> Option<Limit> {
+        self.limit
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            Some(format) => write!(f, "decoding {}: {}", format, self.message),
+            None => write!(f, "decoding: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Cpu;
+    use crate::RawPacket;
+
+    #[test]
+    fn reports_the_format() {
+        let error = RawPacket::from_bytes(b"{", Format::Json).unwrap_err();
+        assert_eq!(error.format(), Some(Format::Json));
+        assert!(error.to_string().starts_with("decoding json: "));
+    }
+
+    #[test]
+    fn sniffs_every_format() {
+        let packet = crate::fixtures::netstat_raw();
+        for &format in Format::all() {
+            let bytes = packet.to_bytes(format).unwrap();
+            assert_eq!(sniff(&bytes), Some(format), "{}", format);
+            let (decoded, detected) = RawPacket::from_bytes_auto(&bytes).unwrap();
+            assert_eq!((decoded, detected), (packet.clone(), format));
+        }
+        assert_eq!(sniff(b"\xef\xbb\xbf\n  # comment\n {}"), Some(Format::Json));
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"\n\n"), None);
+        let error = RawPacket::from_bytes_auto(b"\x00").unwrap_err();
+        assert_eq!(error.format(), None);
+    }
+
+    #[test]
+    fn strict_rejects_unknown_fields() {
+        let strict = DecodeOptions::strict();
+        let mut envelope = serde_json::to_value(crate::fixtures::cpu_raw()).unwrap();
+        for &format in Format::all() {
+            let bytes = crate::encode::to_vec(&envelope, format).unwrap();
+            assert!(strict.decode::<Cpu>(&bytes, format).is_ok(), "{}", format);
+        }
+
+        envelope["routing"] = serde_json::json!("eu");
+        envelope["manifest"]["owner"] = serde_json::json!("team-a");
+        envelope["manifest"]["kind"] = serde_json::json!("");
+        for &format in Format::all() {
+            let bytes = crate::encode::to_vec(&envelope, format).unwrap();
+            let lenient = DecodeOptions::default()
+                .decode::<Cpu>(&bytes, format)
+                .unwrap();
+            assert_eq!(lenient.content.idle, 83.25);
+            let error = strict.decode::<Cpu>(&bytes, format).unwrap_err();
+            assert_eq!(error.format(), Some(format));
+            // The XML layout has no place for fields outside the manifest
+            // and content, so it never carries them.
+            let outside = if format.name() == "xml" {
+                ""
+            } else {
+                "unknown field `routing`; "
+            };
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "decoding {}: {}unknown field `manifest.owner`; `manifest.kind` is empty",
+                    format, outside
+                )
+            );
+            let (_, detected) = DecodeOptions::default().decode_auto::<Cpu>(&bytes).unwrap();
+            assert_eq!(detected, format);
+            assert!(strict.decode_auto::<Cpu>(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_envelopes_over_limits() {
+        let packet = crate::fixtures::cpu_raw();
+        let limited = |options: DecodeOptions| {
+            Format::all()
+                .iter()
+                .map(|&format| {
+                    let bytes = packet.to_bytes(format).unwrap();
+                    options
+                        .decode::<Cpu>(&bytes, format)
+                        .map(|_| ())
+                        .map_err(|e| e.limit())
+                })
+                .collect::<Vec<_>>()
+        };
+        let all = |result| vec![result; Format::all().len()];
+        assert_eq!(limited(DecodeOptions::bounded()), all(Ok(())));
+
+        let options = DecodeOptions {
+            max_labels: Some(1),
+            ..DecodeOptions::default()
+        };
+        assert_eq!(limited(options), all(Err(Some(Limit::Labels))));
+        let options = DecodeOptions {
+            max_label_len: Some("environment".len() - 1),
+            ..DecodeOptions::default()
+        };
+        assert_eq!(limited(options), all(Err(Some(Limit::LabelLen))));
+        let options = DecodeOptions {
+            max_manifest_bytes: Some(64),
+            ..DecodeOptions::default()
+        };
+        assert_eq!(limited(options), all(Err(Some(Limit::ManifestBytes))));
+        let options = DecodeOptions {
+            max_manifest_bytes: Some(64),
+            max_content_bytes: Some(1 << 20),
+            ..DecodeOptions::default()
+        };
+        assert_eq!(limited(options), all(Err(Some(Limit::ManifestBytes))));
+
+        // The content's JSON encoding is exactly this long, in any format.
+        let len = serde_json::to_vec(&packet.content).unwrap().len();
+        let options = |max| DecodeOptions {
+            max_content_bytes: Some(max),
+            ..DecodeOptions::default()
+        };
+        assert_eq!(limited(options(len)), all(Ok(())));
+        assert_eq!(
+            limited(options(len - 1)),
+            all(Err(Some(Limit::ContentBytes)))
+        );
+
+        let options = DecodeOptions {
+            max_manifest_bytes: Some(8),
+            max_content_bytes: Some(8),
+            ..DecodeOptions::default()
+        };
+        let bytes = packet.to_bytes(Format::Json).unwrap();
+        let error = options.decode::<Cpu>(&bytes, Format::Json).unwrap_err();
+        assert_eq!(error.limit(), Some(Limit::EnvelopeBytes));
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "decoding json: envelope is {} bytes, over the limit of 32",
+                bytes.len()
+            )
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn limits_decompressed_content() {
+        let mut packet = crate::fixtures::cpu_raw();
+        packet.content = serde_json::json!("x".repeat(1 << 20));
+        let bytes = packet
+            .compress(crate::compression::Codec::Zstd)
+            .unwrap()
+            .to_bytes(Format::Json)
+            .unwrap();
+        assert!(bytes.len() < 1 << 10);
+
+        let options = DecodeOptions {
+            max_content_bytes: Some(64 << 10),
+            ..DecodeOptions::default()
+        };
+        let error = options.decode::<Value>(&bytes, Format::Json).unwrap_err();
+        assert_eq!(error.limit(), Some(Limit::ContentBytes));
+        assert!(DecodeOptions::default()
+            .decode::<Value>(&bytes, Format::Json)
+            .is_ok());
+    }
+}
@@ -0,0 +1,780 @@
+//! Asynchronous dispatch of packets to handlers, one lane per content type.
+//!
+//! A [`Dispatcher`] routes each packet to a handler by the coordinates of
+//! its manifest. Every set of coordinates gets a lane of its own: a bounded
+//! queue and a cap on how many packets its handler may work on at once. A
+//! slow handler therefore fills only its own queue, and the other content
+//! types keep flowing.
+//!
+//! What happens to a packet that arrives at a full queue is up to the lane's
+//! [`Overflow`] action: wait for room, drop it, hand it to the dead-letter
+//! handler, or set it aside in a [`Spool`].
+//!
+//! ```no_run
+//! # async fn run(packets: Vec<intermodal::RawPacket>) {
+//! use intermodal::dispatch::{Dispatcher, Limits, Overflow};
+//! use intermodal::Coordinates;
+//!
+//! let dispatcher = Dispatcher::builder()
+//!     .handler(
+//!         Coordinates::new("example.org", "metrics/host", "cpu", 1),
+//!         Limits { concurrency: 4, queue: 1000, overflow: Overflow::Drop },
+//!         |packet| async move {
+//!             // store the packet ...
+//!             Ok(())
+//!         },
+//!     )
+//!     .build();
+//! for packet in packets {
+//!     dispatcher.dispatch(packet).await.unwrap();
+//! }
+//! # }
+//! ```
+//!
+//! Lanes run as tasks on the Tokio runtime the dispatcher is used from.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{Id, JoinError, JoinHandle, JoinSet};
+
+use crate::spool::Spool;
+use crate::{Coordinates, RawPacket};
+
+/// The error type handlers fail with.
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Processes packets of one content type.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, packet: RawPacket) -> BoxFuture<'static, Result<(), HandlerError>>;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(RawPacket) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+{
+    fn handle(&self, packet: RawPacket) -> BoxFuture<'static, Result<(), HandlerError>> {
+        self(packet).boxed()
+    }
+}
+
+/// What to do with a packet that arrives at a full queue.
+#[derive(Clone)]
+pub enum Overflow {
+    /// Wait for room in the queue, pushing back on the caller.
+    Wait,
+    /// Discard the packet.
+    Drop,
+    /// Hand the packet to the dispatcher's dead-letter handler, or discard
+    /// it if there is none.
+    DeadLetter,
+    /// Set the packet aside in a spool.
+    Spool(Arc<dyn Spool>),
+}
+
+impl fmt::Debug for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overflow::Wait => f.write_str("Wait"),
+            Overflow::Drop => f.write_str("Drop"),
+            Overflow::DeadLetter => f.write_str("DeadLetter"),
+            Overflow::Spool(_) => f.write_str("Spool"),
+        }
+    }
+}
+
+/// The bounds on a single lane.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// The most packets the lane's handler works on at once.
+    pub concurrency: usize,
+    /// The most packets waiting in the lane's queue.
+    pub queue: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            concurrency: 1,
+            queue: 1024,
+            overflow: Overflow::Wait,
+        }
+    }
+}
+
+/// What became of a dispatched packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The packet is queued for its handler.
+    Queued,
+    /// The queue was full and the packet was discarded.
+    Dropped,
+    /// The queue was full and the packet went to the dead-letter handler.
+    DeadLettered,
+    /// The queue was full and the packet was spooled.
+    Spooled,
+    /// No handler is registered for the packet's coordinates. The packet was
+    /// passed to the dead-letter handler, if there is one.
+    Unhandled,
+}
+
+/// Why a packet was dead-lettered.
+#[derive(Debug)]
+pub enum Reason {
+    /// Its lane's queue was full.
+    Overflow,
+    /// Its handler failed.
+    Failed(HandlerError),
+    /// No handler is registered for its coordinates.
+    Unhandled,
+}
+
+/// Receives packets that could not be handled.
+pub type DeadLetter = Arc<dyn Fn(RawPacket, Reason) + Send + Sync>;
+
+/// Counts of what a lane has done with its packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub queued: u64,
+    pub handled: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub dead_lettered: u64,
+    pub spooled: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicU64,
+    handled: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    dead_lettered: AtomicU64,
+    spooled: AtomicU64,
+}
+
+impl Counters {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Stats {
+        Stats {
+            queued: self.queued.load(Ordering::Relaxed),
+            handled: self.handled.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+            spooled: self.spooled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LaneSpec {
+    limits: Limits,
+    handler: Arc<dyn Handler>,
+}
+
+struct Lane {
+    sender: Option<mpsc::Sender<RawPacket>>,
+    overflow: Overflow,
+    counters: Arc<Counters>,
+    stop: Arc<Notify>,
+    worker: Option<JoinHandle<Drained>>,
+}
+
+/// What was left undone when a [`Dispatcher`] was drained.
+#[derive(Debug, Default)]
+pub struct Drained {
+    /// Packets whose handlers were cancelled at the deadline, then those
+    /// whose handlers panicked with no dead-letter handler to take them,
+    /// followed by those still queued, for the caller to replay or spool.
+    pub undelivered: Vec<RawPacket>,
+    /// The number of packets whose handlers were still running at the
+    /// deadline, and were cancelled. They lead the undelivered packets;
+    /// their handlers may have done part of their work before being
+    /// cancelled, so replaying them relies on handlers being idempotent.
+    pub abandoned: usize,
+}
+
+impl Drained {
+    /// Whether everything was delivered in time.
+    pub fn is_complete(&self) -> bool {
+        self.undelivered.is_empty() && self.abandoned == 0
+    }
+}
+
+/// Configures a [`Dispatcher`].
+#[derive(Default)]
+pub struct Builder {
+    specs: HashMap<Coordinates, LaneSpec>,
+    fallback: Option<LaneSpec>,
+    dead_letter: Option<DeadLetter>,
+}
+
+impl Builder {
+    /// Registers the handler for packets with the given coordinates.
+    pub fn handler<H: Handler>(
+        mut self,
+        coordinates: Coordinates,
+        limits: Limits,
+        handler: H,
+    ) -> Self {
+        let handler = Arc::new(handler);
+        self.specs.insert(coordinates, LaneSpec { limits, handler });
+        self
+    }
+
+    /// Registers a handler for packets whose coordinates have no handler of
+    /// their own. Each distinct set of coordinates still gets its own lane,
+    /// with the given limits.
+    pub fn fallback<H: Handler>(mut self, limits: Limits, handler: H) -> Self {
+        let handler = Arc::new(handler);
+        self.fallback = Some(LaneSpec { limits, handler });
+        self
+    }
+
+    /// Sets the handler for packets that overflow a lane with
+    /// [`Overflow::DeadLetter`], whose handler fails, or that have no handler.
+    pub fn dead_letter<F>(mut self, dead_letter: F) -> Self
+    where
+        F: Fn(RawPacket, Reason) + Send + Sync + 'static,
+    {
+        self.dead_letter = Some(Arc::new(dead_letter));
+        self
+    }
+
+    pub fn build(self) -> Dispatcher {
+        Dispatcher {
+            specs: self.specs,
+            fallback: self.fallback,
+            dead_letter: self.dead_letter,
+            lanes: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Dispatches packets to handlers by their coordinates.
+pub struct Dispatcher {
+    specs: HashMap<Coordinates, LaneSpec>,
+    fallback: Option<LaneSpec>,
+    dead_letter: Option<DeadLetter>,
+    lanes: Mutex<HashMap<Coordinates, Lane>>,
+    closed: AtomicBool,
+}
+
+impl Dispatcher {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Queues a packet for the handler registered for its coordinates,
+    /// applying the lane's overflow action if its queue is full.
+    ///
+    /// Lanes are started on first use, which must be from within a Tokio
+    /// runtime.
+    pub async fn dispatch(&self, packet: RawPacket) -> Result<Outcome, Error> {
+        let coordinates = packet.manifest.coordinates();
+        let (sender, overflow, counters) = match self.lane(&coordinates)? {
+            Some(lane) => lane,
+            None => {
+                if let Some(dead_letter) = &self.dead_letter {
+                    dead_letter(packet, Reason::Unhandled);
+                }
+                return Ok(Outcome::Unhandled);
+            }
+        };
+
+        let packet = match sender.try_send(packet) {
+            Ok(()) => {
+                Counters::bump(&counters.queued);
+                return Ok(Outcome::Queued);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return Err(Error::Closed),
+            Err(mpsc::error::TrySendError::Full(packet)) => packet,
+        };
+        match overflow {
+            Overflow::Wait => {
+                sender.send(packet).await.map_err(|_| Error::Closed)?;
+                Counters::bump(&counters.queued);
+                Ok(Outcome::Queued)
+            }
+            Overflow::Drop => {
+                Counters::bump(&counters.dropped);
+                Ok(Outcome::Dropped)
+            }
+            Overflow::DeadLetter => match &self.dead_letter {
+                Some(dead_letter) => {
+                    dead_letter(packet, Reason::Overflow);
+                    Counters::bump(&counters.dead_lettered);
+                    Ok(Outcome::DeadLettered)
+                }
+                None => {
+                    Counters::bump(&counters.dropped);
+                    Ok(Outcome::Dropped)
+                }
+            },
+            Overflow::Spool(spool) => {
+                spool.spool(&packet).map_err(Error::Spool)?;
+                Counters::bump(&counters.spooled);
+                Ok(Outcome::Spooled)
+            }
+        }
+    }
+
+    /// Returns what the lane for the given coordinates has done so far, if
+    /// it has been started.
+    pub fn stats(&self, coordinates: &Coordinates) -> Option<Stats> {
+        let lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        lanes.get(coordinates).map(|lane| lane.counters.snapshot())
+    }
+
+    /// The coordinates of each started lane, what it has done so far, and
+    /// the number of packets waiting in its queue.
+    pub fn lanes(&self) -> Vec<(Coordinates, Stats, usize)> {
+        let lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        lanes
+            .iter()
+            .map(|(coordinates, lane)| {
+                let depth = lane
+                    .sender
+                    .as_ref()
+                    .map_or(0, |sender| sender.max_capacity() - sender.capacity());
+                (coordinates.clone(), lane.counters.snapshot(), depth)
+            })
+            .collect()
+    }
+
+    /// The number of packets waiting in the queues of all started lanes.
+    pub fn depth(&self) -> usize {
+        let lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        lanes
+            .values()
+            .filter_map(|lane| lane.sender.as_ref())
+            .map(|sender| sender.max_capacity() - sender.capacity())
+            .sum()
+    }
+
+    /// Stops accepting packets and waits until `deadline` for the packets
+    /// already accepted to be handled.
+    ///
+    /// Handlers still running at the deadline are cancelled, and their
+    /// packets are returned undelivered along with those still queued, and
+    /// with those whose handlers panicked when there is no dead-letter
+    /// handler. Dispatching after a drain has begun fails with [`Error::Closed`].
+    pub async fn drain(&self, deadline: Instant) -> Drained {
+        self.closed.store(true, Ordering::SeqCst);
+        let lanes: Vec<_> = {
+            let mut lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+            lanes
+                .values_mut()
+                .filter_map(|lane| {
+                    // Dropping the sender lets the worker finish once the
+                    // queue is empty.
+                    lane.sender = None;
+                    lane.worker.take().map(|w| (w, Arc::clone(&lane.stop)))
+                })
+                .collect()
+        };
+
+        let deadline = tokio::time::Instant::from_std(deadline);
+        let mut drained = Drained::default();
+        for (mut worker, stop) in lanes {
+            let result = match tokio::time::timeout_at(deadline, &mut worker).await {
+                Ok(result) => result,
+                Err(_) => {
+                    stop.notify_one();
+                    worker.await
+                }
+            };
+            if let Ok(lane) = result {
+                drained.undelivered.extend(lane.undelivered);
+                drained.abandoned += lane.abandoned;
+            }
+        }
+        drained
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn lane(
+        &self,
+        coordinates: &Coordinates,
+    ) -> Result<Option<(mpsc::Sender<RawPacket>, Overflow, Arc<Counters>)>, Error> {
+        let mut lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        if !lanes.contains_key(coordinates) {
+            let spec = match self.specs.get(coordinates).or(self.fallback.as_ref()) {
+                Some(spec) => spec,
+                None => return Ok(None),
+            };
+            lanes.insert(coordinates.clone(), self.start(spec.clone()));
+        }
+        let lane = &lanes[coordinates];
+        let sender = lane.sender.clone().ok_or(Error::Closed)?;
+        Ok(Some((
+            sender,
+            lane.overflow.clone(),
+            Arc::clone(&lane.counters),
+        )))
+    }
+
+    fn start(&self, spec: LaneSpec) -> Lane {
+        let (sender, receiver) = mpsc::channel(spec.limits.queue.max(1));
+        let counters = Arc::new(Counters::default());
+        let stop = Arc::new(Notify::new());
+        let worker = tokio::spawn(run_lane(
+            receiver,
+            spec.clone(),
+            Arc::clone(&counters),
+            Arc::clone(&stop),
+            self.dead_letter.clone(),
+        ));
+        Lane {
+            sender: Some(sender),
+            overflow: spec.limits.overflow,
+            counters,
+            stop,
+            worker: Some(worker),
+        }
+    }
+}
+
+/// Feeds a lane's queue to its handler until every sender is gone and the
+/// queue is empty, or until told to stop.
+async fn run_lane(
+    mut receiver: mpsc::Receiver<RawPacket>,
+    spec: LaneSpec,
+    counters: Arc<Counters>,
+    stop: Arc<Notify>,
+    dead_letter: Option<DeadLetter>,
+) -> Drained {
+    let concurrency = spec.limits.concurrency.max(1);
+    let mut running = JoinSet::new();
+    // A copy of each packet being handled, in the order they were taken, so
+    // that those cancelled by a drain, or whose handlers panic, can be
+    // returned.
+    let mut in_flight: Vec<(Id, RawPacket)> = Vec::new();
+    let finished = |in_flight: &mut Vec<(Id, RawPacket)>, id| {
+        let index = in_flight.iter().position(|(running, _)| *running == id)?;
+        Some(in_flight.remove(index).1)
+    };
+    // Packets whose handlers panicked, when there is no dead-letter handler.
+    let mut failed = Vec::new();
+    let mut panicked = |in_flight: &mut Vec<(Id, RawPacket)>, e: JoinError| {
+        if let Some(packet) = finished(in_flight, e.id()) {
+            Counters::bump(&counters.failed);
+            match &dead_letter {
+                Some(dead_letter) => dead_letter(packet, Reason::Failed(panic_error(e))),
+                None => failed.push(packet),
+            }
+        }
+    };
+    let mut open = true;
+    loop {
+        if !open && running.is_empty() {
+            return Drained {
+                undelivered: failed,
+                abandoned: 0,
+            };
+        }
+        tokio::select! {
+            biased;
+            _ = stop.notified() => {
+                running.abort_all();
+                // Handlers that finished before they could be cancelled
+                // delivered their packets.
+                while let Some(result) = running.join_next_with_id().await {
+                    match result {
+                        Ok((id, ())) => {
+                            finished(&mut in_flight, id);
+                        }
+                        Err(e) if e.is_panic() => panicked(&mut in_flight, e),
+                        Err(_) => {}
+                    }
+                }
+                receiver.close();
+                let abandoned = in_flight.len();
+                let mut undelivered: Vec<_> =
+                    in_flight.into_iter().map(|(_, packet)| packet).collect();
+                undelivered.append(&mut failed);
+                while let Ok(packet) = receiver.try_recv() {
+                    undelivered.push(packet);
+                }
+                return Drained { undelivered, abandoned };
+            }
+            Some(result) = running.join_next_with_id(), if !running.is_empty() => {
+                match result {
+                    Ok((id, ())) => {
+                        finished(&mut in_flight, id);
+                    }
+                    Err(e) => panicked(&mut in_flight, e),
+                }
+            }
+            // Packets are only taken from the queue when the handler has
+            // room for them, so that they wait in the bounded queue.
+            packet = receiver.recv(), if open && running.len() < concurrency => {
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => {
+                        open = false;
+                        continue;
+                    }
+                };
+                let copy = packet.clone();
+                let retained = dead_letter.as_ref().map(|_| packet.clone());
+                let handling = spec.handler.handle(packet);
+                let dead_letter = dead_letter.clone();
+                let counters = Arc::clone(&counters);
+                let task = running.spawn(async move {
+                    match handling.await {
+                        Ok(()) => Counters::bump(&counters.handled),
+                        Err(e) => {
+                            Counters::bump(&counters.failed);
+                            if let (Some(dead_letter), Some(packet)) = (dead_letter, retained) {
+                                dead_letter(packet, Reason::Failed(e));
+                            }
+                        }
+                    }
+                });
+                in_flight.push((task.id(), copy));
+            }
+        }
+    }
+}
+
+/// The error a handler's panic is dead-lettered with.
+fn panic_error(e: JoinError) -> HandlerError {
+    let payload = e.into_panic();
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => Some(message.to_string()),
+        None => payload.downcast_ref::<String>().cloned(),
+    };
+    match message {
+        Some(message) => format!("handler panicked: {}", message).into(),
+        None => "handler panicked".into(),
+    }
+}
+
+/// An error dispatching a packet.
+#[derive(Debug)]
+pub enum Error {
+    /// The dispatcher is no longer accepting packets.
+    Closed,
+    /// The packet overflowed its lane and could not be spooled.
+    Spool(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Closed => f.write_str("dispatcher is closed"),
+            Error::Spool(e) => write!(f, "spooling an overflowing packet failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Spool(e) => Some(e),
+            Error::Closed => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fixtures;
+
+    #[derive(Default)]
+    struct MemorySpool(Mutex<Vec<RawPacket>>);
+
+    impl Spool for MemorySpool {
+        fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+            self.0.lock().unwrap().push(packet.clone());
+            Ok(())
+        }
+    }
+
+    /// A handler that signals when it takes a packet, then holds it until
+    /// released.
+    fn stalled(started: mpsc::UnboundedSender<()>, release: Arc<Notify>) -> impl Handler {
+        move |_| {
+            let _ = started.send(());
+            let release = Arc::clone(&release);
+            async move {
+                release.notified().await;
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_kind_does_not_starve_others() {
+        let (started, mut taken) = mpsc::unbounded_channel();
+        let (handled, mut netstats) = mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let spool = Arc::new(MemorySpool::default());
+        let limits = Limits {
+            concurrency: 1,
+            queue: 1,
+            overflow: Overflow::Spool(spool.clone()),
+        };
+        let cpu = fixtures::cpu_manifest().coordinates();
+        let netstat = fixtures::netstat_manifest().coordinates();
+        let dispatcher = Dispatcher::builder()
+            .handler(cpu.clone(), limits, stalled(started, Arc::clone(&release)))
+            .fallback(Limits::default(), move |_| {
+                let _ = handled.send(());
+                async { Ok(()) }
+            })
+            .build();
+
+        // One packet is taken by the handler and one waits in the queue; the
+        // lane is then full.
+        dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        taken.recv().await.unwrap();
+        dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        let outcome = dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        assert_eq!(outcome, Outcome::Spooled);
+        assert_eq!(spool.0.lock().unwrap().len(), 1);
+
+        for _ in 0..10 {
+            let outcome = dispatcher.dispatch(fixtures::netstat_raw()).await.unwrap();
+            assert_eq!(outcome, Outcome::Queued);
+        }
+        for _ in 0..10 {
+            netstats.recv().await.unwrap();
+        }
+        assert_eq!(dispatcher.stats(&cpu).unwrap().handled, 0);
+
+        // Releasing the first packet lets the handler take the second.
+        release.notify_one();
+        taken.recv().await.unwrap();
+        release.notify_one();
+        let drained = dispatcher
+            .drain(Instant::now() + Duration::from_secs(5))
+            .await;
+        assert!(drained.is_complete(), "{:?}", drained);
+        assert_eq!(dispatcher.stats(&netstat).unwrap().handled, 10);
+        assert_eq!(dispatcher.stats(&cpu).unwrap().handled, 2);
+    }
+
+    #[tokio::test]
+    async fn overflow_actions() {
+        let (started, mut taken) = mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let dead = Arc::new(Mutex::new(Vec::new()));
+        let cpu = fixtures::cpu_manifest().coordinates();
+        let dispatcher = Dispatcher::builder()
+            .handler(
+                cpu.clone(),
+                Limits {
+                    concurrency: 1,
+                    queue: 1,
+                    overflow: Overflow::DeadLetter,
+                },
+                stalled(started, Arc::clone(&release)),
+            )
+            .dead_letter({
+                let dead = Arc::clone(&dead);
+                move |packet: RawPacket, reason| {
+                    dead.lock().unwrap().push((packet.manifest.kind, reason));
+                }
+            })
+            .build();
+
+        dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        taken.recv().await.unwrap();
+        dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        let outcome = dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        assert_eq!(outcome, Outcome::DeadLettered);
+        let outcome = dispatcher.dispatch(fixtures::netstat_raw()).await.unwrap();
+        assert_eq!(outcome, Outcome::Unhandled);
+
+        let dead = dead.lock().unwrap();
+        assert!(matches!(dead[0], (ref kind, Reason::Overflow) if kind == "cpu"));
+        assert!(matches!(dead[1], (ref kind, Reason::Unhandled) if kind == "netstat"));
+        assert_eq!(dispatcher.stats(&cpu).unwrap().dead_lettered, 1);
+    }
+
+    #[tokio::test]
+    async fn failures_are_dead_lettered() {
+        let (failed, mut failures) = mpsc::unbounded_channel();
+        let dispatcher = Dispatcher::builder()
+            .fallback(Limits::default(), |_| async {
+                Err::<(), HandlerError>("unavailable".into())
+            })
+            .dead_letter(move |_, reason| {
+                if let Reason::Failed(e) = reason {
+                    let _ = failed.send(e.to_string());
+                }
+            })
+            .build();
+
+        dispatcher.dispatch(fixtures::netstat_raw()).await.unwrap();
+        assert_eq!(failures.recv().await.unwrap(), "unavailable");
+    }
+
+    #[tokio::test]
+    async fn panics_are_failures() {
+        async fn panicking(packet: RawPacket) -> Result<(), HandlerError> {
+            if packet.manifest.kind == "cpu" {
+                panic!("cpu is beyond us");
+            }
+            Ok(())
+        }
+
+        let (failed, mut failures) = mpsc::unbounded_channel();
+        let cpu = fixtures::cpu_manifest().coordinates();
+        let dispatcher = Dispatcher::builder()
+            .fallback(Limits::default(), panicking)
+            .dead_letter(move |packet: RawPacket, reason| {
+                if let Reason::Failed(e) = reason {
+                    let _ = failed.send((packet.manifest.kind, e.to_string()));
+                }
+            })
+            .build();
+        dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        assert_eq!(
+            failures.recv().await.unwrap(),
+            (
+                "cpu".to_string(),
+                "handler panicked: cpu is beyond us".to_string()
+            )
+        );
+        assert_eq!(dispatcher.stats(&cpu).unwrap().failed, 1);
+
+        // With no dead-letter handler, the packet is returned by the drain.
+        let dispatcher = Dispatcher::builder()
+            .fallback(Limits::default(), panicking)
+            .build();
+        dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        dispatcher.dispatch(fixtures::netstat_raw()).await.unwrap();
+        let drained = dispatcher
+            .drain(Instant::now() + Duration::from_secs(5))
+            .await;
+        assert_eq!(drained.abandoned, 0);
+        assert_eq!(drained.undelivered.len(), 1);
+        assert_eq!(drained.undelivered[0].manifest.kind, "cpu");
+        let stats = dispatcher.stats(&cpu).unwrap();
+        assert_eq!((stats.handled, stats.failed), (0, 1));
+    }
+}
@@ -0,0 +1,238 @@
+//! Holding packets back until a review window has passed.
+//!
+//! An [`Embargo`] is a [`Transform`] that takes the packets its
+//! [`Selector`] matches out of the stream, and lets everything else
+//! through. Each held packet is stamped with the time it may be released,
+//! [`UNTIL_LABEL`], and spooled to a file, so that nothing held is lost, or
+//! released early, across a restart. [`release`](Embargo::release) returns
+//! the packets whose time has come, without the label, for the caller to
+//! send on:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use intermodal::embargo::Embargo;
+//!
+//! let embargo = Embargo::open(
+//!     "/var/spool/intermodal/embargo.ndjson",
+//!     "domain=example.org, scope=finance/*".parse()?,
+//!     Duration::from_secs(24 * 3600),
+//! )?;
+//! // In the pipeline, `embargo` holds finance packets back. Meanwhile:
+//! for packet in embargo.release()? {
+//!     // ... send it downstream
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The delay runs from when a packet is held, not from its `ctime`, so a
+//! producer cannot shorten it by backdating. Releasing rewrites the spool
+//! file with what is still held, so is best done periodically rather than
+//! for every packet.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::spool::{FileSpool, Spool};
+use crate::stream::NdjsonReader;
+use crate::transform::{self, Transform};
+use crate::{labels, RawPacket, Selector};
+
+/// The label a held packet carries, giving the RFC 3339 time at which it
+/// may be released.
+pub const UNTIL_LABEL: &str = labels::EMBARGO_UNTIL;
+
+/// Holds matching packets for a fixed delay.
+#[derive(Debug)]
+pub struct Embargo {
+    selector: Selector,
+    delay: chrono::Duration,
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    spool: FileSpool,
+    /// Held packets with their release times, in the order they were held.
+    held: Vec<(DateTime<Utc>, RawPacket)>,
+}
+
+impl Embargo {
+    /// Holds packets matching `selector` for `delay`, spooling them to
+    /// `path`. Packets already spooled there are held again, until the
+    /// times they were stamped with.
+    pub fn open<P: AsRef<Path>>(path: P, selector: Selector, delay: Duration) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut held = Vec::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for packet in NdjsonReader::<_, RawPacket>::new(file) {
+                    let packet =
+                        packet.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    let until = until(&packet).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("held packet without a valid `{}` label", UNTIL_LABEL),
+                        )
+                    })?;
+                    held.push((until, packet));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let delay = chrono::Duration::from_std(delay)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Embargo {
+            selector,
+            delay,
+            state: Mutex::new(State {
+                spool: FileSpool::open(&path)?,
+                held,
+            }),
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of packets held.
+    pub fn held(&self) -> usize {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.held.len()
+    }
+
+    /// When the next held packet may be released, if any is held.
+    pub fn next_release(&self) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.held.iter().map(|(until, _)| *until).min()
+    }
+
+    /// Returns the held packets whose delay has passed, in the order they
+    /// were held, and stops holding them.
+    pub fn release(&self) -> io::Result<Vec<RawPacket>> {
+        self.release_at(Utc::now())
+    }
+
+    fn release_at(&self, now: DateTime<Utc>) -> io::Result<Vec<RawPacket>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.held.iter().any(|(until, _)| *until <= now) {
+            return Ok(Vec::new());
+        }
+        // Write what is still held to a copy and rename it over the spool,
+        // so that a crash leaves either the old spool or the new one.
+        let partial = self.path.with_extension("partial");
+        let spool = (|| {
+            let mut file = io::BufWriter::new(File::create(&partial)?);
+            for (_, packet) in state.held.iter().filter(|(until, _)| *until > now) {
+                serde_json::to_writer(&mut file, packet)?;
+                file.write_all(b"\n")?;
+            }
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&partial, &self.path)?;
+            FileSpool::open(&self.path)
+        })()?;
+        state.spool = spool;
+        let (due, held): (Vec<_>, Vec<_>) = std::mem::take(&mut state.held)
+            .into_iter()
+            .partition(|(until, _)| *until <= now);
+        state.held = held;
+
+        Ok(due
+            .into_iter()
+            .map(|(_, mut packet)| {
+                packet.manifest.labels.remove(UNTIL_LABEL);
+                packet
+            })
+            .collect())
+    }
+
+    fn apply_at(&self, mut packet: RawPacket, now: DateTime<Utc>) -> io::Result<Option<RawPacket>> {
+        if !self.selector.matches(&packet.manifest) {
+            return Ok(Some(packet));
+        }
+        let until = now + self.delay;
+        packet.manifest.labels.insert(
+            UNTIL_LABEL.to_string(),
+            until.to_rfc3339_opts(SecondsFormat::Millis, true),
+        );
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.spool.spool(&packet)?;
+        state.spool.flush()?;
+        state.held.push((until, packet));
+        Ok(None)
+    }
+}
+
+impl Transform for Embargo {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.apply_at(packet, Utc::now())
+            .map_err(|e| transform::Error::new(format!("cannot hold packet: {}", e)))
+    }
+}
+
+fn until(packet: &RawPacket) -> Option<DateTime<Utc>> {
+    let until = packet.manifest.labels.get(UNTIL_LABEL)?;
+    DateTime::parse_from_rfc3339(until)
+        .ok()
+        .map(|until| until.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn holds_across_restarts() {
+        let path = std::env::temp_dir().join(format!("intermodal-embargo-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let open = || Embargo::open(&path, "kind=cpu".parse().unwrap(), Duration::from_secs(60));
+
+        let embargo = open().unwrap();
+        let start = fixtures::cpu_manifest().ctime;
+        let netstat = fixtures::netstat_raw();
+        assert_eq!(
+            embargo.apply_at(netstat.clone(), start).unwrap(),
+            Some(netstat)
+        );
+        assert_eq!(embargo.apply_at(fixtures::cpu_raw(), start).unwrap(), None);
+        let later = start + chrono::Duration::seconds(30);
+        assert_eq!(embargo.apply_at(fixtures::cpu_raw(), later).unwrap(), None);
+        assert_eq!(embargo.held(), 2);
+        assert!(embargo
+            .release_at(start + chrono::Duration::seconds(59))
+            .unwrap()
+            .is_empty());
+        drop(embargo);
+
+        let embargo = open().unwrap();
+        assert_eq!(embargo.held(), 2);
+        assert_eq!(
+            embargo.next_release(),
+            Some(start + chrono::Duration::seconds(60))
+        );
+        let released = embargo
+            .release_at(start + chrono::Duration::seconds(60))
+            .unwrap();
+        assert_eq!(released, vec![fixtures::cpu_raw()]);
+        drop(embargo);
+
+        let embargo = open().unwrap();
+        assert_eq!(embargo.held(), 1);
+        let released = embargo
+            .release_at(start + chrono::Duration::seconds(90))
+            .unwrap();
+        assert_eq!(released.len(), 1);
+        assert_eq!(embargo.held(), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+}
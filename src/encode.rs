@@ -0,0 +1,461 @@
+//! Serializing envelopes in each supported format.
+//!
+//! [`Format`] names the formats an envelope can be put in: JSON always, and
+//! the others with the feature of the same name (`yaml`, `toml`, `xml`,
+//! `cbor` and `msgpack`). Producers encode with [`Packet::to_bytes`], or
+//! [`to_vec`] for headers and other envelope types, and consumers decode
+//! with [`Packet::from_bytes`] or [`decode::from_slice`]:
+//!
+//! ```
+//! use intermodal::{Format, Manifest, Packet};
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .build()
+//!     .unwrap();
+//! let packet = Packet::new(manifest, 86400u64);
+//!
+//! let bytes = packet.to_bytes(Format::Json).unwrap();
+//! let decoded: Packet<u64> = Packet::from_bytes(&bytes, Format::Json).unwrap();
+//! assert_eq!(decoded, packet);
+//! ```
+//!
+//! MessagePack envelopes encode structs as maps keyed by field name, so they
+//! decode to the same [`RawPacket`](crate::RawPacket) as their JSON
+//! counterparts.
+//!
+//! [`decode::from_slice`]: crate::decode::from_slice
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Packet;
+
+/// A serialization format for envelopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Json,
+    #[cfg(feature = "yaml")]
+    Yaml,
+    #[cfg(feature = "toml")]
+    Toml,
+    #[cfg(feature = "xml")]
+    Xml,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    /// Every format enabled in this build.
+    pub fn all() -> &'static [Format] {
+        &[
+            Format::Json,
+            #[cfg(feature = "yaml")]
+            Format::Yaml,
+            #[cfg(feature = "toml")]
+            Format::Toml,
+            #[cfg(feature = "xml")]
+            Format::Xml,
+            #[cfg(feature = "cbor")]
+            Format::Cbor,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack,
+        ]
+    }
+
+    /// The format's short name, as accepted by [`FromStr`], e.g. `msgpack`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            #[cfg(feature = "yaml")]
+            Format::Yaml => "yaml",
+            #[cfg(feature = "toml")]
+            Format::Toml => "toml",
+            #[cfg(feature = "xml")]
+            Format::Xml => "xml",
+            #[cfg(feature = "cbor")]
+            Format::Cbor => "cbor",
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => "msgpack",
+        }
+    }
+
+    /// The media type of envelopes in the format, e.g. `application/cbor`.
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            #[cfg(feature = "yaml")]
+            Format::Yaml => "application/yaml",
+            #[cfg(feature = "toml")]
+            Format::Toml => "application/toml",
+            #[cfg(feature = "xml")]
+            Format::Xml => "application/xml",
+            #[cfg(feature = "cbor")]
+            Format::Cbor => "application/cbor",
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    /// Parses a format's short name. Names of formats disabled in this build
+    /// are unknown.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::all()
+            .iter()
+            .copied()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| UnknownFormat(s.to_string()))
+    }
+}
+
+/// A format name that is not one of [`Format::all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownFormat(pub String);
+
+impl fmt::Display for UnknownFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown format `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownFormat {}
+
+/// Serializes an envelope, such as a [`Packet`] or a
+/// [`Header`](crate::Header), in the given format.
+pub fn to_vec<S: Serialize>(envelope: &S, format: Format) -> Result<Vec<u8>, Error> {
+    let error = |e: &dyn fmt::Display| Error {
+        format,
+        message: e.to_string(),
+    };
+    match format {
+        Format::Json => serde_json::to_vec(envelope).map_err(|e| error(&e)),
+        #[cfg(feature = "yaml")]
+        Format::Yaml => serde_yaml::to_string(envelope)
+            .map(String::into_bytes)
+            .map_err(|e| error(&e)),
+        #[cfg(feature = "toml")]
+        Format::Toml => toml::to_string(envelope)
+            .map(String::into_bytes)
+            .map_err(|e| error(&e)),
+        #[cfg(feature = "xml")]
+        Format::Xml => crate::xml::to_string(envelope)
+            .map(String::into_bytes)
+            .map_err(|e| error(&e)),
+        #[cfg(feature = "cbor")]
+        Format::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(envelope, &mut bytes).map_err(|e| error(&e))?;
+            Ok(bytes)
+        }
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => rmp_serde::to_vec_named(envelope).map_err(|e| error(&e)),
+    }
+}
+
+/// Serializes an envelope into a writer, for the formats whose serializers
+/// stream. Returns `None` for those that build a string instead.
+fn to_writer<W: io::Write, S: Serialize>(
+    writer: W,
+    envelope: &S,
+    format: Format,
+) -> Option<Result<(), Error>> {
+    let error = |e: &dyn fmt::Display| Error {
+        format,
+        message: e.to_string(),
+    };
+    #[cfg(feature = "msgpack")]
+    let mut writer = writer;
+    match format {
+        Format::Json => Some(serde_json::to_writer(writer, envelope).map_err(|e| error(&e))),
+        #[cfg(feature = "cbor")]
+        Format::Cbor => Some(ciborium::ser::into_writer(envelope, writer).map_err(|e| error(&e))),
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => {
+            Some(rmp_serde::encode::write_named(&mut writer, envelope).map_err(|e| error(&e)))
+        }
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+/// A growable byte buffer that envelopes can be encoded into, so that a
+/// sender can reuse one buffer for every packet.
+///
+/// Implemented for `Vec<u8>` and, with the `bytes` feature,
+/// `bytes::BytesMut`.
+pub trait Buffer {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]);
+
+    fn truncate(&mut self, len: usize);
+}
+
+impl Buffer for Vec<u8> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        Vec::extend_from_slice(self, bytes)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Buffer for bytes::BytesMut {
+    fn len(&self) -> usize {
+        bytes::BytesMut::len(self)
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        bytes::BytesMut::extend_from_slice(self, bytes)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        bytes::BytesMut::truncate(self, len)
+    }
+}
+
+/// Writes into a [`Buffer`].
+struct Appender<'a, B: ?Sized>(&'a mut B);
+
+impl<B: Buffer + ?Sized> io::Write for Appender<'_, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes an envelope onto the end of `buf`, returning the number of
+/// bytes appended. On failure `buf` is left as it was.
+///
+/// JSON, CBOR and MessagePack are serialized straight into the buffer; the
+/// other formats are serialized to a string first, and copied.
+pub fn encode_into<S, B>(envelope: &S, format: Format, buf: &mut B) -> Result<usize, Error>
+where
+    S: Serialize,
+    B: Buffer + ?Sized,
+{
+    let start = buf.len();
+    let result = match to_writer(Appender(&mut *buf), envelope, format) {
+        Some(result) => result,
+        None => to_vec(envelope, format).map(|bytes| buf.extend_from_slice(&bytes)),
+    };
+    match result {
+        Ok(()) => Ok(buf.len() - start),
+        Err(e) => {
+            buf.truncate(start);
+            Err(e)
+        }
+    }
+}
+
+/// Counts the bytes written to it.
+struct Counter(usize);
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Serialize> Packet<T> {
+    /// Serializes the packet in the given format.
+    pub fn to_bytes(&self, format: Format) -> Result<Vec<u8>, Error> {
+        to_vec(self, format)
+    }
+
+    /// Serializes the packet onto the end of `buf`, as [`encode_into`].
+    pub fn encode_into<B: Buffer + ?Sized>(
+        &self,
+        format: Format,
+        buf: &mut B,
+    ) -> Result<usize, Error> {
+        encode_into(self, format, buf)
+    }
+
+    /// Returns the number of bytes [`to_bytes`](Packet::to_bytes) would
+    /// produce, so that batching sinks can fit packets within transport
+    /// limits.
+    ///
+    /// For JSON, CBOR and MessagePack the packet is serialized into a
+    /// counter, without building its encoding; for the other formats it is
+    /// encoded and the encoding discarded. Either way the size is exact.
+    pub fn estimated_encoded_size(&self, format: Format) -> Result<usize, Error> {
+        let mut counter = Counter(0);
+        match to_writer(&mut counter, self, format) {
+            Some(result) => result.map(|()| counter.0),
+            None => to_vec(self, format).map(|bytes| bytes.len()),
+        }
+    }
+
+    /// Serializes the packet into the start of `buf`, returning the number of
+    /// bytes written. Fails if `buf` is too small, as it can be sized
+    /// exactly with [`estimated_encoded_size`](Packet::estimated_encoded_size).
+    pub fn encode_to_slice(&self, format: Format, buf: &mut [u8]) -> Result<usize, Error> {
+        let capacity = buf.len();
+        let too_small = || Error {
+            format,
+            message: format!("buffer of {} bytes is too small", capacity),
+        };
+        let mut cursor = io::Cursor::new(buf);
+        match to_writer(&mut cursor, self, format) {
+            Some(Ok(())) => Ok(cursor.position() as usize),
+            // Writers into a full slice fail as a short write, which the
+            // serializers report in their own error types.
+            Some(Err(_)) if cursor.position() as usize == capacity => Err(too_small()),
+            Some(Err(e)) => Err(e),
+            None => {
+                let bytes = to_vec(self, format)?;
+                let buf = cursor.into_inner();
+                if bytes.len() > buf.len() {
+                    return Err(too_small());
+                }
+                buf[..bytes.len()].copy_from_slice(&bytes);
+                Ok(bytes.len())
+            }
+        }
+    }
+}
+
+/// An error serializing an envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    format: Format,
+    message: String,
+}
+
+impl Error {
+    /// The format the envelope was being serialized in.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "encoding as {}: {}", self.format, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Netstat};
+    use crate::{Header, RawPacket};
+
+    #[test]
+    fn round_trips_every_format() {
+        let packet = fixtures::netstat_raw();
+        let typed: Packet<Netstat> = serde_json::from_str(fixtures::NETSTAT_JSON).unwrap();
+        for &format in Format::all() {
+            let bytes = packet.to_bytes(format).unwrap();
+            let raw: RawPacket = Packet::from_bytes(&bytes, format).unwrap();
+            assert_eq!(raw, packet, "{}", format);
+            let decoded: Packet<Netstat> = Packet::from_bytes(&bytes, format).unwrap();
+            assert_eq!(decoded, typed, "{}", format);
+            let header: Header = crate::decode::from_slice(&bytes, format).unwrap();
+            assert_eq!(header.manifest, packet.manifest, "{}", format);
+        }
+    }
+
+    #[test]
+    fn sizes_and_encodes_into_slices() {
+        let packet = fixtures::cpu_raw();
+        for &format in Format::all() {
+            let bytes = packet.to_bytes(format).unwrap();
+            let size = packet.estimated_encoded_size(format).unwrap();
+            assert_eq!(size, bytes.len(), "{}", format);
+
+            let mut buf = vec![0; size + 4];
+            assert_eq!(packet.encode_to_slice(format, &mut buf).unwrap(), size);
+            assert_eq!(&buf[..size], &bytes[..], "{}", format);
+            let error = packet
+                .encode_to_slice(format, &mut buf[..size - 1])
+                .unwrap_err();
+            assert!(error.to_string().contains("too small"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn encodes_into_buffers() {
+        let packet = fixtures::cpu_raw();
+        let mut buf = b"prefix".to_vec();
+        for &format in Format::all() {
+            buf.truncate(6);
+            let n = packet.encode_into(format, &mut buf).unwrap();
+            assert_eq!(&buf[6..], &packet.to_bytes(format).unwrap()[..]);
+            assert_eq!(n, buf.len() - 6);
+        }
+
+        // JSON objects need string keys, which is found only once the
+        // manifest has been written.
+        let mut content = std::collections::HashMap::new();
+        content.insert(vec![1u8], 1);
+        let unencodable = Packet::new(fixtures::cpu_manifest(), content);
+        buf.truncate(6);
+        assert!(unencodable.encode_into(Format::Json, &mut buf).is_err());
+        assert_eq!(buf, b"prefix");
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn encodes_into_bytes_mut() {
+        let packet = fixtures::cpu_raw();
+        let mut buf = bytes::BytesMut::with_capacity(1024);
+        packet.encode_into(Format::Json, &mut buf).unwrap();
+        assert_eq!(&buf[..], &packet.to_bytes(Format::Json).unwrap()[..]);
+    }
+
+    #[test]
+    fn names() {
+        for &format in Format::all() {
+            assert_eq!(format.name().parse::<Format>(), Ok(format));
+        }
+        assert_eq!(
+            "bson".parse::<Format>(),
+            Err(UnknownFormat("bson".to_string()))
+        );
+        assert_eq!(Format::Json.media_type(), "application/json");
+    }
+}
@@ -0,0 +1,404 @@
+//! Encrypting individual content fields.
+//!
+//! A [`FieldEncryptor`] encrypts the content fields at the JSON pointers it
+//! is given, each on its own, and leaves the rest of the content in the
+//! clear, so that a payload with a few sensitive fields can still be
+//! routed, filtered and queried by the others:
+//!
+//! ```
+//! use intermodal::encryption::{FieldEncryptor, Key};
+//! # let mut packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "billing",
+//! #                   "kind": "invoice", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": { "customer": { "email": "ann@example.org" }, "total": 42 }
+//! # })).unwrap();
+//!
+//! let encryptor = FieldEncryptor::new(Key::new("billing-2020", [7; 32]))
+//!     .field("/customer/email")
+//!     .unwrap();
+//! encryptor.encrypt(&mut packet).unwrap();
+//! assert!(packet.content["customer"]["email"].is_string());
+//! assert_eq!(packet.content["total"], 42);
+//!
+//! encryptor.decrypt(&mut packet).unwrap();
+//! assert_eq!(packet.content["customer"]["email"], "ann@example.org");
+//! ```
+//!
+//! Each encrypted field is replaced by a hex string holding a random nonce
+//! and the XChaCha20-Poly1305 encryption of the field's JSON. The packet's
+//! coordinates, the key's id and the field's pointer are its associated
+//! data, so that a ciphertext moved to another field, or to a packet of
+//! another kind, fails to decrypt. Fields may not overlap, since a field
+//! within an encrypted one could not be found.
+//!
+//! The pointers encrypted and the id of the key are recorded in the labels
+//! [`FIELDS_LABEL`] and [`KEY_ID_LABEL`], which tell a receiver what to
+//! decrypt and with which key. Fields that are absent are left so.
+//!
+//! Available with the `encryption` feature.
+
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde_json::Value;
+
+use crate::transform::{self, Transform};
+use crate::{hex, labels, Coordinates, RawPacket};
+
+/// The label listing the pointers of the encrypted fields, as a JSON array.
+pub const FIELDS_LABEL: &str = labels::ENCRYPTED_FIELDS;
+
+/// The label naming the key the fields were encrypted with.
+pub const KEY_ID_LABEL: &str = labels::ENCRYPTION_KEY_ID;
+
+/// The name of the algorithm fields are encrypted with.
+pub const ALGORITHM: &str = "xchacha20poly1305";
+
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit XChaCha20-Poly1305 key, and the id receivers know it by.
+#[derive(Clone)]
+pub struct Key {
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    pub fn new<S: Into<String>>(id: S, key: [u8; 32]) -> Self {
+        Key {
+            id: id.into(),
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Encrypts `plaintext` under a fresh random nonce, returning the nonce
+    /// followed by the ciphertext.
+    pub(crate) fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("encrypting into a Vec cannot fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts what [`encrypt`](Key::encrypt) returned, failing if it was
+    /// not encrypted with this key and associated data.
+    pub(crate) fn decrypt(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The packet already has encrypted fields.
+    AlreadyEncrypted,
+    /// The fields were encrypted with another key.
+    KeyId { expected: String, found: String },
+    /// The encryption labels or an encrypted field are not as written.
+    Malformed(String),
+    /// A field did not decrypt with the key.
+    Decrypt(String),
+    /// A field is within another, or the same as it.
+    Overlap(String, String),
+    /// A field could not be encoded or decoded as JSON.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AlreadyEncrypted => write!(f, "packet already has encrypted fields"),
+            Error::KeyId { expected, found } => write!(
+                f,
+                "fields are encrypted with key `{}`, not `{}`",
+                found, expected
+            ),
+            Error::Malformed(message) => write!(f, "malformed encrypted packet: {}", message),
+            Error::Decrypt(pointer) => write!(f, "field `{}` does not decrypt", pointer),
+            Error::Overlap(a, b) => write!(f, "fields `{}` and `{}` overlap", a, b),
+            Error::Json(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// Encrypts and decrypts the fields at configured JSON pointers.
+#[derive(Debug, Clone)]
+pub struct FieldEncryptor {
+    key: Key,
+    fields: Vec<String>,
+}
+
+impl FieldEncryptor {
+    /// An encryptor with no fields yet, using `key`.
+    pub fn new(key: Key) -> Self {
+        FieldEncryptor {
+            key,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Encrypts the field at the JSON `pointer`, failing if it overlaps a
+    /// field already given.
+    pub fn field<S: Into<String>>(mut self, pointer: S) -> Result<Self, Error> {
+        let pointer = pointer.into();
+        check_overlap(&self.fields, &pointer)?;
+        self.fields.push(pointer);
+        Ok(self)
+    }
+
+    /// Encrypts the configured fields that are present, and records them
+    /// and the key in the packet's labels.
+    pub fn encrypt(&self, packet: &mut RawPacket) -> Result<(), Error> {
+        if packet.manifest.labels.contains_key(FIELDS_LABEL) {
+            return Err(Error::AlreadyEncrypted);
+        }
+        let mut encrypted = Vec::new();
+        for pointer in &self.fields {
+            let field = match packet.content.pointer_mut(pointer) {
+                Some(field) => field,
+                None => continue,
+            };
+            let plaintext = serde_json::to_vec(field)?;
+            let aad = associated_data(packet.manifest.coordinates(), &self.key.id, pointer);
+            let sealed = self.key.encrypt(&plaintext, &aad);
+            *field = Value::String(hex::encode(&sealed));
+            encrypted.push(pointer.as_str());
+        }
+        if encrypted.is_empty() {
+            return Ok(());
+        }
+        let labels = &mut packet.manifest.labels;
+        labels.insert(FIELDS_LABEL.to_string(), serde_json::to_string(&encrypted)?);
+        labels.insert(KEY_ID_LABEL.to_string(), self.key.id.clone());
+        Ok(())
+    }
+
+    /// Decrypts the fields the packet's labels list, and removes the
+    /// labels. A packet without encrypted fields is left as it is.
+    pub fn decrypt(&self, packet: &mut RawPacket) -> Result<(), Error> {
+        let labels = &packet.manifest.labels;
+        let fields: Vec<String> = match labels.get(FIELDS_LABEL) {
+            Some(fields) => serde_json::from_str(fields)
+                .map_err(|e| Error::Malformed(format!("`{}`: {}", FIELDS_LABEL, e)))?,
+            None => return Ok(()),
+        };
+        let key_id = labels.get(KEY_ID_LABEL).map(String::as_str).unwrap_or("");
+        if key_id != self.key.id {
+            return Err(Error::KeyId {
+                expected: self.key.id.clone(),
+                found: key_id.to_string(),
+            });
+        }
+
+        for (i, pointer) in fields.iter().enumerate() {
+            check_overlap(&fields[..i], pointer)
+                .map_err(|e| Error::Malformed(format!("`{}`: {}", FIELDS_LABEL, e)))?;
+        }
+
+        // Decrypt everything before changing anything, so that a packet
+        // that fails is left as it was.
+        let coordinates = packet.manifest.coordinates();
+        let mut plaintexts = Vec::with_capacity(fields.len());
+        for pointer in &fields {
+            let sealed = packet
+                .content
+                .pointer(pointer)
+                .and_then(Value::as_str)
+                .and_then(hex::decode)
+                .ok_or_else(|| Error::Malformed(format!("field `{}` is not encrypted", pointer)))?;
+            let plaintext = self
+                .key
+                .decrypt(
+                    &sealed,
+                    &associated_data(coordinates.clone(), &self.key.id, pointer),
+                )
+                .ok_or_else(|| Error::Decrypt(pointer.clone()))?;
+            plaintexts.push(serde_json::from_slice::<Value>(&plaintext)?);
+        }
+        for (pointer, plaintext) in fields.iter().zip(plaintexts) {
+            if let Some(field) = packet.content.pointer_mut(pointer) {
+                *field = plaintext;
+            }
+        }
+        packet.manifest.labels.remove(FIELDS_LABEL);
+        packet.manifest.labels.remove(KEY_ID_LABEL);
+        Ok(())
+    }
+}
+
+/// Binds a field's ciphertext to the packet's coordinates, the key and the
+/// field, each ended by a NUL.
+fn associated_data(coordinates: Coordinates, key_id: &str, pointer: &str) -> Vec<u8> {
+    let mut aad = Vec::new();
+    for part in [coordinates.to_string().as_str(), key_id, pointer].iter() {
+        aad.extend_from_slice(part.as_bytes());
+        aad.push(0);
+    }
+    aad
+}
+
+/// Fails if `pointer` is one of `fields`, or within or around one of them.
+fn check_overlap(fields: &[String], pointer: &str) -> Result<(), Error> {
+    let within = |inner: &str, outer: &str| {
+        inner
+            .strip_prefix(outer)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    match fields
+        .iter()
+        .find(|field| within(pointer, field) || within(field, pointer))
+    {
+        Some(field) => Err(Error::Overlap(field.clone(), pointer.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Encrypts the configured fields of packets in passing.
+impl Transform for FieldEncryptor {
+    fn apply(&self, mut packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.encrypt(&mut packet)
+            .map_err(|e| transform::Error::new(e.to_string()))?;
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::json;
+
+    fn packet() -> RawPacket {
+        let mut packet = fixtures::cpu_raw();
+        packet.content = json!({ "user": 42.5, "account": { "id": 7, "name": "ann" } });
+        packet
+    }
+
+    #[test]
+    fn encrypts_fields() {
+        let encryptor = FieldEncryptor::new(Key::new("k1", [1; 32]))
+            .field("/account/name")
+            .and_then(|e| e.field("/user"))
+            .and_then(|e| e.field("/missing"))
+            .unwrap();
+        let mut packet = packet();
+        encryptor.encrypt(&mut packet).unwrap();
+        assert_eq!(packet.content["account"]["id"], 7);
+        assert_ne!(packet.content["user"], 42.5);
+        assert_eq!(
+            packet.manifest.labels[FIELDS_LABEL],
+            r#"["/account/name","/user"]"#
+        );
+        assert_eq!(packet.manifest.labels[KEY_ID_LABEL], "k1");
+        assert!(matches!(
+            encryptor.encrypt(&mut packet.clone()),
+            Err(Error::AlreadyEncrypted)
+        ));
+
+        let other = FieldEncryptor::new(Key::new("k2", [1; 32]));
+        assert!(matches!(
+            other.decrypt(&mut packet.clone()),
+            Err(Error::KeyId { .. })
+        ));
+        let wrong = FieldEncryptor::new(Key::new("k1", [2; 32]));
+        let mut failed = packet.clone();
+        assert!(matches!(wrong.decrypt(&mut failed), Err(Error::Decrypt(_))));
+        assert_eq!(failed, packet);
+
+        // A ciphertext moved to another field does not decrypt.
+        let mut swapped = packet.clone();
+        let name = swapped.content["account"]["name"].take();
+        swapped.content["account"]["name"] = swapped.content["user"].take();
+        swapped.content["user"] = name;
+        assert!(matches!(
+            encryptor.decrypt(&mut swapped),
+            Err(Error::Decrypt(_))
+        ));
+
+        // Nor does one moved to a packet of another kind.
+        let mut moved = packet.clone();
+        moved.manifest.kind = "mem".to_string();
+        assert!(matches!(
+            encryptor.decrypt(&mut moved),
+            Err(Error::Decrypt(_))
+        ));
+
+        encryptor.decrypt(&mut packet).unwrap();
+        assert_eq!(packet, self::packet());
+    }
+
+    #[test]
+    fn rejects_overlapping_fields() {
+        let encryptor = FieldEncryptor::new(Key::new("k1", [1; 32]))
+            .field("/account")
+            .unwrap();
+        for pointer in ["/account/name", "/account", ""].iter() {
+            assert!(matches!(
+                encryptor.clone().field(*pointer),
+                Err(Error::Overlap(_, _))
+            ));
+        }
+        assert!(encryptor.clone().field("/accounts").is_ok());
+
+        let mut packet = packet();
+        encryptor.encrypt(&mut packet).unwrap();
+        packet.manifest.labels.insert(
+            FIELDS_LABEL.to_string(),
+            r#"["/account","/account/name"]"#.to_string(),
+        );
+        assert!(matches!(
+            encryptor.decrypt(&mut packet),
+            Err(Error::Malformed(_))
+        ));
+    }
+}
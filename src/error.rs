@@ -0,0 +1,274 @@
+//! A crate-level error for code that calls into several modules.
+//!
+//! Each module reports failures with an error of its own, detailed enough
+//! for its own purposes. Code using several modules can gather them into an
+//! [`Error`], which sorts them into the categories it is likely to react
+//! to, whatever module they came from:
+//!
+//! ```
+//! use intermodal::registry::Registry;
+//! use intermodal::{Error, RawPacket};
+//!
+//! fn handle(registry: &Registry<()>, bytes: &[u8]) -> Result<(), Error> {
+//!     let packet: RawPacket = intermodal::from_slice(bytes)?;
+//!     packet.manifest.validate()?;
+//!     registry.dispatch_raw(packet)?;
+//!     Ok(())
+//! }
+//!
+//! let bytes = br#"{
+//!     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//!                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//!     "content": 86400
+//! }"#;
+//! match handle(&Registry::new(), bytes) {
+//!     Err(Error::UnknownKind(coordinates)) => assert_eq!(coordinates.kind, "uptime"),
+//!     other => panic!("{:?}", other),
+//! }
+//! ```
+//!
+//! The original error stays available as the [`source`] of the decode,
+//! encode and other categories. More categories may be added, so matches on
+//! an [`Error`] need a catch-all arm.
+//!
+//! [`source`]: std::error::Error::source
+
+use std::io;
+
+use crate::{compression, decode, encode, framing, keys, migrate, patch, registry, schema};
+use crate::{stream, transform};
+use crate::{BuildError, Coordinates, ValidationError};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An error from any part of the crate.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// An envelope or its content could not be decoded.
+    #[error("undecodable: {0}")]
+    Decode(#[source] BoxError),
+    /// An envelope or its content could not be encoded.
+    #[error("unencodable: {0}")]
+    Encode(#[source] BoxError),
+    /// A manifest breaks the rules of [`Manifest::validate`](crate::Manifest::validate).
+    #[error("{0}")]
+    Invalid(#[from] ValidationError),
+    /// A manifest could not be built, for want of a field.
+    #[error("{0}")]
+    Incomplete(#[from] BuildError),
+    /// Nothing handles content with these coordinates.
+    #[error("unknown kind: {0}")]
+    UnknownKind(Coordinates),
+    /// Content with these coordinates is of a version that cannot be
+    /// handled, or converted to one that can.
+    #[error("unsupported version: {0}")]
+    VersionMismatch(Coordinates),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// Any other failure.
+    #[error("{0}")]
+    Other(#[source] BoxError),
+}
+
+impl Error {
+    /// Wraps any error as [`Error::Other`].
+    pub fn other<E: Into<BoxError>>(error: E) -> Self {
+        Error::Other(error.into())
+    }
+}
+
+/// A result whose error is the crate-level [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Decode(Box::new(e))
+        }
+    }
+}
+
+impl From<decode::Error> for Error {
+    fn from(e: decode::Error) -> Self {
+        Error::Decode(Box::new(e))
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(e: encode::Error) -> Self {
+        Error::Encode(Box::new(e))
+    }
+}
+
+impl From<registry::Error> for Error {
+    fn from(e: registry::Error) -> Self {
+        match e {
+            registry::Error::Decode(e) => e.into(),
+            registry::Error::Unregistered(coordinates) => Error::UnknownKind(coordinates),
+        }
+    }
+}
+
+impl From<migrate::Error> for Error {
+    fn from(e: migrate::Error) -> Self {
+        match e {
+            migrate::Error::Decode(e) => e.into(),
+            migrate::Error::Type(found) => Error::UnknownKind(found),
+            migrate::Error::Downgrade { from, .. } | migrate::Error::Missing(from) => {
+                Error::VersionMismatch(from)
+            }
+            e @ migrate::Error::Step { .. } => Error::Other(Box::new(e)),
+        }
+    }
+}
+
+impl From<schema::Error> for Error {
+    fn from(e: schema::Error) -> Self {
+        match e {
+            schema::Error::Unregistered(coordinates) => Error::UnknownKind(coordinates),
+            e => Error::Other(Box::new(e)),
+        }
+    }
+}
+
+impl From<stream::Error> for Error {
+    fn from(e: stream::Error) -> Self {
+        match e {
+            stream::Error::Io(e) => Error::Io(e),
+            e @ stream::Error::Decode { .. } => Error::Decode(Box::new(e)),
+            e @ stream::Error::Encode(_) => Error::Encode(Box::new(e)),
+        }
+    }
+}
+
+impl From<framing::Error> for Error {
+    fn from(e: framing::Error) -> Self {
+        match e {
+            framing::Error::Io(e) => Error::Io(e),
+            framing::Error::Encode(e) => e.into(),
+            framing::Error::Decode(e) => e.into(),
+            e => Error::Decode(Box::new(e)),
+        }
+    }
+}
+
+impl From<compression::Error> for Error {
+    fn from(e: compression::Error) -> Self {
+        match e {
+            compression::Error::Io(e) => Error::Io(e),
+            e @ compression::Error::AlreadyCompressed => Error::Other(Box::new(e)),
+            e => Error::Decode(Box::new(e)),
+        }
+    }
+}
+
+impl From<patch::Error> for Error {
+    fn from(e: patch::Error) -> Self {
+        match e {
+            e @ patch::Error::Content(_) | e @ patch::Error::Labels(_) => {
+                Error::Decode(Box::new(e))
+            }
+            #[cfg(feature = "jsonschema")]
+            patch::Error::Schema(e) => e.into(),
+            e => Error::Other(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl From<crate::encryption::Error> for Error {
+    fn from(e: crate::encryption::Error) -> Self {
+        use crate::encryption::Error as E;
+        match e {
+            e @ E::Malformed(_) | e @ E::Decrypt(_) | e @ E::Json(_) => Error::Decode(Box::new(e)),
+            e => Error::Other(Box::new(e)),
+        }
+    }
+}
+
+impl From<keys::Error> for Error {
+    fn from(e: keys::Error) -> Self {
+        match e {
+            keys::Error::Io(e) => Error::Io(e),
+            e => Error::Other(Box::new(e)),
+        }
+    }
+}
+
+impl From<transform::Error> for Error {
+    fn from(e: transform::Error) -> Self {
+        Error::Other(Box::new(e))
+    }
+}
+
+#[cfg(feature = "xml")]
+impl From<crate::xml::Error> for Error {
+    fn from(e: crate::xml::Error) -> Self {
+        Error::Decode(Box::new(e))
+    }
+}
+
+#[cfg(feature = "avro")]
+impl From<crate::avro::Error> for Error {
+    fn from(e: crate::avro::Error) -> Self {
+        match e {
+            e @ crate::avro::Error::Timestamp(_) => Error::Encode(Box::new(e)),
+            e => Error::Decode(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::error::Error as _;
+
+    #[test]
+    fn sorts_errors_by_category() {
+        let e: Error = serde_json::from_str::<crate::RawPacket>("{")
+            .unwrap_err()
+            .into();
+        assert!(matches!(e, Error::Decode(_)));
+        assert!(e.source().unwrap().is::<serde_json::Error>());
+
+        let mut manifest = fixtures::cpu_manifest();
+        manifest.kind.clear();
+        let e: Error = manifest.validate().unwrap_err().into();
+        assert!(matches!(e, Error::Invalid(_)));
+
+        let registry = crate::registry::Registry::<()>::new();
+        let e: Error = registry
+            .dispatch_raw(fixtures::cpu_raw())
+            .unwrap_err()
+            .into();
+        assert!(matches!(&e, Error::UnknownKind(c) if c.kind == "cpu"));
+        assert!(e.to_string().starts_with("unknown kind: "));
+
+        let migrator = migrate::Migrator::new();
+        let e: Error = migrator
+            .upgrade_raw(fixtures::cpu_raw(), 2)
+            .unwrap_err()
+            .into();
+        assert!(matches!(e, Error::VersionMismatch(c) if c.version == 1));
+
+        let e: Error = framing::Error::BadMagic.into();
+        assert!(matches!(e, Error::Decode(_)));
+        let e: Error = compression::Error::TooLarge(1).into();
+        assert!(matches!(e, Error::Decode(_)));
+        let e: Error = keys::Error::NoCurrent.into();
+        assert!(matches!(e, Error::Other(_)));
+        let e: Error = keys::Error::Io(io::ErrorKind::NotFound.into()).into();
+        assert!(matches!(e, Error::Io(_)));
+
+        let e = crate::from_slice::<serde_json::Value>(b"\x00").unwrap_err();
+        assert!(matches!(e, Error::Decode(_)));
+        assert!(e.source().unwrap().is::<decode::Error>());
+
+        let e = Error::other("gone");
+        assert_eq!(e.to_string(), "gone");
+    }
+}
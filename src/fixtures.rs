@@ -0,0 +1,48 @@
+//! Content types and envelopes shared by the unit tests.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Header, Manifest, RawPacket};
+
+pub const CPU_JSON: &str = include_str!("../fixtures/cpu.json");
+pub const NETSTAT_JSON: &str = include_str!("../fixtures/netstat.json");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cpu {
+    pub user: f64,
+    pub system: f64,
+    pub idle: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Netstat {
+    pub connections: Vec<Connection>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Connection {
+    pub proto: String,
+    pub local: String,
+    pub remote: String,
+    pub state: String,
+}
+
+pub fn cpu_manifest() -> Manifest {
+    serde_json::from_str::<Header>(CPU_JSON).unwrap().manifest
+}
+
+pub fn netstat_manifest() -> Manifest {
+    serde_json::from_str::<Header>(NETSTAT_JSON)
+        .unwrap()
+        .manifest
+}
+
+pub fn cpu_raw() -> RawPacket {
+    serde_json::from_str(CPU_JSON).unwrap()
+}
+
+pub fn netstat_raw() -> RawPacket {
+    serde_json::from_str(NETSTAT_JSON).unwrap()
+}
@@ -0,0 +1,1030 @@
+//! A binary framing that keeps the manifest apart from the content.
+//!
+//! Decoding a manifest from an ordinary envelope means parsing past, or
+//! through, its content. A frame instead carries the two in separate
+//! length-prefixed sections, so that a router can decode the manifest alone
+//! and forward the content bytes as they are:
+//!
+//! | bytes | field                                          |
+//! |-------|------------------------------------------------|
+//! | 4     | magic, `\x89IMF`                               |
+//! | 1     | framing version, 1 or 2                        |
+//! | 1     | the [`Format`] of both sections                |
+//! | 4     | manifest length, big-endian                    |
+//! | n     | the manifest                                   |
+//! | 4     | content length, big-endian                     |
+//! | m     | the content                                    |
+//! | 4     | version 2 only: CRC-32 of the bytes before it  |
+//!
+//! Version 2 frames, written by a writer with
+//! [`checksums`](FramedWriter::checksums) on, let a reader tell a frame
+//! damaged in storage from an intact one; see
+//! [`archive`](crate::archive) for checking whole files.
+//!
+//! Formats are numbered JSON 0, YAML 1, TOML 2, XML 3, CBOR 4 and
+//! MessagePack 5. TOML and XML encode only whole envelopes, so frames in
+//! them hold the manifest alone in that format and the content as JSON.
+//!
+//! ```
+//! use intermodal::framing::{FramedReader, FramedWriter};
+//! use intermodal::{Format, Manifest, Packet};
+//!
+//! # let manifest = Manifest::builder().domain("example.org").scope("metrics/host")
+//! #     .kind("uptime").version(1).origin("host01").build().unwrap();
+//! let mut writer = FramedWriter::new(Vec::new());
+//! writer.write_packet(&Packet::new(manifest, 86400), Format::Json).unwrap();
+//!
+//! let bytes = writer.into_inner();
+//! let mut reader = FramedReader::new(&bytes[..]);
+//! let frame = reader.read_frame().unwrap().unwrap();
+//! assert_eq!(frame.manifest.kind, "uptime");
+//! assert_eq!(frame.content, b"86400");
+//! assert!(reader.read_frame().unwrap().is_none());
+//! ```
+//!
+//! Where frames are archived in storage that can read a range of bytes
+//! without the rest, such as a file or, with the `object-store` feature, an
+//! `ObjectRange`, [`scan_manifests`] reads just the manifest of each frame
+//! and finds the [`FrameRange`] it lies in, so that an index of a large
+//! archive can be rebuilt without downloading its content. A frame's
+//! content is then read by its range alone, with [`read_frame_at`]:
+//!
+//! ```
+//! use intermodal::framing::{self, FramedWriter};
+//! use intermodal::{Format, Manifest, Packet};
+//!
+//! # let manifest = Manifest::builder().domain("example.org").scope("metrics/host")
+//! #     .kind("uptime").version(1).origin("host01").build().unwrap();
+//! let mut writer = FramedWriter::new(Vec::new());
+//! writer.write_packet(&Packet::new(manifest.clone(), 86400), Format::Json).unwrap();
+//! writer.write_packet(&Packet::new(manifest, 86460), Format::Json).unwrap();
+//! let archive = writer.into_inner();
+//!
+//! let frames = framing::scan_manifests(&archive[..], archive.len() as u64).unwrap();
+//! assert_eq!(frames.len(), 2);
+//! let frame = framing::read_frame_at(&archive[..], &frames[1].1).unwrap();
+//! assert_eq!(frame.content, b"86460");
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::crc32::Crc32;
+use crate::{decode, encode, Format, Manifest, Packet};
+
+/// The bytes every frame starts with.
+pub const MAGIC: [u8; 4] = *b"\x89IMF";
+
+/// The framing version written by default.
+pub const VERSION: u8 = 1;
+
+/// The framing version of frames ending with a checksum.
+pub const CHECKSUMMED_VERSION: u8 = 2;
+
+/// The largest section a frame can hold.
+pub const MAX_SECTION_LEN: usize = u32::MAX as usize;
+
+/// The largest frame a [`FramedReader`] reads unless set otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 << 20;
+
+fn format_code(format: Format) -> u8 {
+    match format {
+        Format::Json => 0,
+        #[cfg(feature = "yaml")]
+        Format::Yaml => 1,
+        #[cfg(feature = "toml")]
+        Format::Toml => 2,
+        #[cfg(feature = "xml")]
+        Format::Xml => 3,
+        #[cfg(feature = "cbor")]
+        Format::Cbor => 4,
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => 5,
+    }
+}
+
+/// Whether frames of the given version end with a checksum.
+fn checksummed(version: u8) -> Result<bool, Error> {
+    match version {
+        VERSION => Ok(false),
+        CHECKSUMMED_VERSION => Ok(true),
+        other => Err(Error::UnsupportedVersion(other)),
+    }
+}
+
+fn from_code(code: u8) -> Option<Format> {
+    Format::all()
+        .iter()
+        .copied()
+        .find(|&format| format_code(format) == code)
+}
+
+/// The format content sections are in for frames of the given format.
+fn content_format(format: Format) -> Format {
+    match format {
+        #[cfg(feature = "toml")]
+        Format::Toml => Format::Json,
+        #[cfg(feature = "xml")]
+        Format::Xml => Format::Json,
+        other => other,
+    }
+}
+
+/// The length of the frame at the start of `buf`, once enough of it has
+/// arrived to tell. Fails as soon as the prefix is seen to be wrong, or the
+/// frame to be longer than `max`.
+#[cfg(feature = "aio")]
+pub(crate) fn frame_len(buf: &[u8], max: usize) -> Result<Option<usize>, Error> {
+    if buf.len() < 6 {
+        return Ok(None);
+    }
+    if buf[..4] != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let checksummed = checksummed(buf[4])?;
+    from_code(buf[5]).ok_or(Error::UnknownFormat(buf[5]))?;
+
+    let mut len = 6;
+    for _ in 0..2 {
+        let section = match buf.get(len..len + 4) {
+            Some(section) => u32::from_be_bytes([section[0], section[1], section[2], section[3]]),
+            None => return Ok(None),
+        };
+        len += 4 + section as usize;
+        if len > max {
+            return Err(Error::TooLarge(len));
+        }
+    }
+    if checksummed {
+        len += 4;
+    }
+    Ok(Some(len))
+}
+
+/// The encoding of a manifest within envelope formats that cannot encode a
+/// bare manifest.
+#[derive(Serialize, serde::Deserialize)]
+struct ManifestOnly<M> {
+    manifest: M,
+}
+
+/// One framed envelope: its decoded manifest and its still-encoded content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub manifest: Manifest,
+    pub format: Format,
+    /// The content, encoded in `format`, or in JSON for TOML and XML frames.
+    pub content: Vec<u8>,
+}
+
+impl Frame {
+    /// Frames a packet, encoding its content in `format`.
+    pub fn from_packet<T: Serialize>(packet: &Packet<T>, format: Format) -> Result<Self, Error> {
+        Ok(Frame {
+            manifest: packet.manifest.clone(),
+            format,
+            content: encode::to_vec(&packet.content, content_format(format))
+                .map_err(Error::Encode)?,
+        })
+    }
+
+    /// Decodes the content, returning the packet.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<Packet<T>, Error> {
+        let content = decode::from_slice(&self.content, content_format(self.format))
+            .map_err(Error::Decode)?;
+        Ok(Packet::new(self.manifest.clone(), content))
+    }
+}
+
+/// Writes frames to an underlying writer.
+///
+/// The writer keeps the buffers it encodes manifests and content into, so
+/// that writing a packet allocates only while the buffers grow. Each frame
+/// goes to the underlying writer in one vectored write where it can take
+/// it, rather than one write per section.
+#[derive(Debug)]
+pub struct FramedWriter<W> {
+    inner: W,
+    manifest: Vec<u8>,
+    content: Vec<u8>,
+    checksums: bool,
+}
+
+impl<W: Write> FramedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        FramedWriter {
+            inner,
+            manifest: Vec::new(),
+            content: Vec::new(),
+            checksums: false,
+        }
+    }
+
+    /// Ends each frame with a checksum, writing version 2 frames.
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Changes whether the frames written from now on end with a checksum.
+    pub(crate) fn set_checksums(&mut self, checksums: bool) {
+        self.checksums = checksums;
+    }
+
+    /// Frames and writes a packet.
+    pub fn write_packet<T: Serialize>(
+        &mut self,
+        packet: &Packet<T>,
+        format: Format,
+    ) -> Result<(), Error> {
+        let mut content = std::mem::take(&mut self.content);
+        content.clear();
+        let result = encode::encode_into(&packet.content, content_format(format), &mut content)
+            .map_err(Error::Encode)
+            .and_then(|_| self.write_parts(&packet.manifest, format, &content));
+        self.content = content;
+        result
+    }
+
+    /// Writes a frame, as read from elsewhere.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        self.write_parts(&frame.manifest, frame.format, &frame.content)
+    }
+
+    /// Writes a frame of a manifest and content already encoded in
+    /// `format`, as when forwarding content without decoding it.
+    pub fn write_parts(
+        &mut self,
+        manifest: &Manifest,
+        format: Format,
+        content: &[u8],
+    ) -> Result<(), Error> {
+        self.manifest.clear();
+        encode_manifest(manifest, format, &mut self.manifest)?;
+
+        let mut head = [0; 10];
+        head[..4].copy_from_slice(&MAGIC);
+        head[4] = if self.checksums {
+            CHECKSUMMED_VERSION
+        } else {
+            VERSION
+        };
+        head[5] = format_code(format);
+        head[6..].copy_from_slice(&section_len(&self.manifest)?);
+        let content_len = section_len(content)?;
+        let mut crc = Crc32::new();
+        for part in [&head[..], &self.manifest, &content_len, content] {
+            crc.update(part);
+        }
+        let checksum = crc.finish().to_be_bytes();
+        let mut slices = [
+            IoSlice::new(&head),
+            IoSlice::new(&self.manifest),
+            IoSlice::new(&content_len),
+            IoSlice::new(content),
+            IoSlice::new(if self.checksums { &checksum } else { &[] }),
+        ];
+        write_all_vectored(&mut self.inner, &mut slices)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.inner.flush()?)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+fn write_all_vectored<W: Write>(writer: &mut W, mut slices: &mut [IoSlice<'_>]) -> io::Result<()> {
+    // Drop leading empty slices, so that an empty frame section does not
+    // look like a writer that accepts nothing.
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn encode_manifest(manifest: &Manifest, format: Format, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let written = if content_format(format) == format {
+        encode::encode_into(manifest, format, buf)
+    } else {
+        encode::encode_into(&ManifestOnly { manifest }, format, buf)
+    };
+    written.map(|_| ()).map_err(Error::Encode)
+}
+
+fn decode_manifest(bytes: &[u8], format: Format) -> Result<Manifest, Error> {
+    let manifest = if content_format(format) == format {
+        decode::from_slice(bytes, format)
+    } else {
+        decode::from_slice(bytes, format).map(|only: ManifestOnly<Manifest>| only.manifest)
+    };
+    manifest.map_err(Error::Decode)
+}
+
+fn section_len(section: &[u8]) -> Result<[u8; 4], Error> {
+    u32::try_from(section.len())
+        .map(u32::to_be_bytes)
+        .map_err(|_| Error::TooLarge(section.len()))
+}
+
+/// A reader counting the bytes read through it, to tell where each frame
+/// starts.
+pub(crate) struct Counted<R> {
+    pub(crate) inner: R,
+    pub(crate) count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads frames from an underlying reader.
+#[derive(Debug)]
+pub struct FramedReader<R> {
+    inner: R,
+    max_frame_len: usize,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(inner: R) -> Self {
+        FramedReader {
+            inner,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+
+    /// Sets the largest frame to read, [`DEFAULT_MAX_FRAME_LEN`] unless
+    /// set. A frame whose sections are declared longer fails with
+    /// [`Error::TooLarge`] before they are read.
+    pub fn max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len;
+        self
+    }
+
+    /// Reads the next frame, decoding its manifest but not its content.
+    /// Returns `None` at the end of input between frames.
+    pub fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        Ok(self.read_checked_frame()?.map(|(frame, _)| frame))
+    }
+
+    /// Reads the next frame, and whether its checksum was checked.
+    pub(crate) fn read_checked_frame(&mut self) -> Result<Option<(Frame, bool)>, Error> {
+        let mut prefix = [0; 6];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            match self.inner.read(&mut prefix[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if prefix[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let checksummed = checksummed(prefix[4])?;
+        let format = from_code(prefix[5]).ok_or(Error::UnknownFormat(prefix[5]))?;
+
+        let mut len = prefix.len();
+        let manifest = self.read_section(&mut len)?;
+        let content = self.read_section(&mut len)?;
+        if checksummed {
+            let mut crc = Crc32::new();
+            crc.update(&prefix);
+            for section in [&manifest, &content] {
+                crc.update(&section_len(section)?);
+                crc.update(section);
+            }
+            let mut expected = [0; 4];
+            self.inner.read_exact(&mut expected)?;
+            let (expected, actual) = (u32::from_be_bytes(expected), crc.finish());
+            if expected != actual {
+                return Err(Error::Checksum { expected, actual });
+            }
+        }
+        let manifest = decode_manifest(&manifest, format)?;
+        let frame = Frame {
+            manifest,
+            format,
+            content,
+        };
+        Ok(Some((frame, checksummed)))
+    }
+
+    /// Reads a section, adding its length to `frame_len`, the length of the
+    /// frame so far.
+    fn read_section(&mut self, frame_len: &mut usize) -> Result<Vec<u8>, Error> {
+        let mut len = [0; 4];
+        self.inner.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        *frame_len = frame_len.saturating_add(4 + len);
+        if *frame_len > self.max_frame_len {
+            return Err(Error::TooLarge(*frame_len));
+        }
+        let len = len as u64;
+        let mut section = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut section)?;
+        if (section.len() as u64) < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(section)
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Yields frames until the end of input.
+impl<R: Read> Iterator for FramedReader<R> {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Where a frame lies in an archive of frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FrameRange {
+    /// The offset of the frame's first byte.
+    pub offset: u64,
+    pub manifest_len: u32,
+    pub content_len: u32,
+    /// Whether the frame ends with a checksum.
+    pub checksummed: bool,
+}
+
+impl FrameRange {
+    /// The bytes of the whole frame.
+    pub fn range(&self) -> Range<u64> {
+        let trailer = if self.checksummed { 4 } else { 0 };
+        self.offset..self.content_range().end + trailer
+    }
+
+    /// The bytes of the encoded manifest.
+    pub fn manifest_range(&self) -> Range<u64> {
+        let start = self.offset + 10;
+        start..start + u64::from(self.manifest_len)
+    }
+
+    /// The bytes of the encoded content.
+    pub fn content_range(&self) -> Range<u64> {
+        let start = self.manifest_range().end + 4;
+        start..start + u64::from(self.content_len)
+    }
+}
+
+/// Storage that can read a range of the bytes it holds without reading the
+/// rest, such as a file or an object store.
+pub trait RangeRead {
+    /// Reads the bytes in `range`, failing with
+    /// [`UnexpectedEof`](io::ErrorKind::UnexpectedEof) if they run out first.
+    fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>>;
+}
+
+impl RangeRead for [u8] {
+    fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        usize::try_from(range.start)
+            .ok()
+            .zip(usize::try_from(range.end).ok())
+            .and_then(|(start, end)| self.get(start..end))
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+impl RangeRead for File {
+    fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let mut file = self;
+        file.seek(SeekFrom::Start(range.start))?;
+        // Grow the buffer as bytes arrive, so a corrupt length costs no more
+        // than the file holds.
+        let len = range.end.saturating_sub(range.start);
+        let mut bytes = Vec::new();
+        file.take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+}
+
+/// Reads `range` from `source`, failing unless exactly its bytes come back.
+fn read_exact_range<S: RangeRead + ?Sized>(
+    source: &S,
+    range: Range<u64>,
+) -> Result<Vec<u8>, Error> {
+    let len = range.end - range.start;
+    let bytes = source.read_range(range)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Reads the prefix of the frame starting at `offset`: its format, whether
+/// it ends with a checksum, and the length of its manifest.
+fn prefix_at<S: RangeRead + ?Sized>(source: &S, offset: u64) -> Result<(Format, bool, u32), Error> {
+    let head = read_exact_range(source, offset..offset + 10)?;
+    if head[..4] != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let checksummed = checksummed(head[4])?;
+    let format = from_code(head[5]).ok_or(Error::UnknownFormat(head[5]))?;
+    let manifest_len = u32::from_be_bytes([head[6], head[7], head[8], head[9]]);
+    Ok((format, checksummed, manifest_len))
+}
+
+/// Fails if a frame of `len` bytes is longer than `max`.
+fn check_len(len: u64, max: u64) -> Result<(), Error> {
+    if len > max {
+        return Err(Error::TooLarge(usize::try_from(len).unwrap_or(usize::MAX)));
+    }
+    Ok(())
+}
+
+/// Reads the manifest of the frame starting at `offset`, and where the
+/// frame lies, reading neither its content nor anything around it.
+pub fn read_manifest_at<S: RangeRead + ?Sized>(
+    source: &S,
+    offset: u64,
+) -> Result<(Manifest, FrameRange), Error> {
+    read_manifest_at_within(source, offset, u64::MAX)
+}
+
+/// Like [`read_manifest_at`], but fails with [`Error::TooLarge`] before
+/// reading the manifest of a frame longer than `max` bytes.
+pub fn read_manifest_at_within<S: RangeRead + ?Sized>(
+    source: &S,
+    offset: u64,
+    max: u64,
+) -> Result<(Manifest, FrameRange), Error> {
+    let (format, checksummed, manifest_len) = prefix_at(source, offset)?;
+    check_len(14 + u64::from(manifest_len), max)?;
+
+    // Read the content's length along with the manifest, rather than apart.
+    let start = offset + 10;
+    let mut section = read_exact_range(source, start..start + u64::from(manifest_len) + 4)?;
+    let content_len = section.split_off(manifest_len as usize);
+    let content_len = u32::from_be_bytes([
+        content_len[0],
+        content_len[1],
+        content_len[2],
+        content_len[3],
+    ]);
+    let range = FrameRange {
+        offset,
+        manifest_len,
+        content_len,
+        checksummed,
+    };
+    check_len(range.range().end - offset, max)?;
+    Ok((decode_manifest(&section, format)?, range))
+}
+
+/// Finds where the frame starting at `offset` lies, reading only its
+/// prefix and the lengths of its sections.
+pub fn frame_range_at<S: RangeRead + ?Sized>(source: &S, offset: u64) -> Result<FrameRange, Error> {
+    let (_, checksummed, manifest_len) = prefix_at(source, offset)?;
+    let start = offset + 10 + u64::from(manifest_len);
+    let len = read_exact_range(source, start..start + 4)?;
+    Ok(FrameRange {
+        offset,
+        manifest_len,
+        content_len: u32::from_be_bytes([len[0], len[1], len[2], len[3]]),
+        checksummed,
+    })
+}
+
+/// Reads the frame in `range`, as found by [`read_manifest_at`] or
+/// [`scan_manifests`], in one read.
+pub fn read_frame_at<S: RangeRead + ?Sized>(
+    source: &S,
+    range: &FrameRange,
+) -> Result<Frame, Error> {
+    read_frame_at_within(source, range, u64::MAX)
+}
+
+/// Like [`read_frame_at`], but fails with [`Error::TooLarge`] before
+/// reading a frame longer than `max` bytes.
+pub fn read_frame_at_within<S: RangeRead + ?Sized>(
+    source: &S,
+    range: &FrameRange,
+    max: u64,
+) -> Result<Frame, Error> {
+    let range = range.range();
+    check_len(range.end - range.start, max)?;
+    let bytes = read_exact_range(source, range)?;
+    FramedReader::new(&bytes[..])
+        .max_frame_len(usize::MAX)
+        .read_frame()?
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+/// Reads the manifest of every frame in the first `len` bytes of `source`,
+/// and where each frame lies, skipping over their content. Each frame
+/// takes two reads.
+pub fn scan_manifests<S: RangeRead + ?Sized>(
+    source: &S,
+    len: u64,
+) -> Result<Vec<(Manifest, FrameRange)>, Error> {
+    scan_manifests_within(source, len, u64::MAX)
+}
+
+/// Like [`scan_manifests`], but fails with [`Error::TooLarge`] at the first
+/// frame longer than `max` bytes.
+pub fn scan_manifests_within<S: RangeRead + ?Sized>(
+    source: &S,
+    len: u64,
+    max: u64,
+) -> Result<Vec<(Manifest, FrameRange)>, Error> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let (manifest, range) = read_manifest_at_within(source, offset, max)?;
+        offset = range.range().end;
+        found.push((manifest, range));
+    }
+    if offset > len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(found)
+}
+
+#[cfg(feature = "object-store")]
+pub use self::object::ObjectRange;
+
+#[cfg(feature = "object-store")]
+mod object {
+    use std::io;
+    use std::ops::Range;
+    use std::sync::Arc;
+
+    use object_store::path::Path;
+    use object_store::{ObjectStore, ObjectStoreExt};
+    use tokio::runtime::Handle;
+
+    use super::RangeRead;
+
+    /// An object in an [`ObjectStore`], read a range at a time.
+    ///
+    /// `object_store` is asynchronous, so each read blocks on the given
+    /// runtime, and must be made from outside the runtime's worker threads.
+    #[derive(Debug, Clone)]
+    pub struct ObjectRange {
+        store: Arc<dyn ObjectStore>,
+        path: Path,
+        runtime: Handle,
+    }
+
+    impl ObjectRange {
+        pub fn new<P: Into<Path>>(store: Arc<dyn ObjectStore>, path: P, runtime: Handle) -> Self {
+            ObjectRange {
+                store,
+                path: path.into(),
+                runtime,
+            }
+        }
+
+        /// The size of the object, as a bound for
+        /// [`scan_manifests`](super::scan_manifests).
+        pub fn size(&self) -> io::Result<u64> {
+            self.runtime
+                .block_on(self.store.head(&self.path))
+                .map(|meta| meta.size)
+                .map_err(io::Error::other)
+        }
+    }
+
+    impl RangeRead for ObjectRange {
+        fn read_range(&self, range: Range<u64>) -> io::Result<Vec<u8>> {
+            let expected = range.end - range.start;
+            let bytes = self
+                .runtime
+                .block_on(self.store.get_range(&self.path, range))
+                .map_err(io::Error::other)?;
+            if (bytes.len() as u64) < expected {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(bytes.to_vec())
+        }
+    }
+}
+
+/// An error reading or writing frames.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The input does not start with [`MAGIC`].
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The frame's format is unknown, or not enabled in this build.
+    UnknownFormat(u8),
+    /// A section is longer than [`MAX_SECTION_LEN`], or a frame longer
+    /// than the most a reader was given, such as
+    /// [`FramedReader::max_frame_len`].
+    TooLarge(usize),
+    /// A checksummed frame does not match its checksum.
+    Checksum {
+        expected: u32,
+        actual: u32,
+    },
+    Encode(encode::Error),
+    Decode(decode::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::BadMagic => write!(f, "not a frame: bad magic bytes"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported framing version {}", version)
+            }
+            Error::UnknownFormat(code) => write!(f, "unknown frame format {}", code),
+            Error::TooLarge(len) => write!(f, "frame section of {} bytes is too large", len),
+            Error::Checksum { expected, actual } => write!(
+                f,
+                "frame checksum mismatch: expected {:08x}, found {:08x}",
+                expected, actual
+            ),
+            Error::Encode(e) => write!(f, "{}", e),
+            Error::Decode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Encode(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Netstat};
+
+    #[test]
+    fn frames_round_trip() {
+        let mut writer = FramedWriter::new(Vec::new());
+        for &format in Format::all() {
+            writer
+                .write_packet(&fixtures::netstat_raw(), format)
+                .unwrap();
+        }
+        let bytes = writer.into_inner();
+
+        let frames: Vec<Frame> = FramedReader::new(&bytes[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(frames.len(), Format::all().len());
+        for (frame, &format) in frames.iter().zip(Format::all()) {
+            assert_eq!(frame.format, format);
+            assert_eq!(frame.manifest, fixtures::netstat_manifest());
+            let packet: Packet<Netstat> = frame.decode().unwrap();
+            assert_eq!(packet.content.connections.len(), 2);
+        }
+    }
+
+    #[test]
+    fn forwards_content_untouched() {
+        let frame = Frame::from_packet(&fixtures::cpu_raw(), Format::Json).unwrap();
+        let mut writer = FramedWriter::new(Vec::new());
+        writer.write_frame(&frame).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(&bytes[..6], b"\x89IMF\x01\x00");
+
+        let mut forwarded = FramedWriter::new(Vec::new());
+        for frame in FramedReader::new(&bytes[..]) {
+            forwarded.write_frame(&frame.unwrap()).unwrap();
+        }
+        assert_eq!(forwarded.into_inner(), bytes);
+    }
+
+    #[test]
+    fn reads_ranges() {
+        let mut writer = FramedWriter::new(Vec::new());
+        writer
+            .write_packet(&fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        writer
+            .write_packet(&fixtures::netstat_raw(), Format::Json)
+            .unwrap();
+        let archive = writer.into_inner();
+
+        let path = std::env::temp_dir().join(format!("intermodal-ranges-{}", std::process::id()));
+        std::fs::write(&path, &archive).unwrap();
+        let file = File::open(&path).unwrap();
+        let frames = scan_manifests(&file, archive.len() as u64).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, fixtures::cpu_manifest());
+        assert_eq!(frames[1].0, fixtures::netstat_manifest());
+        assert_eq!(frames[0].1.offset, 0);
+        assert_eq!(frames[1].1.offset, frames[0].1.range().end);
+        assert_eq!(frames[1].1.range().end, archive.len() as u64);
+
+        let (manifest, range) = read_manifest_at(&file, frames[1].1.offset).unwrap();
+        assert_eq!((manifest, range), frames[1].clone());
+        assert_eq!(frame_range_at(&file, range.offset).unwrap(), range);
+        let frame = read_frame_at(&file, &range).unwrap();
+        let packet: Packet<Netstat> = frame.decode().unwrap();
+        assert_eq!(packet.content.connections.len(), 2);
+        let content = file.read_range(range.content_range()).unwrap();
+        assert_eq!(content, frame.content);
+
+        assert!(scan_manifests(&archive[..], archive.len() as u64 - 1).is_err());
+        assert!(matches!(
+            read_manifest_at(&archive[..], 1),
+            Err(Error::BadMagic)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Storage that returns fewer bytes than asked for.
+    struct Short;
+
+    impl RangeRead for Short {
+        fn read_range(&self, _: Range<u64>) -> io::Result<Vec<u8>> {
+            Ok(b"\x89IMF".to_vec())
+        }
+    }
+
+    #[test]
+    fn bounds_frames_read() {
+        // A prefix claiming a manifest of 4 GiB, with the rest to come.
+        let claimed = b"\x89IMF\x01\x00\xff\xff\xff\xff";
+        assert!(matches!(
+            FramedReader::new(&claimed[..]).read_frame(),
+            Err(Error::TooLarge(len)) if len == 10 + u32::MAX as usize
+        ));
+
+        let mut writer = FramedWriter::new(Vec::new());
+        writer
+            .write_packet(&fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        let frame = writer.into_inner();
+        let read = |max: usize| {
+            FramedReader::new(&frame[..])
+                .max_frame_len(max)
+                .read_frame()
+        };
+        assert!(read(frame.len()).unwrap().is_some());
+        assert!(matches!(read(frame.len() - 1), Err(Error::TooLarge(_))));
+    }
+
+    #[test]
+    fn bounds_range_reads() {
+        // A prefix claiming a manifest of 4 GiB, followed by nothing.
+        let archive = b"\x89IMF\x01\x00\xff\xff\xff\xff";
+        let path = std::env::temp_dir().join(format!("intermodal-bounds-{}", std::process::id()));
+        std::fs::write(&path, archive).unwrap();
+        let file = File::open(&path).unwrap();
+        let eof = |error: Option<Error>| matches!(error, Some(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof);
+        assert!(eof(read_manifest_at(&file, 0).err()));
+        assert!(eof(read_manifest_at(&archive[..], 0).err()));
+        assert!(matches!(
+            read_manifest_at_within(&file, 0, 1 << 20),
+            Err(Error::TooLarge(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut writer = FramedWriter::new(Vec::new());
+        writer
+            .write_packet(&fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        let archive = writer.into_inner();
+        let max = archive.len() as u64;
+        assert_eq!(
+            scan_manifests_within(&archive[..], max, max).unwrap().len(),
+            1
+        );
+        assert!(matches!(
+            scan_manifests_within(&archive[..], max, max - 1),
+            Err(Error::TooLarge(_))
+        ));
+        let range = frame_range_at(&archive[..], 0).unwrap();
+        assert!(matches!(
+            read_frame_at_within(&archive[..], &range, max - 1),
+            Err(Error::TooLarge(_))
+        ));
+
+        assert!(eof(read_manifest_at(&Short, 0).err()));
+        assert!(eof(frame_range_at(&Short, 0).err()));
+    }
+
+    /// A writer that takes at most a few bytes per call.
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn survives_short_writes() {
+        let mut whole = FramedWriter::new(Vec::new());
+        let mut trickled = FramedWriter::new(Trickle(Vec::new()));
+        for packet in &[fixtures::cpu_raw(), fixtures::netstat_raw()] {
+            whole.write_packet(packet, Format::Json).unwrap();
+            trickled.write_packet(packet, Format::Json).unwrap();
+        }
+        trickled
+            .write_parts(&fixtures::cpu_manifest(), Format::Json, b"")
+            .unwrap();
+        whole
+            .write_parts(&fixtures::cpu_manifest(), Format::Json, b"")
+            .unwrap();
+        assert_eq!(trickled.into_inner().0, whole.into_inner());
+    }
+
+    #[test]
+    fn checks_checksums() {
+        let mut writer = FramedWriter::new(Vec::new()).checksums(true);
+        writer
+            .write_packet(&fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        let mut bytes = writer.into_inner();
+        assert_eq!(bytes[4], CHECKSUMMED_VERSION);
+        let frames = scan_manifests(&bytes[..], bytes.len() as u64).unwrap();
+        assert_eq!(frames[0].1.range(), 0..bytes.len() as u64);
+        assert!(FramedReader::new(&bytes[..]).read_frame().is_ok());
+
+        let last = bytes.len() - 5;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            FramedReader::new(&bytes[..]).read_frame(),
+            Err(Error::Checksum { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let read = |bytes: &[u8]| FramedReader::new(bytes).read_frame();
+        assert!(matches!(read(b"\x89IMP\x01\x00"), Err(Error::BadMagic)));
+        assert!(matches!(
+            read(b"\x89IMF\x03\x00"),
+            Err(Error::UnsupportedVersion(3))
+        ));
+        assert!(matches!(
+            read(b"\x89IMF\x01\xff"),
+            Err(Error::UnknownFormat(255))
+        ));
+        assert!(matches!(read(b"\x89IM"), Err(Error::Io(_))));
+        assert!(matches!(
+            read(b"\x89IMF\x01\x00\x00\x00\x00\x10{}"),
+            Err(Error::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{Manifest, Packet};
+
+/// The manifest portion of an envelope, without its content.
+///
+/// Deserializing a `Header` from a serialized [`Packet`](crate::Packet)
+/// ignores the `content` field, so consumers can inspect what a packet
+/// carries before deciding how, or whether, to decode the rest of it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Header {
+    pub manifest: Manifest,
+}
+
+impl Header {
+    /// Builds a header from a borrowed manifest, cloning it.
+    ///
+    /// Prefer `Header::from(manifest)` when the manifest is owned and no
+    /// longer needed, which moves it instead.
+    pub fn from_ref(manifest: &Manifest) -> Self {
+        Header {
+            manifest: manifest.clone(),
+        }
+    }
+
+    /// Completes the header into a packet carrying the given content.
+    pub fn into_packet<T>(self, content: T) -> Packet<T> {
+        Packet::new(self.manifest, content)
+    }
+
+    /// Completes the header into a packet carrying default content, such as a
+    /// placeholder for content that was unavailable or deliberately withheld.
+    pub fn with_default_content<T: Default>(self) -> Packet<T> {
+        self.into_packet(T::default())
+    }
+
+    /// Completes the header into a packet by decoding `content` as the JSON
+    /// encoding of its content.
+    ///
+    /// This suits gateways that parsed only the header of an envelope and
+    /// received the content separately, as bytes.
+    pub fn try_complete<T: DeserializeOwned>(
+        self,
+        content: &[u8],
+    ) -> Result<Packet<T>, serde_json::Error> {
+        let content = serde_json::from_slice(content)?;
+        Ok(self.into_packet(content))
+    }
+}
+
+impl From<Manifest> for Header {
+    fn from(manifest: Manifest) -> Self {
+        Header { manifest }
+    }
+}
+
+impl From<&Manifest> for Header {
+    fn from(manifest: &Manifest) -> Self {
+        Header::from_ref(manifest)
+    }
+}
+
+impl<T> From<Packet<T>> for Header {
+    fn from(packet: Packet<T>) -> Self {
+        Header {
+            manifest: packet.manifest,
+        }
+    }
+}
+
+impl<T> From<&Packet<T>> for Header {
+    fn from(packet: &Packet<T>) -> Self {
+        Header::from_ref(&packet.manifest)
+    }
+}
+
+impl AsRef<Manifest> for Header {
+    fn as_ref(&self) -> &Manifest {
+        &self.manifest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+
+    fn coordinates<M: AsRef<Manifest>>(m: M) -> String {
+        let m = m.as_ref();
+        format!("{}/{}/{}@{}", m.domain, m.scope, m.kind, m.version)
+    }
+
+    #[test]
+    fn conversions() {
+        let packet = fixtures::cpu_raw();
+        let header = Header::from(&packet);
+        assert_eq!(coordinates(&header), coordinates(&packet));
+        assert_eq!(
+            coordinates(Header::from(&packet.manifest)),
+            coordinates(&packet.manifest)
+        );
+        assert_eq!(Header::from(packet).manifest.kind, "cpu");
+    }
+
+    #[test]
+    fn complete_with_default() {
+        let header = Header::from(fixtures::cpu_manifest());
+        let packet = header.with_default_content::<Vec<u32>>();
+        assert_eq!(packet.manifest.kind, "cpu");
+        assert!(packet.content.is_empty());
+    }
+
+    #[test]
+    fn try_complete() {
+        let header = Header::from(fixtures::cpu_manifest());
+        let packet: Packet<Cpu> = header
+            .clone()
+            .try_complete(br#"{"user": 1.0, "system": 2.0, "idle": 97.0}"#)
+            .unwrap();
+        assert_eq!(packet.content.idle, 97.0);
+        assert_eq!(packet.manifest.ctime, header.manifest.ctime);
+
+        assert!(header.try_complete::<Cpu>(b"{\"user\": 1.0}").is_err());
+    }
+}
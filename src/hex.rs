@@ -0,0 +1,18 @@
+//! Hex encoding, for keys, signatures, ciphertexts and trace ids written as
+//! text.
+
+/// Encodes bytes as lowercase hex.
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes hex, of either case, or returns `None` if it is not hex.
+pub(crate) fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,86 @@
+//! HKDF-SHA256 (RFC 5869), for deriving a key per algorithm from a key's
+//! material.
+
+use sha2::{Digest, Sha256};
+
+const BLOCK_LEN: usize = 64;
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut block = [0; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+
+    let mut inner = Sha256::new();
+    inner.update(pad(0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Sha256::new();
+    outer.update(pad(0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+    hmac(salt, &[ikm])
+}
+
+/// Fills `out`, which must be at most 255 blocks of 32 bytes.
+fn expand(prk: &[u8; 32], info: &[u8], out: &mut [u8]) {
+    let mut previous: Option<[u8; 32]> = None;
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+        let counter = [i as u8 + 1];
+        let block = match &previous {
+            Some(previous) => hmac(prk, &[previous, info, &counter]),
+            None => hmac(prk, &[info, &counter]),
+        };
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        previous = Some(block);
+    }
+}
+
+/// Derives a 32-byte key from `ikm` for the use `info` names, with no salt.
+pub(crate) fn derive(ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    expand(&extract(&[], ikm), info, &mut key);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+
+    #[test]
+    fn matches_rfc_5869() {
+        // Test case 1.
+        let prk = extract(
+            &hex::decode("000102030405060708090a0b0c").unwrap(),
+            &[0x0b; 22],
+        );
+        assert_eq!(
+            hex::encode(&prk),
+            "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5"
+        );
+        let mut okm = [0; 42];
+        expand(
+            &prk,
+            &hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap(),
+            &mut okm,
+        );
+        assert_eq!(
+            hex::encode(&okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+
+        // Test case 3, with no salt or info.
+        assert_eq!(
+            hex::encode(&derive(&[0x0b; 22], b"")),
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d"
+        );
+    }
+}
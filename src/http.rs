@@ -0,0 +1,354 @@
+//! Carrying manifests in HTTP headers.
+//!
+//! [`to_headers`] writes a manifest into an [`http::HeaderMap`], and
+//! [`from_headers`] reads it back, so that a packet travels as an HTTP
+//! request or response whose body is the content alone. An edge service
+//! routes on the headers without buffering or parsing bodies, whatever
+//! framework it is built with:
+//!
+//! ```
+//! use intermodal::{http, Format, Manifest, Packet};
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .label("tenant", "acme")
+//!     .build()
+//!     .unwrap();
+//! let packet = Packet::new(manifest, 86400u64);
+//!
+//! let (headers, body) = http::to_parts(&packet, Format::Json)?;
+//! assert_eq!(headers["intermodal-kind"], "uptime");
+//! assert_eq!(http::from_headers(&headers)?.kind, "uptime");
+//! assert_eq!(http::from_parts::<u64>(&headers, &body)?, packet);
+//! # Ok::<(), http::Error>(())
+//! ```
+//!
+//! The headers are:
+//!
+//! | Header | Holds |
+//! |---|---|
+//! | `Intermodal-Domain`, `Intermodal-Scope`, `Intermodal-Kind`, `Intermodal-Version` | The coordinates |
+//! | `Intermodal-Ctime`, `Intermodal-Expires` | Times, in RFC 3339 |
+//! | `Intermodal-Origin`, `Intermodal-Correlation-Id`, `Intermodal-Reply-To` | As in the manifest |
+//! | `Intermodal-Label` | One `key=value` per label, repeated |
+//! | `traceparent`, `tracestate` | The W3C trace context |
+//! | `Intermodal-Signature` | The signature, as JSON |
+//! | `Content-Type` | The media type of the body, with [`to_parts`] |
+//!
+//! Header values may hold only visible ASCII, so text is percent-encoded:
+//! `%`, and any byte that is not visible ASCII, spaces among them, is
+//! written as `%` and two hex digits, as are `,` and `=` in labels. Labels may also arrive
+//! combined into one comma-separated header, as proxies are allowed to
+//! combine them. A packet's provenance is not carried.
+//!
+//! Available with the `http` feature.
+
+use std::fmt;
+
+use ::http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::signing::Signature;
+use crate::trace::TraceContext;
+use crate::{decode, encode, Format, Manifest, Packet};
+
+pub const DOMAIN: HeaderName = HeaderName::from_static("intermodal-domain");
+pub const SCOPE: HeaderName = HeaderName::from_static("intermodal-scope");
+pub const KIND: HeaderName = HeaderName::from_static("intermodal-kind");
+pub const VERSION: HeaderName = HeaderName::from_static("intermodal-version");
+pub const CTIME: HeaderName = HeaderName::from_static("intermodal-ctime");
+pub const EXPIRES: HeaderName = HeaderName::from_static("intermodal-expires");
+pub const ORIGIN: HeaderName = HeaderName::from_static("intermodal-origin");
+pub const LABEL: HeaderName = HeaderName::from_static("intermodal-label");
+pub const CORRELATION_ID: HeaderName = HeaderName::from_static("intermodal-correlation-id");
+pub const REPLY_TO: HeaderName = HeaderName::from_static("intermodal-reply-to");
+pub const SIGNATURE: HeaderName = HeaderName::from_static("intermodal-signature");
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+pub const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+
+/// Writes a manifest into `headers`, replacing any manifest headers there.
+pub fn to_headers(manifest: &Manifest, headers: &mut HeaderMap) {
+    let text = |value: &str| value_of(&escape(value, b""));
+    let time = |time: DateTime<Utc>| value_of(&time.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+    for name in [&EXPIRES, &LABEL, &CORRELATION_ID, &REPLY_TO, &TRACESTATE] {
+        headers.remove(name);
+    }
+    headers.insert(DOMAIN, text(&manifest.domain));
+    headers.insert(SCOPE, text(&manifest.scope));
+    headers.insert(KIND, text(&manifest.kind));
+    headers.insert(VERSION, HeaderValue::from(manifest.version));
+    headers.insert(CTIME, time(manifest.ctime));
+    if let Some(expires) = manifest.expires {
+        headers.insert(EXPIRES, time(expires));
+    }
+    headers.insert(ORIGIN, text(&manifest.origin));
+    let mut labels: Vec<_> = manifest.labels.iter().collect();
+    labels.sort();
+    for (key, value) in labels {
+        let label = format!("{}={}", escape(key, b",="), escape(value, b",="));
+        headers.append(LABEL, value_of(&label));
+    }
+    match &manifest.trace {
+        Some(trace) => {
+            headers.insert(TRACEPARENT, text(&trace.traceparent));
+            if let Some(tracestate) = &trace.tracestate {
+                headers.insert(TRACESTATE, text(tracestate));
+            }
+        }
+        None => {
+            headers.remove(TRACEPARENT);
+        }
+    }
+    if let Some(correlation_id) = &manifest.correlation_id {
+        headers.insert(CORRELATION_ID, text(correlation_id));
+    }
+    if let Some(reply_to) = &manifest.reply_to {
+        headers.insert(REPLY_TO, text(reply_to));
+    }
+}
+
+/// Reads a manifest from `headers`.
+pub fn from_headers(headers: &HeaderMap) -> Result<Manifest, Error> {
+    let text = |name: &HeaderName| -> Result<Option<String>, Error> {
+        headers
+            .get(name)
+            .map(|value| {
+                let value = value.to_str().map_err(|_| Error::Header(name.clone()))?;
+                unescape(value).ok_or_else(|| Error::Header(name.clone()))
+            })
+            .transpose()
+    };
+    let required = |name: &HeaderName| text(name)?.ok_or_else(|| Error::Missing(name.clone()));
+    let time = |name: &HeaderName, value: String| {
+        DateTime::parse_from_rfc3339(&value)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|_| Error::Header(name.clone()))
+    };
+
+    let mut labels = std::collections::HashMap::new();
+    for value in headers.get_all(LABEL) {
+        let value = value.to_str().map_err(|_| Error::Header(LABEL))?;
+        for label in value.split(',').map(str::trim).filter(|l| !l.is_empty()) {
+            let (key, value) = label
+                .split_once('=')
+                .and_then(|(key, value)| Some((unescape(key)?, unescape(value)?)))
+                .ok_or(Error::Header(LABEL))?;
+            labels.entry(key).or_insert(value);
+        }
+    }
+    Ok(Manifest {
+        domain: required(&DOMAIN)?,
+        scope: required(&SCOPE)?,
+        kind: required(&KIND)?,
+        version: required(&VERSION)?
+            .parse()
+            .map_err(|_| Error::Header(VERSION))?,
+        ctime: time(&CTIME, required(&CTIME)?)?,
+        expires: text(&EXPIRES)?
+            .map(|expires| time(&EXPIRES, expires))
+            .transpose()?,
+        origin: required(&ORIGIN)?,
+        labels,
+        trace: match text(&TRACEPARENT)? {
+            Some(traceparent) => Some(TraceContext {
+                traceparent,
+                tracestate: text(&TRACESTATE)?,
+            }),
+            None => None,
+        },
+        correlation_id: text(&CORRELATION_ID)?,
+        reply_to: text(&REPLY_TO)?,
+    })
+}
+
+/// The headers and body of a packet, its content encoded in `format`.
+pub fn to_parts<T: Serialize>(
+    packet: &Packet<T>,
+    format: Format,
+) -> Result<(HeaderMap, Vec<u8>), Error> {
+    let body = encode::to_vec(&packet.content, format).map_err(Error::Encode)?;
+    let mut headers = HeaderMap::new();
+    to_headers(&packet.manifest, &mut headers);
+    if let Some(signature) = &packet.signature {
+        let json = serde_json::to_string(signature).expect("signatures encode as JSON");
+        headers.insert(SIGNATURE, value_of(&escape(&json, b"")));
+    }
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(format.media_type()));
+    Ok((headers, body))
+}
+
+/// Reads a packet from headers and a body, decoding the body in the format
+/// its `Content-Type` names, or as JSON without one. Media type parameters,
+/// such as `charset`, are ignored.
+pub fn from_parts<T: DeserializeOwned>(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Packet<T>, Error> {
+    let manifest = from_headers(headers)?;
+    let format = match headers.get(CONTENT_TYPE) {
+        Some(media_type) => {
+            let media_type = media_type.to_str().unwrap_or_default();
+            let essence = media_type.split(';').next().unwrap_or_default().trim();
+            Format::all()
+                .iter()
+                .copied()
+                .find(|format| format.media_type().eq_ignore_ascii_case(essence))
+                .ok_or(Error::Header(CONTENT_TYPE))?
+        }
+        None => Format::Json,
+    };
+    let content = decode::from_slice(body, format).map_err(Error::Decode)?;
+    let mut packet = Packet::new(manifest, content);
+    if let Some(signature) = headers.get(SIGNATURE) {
+        let signature = signature
+            .to_str()
+            .ok()
+            .and_then(unescape)
+            .and_then(|json| serde_json::from_str::<Signature>(&json).ok())
+            .ok_or(Error::Header(SIGNATURE))?;
+        packet.signature = Some(signature);
+    }
+    Ok(packet)
+}
+
+fn value_of(escaped: &str) -> HeaderValue {
+    HeaderValue::from_str(escaped).expect("escaped values are visible ASCII")
+}
+
+/// Percent-encodes `%`, bytes that are not visible ASCII, and `also`.
+fn escape(text: &str, also: &[u8]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for &byte in text.as_bytes() {
+        if byte == b'%' || !byte.is_ascii_graphic() || also.contains(&byte) {
+            escaped.push_str(&format!("%{:02X}", byte));
+        } else {
+            escaped.push(byte as char);
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// An error converting between packets and HTTP headers and bodies.
+#[derive(Debug)]
+pub enum Error {
+    /// The content could not be encoded.
+    Encode(encode::Error),
+    /// The body could not be decoded.
+    Decode(decode::Error),
+    /// A required header is absent.
+    Missing(HeaderName),
+    /// A header's value is not one the header can hold.
+    Header(HeaderName),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encode(e) => e.fmt(f),
+            Error::Decode(e) => e.fmt(f),
+            Error::Missing(header) => write!(f, "no `{}` header", header),
+            Error::Header(header) => write!(f, "invalid `{}` header", header),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Encode(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Missing(_) | Error::Header(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+
+    #[test]
+    fn round_trips_packets() {
+        let mut packet = fixtures::cpu_raw();
+        packet.manifest.ctime = Utc::now();
+        packet
+            .manifest
+            .labels
+            .insert("note".to_string(), "50%, idle=yes, naïve".to_string());
+        packet.manifest.trace = Some(TraceContext {
+            traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string(),
+            tracestate: None,
+        });
+        packet.manifest.correlation_id = Some("req 7".to_string());
+        packet.signature = Some(Signature {
+            algorithm: "ed25519".to_string(),
+            key_id: Some("k1".to_string()),
+            value: "00ff".to_string(),
+        });
+
+        let (headers, body) = to_parts(&packet, Format::Json).unwrap();
+        assert_eq!(headers.get_all(LABEL).iter().count(), 3);
+        assert_eq!(headers[&CORRELATION_ID], "req%207");
+        assert_eq!(from_headers(&headers).unwrap(), packet.manifest);
+        assert_eq!(
+            from_parts::<serde_json::Value>(&headers, &body).unwrap(),
+            packet
+        );
+        let cpu = from_parts::<Cpu>(&headers, &body).unwrap();
+        assert_eq!(cpu.content.idle, 83.25);
+
+        // Writing another manifest over the headers leaves none of the old.
+        let mut headers = headers;
+        to_headers(&fixtures::netstat_manifest(), &mut headers);
+        assert_eq!(
+            from_headers(&headers).unwrap(),
+            fixtures::netstat_manifest()
+        );
+    }
+
+    #[test]
+    fn reads_combined_and_bad_headers() {
+        let (mut headers, _) = to_parts(&fixtures::cpu_raw(), Format::Json).unwrap();
+        headers.remove(LABEL);
+        headers.insert(
+            LABEL,
+            HeaderValue::from_static("environment=production, datacenter=us%2Deast"),
+        );
+        assert_eq!(from_headers(&headers).unwrap(), fixtures::cpu_manifest());
+
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        let body = serde_json::to_vec(&fixtures::cpu_raw().content).unwrap();
+        assert!(from_parts::<Cpu>(&headers, &body).is_ok());
+
+        headers.insert(LABEL, HeaderValue::from_static("environment"));
+        assert!(matches!(from_headers(&headers), Err(Error::Header(name)) if name == LABEL));
+        headers.remove(LABEL);
+        headers.remove(KIND);
+        assert!(matches!(from_headers(&headers), Err(Error::Missing(name)) if name == KIND));
+    }
+}
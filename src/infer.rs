@@ -0,0 +1,299 @@
+//! Inferring draft schemas from sampled content.
+//!
+//! Producers that predate the [schema registry](crate::schema) never
+//! published schemas for their content. An [`Inferrer`] samples packets,
+//! up to a limit for each set of [`Coordinates`], and infers from their
+//! content a draft JSON Schema that describes every sample: the types each
+//! value took, the properties of objects, which properties every sample
+//! had, and the items of arrays. A draft is a starting point for review,
+//! not a contract; it knows nothing of values the samples did not show.
+//!
+//! ```
+//! use intermodal::infer::Inferrer;
+//! use intermodal::schema::MemorySchemaRegistry;
+//! use intermodal::{Manifest, Packet};
+//! use serde_json::json;
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .build()
+//!     .unwrap();
+//!
+//! let inferrer = Inferrer::new(100);
+//! inferrer.observe(&Packet::new(manifest.clone(), json!({ "seconds": 86400 })));
+//! inferrer.observe(&Packet::new(manifest.clone(), json!({ "seconds": 60, "boot": "pxe" })));
+//!
+//! let draft = inferrer.draft(&manifest.coordinates()).unwrap();
+//! assert_eq!(draft["properties"]["seconds"]["type"], "integer");
+//! assert_eq!(draft["required"], json!(["seconds"]));
+//!
+//! let registry = MemorySchemaRegistry::new();
+//! assert_eq!(inferrer.drafts(&registry).unwrap().len(), 1);
+//! ```
+//!
+//! As a [`Transform`], an inferrer samples the packets passing through and
+//! leaves them unchanged.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde_json::{json, Map, Value};
+
+use crate::schema::{self, SchemaRegistry};
+use crate::transform::{self, Transform};
+use crate::{Coordinates, RawPacket};
+
+/// The JSON Schema dialect drafts are written in.
+pub const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Samples content and infers schemas for it, by coordinates.
+#[derive(Debug)]
+pub struct Inferrer {
+    samples: u64,
+    shapes: Mutex<HashMap<Coordinates, Shape>>,
+}
+
+/// What the values seen at one place in the content looked like.
+#[derive(Debug, Clone, Default)]
+struct Shape {
+    count: u64,
+    null: bool,
+    boolean: bool,
+    integer: bool,
+    number: bool,
+    string: bool,
+    items: Option<Box<Shape>>,
+    object: Option<Object>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Object {
+    count: u64,
+    properties: BTreeMap<String, Shape>,
+}
+
+impl Inferrer {
+    /// Samples up to `samples` packets for each set of coordinates.
+    pub fn new(samples: u64) -> Self {
+        Inferrer {
+            samples,
+            shapes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Samples a packet's content, unless enough packets with its
+    /// coordinates have been sampled already. Returns whether it was
+    /// sampled.
+    pub fn observe<T: serde::Serialize>(&self, packet: &crate::Packet<T>) -> bool {
+        let mut shapes = self.shapes.lock().unwrap_or_else(|e| e.into_inner());
+        let shape = shapes.entry(packet.manifest.coordinates()).or_default();
+        if shape.count >= self.samples {
+            return false;
+        }
+        match serde_json::to_value(&packet.content) {
+            Ok(content) => {
+                shape.observe(&content);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The number of packets sampled with the given coordinates.
+    pub fn sampled(&self, coordinates: &Coordinates) -> u64 {
+        let shapes = self.shapes.lock().unwrap_or_else(|e| e.into_inner());
+        shapes.get(coordinates).map_or(0, |shape| shape.count)
+    }
+
+    /// The draft schema for the content sampled with the given coordinates,
+    /// if any was.
+    pub fn draft(&self, coordinates: &Coordinates) -> Option<Value> {
+        let shapes = self.shapes.lock().unwrap_or_else(|e| e.into_inner());
+        let shape = shapes.get(coordinates).filter(|shape| shape.count > 0)?;
+        let mut schema = shape.to_schema();
+        schema["$schema"] = Value::String(DIALECT.to_string());
+        schema["title"] = Value::String(coordinates.to_string());
+        Some(schema)
+    }
+
+    /// Drafts for every sampled set of coordinates that has no schema
+    /// registered, in order of coordinates.
+    pub fn drafts<R: SchemaRegistry + ?Sized>(
+        &self,
+        registry: &R,
+    ) -> Result<Vec<(Coordinates, Value)>, schema::Error> {
+        let mut coordinates: Vec<_> = {
+            let shapes = self.shapes.lock().unwrap_or_else(|e| e.into_inner());
+            shapes.keys().cloned().collect()
+        };
+        coordinates.sort_unstable();
+
+        let mut drafts = Vec::new();
+        for coordinates in coordinates {
+            if registry.get_schema(&coordinates)?.is_some() {
+                continue;
+            }
+            if let Some(draft) = self.draft(&coordinates) {
+                drafts.push((coordinates, draft));
+            }
+        }
+        Ok(drafts)
+    }
+}
+
+impl Transform for Inferrer {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.observe(&packet);
+        Ok(Some(packet))
+    }
+}
+
+impl Shape {
+    fn observe(&mut self, value: &Value) {
+        self.count += 1;
+        match value {
+            Value::Null => self.null = true,
+            Value::Bool(_) => self.boolean = true,
+            Value::Number(n) if n.is_i64() || n.is_u64() => self.integer = true,
+            Value::Number(_) => self.number = true,
+            Value::String(_) => self.string = true,
+            Value::Array(items) => {
+                let shape = self.items.get_or_insert_with(Default::default);
+                for item in items {
+                    shape.observe(item);
+                }
+            }
+            Value::Object(properties) => {
+                let object = self.object.get_or_insert_with(Default::default);
+                object.count += 1;
+                for (key, value) in properties {
+                    object
+                        .properties
+                        .entry(key.clone())
+                        .or_default()
+                        .observe(value);
+                }
+            }
+        }
+    }
+
+    fn to_schema(&self) -> Value {
+        let mut types = Vec::new();
+        if self.null {
+            types.push("null");
+        }
+        if self.boolean {
+            types.push("boolean");
+        }
+        // Integers are numbers, so a mix of the two is just numbers.
+        if self.number {
+            types.push("number");
+        } else if self.integer {
+            types.push("integer");
+        }
+        if self.string {
+            types.push("string");
+        }
+        if self.items.is_some() {
+            types.push("array");
+        }
+        if self.object.is_some() {
+            types.push("object");
+        }
+
+        let mut schema = Map::new();
+        match types.as_slice() {
+            [] => {}
+            [one] => {
+                schema.insert("type".into(), json!(one));
+            }
+            many => {
+                schema.insert("type".into(), json!(many));
+            }
+        }
+        if let Some(items) = self.items.as_ref().filter(|items| items.count > 0) {
+            schema.insert("items".into(), items.to_schema());
+        }
+        if let Some(object) = &self.object {
+            let properties: Map<_, _> = object
+                .properties
+                .iter()
+                .map(|(key, shape)| (key.clone(), shape.to_schema()))
+                .collect();
+            let required: Vec<_> = object
+                .properties
+                .iter()
+                .filter(|(_, shape)| shape.count == object.count)
+                .map(|(key, _)| key.clone())
+                .collect();
+            schema.insert("properties".into(), Value::Object(properties));
+            if !required.is_empty() {
+                schema.insert("required".into(), json!(required));
+            }
+        }
+        Value::Object(schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::schema::MemorySchemaRegistry;
+
+    #[test]
+    fn infers_from_samples() {
+        let inferrer = Inferrer::new(2);
+        let netstat = fixtures::netstat_raw();
+        assert!(inferrer.observe(&netstat));
+        let mut other = netstat.clone();
+        other.content = json!({
+            "connections": [{ "port": 8080.5, "state": null }],
+            "extra": true
+        });
+        assert!(inferrer.observe(&other));
+        assert!(!inferrer.observe(&other));
+        assert_eq!(inferrer.sampled(&netstat.manifest.coordinates()), 2);
+
+        let draft = inferrer.draft(&netstat.manifest.coordinates()).unwrap();
+        assert_eq!(draft["$schema"], DIALECT);
+        assert_eq!(draft["type"], "object");
+        assert_eq!(draft["required"], json!(["connections"]));
+        assert_eq!(draft["properties"]["extra"]["type"], "boolean");
+        let connection = &draft["properties"]["connections"]["items"];
+        assert_eq!(connection["properties"]["port"]["type"], "number");
+        assert_eq!(
+            connection["properties"]["state"]["type"],
+            json!(["null", "string"])
+        );
+
+        #[cfg(feature = "jsonschema")]
+        {
+            let validator = jsonschema::validator_for(&draft).unwrap();
+            assert!(validator.is_valid(&netstat.content));
+            assert!(validator.is_valid(&other.content));
+            assert!(!validator.is_valid(&json!({ "extra": true })));
+        }
+    }
+
+    #[test]
+    fn drafts_only_unregistered() {
+        let inferrer = Inferrer::new(10);
+        let cpu = fixtures::cpu_raw();
+        inferrer.apply(cpu.clone()).unwrap();
+        inferrer.apply(fixtures::netstat_raw()).unwrap();
+
+        let registry = MemorySchemaRegistry::new();
+        registry
+            .register_schema(cpu.manifest.coordinates(), json!({ "type": "object" }))
+            .unwrap();
+        let drafts = inferrer.drafts(&registry).unwrap();
+        assert_eq!(drafts.len(), 1);
+        assert_eq!(drafts[0].0, fixtures::netstat_manifest().coordinates());
+        assert_eq!(inferrer.draft(&Coordinates::new("a", "b", "c", 1)), None);
+    }
+}
@@ -0,0 +1,397 @@
+//! Mapping packets to and from Kafka records.
+//!
+//! A [`Codec`] turns a packet into a [`Record`]: its manifest into record
+//! headers, its content into the value, in any [`Format`], and a key chosen
+//! by a [`KeyStrategy`] for partitioning. Consumers route by the headers
+//! without decoding the value, and decode it only for the packets they
+//! handle:
+//!
+//! ```
+//! use intermodal::kafka::{self, Codec, KeyStrategy};
+//! use intermodal::{Manifest, Packet};
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .label("tenant", "acme")
+//!     .build()
+//!     .unwrap();
+//! let packet = Packet::new(manifest, 86400u64);
+//!
+//! let codec = Codec::new().key_strategy(KeyStrategy::Label("tenant".to_string()));
+//! let record = codec.encode(&packet)?;
+//! assert_eq!(record.key.as_deref(), Some(&b"acme"[..]));
+//! assert_eq!(record.header("intermodal.kind"), Some(&b"uptime"[..]));
+//!
+//! assert_eq!(kafka::manifest(&record)?, packet.manifest);
+//! assert_eq!(codec.decode::<u64>(&record)?, packet);
+//! # Ok::<(), kafka::Error>(())
+//! ```
+//!
+//! A [`Record`] holds only what every Kafka client has, so it converts to
+//! and from the record types of whichever client is in use. The headers
+//! are:
+//!
+//! | Header | Holds |
+//! |---|---|
+//! | `intermodal.domain`, `intermodal.scope`, `intermodal.kind`, `intermodal.version` | The coordinates |
+//! | `intermodal.ctime`, `intermodal.expires` | Times, in RFC 3339 |
+//! | `intermodal.origin`, `intermodal.correlation_id`, `intermodal.reply_to` | As in the manifest |
+//! | `intermodal.label.<key>` | Each label |
+//! | `traceparent`, `tracestate` | The W3C trace context |
+//! | `intermodal.signature` | The signature, as JSON |
+//! | `content-type` | The media type of the value |
+//!
+//! Headers absent from the manifest are left out. A packet's provenance is
+//! not carried.
+//!
+//! Available with the `kafka` feature.
+
+use std::fmt;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::signing::Signature;
+use crate::trace::TraceContext;
+use crate::{decode, encode, Format, Manifest, Packet};
+
+/// The prefix of the headers holding labels.
+pub const LABEL_PREFIX: &str = "intermodal.label.";
+
+/// The header holding the media type of a record's value.
+pub const CONTENT_TYPE: &str = "content-type";
+
+const DOMAIN: &str = "intermodal.domain";
+const SCOPE: &str = "intermodal.scope";
+const KIND: &str = "intermodal.kind";
+const VERSION: &str = "intermodal.version";
+const CTIME: &str = "intermodal.ctime";
+const EXPIRES: &str = "intermodal.expires";
+const ORIGIN: &str = "intermodal.origin";
+const CORRELATION_ID: &str = "intermodal.correlation_id";
+const REPLY_TO: &str = "intermodal.reply_to";
+const SIGNATURE: &str = "intermodal.signature";
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// A Kafka record, less the topic, partition and offset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Record {
+    pub key: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+    /// Headers in order. Kafka allows a name more than once; the first
+    /// wins.
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Record {
+    /// The value of the first header by `name`.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+/// How the key a record is partitioned by is chosen.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum KeyStrategy {
+    /// No key, leaving the partition to the producer.
+    None,
+    /// The manifest's origin, so that each origin's packets stay in order.
+    #[default]
+    Origin,
+    /// The [digest](Packet::content_digest) of the manifest and content, so
+    /// that duplicates land together for compaction.
+    Digest,
+    /// The value of this label, or no key for manifests without it.
+    Label(String),
+}
+
+impl KeyStrategy {
+    /// The key of a packet.
+    pub fn key<T: Serialize>(&self, packet: &Packet<T>) -> Result<Option<Vec<u8>>, Error> {
+        Ok(match self {
+            KeyStrategy::None => None,
+            KeyStrategy::Origin => Some(packet.manifest.origin.as_bytes().to_vec()),
+            KeyStrategy::Digest => {
+                let digest = packet.content_digest().map_err(Error::Digest)?;
+                Some(digest.to_string().into_bytes())
+            }
+            KeyStrategy::Label(label) => packet
+                .manifest
+                .labels
+                .get(label)
+                .map(|value| value.as_bytes().to_vec()),
+        })
+    }
+}
+
+/// Converts packets to and from records.
+#[derive(Debug, Clone)]
+pub struct Codec {
+    key_strategy: KeyStrategy,
+    format: Format,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::new()
+    }
+}
+
+impl Codec {
+    /// A codec keying records by origin and writing values as JSON.
+    pub fn new() -> Self {
+        Codec {
+            key_strategy: KeyStrategy::Origin,
+            format: Format::Json,
+        }
+    }
+
+    pub fn key_strategy(mut self, key_strategy: KeyStrategy) -> Self {
+        self.key_strategy = key_strategy;
+        self
+    }
+
+    /// Writes values in `format`. Records are decoded in the format their
+    /// `content-type` header names, whatever this is.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn encode<T: Serialize>(&self, packet: &Packet<T>) -> Result<Record, Error> {
+        let mut headers = headers(&packet.manifest);
+        if let Some(signature) = &packet.signature {
+            let json = serde_json::to_vec(signature).expect("signatures encode as JSON");
+            headers.push((SIGNATURE.to_string(), json));
+        }
+        headers.push((
+            CONTENT_TYPE.to_string(),
+            self.format.media_type().as_bytes().to_vec(),
+        ));
+        Ok(Record {
+            key: self.key_strategy.key(packet)?,
+            value: encode::to_vec(&packet.content, self.format).map_err(Error::Encode)?,
+            headers,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, record: &Record) -> Result<Packet<T>, Error> {
+        let manifest = manifest(record)?;
+        let format = match record.header(CONTENT_TYPE) {
+            Some(media_type) => Format::all()
+                .iter()
+                .copied()
+                .find(|format| format.media_type().as_bytes() == media_type)
+                .ok_or(Error::Header(CONTENT_TYPE))?,
+            None => Format::Json,
+        };
+        let content = decode::from_slice(&record.value, format).map_err(Error::Decode)?;
+        let mut packet = Packet::new(manifest, content);
+        if let Some(signature) = record.header(SIGNATURE) {
+            packet.signature = Some(
+                serde_json::from_slice::<Signature>(signature)
+                    .map_err(|_| Error::Header(SIGNATURE))?,
+            );
+        }
+        Ok(packet)
+    }
+}
+
+/// The headers a manifest maps to.
+pub fn headers(manifest: &Manifest) -> Vec<(String, Vec<u8>)> {
+    let mut headers = Vec::with_capacity(8 + manifest.labels.len());
+    let mut push = |name: &str, value: &str| {
+        headers.push((name.to_string(), value.as_bytes().to_vec()));
+    };
+    let time = |time: DateTime<Utc>| time.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+    push(DOMAIN, &manifest.domain);
+    push(SCOPE, &manifest.scope);
+    push(KIND, &manifest.kind);
+    push(VERSION, &manifest.version.to_string());
+    push(CTIME, &time(manifest.ctime));
+    if let Some(expires) = manifest.expires {
+        push(EXPIRES, &time(expires));
+    }
+    push(ORIGIN, &manifest.origin);
+    let mut labels: Vec<_> = manifest.labels.iter().collect();
+    labels.sort();
+    for (key, value) in labels {
+        push(&format!("{}{}", LABEL_PREFIX, key), value);
+    }
+    if let Some(trace) = &manifest.trace {
+        push(TRACEPARENT, &trace.traceparent);
+        if let Some(tracestate) = &trace.tracestate {
+            push(TRACESTATE, tracestate);
+        }
+    }
+    if let Some(correlation_id) = &manifest.correlation_id {
+        push(CORRELATION_ID, correlation_id);
+    }
+    if let Some(reply_to) = &manifest.reply_to {
+        push(REPLY_TO, reply_to);
+    }
+    headers
+}
+
+/// Reads the manifest from a record's headers, without decoding its value.
+pub fn manifest(record: &Record) -> Result<Manifest, Error> {
+    let text = |name: &'static str| -> Result<Option<String>, Error> {
+        record
+            .header(name)
+            .map(|value| String::from_utf8(value.to_vec()).map_err(|_| Error::Header(name)))
+            .transpose()
+    };
+    let required = |name: &'static str| text(name)?.ok_or(Error::Missing(name));
+    let time = |name: &'static str, value: String| {
+        DateTime::parse_from_rfc3339(&value)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|_| Error::Header(name))
+    };
+
+    let mut labels = std::collections::HashMap::new();
+    for (name, value) in &record.headers {
+        if let Some(key) = name.strip_prefix(LABEL_PREFIX) {
+            let value = String::from_utf8(value.clone()).map_err(|_| Error::Header("label"))?;
+            labels.entry(key.to_string()).or_insert(value);
+        }
+    }
+    Ok(Manifest {
+        domain: required(DOMAIN)?,
+        scope: required(SCOPE)?,
+        kind: required(KIND)?,
+        version: required(VERSION)?
+            .parse()
+            .map_err(|_| Error::Header(VERSION))?,
+        ctime: time(CTIME, required(CTIME)?)?,
+        expires: text(EXPIRES)?
+            .map(|expires| time(EXPIRES, expires))
+            .transpose()?,
+        origin: required(ORIGIN)?,
+        labels,
+        trace: match text(TRACEPARENT)? {
+            Some(traceparent) => Some(TraceContext {
+                traceparent,
+                tracestate: text(TRACESTATE)?,
+            }),
+            None => None,
+        },
+        correlation_id: text(CORRELATION_ID)?,
+        reply_to: text(REPLY_TO)?,
+    })
+}
+
+/// An error converting between packets and records.
+#[derive(Debug)]
+pub enum Error {
+    /// The content could not be encoded.
+    Encode(encode::Error),
+    /// The value could not be decoded.
+    Decode(decode::Error),
+    /// The packet could not be encoded to take its digest.
+    Digest(serde_json::Error),
+    /// A required header is absent.
+    Missing(&'static str),
+    /// A header's value is not one the header can hold.
+    Header(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encode(e) => e.fmt(f),
+            Error::Decode(e) => e.fmt(f),
+            Error::Digest(e) => write!(f, "cannot take packet digest: {}", e),
+            Error::Missing(header) => write!(f, "record has no `{}` header", header),
+            Error::Header(header) => write!(f, "invalid `{}` header", header),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Encode(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Digest(e) => Some(e),
+            Error::Missing(_) | Error::Header(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+
+    #[test]
+    fn round_trips_packets() {
+        let mut packet = fixtures::cpu_raw();
+        packet.manifest.ctime = Utc::now();
+        packet.manifest.expires = Some(packet.manifest.ctime + chrono::Duration::minutes(5));
+        packet.manifest.trace = Some(TraceContext {
+            traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string(),
+            tracestate: Some("vendor=1".to_string()),
+        });
+        packet.manifest.reply_to = Some("replies".to_string());
+        packet.signature = Some(Signature {
+            algorithm: "ed25519".to_string(),
+            key_id: None,
+            value: "00ff".to_string(),
+        });
+
+        let codec = Codec::new();
+        let record = codec.encode(&packet).unwrap();
+        assert_eq!(record.key.as_deref(), Some(&b"host01.example.org"[..]));
+        assert_eq!(
+            record.header("intermodal.label.datacenter"),
+            Some(&b"us-east"[..])
+        );
+        assert_eq!(record.header(CONTENT_TYPE), Some(&b"application/json"[..]));
+        assert_eq!(manifest(&record).unwrap(), packet.manifest);
+        assert_eq!(codec.decode::<serde_json::Value>(&record).unwrap(), packet);
+
+        let cpu = codec.decode::<Cpu>(&record).unwrap();
+        assert_eq!(cpu.content.idle, 83.25);
+        assert_eq!(cpu.signature, packet.signature);
+    }
+
+    #[test]
+    fn keys_records() {
+        let cpu = fixtures::cpu_raw();
+        let key = |strategy: KeyStrategy| strategy.key(&cpu).unwrap();
+        assert_eq!(key(KeyStrategy::None), None);
+        assert_eq!(
+            key(KeyStrategy::Label("environment".to_string())),
+            Some(b"production".to_vec())
+        );
+        assert_eq!(key(KeyStrategy::Label("tenant".to_string())), None);
+        let digest = key(KeyStrategy::Digest).unwrap();
+        assert!(digest.starts_with(b"sha256:"));
+        assert_ne!(
+            KeyStrategy::Digest.key(&fixtures::netstat_raw()).unwrap(),
+            Some(digest)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut record = Codec::new().encode(&fixtures::cpu_raw()).unwrap();
+        record.headers.retain(|(name, _)| name != KIND);
+        assert!(matches!(manifest(&record), Err(Error::Missing(KIND))));
+
+        record.headers.push((KIND.to_string(), b"cpu".to_vec()));
+        record
+            .headers
+            .insert(0, (VERSION.to_string(), b"one".to_vec()));
+        assert!(matches!(manifest(&record), Err(Error::Header(VERSION))));
+    }
+}
@@ -0,0 +1,655 @@
+//! Looking up signing and encryption keys by id.
+//!
+//! A [`KeyProvider`] hands out [`KeyEntry`]s: key material with the id it
+//! is known by and when it was created and expires. Signing and encrypting
+//! use the provider's [`current`](KeyProvider::current) key and record its
+//! id, and verifying and decrypting look the recorded id up again with
+//! [`key`](KeyProvider::key), so that keys can be rotated without
+//! stranding what was signed or encrypted with the old ones:
+//!
+//! ```no_run
+//! use intermodal::keys::{KeyProvider, KeySet};
+//!
+//! let keys = KeySet::from_file("/etc/intermodal/keys.json")?;
+//! let current = keys.current()?;
+//! # Ok::<(), intermodal::keys::Error>(())
+//! ```
+//!
+//! A [`KeySet`] holds keys in memory, read from a JSON file or from
+//! environment variables. A key file lists keys with hex-encoded material:
+//!
+//! ```json
+//! { "keys": [
+//!     { "id": "2020-05", "material": "8f3a…", "created": "2020-05-01T00:00:00Z",
+//!       "expires": "2020-07-01T00:00:00Z" },
+//!     { "id": "2020-06", "material": "c41d…", "created": "2020-06-01T00:00:00Z" }
+//! ] }
+//! ```
+//!
+//! Unless a set names its current key, the current key is the most recently
+//! created one that has not expired. An expired key is no longer current,
+//! but is still returned by id.
+//!
+//! A key's material is never used as a key itself. Signing, sealing and
+//! archive encryption each derive a key of their own from it with
+//! HKDF-SHA256, so one entry serves them all without any two algorithms
+//! sharing a key.
+//!
+//! Verifying needs only public keys. With the `ed25519` feature, a signer
+//! hands its verifiers the [`PublicKeySet`] of its keys, and they look keys
+//! up through [`PublicKeyProvider`], never holding the secret material.
+//!
+//! With the `kms` feature, [`KmsKeyProvider`] keeps keys wrapped by a master
+//! key in a key management service, unwrapping each when first used and
+//! caching it for a while.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::hex;
+
+/// Key material and what is known about it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct KeyEntry {
+    pub id: String,
+    material: Vec<u8>,
+    pub created: Option<DateTime<Utc>>,
+    /// When the key stops being used for new signatures and encryption.
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl KeyEntry {
+    pub fn new<S: Into<String>>(id: S, material: Vec<u8>) -> Self {
+        KeyEntry {
+            id: id.into(),
+            material,
+            created: None,
+            expires: None,
+        }
+    }
+
+    pub fn created(mut self, at: DateTime<Utc>) -> Self {
+        self.created = Some(at);
+        self
+    }
+
+    pub fn expires(mut self, at: DateTime<Utc>) -> Self {
+        self.expires = Some(at);
+        self
+    }
+
+    pub fn material(&self) -> &[u8] {
+        &self.material
+    }
+
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// The key derived from the material for the use `info` names. The
+    /// material must be at least 32 bytes.
+    #[cfg(any(
+        feature = "archive-encryption",
+        feature = "ed25519",
+        feature = "encryption"
+    ))]
+    fn subkey(&self, info: &[u8]) -> Result<[u8; 32], Error> {
+        if self.material.len() < 32 {
+            return Err(Error::Material {
+                id: self.id.clone(),
+                message: format!("{} bytes, fewer than 32", self.material.len()),
+            });
+        }
+        Ok(crate::hkdf::derive(&self.material, info))
+    }
+
+    /// The Ed25519 key derived from the material for signing.
+    #[cfg(feature = "ed25519")]
+    pub fn signing_key(&self) -> Result<ed25519_dalek::SigningKey, Error> {
+        self.subkey(b"intermodal signing ed25519")
+            .map(|secret| ed25519_dalek::SigningKey::from_bytes(&secret))
+    }
+
+    /// The XChaCha20-Poly1305 key derived from the material for
+    /// [sealing](crate::sealed) and [field encryption](crate::encryption).
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(&self) -> Result<crate::encryption::Key, Error> {
+        self.subkey(b"intermodal encryption xchacha20poly1305")
+            .map(|key| crate::encryption::Key::new(self.id.clone(), key))
+    }
+
+    /// The AES-256-GCM key derived from the material for
+    /// [encrypted archives](crate::archive::EncryptedWriter).
+    #[cfg(feature = "archive-encryption")]
+    pub fn archive_key(&self) -> Result<aes_gcm::Aes256Gcm, Error> {
+        use aes_gcm::KeyInit;
+
+        self.subkey(b"intermodal archive aes-256-gcm")
+            .map(|key| aes_gcm::Aes256Gcm::new(&key.into()))
+    }
+}
+
+impl fmt::Debug for KeyEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyEntry")
+            .field("id", &self.id)
+            .field("created", &self.created)
+            .field("expires", &self.expires)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A source of keys.
+pub trait KeyProvider: Send + Sync {
+    /// The key with the given id, expired or not.
+    fn key(&self, id: &str) -> Result<Option<KeyEntry>, Error>;
+
+    /// The key to sign and encrypt with now.
+    fn current(&self) -> Result<KeyEntry, Error>;
+}
+
+/// A source of public keys, for verifying signatures without holding the
+/// keys that made them.
+///
+/// Every [`KeyProvider`] is one, deriving public keys from the secret ones
+/// for a signer checking its own signatures. Anyone else verifies with a
+/// [`PublicKeySet`].
+#[cfg(feature = "ed25519")]
+pub trait PublicKeyProvider: Send + Sync {
+    /// The Ed25519 public key with the given id.
+    fn verifying_key(&self, id: &str) -> Result<Option<ed25519_dalek::VerifyingKey>, Error>;
+}
+
+#[cfg(feature = "ed25519")]
+impl<P: KeyProvider + ?Sized> PublicKeyProvider for P {
+    fn verifying_key(&self, id: &str) -> Result<Option<ed25519_dalek::VerifyingKey>, Error> {
+        match self.key(id)? {
+            Some(key) => Ok(Some(key.signing_key()?.verifying_key())),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A key file or variable could not be parsed.
+    Parse(String),
+    /// A key's material is unusable.
+    Material {
+        id: String,
+        message: String,
+    },
+    /// No key is current: there are none, or all have expired.
+    NoCurrent,
+    /// A key was named that the provider does not have.
+    Unknown(String),
+    /// A key management service failed.
+    Service(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "cannot read keys: {}", e),
+            Error::Parse(message) => write!(f, "cannot parse keys: {}", message),
+            Error::Material { id, message } => {
+                write!(f, "key `{}` has unusable material: {}", id, message)
+            }
+            Error::NoCurrent => f.write_str("no key is current"),
+            Error::Unknown(id) => write!(f, "no key `{}`", id),
+            Error::Service(message) => write!(f, "key service failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Keys held in memory.
+#[derive(Debug, Clone, Default)]
+pub struct KeySet {
+    keys: Vec<KeyEntry>,
+    current: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KeyFile {
+    keys: Vec<StoredKey>,
+    #[serde(default)]
+    current: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StoredKey {
+    id: String,
+    material: String,
+    #[serde(default)]
+    created: Option<DateTime<Utc>>,
+    #[serde(default)]
+    expires: Option<DateTime<Utc>>,
+}
+
+impl KeySet {
+    pub fn new() -> Self {
+        KeySet::default()
+    }
+
+    /// Adds a key, replacing any with the same id.
+    pub fn insert(mut self, key: KeyEntry) -> Self {
+        self.keys.retain(|k| k.id != key.id);
+        self.keys.push(key);
+        self
+    }
+
+    /// Makes the key with the given id current, whenever it was created
+    /// and even once it has expired.
+    pub fn current_id<S: Into<String>>(mut self, id: S) -> Self {
+        self.current = Some(id.into());
+        self
+    }
+
+    /// Reads keys from a JSON key file. A `current` field names the
+    /// current key.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file: KeyFile =
+            serde_json::from_slice(&fs::read(path)?).map_err(|e| Error::Parse(e.to_string()))?;
+        let mut set = KeySet::new();
+        for stored in file.keys {
+            let material = hex::decode(&stored.material).ok_or_else(|| Error::Material {
+                id: stored.id.clone(),
+                message: "not hex-encoded".to_string(),
+            })?;
+            set.keys.push(KeyEntry {
+                id: stored.id,
+                material,
+                created: stored.created,
+                expires: stored.expires,
+            });
+        }
+        set.current = file.current;
+        Ok(set)
+    }
+
+    /// Reads keys from the environment variables named `prefix` followed by
+    /// a key id, holding hex-encoded material. The variable named `prefix`
+    /// followed by `CURRENT` names the current key.
+    ///
+    /// With the prefix `INTERMODAL_KEY_`, `INTERMODAL_KEY_2020_06` holds the
+    /// key with id `2020_06`.
+    pub fn from_env(prefix: &str) -> Result<Self, Error> {
+        Self::from_vars(prefix, env::vars())
+    }
+
+    fn from_vars<I>(prefix: &str, vars: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut set = KeySet::new();
+        for (name, value) in vars {
+            let id = match name.strip_prefix(prefix) {
+                Some("CURRENT") => {
+                    set.current = Some(value);
+                    continue;
+                }
+                Some(id) if !id.is_empty() => id,
+                _ => continue,
+            };
+            let material = hex::decode(value.trim()).ok_or_else(|| Error::Material {
+                id: id.to_string(),
+                message: format!("`{}` is not hex-encoded", name),
+            })?;
+            set.keys.push(KeyEntry::new(id, material));
+        }
+        set.keys.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        Ok(set)
+    }
+
+    pub fn keys(&self) -> &[KeyEntry] {
+        &self.keys
+    }
+
+    fn current_at(&self, now: DateTime<Utc>) -> Result<KeyEntry, Error> {
+        if let Some(id) = &self.current {
+            return self
+                .keys
+                .iter()
+                .find(|key| &key.id == id)
+                .cloned()
+                .ok_or_else(|| Error::Unknown(id.clone()));
+        }
+        // The last of the newest, so that of keys without creation times
+        // the last added is current.
+        self.keys
+            .iter()
+            .filter(|key| !key.is_expired_at(now))
+            .fold(None, |newest: Option<&KeyEntry>, key| match newest {
+                Some(newest) if newest.created > key.created => Some(newest),
+                _ => Some(key),
+            })
+            .cloned()
+            .ok_or(Error::NoCurrent)
+    }
+}
+
+impl KeyProvider for KeySet {
+    fn key(&self, id: &str) -> Result<Option<KeyEntry>, Error> {
+        Ok(self.keys.iter().find(|key| key.id == id).cloned())
+    }
+
+    fn current(&self) -> Result<KeyEntry, Error> {
+        self.current_at(Utc::now())
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl KeySet {
+    /// The public keys of the set's keys, to hand to verifiers.
+    pub fn public_keys(&self) -> Result<PublicKeySet, Error> {
+        let mut public = PublicKeySet::new();
+        for key in &self.keys {
+            public = public.insert(key.id.clone(), key.signing_key()?.verifying_key());
+        }
+        Ok(public)
+    }
+}
+
+/// Ed25519 public keys held in memory, by id.
+///
+/// A public key file lists keys with hex-encoded public keys, as
+/// [`to_json`](PublicKeySet::to_json) writes them:
+///
+/// ```json
+/// { "keys": [ { "id": "2020-06", "public": "d75a98…" } ] }
+/// ```
+#[cfg(feature = "ed25519")]
+#[derive(Debug, Clone, Default)]
+pub struct PublicKeySet {
+    keys: Vec<(String, ed25519_dalek::VerifyingKey)>,
+}
+
+#[cfg(feature = "ed25519")]
+#[derive(serde::Serialize, Deserialize)]
+struct PublicKeyFile {
+    keys: Vec<StoredPublicKey>,
+}
+
+#[cfg(feature = "ed25519")]
+#[derive(serde::Serialize, Deserialize)]
+struct StoredPublicKey {
+    id: String,
+    public: String,
+}
+
+#[cfg(feature = "ed25519")]
+impl PublicKeySet {
+    pub fn new() -> Self {
+        PublicKeySet::default()
+    }
+
+    /// Adds a key, replacing any with the same id.
+    pub fn insert<S: Into<String>>(mut self, id: S, key: ed25519_dalek::VerifyingKey) -> Self {
+        let id = id.into();
+        self.keys.retain(|(k, _)| *k != id);
+        self.keys.push((id, key));
+        self
+    }
+
+    /// Reads keys from a JSON public key file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_json(&fs::read(path)?)
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        use std::convert::TryFrom;
+
+        let file: PublicKeyFile =
+            serde_json::from_slice(json).map_err(|e| Error::Parse(e.to_string()))?;
+        let mut set = PublicKeySet::new();
+        for stored in file.keys {
+            let material = |message: String| Error::Material {
+                id: stored.id.clone(),
+                message,
+            };
+            let bytes = hex::decode(&stored.public)
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes.as_slice()).ok())
+                .ok_or_else(|| material("not 32 hex-encoded bytes".to_string()))?;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                .map_err(|e| material(e.to_string()))?;
+            set = set.insert(stored.id, key);
+        }
+        Ok(set)
+    }
+
+    /// Writes the keys as a public key file.
+    pub fn to_json(&self) -> String {
+        let file = PublicKeyFile {
+            keys: self
+                .keys
+                .iter()
+                .map(|(id, key)| StoredPublicKey {
+                    id: id.clone(),
+                    public: hex::encode(key.as_bytes()),
+                })
+                .collect(),
+        };
+        serde_json::to_string(&file).expect("public key files serialize")
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl PublicKeyProvider for PublicKeySet {
+    fn verifying_key(&self, id: &str) -> Result<Option<ed25519_dalek::VerifyingKey>, Error> {
+        Ok(self.keys.iter().find(|(k, _)| k == id).map(|(_, key)| *key))
+    }
+}
+
+#[cfg(feature = "kms")]
+pub use self::kms::{Kms, KmsKeyProvider};
+
+#[cfg(feature = "kms")]
+mod kms {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use super::{Error, KeyEntry, KeyProvider, KeySet};
+
+    /// A key management service, which decrypts keys wrapped by a master
+    /// key it holds.
+    pub trait Kms: Send + Sync {
+        fn unwrap_key(&self, master_key: &str, wrapped: &[u8]) -> Result<Vec<u8>, Error>;
+    }
+
+    /// Keys whose material is wrapped by a master key in a [`Kms`].
+    ///
+    /// The wrapped keys, and their ids and times, are kept in a [`KeySet`],
+    /// such as one read from a file. Each is unwrapped when first asked
+    /// for, and kept unwrapped for the time to live, an hour unless set
+    /// otherwise.
+    pub struct KmsKeyProvider<K> {
+        kms: K,
+        master_key: String,
+        wrapped: KeySet,
+        ttl: Duration,
+        cache: Mutex<HashMap<String, (Instant, KeyEntry)>>,
+    }
+
+    impl<K: Kms> KmsKeyProvider<K> {
+        pub fn new<S: Into<String>>(kms: K, master_key: S, wrapped: KeySet) -> Self {
+            KmsKeyProvider {
+                kms,
+                master_key: master_key.into(),
+                wrapped,
+                ttl: Duration::from_secs(3600),
+                cache: Mutex::new(HashMap::new()),
+            }
+        }
+
+        pub fn ttl(mut self, ttl: Duration) -> Self {
+            self.ttl = ttl;
+            self
+        }
+
+        fn unwrap_key(&self, wrapped: KeyEntry) -> Result<KeyEntry, Error> {
+            let now = Instant::now();
+            {
+                let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some((fetched, key)) = cache.get(&wrapped.id) {
+                    if now.duration_since(*fetched) < self.ttl {
+                        return Ok(key.clone());
+                    }
+                }
+            }
+            let material = self.kms.unwrap_key(&self.master_key, wrapped.material())?;
+            let key = KeyEntry {
+                material,
+                ..wrapped
+            };
+            let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+            cache.insert(key.id.clone(), (now, key.clone()));
+            Ok(key)
+        }
+    }
+
+    impl<K: Kms> KeyProvider for KmsKeyProvider<K> {
+        fn key(&self, id: &str) -> Result<Option<KeyEntry>, Error> {
+            match self.wrapped.key(id)? {
+                Some(wrapped) => self.unwrap_key(wrapped).map(Some),
+                None => Ok(None),
+            }
+        }
+
+        fn current(&self) -> Result<KeyEntry, Error> {
+            self.unwrap_key(self.wrapped.current()?)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use super::*;
+
+        /// Unwraps by flipping every bit.
+        #[derive(Default)]
+        struct Flip(AtomicUsize);
+
+        impl Kms for Flip {
+            fn unwrap_key(&self, master_key: &str, wrapped: &[u8]) -> Result<Vec<u8>, Error> {
+                assert_eq!(master_key, "master");
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(wrapped.iter().map(|b| !b).collect())
+            }
+        }
+
+        #[test]
+        fn unwraps_and_caches() {
+            let wrapped = KeySet::new().insert(KeyEntry::new("k1", vec![0, 0xff]));
+            let provider = KmsKeyProvider::new(Flip::default(), "master", wrapped);
+            assert_eq!(provider.current().unwrap().material(), [0xff, 0]);
+            assert_eq!(provider.key("k1").unwrap().unwrap().material(), [0xff, 0]);
+            assert!(provider.key("k2").unwrap().is_none());
+            assert_eq!(provider.kms.0.load(Ordering::Relaxed), 1);
+
+            let provider = provider.ttl(Duration::ZERO);
+            provider.current().unwrap();
+            assert_eq!(provider.kms.0.load(Ordering::Relaxed), 2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2020, 6, day, 0, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn rotates() {
+        let keys = KeySet::new()
+            .insert(
+                KeyEntry::new("old", vec![1; 32])
+                    .created(at(1))
+                    .expires(at(20)),
+            )
+            .insert(KeyEntry::new("new", vec![2; 32]).created(at(10)))
+            .insert(
+                KeyEntry::new("next", vec![3; 32])
+                    .created(at(5))
+                    .expires(at(8)),
+            );
+        assert_eq!(keys.current_at(at(15)).unwrap().id, "new");
+        assert_eq!(keys.key("old").unwrap().unwrap().material(), [1; 32]);
+
+        let keys = keys.current_id("old");
+        assert_eq!(keys.current_at(at(25)).unwrap().id, "old");
+        let only_expired = KeySet::new().insert(KeyEntry::new("old", vec![]).expires(at(2)));
+        assert!(matches!(
+            only_expired.current_at(at(3)),
+            Err(Error::NoCurrent)
+        ));
+    }
+
+    #[test]
+    fn reads_files_and_variables() {
+        let path = std::env::temp_dir().join(format!("intermodal-keys-{}", std::process::id()));
+        fs::write(
+            &path,
+            r#"{ "keys": [
+                { "id": "a", "material": "00ff", "created": "2020-06-01T00:00:00Z" },
+                { "id": "b", "material": "0F0F", "expires": "2020-06-02T00:00:00Z" }
+            ], "current": "b" }"#,
+        )
+        .unwrap();
+        let keys = KeySet::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(keys.keys().len(), 2);
+        assert_eq!(keys.keys()[0].created, Some(at(1)));
+        let current = keys.current().unwrap();
+        assert_eq!(
+            (current.id.as_str(), current.material()),
+            ("b", &[15, 15][..])
+        );
+
+        let vars = [
+            ("INTERMODAL_KEY_B", "02"),
+            ("INTERMODAL_KEY_A", "01"),
+            ("INTERMODAL_KEY_CURRENT", "A"),
+            ("PATH", "/bin"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let keys = KeySet::from_vars("INTERMODAL_KEY_", vars).unwrap();
+        let ids: Vec<_> = keys.keys().iter().map(|key| key.id.as_str()).collect();
+        assert_eq!(ids, ["A", "B"]);
+        assert_eq!(keys.current().unwrap().material(), [1]);
+
+        let bad = vec![("INTERMODAL_KEY_A".to_string(), "xyz".to_string())];
+        assert!(matches!(
+            KeySet::from_vars("INTERMODAL_KEY_", bad),
+            Err(Error::Material { .. })
+        ));
+    }
+}
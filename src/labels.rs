@@ -0,0 +1,216 @@
+//! Typed access to manifest labels, and the labels intermodal reserves.
+//!
+//! Label values are strings on the wire. The [`Labels`] trait reads them as
+//! other types and writes them from any [`Display`] value, so the parsing
+//! lives in one place:
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use intermodal::labels::{self, Labels};
+//!
+//! let mut labels = HashMap::new();
+//! labels.set("retry-count", 3);
+//! assert_eq!(labels.get_parsed::<u32>("retry-count")?, Some(3));
+//! assert_eq!(labels.get_parsed::<u32>("missing")?, None);
+//!
+//! labels.set(labels::COMPRESSION, "zstd");
+//! assert!(labels::check_user(&labels).is_err());
+//! # Ok::<(), labels::Error>(())
+//! ```
+//!
+//! Keys starting with [`PREFIX`] are reserved for the labels intermodal's
+//! own stages set, such as [`COMPRESSION`]. Producers passing on labels
+//! from elsewhere should [`check_user`] them, so that a user's label is not
+//! mistaken for one of these; [`check`] catches reserved keys that are not
+//! well known, which are usually misspellings.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// The prefix of reserved label keys.
+pub const PREFIX: &str = "intermodal.io/";
+
+/// The codec a packet's content is compressed with; see
+/// [`compression`](crate::compression).
+pub const COMPRESSION: &str = "intermodal.io/compression";
+
+/// The digest of a packet's content; see [`trust`](crate::trust).
+pub const CONTENT_DIGEST: &str = "intermodal.io/content_digest";
+
+/// The pointers of a packet's encrypted fields, as a JSON array.
+pub const ENCRYPTED_FIELDS: &str = "intermodal.io/encrypted_fields";
+
+/// The key a packet's fields were encrypted with.
+pub const ENCRYPTION_KEY_ID: &str = "intermodal.io/encryption_key_id";
+
+/// The RFC 3339 time until which a packet is held; see
+/// [`embargo`](crate::embargo).
+pub const EMBARGO_UNTIL: &str = "intermodal.io/embargo_until";
+
+/// The label keys that exceeded their cardinality limits, comma-separated;
+/// see [`cardinality`](crate::cardinality).
+pub const CARDINALITY_EXCEEDED: &str = "intermodal.io/cardinality_exceeded";
+
+/// Every reserved label key in use.
+pub const WELL_KNOWN: &[&str] = &[
+    COMPRESSION,
+    CONTENT_DIGEST,
+    ENCRYPTED_FIELDS,
+    ENCRYPTION_KEY_ID,
+    EMBARGO_UNTIL,
+    CARDINALITY_EXCEEDED,
+];
+
+/// Whether the key is reserved for intermodal's own labels.
+pub fn is_reserved(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+/// Typed getters and setters for a manifest's labels.
+pub trait Labels {
+    /// Parses the value of the label, if there is one.
+    fn get_parsed<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: FromStr,
+        T::Err: Display;
+
+    /// Sets the label to the value's display form.
+    fn set<K: Into<String>, V: Display>(&mut self, key: K, value: V);
+}
+
+impl Labels for HashMap<String, String> {
+    fn get_parsed<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.get(key) {
+            Some(value) => value.parse().map(Some).map_err(|e: T::Err| Error::Parse {
+                key: key.to_string(),
+                value: value.clone(),
+                message: e.to_string(),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    fn set<K: Into<String>, V: Display>(&mut self, key: K, value: V) {
+        self.insert(key.into(), value.to_string());
+    }
+}
+
+/// Checks that none of the labels has a reserved key.
+pub fn check_user(labels: &HashMap<String, String>) -> Result<(), Error> {
+    let reserved = sorted(labels.keys().filter(|key| is_reserved(key)));
+    if reserved.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Reserved(reserved))
+    }
+}
+
+/// Checks that every label with a reserved key is a [well-known](WELL_KNOWN)
+/// one.
+pub fn check(labels: &HashMap<String, String>) -> Result<(), Error> {
+    let unknown = sorted(
+        labels
+            .keys()
+            .filter(|key| is_reserved(key) && !WELL_KNOWN.contains(&key.as_str())),
+    );
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Unknown(unknown))
+    }
+}
+
+fn sorted<'a>(keys: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut keys: Vec<_> = keys.cloned().collect();
+    keys.sort();
+    keys
+}
+
+/// An error reading or checking labels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A label's value does not parse as the type asked for.
+    Parse {
+        key: String,
+        value: String,
+        message: String,
+    },
+    /// User labels with reserved keys.
+    Reserved(Vec<String>),
+    /// Reserved keys that are not well known.
+    Unknown(Vec<String>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse {
+                key,
+                value,
+                message,
+            } => write!(f, "label `{}` has value `{}`: {}", key, value, message),
+            Error::Reserved(keys) => {
+                write!(f, "labels with reserved keys: {}", keys.join(", "))
+            }
+            Error::Unknown(keys) => {
+                write!(f, "unknown reserved labels: {}", keys.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn reads_typed_values() {
+        let mut labels = fixtures::cpu_manifest().labels;
+        labels.set("cores", 8);
+        labels.set("sampled", true);
+        assert_eq!(labels["cores"], "8");
+        assert_eq!(labels.get_parsed::<u8>("cores").unwrap(), Some(8));
+        assert_eq!(labels.get_parsed::<bool>("sampled").unwrap(), Some(true));
+        assert_eq!(
+            labels
+                .get_parsed::<String>("environment")
+                .unwrap()
+                .as_deref(),
+            Some("production")
+        );
+
+        let error = labels.get_parsed::<u32>("environment").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "label `environment` has value `production`: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn checks_reserved_keys() {
+        let mut labels = fixtures::cpu_manifest().labels;
+        assert_eq!(check_user(&labels), Ok(()));
+        labels.set(COMPRESSION, "gzip");
+        labels.set("intermodal.io/compresion", "gzip");
+        assert_eq!(
+            check_user(&labels),
+            Err(Error::Reserved(vec![
+                "intermodal.io/compresion".to_string(),
+                COMPRESSION.to_string(),
+            ]))
+        );
+        assert_eq!(
+            check(&labels),
+            Err(Error::Unknown(vec!["intermodal.io/compresion".to_string()]))
+        );
+        assert!(WELL_KNOWN.iter().all(|key| is_reserved(key)));
+    }
+}
@@ -0,0 +1,115 @@
+//! Packets whose content is decoded only when needed.
+//!
+//! A [`LazyPacket`] decodes a JSON envelope's manifest and keeps the content
+//! as the JSON text it arrived in, checked only for being well formed. A
+//! router that needs just the manifest to pick a destination skips building
+//! the content, and forwards it without re-encoding:
+//!
+//! ```
+//! use intermodal::LazyPacket;
+//!
+//! let bytes = br#"{
+//!     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//!                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//!     "content": { "seconds": 86400 }
+//! }"#;
+//! let packet = LazyPacket::from_slice(bytes).unwrap();
+//! assert_eq!(packet.manifest.kind, "uptime");
+//! assert_eq!(packet.content.get(), r#"{ "seconds": 86400 }"#);
+//!
+//! let decoded = packet.decode::<serde_json::Value>().unwrap();
+//! assert_eq!(decoded.content["seconds"], 86400);
+//! ```
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::Packet;
+
+/// Content kept as its JSON text.
+///
+/// Serializes as the JSON it holds, unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RawContent(Box<RawValue>);
+
+impl RawContent {
+    /// Checks that `json` is a single well-formed JSON value and wraps it.
+    pub fn from_string(json: String) -> Result<Self, serde_json::Error> {
+        RawValue::from_string(json).map(RawContent)
+    }
+
+    /// The content's JSON text.
+    pub fn get(&self) -> &str {
+        self.0.get()
+    }
+
+    /// Decodes the content.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_str(self.0.get())
+    }
+}
+
+/// Compares the JSON text, so differently formatted but equal values
+/// differ.
+impl PartialEq for RawContent {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl Eq for RawContent {}
+
+/// A packet with its content not yet decoded.
+pub type LazyPacket = Packet<RawContent>;
+
+impl LazyPacket {
+    /// Decodes a JSON envelope, leaving its content encoded.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(bytes)
+    }
+
+    /// Decodes the content, returning a packet with a copy of the manifest
+    /// and provenance.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<Packet<T>, serde_json::Error> {
+        let mut packet = Packet::new(self.manifest.clone(), self.content.decode()?);
+        packet.provenance = self.provenance.clone();
+        Ok(packet)
+    }
+
+    /// Decodes the content, consuming the packet.
+    pub fn into_decoded<T: DeserializeOwned>(self) -> Result<Packet<T>, serde_json::Error> {
+        self.try_map(|content| content.decode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Netstat};
+
+    #[test]
+    fn defers_content() {
+        let lazy = LazyPacket::from_slice(fixtures::NETSTAT_JSON.as_bytes()).unwrap();
+        assert_eq!(lazy.manifest, fixtures::netstat_manifest());
+        assert!(lazy.content.get().trim_start().starts_with('{'));
+
+        let netstat: Packet<Netstat> = lazy.decode().unwrap();
+        assert_eq!(netstat.content.connections.len(), 2);
+        assert_eq!(
+            lazy.into_decoded::<serde_json::Value>().unwrap(),
+            fixtures::netstat_raw()
+        );
+    }
+
+    #[test]
+    fn forwards_content_verbatim() {
+        let json = r#"{"manifest":{"domain":"example.org","scope":"metrics/host","kind":"cpu","version":1,"ctime":"2020-06-01T12:00:00Z","origin":"host01","labels":{}},"content":{ "idle" : 83.25 }}"#;
+        let lazy = LazyPacket::from_slice(json.as_bytes()).unwrap();
+        assert_eq!(serde_json::to_string(&lazy).unwrap(), json);
+
+        assert!(RawContent::from_string("{".into()).is_err());
+        assert!(LazyPacket::from_slice(b"{\"manifest\": {}}").is_err());
+    }
+}
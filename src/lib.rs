@@ -0,0 +1,173 @@
+//! Intermodal is a standardized envelope for transporting data between
+//! systems.
+//!
+//! Every envelope carries a [`Manifest`] describing its content: what type
+//! of data it is (`domain`, `scope`, `kind` and `version`), where and when it
+//! was created, and any labels attached to it. A [`Packet`] pairs a manifest
+//! with its content, while a [`Header`] holds the manifest alone, letting a
+//! consumer decide how to handle an envelope before decoding its content.
+
+// Lets `::intermodal` paths, as generated by the derive macro, resolve within
+// this crate too.
+extern crate self as intermodal;
+
+#[cfg(feature = "async")]
+pub mod ack;
+pub mod aggregate;
+#[cfg(feature = "aio")]
+pub mod aio;
+pub mod archive;
+#[cfg(feature = "avro")]
+pub mod avro;
+mod base64;
+pub mod batch;
+#[cfg(feature = "blob")]
+pub mod blob;
+mod builder;
+pub mod canonical;
+pub mod cardinality;
+pub mod casing;
+pub mod catalog;
+pub mod census;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod checkpoint;
+pub mod cloudevents;
+pub mod compression;
+pub mod config;
+mod content_type;
+pub mod convert;
+pub mod coordinates;
+mod crc32;
+pub mod decode;
+#[cfg(feature = "async")]
+pub mod dispatch;
+pub mod embargo;
+pub mod encode;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod error;
+pub mod framing;
+mod header;
+mod hex;
+#[cfg(any(
+    feature = "archive-encryption",
+    feature = "ed25519",
+    feature = "encryption"
+))]
+mod hkdf;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod infer;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod keys;
+pub mod labels;
+pub mod lazy;
+#[cfg(feature = "loadgen")]
+pub mod loadgen;
+mod manifest;
+pub mod mapping;
+pub mod migrate;
+#[cfg(feature = "native-plugins")]
+pub mod native;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod negotiate;
+mod packet;
+pub mod patch;
+pub mod peer;
+#[cfg(feature = "async")]
+pub mod pipeline;
+#[cfg(feature = "privacy")]
+pub mod privacy;
+pub mod profile;
+pub mod projection;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "proto")]
+pub mod proto;
+pub mod provenance;
+pub mod quota;
+#[cfg(feature = "async")]
+pub mod reader;
+pub mod registry;
+pub mod relabel;
+pub mod reload;
+pub mod replicate;
+pub mod rewrite;
+pub mod ring;
+pub mod rotation;
+mod router;
+#[cfg(feature = "rayon")]
+pub mod scan;
+pub mod schema;
+#[cfg(feature = "encryption")]
+pub mod sealed;
+pub mod selector;
+pub mod shed;
+pub mod signing;
+pub mod spool;
+#[cfg(feature = "blob")]
+pub mod store;
+pub mod stream;
+pub mod tap;
+pub mod template;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod trace;
+pub mod transform;
+#[cfg(feature = "ed25519")]
+pub mod trust;
+pub mod type_url;
+pub mod validation;
+pub mod version_policy;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod watermark;
+#[cfg(feature = "xml")]
+pub mod xml;
+
+#[cfg(test)]
+mod fixtures;
+
+pub use builder::{BuildError, ManifestBuilder, PacketBuilder};
+pub use content_type::ContentType;
+pub use coordinates::Coordinates;
+pub use encode::Format;
+pub use error::Error;
+pub use header::Header;
+pub use lazy::{LazyPacket, RawContent};
+pub use manifest::Manifest;
+pub use packet::{ByCtime, Packet, RawPacket};
+pub use router::{Route, Router};
+pub use selector::Selector;
+pub use template::Template;
+pub use validation::ValidationError;
+
+#[cfg(feature = "derive")]
+pub use intermodal_derive::Intermodal;
+
+/// Decodes a packet from whichever enabled format it is in, decompressing
+/// [compressed](compression) content, with failures sorted into an
+/// [`Error`]. [`Packet::from_bytes`] and [`decode::DecodeOptions`] decode
+/// with more control.
+///
+/// ```
+/// # let bytes = br#"{ "manifest": { "domain": "example.org", "scope": "metrics/host",
+/// #   "kind": "uptime", "version": 1, "ctime": "2020-06-01T12:00:00Z",
+/// #   "origin": "host01" }, "content": 86400 }"#;
+/// let packet: intermodal::Packet<u64> = intermodal::from_slice(bytes)?;
+/// let json = intermodal::to_vec(&packet, intermodal::Format::Json)?;
+/// assert_eq!(intermodal::from_slice::<u64>(&json)?, packet);
+/// # Ok::<(), intermodal::Error>(())
+/// ```
+pub fn from_slice<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> error::Result<Packet<T>> {
+    Ok(Packet::from_bytes_auto(bytes)?.0)
+}
+
+/// Encodes a packet in the given format, with failures sorted into an
+/// [`Error`].
+pub fn to_vec<T: serde::Serialize>(packet: &Packet<T>, format: Format) -> error::Result<Vec<u8>> {
+    Ok(packet.to_bytes(format)?)
+}
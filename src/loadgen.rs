@@ -0,0 +1,352 @@
+//! Generating synthetic packet streams, for capacity testing.
+//!
+//! A [`LoadGen`] makes packets of a weighted mix of kinds, with payloads of
+//! a range of sizes and labels of a given cardinality, and sends them to
+//! any [`Spool`] at a steady rate, so that a transport or consumer can be
+//! loaded as production would load it, and harder:
+//!
+//! ```
+//! use std::time::Duration;
+//! use intermodal::loadgen::LoadGen;
+//! use intermodal::spool::FileSpool;
+//! # let path = std::env::temp_dir().join(format!("loadgen-doc-{}", std::process::id()));
+//!
+//! let load = LoadGen::new()
+//!     .kind("example.org/metrics/host/cpu@1".parse()?, 3)
+//!     .kind("example.org/metrics/host/netstat@1".parse()?, 1)
+//!     .payload_size(64..=1024)
+//!     .label("tenant", 50)
+//!     .origins(10)
+//!     .rate(2000.0);
+//!
+//! let report = load.run_for(&FileSpool::open(&path)?, Duration::from_millis(50))?;
+//! println!("{}", report);
+//! # std::fs::remove_file(&path)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Each packet's content is an object holding its sequence number, `seq`,
+//! and a random alphanumeric string, `data`, whose length is the payload
+//! size. Label values are `<key>-<n>` for `n` below the label's
+//! cardinality, and origins `loadgen-<n>` below the number of origins.
+//! Packets are sent on a fixed schedule from the start of a run, so a slow
+//! sink does not lower the rate asked for; sending catches up after it,
+//! and the [`Report`] tells what rate was reached. [`LoadGen::packets`]
+//! yields the packets unpaced, for a sink that is not a spool.
+//!
+//! Available with the `loadgen` feature.
+
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::distr::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+
+use crate::spool::Spool;
+use crate::{Coordinates, Manifest, RawPacket};
+
+/// The kind packets are of when no kinds are given.
+pub const DEFAULT_KIND: &str = "loadgen.intermodal/synthetic/load@1";
+
+/// Generates synthetic packets.
+#[derive(Debug)]
+pub struct LoadGen {
+    /// Each kind, and its weight.
+    kinds: Vec<(Coordinates, u32)>,
+    payload: RangeInclusive<usize>,
+    /// Each label, and how many values it takes.
+    labels: Vec<(String, usize)>,
+    origins: usize,
+    rate: Option<f64>,
+    rng: Mutex<StdRng>,
+    seq: AtomicU64,
+}
+
+impl Default for LoadGen {
+    fn default() -> Self {
+        LoadGen::new()
+    }
+}
+
+impl LoadGen {
+    /// A generator of [`DEFAULT_KIND`] packets with 256-byte payloads, no
+    /// labels and one origin, sending as fast as the sink takes them, and
+    /// drawing from a generator seeded by the operating system.
+    pub fn new() -> Self {
+        LoadGen {
+            kinds: Vec::new(),
+            payload: 256..=256,
+            labels: Vec::new(),
+            origins: 1,
+            rate: None,
+            rng: Mutex::new(StdRng::from_os_rng()),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds a kind to the mix, making up `weight` parts of it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is zero.
+    pub fn kind(mut self, coordinates: Coordinates, weight: u32) -> Self {
+        assert!(weight > 0, "kinds need a weight above zero");
+        self.kinds.push((coordinates, weight));
+        self
+    }
+
+    /// Draws payload sizes, in bytes, evenly from `sizes`.
+    pub fn payload_size(mut self, sizes: RangeInclusive<usize>) -> Self {
+        self.payload = sizes;
+        self
+    }
+
+    /// Labels packets with `key`, taking one of `cardinality` values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cardinality` is zero.
+    pub fn label<S: Into<String>>(mut self, key: S, cardinality: usize) -> Self {
+        assert!(cardinality > 0, "labels need at least one value");
+        self.labels.push((key.into(), cardinality));
+        self
+    }
+
+    /// Spreads packets across this many origins.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `origins` is zero.
+    pub fn origins(mut self, origins: usize) -> Self {
+        assert!(origins > 0, "packets need at least one origin");
+        self.origins = origins;
+        self
+    }
+
+    /// Sends this many packets a second.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` is not a positive number.
+    pub fn rate(mut self, per_second: f64) -> Self {
+        assert!(
+            per_second > 0.0 && per_second.is_finite(),
+            "rates must be positive"
+        );
+        self.rate = Some(per_second);
+        self
+    }
+
+    /// Draws from a generator with the given seed, for reproducible runs.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Mutex::new(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    /// Makes the next packet, created now.
+    pub fn generate(&self) -> RawPacket {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        let coordinates = self.choose_kind(&mut rng);
+        let mut manifest = Manifest::builder()
+            .domain(coordinates.domain)
+            .scope(coordinates.scope)
+            .kind(coordinates.kind)
+            .version(coordinates.version)
+            .origin(format!("loadgen-{}", rng.random_range(0..self.origins)));
+        for (key, cardinality) in &self.labels {
+            let value = format!("{}-{}", key, rng.random_range(0..*cardinality));
+            manifest = manifest.label(key.clone(), value);
+        }
+        let size = rng.random_range(self.payload.clone());
+        let data: String = (&mut *rng)
+            .sample_iter(Alphanumeric)
+            .take(size)
+            .map(char::from)
+            .collect();
+        let manifest = manifest.build().expect("generated manifests are complete");
+        RawPacket::new(manifest, json!({ "seq": seq, "data": data }))
+    }
+
+    fn choose_kind(&self, rng: &mut StdRng) -> Coordinates {
+        if self.kinds.is_empty() {
+            return DEFAULT_KIND.parse().expect("the default kind parses");
+        }
+        let total: u64 = self
+            .kinds
+            .iter()
+            .map(|(_, weight)| u64::from(*weight))
+            .sum();
+        let mut pick = rng.random_range(0..total);
+        for (coordinates, weight) in &self.kinds {
+            match pick.checked_sub(u64::from(*weight)) {
+                Some(rest) => pick = rest,
+                None => return coordinates.clone(),
+            }
+        }
+        unreachable!("picks fall below the total weight")
+    }
+
+    /// Generated packets, without end and without pacing.
+    pub fn packets(&self) -> Packets<'_> {
+        Packets { load: self }
+    }
+
+    /// Sends `count` packets to `sink`, then flushes it.
+    pub fn run<S: Spool + ?Sized>(&self, sink: &S, count: u64) -> io::Result<Report> {
+        self.run_until(sink, |sent, _| sent >= count)
+    }
+
+    /// Sends packets to `sink` for `duration`, then flushes it.
+    pub fn run_for<S: Spool + ?Sized>(&self, sink: &S, duration: Duration) -> io::Result<Report> {
+        self.run_until(sink, |_, elapsed| elapsed >= duration)
+    }
+
+    fn run_until<S, F>(&self, sink: &S, done: F) -> io::Result<Report>
+    where
+        S: Spool + ?Sized,
+        F: Fn(u64, Duration) -> bool,
+    {
+        let start = Instant::now();
+        let mut report = Report::default();
+        while !done(report.sent, start.elapsed()) {
+            if let Some(rate) = self.rate {
+                let due = start + Duration::from_secs_f64(report.sent as f64 / rate);
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+            }
+            let packet = self.generate();
+            report.bytes += serde_json::to_vec(&packet)?.len() as u64;
+            sink.spool(&packet)?;
+            report.sent += 1;
+        }
+        sink.flush()?;
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+}
+
+/// Packets from [`LoadGen::packets`].
+#[derive(Debug)]
+pub struct Packets<'a> {
+    load: &'a LoadGen,
+}
+
+impl Iterator for Packets<'_> {
+    type Item = RawPacket;
+
+    fn next(&mut self) -> Option<RawPacket> {
+        Some(self.load.generate())
+    }
+}
+
+/// What a run sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Report {
+    pub sent: u64,
+    /// The size of the packets sent, as JSON envelopes.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// Packets sent a second.
+    pub fn rate(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.sent as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} packets ({} bytes) in {:.3}s, {:.1} per second",
+            self.sent,
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.rate()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    #[derive(Default)]
+    struct Recording(Mutex<Vec<RawPacket>>);
+
+    impl Spool for Recording {
+        fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+            self.0.lock().unwrap().push(packet.clone());
+            Ok(())
+        }
+    }
+
+    fn mix() -> LoadGen {
+        LoadGen::new()
+            .kind("example.org/metrics/host/cpu@1".parse().unwrap(), 3)
+            .kind("example.org/metrics/host/netstat@2".parse().unwrap(), 1)
+            .payload_size(10..=20)
+            .label("tenant", 5)
+            .origins(3)
+            .seed(7)
+    }
+
+    #[test]
+    fn generates_the_mix() {
+        let mut kinds = HashMap::new();
+        let (mut tenants, mut origins) = (HashSet::new(), HashSet::new());
+        for (i, packet) in mix().packets().take(4000).enumerate() {
+            let manifest = &packet.manifest;
+            *kinds.entry(manifest.kind.clone()).or_insert(0) += 1;
+            tenants.insert(manifest.labels["tenant"].clone());
+            origins.insert(manifest.origin.clone());
+            assert_eq!(packet.content["seq"], i);
+            let size = packet.content["data"].as_str().unwrap().len();
+            assert!((10..=20).contains(&size), "{}", size);
+        }
+        assert!((2800..3200).contains(&kinds["cpu"]), "{:?}", kinds);
+        assert_eq!(tenants.len(), 5);
+        assert!(tenants.contains("tenant-4"));
+        assert_eq!(origins.len(), 3);
+
+        // Seeded runs draw the same packets.
+        let (a, b) = (mix().generate(), mix().generate());
+        assert_eq!(
+            (a.manifest.labels, a.content),
+            (b.manifest.labels, b.content)
+        );
+        let default = LoadGen::new().generate();
+        assert_eq!(default.manifest.coordinates().to_string(), DEFAULT_KIND);
+    }
+
+    #[test]
+    fn sends_at_the_rate() {
+        let sink = Recording::default();
+        let report = mix().rate(1000.0).run(&sink, 50).unwrap();
+        assert_eq!(report.sent, 50);
+        assert_eq!(sink.0.lock().unwrap().len(), 50);
+        // The last of 50 packets is due 49ms in.
+        assert!(report.elapsed >= Duration::from_millis(49), "{}", report);
+        assert!(report.bytes > 50 * 10);
+
+        let sink = Recording::default();
+        let report = mix().run_for(&sink, Duration::from_millis(5)).unwrap();
+        assert!(report.sent > 0);
+        assert!(report.to_string().starts_with("sent "));
+    }
+}
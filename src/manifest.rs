@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::trace::TraceContext;
+
+/// Describes the content carried by an envelope.
+///
+/// The `domain`, `scope`, `kind` and `version` fields together identify the
+/// type of the content, while `ctime`, `expires`, `origin`, `labels`,
+/// `trace`, `correlation_id` and `reply_to` describe this particular
+/// instance of it.
+///
+/// Manifests are equal when all of their fields are equal, including the
+/// ones describing the instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Manifest {
+    /// The organization or namespace responsible for the content type, in DNS
+    /// form, e.g. `example.org`.
+    pub domain: String,
+    /// A slash-separated path grouping related kinds within the domain, e.g.
+    /// `metrics/host`.
+    pub scope: String,
+    /// The name of the content type within its scope.
+    pub kind: String,
+    /// The revision of the content type's schema.
+    pub version: u32,
+    /// When the content was created.
+    pub ctime: DateTime<Utc>,
+    /// When the content goes stale, and should be dropped rather than
+    /// delivered; see [`Router::drop_expired`](crate::Router::drop_expired).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    /// The system that created the content, typically a hostname.
+    pub origin: String,
+    /// Arbitrary key/value annotations, serialized in key order so that
+    /// encoding a manifest always yields the same bytes.
+    #[serde(default, serialize_with = "sorted")]
+    pub labels: HashMap<String, String>,
+    /// The trace the content belongs to, if it is traced; see
+    /// [`trace`](crate::trace).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceContext>,
+    /// Ties a response to its request, for request-response exchanges; see
+    /// [`Packet::reply_with`](crate::Packet::reply_with).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Where a response to the content should be sent, such as a queue or
+    /// topic name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+}
+
+fn sorted<S: Serializer>(
+    labels: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(labels.iter().collect::<BTreeMap<_, _>>())
+}
+
+impl Hash for Manifest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.domain.hash(state);
+        self.scope.hash(state);
+        self.kind.hash(state);
+        self.version.hash(state);
+        self.ctime.hash(state);
+        self.expires.hash(state);
+        self.origin.hash(state);
+        // Hash labels in key order, so that equal maps hash equally.
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_unstable();
+        labels.hash(state);
+        self.trace.hash(state);
+        self.correlation_id.hash(state);
+        self.reply_to.hash(state);
+    }
+}
+
+impl Manifest {
+    /// Whether the content has expired by `now`. Content without an
+    /// `expires` time never does.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl AsRef<Manifest> for Manifest {
+    fn as_ref(&self) -> &Manifest {
+        self
+    }
+}
@@ -0,0 +1,377 @@
+//! Adapting the shape of content without code.
+//!
+//! A [`Mapping`] is a list of [`Rule`]s, each taking the value at a JSON
+//! pointer in a packet's content to another, converting it to another type
+//! or supplying a default for it on the way. It is loaded from
+//! configuration and run as a [`Transform`] stage, so that a consumer
+//! expecting a slightly different shape than a producer emits needs no
+//! code deployed to bridge the two. In YAML:
+//!
+//! ```yaml
+//! kind: cpu
+//! version: 2
+//! rules:
+//!   - from: /user_pct
+//!     to: /user
+//!     cast: float
+//!   - from: /host/name
+//!     to: /hostname
+//!   - from: /cores
+//!     default: 1
+//! ```
+//!
+//! A rule with both `from` and `to` moves the value, a rename; one with only
+//! `from` converts the value in place; and one with only `to` sets a
+//! constant `default`. Rules apply in order, each to the content as the
+//! rules before it left it. Content the rules do not mention is kept,
+//! unless the mapping sets `drop_unmapped`.
+//!
+//! ```
+//! use intermodal::mapping::{Cast, Mapping};
+//! use serde_json::json;
+//! # let mut packet: intermodal::RawPacket = serde_json::from_value(json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "cpu", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": null
+//! # })).unwrap();
+//!
+//! let mapping = Mapping::new()
+//!     .rename("/user_pct", "/user")
+//!     .cast("/user", Cast::Float)
+//!     .default_to("/cores", json!(1));
+//!
+//! packet.content = json!({ "user_pct": "12.5", "idle": 83.25 });
+//! let mapped = mapping.map(&packet)?;
+//! assert_eq!(mapped.content, json!({ "user": 12.5, "idle": 83.25, "cores": 1 }));
+//! # Ok::<(), intermodal::mapping::Error>(())
+//! ```
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::transform::{self, Transform};
+use crate::{patch, projection, RawPacket};
+
+/// A configured adaptation of content.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+    /// The kind of the mapped packet. Defaults to that of the source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// The version of the mapped packet. Defaults to that of the source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Whether to leave out content no rule mentions.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub drop_unmapped: bool,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// One value to carry over, and what to do with it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// A JSON pointer into the source content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// A JSON pointer into the mapped content. Defaults to `from`. Objects
+    /// along the way are created as needed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cast: Option<Cast>,
+    /// The value to use when the source has none, or `null`. Without one,
+    /// the rule is skipped for such sources.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+}
+
+/// A type to convert a value to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cast {
+    /// Scalars in their JSON encoding, strings as they are.
+    String,
+    /// Integral numbers, booleans as 0 or 1, and strings that parse as
+    /// integers.
+    Integer,
+    /// Numbers, and strings that parse as numbers.
+    Float,
+    /// Booleans, the numbers 0 and 1, and the strings `true` and `false`.
+    Boolean,
+}
+
+impl Mapping {
+    pub fn new() -> Self {
+        Mapping::default()
+    }
+
+    /// Gives the mapped packet its own kind and version.
+    pub fn into_kind<S: Into<String>>(mut self, kind: S, version: u32) -> Self {
+        self.kind = Some(kind.into());
+        self.version = Some(version);
+        self
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Moves the value at `from` to `to`.
+    pub fn rename<F: Into<String>, T: Into<String>>(self, from: F, to: T) -> Self {
+        self.rule(Rule {
+            from: Some(from.into()),
+            to: Some(to.into()),
+            ..Rule::default()
+        })
+    }
+
+    /// Converts the value at `pointer` in place.
+    pub fn cast<P: Into<String>>(self, pointer: P, cast: Cast) -> Self {
+        self.rule(Rule {
+            from: Some(pointer.into()),
+            cast: Some(cast),
+            ..Rule::default()
+        })
+    }
+
+    /// Sets the value at `pointer` to `value` where the source has none.
+    pub fn default_to<P: Into<String>>(self, pointer: P, value: Value) -> Self {
+        let pointer = pointer.into();
+        self.rule(Rule {
+            from: Some(pointer.clone()),
+            to: Some(pointer),
+            default: Some(value),
+            ..Rule::default()
+        })
+    }
+
+    /// Maps a packet's content.
+    ///
+    /// The mapped packet keeps the source's manifest, apart from its kind
+    /// and version if the mapping sets them, and its provenance.
+    pub fn map(&self, packet: &RawPacket) -> Result<RawPacket, Error> {
+        let content = self.map_content(packet.content.clone())?;
+        let mut mapped = RawPacket::new(packet.manifest.clone(), content);
+        if let Some(kind) = &self.kind {
+            mapped.manifest.kind = kind.clone();
+        }
+        if let Some(version) = self.version {
+            mapped.manifest.version = version;
+        }
+        mapped.provenance = packet.provenance.clone();
+        Ok(mapped)
+    }
+
+    /// Maps content alone, as [`map`](Mapping::map) maps a packet's.
+    pub fn map_content(&self, mut content: Value) -> Result<Value, Error> {
+        let mut mapped = Vec::new();
+        for rule in &self.rules {
+            let to = match rule.to.as_ref().or(rule.from.as_ref()) {
+                Some(to) => to,
+                None => continue,
+            };
+            let found = match &rule.from {
+                Some(from) if from != to => patch::remove(&mut content, from).ok(),
+                Some(from) => content.pointer(from).cloned(),
+                None => None,
+            };
+            let value = match found.filter(|value| !value.is_null()) {
+                Some(value) => value,
+                None => match &rule.default {
+                    Some(default) => default.clone(),
+                    None => continue,
+                },
+            };
+            let value = match rule.cast {
+                Some(cast) => cast.apply(value).map_err(|value| Error::Cast {
+                    pointer: to.clone(),
+                    value,
+                    cast,
+                })?,
+                None => value,
+            };
+            set(&mut content, to, value);
+            mapped.push(to);
+        }
+        if self.drop_unmapped {
+            let mut only = Value::Object(Map::new());
+            for to in mapped {
+                if let Some(value) = content.pointer(to) {
+                    set(&mut only, to, value.clone());
+                }
+            }
+            content = only;
+        }
+        Ok(content)
+    }
+}
+
+fn set(target: &mut Value, pointer: &str, value: Value) {
+    if pointer.is_empty() {
+        *target = value;
+    } else {
+        projection::insert(target, pointer, value);
+    }
+}
+
+impl Cast {
+    /// Converts the value, or returns it if it cannot be converted.
+    fn apply(self, value: Value) -> Result<Value, Value> {
+        let cast = match (self, &value) {
+            (Cast::String, Value::String(_)) => Some(value.clone()),
+            (Cast::String, Value::Number(_) | Value::Bool(_)) => {
+                Some(Value::String(value.to_string()))
+            }
+            (Cast::Integer, Value::Number(n)) => n
+                .as_i64()
+                .map(Value::from)
+                .or_else(|| n.as_u64().map(Value::from))
+                .or_else(|| {
+                    n.as_f64()
+                        .filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64)
+                        .map(|f| Value::from(f as i64))
+                }),
+            (Cast::Integer, Value::Bool(b)) => Some(Value::from(*b as i64)),
+            (Cast::Integer, Value::String(s)) => s.trim().parse::<i64>().ok().map(Value::from),
+            (Cast::Float, Value::Number(n)) => n.as_f64().map(Value::from),
+            (Cast::Float, Value::String(s)) => s
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(Value::from),
+            (Cast::Boolean, Value::Bool(_)) => Some(value.clone()),
+            (Cast::Boolean, Value::Number(n)) => match n.as_u64() {
+                Some(0) => Some(Value::Bool(false)),
+                Some(1) => Some(Value::Bool(true)),
+                _ => None,
+            },
+            (Cast::Boolean, Value::String(s)) => match s.trim() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        };
+        cast.ok_or(value)
+    }
+}
+
+impl fmt::Display for Cast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cast::String => "string",
+            Cast::Integer => "integer",
+            Cast::Float => "float",
+            Cast::Boolean => "boolean",
+        })
+    }
+}
+
+/// Replaces each packet's content with its mapping.
+impl Transform for Mapping {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.map(&packet)
+            .map(Some)
+            .map_err(|e| transform::Error::new(e.to_string()))
+    }
+}
+
+/// An error mapping content.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A value could not be converted as a rule asks.
+    Cast {
+        pointer: String,
+        value: Value,
+        cast: Cast,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Cast {
+                pointer,
+                value,
+                cast,
+            } => write!(f, "cannot cast {} at `{}` to {}", value, pointer, cast),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::json;
+
+    #[test]
+    fn renames_casts_and_defaults() {
+        let mapping: Mapping = serde_json::from_value(json!({
+            "kind": "cpu-v2",
+            "rules": [
+                { "from": "/user", "to": "/usage/user" },
+                { "from": "/system", "cast": "string" },
+                { "from": "/steal", "to": "/usage/steal", "default": 0 },
+                { "to": "/unit", "default": "percent" },
+            ]
+        }))
+        .unwrap();
+        let mapped = mapping.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert_eq!(mapped.manifest.kind, "cpu-v2");
+        assert_eq!(
+            mapped.content,
+            json!({
+                "usage": { "user": 12.5, "steal": 0 },
+                "system": "4.25",
+                "idle": 83.25,
+                "unit": "percent",
+            })
+        );
+
+        let only = Mapping {
+            drop_unmapped: true,
+            ..Mapping::new().rename("/idle", "/free")
+        };
+        assert_eq!(
+            only.map(&fixtures::cpu_raw()).unwrap().content,
+            json!({ "free": 83.25 })
+        );
+    }
+
+    #[test]
+    fn casts() {
+        let cases = [
+            (json!("42"), Cast::Integer, Ok(json!(42))),
+            (json!(42.0), Cast::Integer, Ok(json!(42))),
+            (json!(true), Cast::Integer, Ok(json!(1))),
+            (json!(4.5), Cast::Integer, Err(json!(4.5))),
+            (json!(" 4.5 "), Cast::Float, Ok(json!(4.5))),
+            (json!("NaN"), Cast::Float, Err(json!("NaN"))),
+            (json!(7), Cast::String, Ok(json!("7"))),
+            (json!([7]), Cast::String, Err(json!([7]))),
+            (json!("false"), Cast::Boolean, Ok(json!(false))),
+            (json!(1), Cast::Boolean, Ok(json!(true))),
+            (json!(2), Cast::Boolean, Err(json!(2))),
+        ];
+        for (value, cast, expected) in cases {
+            assert_eq!(cast.apply(value.clone()), expected, "{} to {}", value, cast);
+        }
+
+        let mapping = Mapping::new().cast("/idle", Cast::Boolean);
+        let error = mapping.map(&fixtures::cpu_raw()).unwrap_err();
+        assert_eq!(error.to_string(), "cannot cast 83.25 at `/idle` to boolean");
+    }
+}
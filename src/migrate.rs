@@ -0,0 +1,352 @@
+//! Upgrading content from older versions of its type to newer ones.
+//!
+//! A [`Migrator`] holds one step per version of a content type, each
+//! converting content of that version into content of the next.
+//! [`Migrator::upgrade`] chains the steps from whatever version an envelope
+//! holds up to the version of the type asked for, so consumers of the
+//! latest version stop upcasting each older one their own way:
+//!
+//! ```
+//! use intermodal::migrate::Migrator;
+//! use intermodal::{ContentType, Coordinates};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct UptimeV1(u64);
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct UptimeV2 {
+//!     seconds: u64,
+//! }
+//!
+//! #[derive(Deserialize)]
+//! struct Uptime {
+//!     seconds: u64,
+//!     rebooted: bool,
+//! }
+//!
+//! impl ContentType for Uptime {
+//!     const DOMAIN: &'static str = "example.org";
+//!     const SCOPE: &'static str = "metrics/host";
+//!     const KIND: &'static str = "uptime";
+//!     const VERSION: u32 = 3;
+//! }
+//!
+//! let uptime = |version| Coordinates::new("example.org", "metrics/host", "uptime", version);
+//! let mut migrator = Migrator::new();
+//! migrator
+//!     .register(uptime(1), |UptimeV1(seconds)| UptimeV2 { seconds })
+//!     .register(uptime(2), |v2: UptimeV2| serde_json::json!({
+//!         "seconds": v2.seconds,
+//!         "rebooted": v2.seconds < 600,
+//!     }));
+//!
+//! let bytes = br#"{
+//!     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//!                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//!     "content": 86400
+//! }"#;
+//! let packet = migrator.upgrade::<Uptime>(bytes)?;
+//! assert_eq!(packet.manifest.version, 3);
+//! assert_eq!((packet.content.seconds, packet.content.rebooted), (86400, false));
+//! # Ok::<(), intermodal::migrate::Error>(())
+//! ```
+//!
+//! An upgraded packet's manifest carries the version it was upgraded to.
+//! Its content is no longer what was signed, so its signature is dropped;
+//! its provenance is kept.
+//!
+//! A migrator is also a [`Transform`], upgrading each packet as far as its
+//! steps lead, so that a [`Rewriter`](crate::rewrite::Rewriter) can bring an
+//! archive up to date. Steps kept in configuration rather than code are
+//! [`Mapping`]s, registered with [`register_mapping`](Migrator::register_mapping).
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::mapping::Mapping;
+use crate::transform::{self, Transform};
+use crate::version_policy::Compatibility;
+use crate::{ContentType, Coordinates, Packet, RawPacket};
+
+type Step = Box<dyn Fn(Value) -> Result<Value, Error> + Send + Sync>;
+
+/// Steps between consecutive versions of content types.
+#[derive(Default)]
+pub struct Migrator {
+    /// Each step, keyed by the coordinates of the content it converts.
+    steps: HashMap<Coordinates, Step>,
+}
+
+impl Migrator {
+    pub fn new() -> Self {
+        Migrator::default()
+    }
+
+    /// Registers the step converting content at `from` into content of the
+    /// next version of its type, replacing any registered for it before.
+    pub fn register<A, B, F>(&mut self, from: Coordinates, step: F) -> &mut Self
+    where
+        A: DeserializeOwned,
+        B: Serialize,
+        F: Fn(A) -> B + Send + Sync + 'static,
+    {
+        let coordinates = from.clone();
+        self.register_json(from, move |content| {
+            let content = serde_json::from_value(content).map_err(Error::Decode)?;
+            serde_json::to_value(step(content)).map_err(|e| Error::Step {
+                from: coordinates.clone(),
+                message: e.to_string(),
+            })
+        })
+    }
+
+    /// Registers a step working on the JSON content directly, which may
+    /// reject content it cannot convert with [`Error::Step`].
+    pub fn register_json<F>(&mut self, from: Coordinates, step: F) -> &mut Self
+    where
+        F: Fn(Value) -> Result<Value, Error> + Send + Sync + 'static,
+    {
+        self.steps.insert(from, Box::new(step));
+        self
+    }
+
+    /// Registers a step adapting content with a mapping. The mapping's kind
+    /// and version are not used: the step always leads to the next version
+    /// of the same type.
+    pub fn register_mapping(&mut self, from: Coordinates, mapping: Mapping) -> &mut Self {
+        let coordinates = from.clone();
+        self.register_json(from, move |content| {
+            mapping.map_content(content).map_err(|e| Error::Step {
+                from: coordinates.clone(),
+                message: e.to_string(),
+            })
+        })
+    }
+
+    /// The newest version content at `from` can be upgraded to, which is
+    /// its own if no step is registered from it.
+    pub fn latest(&self, from: &Coordinates) -> u32 {
+        let mut at = from.clone();
+        while self.steps.contains_key(&at) {
+            at.version += 1;
+        }
+        at.version
+    }
+
+    /// Whether content at `from` can be upgraded to `version`.
+    pub fn can_upgrade(&self, from: &Coordinates, version: u32) -> bool {
+        let mut at = from.clone();
+        while at.version < version {
+            if !self.steps.contains_key(&at) {
+                return false;
+            }
+            at.version += 1;
+        }
+        at.version == version
+    }
+
+    /// Decodes a JSON envelope holding any version of `T`'s type, upgrading
+    /// its content to `T`'s version.
+    pub fn upgrade<T: ContentType + DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<Packet<T>, Error> {
+        let packet: RawPacket = serde_json::from_slice(bytes).map_err(Error::Decode)?;
+        let found = packet.manifest.coordinates();
+        if !Compatibility::between(&T::coordinates(), &found).is_same_type() {
+            return Err(Error::Type(found));
+        }
+        self.upgrade_raw(packet, T::VERSION)?
+            .try_map(serde_json::from_value)
+            .map_err(Error::Decode)
+    }
+
+    /// Upgrades the content of a raw packet to `version` of its type.
+    pub fn upgrade_raw(&self, mut packet: RawPacket, version: u32) -> Result<RawPacket, Error> {
+        let mut at = packet.manifest.coordinates();
+        if at.version > version {
+            return Err(Error::Downgrade { from: at, version });
+        }
+        if at.version == version {
+            return Ok(packet);
+        }
+        let mut content = packet.content;
+        while at.version < version {
+            let step = self
+                .steps
+                .get(&at)
+                .ok_or_else(|| Error::Missing(at.clone()))?;
+            content = step(content)?;
+            at.version += 1;
+        }
+        packet.content = content;
+        packet.manifest.version = version;
+        packet.signature = None;
+        Ok(packet)
+    }
+}
+
+/// Upgrades each packet to the [latest](Migrator::latest) version its steps
+/// lead to. Packets with no steps registered pass as they are.
+impl Transform for Migrator {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        let version = self.latest(&packet.manifest.coordinates());
+        self.upgrade_raw(packet, version)
+            .map(Some)
+            .map_err(|e| transform::Error::new(e.to_string()))
+    }
+}
+
+impl fmt::Debug for Migrator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migrator")
+            .field("steps", &self.steps.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// An error upgrading content.
+#[derive(Debug)]
+pub enum Error {
+    /// The envelope, or the content given to a step, could not be decoded.
+    Decode(serde_json::Error),
+    /// The envelope holds content of another type than the one asked for.
+    /// Holds the envelope's coordinates.
+    Type(Coordinates),
+    /// The content is of a newer version than the one asked for.
+    Downgrade { from: Coordinates, version: u32 },
+    /// No step is registered from these coordinates.
+    Missing(Coordinates),
+    /// The step from these coordinates could not convert the content.
+    Step { from: Coordinates, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(e) => write!(f, "undecodable content: {}", e),
+            Error::Type(found) => write!(f, "content of another type: {}", found),
+            Error::Downgrade { from, version } => {
+                write!(f, "cannot downgrade {} to version {}", from, version)
+            }
+            Error::Missing(from) => write!(f, "no migration registered from {}", from),
+            Error::Step { from, message } => {
+                write!(f, "migration from {} failed: {}", from, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    struct CpuV3 {
+        busy: f64,
+        idle: f64,
+    }
+
+    impl ContentType for CpuV3 {
+        const DOMAIN: &'static str = "example.org";
+        const SCOPE: &'static str = "metrics/host";
+        const KIND: &'static str = "cpu";
+        const VERSION: u32 = 3;
+    }
+
+    fn cpu(version: u32) -> Coordinates {
+        Coordinates::new("example.org", "metrics/host", "cpu", version)
+    }
+
+    fn migrator() -> Migrator {
+        let mut migrator = Migrator::new();
+        migrator
+            .register(
+                cpu(1),
+                |v1: Cpu| json!({ "busy": v1.user + v1.system, "idle": v1.idle }),
+            )
+            .register_json(cpu(2), |v2| match v2["busy"].as_f64() {
+                Some(busy) if busy <= 100.0 => Ok(v2),
+                _ => Err(Error::Step {
+                    from: cpu(2),
+                    message: "busy out of range".to_string(),
+                }),
+            });
+        migrator
+    }
+
+    #[test]
+    fn chains_steps() {
+        let migrator = migrator();
+        let packet = migrator
+            .upgrade::<CpuV3>(fixtures::CPU_JSON.as_bytes())
+            .unwrap();
+        assert_eq!(packet.manifest.version, 3);
+        assert_eq!(packet.content.busy, 16.75);
+        assert_eq!(packet.content.idle, 83.25);
+        assert!(migrator.can_upgrade(&cpu(1), 3));
+        assert!(!migrator.can_upgrade(&cpu(1), 4));
+        assert!(!migrator.can_upgrade(&cpu(4), 3));
+
+        let current = migrator.upgrade_raw(fixtures::cpu_raw(), 1).unwrap();
+        assert_eq!(current, fixtures::cpu_raw());
+    }
+
+    #[test]
+    fn reports_what_cannot_be_upgraded() {
+        let migrator = migrator();
+        assert!(matches!(
+            migrator.upgrade::<CpuV3>(fixtures::NETSTAT_JSON.as_bytes()),
+            Err(Error::Type(_))
+        ));
+        assert!(matches!(
+            migrator.upgrade_raw(fixtures::cpu_raw(), 4),
+            Err(Error::Missing(from)) if from == cpu(3)
+        ));
+        assert!(matches!(
+            migrator.upgrade_raw(fixtures::cpu_raw(), 0),
+            Err(Error::Downgrade { .. })
+        ));
+
+        let mut hot = fixtures::cpu_raw();
+        hot.content["user"] = json!(99.0);
+        assert!(matches!(
+            migrator.upgrade_raw(hot, 3),
+            Err(Error::Step { from, .. }) if from == cpu(2)
+        ));
+    }
+
+    #[test]
+    fn upgrades_as_a_transform() {
+        let mut migrator = migrator();
+        migrator.register_mapping(cpu(3), Mapping::new().rename("/busy", "/utilization/busy"));
+        assert_eq!(migrator.latest(&cpu(1)), 4);
+        assert_eq!(migrator.latest(&cpu(7)), 7);
+
+        let packet = migrator.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert_eq!(packet.manifest.version, 4);
+        assert_eq!(
+            packet.content,
+            json!({ "utilization": { "busy": 16.75 }, "idle": 83.25 })
+        );
+        let netstat = migrator.apply(fixtures::netstat_raw()).unwrap().unwrap();
+        assert_eq!(netstat, fixtures::netstat_raw());
+    }
+}
@@ -0,0 +1,324 @@
+//! Handler plugins loaded from shared libraries.
+//!
+//! A native plugin is a shared library exporting a single function,
+//!
+//! ```c
+//! const IntermodalPlugin *intermodal_plugin(void);
+//! ```
+//!
+//! returning a pointer to a static [`PluginVTable`]. The declarations in
+//! `include/intermodal_plugin.h` describe the same layout for plugins written
+//! in C. The vtable names the kinds the plugin handles and the functions the
+//! host calls to pass it packets. Packets cross the boundary as JSON-encoded
+//! [`RawPacket`]s.
+//!
+//! Unlike [WebAssembly plugins](crate::wasm), native plugins run in the host
+//! process without any isolation; they are only as trustworthy as the code
+//! they were built from.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::transform::{self, Transform};
+use crate::{Manifest, RawPacket, Selector};
+
+/// The plugin interface version this host implements.
+pub const ABI_VERSION: u32 = 1;
+
+const ENTRY_POINT: &[u8] = b"intermodal_plugin\0";
+
+/// The table of kinds and functions a plugin exposes to its host.
+#[repr(C)]
+pub struct PluginVTable {
+    /// Must equal [`ABI_VERSION`].
+    pub abi_version: u32,
+    /// A NUL-terminated name for the plugin, used in diagnostics.
+    pub name: *const c_char,
+    pub kinds: *const KindSpec,
+    pub kinds_len: usize,
+    /// Handles the JSON-encoded packet in `packet[..len]`.
+    ///
+    /// Returns zero on success, optionally filling `out` with the JSON
+    /// encoding of a packet to pass on; a null `out.ptr` means nothing is
+    /// passed on. Any other return value is an error code.
+    pub handle: unsafe extern "C" fn(packet: *const u8, len: usize, out: *mut Buffer) -> i32,
+    /// Releases a buffer previously filled in by `handle`.
+    pub free_buffer: unsafe extern "C" fn(buffer: *mut Buffer),
+}
+
+/// Coordinates of a kind handled by a plugin.
+///
+/// Null strings, and a `version` of zero, match anything.
+#[repr(C)]
+pub struct KindSpec {
+    pub domain: *const c_char,
+    pub scope: *const c_char,
+    pub kind: *const c_char,
+    pub version: u32,
+}
+
+/// A plugin-owned byte buffer.
+#[repr(C)]
+pub struct Buffer {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+/// A loaded native plugin.
+pub struct NativePlugin {
+    name: String,
+    vtable: &'static PluginVTable,
+    selectors: Vec<Selector>,
+    // Keeps the library mapped for as long as the vtable is in use.
+    _library: Option<libloading::Library>,
+}
+
+// The vtable is immutable and plugins are required to be thread-safe.
+unsafe impl Send for NativePlugin {}
+unsafe impl Sync for NativePlugin {}
+
+impl NativePlugin {
+    /// Loads a plugin from a shared library.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialization code, and the plugin's
+    /// functions are trusted to honor the interface described in this
+    /// module. Neither can be checked.
+    pub unsafe fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let library =
+            libloading::Library::new(path.as_ref()).map_err(|e| Error::new(e.to_string()))?;
+        let entry: libloading::Symbol<unsafe extern "C" fn() -> *const PluginVTable> = library
+            .get(ENTRY_POINT)
+            .map_err(|e| Error::new(e.to_string()))?;
+        let vtable = entry();
+        if vtable.is_null() {
+            return Err(Error::new("`intermodal_plugin` returned null"));
+        }
+        let mut plugin = NativePlugin::from_vtable(&*vtable)?;
+        plugin._library = Some(library);
+        Ok(plugin)
+    }
+
+    /// Wraps a vtable that is already present in the process, such as one
+    /// from a statically linked plugin.
+    ///
+    /// # Safety
+    ///
+    /// The vtable's pointers and functions must honor the interface
+    /// described in this module.
+    pub unsafe fn from_vtable(vtable: &'static PluginVTable) -> Result<Self, Error> {
+        if vtable.abi_version != ABI_VERSION {
+            return Err(Error::new(format!(
+                "plugin ABI version {} is not supported (expected {})",
+                vtable.abi_version, ABI_VERSION
+            )));
+        }
+        let name = string(vtable.name).unwrap_or_else(|| String::from("unnamed"));
+
+        let kinds: &[KindSpec] = if vtable.kinds.is_null() || vtable.kinds_len == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(vtable.kinds, vtable.kinds_len)
+        };
+        // Kinds are taken as they are, so a plugin cannot widen what it
+        // handles with a pattern, nor fail by naming a kind oddly.
+        let selectors = kinds
+            .iter()
+            .map(|spec| {
+                Selector::exact(
+                    string(spec.domain),
+                    string(spec.scope),
+                    string(spec.kind),
+                    Some(spec.version).filter(|&version| version != 0),
+                )
+            })
+            .collect();
+
+        Ok(NativePlugin {
+            name,
+            vtable,
+            selectors,
+            _library: None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// One selector per kind the plugin declared, for registering the plugin
+    /// with a [`Router`](crate::Router).
+    pub fn selectors(&self) -> &[Selector] {
+        &self.selectors
+    }
+
+    /// Returns whether the plugin declared a kind matching the manifest.
+    pub fn handles(&self, manifest: &Manifest) -> bool {
+        self.selectors
+            .iter()
+            .any(|selector| selector.matches(manifest))
+    }
+
+    /// Passes a packet to the plugin, returning whatever it passes on.
+    pub fn handle(&self, packet: &RawPacket) -> Result<Option<RawPacket>, Error> {
+        let input = serde_json::to_vec(packet).map_err(|e| Error::new(e.to_string()))?;
+        let mut out = Buffer {
+            ptr: std::ptr::null_mut(),
+            len: 0,
+        };
+        let code = unsafe { (self.vtable.handle)(input.as_ptr(), input.len(), &mut out) };
+        if out.ptr.is_null() {
+            return match code {
+                0 => Ok(None),
+                code => Err(Error::new(format!(
+                    "plugin `{}` returned error code {}",
+                    self.name, code
+                ))),
+            };
+        }
+
+        let output = unsafe { std::slice::from_raw_parts(out.ptr, out.len) }.to_vec();
+        unsafe { (self.vtable.free_buffer)(&mut out) };
+        if code != 0 {
+            return Err(Error::new(format!(
+                "plugin `{}` returned error code {}",
+                self.name, code
+            )));
+        }
+        serde_json::from_slice(&output).map(Some).map_err(|e| {
+            Error::new(format!(
+                "plugin `{}` returned an invalid packet: {}",
+                self.name, e
+            ))
+        })
+    }
+}
+
+impl Transform for NativePlugin {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.handle(&packet)
+            .map_err(|e| transform::Error::new(e.to_string()))
+    }
+}
+
+impl fmt::Debug for NativePlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativePlugin")
+            .field("name", &self.name)
+            .field("selectors", &self.selectors)
+            .finish()
+    }
+}
+
+unsafe fn string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+    }
+}
+
+/// An error loading or calling a native plugin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(message: S) -> Self {
+        Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "native plugin: {}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    struct Kinds([KindSpec; 1]);
+    unsafe impl Sync for Kinds {}
+    struct VTable(PluginVTable);
+    unsafe impl Sync for VTable {}
+
+    static KINDS: Kinds = Kinds([KindSpec {
+        domain: b"example.org\0".as_ptr() as *const c_char,
+        scope: std::ptr::null(),
+        kind: b"cpu\0".as_ptr() as *const c_char,
+        version: 1,
+    }]);
+
+    // Tags every packet with a label, and fails on packets without content.
+    unsafe extern "C" fn handle(packet: *const u8, len: usize, out: *mut Buffer) -> i32 {
+        let input = std::slice::from_raw_parts(packet, len);
+        let mut packet: RawPacket = serde_json::from_slice(input).unwrap();
+        if packet.content.is_null() {
+            return 7;
+        }
+        packet
+            .manifest
+            .labels
+            .insert("plugin".into(), "tagger".into());
+        let mut output = serde_json::to_vec(&packet).unwrap().into_boxed_slice();
+        (*out).len = output.len();
+        (*out).ptr = output.as_mut_ptr();
+        std::mem::forget(output);
+        0
+    }
+
+    unsafe extern "C" fn free_buffer(buffer: *mut Buffer) {
+        let buffer = &mut *buffer;
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            buffer.ptr, buffer.len,
+        )));
+    }
+
+    static VTABLE: VTable = VTable(PluginVTable {
+        abi_version: ABI_VERSION,
+        name: b"tagger\0".as_ptr() as *const c_char,
+        kinds: &KINDS.0 as *const KindSpec,
+        kinds_len: 1,
+        handle,
+        free_buffer,
+    });
+
+    #[test]
+    fn handles_declared_kinds() {
+        let plugin = unsafe { NativePlugin::from_vtable(&VTABLE.0) }.unwrap();
+        assert_eq!(plugin.name(), "tagger");
+        assert_eq!(
+            plugin.selectors()[0].to_string(),
+            r#"domain="example.org", kind="cpu", version=1"#
+        );
+        assert!(plugin.handles(&fixtures::cpu_manifest()));
+        assert!(!plugin.handles(&fixtures::netstat_manifest()));
+
+        let packet = plugin.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert_eq!(packet.manifest.labels["plugin"], "tagger");
+
+        let mut empty = fixtures::cpu_raw();
+        empty.content = serde_json::Value::Null;
+        assert!(plugin
+            .handle(&empty)
+            .unwrap_err()
+            .to_string()
+            .contains("error code 7"));
+    }
+
+    #[test]
+    fn missing_library() {
+        assert!(unsafe { NativePlugin::load("/nonexistent/libplugin.so") }.is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Publishing and subscribing to packets over NATS.
+//!
+//! [`Subjects`] maps a packet's coordinates to a NATS subject,
+//! `{domain}.{scope}.{kind}.v{version}` beneath an optional prefix, and a
+//! [`Selector`] to the subject filter receiving what it matches, so that
+//! the server does the routing envelopes would otherwise need:
+//!
+//! ```
+//! use intermodal::nats::Subjects;
+//! # let coordinates = intermodal::Coordinates {
+//! #     domain: "example.org".to_string(),
+//! #     scope: "metrics/host".to_string(),
+//! #     kind: "cpu".to_string(),
+//! #     version: 1,
+//! # };
+//!
+//! let subjects = Subjects::new().prefix("intermodal");
+//! assert_eq!(subjects.subject(&coordinates), "intermodal.example_org.metrics/host.cpu.v1");
+//! assert_eq!(
+//!     subjects.filter(&"domain=example.org, kind=cpu".parse()?),
+//!     "intermodal.example_org.*.cpu.*"
+//! );
+//! # Ok::<(), intermodal::selector::ParseError>(())
+//! ```
+//!
+//! Each part of the coordinates becomes one token: characters other than
+//! ASCII letters, digits, `-`, `_` and `/` are replaced with `_`, so that a
+//! domain's dots do not split it. Distinct coordinates can therefore share
+//! a subject. That costs only bandwidth, since a [`Nats`] subscription
+//! checks each packet's manifest against its selector as well, and drops
+//! those that do not match:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use futures_util::StreamExt;
+//! use intermodal::nats::Nats;
+//! # #[derive(serde::Deserialize)] struct Cpu {}
+//! # let packet: intermodal::RawPacket = unimplemented!();
+//!
+//! let nats = Nats::new(async_nats::connect("nats://127.0.0.1:4222").await?);
+//! nats.publish(&packet).await?;
+//!
+//! let mut cpu = nats.subscribe::<Cpu>("kind=cpu, environment=production".parse()?).await?;
+//! while let Some(packet) = cpu.next().await {
+//!     let packet = packet?;
+//!     // ...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Messages carry the whole envelope, in the [`Format`] the publisher
+//! chose; subscribers read any enabled format.
+//!
+//! Available with the `nats` feature.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::Stream;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{decode, encode, Coordinates, Format, Packet, Selector};
+
+/// Maps coordinates to NATS subjects.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subjects {
+    prefix: Option<String>,
+}
+
+impl Subjects {
+    /// Subjects without a prefix.
+    pub fn new() -> Self {
+        Subjects::default()
+    }
+
+    /// Puts subjects beneath `prefix`, which may itself hold several
+    /// tokens, e.g. `prod.intermodal`.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// The subject packets with the coordinates are published to.
+    pub fn subject(&self, coordinates: &Coordinates) -> String {
+        self.join(
+            &token(&coordinates.domain),
+            &token(&coordinates.scope),
+            &token(&coordinates.kind),
+            &format!("v{}", coordinates.version),
+        )
+    }
+
+    /// The subject filter receiving every packet the selector may match.
+    /// Only a selector's exact domain, scope, kind and version narrow it;
+    /// other conditions are left to the subscriber.
+    pub fn filter(&self, selector: &Selector) -> String {
+        let exact = |value: Option<&str>| value.map_or_else(|| "*".to_string(), token);
+        self.join(
+            &exact(selector.domain()),
+            &exact(selector.scope()),
+            &exact(selector.kind()),
+            &selector
+                .version()
+                .map_or_else(|| "*".to_string(), |version| format!("v{}", version)),
+        )
+    }
+
+    fn join(&self, domain: &str, scope: &str, kind: &str, version: &str) -> String {
+        let subject = format!("{}.{}.{}.{}", domain, scope, kind, version);
+        match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, subject),
+            None => subject,
+        }
+    }
+}
+
+/// A part of the coordinates as one subject token.
+fn token(part: &str) -> String {
+    if part.is_empty() {
+        return "_".to_string();
+    }
+    part.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '/' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// A NATS client publishing and subscribing to packets.
+#[derive(Debug, Clone)]
+pub struct Nats {
+    client: async_nats::Client,
+    subjects: Subjects,
+    format: Format,
+}
+
+impl Nats {
+    /// A client publishing JSON envelopes to unprefixed subjects.
+    pub fn new(client: async_nats::Client) -> Self {
+        Nats {
+            client,
+            subjects: Subjects::new(),
+            format: Format::Json,
+        }
+    }
+
+    pub fn subjects(mut self, subjects: Subjects) -> Self {
+        self.subjects = subjects;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn client(&self) -> &async_nats::Client {
+        &self.client
+    }
+
+    /// Publishes a packet to the subject of its coordinates.
+    pub async fn publish<T: Serialize>(&self, packet: &Packet<T>) -> Result<(), Error> {
+        let subject = self.subjects.subject(&packet.manifest.coordinates());
+        let bytes = packet.to_bytes(self.format).map_err(Error::Encode)?;
+        self.client
+            .publish(subject, bytes.into())
+            .await
+            .map_err(Error::Publish)
+    }
+
+    /// Subscribes to the packets matching the selector, decoding their
+    /// content as `T`.
+    pub async fn subscribe<T: DeserializeOwned>(
+        &self,
+        selector: Selector,
+    ) -> Result<Subscription<T>, Error> {
+        let subscriber = self
+            .client
+            .subscribe(self.subjects.filter(&selector))
+            .await
+            .map_err(Error::Subscribe)?;
+        Ok(Subscription {
+            subscriber,
+            selector,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// The packets matching a selector, from [`Nats::subscribe`].
+///
+/// A message that does not decode is yielded as an error, and the
+/// subscription carries on.
+#[derive(Debug)]
+pub struct Subscription<T> {
+    subscriber: async_nats::Subscriber,
+    selector: Selector,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Subscription<T> {
+    pub fn selector(&self) -> &Selector {
+        &self.selector
+    }
+
+    /// Unsubscribes, ending the stream once messages already received are
+    /// read.
+    pub async fn unsubscribe(&mut self) -> Result<(), Error> {
+        self.subscriber
+            .unsubscribe()
+            .await
+            .map_err(|e| Error::Unsubscribe(Box::new(e)))
+    }
+}
+
+impl<T: DeserializeOwned> Stream for Subscription<T> {
+    type Item = Result<Packet<T>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match Pin::new(&mut self.subscriber).poll_next(cx) {
+                Poll::Ready(Some(message)) => message,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match Packet::<T>::from_bytes_auto(&message.payload) {
+                Ok((packet, _)) if self.selector.matches(&packet.manifest) => {
+                    return Poll::Ready(Some(Ok(packet)))
+                }
+                Ok(_) => continue,
+                Err(e) => return Poll::Ready(Some(Err(Error::Decode(e)))),
+            }
+        }
+    }
+}
+
+/// An error publishing or subscribing to packets.
+#[derive(Debug)]
+pub enum Error {
+    Encode(encode::Error),
+    Decode(decode::Error),
+    Publish(async_nats::PublishError),
+    Subscribe(async_nats::SubscribeError),
+    Unsubscribe(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encode(e) => e.fmt(f),
+            Error::Decode(e) => e.fmt(f),
+            Error::Publish(e) => write!(f, "cannot publish: {}", e),
+            Error::Subscribe(e) => write!(f, "cannot subscribe: {}", e),
+            Error::Unsubscribe(e) => write!(f, "cannot unsubscribe: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Encode(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Publish(e) => Some(e),
+            Error::Subscribe(e) => Some(e),
+            Error::Unsubscribe(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn maps_coordinates_to_subjects() {
+        let subjects = Subjects::new();
+        let cpu = fixtures::cpu_manifest().coordinates();
+        assert_eq!(subjects.subject(&cpu), "example_org.metrics/host.cpu.v1");
+        let odd = Coordinates {
+            domain: "ex ample.org".to_string(),
+            scope: "a>b".to_string(),
+            kind: "*".to_string(),
+            version: 2,
+        };
+        assert_eq!(
+            subjects.prefix("prod.im").subject(&odd),
+            "prod.im.ex_ample_org.a_b._.v2"
+        );
+    }
+
+    #[test]
+    fn narrows_filters_by_selector() {
+        let subjects = Subjects::new();
+        let filter = |selector: &str| subjects.filter(&selector.parse().unwrap());
+        assert_eq!(filter("*"), "*.*.*.*");
+        assert_eq!(
+            filter("scope=metrics/host, kind=cpu, version=1, environment=production"),
+            "*.metrics/host.cpu.v1"
+        );
+        assert_eq!(filter("kind=c*, version>=2"), "*.*.*.*");
+        let cpu = fixtures::cpu_manifest().coordinates();
+        assert_eq!(
+            filter("domain=example.org, scope=metrics/host, kind=cpu, version=1"),
+            subjects.subject(&cpu)
+        );
+    }
+}
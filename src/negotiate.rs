@@ -0,0 +1,307 @@
+//! Negotiating what two ends of a connection both support.
+//!
+//! During a rolling upgrade, a fleet runs builds with different formats,
+//! compression codecs and framing versions enabled. Before sending packets,
+//! the two ends of a connection exchange their [`Capabilities`], as packets
+//! of their own [kind](Capabilities::coordinates), and settle on an
+//! [`Agreement`] each of them can honour:
+//!
+//! ```
+//! use intermodal::negotiate::{self, Capabilities};
+//! use intermodal::Format;
+//!
+//! let client = Capabilities::local();
+//! let server = Capabilities::local()
+//!     .formats(vec![Format::Json])
+//!     .max_frame_size(1 << 20);
+//!
+//! let agreement = negotiate::agree(&client, &server)?;
+//! assert_eq!(agreement.format, Format::Json);
+//! assert_eq!(agreement.max_frame_size, 1 << 20);
+//! # Ok::<(), negotiate::Error>(())
+//! ```
+//!
+//! The agreement takes the first format and codec in the client's order of
+//! preference that the server supports too, the highest framing version
+//! both read, and the smaller of their frame size limits, so that client
+//! and server reach the same one. Names of formats and codecs a build does
+//! not know are kept, and never chosen, so that a newer peer's capabilities
+//! read in an older build.
+//!
+//! Over a byte stream, such as a TCP connection, [`initiate`] and
+//! [`accept`] make the exchange as version 1 [frames](crate::framing) of
+//! JSON, which every build reads: the client sends its capabilities, and
+//! the server answers with its own. Transports with messages of their own
+//! exchange capabilities packets, made with
+//! [`Packet::from_content`](crate::Packet::from_content), in them and call
+//! [`agree`] with what they receive.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Codec;
+use crate::framing::{self, FramedReader, FramedWriter};
+use crate::{ContentType, Coordinates, Format, Packet};
+
+/// The largest frame a connection carries unless given otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+/// What one end of a connection supports. Lists are in order of
+/// preference.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// The short names of formats, e.g. `json`.
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// The names of compression codecs, e.g. `zstd`.
+    #[serde(default)]
+    pub compression: Vec<String>,
+    /// The largest frame, in bytes, this end accepts.
+    pub max_frame_size: u64,
+    #[serde(default)]
+    pub framing_versions: Vec<u8>,
+}
+
+impl ContentType for Capabilities {
+    const DOMAIN: &'static str = "intermodal";
+    const SCOPE: &'static str = "control";
+    const KIND: &'static str = "capabilities";
+    const VERSION: u32 = 1;
+}
+
+impl Capabilities {
+    /// What this build supports: the formats and codecs its features
+    /// enable, both framing versions, newest first, and frames up to
+    /// [`DEFAULT_MAX_FRAME_SIZE`].
+    pub fn local() -> Self {
+        let mut compression = Vec::new();
+        if cfg!(feature = "zstd") {
+            compression.push(Codec::Zstd.name().to_string());
+        }
+        if cfg!(feature = "gzip") {
+            compression.push(Codec::Gzip.name().to_string());
+        }
+        Capabilities {
+            formats: Format::all().iter().map(|f| f.name().to_string()).collect(),
+            compression,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            framing_versions: vec![framing::CHECKSUMMED_VERSION, framing::VERSION],
+        }
+    }
+
+    pub fn formats(mut self, formats: Vec<Format>) -> Self {
+        self.formats = formats.iter().map(|f| f.name().to_string()).collect();
+        self
+    }
+
+    pub fn compression(mut self, codecs: Vec<Codec>) -> Self {
+        self.compression = codecs.iter().map(|c| c.name().to_string()).collect();
+        self
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: u64) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn framing_versions(mut self, versions: Vec<u8>) -> Self {
+        self.framing_versions = versions;
+        self
+    }
+}
+
+/// What two ends of a connection settled on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Agreement {
+    pub format: Format,
+    /// The codec to compress content with, if both support one.
+    pub compression: Option<Codec>,
+    pub max_frame_size: u64,
+    pub framing_version: u8,
+}
+
+/// The agreement between a client and the server it connects to.
+pub fn agree(client: &Capabilities, server: &Capabilities) -> Result<Agreement, Error> {
+    let format = client
+        .formats
+        .iter()
+        .filter(|name| server.formats.contains(name))
+        .find_map(|name| name.parse::<Format>().ok())
+        .ok_or(Error::NoCommonFormat)?;
+    let compression = client
+        .compression
+        .iter()
+        .filter(|name| server.compression.contains(name))
+        .find_map(|name| name.parse::<Codec>().ok());
+    let framing_version = client
+        .framing_versions
+        .iter()
+        .copied()
+        .filter(|version| server.framing_versions.contains(version))
+        .filter(|version| (framing::VERSION..=framing::CHECKSUMMED_VERSION).contains(version))
+        .max()
+        .ok_or(Error::NoCommonFramingVersion)?;
+    Ok(Agreement {
+        format,
+        compression,
+        max_frame_size: client.max_frame_size.min(server.max_frame_size),
+        framing_version,
+    })
+}
+
+/// Makes the client's side of the exchange over `stream`: sends the local
+/// capabilities, then reads the server's.
+pub fn initiate<S: Read + Write>(stream: &mut S, local: &Capabilities) -> Result<Agreement, Error> {
+    send(stream, local)?;
+    let server = receive(stream)?;
+    agree(local, &server)
+}
+
+/// Makes the server's side of the exchange over `stream`: reads the
+/// client's capabilities, then answers with the local ones.
+pub fn accept<S: Read + Write>(stream: &mut S, local: &Capabilities) -> Result<Agreement, Error> {
+    let client = receive(stream)?;
+    send(stream, local)?;
+    agree(&client, local)
+}
+
+fn send<W: Write>(stream: &mut W, local: &Capabilities) -> Result<(), Error> {
+    let mut writer = FramedWriter::new(stream);
+    writer.write_packet(&Packet::from_content(local.clone()), Format::Json)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn receive<R: Read>(stream: &mut R) -> Result<Capabilities, Error> {
+    let frame = FramedReader::new(stream)
+        .read_frame()?
+        .ok_or(Error::Closed)?;
+    let coordinates = frame.manifest.coordinates();
+    if coordinates != Capabilities::coordinates() {
+        return Err(Error::Unexpected(coordinates));
+    }
+    Ok(frame.decode::<Capabilities>()?.content)
+}
+
+/// An error negotiating a connection.
+#[derive(Debug)]
+pub enum Error {
+    NoCommonFormat,
+    NoCommonFramingVersion,
+    /// The peer sent a packet of another kind than capabilities.
+    Unexpected(Coordinates),
+    /// The peer closed the connection before sending its capabilities.
+    Closed,
+    Framing(framing::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoCommonFormat => f.write_str("peers support no format in common"),
+            Error::NoCommonFramingVersion => {
+                f.write_str("peers support no framing version in common")
+            }
+            Error::Unexpected(coordinates) => {
+                write!(f, "expected capabilities, received {}", coordinates)
+            }
+            Error::Closed => f.write_str("peer closed the connection during negotiation"),
+            Error::Framing(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Framing(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<framing::Error> for Error {
+    fn from(e: framing::Error) -> Self {
+        Error::Framing(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn agrees_on_common_ground() {
+        let client = Capabilities {
+            formats: vec!["flatbuffers".to_string(), "json".to_string()],
+            compression: vec!["brotli".to_string(), "gzip".to_string()],
+            max_frame_size: 1024,
+            framing_versions: vec![3, 2, 1],
+        };
+        let server = Capabilities {
+            formats: vec!["json".to_string(), "flatbuffers".to_string()],
+            compression: vec!["gzip".to_string(), "brotli".to_string()],
+            max_frame_size: 4096,
+            framing_versions: vec![1, 3],
+        };
+        assert_eq!(
+            agree(&client, &server).unwrap(),
+            Agreement {
+                format: Format::Json,
+                compression: Some(Codec::Gzip),
+                max_frame_size: 1024,
+                framing_version: 1,
+            }
+        );
+
+        let old = server.clone().framing_versions(vec![3]);
+        assert!(matches!(
+            agree(&client, &old),
+            Err(Error::NoCommonFramingVersion)
+        ));
+        let old = Capabilities {
+            formats: vec!["flatbuffers".to_string()],
+            ..server
+        };
+        assert!(matches!(agree(&client, &old), Err(Error::NoCommonFormat)));
+
+        let newer: Capabilities = serde_json::from_str(
+            r#"{"formats":["json"],"max_frame_size":10,"framing_versions":[3,1],"transports":["quic"]}"#,
+        )
+        .unwrap();
+        let agreement = agree(&Capabilities::local(), &newer).unwrap();
+        assert_eq!(agreement.compression, None);
+        assert_eq!(agreement.framing_version, framing::VERSION);
+    }
+
+    #[test]
+    fn shakes_hands_over_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let local = Capabilities::local().max_frame_size(512);
+            accept(&mut stream, &local).unwrap()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let client = initiate(&mut stream, &Capabilities::local()).unwrap();
+        assert_eq!(client, server.join().unwrap());
+        assert_eq!(client.max_frame_size, 512);
+        assert_eq!(client.framing_version, framing::CHECKSUMMED_VERSION);
+
+        let mut other = Vec::new();
+        FramedWriter::new(&mut other)
+            .write_packet(&crate::fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        assert!(matches!(
+            receive(&mut Cursor::new(other)),
+            Err(Error::Unexpected(_))
+        ));
+        assert!(matches!(receive(&mut io::empty()), Err(Error::Closed)));
+    }
+}
@@ -0,0 +1,318 @@
+use std::cmp::Ordering;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::provenance::Hop;
+use crate::signing::Signature;
+use crate::{Header, Manifest};
+
+/// An envelope: a [`Manifest`] describing some content, and the content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Packet<T> {
+    pub manifest: Manifest,
+    pub content: T,
+    /// A detached signature over the manifest and content; see
+    /// [`signing`](crate::signing).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+    /// The services the packet has passed through, oldest first; see
+    /// [`provenance`](crate::provenance).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provenance: Vec<Hop>,
+}
+
+/// A packet whose content has not been decoded into a concrete type.
+pub type RawPacket = Packet<serde_json::Value>;
+
+impl<T> Packet<T> {
+    /// A packet of `content` under `manifest`, unsigned and with no provenance.
+    pub fn new(manifest: Manifest, content: T) -> Self {
+        Packet {
+            manifest,
+            content,
+            signature: None,
+            provenance: Vec::new(),
+        }
+    }
+
+    /// Assembles a packet from a previously decoded header and its content.
+    /// The manifest is kept as it is, `ctime` included; to emit new content
+    /// derived from the header's, build its packet with
+    /// [`PacketBuilder::inherit`](crate::PacketBuilder::inherit) instead.
+    pub fn from_obj(header: Header, content: T) -> Self {
+        Packet::new(header.manifest, content)
+    }
+
+    /// Splits the packet into its manifest and content.
+    pub fn into_parts(self) -> (Manifest, T) {
+        (self.manifest, self.content)
+    }
+
+    /// Transforms the content, keeping the manifest and provenance. The
+    /// signature, which covered the old content, is dropped.
+    pub fn map<U, F>(self, f: F) -> Packet<U>
+    where
+        F: FnOnce(T) -> U,
+    {
+        Packet {
+            manifest: self.manifest,
+            content: f(self.content),
+            signature: None,
+            provenance: self.provenance,
+        }
+    }
+
+    /// Transforms the content with a fallible function, keeping the manifest
+    /// and provenance. The signature is dropped, as by [`map`](Packet::map).
+    pub fn try_map<U, E, F>(self, f: F) -> Result<Packet<U>, E>
+    where
+        F: FnOnce(T) -> Result<U, E>,
+    {
+        Ok(Packet {
+            manifest: self.manifest,
+            content: f(self.content)?,
+            signature: None,
+            provenance: self.provenance,
+        })
+    }
+
+    /// Returns a header carrying a copy of this packet's manifest.
+    pub fn header(&self) -> Header {
+        Header::from_ref(&self.manifest)
+    }
+
+    /// Builds a response to this packet, a request, carrying its
+    /// correlation id back.
+    ///
+    /// The response's manifest is a copy of the request's, created now,
+    /// without labels or a `reply_to` of its own; it stays in the request's
+    /// trace. Set its kind and origin as the response needs:
+    ///
+    /// ```
+    /// # let mut request: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+    /// #     "manifest": { "domain": "example.org", "scope": "inventory",
+    /// #                   "kind": "lookup", "version": 1,
+    /// #                   "ctime": "2020-06-01T12:00:00Z", "origin": "web01" },
+    /// #     "content": { "sku": "A-1001" }
+    /// # })).unwrap();
+    /// request.manifest.correlation_id = Some("7c1e".to_string());
+    /// request.manifest.reply_to = Some("inventory.replies.web01".to_string());
+    ///
+    /// let mut response = request.reply_with(serde_json::json!({ "in_stock": 4 }));
+    /// response.manifest.kind = "lookup_result".to_string();
+    /// response.manifest.origin = "inventory01".to_string();
+    /// assert_eq!(response.manifest.correlation_id.as_deref(), Some("7c1e"));
+    /// assert_eq!(response.manifest.reply_to, None);
+    /// ```
+    pub fn reply_with<U>(&self, content: U) -> Packet<U> {
+        let mut manifest = self.manifest.clone();
+        manifest.ctime = Utc::now();
+        manifest.labels.clear();
+        manifest.reply_to = None;
+        Packet::new(manifest, content)
+    }
+}
+
+impl RawPacket {
+    /// Looks up a value within the content by JSON pointer, such as
+    /// `/connections/0/state`, and decodes just that value.
+    ///
+    /// Returns `Ok(None)` when nothing is at the pointer. Strings may be
+    /// borrowed from the content:
+    ///
+    /// ```
+    /// # let packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+    /// #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+    /// #                   "kind": "netstat", "version": 1,
+    /// #                   "ctime": "2020-06-01T12:00:05Z", "origin": "host01" },
+    /// #     "content": { "connections": [ { "state": "ESTABLISHED" } ] }
+    /// # })).unwrap();
+    /// let state: Option<&str> = packet.content_pointer("/connections/0/state").unwrap();
+    /// assert_eq!(state, Some("ESTABLISHED"));
+    /// ```
+    pub fn content_pointer<'a, T: Deserialize<'a>>(
+        &'a self,
+        pointer: &str,
+    ) -> Result<Option<T>, serde_json::Error> {
+        match self.content.pointer(pointer) {
+            Some(value) => T::deserialize(value).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T> AsRef<Manifest> for Packet<T> {
+    fn as_ref(&self) -> &Manifest {
+        &self.manifest
+    }
+}
+
+/// Orders envelopes by the `ctime` of their manifests.
+///
+/// Two wrapped envelopes compare equal when their `ctime`s are equal,
+/// whatever else they hold. `BinaryHeap<ByCtime<_>>` pops the newest
+/// envelope first; wrap in [`std::cmp::Reverse`] to pop the oldest.
+#[derive(Debug, Clone, Copy)]
+pub struct ByCtime<P>(pub P);
+
+impl<P> ByCtime<P> {
+    pub fn into_inner(self) -> P {
+        self.0
+    }
+}
+
+impl<P: AsRef<Manifest>> PartialEq for ByCtime<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_ref().ctime == other.0.as_ref().ctime
+    }
+}
+
+impl<P: AsRef<Manifest>> Eq for ByCtime<P> {}
+
+impl<P: AsRef<Manifest>> PartialOrd for ByCtime<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P: AsRef<Manifest>> Ord for ByCtime<P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.as_ref().ctime.cmp(&other.0.as_ref().ctime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu, Netstat};
+
+    #[test]
+    fn replies_carry_correlation_ids() {
+        let mut request = fixtures::cpu_raw();
+        let json = serde_json::to_value(&request.manifest).unwrap();
+        assert!(json.get("correlation_id").is_none());
+        assert!(json.get("reply_to").is_none());
+
+        request.manifest.correlation_id = Some("req-42".to_string());
+        request.manifest.reply_to = Some("replies.host01".to_string());
+        let json = serde_json::to_string(&request).unwrap();
+        assert!(json.contains(r#""correlation_id":"req-42","reply_to":"replies.host01""#));
+        let decoded: RawPacket = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, request);
+
+        let response = request.reply_with(1u8);
+        assert_eq!(response.manifest.correlation_id.as_deref(), Some("req-42"));
+        assert_eq!(response.manifest.reply_to, None);
+        assert!(response.manifest.labels.is_empty());
+        assert!(response.manifest.ctime > request.manifest.ctime);
+        assert_eq!(response.manifest.kind, "cpu");
+    }
+
+    #[test]
+    fn header_ignores_content() {
+        let header: Header = serde_json::from_str(fixtures::CPU_JSON).unwrap();
+        assert_eq!(header.manifest.domain, "example.org");
+        assert_eq!(header.manifest.scope, "metrics/host");
+        assert_eq!(header.manifest.kind, "cpu");
+        assert_eq!(header.manifest.version, 1);
+        assert_eq!(header.manifest.labels["environment"], "production");
+    }
+
+    #[test]
+    fn typed_content() {
+        let packet: Packet<Cpu> = serde_json::from_str(fixtures::CPU_JSON).unwrap();
+        assert_eq!(packet.content.idle, 83.25);
+
+        let packet: Packet<Netstat> = serde_json::from_str(fixtures::NETSTAT_JSON).unwrap();
+        assert_eq!(packet.content.connections.len(), 2);
+        assert_eq!(packet.content.connections[1].state, "TIME_WAIT");
+    }
+
+    #[test]
+    fn map_keeps_manifest() {
+        let packet: Packet<Cpu> = serde_json::from_str(fixtures::CPU_JSON).unwrap();
+        let ctime = packet.manifest.ctime;
+
+        let busy = packet.clone().map(|cpu| 100.0 - cpu.idle);
+        assert_eq!(busy.content, 16.75);
+        assert_eq!(busy.manifest.ctime, ctime);
+
+        let raw = packet.clone().try_map(serde_json::to_value).unwrap();
+        assert_eq!(raw.content["user"], 12.5);
+
+        let err = packet.try_map(|_| Err::<(), _>("rejected")).unwrap_err();
+        assert_eq!(err, "rejected");
+    }
+
+    #[test]
+    fn dedupe_and_order() {
+        use std::collections::{BinaryHeap, HashSet};
+
+        let cpu = fixtures::cpu_raw();
+        let netstat = fixtures::netstat_raw();
+        let mut relabeled = cpu.clone();
+        relabeled
+            .manifest
+            .labels
+            .insert("replayed".into(), "true".into());
+
+        let headers: HashSet<Header> = vec![
+            cpu.header(),
+            netstat.header(),
+            cpu.header(),
+            relabeled.header(),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(cpu, fixtures::cpu_raw());
+        assert_ne!(cpu, relabeled);
+
+        let mut heap = BinaryHeap::new();
+        heap.push(std::cmp::Reverse(ByCtime(netstat)));
+        heap.push(std::cmp::Reverse(ByCtime(cpu)));
+        assert_eq!(heap.pop().unwrap().0.into_inner().manifest.kind, "cpu");
+        assert_eq!(heap.pop().unwrap().0.into_inner().manifest.kind, "netstat");
+    }
+
+    #[test]
+    fn parts() {
+        let (manifest, content) = fixtures::cpu_raw().into_parts();
+        let packet = Packet::new(manifest.clone(), content);
+        assert_eq!(packet, fixtures::cpu_raw());
+        assert_eq!(Header::from(manifest).into_packet(()).manifest.kind, "cpu");
+    }
+
+    #[test]
+    fn content_pointer() {
+        let packet = fixtures::netstat_raw();
+        let state: &str = packet
+            .content_pointer("/connections/1/state")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state, "TIME_WAIT");
+
+        let connection: fixtures::Connection =
+            packet.content_pointer("/connections/0").unwrap().unwrap();
+        assert_eq!(connection.proto, "tcp");
+
+        assert_eq!(
+            packet.content_pointer::<u32>("/connections/9").unwrap(),
+            None
+        );
+        assert!(packet
+            .content_pointer::<u32>("/connections/0/state")
+            .is_err());
+    }
+
+    #[test]
+    fn from_obj_keeps_manifest() {
+        let header: Header = serde_json::from_str(fixtures::CPU_JSON).unwrap();
+        let raw: RawPacket = serde_json::from_str(fixtures::CPU_JSON).unwrap();
+        let packet = Packet::from_obj(header.clone(), raw.content);
+        assert_eq!(packet.manifest.ctime, header.manifest.ctime);
+        assert_eq!(packet.content["user"], 12.5);
+    }
+}
@@ -0,0 +1,713 @@
+//! Differences between the content of packets of the same kind.
+//!
+//! [`diff_content`] compares the content of two packets and describes how
+//! to get from the first to the second as a [`Patch`], a list of operations
+//! in the form of RFC 6902 JSON Patch. A consumer watching for changes can
+//! send the patch, usually far smaller than the content, in place of a full
+//! snapshot, and the receiver [`apply`] it to the snapshot it already has:
+//!
+//! ```
+//! use intermodal::patch::{self, Operation};
+//! use intermodal::RawPacket;
+//! use serde_json::json;
+//! # let envelope = |content| -> RawPacket { serde_json::from_value(json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "cpu", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": content
+//! # })).unwrap() };
+//!
+//! let mut before = envelope(json!({ "user": 12.5, "idle": 83.25 }));
+//! let after = envelope(json!({ "user": 14.0, "idle": 83.25 }));
+//!
+//! let delta = patch::diff_content(&before, &after)?;
+//! assert_eq!(
+//!     delta.operations,
+//!     vec![Operation::Replace { path: "/user".to_string(), value: json!(14.0) }]
+//! );
+//!
+//! patch::apply(&mut before, &delta)?;
+//! assert_eq!(before.content, after.content);
+//! # Ok::<(), patch::Error>(())
+//! ```
+//!
+//! Objects are compared key by key, and arrays element by element, with
+//! elements added or removed at the end; a value whose type changed is
+//! replaced whole. Patches serialize as JSON Patch documents, so they can be
+//! carried as the content of delta packets.
+//!
+//! Patches written by hand may use all of JSON Patch's operations, `move`,
+//! `copy` and `test` included; [`merge`] applies RFC 7386 merge patches. A
+//! [`Correction`] patches the content and labels of the archived packets it
+//! selects, with either kind of [`Document`], and with the `jsonschema`
+//! feature can check the corrected content against its kind's schema.
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[cfg(feature = "jsonschema")]
+use crate::schema::{self, SchemaRegistry};
+use crate::transform::{self, Transform};
+use crate::{Manifest, Packet, RawPacket, Selector};
+
+/// One step of a [`Patch`], addressing a value by JSON pointer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    /// Adds a member to an object, or inserts an element into an array; a
+    /// path ending in `-` appends to an array.
+    Add {
+        path: String,
+        value: Value,
+    },
+    Remove {
+        path: String,
+    },
+    Replace {
+        path: String,
+        value: Value,
+    },
+    /// Removes the value at `from` and adds it at `path`.
+    Move {
+        from: String,
+        path: String,
+    },
+    /// Adds a copy of the value at `from` at `path`.
+    Copy {
+        from: String,
+        path: String,
+    },
+    /// Fails the patch unless the value at `path` equals `value`.
+    Test {
+        path: String,
+        value: Value,
+    },
+}
+
+impl Operation {
+    /// The JSON pointer the operation applies at.
+    pub fn path(&self) -> &str {
+        match self {
+            Operation::Add { path, .. }
+            | Operation::Remove { path }
+            | Operation::Replace { path, .. }
+            | Operation::Move { path, .. }
+            | Operation::Copy { path, .. }
+            | Operation::Test { path, .. } => path,
+        }
+    }
+}
+
+/// A list of operations transforming one value into another.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Patch {
+    pub operations: Vec<Operation>,
+}
+
+impl Patch {
+    /// Whether the patch changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Applies the operations to `target` in order. If one fails, `target`
+    /// is left as it was.
+    pub fn apply_to(&self, target: &mut Value) -> Result<(), Error> {
+        let mut patched = target.clone();
+        for operation in &self.operations {
+            apply_operation(&mut patched, operation)?;
+        }
+        *target = patched;
+        Ok(())
+    }
+}
+
+/// Describes how the content of `old` changed into that of `new`.
+///
+/// Fails if the packets are of different kinds, as their content then has
+/// no meaningful difference.
+pub fn diff_content<T: Serialize>(old: &Packet<T>, new: &Packet<T>) -> Result<Patch, Error> {
+    let (from, to) = (old.manifest.coordinates(), new.manifest.coordinates());
+    if from != to {
+        return Err(Error::Kind(from.to_string(), to.to_string()));
+    }
+    let old = serde_json::to_value(&old.content).map_err(Error::Content)?;
+    let new = serde_json::to_value(&new.content).map_err(Error::Content)?;
+    Ok(diff(&old, &new))
+}
+
+/// Describes how `old` changed into `new`.
+pub fn diff(old: &Value, new: &Value) -> Patch {
+    let mut operations = Vec::new();
+    diff_at(&mut String::new(), old, new, &mut operations);
+    Patch { operations }
+}
+
+/// Applies the patch to the packet's content. If it fails, the packet is
+/// left as it was.
+pub fn apply<T: Serialize + DeserializeOwned>(
+    packet: &mut Packet<T>,
+    patch: &Patch,
+) -> Result<(), Error> {
+    let mut content = serde_json::to_value(&packet.content).map_err(Error::Content)?;
+    patch.apply_to(&mut content)?;
+    packet.content = serde_json::from_value(content).map_err(Error::Content)?;
+    Ok(())
+}
+
+fn diff_at(path: &mut String, old: &Value, new: &Value, operations: &mut Vec<Operation>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let len = push(path, key);
+                match new.get(key) {
+                    Some(new_value) => diff_at(path, old_value, new_value, operations),
+                    None => operations.push(Operation::Remove { path: path.clone() }),
+                }
+                path.truncate(len);
+            }
+            for (key, value) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+                let len = push(path, key);
+                operations.push(Operation::Add {
+                    path: path.clone(),
+                    value: value.clone(),
+                });
+                path.truncate(len);
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for (i, (old_value, new_value)) in old.iter().zip(new).enumerate() {
+                let len = push(path, &i.to_string());
+                diff_at(path, old_value, new_value, operations);
+                path.truncate(len);
+            }
+            // Removing from the end first keeps the indices of the elements
+            // still to remove in place.
+            for i in (new.len()..old.len()).rev() {
+                let len = push(path, &i.to_string());
+                operations.push(Operation::Remove { path: path.clone() });
+                path.truncate(len);
+            }
+            for (i, value) in new.iter().enumerate().skip(old.len()) {
+                let len = push(path, &i.to_string());
+                operations.push(Operation::Add {
+                    path: path.clone(),
+                    value: value.clone(),
+                });
+                path.truncate(len);
+            }
+        }
+        _ if old == new => {}
+        _ => operations.push(Operation::Replace {
+            path: path.clone(),
+            value: new.clone(),
+        }),
+    }
+}
+
+/// Appends a token to a JSON pointer, returning the pointer's length
+/// before it.
+fn push(path: &mut String, token: &str) -> usize {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&token.replace('~', "~0").replace('/', "~1"));
+    len
+}
+
+fn apply_operation(target: &mut Value, operation: &Operation) -> Result<(), Error> {
+    match operation {
+        Operation::Add { path, value } => add(target, path, value.clone()),
+        Operation::Remove { path } => remove(target, path).map(drop),
+        Operation::Replace { path, value } => {
+            *target.pointer_mut(path).ok_or_else(|| missing(path))? = value.clone();
+            Ok(())
+        }
+        Operation::Move { from, path } => {
+            if path.starts_with(from.as_str()) && path[from.len()..].starts_with('/') {
+                return Err(Error::Path(path.clone()));
+            }
+            let value = remove(target, from)?;
+            add(target, path, value)
+        }
+        Operation::Copy { from, path } => {
+            let value = target.pointer(from).ok_or_else(|| missing(from))?.clone();
+            add(target, path, value)
+        }
+        Operation::Test { path, value } => match target.pointer(path) {
+            Some(actual) if actual == value => Ok(()),
+            _ => Err(Error::Test(path.clone())),
+        },
+    }
+}
+
+fn missing(path: &str) -> Error {
+    Error::Path(path.to_string())
+}
+
+/// Splits a pointer into that of the parent and the unescaped last token.
+fn split(path: &str) -> Option<(&str, String)> {
+    let i = path.rfind('/')?;
+    Some((&path[..i], unescape(&path[i + 1..])))
+}
+
+fn add(target: &mut Value, path: &str, value: Value) -> Result<(), Error> {
+    let (parent, last) = match split(path) {
+        Some(split) => split,
+        None if path.is_empty() => {
+            *target = value;
+            return Ok(());
+        }
+        None => return Err(missing(path)),
+    };
+    match target.pointer_mut(parent).ok_or_else(|| missing(path))? {
+        Value::Object(members) => {
+            members.insert(last, value);
+        }
+        Value::Array(elements) => {
+            let index = match last.as_str() {
+                "-" => elements.len(),
+                token => index(token)
+                    .filter(|&i| i <= elements.len())
+                    .ok_or_else(|| missing(path))?,
+            };
+            elements.insert(index, value);
+        }
+        _ => return Err(missing(path)),
+    }
+    Ok(())
+}
+
+pub(crate) fn remove(target: &mut Value, path: &str) -> Result<Value, Error> {
+    let (parent, last) = split(path).ok_or_else(|| missing(path))?;
+    let removed = match target.pointer_mut(parent) {
+        Some(Value::Object(members)) => members.remove(&last),
+        Some(Value::Array(elements)) => index(&last)
+            .filter(|&i| i < elements.len())
+            .map(|i| elements.remove(i)),
+        _ => None,
+    };
+    removed.ok_or_else(|| missing(path))
+}
+
+/// The array index a pointer token stands for. Leading zeros are not array
+/// indices in JSON pointers.
+fn index(token: &str) -> Option<usize> {
+    if token.len() > 1 && token.starts_with('0') {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+/// Applies an RFC 7386 JSON merge patch to `target`: members of an object
+/// patch are merged into the target recursively, `null` members removing
+/// theirs, and a patch of any other kind replaces the target.
+pub fn merge(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let members = match target {
+        Value::Object(members) => members,
+        _ => unreachable!(),
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            members.remove(key);
+        } else {
+            merge(members.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// A patch document of either kind: a JSON Patch, which is an array of
+/// operations, or a merge patch, which is anything else.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Document {
+    Patch(Patch),
+    Merge(Value),
+}
+
+impl Document {
+    /// Applies the document to `target`. If it fails, `target` is left as
+    /// it was.
+    pub fn apply_to(&self, target: &mut Value) -> Result<(), Error> {
+        match self {
+            Document::Patch(patch) => patch.apply_to(target),
+            Document::Merge(patch) => {
+                merge(target, patch);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A correction to archived packets: patches to the content and labels of
+/// those a selector matches.
+///
+/// As a [`Transform`], a correction can run as a [`Rewriter`] stage over a
+/// whole archive, leaving the packets it does not select as they are. In
+/// YAML:
+///
+/// ```yaml
+/// select: kind=cpu, origin=host01.example.org
+/// content:
+///   - { op: test, path: /idle, value: 183.25 }
+///   - { op: replace, path: /idle, value: 83.25 }
+/// labels:
+///   datacenter: us-west
+///   corrected: "true"
+/// ```
+///
+/// Labels are patched as an object of strings.
+///
+/// [`Rewriter`]: crate::rewrite::Rewriter
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Correction {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<Selector>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Document>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Document>,
+}
+
+impl Correction {
+    /// Whether the correction applies to packets with the manifest.
+    pub fn selects(&self, manifest: &Manifest) -> bool {
+        self.select
+            .as_ref()
+            .is_none_or(|selector| selector.matches(manifest))
+    }
+
+    /// Patches the packet's content and labels, whether or not it is
+    /// selected. If either fails, the packet is left as it was.
+    pub fn apply_to(&self, packet: &mut RawPacket) -> Result<(), Error> {
+        let mut content = packet.content.clone();
+        if let Some(document) = &self.content {
+            document.apply_to(&mut content)?;
+        }
+        if let Some(document) = &self.labels {
+            let mut labels =
+                serde_json::to_value(&packet.manifest.labels).map_err(Error::Content)?;
+            document.apply_to(&mut labels)?;
+            packet.manifest.labels = serde_json::from_value(labels).map_err(Error::Labels)?;
+        }
+        packet.content = content;
+        Ok(())
+    }
+
+    /// Like [`apply_to`](Correction::apply_to), then checks the corrected
+    /// content against the schema registered for the packet's kind, leaving
+    /// the packet as it was if it does not match. Available with the
+    /// `jsonschema` feature.
+    #[cfg(feature = "jsonschema")]
+    pub fn apply_validated<R: SchemaRegistry + ?Sized>(
+        &self,
+        packet: &mut RawPacket,
+        registry: &R,
+    ) -> Result<(), Error> {
+        let mut corrected = packet.clone();
+        self.apply_to(&mut corrected)?;
+        corrected
+            .validate_against(registry)
+            .map_err(Error::Schema)?;
+        *packet = corrected;
+        Ok(())
+    }
+}
+
+/// Corrects the packets the correction selects.
+impl Transform for Correction {
+    fn apply(&self, mut packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        if self.selects(&packet.manifest) {
+            self.apply_to(&mut packet)
+                .map_err(|e| transform::Error::new(e.to_string()))?;
+        }
+        Ok(Some(packet))
+    }
+}
+
+/// An error diffing or patching content.
+#[derive(Debug)]
+pub enum Error {
+    /// The packets compared are of different kinds, with the given
+    /// coordinates.
+    Kind(String, String),
+    /// The content could not be converted to or from JSON.
+    Content(serde_json::Error),
+    /// An operation's path does not address a value it can apply to.
+    Path(String),
+    /// The value at the path of a test operation differs from the one
+    /// expected.
+    Test(String),
+    /// Patched labels are not an object of strings.
+    Labels(serde_json::Error),
+    /// The corrected content does not match its schema.
+    #[cfg(feature = "jsonschema")]
+    Schema(schema::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Kind(old, new) => {
+                write!(f, "cannot diff content of {} against {}", old, new)
+            }
+            Error::Content(e) => write!(f, "converting content: {}", e),
+            Error::Path(path) => write!(f, "no value to patch at `{}`", path),
+            Error::Test(path) => write!(f, "test failed at `{}`", path),
+            Error::Labels(e) => write!(f, "patched labels are not strings: {}", e),
+            #[cfg(feature = "jsonschema")]
+            Error::Schema(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Content(e) | Error::Labels(e) => Some(e),
+            #[cfg(feature = "jsonschema")]
+            Error::Schema(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{cpu_raw, netstat_raw, Netstat, NETSTAT_JSON};
+    use serde_json::json;
+
+    #[test]
+    fn diffs_and_applies() {
+        let old = json!({
+            "host": "web01",
+            "tags": ["a", "b", "c"],
+            "disks": { "sda": { "used": 10 }, "a/b~c": 1 },
+            "load": [1, 2],
+        });
+        let new = json!({
+            "tags": ["a", "x"],
+            "disks": { "sda": { "used": 12 }, "a/b~c": 1, "sdb": { "used": 0 } },
+            "load": [1, 2, 3, 4],
+            "uptime": 86400,
+        });
+        let patch = diff(&old, &new);
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap(),
+            json!([
+                { "op": "replace", "path": "/disks/sda/used", "value": 12 },
+                { "op": "add", "path": "/disks/sdb", "value": { "used": 0 } },
+                { "op": "remove", "path": "/host" },
+                { "op": "add", "path": "/load/2", "value": 3 },
+                { "op": "add", "path": "/load/3", "value": 4 },
+                { "op": "replace", "path": "/tags/1", "value": "x" },
+                { "op": "remove", "path": "/tags/2" },
+                { "op": "add", "path": "/uptime", "value": 86400 },
+            ])
+        );
+        let mut patched = old.clone();
+        patch.apply_to(&mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        let escaped = diff(&json!({ "a/b~c": 1 }), &json!({ "a/b~c": 2 }));
+        assert_eq!(escaped.operations[0].path(), "/a~1b~0c");
+        assert!(diff(&new, &new).is_empty());
+        assert_eq!(diff(&json!(1), &json!("1")).operations[0].path(), "");
+    }
+
+    #[test]
+    fn patches_packets() {
+        let mut old = netstat_raw();
+        let mut new = netstat_raw();
+        new.manifest.ctime = chrono::Utc::now();
+        new.content["connections"][1]["state"] = json!("CLOSE_WAIT");
+
+        let patch = diff_content(&old, &new).unwrap();
+        assert_eq!(patch.operations.len(), 1);
+        apply(&mut old, &patch).unwrap();
+        assert_eq!(old.content, new.content);
+
+        let mut typed: Packet<Netstat> = serde_json::from_str(NETSTAT_JSON).unwrap();
+        apply(&mut typed, &patch).unwrap();
+        assert_eq!(typed.content.connections[1].state, "CLOSE_WAIT");
+
+        assert!(matches!(
+            diff_content(&cpu_raw(), &netstat_raw()),
+            Err(Error::Kind(..))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_paths() {
+        let mut value = json!({ "list": [1, 2] });
+        for operation in [
+            Operation::Remove {
+                path: "/missing".to_string(),
+            },
+            Operation::Replace {
+                path: "/list/2".to_string(),
+                value: json!(0),
+            },
+            Operation::Add {
+                path: "/list/01".to_string(),
+                value: json!(0),
+            },
+            Operation::Add {
+                path: "/none/x".to_string(),
+                value: json!(0),
+            },
+            Operation::Remove {
+                path: "".to_string(),
+            },
+        ] {
+            let patch = Patch {
+                operations: vec![
+                    Operation::Add {
+                        path: "/new".to_string(),
+                        value: json!(1),
+                    },
+                    operation,
+                ],
+            };
+            assert!(matches!(patch.apply_to(&mut value), Err(Error::Path(_))));
+        }
+        assert_eq!(value, json!({ "list": [1, 2] }));
+
+        let test = Patch {
+            operations: vec![Operation::Test {
+                path: "/list/0".to_string(),
+                value: json!(2),
+            }],
+        };
+        assert!(matches!(test.apply_to(&mut value), Err(Error::Test(_))));
+        let into_itself = Operation::Move {
+            from: "/list".to_string(),
+            path: "/list/0".to_string(),
+        };
+        let patch = Patch {
+            operations: vec![into_itself],
+        };
+        assert!(matches!(patch.apply_to(&mut value), Err(Error::Path(_))));
+
+        let append = Patch {
+            operations: vec![Operation::Add {
+                path: "/list/-".to_string(),
+                value: json!(3),
+            }],
+        };
+        append.apply_to(&mut value).unwrap();
+        assert_eq!(value, json!({ "list": [1, 2, 3] }));
+    }
+
+    #[test]
+    fn applies_rfc_6902_operations() {
+        let patch: Patch = serde_json::from_value(json!([
+            { "op": "test", "path": "/a/b", "value": [1, 2] },
+            { "op": "copy", "from": "/a/b", "path": "/c" },
+            { "op": "move", "from": "/a/b/0", "path": "/a/b/-" },
+            { "op": "move", "from": "/a", "path": "/d" },
+        ]))
+        .unwrap();
+        let mut value = json!({ "a": { "b": [1, 2] } });
+        patch.apply_to(&mut value).unwrap();
+        assert_eq!(value, json!({ "c": [1, 2], "d": { "b": [2, 1] } }));
+    }
+
+    #[test]
+    fn merges() {
+        // The example of RFC 7386, section 3.
+        let mut value = json!({
+            "title": "Goodbye!",
+            "author": { "givenName": "John", "familyName": "Doe" },
+            "tags": ["example", "sample"],
+            "content": "This will be unchanged",
+        });
+        merge(
+            &mut value,
+            &json!({
+                "title": "Hello!",
+                "phoneNumber": "+01-123-456-7890",
+                "author": { "familyName": null },
+                "tags": ["example"],
+            }),
+        );
+        assert_eq!(
+            value,
+            json!({
+                "title": "Hello!",
+                "author": { "givenName": "John" },
+                "tags": ["example"],
+                "content": "This will be unchanged",
+                "phoneNumber": "+01-123-456-7890",
+            })
+        );
+    }
+
+    #[test]
+    fn corrects_selected_packets() {
+        let correction: Correction = serde_json::from_value(json!({
+            "select": "kind=cpu",
+            "content": [{ "op": "replace", "path": "/idle", "value": 80.0 }],
+            "labels": { "datacenter": null, "corrected": "true" },
+        }))
+        .unwrap();
+        let corrected = correction.apply(cpu_raw()).unwrap().unwrap();
+        assert_eq!(corrected.content["idle"], 80.0);
+        assert_eq!(corrected.manifest.labels.get("datacenter"), None);
+        assert_eq!(corrected.manifest.labels["corrected"], "true");
+        assert_eq!(
+            correction.apply(netstat_raw()).unwrap().unwrap(),
+            netstat_raw()
+        );
+
+        let bad = Correction {
+            labels: Some(Document::Merge(json!({ "corrected": true }))),
+            ..correction
+        };
+        let mut packet = cpu_raw();
+        assert!(matches!(bad.apply_to(&mut packet), Err(Error::Labels(_))));
+        assert_eq!(packet, cpu_raw());
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn validates_corrections() {
+        use crate::schema::MemorySchemaRegistry;
+
+        let registry = MemorySchemaRegistry::new();
+        registry
+            .register_schema(
+                cpu_raw().manifest.coordinates(),
+                json!({ "properties": { "idle": { "type": "number", "maximum": 100 } } }),
+            )
+            .unwrap();
+        let correction = Correction {
+            content: Some(Document::Merge(json!({ "idle": 183.25 }))),
+            ..Correction::default()
+        };
+        let mut packet = cpu_raw();
+        assert!(matches!(
+            correction.apply_validated(&mut packet, &registry),
+            Err(Error::Schema(_))
+        ));
+        assert_eq!(packet, cpu_raw());
+    }
+}
@@ -0,0 +1,339 @@
+//! Binding packet origins to the identities of authenticated peers.
+//!
+//! A packet's `origin` is only what its sender claims. Where senders
+//! connect with TLS client certificates, an [`OriginBinding`] holds them to
+//! it: each packet a peer sends must name an origin the peer's
+//! [`Identity`] is allowed, either one of the certificate's own names or
+//! one bound to it explicitly, so that one host cannot pass its packets
+//! off as another's:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use intermodal::peer::{Identity, OriginBinding};
+//! use intermodal::transform::Transform;
+//! # let packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//! #                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": 86400
+//! # })).unwrap();
+//!
+//! let binding = Arc::new(OriginBinding::new().allow("relay01", "host*"));
+//!
+//! // Names taken from the client certificate of each connection.
+//! let relay = binding.peer(Identity::new(["relay01"]));
+//! let other = binding.peer(Identity::new(["host02"]));
+//! assert!(relay.apply(packet.clone())?.is_some());
+//! assert!(other.apply(packet).is_err());
+//! # Ok::<(), intermodal::transform::Error>(())
+//! ```
+//!
+//! The binding knows nothing of TLS: the ingest accepting a connection
+//! takes the names from the verified certificate, its subject common name
+//! and DNS or URI alternative names, and gives them to
+//! [`OriginBinding::peer`], whose [`Peer`] is a [`Transform`] for the
+//! packets of that connection. Peers that presented no certificate have
+//! the [anonymous](Identity::anonymous) identity, which is bound to no
+//! origins. What happens to a mismatched packet depends on the [`Action`];
+//! unlike a `trust::Policy`, a binding rejects by default.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::selector::Pattern;
+use crate::transform::{self, Transform};
+use crate::{Coordinates, Manifest, RawPacket};
+
+/// The names a peer authenticated as.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    names: Vec<String>,
+}
+
+impl Identity {
+    pub fn new<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Identity {
+            names: names.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// The identity of a peer that did not authenticate.
+    pub fn anonymous() -> Self {
+        Identity::default()
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.names.as_slice() {
+            [] => f.write_str("anonymous peer"),
+            [name, ..] => write!(f, "peer `{}`", name),
+        }
+    }
+}
+
+/// What to do with a packet whose origin its peer is not allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Let it through, auditing it as mismatched.
+    Observe,
+    Drop,
+    /// Fail the transform with an error naming the peer and origin.
+    Reject,
+    /// Replace the origin with the peer's first name, dropping any
+    /// signature over the old one. Anonymous peers are rejected.
+    Rewrite,
+}
+
+/// A packet whose origin its peer is not allowed, as passed to the audit
+/// function.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub coordinates: Coordinates,
+    pub origin: String,
+    pub identity: Identity,
+    pub action: Action,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} from {} claims origin `{}`, ",
+            self.coordinates, self.identity, self.origin
+        )?;
+        f.write_str(match self.action {
+            Action::Observe => "observed",
+            Action::Drop => "dropped",
+            Action::Reject => "rejected",
+            Action::Rewrite => "rewritten",
+        })
+    }
+}
+
+type AuditFn = Box<dyn Fn(&Mismatch) + Send + Sync>;
+
+/// The origins each peer identity may send packets from.
+pub struct OriginBinding {
+    own_names: bool,
+    /// Each name, and a pattern of origins it may use.
+    allowed: Vec<(String, Pattern)>,
+    action: Action,
+    audit: Option<AuditFn>,
+}
+
+impl fmt::Debug for OriginBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OriginBinding")
+            .field("own_names", &self.own_names)
+            .field("allowed", &self.allowed)
+            .field("action", &self.action)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for OriginBinding {
+    fn default() -> Self {
+        OriginBinding::new()
+    }
+}
+
+impl OriginBinding {
+    /// A binding allowing each peer its own names as origins, and
+    /// rejecting packets with any other.
+    pub fn new() -> Self {
+        OriginBinding {
+            own_names: true,
+            allowed: Vec::new(),
+            action: Action::Reject,
+            audit: None,
+        }
+    }
+
+    /// Allows peers with the name `name` origins matching the glob
+    /// `origins`, in which `*` matches any run of characters and `?` any
+    /// one.
+    pub fn allow<N: Into<String>, O: Into<String>>(mut self, name: N, origins: O) -> Self {
+        self.allowed.push((name.into(), Pattern(origins.into())));
+        self
+    }
+
+    /// Whether peers may use their own names as origins without being
+    /// allowed them, as they may unless set otherwise.
+    pub fn own_names(mut self, own_names: bool) -> Self {
+        self.own_names = own_names;
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Passes every mismatch to `audit`.
+    pub fn audit<F>(mut self, audit: F) -> Self
+    where
+        F: Fn(&Mismatch) + Send + Sync + 'static,
+    {
+        self.audit = Some(Box::new(audit));
+        self
+    }
+
+    /// Whether a peer with the identity may send packets from `origin`.
+    pub fn allows(&self, identity: &Identity, origin: &str) -> bool {
+        identity.names.iter().any(|name| {
+            (self.own_names && name == origin)
+                || self
+                    .allowed
+                    .iter()
+                    .any(|(allowed, origins)| allowed == name && origins.matches(origin))
+        })
+    }
+
+    /// Checks a packet from a peer with the identity, applying the action
+    /// if its origin is not allowed.
+    pub fn bind(
+        &self,
+        identity: &Identity,
+        mut packet: RawPacket,
+    ) -> Result<Option<RawPacket>, transform::Error> {
+        if self.allows(identity, &packet.manifest.origin) {
+            return Ok(Some(packet));
+        }
+        let mismatch = self.mismatch(identity, &packet.manifest);
+        if let Some(audit) = &self.audit {
+            audit(&mismatch);
+        }
+        match (mismatch.action, identity.names.first()) {
+            (Action::Observe, _) => Ok(Some(packet)),
+            (Action::Drop, _) => Ok(None),
+            (Action::Rewrite, Some(name)) => {
+                packet.manifest.origin = name.clone();
+                packet.signature = None;
+                Ok(Some(packet))
+            }
+            (Action::Reject, _) | (Action::Rewrite, None) => Err(transform::Error::new(format!(
+                "{} may not send packets from `{}`",
+                identity, mismatch.origin
+            ))),
+        }
+    }
+
+    fn mismatch(&self, identity: &Identity, manifest: &Manifest) -> Mismatch {
+        let action = match self.action {
+            Action::Rewrite if identity.is_anonymous() => Action::Reject,
+            action => action,
+        };
+        Mismatch {
+            coordinates: manifest.coordinates(),
+            origin: manifest.origin.clone(),
+            identity: identity.clone(),
+            action,
+        }
+    }
+
+    /// The binding applied to the packets of one peer.
+    pub fn peer(self: &Arc<Self>, identity: Identity) -> Peer {
+        Peer {
+            binding: Arc::clone(self),
+            identity,
+        }
+    }
+}
+
+/// An [`OriginBinding`] applied to the packets of one peer, as a
+/// [`Transform`].
+#[derive(Debug, Clone)]
+pub struct Peer {
+    binding: Arc<OriginBinding>,
+    identity: Identity,
+}
+
+impl Peer {
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+}
+
+impl Transform for Peer {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.binding.bind(&self.identity, packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::sync::Mutex;
+
+    #[test]
+    fn binds_origins_to_names() {
+        let binding = OriginBinding::new().allow("relay01", "*.example.org");
+        let host01 = Identity::new(["host01.example.org", "10.0.0.1"]);
+        let relay = Identity::new(["relay01"]);
+        assert!(binding.allows(&host01, "host01.example.org"));
+        assert!(!binding.allows(&host01, "host02.example.org"));
+        assert!(binding.allows(&relay, "host02.example.org"));
+        assert!(!binding.allows(&relay, "host02.example.net"));
+        assert!(!binding.allows(&Identity::anonymous(), "host01.example.org"));
+
+        let binding = binding.own_names(false);
+        assert!(!binding.allows(&host01, "host01.example.org"));
+    }
+
+    #[test]
+    fn applies_the_action() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let audited = Arc::clone(&log);
+        let binding = Arc::new(OriginBinding::new().audit(move |mismatch| {
+            audited.lock().unwrap().push(mismatch.to_string());
+        }));
+        let packet = fixtures::cpu_raw();
+        assert!(binding
+            .peer(Identity::new(["host01.example.org"]))
+            .apply(packet.clone())
+            .unwrap()
+            .is_some());
+        let error = binding
+            .peer(Identity::new(["host02.example.org"]))
+            .apply(packet.clone())
+            .unwrap_err();
+        assert!(
+            error.to_string().ends_with(
+                "peer `host02.example.org` may not send packets from `host01.example.org`"
+            ),
+            "{}",
+            error
+        );
+        assert_eq!(
+            log.lock().unwrap()[0],
+            "example.org/metrics/host/cpu@1 from peer `host02.example.org` \
+             claims origin `host01.example.org`, rejected"
+        );
+
+        let rewrite = OriginBinding::new().action(Action::Rewrite);
+        let rewritten = rewrite
+            .bind(&Identity::new(["host02.example.org"]), packet.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rewritten.manifest.origin, "host02.example.org");
+        assert!(rewrite
+            .bind(&Identity::anonymous(), packet.clone())
+            .is_err());
+
+        let drop = OriginBinding::new().action(Action::Drop);
+        assert!(drop.bind(&Identity::anonymous(), packet).unwrap().is_none());
+    }
+}
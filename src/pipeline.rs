@@ -0,0 +1,358 @@
+//! Running a dispatcher as a pipeline that can be shut down cleanly.
+//!
+//! A [`Pipeline`] couples a [`Dispatcher`] with the sinks that batch packets
+//! before writing them and the spools that hold overflow, so that all of them
+//! can be brought to rest together. [`Pipeline::shutdown`] stops intake,
+//! drains the packets already accepted, flushes every batching sink and
+//! spool, and reports whatever could not be delivered:
+//!
+//! ```no_run
+//! # async fn run(pipeline: intermodal::pipeline::Pipeline, sigterm: impl std::future::Future) {
+//! use std::time::{Duration, Instant};
+//!
+//! sigterm.await;
+//! let report = pipeline.shutdown(Instant::now() + Duration::from_secs(10)).await;
+//! if !report.is_complete() {
+//!     eprintln!("{} packets undelivered", report.undelivered.len());
+//! }
+//! # }
+//! ```
+//!
+//! A pipeline given a [`Shedder`] consults it before dispatching each
+//! packet, with the dispatcher's queue depth as the load, so that under
+//! overload the packets its rules cover are shed rather than queued.
+//!
+//! [`Tap`]s given to a pipeline copy what it is sent, shed or not, to their
+//! sinks for debugging; see [`tap`](crate::tap).
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures_util::future::BoxFuture;
+
+use crate::dispatch::{Dispatcher, Error, Outcome};
+use crate::shed::{self, Shedder};
+use crate::spool::Spool;
+use crate::tap::Tap;
+use crate::RawPacket;
+
+/// A sink that holds packets back to write them in batches.
+pub trait Flush: Send + Sync {
+    /// Writes out everything the sink is holding.
+    fn flush(&self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// A dispatcher together with the sinks and spools it feeds.
+pub struct Pipeline {
+    dispatcher: Dispatcher,
+    sinks: Vec<(String, Arc<dyn Flush>)>,
+    spools: Vec<(String, Arc<dyn Spool>)>,
+    shedder: Option<Shedder>,
+    taps: Vec<Tap>,
+}
+
+impl Pipeline {
+    pub fn new(dispatcher: Dispatcher) -> Self {
+        Pipeline {
+            dispatcher,
+            sinks: Vec::new(),
+            spools: Vec::new(),
+            shedder: None,
+            taps: Vec::new(),
+        }
+    }
+
+    /// Registers a batching sink to flush on shutdown, once the dispatcher
+    /// has drained.
+    pub fn sink<N: Into<String>>(mut self, name: N, sink: Arc<dyn Flush>) -> Self {
+        self.sinks.push((name.into(), sink));
+        self
+    }
+
+    /// Registers a spool to flush on shutdown, after the sinks.
+    pub fn spool<N: Into<String>>(mut self, name: N, spool: Arc<dyn Spool>) -> Self {
+        self.spools.push((name.into(), spool));
+        self
+    }
+
+    /// Sheds packets under load by `shedder`'s rules.
+    pub fn shed(mut self, shedder: Shedder) -> Self {
+        self.shedder = Some(shedder);
+        self
+    }
+
+    /// Copies the packets sent to the pipeline to `tap`'s sink.
+    pub fn tap(mut self, tap: Tap) -> Self {
+        self.taps.push(tap);
+        self
+    }
+
+    pub fn taps(&self) -> &[Tap] {
+        &self.taps
+    }
+
+    pub fn dispatcher(&self) -> &Dispatcher {
+        &self.dispatcher
+    }
+
+    /// The shedder, whose [`report`](Shedder::report) says what was shed.
+    pub fn shedder(&self) -> Option<&Shedder> {
+        self.shedder.as_ref()
+    }
+
+    /// Dispatches a packet into the pipeline, unless it is shed. A shed
+    /// packet is reported as [`Outcome::Dropped`] or [`Outcome::Spooled`].
+    pub async fn send(&self, packet: RawPacket) -> Result<Outcome, Error> {
+        for tap in &self.taps {
+            tap.observe(&packet);
+        }
+        if let Some(shedder) = &self.shedder {
+            let load = shedder.load(self.dispatcher.depth());
+            if let Some(rule) = shedder.check(&packet.manifest, load) {
+                shedder.shed(rule, &packet).map_err(Error::Spool)?;
+                return Ok(match rule.action {
+                    shed::Action::Drop => Outcome::Dropped,
+                    shed::Action::Spool(_) => Outcome::Spooled,
+                });
+            }
+        }
+        self.dispatcher.dispatch(packet).await
+    }
+
+    /// Shuts the pipeline down.
+    ///
+    /// Intake stops at once. Packets already accepted are handled until
+    /// `deadline`, then the sinks are flushed, each given until the deadline
+    /// to finish, and finally the spools are flushed. Spools are flushed
+    /// even once the deadline has passed, since they are the last resort
+    /// for anything held in them.
+    pub async fn shutdown(&self, deadline: Instant) -> ShutdownReport {
+        let drained = self.dispatcher.drain(deadline).await;
+        let mut report = ShutdownReport {
+            undelivered: drained.undelivered,
+            abandoned: drained.abandoned,
+            failures: Vec::new(),
+        };
+
+        let tokio_deadline = tokio::time::Instant::from_std(deadline);
+        for (name, sink) in &self.sinks {
+            let error = match tokio::time::timeout_at(tokio_deadline, sink.flush()).await {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e,
+                Err(_) => io::Error::new(io::ErrorKind::TimedOut, "deadline passed"),
+            };
+            report.failures.push(FlushFailure {
+                name: name.clone(),
+                error,
+            });
+        }
+        for (name, spool) in &self.spools {
+            if let Err(error) = spool.flush() {
+                report.failures.push(FlushFailure {
+                    name: name.clone(),
+                    error,
+                });
+            }
+        }
+        report
+    }
+}
+
+/// What a [`Pipeline::shutdown`] could not deliver.
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// Packets whose handlers were cancelled at the deadline, followed by
+    /// those still queued; see [`Drained`](crate::dispatch::Drained).
+    pub undelivered: Vec<RawPacket>,
+    /// The number of packets whose handlers were cancelled at the deadline,
+    /// which lead the undelivered packets.
+    pub abandoned: usize,
+    /// Sinks and spools that could not be flushed.
+    pub failures: Vec<FlushFailure>,
+}
+
+impl ShutdownReport {
+    /// Whether everything accepted was delivered and flushed.
+    pub fn is_complete(&self) -> bool {
+        self.undelivered.is_empty() && self.abandoned == 0 && self.failures.is_empty()
+    }
+}
+
+/// A sink or spool that could not be flushed.
+#[derive(Debug)]
+pub struct FlushFailure {
+    pub name: String,
+    pub error: io::Error,
+}
+
+impl fmt::Display for FlushFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "flushing `{}` failed: {}", self.name, self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use futures_util::FutureExt;
+    use tokio::sync::{mpsc, Semaphore};
+
+    use super::*;
+    use crate::dispatch::{Handler, Limits};
+    use crate::fixtures;
+
+    /// A handler that tells `started` of each packet it takes, then holds it
+    /// until `gate` is closed.
+    fn held(started: mpsc::UnboundedSender<()>, gate: Arc<Semaphore>) -> impl Handler {
+        move |_| {
+            let _ = started.send(());
+            let gate = Arc::clone(&gate);
+            async move {
+                let _ = gate.acquire().await;
+                Ok(())
+            }
+        }
+    }
+
+    /// A sink that buffers packets until flushed.
+    #[derive(Default)]
+    struct Buffered {
+        pending: Mutex<Vec<RawPacket>>,
+        written: Mutex<Vec<RawPacket>>,
+    }
+
+    impl Flush for Buffered {
+        fn flush(&self) -> BoxFuture<'_, io::Result<()>> {
+            async move {
+                let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+                self.written.lock().unwrap().extend(pending);
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn drains_and_flushes() {
+        let sink = Arc::new(Buffered::default());
+        let limits = Limits {
+            concurrency: 2,
+            ..Limits::default()
+        };
+        let dispatcher = Dispatcher::builder()
+            .fallback(limits, {
+                let sink = Arc::clone(&sink);
+                move |packet| {
+                    let sink = Arc::clone(&sink);
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                        sink.pending.lock().unwrap().push(packet);
+                        Ok(())
+                    }
+                }
+            })
+            .build();
+        let (copies, tapped) = std::sync::mpsc::channel();
+        let tap = Tap::new(move |packet: &RawPacket| {
+            copies.send(packet.clone()).map_err(io::Error::other)
+        })
+        .sample_rate(0.5);
+        let pipeline = Pipeline::new(dispatcher)
+            .sink("buffered", sink.clone())
+            .tap(tap);
+
+        for _ in 0..10 {
+            pipeline.send(fixtures::cpu_raw()).await.unwrap();
+        }
+        let report = pipeline
+            .shutdown(Instant::now() + Duration::from_secs(5))
+            .await;
+        assert!(report.is_complete(), "{:?}", report);
+        assert_eq!(sink.written.lock().unwrap().len(), 10);
+        assert_eq!(pipeline.taps()[0].stats().sampled, 5);
+        for _ in 0..5 {
+            tapped.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(matches!(
+            pipeline.send(fixtures::cpu_raw()).await,
+            Err(Error::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_what_missed_the_deadline() {
+        tokio::time::pause();
+        let (started, mut taken) = mpsc::unbounded_channel();
+        let dispatcher = Dispatcher::builder()
+            .fallback(
+                Limits::default(),
+                held(started, Arc::new(Semaphore::new(0))),
+            )
+            .build();
+        let pipeline = Pipeline::new(dispatcher);
+
+        for _ in 0..3 {
+            pipeline.send(fixtures::netstat_raw()).await.unwrap();
+        }
+        // The first packet is held by the handler past the deadline.
+        taken.recv().await.unwrap();
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(20);
+        let (report, ()) = tokio::join!(
+            pipeline.shutdown(deadline.into_std()),
+            tokio::time::advance(Duration::from_millis(20)),
+        );
+        assert_eq!(report.abandoned, 1);
+        assert_eq!(report.undelivered.len(), 3);
+        assert!(report
+            .undelivered
+            .iter()
+            .all(|packet| packet.manifest.kind == "netstat"));
+        assert!(!report.is_complete());
+    }
+
+    #[tokio::test]
+    async fn sheds_under_load() {
+        let (started, mut taken) = mpsc::unbounded_channel();
+        let gate = Arc::new(Semaphore::new(0));
+        let dispatcher = Dispatcher::builder()
+            .fallback(Limits::default(), held(started, Arc::clone(&gate)))
+            .build();
+        let rule = shed::Rule::new(
+            "netstat",
+            "kind=netstat".parse().unwrap(),
+            shed::Action::Drop,
+        )
+        .queue_depth(2);
+        let pipeline = Pipeline::new(dispatcher).shed(Shedder::new().rule(rule));
+
+        // The first packet is held by the handler, and the next two queue.
+        for i in 0..3 {
+            let outcome = pipeline.send(fixtures::netstat_raw()).await.unwrap();
+            assert_eq!(outcome, Outcome::Queued);
+            if i == 0 {
+                taken.recv().await.unwrap();
+            }
+        }
+        assert_eq!(pipeline.dispatcher().depth(), 2);
+        let outcome = pipeline.send(fixtures::netstat_raw()).await.unwrap();
+        assert_eq!(outcome, Outcome::Dropped);
+        let outcome = pipeline.send(fixtures::cpu_raw()).await.unwrap();
+        assert_eq!(outcome, Outcome::Queued);
+        assert_eq!(pipeline.shedder().unwrap().report()[0].dropped, 1);
+
+        // Everything not shed is handled once the handler is let go.
+        gate.close();
+        let report = pipeline
+            .shutdown(Instant::now() + Duration::from_secs(5))
+            .await;
+        assert!(report.is_complete(), "{:?}", report);
+        let netstat = fixtures::netstat_manifest().coordinates();
+        let cpu = fixtures::cpu_manifest().coordinates();
+        let handled = |coordinates| pipeline.dispatcher().stats(coordinates).unwrap().handled;
+        assert_eq!((handled(&netstat), handled(&cpu)), (3, 1));
+    }
+}
@@ -0,0 +1,219 @@
+//! Adding noise to numeric content before it leaves a trust boundary.
+//!
+//! A [`Privatizer`] applies [`Mechanism`]s to numeric content fields, each
+//! addressed by a JSON pointer. Fields are bounded with
+//! [`Clamp`](Mechanism::Clamp) and coarsened with
+//! [`Round`](Mechanism::Round), and noise from the Laplace or Gaussian
+//! distribution is added to them, so that what is shared describes the
+//! population without exposing any one member of it:
+//!
+//! ```
+//! use intermodal::privacy::{Mechanism, Privatizer};
+//!
+//! let privatizer = Privatizer::new()
+//!     .field("/latency_ms", Mechanism::Clamp { min: 0.0, max: 1000.0 })
+//!     .field("/latency_ms", Mechanism::laplace(1000.0, 0.5))
+//!     .field("/sessions", Mechanism::Round { step: 10.0 });
+//! ```
+//!
+//! Mechanisms apply in the order they were added. A field that is absent
+//! is left so; a field that is present but not a number fails the packet,
+//! rather than letting through a value that was never made private. Integer
+//! fields stay integers, rounded to the nearest after noise is added.
+//!
+//! Noise bounds what one packet reveals only. Sharing many packets about
+//! the same subject spends the privacy budget once per packet.
+//!
+//! Available with the `privacy` feature.
+
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{Number, Value};
+
+use crate::transform::{self, Transform};
+use crate::RawPacket;
+
+/// A way of making a numeric value private.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mechanism {
+    /// Limits the value to a range, which bounds the sensitivity that noise
+    /// must hide.
+    Clamp { min: f64, max: f64 },
+    /// Rounds the value to the nearest multiple of `step`.
+    Round { step: f64 },
+    /// Adds noise from the Laplace distribution with the given scale.
+    Laplace { scale: f64 },
+    /// Adds noise from the normal distribution with the given standard
+    /// deviation.
+    Gaussian { sigma: f64 },
+}
+
+impl Mechanism {
+    /// The Laplace mechanism giving `epsilon`-differential privacy to a
+    /// value that one subject can change by at most `sensitivity`.
+    pub fn laplace(sensitivity: f64, epsilon: f64) -> Self {
+        Mechanism::Laplace {
+            scale: sensitivity / epsilon,
+        }
+    }
+
+    fn apply<R: Rng>(self, value: f64, rng: &mut R) -> f64 {
+        match self {
+            Mechanism::Clamp { min, max } => value.max(min).min(max),
+            Mechanism::Round { step } if step > 0.0 => (value / step).round() * step,
+            Mechanism::Round { .. } => value,
+            Mechanism::Laplace { scale } => {
+                let u: f64 = rng.random::<f64>() - 0.5;
+                value - scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+            }
+            Mechanism::Gaussian { sigma } => {
+                // Box-Muller, taking the first of the pair.
+                let u1: f64 = 1.0 - rng.random::<f64>();
+                let u2: f64 = rng.random();
+                value + sigma * (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+            }
+        }
+    }
+}
+
+/// Applies mechanisms to content fields.
+#[derive(Debug)]
+pub struct Privatizer {
+    fields: Vec<(String, Mechanism)>,
+    rng: Mutex<StdRng>,
+}
+
+impl Default for Privatizer {
+    fn default() -> Self {
+        Privatizer::new()
+    }
+}
+
+impl Privatizer {
+    /// A privatizer with no fields yet, drawing noise from a generator
+    /// seeded by the operating system.
+    pub fn new() -> Self {
+        Privatizer {
+            fields: Vec::new(),
+            rng: Mutex::new(StdRng::from_os_rng()),
+        }
+    }
+
+    /// Applies `mechanism` to the field at the JSON `pointer`, after any
+    /// mechanisms already added.
+    pub fn field<S: Into<String>>(mut self, pointer: S, mechanism: Mechanism) -> Self {
+        self.fields.push((pointer.into(), mechanism));
+        self
+    }
+
+    /// Draws noise from a generator with the given seed, for reproducible
+    /// output in tests. Noise that can be reproduced protects nothing.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Mutex::new(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    /// Applies every mechanism to its field of `content`.
+    pub fn privatize(&self, content: &mut Value) -> Result<(), transform::Error> {
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        for (pointer, mechanism) in &self.fields {
+            let field = match content.pointer_mut(pointer) {
+                Some(field) => field,
+                None => continue,
+            };
+            let number = match field {
+                Value::Number(number) => number,
+                _ => {
+                    return Err(transform::Error::new(format!(
+                        "field `{}` is not a number",
+                        pointer
+                    )))
+                }
+            };
+            let value = number.as_f64().unwrap_or_default();
+            let private = mechanism.apply(value, &mut *rng);
+            *number = if number.is_f64() {
+                Number::from_f64(private).unwrap_or_else(|| Number::from(0))
+            } else {
+                Number::from(private.round() as i64)
+            };
+        }
+        Ok(())
+    }
+}
+
+impl Transform for Privatizer {
+    fn apply(&self, mut packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.privatize(&mut packet.content)?;
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::json;
+
+    #[test]
+    fn applies_in_order() {
+        let privatizer = Privatizer::new()
+            .field(
+                "/user",
+                Mechanism::Clamp {
+                    min: 0.0,
+                    max: 10.0,
+                },
+            )
+            .field("/system", Mechanism::Round { step: 5.0 })
+            .field("/missing", Mechanism::Round { step: 5.0 });
+        let mut packet = fixtures::cpu_raw();
+        packet.content = json!({ "user": 42.5, "system": 12, "idle": 3.0 });
+        let packet = privatizer.apply(packet).unwrap().unwrap();
+        assert_eq!(
+            packet.content,
+            json!({ "user": 10.0, "system": 10, "idle": 3.0 })
+        );
+
+        let mut content = json!({ "user": "lots" });
+        let error = privatizer.privatize(&mut content).unwrap_err();
+        assert!(error.to_string().contains("`/user` is not a number"));
+    }
+
+    #[test]
+    fn adds_noise() {
+        for mechanism in [
+            Mechanism::laplace(1.0, 0.5),
+            Mechanism::Gaussian { sigma: 2.0 },
+        ] {
+            let privatizer = Privatizer::new().field("/n", mechanism).seed(7);
+            let n = 10_000;
+            let mut sum = 0.0;
+            let mut sum_of_squares = 0.0;
+            for _ in 0..n {
+                let mut content = json!({ "n": 100.0 });
+                privatizer.privatize(&mut content).unwrap();
+                let noise = content["n"].as_f64().unwrap() - 100.0;
+                sum += noise;
+                sum_of_squares += noise * noise;
+            }
+            // Both have mean 0 and, as configured, variance 8 (Laplace:
+            // 2 * scale^2) or 4.
+            let mean = sum / n as f64;
+            let variance = sum_of_squares / n as f64;
+            let expected = match mechanism {
+                Mechanism::Laplace { .. } => 8.0,
+                _ => 4.0,
+            };
+            assert!(mean.abs() < 0.2, "{:?}: mean {}", mechanism, mean);
+            assert!(
+                (variance - expected).abs() < expected * 0.15,
+                "{:?}: variance {}",
+                mechanism,
+                variance
+            );
+        }
+    }
+}
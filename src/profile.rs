@@ -0,0 +1,466 @@
+//! Named encode profiles.
+//!
+//! A [`Profile`] decides how envelopes are put on the wire: the format they
+//! are serialized in, whether they are compressed, and how their timestamps
+//! are written. Programs pick one by name at runtime, typically from their
+//! configuration, so operators can trade legibility for size without code
+//! changes. Three profiles are built in:
+//!
+//! | name       | format      | compression | timestamps   |
+//! |------------|-------------|-------------|--------------|
+//! | `standard` | JSON        | none        | RFC 3339     |
+//! | `debug`    | pretty JSON | none        | RFC 3339     |
+//! | `compact`  | MessagePack | zstd        | milliseconds |
+//!
+//! More can be configured alongside them; in YAML:
+//!
+//! ```yaml
+//! archive:
+//!   format: json
+//!   compression: zstd
+//! ```
+//!
+//! Encoding yields the bytes together with a content encoding, such as
+//! `msgpack+zstd+ms`, describing them. Transports carry it next to the
+//! envelope, as a message header or attribute, and receivers pass it to
+//! [`decode`], so they need not know which profile a sender chose.
+//!
+//! MessagePack requires the `msgpack` feature, and zstd the `zstd` feature.
+//! Profiles using them can be configured without those features, but fail to
+//! encode or decode.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use chrono::{TimeZone, Utc};
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Packet;
+
+/// How a profile serializes envelopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    Json,
+    /// JSON, indented for reading.
+    JsonPretty,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+/// How a profile compresses serialized envelopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+/// How a profile writes the manifest's `ctime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Timestamps {
+    /// An RFC 3339 string, as in the standard encoding.
+    Rfc3339,
+    /// An integer number of milliseconds since the Unix epoch.
+    Millis,
+}
+
+/// A way of putting envelopes on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub format: Format,
+    #[serde(default = "Profile::no_compression")]
+    pub compression: Compression,
+    #[serde(default = "Profile::rfc3339")]
+    pub timestamps: Timestamps,
+}
+
+impl Profile {
+    /// Plain JSON, the encoding used everywhere else in this crate.
+    pub const STANDARD: Profile = Profile {
+        format: Format::Json,
+        compression: Compression::None,
+        timestamps: Timestamps::Rfc3339,
+    };
+
+    /// Indented JSON.
+    pub const DEBUG: Profile = Profile {
+        format: Format::JsonPretty,
+        compression: Compression::None,
+        timestamps: Timestamps::Rfc3339,
+    };
+
+    /// Zstd-compressed MessagePack with millisecond timestamps.
+    pub const COMPACT: Profile = Profile {
+        format: Format::MessagePack,
+        compression: Compression::Zstd,
+        timestamps: Timestamps::Millis,
+    };
+
+    fn no_compression() -> Compression {
+        Compression::None
+    }
+
+    fn rfc3339() -> Timestamps {
+        Timestamps::Rfc3339
+    }
+
+    /// Parses a content encoding, as returned by
+    /// [`content_encoding`](Profile::content_encoding), into a profile that
+    /// decodes it.
+    ///
+    /// Pretty and plain JSON share the content encoding `json`, so this
+    /// never returns a profile using [`Format::JsonPretty`].
+    pub fn from_content_encoding(encoding: &str) -> Result<Self, Error> {
+        let invalid = || Error::Encoding(encoding.to_string());
+        let mut tokens = encoding.split('+');
+        let format = match tokens.next() {
+            Some("json") => Format::Json,
+            Some("msgpack") => Format::MessagePack,
+            _ => return Err(invalid()),
+        };
+        let mut profile = Profile {
+            format,
+            ..Profile::STANDARD
+        };
+        let mut next = tokens.next();
+        if next == Some("zstd") {
+            profile.compression = Compression::Zstd;
+            next = tokens.next();
+        }
+        if next == Some("ms") {
+            profile.timestamps = Timestamps::Millis;
+            next = tokens.next();
+        }
+        match next {
+            None => Ok(profile),
+            Some(_) => Err(invalid()),
+        }
+    }
+
+    /// Describes the bytes this profile produces, e.g. `json` or
+    /// `msgpack+zstd+ms`.
+    pub fn content_encoding(&self) -> String {
+        let mut encoding = String::from(match self.format {
+            Format::Json | Format::JsonPretty => "json",
+            Format::MessagePack => "msgpack",
+        });
+        if self.compression == Compression::Zstd {
+            encoding.push_str("+zstd");
+        }
+        if self.timestamps == Timestamps::Millis {
+            encoding.push_str("+ms");
+        }
+        encoding
+    }
+
+    /// Encodes a packet.
+    pub fn encode<T: Serialize>(&self, packet: &Packet<T>) -> Result<Encoded, Error> {
+        let mut value = serde_json::to_value(packet)?;
+        if self.timestamps == Timestamps::Millis {
+            let ctime = &mut value["manifest"]["ctime"];
+            *ctime = Value::from(packet.manifest.ctime.timestamp_millis());
+        }
+
+        let bytes = match self.format {
+            Format::Json => serde_json::to_vec(&value)?,
+            Format::JsonPretty => serde_json::to_vec_pretty(&value)?,
+            Format::MessagePack => msgpack::to_vec(&value)?,
+        };
+        let bytes = match self.compression {
+            Compression::None => bytes,
+            Compression::Zstd => zstd::compress(&bytes)?,
+        };
+        Ok(Encoded {
+            bytes,
+            content_encoding: self.content_encoding(),
+        })
+    }
+
+    /// Decodes a packet encoded with this profile.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Packet<T>, Error> {
+        let decompressed;
+        let bytes = match self.compression {
+            Compression::None => bytes,
+            Compression::Zstd => {
+                decompressed = zstd::decompress(bytes)?;
+                &decompressed
+            }
+        };
+        let mut value: Value = match self.format {
+            Format::Json | Format::JsonPretty => serde_json::from_slice(bytes)?,
+            Format::MessagePack => msgpack::from_slice(bytes)?,
+        };
+
+        if self.timestamps == Timestamps::Millis {
+            let ctime = &mut value["manifest"]["ctime"];
+            if let Some(millis) = ctime.as_i64() {
+                let time = Utc
+                    .timestamp_millis_opt(millis)
+                    .single()
+                    .ok_or_else(|| Error::Encoding(format!("ctime {} out of range", millis)))?;
+                *ctime = Value::String(time.to_rfc3339());
+            }
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Decodes a packet given the content encoding it was sent with.
+pub fn decode<T: DeserializeOwned>(
+    content_encoding: &str,
+    bytes: &[u8],
+) -> Result<Packet<T>, Error> {
+    Profile::from_content_encoding(content_encoding)?.decode(bytes)
+}
+
+/// An encoded packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encoded {
+    pub bytes: Vec<u8>,
+    /// Describes `bytes`, for transports to carry alongside them.
+    pub content_encoding: String,
+}
+
+/// Profiles by name: the built-in profiles and any configured ones.
+///
+/// Deserializes from a map of names to profiles. Configured profiles
+/// replace built-in profiles of the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profiles {
+    profiles: HashMap<String, Profile>,
+}
+
+impl Profiles {
+    /// Returns the built-in profiles.
+    pub fn new() -> Self {
+        let mut profiles = HashMap::new();
+        profiles.insert("standard".to_string(), Profile::STANDARD);
+        profiles.insert("debug".to_string(), Profile::DEBUG);
+        profiles.insert("compact".to_string(), Profile::COMPACT);
+        Profiles { profiles }
+    }
+
+    /// Adds a profile, replacing any profile of the same name.
+    pub fn insert<S: Into<String>>(&mut self, name: S, profile: Profile) -> &mut Self {
+        self.profiles.insert(name.into(), profile);
+        self
+    }
+
+    /// Looks up a profile by name.
+    pub fn get(&self, name: &str) -> Result<Profile, Error> {
+        self.profiles
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::Unknown(name.to_string()))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Profiles::new()
+    }
+}
+
+impl<'de> Deserialize<'de> for Profiles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let configured = HashMap::<String, Profile>::deserialize(deserializer)?;
+        let mut profiles = Profiles::new();
+        profiles.profiles.extend(configured);
+        Ok(profiles)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+mod msgpack {
+    use serde_json::Value;
+
+    use super::Error;
+
+    pub fn to_vec(value: &Value) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec(value).map_err(|e| Error::Format(e.to_string()))
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Value, Error> {
+        rmp_serde::from_slice(bytes).map_err(|e| Error::Format(e.to_string()))
+    }
+}
+
+#[cfg(not(feature = "msgpack"))]
+mod msgpack {
+    use serde_json::Value;
+
+    use super::Error;
+
+    pub fn to_vec(_: &Value) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("msgpack"))
+    }
+
+    pub fn from_slice(_: &[u8]) -> Result<Value, Error> {
+        Err(Error::Unsupported("msgpack"))
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use super::Error;
+
+    pub fn compress(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(::zstd::encode_all(bytes, 0)?)
+    }
+
+    pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(::zstd::decode_all(bytes)?)
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+mod zstd {
+    use super::Error;
+
+    pub fn compress(_: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("zstd"))
+    }
+
+    pub fn decompress(_: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("zstd"))
+    }
+}
+
+/// An error selecting a profile, or encoding or decoding with one.
+#[derive(Debug)]
+pub enum Error {
+    /// No profile has the name.
+    Unknown(String),
+    /// The content encoding is not one profiles produce.
+    Encoding(String),
+    /// The profile needs a feature this build lacks.
+    Unsupported(&'static str),
+    Json(serde_json::Error),
+    /// The bytes could not be serialized or deserialized in a format other
+    /// than JSON.
+    Format(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unknown(name) => write!(f, "no encode profile named `{}`", name),
+            Error::Encoding(encoding) => write!(f, "invalid content encoding: {}", encoding),
+            Error::Unsupported(feature) => {
+                write!(f, "encoding requires the `{}` feature", feature)
+            }
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Format(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+
+    #[test]
+    fn content_encodings_round_trip() {
+        for profile in &[Profile::STANDARD, Profile::COMPACT] {
+            let encoding = profile.content_encoding();
+            assert_eq!(Profile::from_content_encoding(&encoding).unwrap(), *profile);
+        }
+        assert_eq!(Profile::COMPACT.content_encoding(), "msgpack+zstd+ms");
+        assert_eq!(Profile::DEBUG.content_encoding(), "json");
+        assert!(Profile::from_content_encoding("json+ms+zstd").is_err());
+        assert!(Profile::from_content_encoding("gzip").is_err());
+    }
+
+    #[test]
+    fn json_with_millis() {
+        let profile = Profile {
+            timestamps: Timestamps::Millis,
+            ..Profile::DEBUG
+        };
+        let encoded = profile.encode(&fixtures::cpu_raw()).unwrap();
+        assert_eq!(encoded.content_encoding, "json+ms");
+        let value: Value = serde_json::from_slice(&encoded.bytes).unwrap();
+        assert_eq!(value["manifest"]["ctime"], 1591012800000i64);
+
+        let packet: Packet<Cpu> = decode(&encoded.content_encoding, &encoded.bytes).unwrap();
+        assert_eq!(packet.manifest, fixtures::cpu_manifest());
+    }
+
+    #[cfg(all(feature = "msgpack", feature = "zstd"))]
+    #[test]
+    fn compact_round_trips() {
+        let packet = fixtures::netstat_raw();
+        let compact = Profile::COMPACT.encode(&packet).unwrap();
+        let standard = Profile::STANDARD.encode(&packet).unwrap();
+        assert!(compact.bytes.len() < standard.bytes.len());
+        assert_eq!(
+            decode::<Value>(&compact.content_encoding, &compact.bytes).unwrap(),
+            packet
+        );
+    }
+
+    #[cfg(not(feature = "msgpack"))]
+    #[test]
+    fn compact_needs_features() {
+        assert!(matches!(
+            Profile::COMPACT.encode(&fixtures::cpu_raw()),
+            Err(Error::Unsupported("msgpack"))
+        ));
+    }
+
+    #[test]
+    fn configured() {
+        let profiles: Profiles = serde_json::from_value(serde_json::json!({
+            "archive": { "format": "json", "compression": "zstd" },
+            "debug": { "format": "json", "timestamps": "millis" }
+        }))
+        .unwrap();
+        assert_eq!(
+            profiles.get("archive").unwrap(),
+            Profile {
+                compression: Compression::Zstd,
+                ..Profile::STANDARD
+            }
+        );
+        assert_eq!(
+            profiles.get("debug").unwrap().timestamps,
+            Timestamps::Millis
+        );
+        assert_eq!(profiles.get("compact").unwrap(), Profile::COMPACT);
+        assert!(matches!(profiles.get("fast"), Err(Error::Unknown(_))));
+    }
+}
@@ -0,0 +1,213 @@
+//! Extracting a few content fields from a heavyweight packet.
+//!
+//! A [`Projection`] picks values out of a packet's content by JSON pointer
+//! and places them in a new, smaller packet: either in its content, or in
+//! its labels. Edge collectors use projections to produce lightweight index
+//! or summary packets alongside the full payloads they forward.
+//!
+//! Projections can be configured; in YAML:
+//!
+//! ```yaml
+//! kind: netstat-summary
+//! fields:
+//!   - from: /connections/0/state
+//!     to: /first_state
+//!   - from: /connections/0/remote
+//!     label: first_remote
+//! ```
+//!
+//! Because the result carries different content, a projection usually gives
+//! it a kind of its own, so consumers do not mistake a summary for the full
+//! payload.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::transform::{self, Transform};
+use crate::RawPacket;
+
+/// A configured subset of content fields.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Projection {
+    /// The kind of the projected packet. Defaults to that of the source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// The version of the projected packet. Defaults to that of the source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    #[serde(default)]
+    pub fields: Vec<Field>,
+}
+
+/// One value to extract, and where to put it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Field {
+    /// A JSON pointer into the source content.
+    pub from: String,
+    #[serde(flatten)]
+    pub target: Target,
+}
+
+/// Where a projected value goes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// A JSON pointer into the projected content. Objects along the way are
+    /// created as needed.
+    To(String),
+    /// A label on the projected packet. Strings are used as they are, and
+    /// other values in their JSON encoding.
+    Label(String),
+}
+
+impl Projection {
+    pub fn new() -> Self {
+        Projection::default()
+    }
+
+    /// Gives the projected packet its own kind and version.
+    pub fn into_kind<S: Into<String>>(mut self, kind: S, version: u32) -> Self {
+        self.kind = Some(kind.into());
+        self.version = Some(version);
+        self
+    }
+
+    /// Copies the value at `from` in the source content to `to` in the
+    /// projected content.
+    pub fn content<F: Into<String>, T: Into<String>>(mut self, from: F, to: T) -> Self {
+        self.fields.push(Field {
+            from: from.into(),
+            target: Target::To(to.into()),
+        });
+        self
+    }
+
+    /// Copies the value at `from` in the source content to a label.
+    pub fn label<F: Into<String>, K: Into<String>>(mut self, from: F, key: K) -> Self {
+        self.fields.push(Field {
+            from: from.into(),
+            target: Target::Label(key.into()),
+        });
+        self
+    }
+
+    /// Projects a packet.
+    ///
+    /// The projected packet keeps the source's provenance, and its manifest
+    /// apart from its kind and version if the projection sets them, and the
+    /// labels it adds. Its content is an object holding the fields projected
+    /// into content. Fields absent from the source are skipped.
+    pub fn project(&self, packet: &RawPacket) -> RawPacket {
+        let mut manifest = packet.manifest.clone();
+        if let Some(kind) = &self.kind {
+            manifest.kind = kind.clone();
+        }
+        if let Some(version) = self.version {
+            manifest.version = version;
+        }
+
+        let mut content = Value::Object(Map::new());
+        for field in &self.fields {
+            let value = match packet.content.pointer(&field.from) {
+                Some(value) => value,
+                None => continue,
+            };
+            match &field.target {
+                Target::To(pointer) => insert(&mut content, pointer, value.clone()),
+                Target::Label(key) => {
+                    let value = match value {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    manifest.labels.insert(key.clone(), value);
+                }
+            }
+        }
+        let mut projected = RawPacket::new(manifest, content);
+        projected.provenance = packet.provenance.clone();
+        projected
+    }
+}
+
+/// Replaces each packet with its projection.
+impl Transform for Projection {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        Ok(Some(self.project(&packet)))
+    }
+}
+
+/// Places `value` at `pointer` within `target`, creating objects along the
+/// way and replacing any non-object in their place.
+pub(crate) fn insert(target: &mut Value, pointer: &str, value: Value) {
+    let mut slot = target;
+    for token in pointer.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        if !slot.is_object() {
+            *slot = Value::Object(Map::new());
+        }
+        slot = match slot {
+            Value::Object(fields) => fields.entry(token).or_insert(Value::Null),
+            _ => unreachable!(),
+        };
+    }
+    *slot = value;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn projects_content_and_labels() {
+        let projection = Projection::new()
+            .into_kind("netstat-summary", 1)
+            .content("/connections/0/state", "/first/state")
+            .content("/connections/1/proto", "/second_proto")
+            .content("/connections/9/state", "/missing")
+            .label("/connections/0/remote", "first_remote");
+
+        let source = fixtures::netstat_raw();
+        let summary = projection.project(&source);
+        assert_eq!(summary.manifest.kind, "netstat-summary");
+        assert_eq!(summary.manifest.ctime, source.manifest.ctime);
+        assert_eq!(
+            summary.content,
+            serde_json::json!({
+                "first": { "state": "ESTABLISHED" },
+                "second_proto": "tcp"
+            })
+        );
+        assert_eq!(
+            summary.manifest.labels["first_remote"],
+            source.content["connections"][0]["remote"]
+        );
+        assert_eq!(summary.manifest.labels["environment"], "production");
+    }
+
+    #[test]
+    fn configured() {
+        let projection: Projection = serde_json::from_value(serde_json::json!({
+            "kind": "cpu-summary",
+            "fields": [
+                { "from": "/idle", "to": "/idle" },
+                { "from": "/user", "label": "user" }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            projection,
+            Projection {
+                kind: Some("cpu-summary".into()),
+                version: None,
+                fields: vec![],
+            }
+            .content("/idle", "/idle")
+            .label("/user", "user")
+        );
+
+        let summary = projection.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert_eq!(summary.content, serde_json::json!({ "idle": 83.25 }));
+        assert_eq!(summary.manifest.labels["user"], "12.5");
+    }
+}
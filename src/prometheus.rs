@@ -0,0 +1,704 @@
+//! Exposing pipeline internals to Prometheus.
+//!
+//! [`Metrics`] holds counters, gauges and histograms, and renders them in
+//! the Prometheus text exposition format, from [`Metrics::render`] or an
+//! endpoint of its own started with [`Metrics::serve`]. It comes with
+//! meters for the parts of a pipeline worth watching, so that operators
+//! need not wire up an exporter:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use intermodal::prometheus::Metrics;
+//! use intermodal::spool::Spool;
+//! use intermodal::transform::Transform;
+//! # let packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//! #                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": 86400
+//! # })).unwrap();
+//! # let path = std::env::temp_dir().join(format!("prometheus-doc-{}", std::process::id()));
+//!
+//! let metrics = Arc::new(Metrics::new());
+//! let ingest = metrics.meter("ingest");
+//! let spool = metrics.spool("overflow", intermodal::spool::FileSpool::open(&path)?);
+//!
+//! let packet = ingest.apply(packet)?.unwrap();
+//! spool.spool(&packet)?;
+//!
+//! let text = metrics.render();
+//! assert!(text.contains(
+//!     r#"intermodal_packets_total{stage="ingest",kind="example.org/metrics/host/uptime@1"} 1"#
+//! ));
+//! assert!(text.contains(r#"intermodal_spooled_packets_total{spool="overflow"} 1"#));
+//! # std::fs::remove_file(&path)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The meters record:
+//!
+//! | Meter | Metric | Labels |
+//! |---|---|---|
+//! | [`Metrics::meter`] | `intermodal_packets_total` | `stage`, `kind` |
+//! | [`Metrics::spool`] | `intermodal_spooled_packets_total`, `intermodal_spooled_bytes_total` | `spool` |
+//! | [`Metrics::dispatcher`] | `intermodal_dispatch_queue_depth`, `intermodal_dispatch_packets_total` | `kind`, and `outcome` |
+//! | [`Metrics::handler`] | `intermodal_handler_duration_seconds`, a histogram | `kind` |
+//!
+//! where `kind` is a packet's coordinates, such as
+//! `example.org/metrics/host/cpu@1`. Rates per kind are the rates of the
+//! counters, as Prometheus takes them. Anything else can be recorded with
+//! [`Metrics::inc_by`], [`Metrics::set_gauge`] and [`Metrics::observe`],
+//! or at each scrape by a [collector](Metrics::collect). Spooled bytes are
+//! counted as JSON envelopes.
+//!
+//! Available with the `prometheus` feature. The dispatcher and handler
+//! meters need the `async` feature as well.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "async")]
+use std::time::Instant;
+
+#[cfg(feature = "async")]
+use futures_util::future::BoxFuture;
+
+#[cfg(feature = "async")]
+use crate::dispatch::{Dispatcher, Handler, HandlerError};
+use crate::spool::Spool;
+use crate::transform::{self, Transform};
+use crate::RawPacket;
+
+/// The media type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The upper bounds of histogram buckets, in seconds, unless others are
+/// given.
+pub const DEFAULT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long [`Metrics::serve`] waits on a scraper's request, and on a
+/// scraper taking its response.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most bytes a scraper may send [`Metrics::serve`] before its request
+/// headers end.
+pub const MAX_REQUEST_BYTES: u64 = 8 << 10;
+
+/// The most connections [`Metrics::serve`] answers at once.
+pub const MAX_CONNECTIONS: usize = 16;
+
+type Labels = Vec<(String, String)>;
+type CollectFn = Box<dyn Fn(&Metrics) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::Counter => "counter",
+            Type::Gauge => "gauge",
+            Type::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f64),
+    Histogram {
+        /// The count in each bucket, not cumulative.
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Family {
+    help: Option<String>,
+    kind: Type,
+    series: BTreeMap<Labels, Value>,
+}
+
+/// A set of metrics, rendered for Prometheus to scrape.
+pub struct Metrics {
+    families: Mutex<BTreeMap<String, Family>>,
+    collectors: Mutex<Vec<CollectFn>>,
+    buckets: Vec<f64>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        f.debug_struct("Metrics")
+            .field("families", &families.keys().collect::<Vec<_>>())
+            .field("buckets", &self.buckets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    /// Metrics with histograms of the [`DEFAULT_BUCKETS`].
+    pub fn new() -> Self {
+        Metrics {
+            families: Mutex::new(BTreeMap::new()),
+            collectors: Mutex::new(Vec::new()),
+            buckets: DEFAULT_BUCKETS.to_vec(),
+        }
+    }
+
+    /// Gives histograms buckets with these upper bounds, and one for
+    /// everything above them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bounds are not increasing.
+    pub fn buckets(mut self, bounds: Vec<f64>) -> Self {
+        assert!(
+            bounds.windows(2).all(|pair| pair[0] < pair[1]),
+            "bucket bounds must increase"
+        );
+        self.buckets = bounds;
+        self
+    }
+
+    /// Sets the help text of the metric `name`.
+    pub fn help<S: Into<String>>(&self, name: &str, help: S) -> &Self {
+        let mut families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(family) = families.get_mut(name) {
+            family.help = Some(help.into());
+        } else {
+            families.insert(
+                name.to_string(),
+                Family {
+                    help: Some(help.into()),
+                    kind: Type::Gauge,
+                    series: BTreeMap::new(),
+                },
+            );
+        }
+        self
+    }
+
+    /// Adds `by` to a counter.
+    ///
+    /// # Panics
+    ///
+    /// This, like the other methods recording values, panics if `name` is a
+    /// metric of another type.
+    pub fn inc_by(&self, name: &str, labels: &[(&str, &str)], by: u64) {
+        self.update(name, labels, Type::Counter, |value| {
+            if let Value::Number(total) = value {
+                *total += by as f64;
+            }
+        });
+    }
+
+    /// Sets a counter to a total counted elsewhere.
+    pub fn set_counter(&self, name: &str, labels: &[(&str, &str)], total: u64) {
+        self.update(name, labels, Type::Counter, |value| {
+            *value = Value::Number(total as f64)
+        });
+    }
+
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, labels, Type::Gauge, |old| *old = Value::Number(value));
+    }
+
+    /// Records an observation in a histogram.
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.buckets.len());
+        self.update(name, labels, Type::Histogram, |old| {
+            if let Value::Histogram { counts, sum, count } = old {
+                counts[bucket] += 1;
+                *sum += value;
+                *count += 1;
+            }
+        });
+    }
+
+    fn update<F: FnOnce(&mut Value)>(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        kind: Type,
+        update: F,
+    ) {
+        let mut families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        let family = families.entry(name.to_string()).or_insert_with(|| Family {
+            help: None,
+            kind,
+            series: BTreeMap::new(),
+        });
+        if family.series.is_empty() && family.kind != kind {
+            // A family given only help text takes the type first recorded.
+            family.kind = kind;
+        }
+        assert!(
+            family.kind == kind,
+            "`{}` is a {}, not a {}",
+            name,
+            family.kind.name(),
+            kind.name()
+        );
+        let labels = labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let value = family.series.entry(labels).or_insert_with(|| match kind {
+            Type::Histogram => Value::Histogram {
+                counts: vec![0; self.buckets.len() + 1],
+                sum: 0.0,
+                count: 0,
+            },
+            Type::Counter | Type::Gauge => Value::Number(0.0),
+        });
+        update(value);
+    }
+
+    /// Calls `collect` before each render, to record values read from
+    /// elsewhere, such as the length of a queue.
+    pub fn collect<F>(&self, collect: F) -> &Self
+    where
+        F: Fn(&Metrics) + Send + Sync + 'static,
+    {
+        let mut collectors = self.collectors.lock().unwrap_or_else(|e| e.into_inner());
+        collectors.push(Box::new(collect));
+        self
+    }
+
+    /// The metrics in the text exposition format.
+    pub fn render(&self) -> String {
+        {
+            let collectors = self.collectors.lock().unwrap_or_else(|e| e.into_inner());
+            for collect in collectors.iter() {
+                collect(self);
+            }
+        }
+        let families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        let mut text = String::new();
+        for (name, family) in families.iter() {
+            if family.series.is_empty() {
+                continue;
+            }
+            if let Some(help) = &family.help {
+                let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+                let _ = writeln!(text, "# HELP {} {}", name, help);
+            }
+            let _ = writeln!(text, "# TYPE {} {}", name, family.kind.name());
+            for (labels, value) in &family.series {
+                match value {
+                    Value::Number(number) => {
+                        let _ = writeln!(text, "{}{} {}", name, braced(labels, None), number);
+                    }
+                    Value::Histogram { counts, sum, count } => {
+                        let mut cumulative = 0;
+                        for (i, bucket) in counts.iter().enumerate() {
+                            cumulative += bucket;
+                            let le = match self.buckets.get(i) {
+                                Some(bound) => bound.to_string(),
+                                None => "+Inf".to_string(),
+                            };
+                            let labels = braced(labels, Some(&le));
+                            let _ = writeln!(text, "{}_bucket{} {}", name, labels, cumulative);
+                        }
+                        let labels = braced(labels, None);
+                        let _ = writeln!(text, "{}_sum{} {}", name, labels, sum);
+                        let _ = writeln!(text, "{}_count{} {}", name, labels, count);
+                    }
+                }
+            }
+        }
+        text
+    }
+
+    /// Serves the metrics over HTTP at `addr`, from a thread of their own,
+    /// returning the address bound. Every request is answered with the
+    /// rendered metrics, whatever its path.
+    ///
+    /// Each connection is answered on a thread of its own, up to
+    /// [`MAX_CONNECTIONS`] at once, beyond which connections are closed
+    /// unanswered. A request taking longer than [`REQUEST_TIMEOUT`] to
+    /// arrive, or with more than [`MAX_REQUEST_BYTES`] of headers, is
+    /// dropped.
+    pub fn serve<A: ToSocketAddrs>(self: &Arc<Self>, addr: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let metrics = Arc::clone(self);
+        thread::Builder::new()
+            .name("intermodal-prometheus".to_string())
+            .spawn(move || {
+                let active = Arc::new(AtomicUsize::new(0));
+                for stream in listener.incoming().flatten() {
+                    if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+                    let slot = Slot(Arc::clone(&active));
+                    let metrics = Arc::clone(&metrics);
+                    // A scraper that goes away mid-request is no concern,
+                    // and neither is one that cannot be answered.
+                    let _ = thread::Builder::new()
+                        .name("intermodal-prometheus-conn".to_string())
+                        .spawn(move || {
+                            let _slot = slot;
+                            let _ = metrics.respond(stream);
+                        });
+                }
+            })?;
+        Ok(addr)
+    }
+
+    fn respond(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new((&stream).take(MAX_REQUEST_BYTES));
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            if line.trim_end().is_empty() {
+                break;
+            }
+        }
+        let body = self.render();
+        let mut stream = &stream;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            CONTENT_TYPE,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+
+    /// A transform counting the packets passing `stage`, by kind.
+    pub fn meter<S: Into<String>>(self: &Arc<Self>, stage: S) -> Meter {
+        Meter {
+            metrics: Arc::clone(self),
+            stage: stage.into(),
+        }
+    }
+
+    /// Wraps a spool, counting the packets and bytes set aside in it.
+    pub fn spool<N: Into<String>, S: Spool>(
+        self: &Arc<Self>,
+        name: N,
+        spool: S,
+    ) -> MeteredSpool<S> {
+        MeteredSpool {
+            metrics: Arc::clone(self),
+            name: name.into(),
+            spool,
+        }
+    }
+
+    /// Records the queue depth and counts of each started lane of a
+    /// dispatcher at each render.
+    #[cfg(feature = "async")]
+    pub fn dispatcher(&self, dispatcher: Arc<Dispatcher>) -> &Self {
+        self.collect(move |metrics| {
+            for (coordinates, stats, depth) in dispatcher.lanes() {
+                let kind = coordinates.to_string();
+                metrics.set_gauge(
+                    "intermodal_dispatch_queue_depth",
+                    &[("kind", &kind)],
+                    depth as f64,
+                );
+                for (outcome, total) in [
+                    ("queued", stats.queued),
+                    ("handled", stats.handled),
+                    ("failed", stats.failed),
+                    ("dropped", stats.dropped),
+                    ("dead_lettered", stats.dead_lettered),
+                    ("spooled", stats.spooled),
+                ] {
+                    metrics.set_counter(
+                        "intermodal_dispatch_packets_total",
+                        &[("kind", &kind), ("outcome", outcome)],
+                        total,
+                    );
+                }
+            }
+        })
+    }
+
+    /// Wraps a handler, timing how long it takes over each packet.
+    #[cfg(feature = "async")]
+    pub fn handler<H: Handler>(self: &Arc<Self>, handler: H) -> MeteredHandler<H> {
+        MeteredHandler {
+            metrics: Arc::clone(self),
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+/// The labels of a series in braces, with `le` for a histogram bucket.
+fn braced(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Gives back a connection's place under [`MAX_CONNECTIONS`] when dropped,
+/// however its thread ends.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts the packets passing a stage, from [`Metrics::meter`].
+#[derive(Debug, Clone)]
+pub struct Meter {
+    metrics: Arc<Metrics>,
+    stage: String,
+}
+
+impl Transform for Meter {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        let kind = packet.manifest.coordinates().to_string();
+        self.metrics.inc_by(
+            "intermodal_packets_total",
+            &[("stage", &self.stage), ("kind", &kind)],
+            1,
+        );
+        Ok(Some(packet))
+    }
+}
+
+/// A spool counting what is set aside in it, from [`Metrics::spool`].
+#[derive(Debug)]
+pub struct MeteredSpool<S> {
+    metrics: Arc<Metrics>,
+    name: String,
+    spool: S,
+}
+
+impl<S> MeteredSpool<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.spool
+    }
+}
+
+impl<S: Spool> Spool for MeteredSpool<S> {
+    fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+        self.spool.spool(packet)?;
+        let bytes = serde_json::to_vec(packet)?.len() as u64;
+        let labels = [("spool", self.name.as_str())];
+        self.metrics
+            .inc_by("intermodal_spooled_packets_total", &labels, 1);
+        self.metrics
+            .inc_by("intermodal_spooled_bytes_total", &labels, bytes);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}
+
+/// A handler timing itself, from [`Metrics::handler`].
+#[cfg(feature = "async")]
+pub struct MeteredHandler<H> {
+    metrics: Arc<Metrics>,
+    handler: Arc<H>,
+}
+
+#[cfg(feature = "async")]
+impl<H: Handler> Handler for MeteredHandler<H> {
+    fn handle(&self, packet: RawPacket) -> BoxFuture<'static, Result<(), HandlerError>> {
+        let kind = packet.manifest.coordinates().to_string();
+        let metrics = Arc::clone(&self.metrics);
+        let handling = self.handler.handle(packet);
+        Box::pin(async move {
+            let start = Instant::now();
+            let result = handling.await;
+            metrics.observe(
+                "intermodal_handler_duration_seconds",
+                &[("kind", &kind)],
+                start.elapsed().as_secs_f64(),
+            );
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn renders_the_text_format() {
+        let metrics = Metrics::new().buckets(vec![0.1, 1.0]);
+        metrics.help("requests_total", "Requests seen.\nAll of them.");
+        metrics.inc_by("requests_total", &[("path", "/a\"b")], 2);
+        metrics.inc_by("requests_total", &[("path", "/a\"b")], 1);
+        metrics.set_gauge("depth", &[], 7.5);
+        metrics.observe("latency_seconds", &[("kind", "cpu")], 0.05);
+        metrics.observe("latency_seconds", &[("kind", "cpu")], 3.0);
+        metrics.collect(|metrics| metrics.set_gauge("collected", &[], 1.0));
+        assert_eq!(
+            metrics.render(),
+            "# TYPE collected gauge\n\
+             collected 1\n\
+             # TYPE depth gauge\n\
+             depth 7.5\n\
+             # TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{kind=\"cpu\",le=\"0.1\"} 1\n\
+             latency_seconds_bucket{kind=\"cpu\",le=\"1\"} 1\n\
+             latency_seconds_bucket{kind=\"cpu\",le=\"+Inf\"} 2\n\
+             latency_seconds_sum{kind=\"cpu\"} 3.05\n\
+             latency_seconds_count{kind=\"cpu\"} 2\n\
+             # HELP requests_total Requests seen.\\nAll of them.\n\
+             # TYPE requests_total counter\n\
+             requests_total{path=\"/a\\\"b\"} 3\n"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "`depth` is a gauge, not a counter")]
+    fn rejects_changes_of_type() {
+        let metrics = Metrics::new();
+        metrics.set_gauge("depth", &[], 1.0);
+        metrics.inc_by("depth", &[], 1);
+    }
+
+    #[test]
+    fn serves_meters() {
+        #[derive(Default)]
+        struct Discard;
+
+        impl Spool for Discard {
+            fn spool(&self, _: &RawPacket) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let metrics = Arc::new(Metrics::new());
+        let meter = metrics.meter("ingest");
+        let spool = metrics.spool("overflow", Discard);
+        for packet in [
+            fixtures::cpu_raw(),
+            fixtures::cpu_raw(),
+            fixtures::netstat_raw(),
+        ] {
+            spool.spool(&meter.apply(packet).unwrap().unwrap()).unwrap();
+        }
+        let addr = metrics.serve("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.contains(
+            "intermodal_packets_total{stage=\"ingest\",kind=\"example.org/metrics/host/cpu@1\"} 2\n"
+        ));
+        assert!(response.contains("intermodal_spooled_packets_total{spool=\"overflow\"} 3\n"));
+    }
+
+    #[test]
+    fn answers_past_stalled_and_oversized_requests() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.inc_by("requests_total", &[], 1);
+        let addr = metrics.serve("127.0.0.1:0").unwrap();
+
+        // A scraper that never finishes its request holds up no one else.
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+
+        let mut oversized = TcpStream::connect(addr).unwrap();
+        let header = format!("X-Padding: {}\r\n", "a".repeat(MAX_REQUEST_BYTES as usize));
+        let _ = oversized.write_all(header.as_bytes());
+        let mut response = String::new();
+        let _ = oversized.read_to_string(&mut response);
+        assert_eq!(response, "");
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("requests_total 1\n"));
+        drop(stalled);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn meters_dispatchers() {
+        use crate::dispatch::Limits;
+
+        let metrics = Arc::new(Metrics::new());
+        let handler = metrics.handler(|_packet| async { Ok(()) });
+        let cpu = fixtures::cpu_manifest().coordinates();
+        let dispatcher = Arc::new(
+            Dispatcher::builder()
+                .handler(cpu.clone(), Limits::default(), handler)
+                .build(),
+        );
+        metrics.dispatcher(Arc::clone(&dispatcher));
+        dispatcher.dispatch(fixtures::cpu_raw()).await.unwrap();
+        while dispatcher.stats(&cpu).unwrap().handled == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let text = metrics.render();
+        let kind = "kind=\"example.org/metrics/host/cpu@1\"";
+        assert!(text.contains(&format!("intermodal_dispatch_queue_depth{{{}}} 0\n", kind)));
+        assert!(text.contains(&format!(
+            "intermodal_dispatch_packets_total{{{},outcome=\"handled\"}} 1\n",
+            kind
+        )));
+        assert!(text.contains(&format!(
+            "intermodal_handler_duration_seconds_count{{{}}} 1\n",
+            kind
+        )));
+    }
+}
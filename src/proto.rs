@@ -0,0 +1,432 @@
+//! Protocol buffer envelopes, for gRPC services.
+//!
+//! The messages here are those of `include/intermodal.proto`, which
+//! services in other languages can compile for themselves. A packet's
+//! content is carried either as a `google.protobuf.Any` holding a protobuf
+//! message, with [`encode`] and [`decode`], or as opaque bytes, with
+//! [`encode_bytes`] and [`decode_bytes`], so that a service already
+//! speaking protobuf wraps its messages without converting them to JSON:
+//!
+//! ```
+//! use intermodal::{proto, Manifest, Packet};
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .build()
+//!     .unwrap();
+//! let packet = Packet::new(manifest, 86400u64);
+//!
+//! let bytes = proto::encode(&packet);
+//! assert_eq!(proto::decode::<u64>(&bytes)?, packet);
+//! assert_eq!(proto::decode_header(&bytes)?.manifest.kind, "uptime");
+//! # Ok::<(), proto::Error>(())
+//! ```
+//!
+//! A `Packet` encoding decodes as a `Header` whose manifest is the
+//! packet's, skipping the content, so [`decode_header`] reads either. A
+//! packet's signature and provenance are carried with it.
+//!
+//! Available with the `proto` feature.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use prost::{Message, Name};
+
+use crate::provenance;
+use crate::signing;
+use crate::trace;
+
+/// The protobuf package of the messages.
+pub const PACKAGE: &str = "intermodal.v1";
+
+/// W3C trace context.
+#[derive(Clone, PartialEq, Message)]
+pub struct TraceContext {
+    #[prost(string, tag = "1")]
+    pub traceparent: String,
+    #[prost(string, optional, tag = "2")]
+    pub tracestate: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Manifest {
+    #[prost(string, tag = "1")]
+    pub domain: String,
+    #[prost(string, tag = "2")]
+    pub scope: String,
+    #[prost(string, tag = "3")]
+    pub kind: String,
+    #[prost(uint32, tag = "4")]
+    pub version: u32,
+    #[prost(message, optional, tag = "5")]
+    pub ctime: Option<prost_types::Timestamp>,
+    #[prost(message, optional, tag = "6")]
+    pub expires: Option<prost_types::Timestamp>,
+    #[prost(string, tag = "7")]
+    pub origin: String,
+    #[prost(map = "string, string", tag = "8")]
+    pub labels: HashMap<String, String>,
+    #[prost(message, optional, tag = "9")]
+    pub trace: Option<TraceContext>,
+    #[prost(string, optional, tag = "10")]
+    pub correlation_id: Option<String>,
+    #[prost(string, optional, tag = "11")]
+    pub reply_to: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Header {
+    #[prost(message, optional, tag = "1")]
+    pub manifest: Option<Manifest>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Signature {
+    #[prost(string, tag = "1")]
+    pub algorithm: String,
+    #[prost(string, optional, tag = "2")]
+    pub key_id: Option<String>,
+    #[prost(string, tag = "3")]
+    pub value: String,
+}
+
+/// One service's handling of an envelope.
+#[derive(Clone, PartialEq, Message)]
+pub struct Hop {
+    #[prost(string, tag = "1")]
+    pub processor: String,
+    #[prost(message, optional, tag = "2")]
+    pub at: Option<prost_types::Timestamp>,
+    #[prost(string, tag = "3")]
+    pub action: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Packet {
+    #[prost(message, optional, tag = "1")]
+    pub manifest: Option<Manifest>,
+    #[prost(oneof = "packet::Content", tags = "2, 3")]
+    pub content: Option<packet::Content>,
+    #[prost(message, optional, tag = "4")]
+    pub signature: Option<Signature>,
+    /// Oldest first.
+    #[prost(message, repeated, tag = "5")]
+    pub provenance: Vec<Hop>,
+}
+
+/// Types nested in [`Packet`].
+pub mod packet {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Content {
+        #[prost(bytes, tag = "2")]
+        Data(Vec<u8>),
+        #[prost(message, tag = "3")]
+        Any(prost_types::Any),
+    }
+}
+
+macro_rules! name {
+    ($($message:ident),*) => {
+        $(
+            impl Name for $message {
+                const NAME: &'static str = stringify!($message);
+                const PACKAGE: &'static str = PACKAGE;
+            }
+        )*
+    };
+}
+
+name!(TraceContext, Manifest, Header, Signature, Hop, Packet);
+
+fn timestamp(time: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn time(field: &'static str, timestamp: prost_types::Timestamp) -> Result<DateTime<Utc>, Error> {
+    u32::try_from(timestamp.nanos)
+        .ok()
+        .and_then(|nanos| DateTime::from_timestamp(timestamp.seconds, nanos))
+        .ok_or(Error::Timestamp(field))
+}
+
+impl From<&crate::Manifest> for Manifest {
+    fn from(manifest: &crate::Manifest) -> Self {
+        Manifest {
+            domain: manifest.domain.clone(),
+            scope: manifest.scope.clone(),
+            kind: manifest.kind.clone(),
+            version: manifest.version,
+            ctime: Some(timestamp(manifest.ctime)),
+            expires: manifest.expires.map(timestamp),
+            origin: manifest.origin.clone(),
+            labels: manifest.labels.clone(),
+            trace: manifest.trace.as_ref().map(|trace| TraceContext {
+                traceparent: trace.traceparent.clone(),
+                tracestate: trace.tracestate.clone(),
+            }),
+            correlation_id: manifest.correlation_id.clone(),
+            reply_to: manifest.reply_to.clone(),
+        }
+    }
+}
+
+impl TryFrom<Manifest> for crate::Manifest {
+    type Error = Error;
+
+    fn try_from(manifest: Manifest) -> Result<Self, Error> {
+        Ok(crate::Manifest {
+            domain: manifest.domain,
+            scope: manifest.scope,
+            kind: manifest.kind,
+            version: manifest.version,
+            ctime: time("ctime", manifest.ctime.ok_or(Error::Missing("ctime"))?)?,
+            expires: manifest
+                .expires
+                .map(|expires| time("expires", expires))
+                .transpose()?,
+            origin: manifest.origin,
+            labels: manifest.labels,
+            trace: manifest.trace.map(|trace| trace::TraceContext {
+                traceparent: trace.traceparent,
+                tracestate: trace.tracestate,
+            }),
+            correlation_id: manifest.correlation_id,
+            reply_to: manifest.reply_to,
+        })
+    }
+}
+
+impl From<&signing::Signature> for Signature {
+    fn from(signature: &signing::Signature) -> Self {
+        Signature {
+            algorithm: signature.algorithm.clone(),
+            key_id: signature.key_id.clone(),
+            value: signature.value.clone(),
+        }
+    }
+}
+
+impl From<Signature> for signing::Signature {
+    fn from(signature: Signature) -> Self {
+        signing::Signature {
+            algorithm: signature.algorithm,
+            key_id: signature.key_id,
+            value: signature.value,
+        }
+    }
+}
+
+impl From<&provenance::Hop> for Hop {
+    fn from(hop: &provenance::Hop) -> Self {
+        Hop {
+            processor: hop.processor.clone(),
+            at: Some(timestamp(hop.at)),
+            action: hop.action.clone(),
+        }
+    }
+}
+
+impl TryFrom<Hop> for provenance::Hop {
+    type Error = Error;
+
+    fn try_from(hop: Hop) -> Result<Self, Error> {
+        Ok(provenance::Hop {
+            processor: hop.processor,
+            at: time("at", hop.at.ok_or(Error::Missing("at"))?)?,
+            action: hop.action,
+        })
+    }
+}
+
+fn to_message<T>(packet: &crate::Packet<T>, content: packet::Content) -> Packet {
+    Packet {
+        manifest: Some(Manifest::from(&packet.manifest)),
+        content: Some(content),
+        signature: packet.signature.as_ref().map(Signature::from),
+        provenance: packet.provenance.iter().map(Hop::from).collect(),
+    }
+}
+
+/// Decodes a packet message, returning it with its content taken out.
+fn from_message(bytes: &[u8]) -> Result<(crate::Packet<()>, packet::Content), Error> {
+    let message = Packet::decode(bytes)?;
+    let manifest = message.manifest.ok_or(Error::Missing("manifest"))?;
+    let content = message.content.ok_or(Error::Missing("content"))?;
+    let mut packet = crate::Packet::new(crate::Manifest::try_from(manifest)?, ());
+    packet.signature = message.signature.map(signing::Signature::from);
+    packet.provenance = message
+        .provenance
+        .into_iter()
+        .map(provenance::Hop::try_from)
+        .collect::<Result<_, _>>()?;
+    Ok((packet, content))
+}
+
+/// Puts the content back into a packet from [`from_message`], keeping its
+/// signature, which [`Packet::map`](crate::Packet::map) would drop, and
+/// its provenance.
+fn with_content<T>(packet: crate::Packet<()>, content: T) -> crate::Packet<T> {
+    let mut with = crate::Packet::new(packet.manifest, content);
+    with.signature = packet.signature;
+    with.provenance = packet.provenance;
+    with
+}
+
+/// Encodes a packet whose content is a protobuf message, carrying it as an
+/// `Any`.
+pub fn encode<T: Message + Name>(packet: &crate::Packet<T>) -> Vec<u8> {
+    let any = prost_types::Any::from_msg(&packet.content).expect("encoding into a Vec never fails");
+    to_message(packet, packet::Content::Any(any)).encode_to_vec()
+}
+
+/// Decodes a packet whose content is an `Any` holding a `T`.
+pub fn decode<T: Message + Name + Default>(bytes: &[u8]) -> Result<crate::Packet<T>, Error> {
+    let (packet, content) = from_message(bytes)?;
+    let content = match content {
+        packet::Content::Any(any) => any.to_msg()?,
+        packet::Content::Data(_) => return Err(Error::Content("bytes")),
+    };
+    Ok(with_content(packet, content))
+}
+
+/// Encodes a packet whose content is opaque bytes.
+pub fn encode_bytes(packet: &crate::Packet<Vec<u8>>) -> Vec<u8> {
+    to_message(packet, packet::Content::Data(packet.content.clone())).encode_to_vec()
+}
+
+/// Decodes a packet whose content is opaque bytes.
+pub fn decode_bytes(bytes: &[u8]) -> Result<crate::Packet<Vec<u8>>, Error> {
+    let (packet, content) = from_message(bytes)?;
+    match content {
+        packet::Content::Data(data) => Ok(with_content(packet, data)),
+        packet::Content::Any(_) => Err(Error::Content("an Any")),
+    }
+}
+
+/// Encodes a header.
+pub fn encode_header(header: &crate::Header) -> Vec<u8> {
+    Header {
+        manifest: Some(Manifest::from(&header.manifest)),
+    }
+    .encode_to_vec()
+}
+
+/// Decodes a header, or the header of a packet.
+pub fn decode_header(bytes: &[u8]) -> Result<crate::Header, Error> {
+    let manifest = Header::decode(bytes)?
+        .manifest
+        .ok_or(Error::Missing("manifest"))?;
+    Ok(crate::Header::from_ref(&crate::Manifest::try_from(
+        manifest,
+    )?))
+}
+
+/// An error decoding a protobuf envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The input is not a valid encoding of the message.
+    Decode(prost::DecodeError),
+    /// A required field is absent.
+    Missing(&'static str),
+    /// A timestamp field is out of range.
+    Timestamp(&'static str),
+    /// The content is of the other kind, as named.
+    Content(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(e) => write!(f, "undecodable protobuf envelope: {}", e),
+            Error::Missing(field) => write!(f, "protobuf envelope has no `{}`", field),
+            Error::Timestamp(field) => write!(f, "`{}` is out of range", field),
+            Error::Content(found) => write!(f, "content is unexpectedly {}", found),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<prost::DecodeError> for Error {
+    fn from(e: prost::DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn round_trips_manifests() {
+        let mut packet = crate::Packet::new(fixtures::cpu_manifest(), b"idle 83.25".to_vec());
+        packet.manifest.expires = Some(packet.manifest.ctime + chrono::Duration::minutes(5));
+        packet.manifest.correlation_id = Some("req-7".to_string());
+        packet.signature = Some(signing::Signature {
+            algorithm: "ed25519".to_string(),
+            key_id: None,
+            value: "00ff".to_string(),
+        });
+        let received = packet.manifest.ctime + chrono::Duration::seconds(1);
+        packet.provenance = vec![
+            provenance::Hop::new("gateway@edge-01", "received").at(received),
+            provenance::Hop::new("enricher@core-02", "enriched")
+                .at(received + chrono::Duration::nanoseconds(1500)),
+        ];
+        let bytes = encode_bytes(&packet);
+        assert_eq!(decode_bytes(&bytes).unwrap(), packet);
+        assert_eq!(decode_header(&bytes).unwrap().manifest, packet.manifest);
+        assert_eq!(
+            decode::<String>(&bytes).unwrap_err(),
+            Error::Content("bytes")
+        );
+
+        let mut message = Packet::decode(&bytes[..]).unwrap();
+        message.provenance[1].at = None;
+        assert_eq!(
+            decode_bytes(&message.encode_to_vec()).unwrap_err(),
+            Error::Missing("at")
+        );
+
+        let header = fixtures::netstat_raw().header();
+        assert_eq!(decode_header(&encode_header(&header)).unwrap(), header);
+    }
+
+    #[test]
+    fn carries_messages_as_any() {
+        let packet = crate::Packet::new(fixtures::cpu_manifest(), "83.25".to_string());
+        let bytes = encode(&packet);
+        let message = Packet::decode(&bytes[..]).unwrap();
+        match message.content {
+            Some(packet::Content::Any(any)) => {
+                assert_eq!(
+                    any.type_url,
+                    "type.googleapis.com/google.protobuf.StringValue"
+                )
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(decode::<String>(&bytes).unwrap(), packet);
+        assert!(matches!(decode::<u64>(&bytes), Err(Error::Decode(_))));
+        assert_eq!(decode_bytes(&bytes).unwrap_err(), Error::Content("an Any"));
+        assert_eq!(Packet::full_name(), "intermodal.v1.Packet");
+    }
+}
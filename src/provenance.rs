@@ -0,0 +1,105 @@
+//! Recording the services an envelope passes through.
+//!
+//! A packet's [`provenance`](crate::Packet::provenance) is the ordered list
+//! of [`Hop`]s it has made: which processor handled it, when, and what it
+//! did. Each service appends its own hop with [`Packet::append_hop`] before
+//! passing the envelope on:
+//!
+//! ```
+//! use intermodal::provenance::Hop;
+//! # let mut packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "uptime", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": 86400
+//! # })).unwrap();
+//!
+//! packet.append_hop(Hop::new("collector@edge-07", "received"));
+//! packet.append_hop(Hop::new("enricher@core-02", "enriched"));
+//!
+//! let processors: Vec<_> = packet.provenance.iter().map(|hop| &hop.processor).collect();
+//! assert_eq!(processors, ["collector@edge-07", "enricher@core-02"]);
+//! ```
+//!
+//! Provenance sits beside the manifest rather than in it, and like the
+//! signature it is left out of the [canonical encoding](crate::canonical),
+//! so that recording a hop neither breaks a signature nor changes a content
+//! digest. It is carried by encodings of the whole packet, and kept by
+//! [`Packet::map`]; the [framing](crate::framing) format drops it.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::Packet;
+
+/// One service's handling of an envelope.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Hop {
+    /// The service that handled the envelope, e.g. `enricher@core-02`.
+    pub processor: String,
+    /// When it handled it.
+    pub at: DateTime<Utc>,
+    /// What it did, e.g. `received`, `enriched` or `forwarded`.
+    pub action: String,
+}
+
+impl Hop {
+    /// A hop made now.
+    pub fn new<P: Into<String>, A: Into<String>>(processor: P, action: A) -> Self {
+        Hop {
+            processor: processor.into(),
+            at: Utc::now(),
+            action: action.into(),
+        }
+    }
+
+    pub fn at(mut self, at: DateTime<Utc>) -> Self {
+        self.at = at;
+        self
+    }
+}
+
+impl<T> Packet<T> {
+    /// Records a hop after those the packet has already made.
+    pub fn append_hop(&mut self, hop: Hop) {
+        self.provenance.push(hop);
+    }
+
+    /// Whether `processor` has handled the packet.
+    pub fn has_visited(&self, processor: &str) -> bool {
+        self.provenance.iter().any(|hop| hop.processor == processor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use chrono::TimeZone;
+
+    #[test]
+    fn travels_with_packets() {
+        let mut packet = fixtures::cpu_raw();
+        let json = serde_json::to_value(&packet).unwrap();
+        assert!(json.get("provenance").is_none());
+        let canonical = packet.canonical_bytes().unwrap();
+
+        let at = Utc.with_ymd_and_hms(2020, 6, 1, 12, 0, 1).unwrap();
+        packet.append_hop(Hop::new("collector@edge-07", "received").at(at));
+        packet.append_hop(Hop::new("enricher@core-02", "enriched"));
+        assert!(packet.has_visited("collector@edge-07"));
+        assert!(!packet.has_visited("archiver"));
+        assert_eq!(packet.canonical_bytes().unwrap(), canonical);
+
+        let json = serde_json::to_string(&packet).unwrap();
+        assert!(json.contains(
+            r#""provenance":[{"processor":"collector@edge-07","at":"2020-06-01T12:00:01Z","action":"received"}"#
+        ));
+        let decoded: crate::RawPacket = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, packet);
+
+        let mapped = decoded.map(|content| content["user"].clone());
+        assert_eq!(mapped.provenance, packet.provenance);
+    }
+}
@@ -0,0 +1,317 @@
+//! Accounting and limiting what each origin sends.
+//!
+//! [`Quotas`] counts the packets and bytes each origin sends, both within
+//! the current window and in total, so that platform teams can bill
+//! producer teams for what they send and hold them to agreed limits. Each
+//! origin has a [`Quota`]: a window length and optional packet and byte
+//! limits per window. Windows are aligned to the Unix epoch, so a one-hour
+//! window runs from the top of one hour to the next. What happens to
+//! packets beyond a limit depends on the [`Action`].
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use intermodal::quota::{Action, Quota, Quotas};
+//!
+//! let quotas = Quotas::new(Quota::new(Duration::from_secs(60)).packets(1000))
+//!     .origin("batch01", Quota::new(Duration::from_secs(3600)).bytes(1 << 30))
+//!     .action(Action::Drop);
+//! ```
+//!
+//! As a [`Transform`], quotas measure each packet by its JSON encoding.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::transform::{self, Transform};
+use crate::{Format, RawPacket};
+
+/// Limits on what an origin may send per window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub window: Duration,
+    pub packets: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+impl Quota {
+    /// A quota with windows of the given length, and no limits yet.
+    pub fn new(window: Duration) -> Self {
+        Quota {
+            window,
+            packets: None,
+            bytes: None,
+        }
+    }
+
+    pub fn packets(mut self, limit: u64) -> Self {
+        self.packets = Some(limit);
+        self
+    }
+
+    pub fn bytes(mut self, limit: u64) -> Self {
+        self.bytes = Some(limit);
+        self
+    }
+}
+
+/// What to do with a packet that would exceed its origin's quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Let it through, counting it as over quota.
+    Observe,
+    Drop,
+    /// Fail the transform with an error naming the origin.
+    Reject,
+}
+
+/// The limit a packet would exceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Packets(u64),
+    Bytes(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Packets(limit) => write!(f, "{} packets", limit),
+            Limit::Bytes(limit) => write!(f, "{} bytes", limit),
+        }
+    }
+}
+
+/// The outcome of [`Quotas::admit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Admitted,
+    Exceeded(Limit),
+}
+
+/// What an origin has sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub origin: String,
+    /// When the current window started. The window counts are for the
+    /// window the origin last sent in, which may have since ended.
+    pub window_start: DateTime<Utc>,
+    /// Packets admitted within the current window.
+    pub packets: u64,
+    /// Bytes admitted within the current window.
+    pub bytes: u64,
+    /// Packets over quota within the current window, admitted or not.
+    pub exceeded: u64,
+    /// Packets admitted since accounting began.
+    pub total_packets: u64,
+    /// Bytes admitted since accounting began.
+    pub total_bytes: u64,
+    /// Packets over quota since accounting began.
+    pub total_exceeded: u64,
+}
+
+impl Usage {
+    fn new(origin: &str, window_start: DateTime<Utc>) -> Self {
+        Usage {
+            origin: origin.to_string(),
+            window_start,
+            packets: 0,
+            bytes: 0,
+            exceeded: 0,
+            total_packets: 0,
+            total_bytes: 0,
+            total_exceeded: 0,
+        }
+    }
+}
+
+/// Per-origin quotas and usage.
+#[derive(Debug)]
+pub struct Quotas {
+    default: Quota,
+    origins: HashMap<String, Quota>,
+    action: Action,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Quotas {
+    /// Applies `default` to every origin without a quota of its own, only
+    /// observing packets beyond it.
+    pub fn new(default: Quota) -> Self {
+        Quotas {
+            default,
+            origins: HashMap::new(),
+            action: Action::Observe,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gives an origin a quota of its own.
+    pub fn origin<S: Into<String>>(mut self, origin: S, quota: Quota) -> Self {
+        self.origins.insert(origin.into(), quota);
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// The quota that applies to an origin.
+    pub fn quota(&self, origin: &str) -> Quota {
+        self.origins.get(origin).copied().unwrap_or(self.default)
+    }
+
+    /// Accounts for a packet of `bytes` bytes from `origin`, returning
+    /// whether it fits within the quota. Packets that do not fit count only
+    /// towards `exceeded`, unless the action is [`Action::Observe`], which
+    /// admits them anyway.
+    pub fn admit(&self, origin: &str, bytes: u64) -> Decision {
+        self.admit_at(origin, bytes, Utc::now())
+    }
+
+    fn admit_at(&self, origin: &str, bytes: u64, now: DateTime<Utc>) -> Decision {
+        let quota = self.quota(origin);
+        let window_start = window_start(now, quota.window);
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let usage = usage
+            .entry(origin.to_string())
+            .or_insert_with(|| Usage::new(origin, window_start));
+        if usage.window_start != window_start {
+            usage.window_start = window_start;
+            usage.packets = 0;
+            usage.bytes = 0;
+            usage.exceeded = 0;
+        }
+
+        let exceeded = match (quota.packets, quota.bytes) {
+            (Some(limit), _) if usage.packets + 1 > limit => Some(Limit::Packets(limit)),
+            (_, Some(limit)) if usage.bytes + bytes > limit => Some(Limit::Bytes(limit)),
+            _ => None,
+        };
+        if exceeded.is_some() {
+            usage.exceeded += 1;
+            usage.total_exceeded += 1;
+        }
+        if exceeded.is_none() || self.action == Action::Observe {
+            usage.packets += 1;
+            usage.bytes += bytes;
+            usage.total_packets += 1;
+            usage.total_bytes += bytes;
+        }
+        match exceeded {
+            Some(limit) => Decision::Exceeded(limit),
+            None => Decision::Admitted,
+        }
+    }
+
+    /// What an origin has sent, if anything.
+    pub fn usage(&self, origin: &str) -> Option<Usage> {
+        let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        usage.get(origin).cloned()
+    }
+
+    /// What every origin has sent, by origin.
+    pub fn report(&self) -> Vec<Usage> {
+        let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let mut report: Vec<_> = usage.values().cloned().collect();
+        report.sort_unstable_by(|a, b| a.origin.cmp(&b.origin));
+        report
+    }
+
+    fn apply_at(
+        &self,
+        packet: RawPacket,
+        now: DateTime<Utc>,
+    ) -> Result<Option<RawPacket>, transform::Error> {
+        let bytes = packet
+            .estimated_encoded_size(Format::Json)
+            .map_err(|e| transform::Error::new(e.to_string()))?;
+        match self.admit_at(&packet.manifest.origin, bytes as u64, now) {
+            Decision::Admitted => Ok(Some(packet)),
+            Decision::Exceeded(limit) => match self.action {
+                Action::Observe => Ok(Some(packet)),
+                Action::Drop => Ok(None),
+                Action::Reject => Err(transform::Error::new(format!(
+                    "origin `{}` is over its quota of {}",
+                    packet.manifest.origin, limit
+                ))),
+            },
+        }
+    }
+}
+
+impl Transform for Quotas {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.apply_at(packet, Utc::now())
+    }
+}
+
+/// The start of the epoch-aligned window containing `now`.
+fn window_start(now: DateTime<Utc>, window: Duration) -> DateTime<Utc> {
+    let window = window.as_millis().max(1) as i64;
+    let millis = now.timestamp_millis();
+    Utc.timestamp_millis_opt(millis - millis.rem_euclid(window))
+        .single()
+        .unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_600_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn accounts_per_window() {
+        let quotas = Quotas::new(Quota::new(Duration::from_secs(100)).packets(2));
+        assert_eq!(quotas.admit_at("a", 10, at(0)), Decision::Admitted);
+        assert_eq!(quotas.admit_at("a", 10, at(1)), Decision::Admitted);
+        assert_eq!(
+            quotas.admit_at("a", 10, at(2)),
+            Decision::Exceeded(Limit::Packets(2))
+        );
+        assert_eq!(quotas.admit_at("b", 10, at(2)), Decision::Admitted);
+
+        let usage = quotas.usage("a").unwrap();
+        assert_eq!((usage.packets, usage.bytes, usage.exceeded), (3, 30, 1));
+        assert_eq!(usage.window_start, at(0));
+
+        assert_eq!(quotas.admit_at("a", 5, at(100)), Decision::Admitted);
+        let usage = quotas.usage("a").unwrap();
+        assert_eq!((usage.packets, usage.bytes, usage.exceeded), (1, 5, 0));
+        assert_eq!((usage.total_packets, usage.total_bytes), (4, 35));
+        assert_eq!(usage.total_exceeded, 1);
+
+        let origins: Vec<_> = quotas.report().into_iter().map(|u| u.origin).collect();
+        assert_eq!(origins, ["a", "b"]);
+    }
+
+    #[test]
+    fn enforces() {
+        let packet = fixtures::cpu_raw();
+        let size = packet.estimated_encoded_size(Format::Json).unwrap() as u64;
+        let quota = Quota::new(Duration::from_secs(3600)).bytes(size * 2);
+        let quotas = Quotas::new(Quota::new(Duration::from_secs(60)))
+            .origin(packet.manifest.origin.clone(), quota)
+            .action(Action::Drop);
+        assert!(quotas.apply_at(packet.clone(), at(0)).unwrap().is_some());
+        assert!(quotas.apply_at(packet.clone(), at(0)).unwrap().is_some());
+        assert!(quotas.apply_at(packet.clone(), at(0)).unwrap().is_none());
+        assert_eq!(
+            quotas.usage(&packet.manifest.origin).unwrap().bytes,
+            size * 2
+        );
+
+        let quotas =
+            Quotas::new(Quota::new(Duration::from_secs(60)).packets(0)).action(Action::Reject);
+        let error = quotas.apply_at(packet, at(0)).unwrap_err();
+        assert!(error.to_string().contains("over its quota of 0 packets"));
+    }
+}
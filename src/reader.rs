@@ -0,0 +1,336 @@
+//! Reading envelopes from a transport in bounded batches.
+//!
+//! A [`BatchReader`] pulls frames, each the JSON encoding of one envelope,
+//! from any stream of byte buffers. It decodes each frame's [`Header`] as it
+//! arrives but leaves the content encoded until the consumer asks for it,
+//! and groups frames into [`Batch`]es bounded by count and by bytes.
+//!
+//! Batches hold a share of a fixed in-flight byte budget until they are
+//! acknowledged. Once the budget is spent the reader stops pulling from the
+//! transport, so a burst of traffic backs up in the transport rather than in
+//! the consumer's memory.
+//!
+//! ```no_run
+//! # async fn run<S>(frames: S)
+//! # where S: futures_util::Stream<Item = std::io::Result<Vec<u8>>> + Unpin {
+//! use intermodal::reader::BatchReader;
+//! use intermodal::RawPacket;
+//!
+//! let mut reader = BatchReader::new(frames);
+//! while let Some(batch) = reader.next_batch().await {
+//!     let batch = batch.expect("transport failed");
+//!     for frame in batch.frames() {
+//!         if frame.header.manifest.kind == "cpu" {
+//!             let packet: RawPacket = frame.decode().unwrap();
+//!             // ...
+//!         }
+//!     }
+//!     batch.ack();
+//! }
+//! # }
+//! ```
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+
+use futures_util::{FutureExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{Header, Packet};
+
+/// Bounds on the batches a [`BatchReader`] yields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The most frames in one batch.
+    pub max_count: usize,
+    /// The most bytes of frames in one batch. A batch always holds at least
+    /// one frame, however large.
+    pub max_bytes: usize,
+    /// The most bytes of frames held in batches that have not yet been
+    /// acknowledged. A single frame larger than this is admitted on its own
+    /// once everything else has been acknowledged.
+    pub max_in_flight_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_count: 1000,
+            max_bytes: 1 << 20,
+            max_in_flight_bytes: 8 << 20,
+        }
+    }
+}
+
+/// One envelope read from the transport, with its header decoded.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub header: Header,
+    bytes: Vec<u8>,
+}
+
+impl Frame {
+    /// Decodes the whole envelope.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<Packet<T>, serde_json::Error> {
+        serde_json::from_slice(&self.bytes)
+    }
+
+    /// The envelope as it was read from the transport.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// A frame whose header could not be decoded.
+#[derive(Debug)]
+pub struct Rejected {
+    pub bytes: Vec<u8>,
+    pub error: serde_json::Error,
+}
+
+/// A bounded group of frames.
+///
+/// A batch holds its frames' share of the reader's in-flight budget until
+/// it is acknowledged with [`ack`](Batch::ack). Dropping a batch also
+/// returns its share.
+#[derive(Debug)]
+pub struct Batch {
+    frames: Vec<Frame>,
+    rejected: Vec<Rejected>,
+    bytes: usize,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Batch {
+    /// The frames whose headers were decoded, in the order they were read.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The frames whose headers could not be decoded.
+    pub fn rejected(&self) -> &[Rejected] {
+        &self.rejected
+    }
+
+    /// The number of frames in the batch, including rejected ones.
+    pub fn len(&self) -> usize {
+        self.frames.len() + self.rejected.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total size of the batch's frames.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Acknowledges the batch, returning its share of the in-flight budget
+    /// to the reader.
+    pub fn ack(self) {}
+
+    fn add(&mut self, bytes: Vec<u8>, permit: OwnedSemaphorePermit) {
+        self.bytes += bytes.len();
+        match self.permit.as_mut() {
+            Some(held) => held.merge(permit),
+            None => self.permit = Some(permit),
+        }
+        match serde_json::from_slice(&bytes) {
+            Ok(header) => self.frames.push(Frame { header, bytes }),
+            Err(error) => self.rejected.push(Rejected { bytes, error }),
+        }
+    }
+}
+
+/// Reads envelopes from a stream of frames in bounded batches.
+pub struct BatchReader<S> {
+    frames: S,
+    limits: Limits,
+    budget: Arc<Semaphore>,
+    pending: Option<Vec<u8>>,
+    error: Option<io::Error>,
+}
+
+impl<S> BatchReader<S>
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+    pub fn new(frames: S) -> Self {
+        BatchReader::with_limits(frames, Limits::default())
+    }
+
+    pub fn with_limits(frames: S, limits: Limits) -> Self {
+        let budget = limits.max_in_flight_bytes.clamp(1, Semaphore::MAX_PERMITS);
+        BatchReader {
+            frames,
+            limits,
+            budget: Arc::new(Semaphore::new(budget)),
+            pending: None,
+            error: None,
+        }
+    }
+
+    /// The number of bytes held by batches that have not been acknowledged.
+    pub fn in_flight_bytes(&self) -> usize {
+        self.limits
+            .max_in_flight_bytes
+            .saturating_sub(self.budget.available_permits())
+    }
+
+    /// Reads the next batch, or `None` once the transport is exhausted.
+    ///
+    /// Waits for at least one frame, then adds whatever further frames are
+    /// already available without waiting, up to the batch limits. A
+    /// transport error is returned only after any frames read before it have
+    /// been yielded.
+    pub async fn next_batch(&mut self) -> Option<io::Result<Batch>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+
+        let mut batch = Batch {
+            frames: Vec::new(),
+            rejected: Vec::new(),
+            bytes: 0,
+            permit: None,
+        };
+        // The first frame is parked in `pending` while waiting on the budget,
+        // so that it survives the future being dropped.
+        if self.pending.is_none() {
+            match self.frames.next().await {
+                Some(Ok(bytes)) => self.pending = Some(bytes),
+                Some(Err(e)) => return Some(Err(e)),
+                None => return None,
+            }
+        }
+        let len = self.pending.as_ref().map_or(0, Vec::len);
+        let permit = self.reserve(len).await;
+        batch.add(self.pending.take().unwrap_or_default(), permit);
+
+        while batch.len() < self.limits.max_count && batch.bytes < self.limits.max_bytes {
+            match self.frames.next().now_or_never() {
+                // The batch cannot be acknowledged while it is being built,
+                // so waiting on the budget here could wait forever. Hold the
+                // frame over for the next batch instead.
+                Some(Some(Ok(bytes))) => match self.try_reserve(bytes.len()) {
+                    Some(permit) => batch.add(bytes, permit),
+                    None => {
+                        self.pending = Some(bytes);
+                        break;
+                    }
+                },
+                Some(Some(Err(e))) => {
+                    self.error = Some(e);
+                    break;
+                }
+                Some(None) | None => break,
+            }
+        }
+
+        Some(Ok(batch))
+    }
+
+    async fn reserve(&self, bytes: usize) -> OwnedSemaphorePermit {
+        Arc::clone(&self.budget)
+            .acquire_many_owned(self.permits(bytes))
+            .await
+            .expect("the budget is never closed")
+    }
+
+    fn try_reserve(&self, bytes: usize) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.budget)
+            .try_acquire_many_owned(self.permits(bytes))
+            .ok()
+    }
+
+    fn permits(&self, bytes: usize) -> u32 {
+        let permits = bytes.clamp(1, self.limits.max_in_flight_bytes.max(1));
+        u32::try_from(permits).unwrap_or(u32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::stream;
+
+    use super::*;
+    use crate::{fixtures, RawPacket};
+
+    fn frames(n: usize) -> Vec<io::Result<Vec<u8>>> {
+        (0..n)
+            .map(|i| {
+                let mut packet = fixtures::cpu_raw();
+                packet.manifest.labels.insert("seq".into(), i.to_string());
+                Ok(serde_json::to_vec(&packet).unwrap())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn bounds_batches() {
+        let limits = Limits {
+            max_count: 3,
+            ..Limits::default()
+        };
+        let mut reader = BatchReader::with_limits(stream::iter(frames(7)), limits);
+        let mut sizes = Vec::new();
+        while let Some(batch) = reader.next_batch().await {
+            let batch = batch.unwrap();
+            sizes.push(batch.len());
+            let packet: RawPacket = batch.frames()[0].decode().unwrap();
+            assert_eq!(packet.content["user"], 12.5);
+        }
+        assert_eq!(sizes, vec![3, 3, 1]);
+    }
+
+    #[tokio::test]
+    async fn waits_for_acknowledgment() {
+        let frame_len = frames(1)[0].as_ref().unwrap().len();
+        let limits = Limits {
+            max_count: 2,
+            max_bytes: usize::MAX,
+            max_in_flight_bytes: frame_len * 2,
+        };
+        let mut reader = BatchReader::with_limits(stream::iter(frames(5)), limits);
+
+        let first = reader.next_batch().await.unwrap().unwrap();
+        assert_eq!(reader.in_flight_bytes(), frame_len * 2);
+        let blocked = tokio::time::timeout(Duration::from_millis(20), reader.next_batch()).await;
+        assert!(blocked.is_err());
+
+        first.ack();
+        let second = reader.next_batch().await.unwrap().unwrap();
+        assert_eq!(second.len(), 2);
+        second.ack();
+
+        // A batch stops short rather than waiting on its own budget.
+        let limits = Limits {
+            max_count: 3,
+            ..limits
+        };
+        let mut reader = BatchReader::with_limits(stream::iter(frames(3)), limits);
+        let first = reader.next_batch().await.unwrap().unwrap();
+        assert_eq!(first.len(), 2);
+        first.ack();
+        assert_eq!(reader.next_batch().await.unwrap().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reports_bad_frames_and_errors() {
+        let mut input = frames(1);
+        input.push(Ok(b"{\"manifest\":{}}".to_vec()));
+        input.push(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed")));
+        let mut reader = BatchReader::new(stream::iter(input));
+
+        let batch = reader.next_batch().await.unwrap().unwrap();
+        assert_eq!(batch.frames().len(), 1);
+        assert_eq!(batch.rejected().len(), 1);
+        assert!(reader.next_batch().await.unwrap().is_err());
+        assert!(reader.next_batch().await.is_none());
+    }
+}
@@ -0,0 +1,273 @@
+//! Dispatching envelopes to typed handlers by their coordinates.
+//!
+//! A [`Registry`] holds one handler per set of [`Coordinates`], each taking
+//! packets with content of its own concrete type. [`Registry::dispatch`]
+//! reads an envelope's header, picks the handler registered for its
+//! coordinates, and decodes the content as that handler's type:
+//!
+//! ```
+//! use intermodal::registry::Registry;
+//! use intermodal::{Coordinates, Packet};
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Cpu {
+//!     idle: f64,
+//! }
+//!
+//! let mut registry = Registry::new();
+//! registry.register(
+//!     Coordinates::new("example.org", "metrics/host", "cpu", 1),
+//!     |packet: Packet<Cpu>| format!("idle {}", packet.content.idle),
+//! );
+//!
+//! let bytes = br#"{
+//!     "manifest": { "domain": "example.org", "scope": "metrics/host", "kind": "cpu",
+//!                   "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//!     "content": { "user": 12.5, "system": 4.25, "idle": 83.25 }
+//! }"#;
+//! assert_eq!(registry.dispatch(bytes).unwrap(), "idle 83.25");
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+
+use crate::version_policy::VersionPolicy;
+use crate::{Coordinates, Header, Packet, RawPacket};
+
+/// An envelope on its way to a handler, either still encoded or already
+/// decoded as a raw packet.
+enum Input<'a> {
+    Bytes(&'a [u8]),
+    Raw(Box<RawPacket>),
+}
+
+type Entry<R> = Box<dyn Fn(Input<'_>) -> Result<R, serde_json::Error> + Send + Sync>;
+
+/// Typed handlers keyed by the coordinates of the content they accept.
+///
+/// Every handler returns the same type `R`, which may be `()`, a `Result`,
+/// or whatever else the consumer needs back.
+pub struct Registry<R> {
+    handlers: HashMap<Coordinates, Entry<R>>,
+    fallback: Option<Box<dyn Fn(RawPacket) -> R + Send + Sync>>,
+    policy: VersionPolicy,
+}
+
+impl<R> Registry<R> {
+    pub fn new() -> Self {
+        Registry {
+            handlers: HashMap::new(),
+            fallback: None,
+            policy: VersionPolicy::Exact,
+        }
+    }
+
+    /// Registers the handler for content with the given coordinates,
+    /// replacing any handler registered for them before.
+    pub fn register<T, F>(&mut self, coordinates: Coordinates, handler: F) -> &mut Self
+    where
+        T: DeserializeOwned,
+        F: Fn(Packet<T>) -> R + Send + Sync + 'static,
+    {
+        let entry: Entry<R> = Box::new(move |input| {
+            let packet = match input {
+                Input::Bytes(bytes) => serde_json::from_slice(bytes)?,
+                Input::Raw(packet) => packet.try_map(serde_json::from_value)?,
+            };
+            Ok(handler(packet))
+        });
+        self.handlers.insert(coordinates, entry);
+        self
+    }
+
+    /// Registers the handler for envelopes whose coordinates have no handler
+    /// of their own. It receives them as raw packets.
+    pub fn fallback<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(RawPacket) -> R + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Lets envelopes with no handler for their own version go to the
+    /// handler for another version of their type that `policy` accepts
+    /// them for, as [`VersionPolicy::select`] picks it. By default handlers
+    /// take only their own version.
+    pub fn version_policy(&mut self, policy: VersionPolicy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Whether a handler accepts content with the coordinates.
+    pub fn handles(&self, coordinates: &Coordinates) -> bool {
+        self.handler(coordinates).is_some()
+    }
+
+    fn handler(&self, coordinates: &Coordinates) -> Option<&Entry<R>> {
+        if let Some(handler) = self.handlers.get(coordinates) {
+            return Some(handler);
+        }
+        let supported = self.policy.select(self.handlers.keys(), coordinates)?;
+        self.handlers.get(supported)
+    }
+
+    /// Decodes a JSON envelope and passes it to the handler for its
+    /// coordinates.
+    pub fn dispatch(&self, bytes: &[u8]) -> Result<R, Error> {
+        let header: Header = serde_json::from_slice(bytes).map_err(Error::Decode)?;
+        let coordinates = header.manifest.coordinates();
+        match self.handler(&coordinates) {
+            Some(handler) => handler(Input::Bytes(bytes)).map_err(Error::Decode),
+            None => match &self.fallback {
+                Some(fallback) => {
+                    let packet = serde_json::from_slice(bytes).map_err(Error::Decode)?;
+                    Ok(fallback(packet))
+                }
+                None => Err(Error::Unregistered(coordinates)),
+            },
+        }
+    }
+
+    /// Passes an already decoded packet to the handler for its coordinates,
+    /// converting its content to the handler's type.
+    pub fn dispatch_raw(&self, packet: RawPacket) -> Result<R, Error> {
+        let coordinates = packet.manifest.coordinates();
+        match self.handler(&coordinates) {
+            Some(handler) => handler(Input::Raw(Box::new(packet))).map_err(Error::Decode),
+            None => match &self.fallback {
+                Some(fallback) => Ok(fallback(packet)),
+                None => Err(Error::Unregistered(coordinates)),
+            },
+        }
+    }
+}
+
+impl<R> Default for Registry<R> {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl<R> fmt::Debug for Registry<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .field("fallback", &self.fallback.is_some())
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// An error dispatching an envelope.
+#[derive(Debug)]
+pub enum Error {
+    /// The envelope, or its content, could not be decoded.
+    Decode(serde_json::Error),
+    /// No handler is registered for the envelope's coordinates, and there is
+    /// no fallback.
+    Unregistered(Coordinates),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(e) => write!(f, "undecodable envelope: {}", e),
+            Error::Unregistered(coordinates) => {
+                write!(f, "no handler registered for {}", coordinates)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(e) => Some(e),
+            Error::Unregistered(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu, Netstat};
+
+    fn registry() -> Registry<String> {
+        let mut registry = Registry::new();
+        registry
+            .register(fixtures::cpu_manifest().coordinates(), |p: Packet<Cpu>| {
+                format!("cpu {}", p.content.idle)
+            })
+            .register(
+                fixtures::netstat_manifest().coordinates(),
+                |p: Packet<Netstat>| format!("netstat {}", p.content.connections.len()),
+            );
+        registry
+    }
+
+    #[test]
+    fn dispatches_by_coordinates() {
+        let registry = registry();
+        assert_eq!(
+            registry.dispatch(fixtures::CPU_JSON.as_bytes()).unwrap(),
+            "cpu 83.25"
+        );
+        assert_eq!(
+            registry
+                .dispatch(fixtures::NETSTAT_JSON.as_bytes())
+                .unwrap(),
+            "netstat 2"
+        );
+        assert_eq!(
+            registry.dispatch_raw(fixtures::netstat_raw()).unwrap(),
+            "netstat 2"
+        );
+    }
+
+    #[test]
+    fn unregistered_and_undecodable() {
+        let mut registry = registry();
+        let mut packet = fixtures::cpu_raw();
+        packet.manifest.version = 2;
+        assert!(matches!(
+            registry.dispatch_raw(packet.clone()),
+            Err(Error::Unregistered(_))
+        ));
+
+        registry.fallback(|p| format!("other {}", p.manifest.version));
+        assert_eq!(registry.dispatch_raw(packet).unwrap(), "other 2");
+
+        let mut wrong = fixtures::cpu_raw();
+        wrong.content = serde_json::json!({ "idle": "none" });
+        assert!(matches!(
+            registry.dispatch_raw(wrong),
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(registry.dispatch(b"{}"), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn falls_back_to_accepted_versions() {
+        let mut registry = registry();
+        let mut older = fixtures::cpu_raw();
+        older.manifest.version = 0;
+        let mut newer = fixtures::cpu_raw();
+        newer.manifest.version = 2;
+        assert!(!registry.handles(&older.manifest.coordinates()));
+
+        registry.version_policy(VersionPolicy::Backward);
+        assert_eq!(registry.dispatch_raw(older).unwrap(), "cpu 83.25");
+        assert!(matches!(
+            registry.dispatch_raw(newer.clone()),
+            Err(Error::Unregistered(_))
+        ));
+
+        registry.version_policy(VersionPolicy::Any);
+        assert_eq!(registry.dispatch_raw(newer).unwrap(), "cpu 83.25");
+    }
+}
@@ -0,0 +1,223 @@
+//! Rewriting manifest labels by rule.
+//!
+//! A [`Relabeler`] applies [`Rule`]s to the labels of each manifest it is
+//! given: renaming keys, mapping values to new ones, and dropping keys that
+//! match a glob pattern. It is a [`Transform`], so it rewrites live traffic
+//! in a [`Pipeline`](crate::pipeline::Pipeline) or archives with a
+//! [`Rewriter`](crate::rewrite::Rewriter), as when a label naming
+//! convention changes:
+//!
+//! ```
+//! use intermodal::relabel::Relabeler;
+//! use intermodal::rewrite::Rewriter;
+//!
+//! let relabeler = Relabeler::new()
+//!     .rename("env", "environment")
+//!     .map_value("environment", "prod", "production")
+//!     .drop_matching("tmp_*");
+//!
+//! let archive = br#"{"manifest":{"domain":"example.org","scope":"metrics/host","kind":"uptime","version":1,"ctime":"2020-06-01T12:00:00Z","origin":"host01","labels":{"env":"prod","tmp_batch":"7"}},"content":86400}
+//! "#;
+//! let mut rewritten = Vec::new();
+//! Rewriter::new().stage(relabeler).run(&archive[..], &mut rewritten)?;
+//! assert!(String::from_utf8(rewritten)?.contains(r#""labels":{"environment":"production"}"#));
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Rules apply in the order they were added, each to the labels as the
+//! rules before it left them. Renaming a key onto one already present
+//! replaces that label. Rules can also be read from configuration, as a
+//! list such as:
+//!
+//! ```json
+//! [
+//!   { "rename": { "from": "env", "to": "environment" } },
+//!   { "map": { "key": "environment", "values": { "prod": "production" } } },
+//!   { "drop": "tmp_*" }
+//! ]
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::selector::Pattern;
+use crate::transform::{self, Transform};
+use crate::{Manifest, RawPacket};
+
+/// One rewrite of a manifest's labels.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// Moves the label at `from` to `to`.
+    Rename { from: String, to: String },
+    /// Replaces the value of the label at `key` with the one `values` maps
+    /// it to, if any.
+    Map {
+        key: String,
+        values: BTreeMap<String, String>,
+    },
+    /// Removes the labels whose keys match the glob pattern.
+    Drop(String),
+}
+
+impl Rule {
+    /// Applies the rule, returning whether it changed anything.
+    fn apply(&self, manifest: &mut Manifest) -> bool {
+        let labels = &mut manifest.labels;
+        match self {
+            Rule::Rename { from, to } => match labels.remove(from) {
+                Some(value) => {
+                    labels.insert(to.clone(), value);
+                    from != to
+                }
+                None => false,
+            },
+            Rule::Map { key, values } => match labels.get_mut(key) {
+                Some(value) => match values.get(value.as_str()) {
+                    Some(mapped) if mapped != value => {
+                        *value = mapped.clone();
+                        true
+                    }
+                    _ => false,
+                },
+                None => false,
+            },
+            Rule::Drop(pattern) => {
+                let pattern = Pattern(pattern.clone());
+                let before = labels.len();
+                labels.retain(|key, _| !pattern.matches(key));
+                labels.len() != before
+            }
+        }
+    }
+}
+
+/// Applies label rules to manifests.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Relabeler {
+    rules: Vec<Rule>,
+}
+
+impl Relabeler {
+    pub fn new() -> Self {
+        Relabeler::default()
+    }
+
+    /// A relabeler applying `rules` in order.
+    pub fn from_rules(rules: Vec<Rule>) -> Self {
+        Relabeler { rules }
+    }
+
+    /// Adds a rule to apply after those already added.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Renames the label at `from` to `to`.
+    pub fn rename<F: Into<String>, T: Into<String>>(self, from: F, to: T) -> Self {
+        self.rule(Rule::Rename {
+            from: from.into(),
+            to: to.into(),
+        })
+    }
+
+    /// Replaces the value `from` of the label at `key` with `to`. Values
+    /// mapped for the same key by consecutive calls share a rule, so that
+    /// values can be swapped.
+    pub fn map_value<K, F, T>(mut self, key: K, from: F, to: T) -> Self
+    where
+        K: Into<String>,
+        F: Into<String>,
+        T: Into<String>,
+    {
+        let key = key.into();
+        if let Some(Rule::Map { key: last, values }) = self.rules.last_mut() {
+            if *last == key {
+                values.insert(from.into(), to.into());
+                return self;
+            }
+        }
+        let values = BTreeMap::from([(from.into(), to.into())]);
+        self.rule(Rule::Map { key, values })
+    }
+
+    /// Drops the labels whose keys match the glob `pattern`, in which `*`
+    /// matches any run of characters and `?` any one.
+    pub fn drop_matching<S: Into<String>>(self, pattern: S) -> Self {
+        self.rule(Rule::Drop(pattern.into()))
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Applies every rule to the manifest's labels, returning whether they
+    /// changed.
+    pub fn relabel(&self, manifest: &mut Manifest) -> bool {
+        let mut changed = false;
+        for rule in &self.rules {
+            changed |= rule.apply(manifest);
+        }
+        changed
+    }
+}
+
+impl Transform for Relabeler {
+    /// Relabels the packet. A signature over the old labels is dropped,
+    /// since it no longer matches them.
+    fn apply(&self, mut packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        if self.relabel(&mut packet.manifest) {
+            packet.signature = None;
+        }
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn applies_rules_in_order() {
+        let relabeler = Relabeler::new()
+            .rename("datacenter", "region")
+            .map_value("region", "us-east", "use1")
+            .map_value("region", "use1", "us-east")
+            .map_value("environment", "production", "prod")
+            .drop_matching("env*");
+        let mut manifest = fixtures::cpu_manifest();
+        assert!(relabeler.relabel(&mut manifest));
+        assert_eq!(manifest.labels.len(), 1);
+        // Values mapped in one rule are swapped, not mapped twice.
+        assert_eq!(manifest.labels["region"], "use1");
+        assert!(!Relabeler::new()
+            .rename("absent", "x")
+            .relabel(&mut manifest));
+
+        let packet = relabeler.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert_eq!(packet.manifest.labels, manifest.labels);
+    }
+
+    #[test]
+    fn reads_rules_from_config() {
+        let relabeler: Relabeler = serde_json::from_str(
+            r#"[
+                { "rename": { "from": "env", "to": "environment" } },
+                { "map": { "key": "environment", "values": { "prod": "production" } } },
+                { "drop": "tmp_*" }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            relabeler,
+            Relabeler::new()
+                .rename("env", "environment")
+                .map_value("environment", "prod", "production")
+                .drop_matching("tmp_*")
+        );
+    }
+}
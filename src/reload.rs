@@ -0,0 +1,197 @@
+//! Hot reloading of configuration.
+//!
+//! A [`Reloadable`] is a shared handle to the current version of some
+//! configuration-derived value, such as a [`Router`](crate::Router) built from
+//! a [`RoutingConfig`](crate::config::RoutingConfig). Readers take a snapshot
+//! with [`Reloadable::load`] and keep using it for as long as they like;
+//! replacing the value never disturbs a snapshot already taken.
+//!
+//! New versions are always produced by a fallible loader, and the current
+//! value is only replaced once the loader succeeds, so an invalid edit leaves
+//! the running configuration untouched. With the `reload` feature, [`watch`]
+//! runs the loader whenever the file on disk changes.
+//!
+//! Loaders come with the configuration they load:
+//! [`RoutingConfig::loader`](crate::config::RoutingConfig::loader) for
+//! routers, [`MemorySchemaRegistry::loader`] for schema registries and,
+//! with the `ed25519` feature, `trust::Policy::loader` for trust policies,
+//! which reloads the partners' public keys.
+//!
+//! [`MemorySchemaRegistry::loader`]: crate::schema::MemorySchemaRegistry::loader
+
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// A shared, atomically replaceable value.
+#[derive(Debug)]
+pub struct Reloadable<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Reloadable {
+            current: Arc::new(RwLock::new(Arc::new(value))),
+        }
+    }
+
+    /// Returns a snapshot of the current value.
+    pub fn load(&self) -> Arc<T> {
+        let current = self.current.read().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(&current)
+    }
+
+    /// Replaces the current value, returning the previous one.
+    pub fn store(&self, value: T) -> Arc<T> {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *current, Arc::new(value))
+    }
+
+    /// Runs the loader against `path` and, if it succeeds, replaces the
+    /// current value with the result.
+    pub fn reload_from<P, E, F>(&self, path: P, load: F) -> Result<(), E>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&Path) -> Result<T, E>,
+    {
+        let value = load(path.as_ref())?;
+        self.store(value);
+        Ok(())
+    }
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Reloadable {
+            current: Arc::clone(&self.current),
+        }
+    }
+}
+
+#[cfg(feature = "reload")]
+pub use self::watch::{watch, Watcher};
+
+#[cfg(feature = "reload")]
+mod watch {
+    use std::path::{Path, PathBuf};
+
+    use notify::{EventKind, RecursiveMode, Watcher as _};
+
+    use super::Reloadable;
+
+    /// Keeps a file watch started by [`watch`] alive. Dropping it stops
+    /// watching.
+    pub struct Watcher {
+        _inner: notify::RecommendedWatcher,
+    }
+
+    /// Watches `path` and reloads `target` from it whenever it changes.
+    ///
+    /// The file's parent directory is watched rather than the file itself, so
+    /// that editors and deployment tools that replace the file by renaming a
+    /// new one into place are noticed. Loader failures are passed to
+    /// `on_error` and leave the current value in place.
+    pub fn watch<T, E, F, R>(
+        path: impl AsRef<Path>,
+        target: Reloadable<T>,
+        load: F,
+        on_error: R,
+    ) -> notify::Result<Watcher>
+    where
+        T: Send + Sync + 'static,
+        F: Fn(&Path) -> Result<T, E> + Send + 'static,
+        R: Fn(E) + Send + 'static,
+    {
+        let path = absolute(path.as_ref())?;
+        let dir = match path.parent() {
+            Some(dir) => dir.to_path_buf(),
+            None => path.clone(),
+        };
+        let mut inner =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(_) => return,
+                };
+                match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(_) => {}
+                    _ => return,
+                }
+                if !event.paths.iter().any(|p| p == &path) {
+                    return;
+                }
+                if let Err(e) = target.reload_from(&path, &load) {
+                    on_error(e);
+                }
+            })?;
+        inner.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(Watcher { _inner: inner })
+    }
+
+    fn absolute(path: &Path) -> notify::Result<PathBuf> {
+        if path.is_absolute() {
+            Ok(path.to_path_buf())
+        } else {
+            Ok(std::env::current_dir()?.join(path))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_survive_store() {
+        let value = Reloadable::new(1);
+        let handle = value.clone();
+        let snapshot = value.load();
+        assert_eq!(*handle.store(2), 1);
+        assert_eq!(*snapshot, 1);
+        assert_eq!(*value.load(), 2);
+    }
+
+    #[test]
+    fn failed_reload_keeps_current() {
+        let value = Reloadable::new(String::from("old"));
+        let result = value.reload_from("routes.yaml", |_| Err::<String, _>("invalid"));
+        assert_eq!(result, Err("invalid"));
+        assert_eq!(*value.load(), "old");
+
+        value
+            .reload_from("routes.yaml", |path| {
+                Ok::<_, ()>(path.display().to_string())
+            })
+            .unwrap();
+        assert_eq!(*value.load(), "routes.yaml");
+    }
+
+    #[cfg(feature = "reload")]
+    #[test]
+    fn watch_reloads_on_change() {
+        use std::time::{Duration, Instant};
+
+        let dir = std::env::temp_dir().join(format!("intermodal-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("value.txt");
+        std::fs::write(&path, "1").unwrap();
+
+        let value = Reloadable::new(1u32);
+        let load = |p: &Path| -> Result<u32, String> {
+            let text = std::fs::read_to_string(p).map_err(|e| e.to_string())?;
+            text.trim()
+                .parse()
+                .map_err(|_| format!("bad value {:?}", text))
+        };
+        let _watcher = watch(&path, value.clone(), load, |_| {}).unwrap();
+
+        std::fs::write(&path, "not a number").unwrap();
+        std::fs::write(&path, "2").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while *value.load() != 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(*value.load(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
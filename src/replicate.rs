@@ -0,0 +1,342 @@
+//! Mirroring a spool file to other destinations.
+//!
+//! A [`Replicator`] tails a newline-delimited JSON file, such as one written
+//! by a [`FileSpool`](crate::spool::FileSpool), and forwards the packets each
+//! destination's [`Selector`] matches to that destination's [`Spool`]. Each
+//! destination keeps its own byte offset with a [`Checkpointer`], so a
+//! destination that is down or slow falls behind on its own and catches up
+//! from where it stopped, without holding up the others:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use intermodal::checkpoint::FileCheckpointer;
+//! use intermodal::replicate::Replicator;
+//! use intermodal::spool::FileSpool;
+//! use intermodal::Selector;
+//!
+//! let replicator = Replicator::new("/var/spool/intermodal/archive.ndjson",
+//!                                  FileCheckpointer::new("/var/lib/intermodal/replicate"))
+//!     .destination("dr", Selector::any(), FileSpool::open("/mnt/dr/archive.ndjson")?)
+//!     .destination("billing", "domain=example.org,scope=billing".parse()?,
+//!                  FileSpool::open("/mnt/billing/archive.ndjson")?);
+//! loop {
+//!     for progress in replicator.poll()? {
+//!         if let Some(e) = &progress.error {
+//!             eprintln!("{}: {}", progress.destination, e);
+//!         }
+//!     }
+//!     std::thread::sleep(Duration::from_secs(1));
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Delivery is at least once: offsets are committed only after the
+//! destination has been flushed, so packets forwarded after the last commit
+//! are forwarded again after a crash. A trailing line without its newline is
+//! still being written, and is left for the next poll.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::{self, Checkpointer, Position};
+use crate::spool::Spool;
+use crate::{RawPacket, Selector};
+
+/// The most lines each destination reads per poll, unless set otherwise.
+const DEFAULT_BATCH: usize = 1000;
+
+/// Forwards packets from a spool file to destinations.
+pub struct Replicator<C> {
+    path: PathBuf,
+    checkpointer: C,
+    destinations: Vec<Destination>,
+    batch: usize,
+}
+
+struct Destination {
+    name: String,
+    selector: Selector,
+    spool: Box<dyn Spool>,
+}
+
+/// How far a poll took one destination.
+#[derive(Debug)]
+pub struct Progress {
+    pub destination: String,
+    /// The committed offset into the source file.
+    pub offset: u64,
+    /// Bytes of the source file beyond `offset`.
+    pub behind: u64,
+    pub forwarded: u64,
+    /// Packets the destination's selector did not match.
+    pub filtered: u64,
+    /// Lines that are not JSON envelopes, which are skipped.
+    pub malformed: u64,
+    /// The error that stopped forwarding to the destination, if any.
+    /// Forwarding resumes from `offset` on the next poll.
+    pub error: Option<io::Error>,
+}
+
+impl<C: Checkpointer> Replicator<C> {
+    /// Replicates the file at `path`, keeping each destination's offset
+    /// with `checkpointer`.
+    pub fn new<P: Into<PathBuf>>(path: P, checkpointer: C) -> Self {
+        Replicator {
+            path: path.into(),
+            checkpointer,
+            destinations: Vec::new(),
+            batch: DEFAULT_BATCH,
+        }
+    }
+
+    /// Adds a destination for the packets `selector` matches. The name
+    /// identifies the destination's checkpoint, so must stay the same from
+    /// one run to the next.
+    pub fn destination<S, D>(mut self, name: S, selector: Selector, spool: D) -> Self
+    where
+        S: Into<String>,
+        D: Spool + 'static,
+    {
+        self.destinations.push(Destination {
+            name: name.into(),
+            selector,
+            spool: Box::new(spool),
+        });
+        self
+    }
+
+    /// Sets the most lines each destination reads per poll, and so between
+    /// its commits.
+    pub fn batch(mut self, lines: usize) -> Self {
+        self.batch = lines.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Forwards what each destination has not yet received, up to a batch,
+    /// and commits their new offsets.
+    ///
+    /// A destination that fails is reported in its [`Progress`] and left
+    /// for the next poll; an error reading the source or committing an
+    /// offset stops the poll.
+    pub fn poll(&self) -> Result<Vec<Progress>, Error> {
+        self.destinations
+            .iter()
+            .map(|destination| self.poll_one(destination))
+            .collect()
+    }
+
+    fn poll_one(&self, destination: &Destination) -> Result<Progress, Error> {
+        let source = self.source(&destination.name);
+        let mut offset = match self.checkpointer.load(&source)? {
+            Some(Position::Offset { offset }) => offset,
+            Some(position) => return Err(Error::Position(position)),
+            None => 0,
+        };
+
+        let mut file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        if offset > len {
+            // The file has been truncated or replaced since the last commit.
+            offset = 0;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+
+        let mut progress = Progress {
+            destination: destination.name.clone(),
+            offset,
+            behind: 0,
+            forwarded: 0,
+            filtered: 0,
+            malformed: 0,
+            error: None,
+        };
+        let mut line = Vec::new();
+        for _ in 0..self.batch {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            let text = line.trim_ascii();
+            if !text.is_empty() {
+                match serde_json::from_slice::<RawPacket>(text) {
+                    Ok(packet) if destination.selector.matches(&packet.manifest) => {
+                        if let Err(e) = destination.spool.spool(&packet) {
+                            progress.error = Some(e);
+                            break;
+                        }
+                        progress.forwarded += 1;
+                    }
+                    Ok(_) => progress.filtered += 1,
+                    Err(_) => progress.malformed += 1,
+                }
+            }
+            offset += read as u64;
+        }
+
+        if offset != progress.offset {
+            match destination.spool.flush() {
+                Ok(()) => {
+                    self.checkpointer
+                        .commit(&source, &Position::Offset { offset })?;
+                    progress.offset = offset;
+                }
+                Err(e) => progress.error = Some(e),
+            }
+        }
+        progress.behind = len.saturating_sub(progress.offset);
+        Ok(progress)
+    }
+
+    /// The name a destination's offset is committed under.
+    fn source(&self, destination: &str) -> String {
+        format!("replicate:{}:{}", self.path.display(), destination)
+    }
+}
+
+impl<C> fmt::Debug for Replicator<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.destinations.iter().map(|d| d.name.as_str()).collect();
+        f.debug_struct("Replicator")
+            .field("path", &self.path)
+            .field("destinations", &names)
+            .field("batch", &self.batch)
+            .finish()
+    }
+}
+
+/// An error that stopped a poll.
+#[derive(Debug)]
+pub enum Error {
+    /// The source file could not be read.
+    Io(io::Error),
+    Checkpoint(checkpoint::Error),
+    /// A destination's committed position is not a file offset.
+    Position(Position),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Checkpoint(e) => write!(f, "{}", e),
+            Error::Position(position) => {
+                write!(f, "checkpoint is not a file offset: {:?}", position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Checkpoint(e) => Some(e),
+            Error::Position(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<checkpoint::Error> for Error {
+    fn from(e: checkpoint::Error) -> Self {
+        Error::Checkpoint(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::FileCheckpointer;
+    use crate::fixtures;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Collect {
+        kinds: Arc<Mutex<Vec<String>>>,
+        down: Arc<AtomicBool>,
+    }
+
+    impl Spool for Collect {
+        fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(io::Error::other("down"));
+            }
+            let mut kinds = self.kinds.lock().unwrap();
+            kinds.push(packet.manifest.kind.clone());
+            Ok(())
+        }
+    }
+
+    fn line(packet: &RawPacket) -> String {
+        format!("{}\n", serde_json::to_string(packet).unwrap())
+    }
+
+    #[test]
+    fn mirrors_per_destination() {
+        let dir = std::env::temp_dir().join(format!("intermodal-replicate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("archive.ndjson");
+        let cpu = line(&fixtures::cpu_raw());
+        let netstat = line(&fixtures::netstat_raw());
+        std::fs::write(&path, format!("{}not json\n{}", cpu, netstat)).unwrap();
+
+        let all = Collect::default();
+        let cpu_only = Collect::default();
+        let replicator = Replicator::new(&path, FileCheckpointer::new(dir.join("checkpoints")))
+            .destination("all", Selector::any(), all.clone())
+            .destination("cpu", "kind=cpu".parse().unwrap(), cpu_only.clone());
+
+        let progress = replicator.poll().unwrap();
+        assert_eq!(
+            (
+                progress[0].forwarded,
+                progress[0].malformed,
+                progress[0].behind
+            ),
+            (2, 1, 0)
+        );
+        assert_eq!((progress[1].forwarded, progress[1].filtered), (1, 1));
+        assert_eq!(*cpu_only.kinds.lock().unwrap(), ["cpu"]);
+
+        // A destination that is down falls behind on its own, and a partly
+        // written line waits for its newline.
+        cpu_only.down.store(true, Ordering::SeqCst);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        write!(file, "{}{}", cpu, &cpu[..10]).unwrap();
+        let progress = replicator.poll().unwrap();
+        assert_eq!(progress[0].forwarded, 1);
+        assert_eq!(progress[0].behind, 10);
+        assert!(progress[1].error.is_some());
+        assert_eq!(progress[1].behind as usize, cpu.len() + 10);
+
+        cpu_only.down.store(false, Ordering::SeqCst);
+        write!(file, "{}", &cpu[10..]).unwrap();
+        let replicator = Replicator::new(&path, FileCheckpointer::new(dir.join("checkpoints")))
+            .destination("all", Selector::any(), all.clone())
+            .destination("cpu", "kind=cpu".parse().unwrap(), cpu_only.clone());
+        let progress = replicator.poll().unwrap();
+        assert_eq!((progress[0].forwarded, progress[1].forwarded), (1, 2));
+        assert_eq!(*all.kinds.lock().unwrap(), ["cpu", "netstat", "cpu", "cpu"]);
+        assert_eq!(*cpu_only.kinds.lock().unwrap(), ["cpu", "cpu", "cpu"]);
+    }
+}
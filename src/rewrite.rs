@@ -0,0 +1,303 @@
+//! Rewriting archived envelopes.
+//!
+//! A [`Rewriter`] streams an archive through a series of [`Transform`]
+//! stages and writes the results to a new archive, in their original order:
+//! [`run`](Rewriter::run) reads and writes newline-delimited JSON envelopes,
+//! and [`run_framed`](Rewriter::run_framed) [frames](crate::framing), each
+//! rewritten in the format and with the checksum setting it was read with.
+//! It is meant for backfills: upgrading old content versions, with a
+//! [`Migrator`](crate::migrate::Migrator) as a stage, correcting manifests,
+//! or enriching historical captures.
+//!
+//! ```
+//! use intermodal::migrate::Migrator;
+//! use intermodal::rewrite::Rewriter;
+//! use intermodal::Coordinates;
+//!
+//! let mut migrator = Migrator::new();
+//! migrator.register_json(
+//!     Coordinates::new("example.org", "metrics/host", "uptime", 1),
+//!     |seconds| Ok(serde_json::json!({ "seconds": seconds })),
+//! );
+//! let archive = br#"{"manifest":{"domain":"example.org","scope":"metrics/host","kind":"uptime","version":1,"ctime":"2020-06-01T12:00:00Z","origin":"host01"},"content":86400}"#;
+//! let mut rewritten = Vec::new();
+//! let summary = Rewriter::new().stage(migrator).run(&archive[..], &mut rewritten)?;
+//! assert_eq!(summary.written, 1);
+//! # Ok::<(), intermodal::rewrite::Error>(())
+//! ```
+//!
+//! Whatever the stages do, each envelope keeps the `ctime` it was archived
+//! with, since a rewritten envelope describes data created at the original
+//! time.
+
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::framing::{self, FramedReader, FramedWriter};
+use crate::transform::{self, Transform};
+use crate::RawPacket;
+
+/// Streams an archive through a series of transforms.
+#[derive(Clone, Default)]
+pub struct Rewriter {
+    stages: Vec<Arc<dyn Transform>>,
+}
+
+/// Counts of what happened to the envelopes in a rewritten archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub read: u64,
+    pub written: u64,
+    pub dropped: u64,
+}
+
+impl Rewriter {
+    pub fn new() -> Self {
+        Rewriter::default()
+    }
+
+    /// Adds a stage to run after those already added.
+    pub fn stage<T: Transform + 'static>(mut self, stage: T) -> Self {
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    /// Rewrites a single envelope, restoring its original `ctime`.
+    pub fn rewrite(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        let ctime = packet.manifest.ctime;
+        Ok(
+            transform::apply_all(&self.stages, packet)?.map(|mut packet| {
+                packet.manifest.ctime = ctime;
+                packet
+            }),
+        )
+    }
+
+    /// Rewrites every envelope read from `input` into `output`.
+    ///
+    /// Blank lines are skipped. The first envelope that cannot be decoded or
+    /// transformed stops the rewrite, identifying the offending line.
+    pub fn run<R: BufRead, W: Write>(&self, input: R, mut output: W) -> Result<Summary, Error> {
+        let mut summary = Summary::default();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.map_err(Error::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let lineno = i as u64 + 1;
+            let packet: RawPacket = serde_json::from_str(&line).map_err(|e| Error::Decode {
+                line: lineno,
+                message: e.to_string(),
+            })?;
+            summary.read += 1;
+
+            match self.rewrite(packet) {
+                Ok(Some(packet)) => {
+                    serde_json::to_writer(&mut output, &packet).map_err(|e| Error::Io(e.into()))?;
+                    output.write_all(b"\n").map_err(Error::Io)?;
+                    summary.written += 1;
+                }
+                Ok(None) => summary.dropped += 1,
+                Err(error) => {
+                    return Err(Error::Transform {
+                        line: lineno,
+                        error,
+                    })
+                }
+            }
+        }
+        output.flush().map_err(Error::Io)?;
+        Ok(summary)
+    }
+
+    /// Rewrites every frame read from `input` into `output`, as
+    /// [`run`](Rewriter::run) does lines. Errors identify the offending
+    /// frame by its position, counting from 1, as its `line`.
+    pub fn run_framed<R: Read, W: Write>(&self, input: R, output: W) -> Result<Summary, Error> {
+        let mut summary = Summary::default();
+        let mut reader = FramedReader::new(input);
+        let mut writer = FramedWriter::new(output);
+        let mut position = 0;
+        loop {
+            position += 1;
+            let decode = |e: framing::Error| Error::Decode {
+                line: position,
+                message: e.to_string(),
+            };
+            let (frame, checksummed) = match reader.read_checked_frame().map_err(decode)? {
+                Some(frame) => frame,
+                None => break,
+            };
+            let packet = frame.decode::<Value>().map_err(decode)?;
+            summary.read += 1;
+
+            match self.rewrite(packet) {
+                Ok(Some(packet)) => {
+                    writer.set_checksums(checksummed);
+                    writer
+                        .write_packet(&packet, frame.format)
+                        .map_err(framing_io)?;
+                    summary.written += 1;
+                }
+                Ok(None) => summary.dropped += 1,
+                Err(error) => {
+                    return Err(Error::Transform {
+                        line: position,
+                        error,
+                    })
+                }
+            }
+        }
+        writer.flush().map_err(framing_io)?;
+        Ok(summary)
+    }
+}
+
+fn framing_io(e: framing::Error) -> Error {
+    match e {
+        framing::Error::Io(e) => Error::Io(e),
+        e => Error::Io(std::io::Error::other(e)),
+    }
+}
+
+impl fmt::Debug for Rewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rewriter")
+            .field("stages", &self.stages.len())
+            .finish()
+    }
+}
+
+/// An error that stopped a rewrite.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Decode { line: u64, message: String },
+    Transform { line: u64, error: transform::Error },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Decode { line, message } => write!(f, "line {}: {}", line, message),
+            Error::Transform { line, error } => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Transform { error, .. } => Some(error),
+            Error::Decode { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn archive() -> String {
+        let cpu = serde_json::to_string(&fixtures::cpu_raw()).unwrap();
+        let netstat = serde_json::to_string(&fixtures::netstat_raw()).unwrap();
+        format!("{}\n\n{}\n", cpu, netstat)
+    }
+
+    #[test]
+    fn rewrites_and_preserves_ctime() {
+        let rewriter = Rewriter::new()
+            .stage(|mut packet: RawPacket| {
+                if packet.manifest.kind == "netstat" {
+                    return Ok(None);
+                }
+                packet.manifest.version = 2;
+                packet.manifest.ctime = chrono::Utc::now();
+                Ok(Some(packet))
+            })
+            .stage(|mut packet: RawPacket| {
+                packet.manifest.origin = "host01".into();
+                Ok(Some(packet))
+            });
+
+        let mut output = Vec::new();
+        let summary = rewriter.run(archive().as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                read: 2,
+                written: 1,
+                dropped: 1
+            }
+        );
+
+        let packet: RawPacket = serde_json::from_slice(&output).unwrap();
+        assert_eq!(packet.manifest.version, 2);
+        assert_eq!(packet.manifest.origin, "host01");
+        assert_eq!(packet.manifest.ctime, fixtures::cpu_manifest().ctime);
+    }
+
+    #[test]
+    fn reports_failing_line() {
+        let rewriter = Rewriter::new().stage(|packet: RawPacket| {
+            if packet.manifest.kind == "netstat" {
+                Err(transform::Error::new("unsupported"))
+            } else {
+                Ok(Some(packet))
+            }
+        });
+        let err = rewriter.run(archive().as_bytes(), Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::Transform { line: 3, .. }));
+
+        let err = Rewriter::new()
+            .run("{}\n".as_bytes(), Vec::new())
+            .unwrap_err();
+        assert!(matches!(err, Error::Decode { line: 1, .. }));
+    }
+
+    #[test]
+    fn rewrites_framed_archives() {
+        // The last format is a binary one, in builds with any.
+        let format = *crate::Format::all().last().unwrap();
+        let mut archive = FramedWriter::new(Vec::new()).checksums(true);
+        archive.write_packet(&fixtures::cpu_raw(), format).unwrap();
+        archive
+            .write_packet(&fixtures::netstat_raw(), crate::Format::Json)
+            .unwrap();
+        let archive = archive.into_inner();
+
+        let mut migrator = crate::migrate::Migrator::new();
+        migrator.register_json(fixtures::cpu_manifest().coordinates(), |v1| {
+            Ok(serde_json::json!({ "idle": v1["idle"] }))
+        });
+        let mut output = Vec::new();
+        let summary = Rewriter::new()
+            .stage(migrator)
+            .run_framed(&archive[..], &mut output)
+            .unwrap();
+        assert_eq!(summary.written, 2);
+
+        let mut frames = FramedReader::new(&output[..]);
+        let (cpu, checksummed) = frames.read_checked_frame().unwrap().unwrap();
+        assert!(checksummed);
+        assert_eq!(cpu.format, format);
+        assert_eq!(cpu.manifest.version, 2);
+        assert_eq!(cpu.manifest.ctime, fixtures::cpu_manifest().ctime);
+        let cpu = cpu.decode::<Value>().unwrap();
+        assert_eq!(cpu.content, serde_json::json!({ "idle": 83.25 }));
+        let netstat = frames.read_frame().unwrap().unwrap();
+        assert_eq!(netstat.decode::<Value>().unwrap(), fixtures::netstat_raw());
+        assert!(frames.read_frame().unwrap().is_none());
+
+        let err = Rewriter::new()
+            .run_framed(&archive[..archive.len() - 1], Vec::new())
+            .unwrap_err();
+        assert!(matches!(err, Error::Decode { line: 2, .. }));
+    }
+}
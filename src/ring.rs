@@ -0,0 +1,281 @@
+//! Sharding packets across workers with a consistent-hash ring.
+//!
+//! A [`HashRing`] maps each packet to one of its members, such as workers
+//! or sinks, by hashing a key taken from the packet's manifest: a partition
+//! label, the origin, or a fingerprint of the whole manifest. Packets with
+//! the same key always go to the same member. Each member holds many
+//! points on the ring, so keys spread evenly, and adding or removing one
+//! member moves only the keys it gains or loses:
+//!
+//! ```
+//! use intermodal::ring::{HashRing, ShardBy};
+//! # let manifest: intermodal::Manifest = serde_json::from_value(serde_json::json!({
+//! #     "domain": "example.org", "scope": "metrics/host", "kind": "uptime",
+//! #     "version": 1, "ctime": "2020-06-01T12:00:00Z", "origin": "host01"
+//! # })).unwrap();
+//!
+//! let mut ring = HashRing::new().shard_by(ShardBy::Origin);
+//! ring.insert("worker-1", 1);
+//! ring.insert("worker-2", 2);
+//! ring.insert("worker-3", 3);
+//!
+//! let worker = *ring.route(&manifest).unwrap();
+//! // Removing another worker leaves the key where it was.
+//! ring.remove(if worker == 3 { "worker-1" } else { "worker-3" });
+//! assert_eq!(ring.route(&manifest), Some(&worker));
+//! ```
+//!
+//! A ring of [`Spool`]s is a spool itself, spooling each packet to its
+//! member, so a [`Replicator`](crate::replicate::Replicator) destination
+//! can be sharded across several.
+//!
+//! Keys are hashed with 64-bit FNV-1a, which is the same on every platform
+//! and release, so that processes agree on where each key belongs.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::spool::Spool;
+use crate::{Manifest, RawPacket};
+
+/// Points each member holds on the ring, unless set otherwise.
+pub const DEFAULT_POINTS: usize = 128;
+
+/// What a packet is sharded by.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ShardBy {
+    /// The canonical encoding of the whole manifest.
+    #[default]
+    Fingerprint,
+    /// The manifest's origin.
+    Origin,
+    /// The value of this label, or the fingerprint for manifests without
+    /// it.
+    Label(String),
+}
+
+impl ShardBy {
+    /// The key the manifest is sharded by.
+    pub fn key(&self, manifest: &Manifest) -> Vec<u8> {
+        match self {
+            ShardBy::Fingerprint => manifest.canonical_bytes(),
+            ShardBy::Origin => manifest.origin.as_bytes().to_vec(),
+            ShardBy::Label(label) => match manifest.labels.get(label) {
+                Some(value) => value.as_bytes().to_vec(),
+                None => manifest.canonical_bytes(),
+            },
+        }
+    }
+}
+
+/// Members placed on a consistent-hash ring.
+#[derive(Debug, Clone)]
+pub struct HashRing<N> {
+    points_per_member: usize,
+    shard_by: ShardBy,
+    /// Each point, and the member holding it.
+    points: BTreeMap<u64, String>,
+    members: BTreeMap<String, N>,
+}
+
+impl<N> Default for HashRing<N> {
+    fn default() -> Self {
+        HashRing::new()
+    }
+}
+
+impl<N> HashRing<N> {
+    /// An empty ring, sharding by [fingerprint](ShardBy::Fingerprint).
+    pub fn new() -> Self {
+        HashRing {
+            points_per_member: DEFAULT_POINTS,
+            shard_by: ShardBy::Fingerprint,
+            points: BTreeMap::new(),
+            members: BTreeMap::new(),
+        }
+    }
+
+    /// Gives each member this many points. More spread keys more evenly, at
+    /// the cost of memory. Members already present keep theirs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `points` is zero.
+    pub fn points(mut self, points: usize) -> Self {
+        assert!(points > 0, "members need at least one point");
+        self.points_per_member = points;
+        self
+    }
+
+    pub fn shard_by(mut self, shard_by: ShardBy) -> Self {
+        self.shard_by = shard_by;
+        self
+    }
+
+    /// Adds a member under `name`, replacing and returning any member by
+    /// that name.
+    pub fn insert<S: Into<String>>(&mut self, name: S, member: N) -> Option<N> {
+        let name = name.into();
+        let replaced = self.remove(&name);
+        for i in 0..self.points_per_member {
+            let point = hash(format!("{}#{}", name, i).as_bytes());
+            // On the rare collision the point stays with its first holder.
+            self.points.entry(point).or_insert_with(|| name.clone());
+        }
+        self.members.insert(name, member);
+        replaced
+    }
+
+    /// Removes the member by `name`, whose keys pass to the members
+    /// holding the next points round the ring.
+    pub fn remove(&mut self, name: &str) -> Option<N> {
+        let member = self.members.remove(name)?;
+        self.points.retain(|_, holder| holder != name);
+        Some(member)
+    }
+
+    /// The member a key belongs to, and its name.
+    pub fn get(&self, key: &[u8]) -> Option<(&str, &N)> {
+        let point = hash(key);
+        let (_, name) = self
+            .points
+            .range(point..)
+            .next()
+            .or_else(|| self.points.iter().next())?;
+        self.members
+            .get_key_value(name)
+            .map(|(name, member)| (name.as_str(), member))
+    }
+
+    /// The member a packet with the manifest belongs to.
+    pub fn route(&self, manifest: &Manifest) -> Option<&N> {
+        self.get(&self.shard_by.key(manifest))
+            .map(|(_, member)| member)
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = (&str, &N)> {
+        self.members
+            .iter()
+            .map(|(name, member)| (name.as_str(), member))
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+impl<S: Spool> Spool for HashRing<S> {
+    fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+        match self.route(&packet.manifest) {
+            Some(spool) => spool.spool(packet),
+            None => Err(io::Error::other("hash ring has no members")),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for spool in self.members.values() {
+            spool.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a, with a final mix so that similar keys, such as a
+/// member's point names, land far apart.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::sync::Mutex;
+
+    fn ring(members: usize) -> HashRing<usize> {
+        let mut ring = HashRing::new();
+        for i in 0..members {
+            ring.insert(format!("worker-{}", i), i);
+        }
+        ring
+    }
+
+    #[test]
+    fn spreads_keys_and_moves_few() {
+        let keys: Vec<_> = (0..10_000).map(|i| format!("key-{}", i)).collect();
+        let before = ring(4);
+        let mut counts = [0; 4];
+        for key in &keys {
+            counts[*before.get(key.as_bytes()).unwrap().1] += 1;
+        }
+        for count in counts {
+            assert!((1800..3200).contains(&count), "{:?}", counts);
+        }
+
+        let after = ring(5);
+        let mut moved = 0;
+        for key in &keys {
+            let (old, new) = (before.get(key.as_bytes()), after.get(key.as_bytes()));
+            if old != new {
+                assert_eq!(new.unwrap().0, "worker-4");
+                moved += 1;
+            }
+        }
+        assert!((1500..2600).contains(&moved), "{}", moved);
+    }
+
+    #[test]
+    fn routes_manifests() {
+        let mut ring = ring(3).shard_by(ShardBy::Label("environment".to_string()));
+        let cpu = fixtures::cpu_manifest();
+        let mut moved = cpu.clone();
+        moved.origin = "host02.example.org".to_string();
+        assert_eq!(ring.route(&cpu), ring.route(&moved));
+
+        let member = *ring.route(&cpu).unwrap();
+        assert_eq!(ring.remove(&format!("worker-{}", member)), Some(member));
+        assert_ne!(ring.route(&cpu), Some(&member));
+        assert_eq!(ring.len(), 2);
+
+        let empty = HashRing::<usize>::new();
+        assert_eq!(empty.route(&cpu), None);
+    }
+
+    #[derive(Default)]
+    struct Recording(Mutex<Vec<String>>);
+
+    impl Spool for Recording {
+        fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+            let mut kinds = self.0.lock().unwrap();
+            kinds.push(packet.manifest.kind.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shards_spools() {
+        let mut ring = HashRing::new().shard_by(ShardBy::Origin);
+        ring.insert("a", Recording::default());
+        ring.insert("b", Recording::default());
+        ring.spool(&fixtures::cpu_raw()).unwrap();
+        ring.spool(&fixtures::netstat_raw()).unwrap();
+        let spooled: Vec<_> = ring
+            .members()
+            .map(|(_, spool)| spool.0.lock().unwrap().len())
+            .collect();
+        // Both fixtures come from the same origin.
+        assert!(spooled == [2, 0] || spooled == [0, 2], "{:?}", spooled);
+    }
+}
@@ -0,0 +1,581 @@
+//! Writing archives that rotate by size, count and time.
+//!
+//! A [`RotatingWriter`] writes framed packets into a tree of archive files
+//! under a root directory. A [`Template`] names the file each packet goes
+//! to from its manifest, so that each kind of content, or each origin,
+//! gets its own archives, and the writer starts a new file once the
+//! current one holds enough bytes or packets, or once an hour or a day has
+//! passed:
+//!
+//! ```no_run
+//! use intermodal::rotation::{Clock, Interval, RotatingWriter};
+//! use intermodal::Format;
+//! # let packet: intermodal::RawPacket = unimplemented!();
+//!
+//! let mut writer = RotatingWriter::new("/var/lib/intermodal", "{domain}/{scope}/{kind}".parse()?)
+//!     .max_bytes(256 * 1024 * 1024)
+//!     .interval(Interval::Hourly, Clock::Ctime)
+//!     .on_rotate(|path| println!("{} is complete", path.display()));
+//! writer.write_packet(&packet, Format::Json)?;
+//! writer.close()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Files are named after the rendered template, then the period they
+//! cover if they rotate by time, then a sequence number counting the files
+//! of that period, then `.imf`:
+//!
+//! ```text
+//! /var/lib/intermodal/example.org/metrics/host/cpu.2020-06-01T12.000000.imf
+//! /var/lib/intermodal/example.org/metrics/host/cpu.2020-06-01T12.000001.imf
+//! /var/lib/intermodal/example.org/metrics/host/cpu.2020-06-01T13.000000.imf
+//! ```
+//!
+//! New files never overwrite old ones: a writer restarted in the same
+//! period carries on numbering after the files it finds. A file is complete
+//! once [`on_rotate`](RotatingWriter::on_rotate) is called for it, and can
+//! then be moved, uploaded or deleted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::framing::{self, FramedWriter};
+use crate::template::{self, Template};
+use crate::{Format, Packet};
+
+/// The extension of archive files.
+pub const EXTENSION: &str = "imf";
+
+/// How long a file's period lasts, aligned to UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Interval {
+    Hourly,
+    Daily,
+}
+
+impl Interval {
+    /// The name of the period holding `time`, as it appears in file names.
+    fn period(self, time: DateTime<Utc>) -> String {
+        match self {
+            Interval::Hourly => time.format("%Y-%m-%dT%H").to_string(),
+            Interval::Daily => time.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+/// Which time decides a packet's period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Clock {
+    /// When the content was created, so that a file holds the packets
+    /// created in its period, however late they arrive.
+    Ctime,
+    /// When the packet is written.
+    Received,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The template does not render for a packet's manifest.
+    Template(template::Error),
+    /// The rendered name would lead outside the root: it is absolute, or
+    /// has an empty, `.` or `..` segment.
+    Path(String),
+    Frame(framing::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Template(e) => write!(f, "{}", e),
+            Error::Path(name) => write!(f, "`{}` is not a path within the root", name),
+            Error::Frame(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Template(e) => Some(e),
+            Error::Path(_) => None,
+            Error::Frame(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<template::Error> for Error {
+    fn from(e: template::Error) -> Self {
+        Error::Template(e)
+    }
+}
+
+impl From<framing::Error> for Error {
+    fn from(e: framing::Error) -> Self {
+        Error::Frame(e)
+    }
+}
+
+type RotateFn = Box<dyn Fn(&Path) + Send + Sync>;
+
+/// Writes framed packets to archive files that rotate.
+///
+/// Without limits, each rendered name has a single file. A file rotates
+/// once it holds [`max_bytes`](RotatingWriter::max_bytes) or more, so it
+/// may exceed the limit by part of a frame, never splitting one.
+pub struct RotatingWriter {
+    root: PathBuf,
+    template: Template,
+    max_bytes: Option<u64>,
+    max_packets: Option<u64>,
+    interval: Option<(Interval, Clock)>,
+    checksums: bool,
+    on_rotate: Option<RotateFn>,
+    open: HashMap<String, Current>,
+}
+
+/// The file a rendered name is being written to.
+struct Current {
+    path: PathBuf,
+    period: Option<String>,
+    sequence: u32,
+    packets: u64,
+    writer: FramedWriter<Counting>,
+}
+
+/// A buffered file that counts the bytes written to it.
+struct Counting {
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl Write for Counting {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(bytes)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl fmt::Debug for RotatingWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotatingWriter")
+            .field("root", &self.root)
+            .field("template", &self.template)
+            .field("max_bytes", &self.max_bytes)
+            .field("max_packets", &self.max_packets)
+            .field("interval", &self.interval)
+            .field("open", &self.open.len())
+            .finish()
+    }
+}
+
+impl RotatingWriter {
+    /// Writes under `root`, to files named by rendering `template`, which
+    /// may contain `/` to make directories.
+    pub fn new<P: Into<PathBuf>>(root: P, template: Template) -> Self {
+        RotatingWriter {
+            root: root.into(),
+            template,
+            max_bytes: None,
+            max_packets: None,
+            interval: None,
+            checksums: false,
+            on_rotate: None,
+            open: HashMap::new(),
+        }
+    }
+
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn max_packets(mut self, max_packets: u64) -> Self {
+        self.max_packets = Some(max_packets);
+        self
+    }
+
+    /// Starts a new file for each period, deciding a packet's period by
+    /// `clock`.
+    pub fn interval(mut self, interval: Interval, clock: Clock) -> Self {
+        self.interval = Some((interval, clock));
+        self
+    }
+
+    /// Writes checksummed frames; see [`framing`](crate::framing).
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Calls `f` with the path of each file once it is complete: when it
+    /// rotates, and when the writer closes.
+    pub fn on_rotate<F: Fn(&Path) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_rotate = Some(Box::new(f));
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The files being written.
+    pub fn open_files(&self) -> Vec<&Path> {
+        let mut paths: Vec<_> = self.open.values().map(|c| c.path.as_path()).collect();
+        paths.sort_unstable();
+        paths
+    }
+
+    /// Frames and writes a packet, returning the path of the file it went
+    /// to.
+    pub fn write_packet<T: Serialize>(
+        &mut self,
+        packet: &Packet<T>,
+        format: Format,
+    ) -> Result<PathBuf, Error> {
+        self.write_packet_at(packet, format, Utc::now())
+    }
+
+    fn write_packet_at<T: Serialize>(
+        &mut self,
+        packet: &Packet<T>,
+        format: Format,
+        now: DateTime<Utc>,
+    ) -> Result<PathBuf, Error> {
+        let name = self.template.render(&packet.manifest)?;
+        // Manifests are not validated on decoding, so their fields may hold
+        // anything.
+        if !within_root(&name) {
+            return Err(Error::Path(name));
+        }
+        let period = self.interval.map(|(interval, clock)| match clock {
+            Clock::Ctime => interval.period(packet.manifest.ctime),
+            Clock::Received => interval.period(now),
+        });
+
+        let full = self.open.get(&name).map(|current| {
+            current.period != period
+                || self
+                    .max_bytes
+                    .is_some_and(|max| current.writer.get_ref().written >= max)
+                || self.max_packets.is_some_and(|max| current.packets >= max)
+        });
+        let sequence = match full {
+            Some(false) => None,
+            Some(true) => {
+                let current = self.open.remove(&name).expect("the file is open");
+                let sequence = (current.period == period).then(|| current.sequence + 1);
+                self.finish(current)?;
+                Some(sequence)
+            }
+            None => Some(None),
+        };
+        if let Some(sequence) = sequence {
+            let current = self.create(&name, period, sequence)?;
+            self.open.insert(name.clone(), current);
+        }
+
+        let current = self.open.get_mut(&name).expect("the file is open");
+        current.writer.write_packet(packet, format)?;
+        current.packets += 1;
+        Ok(current.path.clone())
+    }
+
+    /// Opens the next file for `name` in `period`: `sequence` if given, or
+    /// else the one after the last found on disk.
+    fn create(
+        &self,
+        name: &str,
+        period: Option<String>,
+        sequence: Option<u32>,
+    ) -> Result<Current, Error> {
+        let base = self.root.join(name);
+        let dir = base.parent().unwrap_or(&self.root).to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let stem = base
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let prefix = match &period {
+            Some(period) => format!("{}.{}.", stem, period),
+            None => format!("{}.", stem),
+        };
+        let sequence = match sequence {
+            Some(sequence) => sequence,
+            None => next_sequence(&dir, &prefix)?,
+        };
+        let path = dir.join(format!("{}{:06}.{}", prefix, sequence, EXTENSION));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let writer = FramedWriter::new(Counting {
+            file: BufWriter::new(file),
+            written: 0,
+        })
+        .checksums(self.checksums);
+        Ok(Current {
+            path,
+            period,
+            sequence,
+            packets: 0,
+            writer,
+        })
+    }
+
+    /// Flushes and syncs a file, and reports it complete.
+    fn finish(&self, mut current: Current) -> Result<(), Error> {
+        current.writer.flush()?;
+        current.writer.get_ref().file.get_ref().sync_all()?;
+        if let Some(f) = &self.on_rotate {
+            f(&current.path);
+        }
+        Ok(())
+    }
+
+    /// Closes the files whose period has ended, with the [`Clock::Received`]
+    /// clock, rather than waiting for the next packet to rotate them. Call
+    /// it every so often when traffic is sparse.
+    pub fn close_elapsed(&mut self) -> Result<(), Error> {
+        self.close_elapsed_at(Utc::now())
+    }
+
+    fn close_elapsed_at(&mut self, now: DateTime<Utc>) -> Result<(), Error> {
+        let (interval, clock) = match self.interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        if clock != Clock::Received {
+            return Ok(());
+        }
+        let period = interval.period(now);
+        let mut elapsed: Vec<_> = self
+            .open
+            .iter()
+            .filter(|(_, current)| current.period.as_deref() != Some(period.as_str()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        elapsed.sort_unstable();
+        for name in elapsed {
+            let current = self.open.remove(&name).expect("the file is open");
+            self.finish(current)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes every open file.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for current in self.open.values_mut() {
+            current.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Closes every open file, reporting each complete.
+    pub fn close(mut self) -> Result<(), Error> {
+        let mut open: Vec<_> = self.open.drain().collect();
+        open.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        for (_, current) in open {
+            self.finish(current)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sequence number after those of the files in `dir` named `prefix`,
+/// a number and the extension.
+fn next_sequence(dir: &Path, prefix: &str) -> io::Result<u32> {
+    let mut next = 0;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let sequence = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(&format!(".{}", EXTENSION)))
+            .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse::<u32>().ok());
+        if let Some(sequence) = sequence {
+            next = next.max(sequence + 1);
+        }
+    }
+    Ok(next)
+}
+
+/// Whether `name` is a relative path of plain segments.
+fn within_root(name: &str) -> bool {
+    name.split('/')
+        .all(|segment| !matches!(segment, "" | "." | ".."))
+        && Path::new(name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::framing::FramedReader;
+    use chrono::{Duration, TimeZone};
+    use std::sync::{Arc, Mutex};
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "intermodal-rotation-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    fn names(paths: &[PathBuf], root: &Path) -> Vec<String> {
+        paths
+            .iter()
+            .map(|p| p.strip_prefix(root).unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn frames(path: &Path) -> usize {
+        FramedReader::new(File::open(path).unwrap()).count()
+    }
+
+    #[test]
+    fn rotates_by_count_and_size() {
+        let root = root("count");
+        let rotated = Arc::new(Mutex::new(Vec::new()));
+        let seen = rotated.clone();
+        let mut writer = RotatingWriter::new(&root, "{domain}/{kind}".parse().unwrap())
+            .max_packets(2)
+            .on_rotate(move |path| seen.lock().unwrap().push(path.to_path_buf()));
+        for _ in 0..5 {
+            writer
+                .write_packet(&fixtures::cpu_raw(), Format::Json)
+                .unwrap();
+        }
+        writer
+            .write_packet(&fixtures::netstat_raw(), Format::Json)
+            .unwrap();
+        assert_eq!(writer.open_files().len(), 2);
+        writer.close().unwrap();
+
+        let rotated = rotated.lock().unwrap().clone();
+        assert_eq!(
+            names(&rotated, &root),
+            [
+                "example.org/cpu.000000.imf",
+                "example.org/cpu.000001.imf",
+                "example.org/cpu.000002.imf",
+                "example.org/netstat.000000.imf",
+            ]
+        );
+        let counts: Vec<_> = rotated.iter().map(|p| frames(p)).collect();
+        assert_eq!(counts, [2, 2, 1, 1]);
+
+        // A writer restarted later carries on numbering, and rotates by
+        // size.
+        let mut writer =
+            RotatingWriter::new(&root, "{domain}/{kind}".parse().unwrap()).max_bytes(1);
+        let first = writer
+            .write_packet(&fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        let second = writer
+            .write_packet(&fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        assert_eq!(
+            names(&[first, second], &root),
+            ["example.org/cpu.000003.imf", "example.org/cpu.000004.imf"]
+        );
+        writer.close().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stays_within_root() {
+        let root = root("traversal");
+        let mut writer = RotatingWriter::new(&root, "{domain}/{scope}/{origin}".parse().unwrap());
+        for (scope, origin) in [
+            ("../../escaped", "host01"),
+            ("metrics/./host", "host01"),
+            ("metrics", "/tmp/escaped"),
+            ("metrics", ".."),
+        ]
+        .iter()
+        {
+            let mut packet = fixtures::cpu_raw();
+            packet.manifest.scope = scope.to_string();
+            packet.manifest.origin = origin.to_string();
+            assert!(
+                matches!(
+                    writer.write_packet(&packet, Format::Json),
+                    Err(Error::Path(_))
+                ),
+                "{}/{}",
+                scope,
+                origin
+            );
+        }
+        assert!(writer.open_files().is_empty());
+        writer
+            .write_packet(&fixtures::cpu_raw(), Format::Json)
+            .unwrap();
+        writer.close().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_time() {
+        let root = root("time");
+        let mut writer = RotatingWriter::new(&root, "{kind}".parse().unwrap())
+            .interval(Interval::Hourly, Clock::Ctime);
+        let mut packet = fixtures::cpu_raw();
+        let noon = Utc.with_ymd_and_hms(2020, 6, 1, 12, 0, 0).unwrap();
+        let mut paths = Vec::new();
+        for minutes in [0, 59, 60, 30] {
+            packet.manifest.ctime = noon + Duration::minutes(minutes);
+            paths.push(writer.write_packet(&packet, Format::Json).unwrap());
+        }
+        writer.close().unwrap();
+        assert_eq!(
+            names(&paths, &root),
+            [
+                "cpu.2020-06-01T12.000000.imf",
+                "cpu.2020-06-01T12.000000.imf",
+                "cpu.2020-06-01T13.000000.imf",
+                // A late packet for an hour already closed starts another
+                // file for it.
+                "cpu.2020-06-01T12.000001.imf",
+            ]
+        );
+
+        let mut writer = RotatingWriter::new(&root, "{kind}".parse().unwrap())
+            .interval(Interval::Daily, Clock::Received);
+        let path = writer
+            .write_packet_at(&packet, Format::Json, noon + Duration::days(3))
+            .unwrap();
+        assert_eq!(names(&[path], &root), ["cpu.2020-06-04.000000.imf"]);
+        writer.close_elapsed_at(noon + Duration::days(3)).unwrap();
+        assert_eq!(writer.open_files().len(), 1);
+        writer.close_elapsed_at(noon + Duration::days(4)).unwrap();
+        assert!(writer.open_files().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use crate::{decode, Header, Manifest, Selector};
+
+/// Maps manifests to targets by way of [`Selector`]s.
+///
+/// Targets are whatever the caller routes to, such as sink handles, channel
+/// senders, or closures. Routes are consulted from the highest priority to
+/// the lowest, and in the order they were added within a priority. A
+/// manifest no route matches goes to the fallback, if one is set.
+///
+/// [`explain`](Router::explain) shows how a manifest was routed, for
+/// debugging routing decisions.
+///
+/// Expired manifests, those past their `expires` time, are routed like any
+/// other unless the router is told to [drop](Router::drop_expired) them or
+/// to send them to [a target of their own](Router::expired).
+///
+/// Routes are indexed by the domain, kind and scope their selectors require,
+/// so that looking up a manifest only tests the selectors that could match
+/// it, however many routes there are.
+#[derive(Debug, Clone)]
+pub struct Router<T> {
+    routes: Vec<Route<T>>,
+    index: Index,
+    fallback: Option<Route<T>>,
+    expired: Option<Expired<T>>,
+}
+
+/// What a [`Router`] does with expired manifests.
+#[derive(Debug, Clone)]
+enum Expired<T> {
+    Drop,
+    Redirect(Route<T>),
+}
+
+/// A single rule within a [`Router`].
+#[derive(Debug, Clone)]
+pub struct Route<T> {
+    /// A name identifying the route in logs and diagnostics.
+    pub name: String,
+    /// Routes with higher priorities are consulted first.
+    pub priority: i32,
+    pub selector: Selector,
+    pub target: T,
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            index: Index::default(),
+            fallback: None,
+            expired: None,
+        }
+    }
+
+    /// Adds a route, naming it after its position in the router.
+    pub fn add(&mut self, selector: Selector, target: T) -> &mut Self {
+        let name = format!("route-{}", self.routes.len());
+        self.add_named(name, selector, target)
+    }
+
+    /// Adds a route with the given name.
+    pub fn add_named<S: Into<String>>(
+        &mut self,
+        name: S,
+        selector: Selector,
+        target: T,
+    ) -> &mut Self {
+        self.add_prioritized(name, 0, selector, target)
+    }
+
+    /// Adds a route with the given name and priority. Routes added with
+    /// [`add`](Router::add) and [`add_named`](Router::add_named) have
+    /// priority 0.
+    pub fn add_prioritized<S: Into<String>>(
+        &mut self,
+        name: S,
+        priority: i32,
+        selector: Selector,
+        target: T,
+    ) -> &mut Self {
+        self.index.insert(&selector, self.routes.len());
+        self.routes.push(Route {
+            name: name.into(),
+            priority,
+            selector,
+            target,
+        });
+        self
+    }
+
+    /// Sets the target for manifests no route matches. Its route is named
+    /// `fallback`.
+    pub fn fallback(&mut self, target: T) -> &mut Self {
+        self.fallback = Some(Route {
+            name: "fallback".to_string(),
+            priority: i32::MIN,
+            selector: Selector::any(),
+            target,
+        });
+        self
+    }
+
+    /// Routes expired manifests nowhere, so that they are dropped.
+    pub fn drop_expired(&mut self) -> &mut Self {
+        self.expired = Some(Expired::Drop);
+        self
+    }
+
+    /// Sets the target for expired manifests, whatever routes they match.
+    /// Its route is named `expired`.
+    pub fn expired(&mut self, target: T) -> &mut Self {
+        self.expired = Some(Expired::Redirect(Route {
+            name: "expired".to_string(),
+            priority: i32::MAX,
+            selector: Selector::any(),
+            target,
+        }));
+        self
+    }
+
+    pub fn routes(&self) -> &[Route<T>] {
+        &self.routes
+    }
+
+    pub fn fallback_route(&self) -> Option<&Route<T>> {
+        self.fallback.as_ref()
+    }
+
+    /// Returns the first route whose selector matches the manifest, or the
+    /// fallback if none does. An expired manifest is dropped or redirected
+    /// instead, if the router is set to.
+    pub fn route(&self, manifest: &Manifest) -> Option<&Route<T>> {
+        self.route_at(manifest, Utc::now())
+    }
+
+    fn route_at(&self, manifest: &Manifest, now: DateTime<Utc>) -> Option<&Route<T>> {
+        if let Some(expired) = self.expired_route(manifest, now) {
+            return expired;
+        }
+        self.candidates(manifest)
+            .find(|route| route.selector.matches(manifest))
+            .or(self.fallback.as_ref())
+    }
+
+    /// Where an expired manifest goes, if it has expired and the router
+    /// treats expired manifests apart.
+    fn expired_route(&self, manifest: &Manifest, now: DateTime<Utc>) -> Option<Option<&Route<T>>> {
+        match &self.expired {
+            Some(expired) if manifest.is_expired(now) => Some(match expired {
+                Expired::Drop => None,
+                Expired::Redirect(route) => Some(route),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Routes an encoded envelope, decoding only its manifest. The format
+    /// is sniffed from the bytes.
+    pub fn route_bytes(&self, bytes: &[u8]) -> Result<Option<&Route<T>>, decode::Error> {
+        let (header, _): (Header, _) = decode::from_slice_auto(bytes)?;
+        Ok(self.route(&header.manifest))
+    }
+
+    /// Returns every route whose selector matches the manifest, in the order
+    /// they are consulted, whether or not it has expired. The fallback is
+    /// not included.
+    pub fn matching<'a>(
+        &'a self,
+        manifest: &'a Manifest,
+    ) -> impl Iterator<Item = &'a Route<T>> + 'a {
+        self.candidates(manifest)
+            .filter(move |route| route.selector.matches(manifest))
+    }
+
+    /// Routes the manifest, recording each route consulted on the way.
+    pub fn explain(&self, manifest: &Manifest) -> Trace<'_, T> {
+        self.explain_at(manifest, Utc::now())
+    }
+
+    fn explain_at(&self, manifest: &Manifest, now: DateTime<Utc>) -> Trace<'_, T> {
+        if let Some(route) = self.expired_route(manifest, now) {
+            return Trace {
+                consulted: Vec::new(),
+                route,
+                fallback: false,
+                expired: true,
+            };
+        }
+        let mut consulted = Vec::new();
+        let mut chosen = None;
+        for route in self.candidates(manifest) {
+            let matched = route.selector.matches(manifest);
+            consulted.push(Consulted {
+                name: &route.name,
+                priority: route.priority,
+                selector: &route.selector,
+                matched,
+            });
+            if matched {
+                chosen = Some(route);
+                break;
+            }
+        }
+        let fallback = chosen.is_none() && self.fallback.is_some();
+        Trace {
+            consulted,
+            route: chosen.or(self.fallback.as_ref()),
+            fallback,
+            expired: false,
+        }
+    }
+
+    /// The routes the index admits for the manifest, in the order they are
+    /// consulted.
+    fn candidates<'a>(&'a self, manifest: &Manifest) -> impl Iterator<Item = &'a Route<T>> + 'a {
+        let mut positions = self.index.candidates(manifest);
+        // The positions are ascending, and the sort is stable, so insertion
+        // order holds within a priority.
+        positions.sort_by_key(|&i| std::cmp::Reverse(self.routes[i].priority));
+        positions.into_iter().map(move |i| &self.routes[i])
+    }
+}
+
+/// How a [`Router`] routed a manifest.
+///
+/// Displays as one line per route consulted, then the outcome. Routes whose
+/// required domain, kind or scope rule them out are not consulted.
+#[derive(Debug)]
+pub struct Trace<'a, T> {
+    /// The routes consulted, in order, up to the one that matched.
+    pub consulted: Vec<Consulted<'a>>,
+    /// The chosen route, which may be the fallback.
+    pub route: Option<&'a Route<T>>,
+    /// Whether the fallback was chosen.
+    pub fallback: bool,
+    /// Whether the manifest had expired, and was dropped or redirected
+    /// without consulting any route.
+    pub expired: bool,
+}
+
+/// One route consulted while routing a manifest.
+#[derive(Debug, Clone, Copy)]
+pub struct Consulted<'a> {
+    pub name: &'a str,
+    pub priority: i32,
+    pub selector: &'a Selector,
+    pub matched: bool,
+}
+
+impl<T> fmt::Display for Trace<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for consulted in &self.consulted {
+            writeln!(
+                f,
+                "{} (priority {}, `{}`): {}",
+                consulted.name,
+                consulted.priority,
+                consulted.selector,
+                if consulted.matched {
+                    "matched"
+                } else {
+                    "no match"
+                }
+            )?;
+        }
+        match (self.route, self.fallback, self.expired) {
+            (Some(route), _, true) => write!(f, "routed to {} (expired)", route.name),
+            (None, _, true) => f.write_str("dropped (expired)"),
+            (Some(route), true, _) => write!(f, "routed to {} (no route matched)", route.name),
+            (Some(route), false, _) => write!(f, "routed to {}", route.name),
+            (None, _, _) => f.write_str("no route matched"),
+        }
+    }
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+/// Route positions, bucketed by the domain, then the kind, then the scope
+/// their selectors require.
+#[derive(Debug, Clone, Default)]
+struct Index {
+    domains: Keyed<Keyed<ScopeTrie>>,
+}
+
+impl Index {
+    fn insert(&mut self, selector: &Selector, position: usize) {
+        self.domains
+            .entry(selector.domain())
+            .entry(selector.kind())
+            .insert(selector.scope(), position);
+    }
+
+    /// Returns, in ascending order, the positions of every route that might
+    /// match the manifest.
+    fn candidates(&self, manifest: &Manifest) -> Vec<usize> {
+        let mut positions = Vec::new();
+        for kinds in self.domains.get(&manifest.domain) {
+            for scopes in kinds.get(&manifest.kind) {
+                scopes.collect(&manifest.scope, &mut positions);
+            }
+        }
+        // Each route sits in exactly one bucket, so there are no duplicates.
+        positions.sort_unstable();
+        positions
+    }
+}
+
+/// Values keyed by a required field value, plus one for selectors that do
+/// not constrain the field.
+#[derive(Debug, Clone)]
+struct Keyed<V> {
+    by_value: HashMap<String, V>,
+    any: Option<V>,
+}
+
+impl<V> Default for Keyed<V> {
+    fn default() -> Self {
+        Keyed {
+            by_value: HashMap::new(),
+            any: None,
+        }
+    }
+}
+
+impl<V: Default> Keyed<V> {
+    fn entry(&mut self, value: Option<&str>) -> &mut V {
+        match value {
+            Some(value) => self.by_value.entry(value.to_string()).or_default(),
+            None => self.any.get_or_insert_with(V::default),
+        }
+    }
+
+    fn get<'a>(&'a self, value: &str) -> impl Iterator<Item = &'a V> + 'a {
+        self.by_value
+            .get(value)
+            .into_iter()
+            .chain(self.any.as_ref())
+    }
+}
+
+/// Route positions keyed by scope, one trie level per scope segment.
+#[derive(Debug, Clone, Default)]
+struct ScopeTrie {
+    any: Vec<usize>,
+    root: ScopeNode,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ScopeNode {
+    exact: Vec<usize>,
+    children: HashMap<String, ScopeNode>,
+}
+
+impl ScopeTrie {
+    fn insert(&mut self, scope: Option<&str>, position: usize) {
+        match scope {
+            Some(scope) => {
+                let mut node = &mut self.root;
+                for segment in scope.split('/') {
+                    node = node.children.entry(segment.to_string()).or_default();
+                }
+                node.exact.push(position);
+            }
+            None => self.any.push(position),
+        }
+    }
+
+    fn collect(&self, scope: &str, positions: &mut Vec<usize>) {
+        positions.extend_from_slice(&self.any);
+        let mut node = &self.root;
+        for segment in scope.split('/') {
+            match node.children.get(segment) {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+        positions.extend_from_slice(&node.exact);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn first_match_wins() {
+        let mut router = Router::new();
+        router
+            .add_named("cpu", "kind=cpu".parse().unwrap(), 1)
+            .add("scope=metrics/host".parse().unwrap(), 2);
+
+        let cpu = fixtures::cpu_manifest();
+        let route = router.route(&cpu).unwrap();
+        assert_eq!(route.name, "cpu");
+        assert_eq!(route.target, 1);
+        assert_eq!(router.matching(&cpu).count(), 2);
+
+        let netstat = fixtures::netstat_manifest();
+        let route = router.route(&netstat).unwrap();
+        assert_eq!(route.name, "route-1");
+        assert_eq!(route.target, 2);
+    }
+
+    #[test]
+    fn index_preserves_order() {
+        let mut router = Router::new();
+        router
+            .add("scope=metrics/host, kind=cpu".parse().unwrap(), 0)
+            .add("environment=production".parse().unwrap(), 1)
+            .add("domain=example.org, kind=netstat".parse().unwrap(), 2)
+            .add("domain=example.com".parse().unwrap(), 3)
+            .add("scope=metrics".parse().unwrap(), 4)
+            .add("kind=cpu, domain=example.org".parse().unwrap(), 5);
+
+        let targets = |manifest: &Manifest| -> Vec<i32> {
+            router
+                .matching(manifest)
+                .map(|route| route.target)
+                .collect()
+        };
+        assert_eq!(targets(&fixtures::cpu_manifest()), vec![0, 1, 5]);
+        assert_eq!(targets(&fixtures::netstat_manifest()), vec![1, 2]);
+        assert_eq!(
+            router.route(&fixtures::netstat_manifest()).unwrap().target,
+            1
+        );
+    }
+
+    #[test]
+    fn no_match() {
+        let mut router = Router::new();
+        router.add("kind=memory".parse().unwrap(), ());
+        assert!(router.route(&fixtures::cpu_manifest()).is_none());
+    }
+
+    #[test]
+    fn priorities_and_fallback() {
+        let mut router = Router::new();
+        router
+            .add_named("host", "scope=metrics/host".parse().unwrap(), 1)
+            .add_prioritized("cpu", 10, "kind=cpu".parse().unwrap(), 2)
+            .add_prioritized("archive", -1, "*".parse().unwrap(), 3)
+            .add_prioritized("cpu-again", 10, "kind=cpu".parse().unwrap(), 4);
+
+        let cpu = fixtures::cpu_manifest();
+        assert_eq!(router.route(&cpu).unwrap().name, "cpu");
+        let order: Vec<_> = router.matching(&cpu).map(|route| route.target).collect();
+        assert_eq!(order, vec![2, 4, 1, 3]);
+
+        let mut other = fixtures::netstat_manifest();
+        other.scope = "logs".into();
+        assert_eq!(router.route(&other).unwrap().name, "archive");
+
+        let mut router = Router::new();
+        router.add_named("memory", "kind=memory".parse().unwrap(), 1);
+        assert!(router.route(&cpu).is_none());
+        router.fallback(0);
+        assert_eq!(router.route(&cpu).unwrap().name, "fallback");
+    }
+
+    #[test]
+    fn routes_bytes_and_explains() {
+        let mut router = Router::new();
+        router
+            .add_named("memory", "kind=memory".parse().unwrap(), 1)
+            .add_named("production", "environment=production".parse().unwrap(), 2)
+            .add_named("cpu", "kind=cpu".parse().unwrap(), 3)
+            .fallback(0);
+
+        let route = router.route_bytes(fixtures::CPU_JSON.as_bytes()).unwrap();
+        assert_eq!(route.unwrap().name, "production");
+        assert!(router.route_bytes(b"\x00").is_err());
+
+        let trace = router.explain(&fixtures::cpu_manifest());
+        assert_eq!(
+            trace.to_string(),
+            "production (priority 0, `environment=production`): matched\n\
+             routed to production"
+        );
+
+        let mut manifest = fixtures::cpu_manifest();
+        manifest.labels.clear();
+        let trace = router.explain(&manifest);
+        assert!(!trace.fallback);
+        assert_eq!(trace.route.unwrap().name, "cpu");
+        assert_eq!(trace.consulted.len(), 2);
+
+        manifest.kind = "disk".into();
+        let trace = router.explain(&manifest);
+        assert!(trace.fallback);
+        assert!(trace
+            .to_string()
+            .ends_with("routed to fallback (no route matched)"));
+    }
+
+    #[test]
+    fn drops_or_redirects_expired() {
+        let mut router = Router::new();
+        router.add_named("cpu", "kind=cpu".parse().unwrap(), 1);
+        let mut manifest = fixtures::cpu_manifest();
+        manifest.expires = Some(manifest.ctime + chrono::Duration::hours(1));
+        let later = manifest.ctime + chrono::Duration::hours(2);
+
+        // Routers pay no heed to expiry unless told to.
+        assert_eq!(router.route_at(&manifest, later).unwrap().name, "cpu");
+
+        router.drop_expired();
+        assert!(router.route_at(&manifest, later).is_none());
+        assert_eq!(
+            router.route_at(&manifest, manifest.ctime).unwrap().name,
+            "cpu"
+        );
+        assert_eq!(
+            router.explain_at(&manifest, later).to_string(),
+            "dropped (expired)"
+        );
+
+        router.expired(0);
+        let trace = router.explain_at(&manifest, later);
+        assert!(trace.expired);
+        assert_eq!(trace.route.unwrap().target, 0);
+        assert_eq!(trace.to_string(), "routed to expired (expired)");
+        assert_eq!(router.matching(&manifest).count(), 1);
+    }
+}
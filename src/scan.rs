@@ -0,0 +1,389 @@
+//! Scanning archives on many threads.
+//!
+//! A [`ParallelScan`] splits an archive file into chunks of about the same
+//! size, on line boundaries for newline-delimited JSON and on frame
+//! boundaries for [framed](crate::framing) archives, and decodes the chunks
+//! on [rayon]'s thread pool. Envelopes not matching the scan's selector are
+//! skipped; in a framed archive, without decoding their content:
+//!
+//! ```no_run
+//! use intermodal::scan::ParallelScan;
+//!
+//! let scan = ParallelScan::ndjson("/var/spool/intermodal/2020-06-01.ndjson")
+//!     .selector("kind=cpu, environment=production".parse()?);
+//! let busy = scan.count_matching(|packet| packet.content["user"].as_f64() > Some(90.0))?;
+//! let origins = scan.filter_map(|packet| Some(packet.manifest.origin))?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Results come back in the order of the archive. Scans run on the pool
+//! they are called from, so [`ThreadPool::install`] bounds the threads a
+//! scan takes.
+//!
+//! Available with the `rayon` feature.
+//!
+//! [`ThreadPool::install`]: rayon::ThreadPool::install
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::framing::{self, Counted, FramedReader};
+use crate::{RawPacket, Selector};
+
+/// The chunk size scans use unless told otherwise.
+pub const DEFAULT_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How an archive lays out its envelopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// One JSON envelope per line.
+    Ndjson,
+    /// One [frame](crate::framing) per envelope.
+    Framed,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// An envelope could not be decoded.
+    Decode {
+        offset: u64,
+        message: String,
+    },
+    /// A frame is malformed.
+    Frame {
+        offset: u64,
+        error: framing::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Decode { offset, message } => {
+                write!(f, "envelope at byte {}: {}", offset, message)
+            }
+            Error::Frame { offset, error } => write!(f, "frame at byte {}: {}", offset, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Frame { error, .. } => Some(error),
+            Error::Decode { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A scan of one archive file.
+#[derive(Debug, Clone)]
+pub struct ParallelScan {
+    path: PathBuf,
+    layout: Layout,
+    selector: Option<Selector>,
+    chunk_size: u64,
+}
+
+impl ParallelScan {
+    /// Scans a file of newline-delimited JSON envelopes.
+    pub fn ndjson<P: Into<PathBuf>>(path: P) -> Self {
+        ParallelScan::new(path, Layout::Ndjson)
+    }
+
+    /// Scans a file of framed envelopes.
+    pub fn framed<P: Into<PathBuf>>(path: P) -> Self {
+        ParallelScan::new(path, Layout::Framed)
+    }
+
+    pub fn new<P: Into<PathBuf>>(path: P, layout: Layout) -> Self {
+        ParallelScan {
+            path: path.into(),
+            layout,
+            selector: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Scans only the envelopes matching `selector`.
+    pub fn selector(mut self, selector: Selector) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// Splits the archive into chunks of about `chunk_size` bytes, the unit
+    /// of work given to each thread. A framed chunk holds at least one
+    /// frame, however large.
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Folds each chunk's envelopes into a value starting from `identity`,
+    /// and reduces the chunks' values into one.
+    pub fn fold<A, I, F, R>(&self, identity: I, fold: F, reduce: R) -> Result<A, Error>
+    where
+        A: Send,
+        I: Fn() -> A + Sync + Send,
+        F: Fn(A, RawPacket) -> A + Sync + Send,
+        R: Fn(A, A) -> A + Sync + Send,
+    {
+        let chunks = self.chunks()?;
+        chunks
+            .into_par_iter()
+            .map(|chunk| self.scan_chunk(chunk, identity(), &fold))
+            .try_reduce(&identity, |a, b| Ok(reduce(a, b)))
+    }
+
+    /// The values `f` returns for each envelope, in the order of the
+    /// archive.
+    pub fn filter_map<T, F>(&self, f: F) -> Result<Vec<T>, Error>
+    where
+        T: Send,
+        F: Fn(RawPacket) -> Option<T> + Sync + Send,
+    {
+        self.fold(
+            Vec::new,
+            |mut found, packet| {
+                found.extend(f(packet));
+                found
+            },
+            |mut a, b| {
+                a.extend(b);
+                a
+            },
+        )
+    }
+
+    /// The number of envelopes for which `f` returns true.
+    pub fn count_matching<F>(&self, f: F) -> Result<u64, Error>
+    where
+        F: Fn(&RawPacket) -> bool + Sync + Send,
+    {
+        self.fold(|| 0, |n, packet| n + u64::from(f(&packet)), |a, b| a + b)
+    }
+
+    /// The byte ranges of the chunks, in order.
+    fn chunks(&self) -> Result<Vec<(u64, u64)>, Error> {
+        let file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        match self.layout {
+            // Line boundaries are found by each chunk's reader.
+            Layout::Ndjson => Ok((0..len.div_ceil(self.chunk_size))
+                .map(|i| (i * self.chunk_size, ((i + 1) * self.chunk_size).min(len)))
+                .collect()),
+            // Frames carry no marker to resynchronize on, so their
+            // boundaries are found by walking the frames' prefixes.
+            Layout::Framed => {
+                let mut chunks = Vec::new();
+                let (mut start, mut offset) = (0, 0);
+                while offset < len {
+                    let range = framing::frame_range_at(&file, offset)
+                        .map_err(|error| Error::Frame { offset, error })?;
+                    offset = range.range().end;
+                    if offset - start >= self.chunk_size {
+                        chunks.push((start, offset));
+                        start = offset;
+                    }
+                }
+                if start < offset {
+                    chunks.push((start, offset));
+                }
+                Ok(chunks)
+            }
+        }
+    }
+
+    fn scan_chunk<A, F>(&self, (start, end): (u64, u64), init: A, fold: &F) -> Result<A, Error>
+    where
+        F: Fn(A, RawPacket) -> A,
+    {
+        let mut file = File::open(&self.path)?;
+        let matches = |packet: &RawPacket| {
+            self.selector
+                .as_ref()
+                .is_none_or(|selector| selector.matches(&packet.manifest))
+        };
+        let mut acc = init;
+        match self.layout {
+            Layout::Ndjson => {
+                // A chunk holds the lines starting within it. Unless the
+                // chunk starts the file, the line running into it from
+                // before belongs to the previous chunk.
+                file.seek(SeekFrom::Start(start.saturating_sub(1)))?;
+                let mut reader = BufReader::new(file);
+                let mut line = Vec::new();
+                let mut offset = start;
+                if start > 0 {
+                    offset += reader.read_until(b'\n', &mut line)? as u64 - 1;
+                }
+                while offset < end {
+                    line.clear();
+                    let n = reader.read_until(b'\n', &mut line)?;
+                    if n == 0 {
+                        break;
+                    }
+                    let trimmed = line.trim_ascii();
+                    if !trimmed.is_empty() {
+                        let packet: RawPacket =
+                            serde_json::from_slice(trimmed).map_err(|e| Error::Decode {
+                                offset,
+                                message: e.to_string(),
+                            })?;
+                        if matches(&packet) {
+                            acc = fold(acc, packet);
+                        }
+                    }
+                    offset += n as u64;
+                }
+            }
+            Layout::Framed => {
+                file.seek(SeekFrom::Start(start))?;
+                let counted = Counted {
+                    inner: BufReader::new(file.take(end - start)),
+                    count: 0,
+                };
+                let mut reader = FramedReader::new(counted);
+                loop {
+                    let offset = start + reader.get_ref().count;
+                    let frame = match reader.read_frame() {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(error) => return Err(Error::Frame { offset, error }),
+                    };
+                    if let Some(selector) = &self.selector {
+                        if !selector.matches(&frame.manifest) {
+                            continue;
+                        }
+                    }
+                    let packet = frame
+                        .decode()
+                        .map_err(|error| Error::Frame { offset, error })?;
+                    acc = fold(acc, packet);
+                }
+            }
+        }
+        Ok(acc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::fixtures;
+    use crate::framing::FramedWriter;
+    use crate::Format;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("intermodal-scan-{}-{}", name, std::process::id()))
+    }
+
+    fn packets() -> Vec<RawPacket> {
+        (0..50)
+            .map(|i| {
+                let mut packet = if i % 3 == 0 {
+                    fixtures::netstat_raw()
+                } else {
+                    fixtures::cpu_raw()
+                };
+                packet.manifest.origin = format!("host{:02}", i);
+                packet
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scans_ndjson_in_chunks() {
+        let path = temp("ndjson");
+        let mut file = File::create(&path).unwrap();
+        for packet in packets() {
+            writeln!(file, "{}", serde_json::to_string(&packet).unwrap()).unwrap();
+            writeln!(file).unwrap();
+        }
+        drop(file);
+
+        let expected: Vec<_> = packets()
+            .into_iter()
+            .filter(|p| p.manifest.kind == "cpu")
+            .map(|p| p.manifest.origin)
+            .collect();
+        for chunk_size in [1, 7, 100, 1000, DEFAULT_CHUNK_SIZE] {
+            let scan = ParallelScan::ndjson(&path)
+                .chunk_size(chunk_size)
+                .selector("kind=cpu".parse().unwrap());
+            let origins = scan.filter_map(|p| Some(p.manifest.origin)).unwrap();
+            assert_eq!(origins, expected, "chunks of {} bytes", chunk_size);
+            assert_eq!(scan.count_matching(|_| true).unwrap(), 33);
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        writeln!(file, "not json").unwrap();
+        drop(file);
+        let len = std::fs::metadata(&path).unwrap().len();
+        match ParallelScan::ndjson(&path)
+            .chunk_size(500)
+            .count_matching(|_| true)
+        {
+            Err(Error::Decode { offset, .. }) => assert_eq!(offset, len - 9),
+            other => panic!("{:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn scans_frames_in_chunks() {
+        let path = temp("framed");
+        let mut writer = FramedWriter::new(File::create(&path).unwrap());
+        for packet in packets() {
+            writer.write_packet(&packet, Format::Json).unwrap();
+        }
+        drop(writer);
+
+        let expected: Vec<_> = packets()
+            .into_iter()
+            .filter(|p| p.manifest.kind == "netstat")
+            .map(|p| p.manifest.origin)
+            .collect();
+        for chunk_size in [1, 1000, DEFAULT_CHUNK_SIZE] {
+            let scan = ParallelScan::framed(&path)
+                .chunk_size(chunk_size)
+                .selector("kind=netstat".parse().unwrap());
+            let origins = scan.filter_map(|p| Some(p.manifest.origin)).unwrap();
+            assert_eq!(origins, expected, "chunks of {} bytes", chunk_size);
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"\x89IMF\x09").unwrap();
+        drop(file);
+        assert!(matches!(
+            ParallelScan::framed(&path).count_matching(|_| true),
+            Err(Error::Frame { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
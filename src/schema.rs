@@ -0,0 +1,427 @@
+//! Schemas for content, by the coordinates of its type.
+//!
+//! A [`SchemaRegistry`] holds a JSON Schema document for each registered set
+//! of [`Coordinates`]. A version's schema is immutable once registered: a
+//! change to a content type's schema means a new version.
+//! [`MemorySchemaRegistry`] keeps schemas in memory, and can read them from
+//! a file of its own, mapping coordinates to schemas:
+//!
+//! ```json
+//! { "example.org/metrics/host/uptime@1": { "type": "integer", "minimum": 0 } }
+//! ```
+//!
+//! so that, with [`MemorySchemaRegistry::loader`], a
+//! [`Reloadable`](crate::reload::Reloadable) registry picks up new versions'
+//! schemas as the file is edited. Other implementations can front a shared
+//! registry service.
+//!
+//! With the `schemars` feature, [`for_kind`] generates the schema of whole
+//! envelopes of a Rust content type, for publishing. With the `jsonschema`
+//! feature, [`Packet::validate_against`] checks a packet's content against
+//! the schema registered for its coordinates:
+//!
+//! ```
+//! # #[cfg(feature = "jsonschema")] {
+//! use intermodal::schema::{MemorySchemaRegistry, SchemaRegistry};
+//! use intermodal::{Manifest, Packet};
+//! use serde_json::json;
+//!
+//! let manifest = Manifest::builder()
+//!     .domain("example.org")
+//!     .scope("metrics/host")
+//!     .kind("uptime")
+//!     .version(1)
+//!     .origin("host01")
+//!     .build()
+//!     .unwrap();
+//!
+//! let registry = MemorySchemaRegistry::new();
+//! registry
+//!     .register_schema(manifest.coordinates(), json!({ "type": "integer", "minimum": 0 }))
+//!     .unwrap();
+//!
+//! assert!(Packet::new(manifest.clone(), 86400).validate_against(&registry).is_ok());
+//! assert!(Packet::new(manifest, -1).validate_against(&registry).is_err());
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde_json::Value;
+
+use crate::version_policy::VersionPolicy;
+use crate::Coordinates;
+
+/// Storage for content schemas.
+pub trait SchemaRegistry: Send + Sync {
+    /// Registers the schema for content with the given coordinates.
+    /// Registering the same schema again succeeds; registering a different
+    /// one fails with [`Error::Conflict`].
+    fn register_schema(&self, coordinates: Coordinates, schema: Value) -> Result<(), Error>;
+
+    /// Returns the schema registered for the coordinates, if any.
+    fn get_schema(&self, coordinates: &Coordinates) -> Result<Option<Value>, Error>;
+}
+
+/// Keeps schemas in memory.
+#[derive(Debug, Default)]
+pub struct MemorySchemaRegistry {
+    schemas: RwLock<HashMap<Coordinates, Value>>,
+}
+
+impl MemorySchemaRegistry {
+    pub fn new() -> Self {
+        MemorySchemaRegistry::default()
+    }
+
+    /// Reads a registry from a JSON file mapping coordinates to schemas,
+    /// registering each schema as [`register_schema`] does.
+    ///
+    /// [`register_schema`]: SchemaRegistry::register_schema
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Load(format!("{}: {}", path.display(), e)))?;
+        let schemas: BTreeMap<String, Value> =
+            serde_json::from_str(&text).map_err(|e| Error::Load(e.to_string()))?;
+        let registry = MemorySchemaRegistry::new();
+        for (coordinates, schema) in schemas {
+            let coordinates: Coordinates = coordinates
+                .parse()
+                .map_err(|e| Error::Load(format!("`{}`: {}", coordinates, e)))?;
+            registry.register_schema(coordinates, schema)?;
+        }
+        Ok(registry)
+    }
+
+    /// Returns a loader that reads a registry with [`from_path`], suitable
+    /// for [`Reloadable::reload_from`] and
+    /// [`reload::watch`](crate::reload).
+    ///
+    /// [`from_path`]: MemorySchemaRegistry::from_path
+    /// [`Reloadable::reload_from`]: crate::reload::Reloadable::reload_from
+    pub fn loader() -> impl Fn(&Path) -> Result<MemorySchemaRegistry, Error> {
+        |path| MemorySchemaRegistry::from_path(path)
+    }
+
+    /// The schema for content with the coordinates, or failing that the
+    /// schema of another version of its type that `policy` accepts it for,
+    /// as [`VersionPolicy::select`] picks it, with that schema's
+    /// coordinates.
+    pub fn find_schema(
+        &self,
+        coordinates: &Coordinates,
+        policy: VersionPolicy,
+    ) -> Option<(Coordinates, Value)> {
+        let schemas = self.schemas.read().unwrap_or_else(|e| e.into_inner());
+        let supported = policy.select(schemas.keys(), coordinates)?;
+        Some((supported.clone(), schemas[supported].clone()))
+    }
+
+    /// Every registered schema, in order of coordinates.
+    pub fn schemas(&self) -> Vec<(Coordinates, Value)> {
+        let schemas = self.schemas.read().unwrap_or_else(|e| e.into_inner());
+        let mut schemas: Vec<_> = schemas
+            .iter()
+            .map(|(coordinates, schema)| (coordinates.clone(), schema.clone()))
+            .collect();
+        schemas.sort_by(|a, b| a.0.cmp(&b.0));
+        schemas
+    }
+}
+
+impl SchemaRegistry for MemorySchemaRegistry {
+    fn register_schema(&self, coordinates: Coordinates, schema: Value) -> Result<(), Error> {
+        #[cfg(feature = "jsonschema")]
+        jsonschema::validator_for(&schema).map_err(|e| Error::InvalidSchema(e.to_string()))?;
+
+        let mut schemas = self.schemas.write().unwrap_or_else(|e| e.into_inner());
+        match schemas.get(&coordinates) {
+            Some(existing) if *existing != schema => Err(Error::Conflict(coordinates)),
+            Some(_) => Ok(()),
+            None => {
+                schemas.insert(coordinates, schema);
+                Ok(())
+            }
+        }
+    }
+
+    fn get_schema(&self, coordinates: &Coordinates) -> Result<Option<Value>, Error> {
+        let schemas = self.schemas.read().unwrap_or_else(|e| e.into_inner());
+        Ok(schemas.get(coordinates).cloned())
+    }
+}
+
+#[cfg(feature = "jsonschema")]
+impl<T: serde::Serialize> crate::Packet<T> {
+    /// Checks the content against the schema registered for the packet's
+    /// coordinates, reporting every violation.
+    ///
+    /// The schema is compiled on each call; callers validating many packets
+    /// of one type should compile it once with the `jsonschema` crate.
+    pub fn validate_against<R: SchemaRegistry + ?Sized>(&self, registry: &R) -> Result<(), Error> {
+        let coordinates = self.manifest.coordinates();
+        let schema = registry
+            .get_schema(&coordinates)?
+            .ok_or_else(|| Error::Unregistered(coordinates.clone()))?;
+        let validator =
+            jsonschema::validator_for(&schema).map_err(|e| Error::InvalidSchema(e.to_string()))?;
+        let content =
+            serde_json::to_value(&self.content).map_err(|e| Error::Content(e.to_string()))?;
+
+        let violations: Vec<_> = validator
+            .iter_errors(&content)
+            .map(|e| Violation {
+                pointer: e.instance_path().to_string(),
+                message: e.to_string(),
+            })
+            .collect();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid {
+                coordinates,
+                violations,
+            })
+        }
+    }
+}
+
+/// Generates the JSON Schema of envelopes of content type `T` with the
+/// given coordinates, for publishing to a schema catalog.
+///
+/// The schema describes the whole envelope: the manifest, with its
+/// `domain`, `scope`, `kind` and `version` fixed to the coordinates, and
+/// content as `T` describes it. Available with the `schemars` feature.
+///
+/// ```
+/// # #[cfg(feature = "schemars")] {
+/// use intermodal::Coordinates;
+///
+/// #[derive(schemars::JsonSchema)]
+/// struct Uptime {
+///     seconds: u64,
+/// }
+///
+/// let coordinates = Coordinates::new("example.org", "metrics/host", "uptime", 1);
+/// let schema = intermodal::schema::for_kind::<Uptime>(&coordinates);
+/// assert_eq!(schema["properties"]["manifest"]["properties"]["kind"]["const"], "uptime");
+/// # }
+/// ```
+#[cfg(feature = "schemars")]
+pub fn for_kind<T: schemars::JsonSchema>(coordinates: &Coordinates) -> Value {
+    let mut schema = schemars::schema_for!(crate::Packet<T>).to_value();
+    schema["title"] = Value::String(coordinates.to_string());
+    schema["properties"]["manifest"] = serde_json::json!({
+        "allOf": [{ "$ref": "#/$defs/Manifest" }],
+        "properties": {
+            "domain": { "const": coordinates.domain },
+            "scope": { "const": coordinates.scope },
+            "kind": { "const": coordinates.kind },
+            "version": { "const": coordinates.version },
+        },
+    });
+    schema
+}
+
+/// One way in which content does not match its schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// A JSON pointer to the offending value within the content.
+    pub pointer: String,
+    pub message: String,
+}
+
+/// An error registering or looking up a schema, or validating against one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// No schema is registered for the coordinates.
+    Unregistered(Coordinates),
+    /// A different schema is already registered for the coordinates.
+    Conflict(Coordinates),
+    /// The schema is not a valid JSON Schema document.
+    InvalidSchema(String),
+    /// The content could not be converted to JSON.
+    Content(String),
+    /// The content does not match its schema.
+    Invalid {
+        coordinates: Coordinates,
+        violations: Vec<Violation>,
+    },
+    /// The registry's backing store failed.
+    Backend(String),
+    /// A registry file could not be read, or parsed.
+    Load(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unregistered(coordinates) => {
+                write!(f, "no schema registered for {}", coordinates)
+            }
+            Error::Conflict(coordinates) => write!(
+                f,
+                "a different schema is already registered for {}",
+                coordinates
+            ),
+            Error::InvalidSchema(message) => write!(f, "invalid schema: {}", message),
+            Error::Content(message) => write!(f, "invalid content: {}", message),
+            Error::Invalid {
+                coordinates,
+                violations,
+            } => {
+                write!(f, "content does not match the schema for {}", coordinates)?;
+                for violation in violations {
+                    write!(f, "; at `{}`: {}", violation.pointer, violation.message)?;
+                }
+                Ok(())
+            }
+            Error::Backend(message) => write!(f, "schema registry: {}", message),
+            Error::Load(message) => write!(f, "cannot load schemas: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::json;
+
+    #[test]
+    fn reloads_from_a_file() {
+        use crate::reload::Reloadable;
+
+        let path =
+            std::env::temp_dir().join(format!("intermodal-schemas-{}.json", std::process::id()));
+        let cpu = fixtures::cpu_manifest().coordinates();
+        std::fs::write(
+            &path,
+            json!({ cpu.to_string(): { "type": "object" } }).to_string(),
+        )
+        .unwrap();
+        let registry = Reloadable::new(MemorySchemaRegistry::new());
+        registry
+            .reload_from(&path, MemorySchemaRegistry::loader())
+            .unwrap();
+        assert_eq!(
+            registry.load().get_schema(&cpu).unwrap(),
+            Some(json!({ "type": "object" }))
+        );
+
+        std::fs::write(&path, r#"{ "cpu": {} }"#).unwrap();
+        assert!(matches!(
+            registry.reload_from(&path, MemorySchemaRegistry::loader()),
+            Err(Error::Load(_))
+        ));
+        assert!(registry.load().get_schema(&cpu).unwrap().is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn registers_once_per_version() {
+        let registry = MemorySchemaRegistry::new();
+        let coordinates = fixtures::cpu_manifest().coordinates();
+        let schema = json!({ "type": "object" });
+        registry
+            .register_schema(coordinates.clone(), schema.clone())
+            .unwrap();
+        registry
+            .register_schema(coordinates.clone(), schema.clone())
+            .unwrap();
+        assert_eq!(
+            registry.register_schema(coordinates.clone(), json!({ "type": "array" })),
+            Err(Error::Conflict(coordinates.clone()))
+        );
+        assert_eq!(
+            registry.get_schema(&coordinates).unwrap(),
+            Some(schema.clone())
+        );
+        let netstat = fixtures::netstat_manifest().coordinates();
+        assert_eq!(registry.get_schema(&netstat).unwrap(), None);
+
+        let mut older = coordinates.clone();
+        older.version = 0;
+        assert_eq!(registry.find_schema(&older, VersionPolicy::Exact), None);
+        assert_eq!(
+            registry.find_schema(&older, VersionPolicy::Backward),
+            Some((coordinates, schema))
+        );
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn generates_envelope_schemas() {
+        let coordinates = fixtures::cpu_manifest().coordinates();
+        let schema = for_kind::<HashMap<String, f64>>(&coordinates);
+        assert_eq!(schema["title"], "example.org/metrics/host/cpu@1");
+        let manifest = &schema["properties"]["manifest"];
+        assert_eq!(manifest["allOf"][0]["$ref"], "#/$defs/Manifest");
+        assert_eq!(manifest["properties"]["version"]["const"], 1);
+        assert!(schema["$defs"]["Manifest"]["required"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("ctime")));
+        assert_eq!(
+            schema["properties"]["content"]["additionalProperties"]["type"],
+            "number"
+        );
+
+        #[cfg(feature = "jsonschema")]
+        {
+            let validator = jsonschema::validator_for(&schema).unwrap();
+            let cpu = serde_json::to_value(fixtures::cpu_raw()).unwrap();
+            assert!(validator.is_valid(&cpu));
+            let netstat = serde_json::to_value(fixtures::netstat_raw()).unwrap();
+            assert!(!validator.is_valid(&netstat));
+        }
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn validates_content() {
+        let registry = MemorySchemaRegistry::new();
+        let cpu = fixtures::cpu_raw();
+        assert!(matches!(
+            cpu.validate_against(&registry),
+            Err(Error::Unregistered(_))
+        ));
+
+        let schema = json!({
+            "type": "object",
+            "required": ["user", "system", "idle"],
+            "properties": {
+                "user": { "type": "number", "maximum": 100 },
+                "idle": { "type": "number", "maximum": 100 }
+            }
+        });
+        registry
+            .register_schema(cpu.manifest.coordinates(), schema)
+            .unwrap();
+        cpu.validate_against(&registry).unwrap();
+
+        let mut bad = cpu.clone();
+        bad.content = json!({ "user": 120, "idle": "none" });
+        let violations = match bad.validate_against(&registry) {
+            Err(Error::Invalid { violations, .. }) => violations,
+            other => panic!("{:?}", other),
+        };
+        let mut pointers: Vec<_> = violations.iter().map(|v| v.pointer.as_str()).collect();
+        pointers.sort_unstable();
+        assert_eq!(pointers, ["", "/idle", "/user"]);
+
+        assert!(matches!(
+            registry.register_schema(
+                fixtures::netstat_manifest().coordinates(),
+                json!({ "type": 7 })
+            ),
+            Err(Error::InvalidSchema(_))
+        ));
+    }
+}
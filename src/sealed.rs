@@ -0,0 +1,245 @@
+//! Encrypting content while leaving the manifest in the clear.
+//!
+//! [`Packet::seal`] encrypts a packet's content with XChaCha20-Poly1305 into
+//! a [`SealedPacket`], which keeps the manifest readable, so that sealed
+//! envelopes can still be routed, filtered and counted on their way to a
+//! receiver holding the key. The ciphertext is recorded with the algorithm
+//! and the id of the key it was sealed with, for the receiver to pick the
+//! key to [`open`](SealedPacket::open) it with:
+//!
+//! ```
+//! use intermodal::encryption::Key;
+//! use intermodal::sealed::SealedPacket;
+//! # let packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "uptime", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": 86400
+//! # })).unwrap();
+//!
+//! let key = Key::new("hosts-2020", [7; 32]);
+//! let sealed = packet.seal(&key).unwrap();
+//! let json = serde_json::to_string(&sealed).unwrap();
+//! assert!(json.contains(r#""key_id":"hosts-2020""#));
+//!
+//! let sealed: SealedPacket = serde_json::from_str(&json).unwrap();
+//! let opened = sealed.open::<u64>(&key).unwrap();
+//! assert_eq!(opened.content, 86400);
+//! ```
+//!
+//! A sealed envelope encodes as `{"manifest": ..., "sealed": ...}`, so that
+//! a [`Header`](crate::Header) decodes from it as from any other envelope.
+//! The ciphertext is bound to the content's coordinates: opening fails if
+//! they were changed, while the rest of the manifest, such as its labels,
+//! may be changed on the way. A packet's signature is not sealed with it.
+//!
+//! Available with the `encryption` feature.
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::encryption::{Key, ALGORITHM};
+use crate::keys::{self, KeyProvider};
+use crate::{hex, Manifest, Packet};
+
+/// Encrypted content, and how to decrypt it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Sealed {
+    pub algorithm: String,
+    /// The id of the key the content was sealed with.
+    pub key_id: String,
+    /// The nonce followed by the encrypted JSON encoding of the content,
+    /// hex-encoded.
+    pub ciphertext: String,
+}
+
+/// An envelope whose content is sealed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SealedPacket {
+    pub manifest: Manifest,
+    pub sealed: Sealed,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The content was sealed with an algorithm other than [`ALGORITHM`].
+    Algorithm(String),
+    /// The content was sealed with another key.
+    KeyId { expected: String, found: String },
+    /// The ciphertext is not hex-encoded.
+    Malformed,
+    /// The ciphertext does not decrypt with the key and coordinates.
+    Decrypt,
+    /// The content could not be encoded or decoded as JSON.
+    Json(serde_json::Error),
+    /// The key could not be had from its provider.
+    Key(keys::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Algorithm(algorithm) => write!(f, "unsupported algorithm `{}`", algorithm),
+            Error::KeyId { expected, found } => write!(
+                f,
+                "content is sealed with key `{}`, not `{}`",
+                found, expected
+            ),
+            Error::Malformed => f.write_str("ciphertext is not hex-encoded"),
+            Error::Decrypt => f.write_str("content does not decrypt"),
+            Error::Json(e) => e.fmt(f),
+            Error::Key(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(e) => Some(e),
+            Error::Key(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<keys::Error> for Error {
+    fn from(e: keys::Error) -> Self {
+        Error::Key(e)
+    }
+}
+
+impl<T: Serialize> Packet<T> {
+    /// Encrypts the content with `key`.
+    pub fn seal(&self, key: &Key) -> Result<SealedPacket, Error> {
+        let plaintext = serde_json::to_vec(&self.content)?;
+        let aad = self.manifest.coordinates().to_string();
+        let ciphertext = key.encrypt(&plaintext, aad.as_bytes());
+        Ok(SealedPacket {
+            manifest: self.manifest.clone(),
+            sealed: Sealed {
+                algorithm: ALGORITHM.to_string(),
+                key_id: key.id().to_string(),
+                ciphertext: hex::encode(&ciphertext),
+            },
+        })
+    }
+
+    /// Encrypts the content with the provider's current key.
+    pub fn seal_with<P: KeyProvider + ?Sized>(&self, keys: &P) -> Result<SealedPacket, Error> {
+        self.seal(&keys.current()?.encryption_key()?)
+    }
+}
+
+impl SealedPacket {
+    /// Decrypts the content with `key`, and decodes it as a `T`.
+    pub fn open<T: DeserializeOwned>(&self, key: &Key) -> Result<Packet<T>, Error> {
+        if self.sealed.algorithm != ALGORITHM {
+            return Err(Error::Algorithm(self.sealed.algorithm.clone()));
+        }
+        if self.sealed.key_id != key.id() {
+            return Err(Error::KeyId {
+                expected: key.id().to_string(),
+                found: self.sealed.key_id.clone(),
+            });
+        }
+        let ciphertext = hex::decode(&self.sealed.ciphertext).ok_or(Error::Malformed)?;
+        let aad = self.manifest.coordinates().to_string();
+        let plaintext = key
+            .decrypt(&ciphertext, aad.as_bytes())
+            .ok_or(Error::Decrypt)?;
+        let content = serde_json::from_slice(&plaintext)?;
+        Ok(Packet::new(self.manifest.clone(), content))
+    }
+
+    /// Decrypts the content with the key it was sealed with, from the
+    /// provider, and decodes it as a `T`.
+    pub fn open_with<T, P>(&self, keys: &P) -> Result<Packet<T>, Error>
+    where
+        T: DeserializeOwned,
+        P: KeyProvider + ?Sized,
+    {
+        let id = &self.sealed.key_id;
+        let key = keys
+            .key(id)?
+            .ok_or_else(|| keys::Error::Unknown(id.clone()))?;
+        self.open(&key.encryption_key()?)
+    }
+}
+
+impl AsRef<Manifest> for SealedPacket {
+    fn as_ref(&self) -> &Manifest {
+        &self.manifest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+    use crate::Header;
+
+    #[test]
+    fn seals_and_opens() {
+        let key = Key::new("k1", [1; 32]);
+        let packet = fixtures::cpu_raw();
+        let sealed = packet.seal(&key).unwrap();
+        assert_eq!(sealed.sealed.algorithm, "xchacha20poly1305");
+        assert_eq!(sealed.sealed.key_id, "k1");
+
+        let json = serde_json::to_string(&sealed).unwrap();
+        assert!(!json.contains("user"));
+        let header: Header = serde_json::from_str(&json).unwrap();
+        assert_eq!(header.manifest, packet.manifest);
+
+        let opened: Packet<Cpu> = sealed.open(&key).unwrap();
+        assert_eq!(
+            opened,
+            packet.clone().try_map(serde_json::from_value).unwrap()
+        );
+
+        assert!(matches!(
+            sealed.open::<Cpu>(&Key::new("k2", [1; 32])),
+            Err(Error::KeyId { .. })
+        ));
+        assert!(matches!(
+            sealed.open::<Cpu>(&Key::new("k1", [2; 32])),
+            Err(Error::Decrypt)
+        ));
+
+        let mut relabelled = sealed.clone();
+        relabelled
+            .manifest
+            .labels
+            .insert("hop".to_string(), "1".to_string());
+        assert!(relabelled.open::<Cpu>(&key).is_ok());
+        let mut moved = sealed;
+        moved.manifest.kind = "memory".to_string();
+        assert!(matches!(moved.open::<Cpu>(&key), Err(Error::Decrypt)));
+    }
+
+    #[test]
+    fn seals_with_providers() {
+        use crate::keys::{KeyEntry, KeySet};
+
+        let keys = KeySet::new().insert(KeyEntry::new("k1", vec![1; 32]));
+        let sealed = fixtures::cpu_raw().seal_with(&keys).unwrap();
+        assert_eq!(sealed.sealed.key_id, "k1");
+        let opened: Packet<Cpu> = sealed.open_with(&keys).unwrap();
+        assert_eq!(opened.content.user, 12.5);
+        assert!(matches!(
+            sealed.open_with::<Cpu, _>(&KeySet::new()),
+            Err(Error::Key(keys::Error::Unknown(_)))
+        ));
+    }
+}
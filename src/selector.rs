@@ -0,0 +1,741 @@
+use std::fmt::{self, Write as _};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Manifest;
+
+/// A predicate over manifests.
+///
+/// Selectors are written as a comma-separated list of terms, all of which
+/// must hold for a manifest to match:
+///
+/// ```text
+/// domain=example.org, scope=metrics/*, kind in (cpu, mem), version>=2
+/// ```
+///
+/// The fields `domain`, `scope`, `kind`, `version` and `origin` refer to the
+/// manifest fields of the same name; any other field name refers to a label.
+/// Terms take the forms, after Kubernetes label selectors:
+///
+/// | term                  | holds when the field                        |
+/// |-----------------------|---------------------------------------------|
+/// | `field=value`         | matches the value (`==` also works)         |
+/// | `field="value"`       | equals the value exactly                    |
+/// | `field!=value`        | does not match the value                    |
+/// | `field in (a, b)`     | matches one of the values                   |
+/// | `field notin (a, b)`  | matches none of the values                  |
+/// | `label`               | is a label the manifest has                 |
+/// | `!label`              | is a label the manifest does not have       |
+/// | `version>=2`          | compares so (also `>`, `<` and `<=`)        |
+///
+/// Values may be glob patterns, in which `*` matches any run of characters
+/// and `?` any one character. A label the manifest does not have matches no
+/// value, so satisfies `!=` and `notin` terms. A quoted value is no
+/// pattern, and may hold any character, with `"` and `\` escaped by a `\`.
+/// Versions compare as numbers and take no patterns. An empty selector, or
+/// `*`, matches every manifest.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selector {
+    terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Text { field: Field, op: Op },
+    Version { cmp: Cmp, version: u32 },
+    Exists { key: String, exists: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Domain,
+    Scope,
+    Kind,
+    Origin,
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Eq(Pattern),
+    /// Equals the value exactly, even if it looks like a pattern.
+    Is(String),
+    Ne(Pattern),
+    In(Vec<Pattern>),
+    NotIn(Vec<Pattern>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A value, or a glob pattern if it contains `*` or `?`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Pattern(pub(crate) String);
+
+impl Selector {
+    /// A selector matching every manifest.
+    pub fn any() -> Self {
+        Selector::default()
+    }
+
+    /// Returns whether the manifest satisfies every term of the selector.
+    pub fn matches(&self, manifest: &Manifest) -> bool {
+        self.terms.iter().all(|term| term.matches(manifest))
+    }
+
+    /// A selector requiring each field given to equal its value exactly,
+    /// taking no patterns, and the version to equal `version` if given.
+    ///
+    /// Unlike parsing, this takes values from untrusted sources as they are.
+    pub fn exact(
+        domain: Option<String>,
+        scope: Option<String>,
+        kind: Option<String>,
+        version: Option<u32>,
+    ) -> Self {
+        let fields = vec![
+            (Field::Domain, domain),
+            (Field::Scope, scope),
+            (Field::Kind, kind),
+        ];
+        let mut terms: Vec<Term> = fields
+            .into_iter()
+            .filter_map(|(field, value)| {
+                value.map(|value| Term::Text {
+                    field,
+                    op: Op::Is(value),
+                })
+            })
+            .collect();
+        if let Some(version) = version {
+            terms.push(Term::Version {
+                cmp: Cmp::Eq,
+                version,
+            });
+        }
+        Selector { terms }
+    }
+
+    /// The domain the selector requires, if it constrains the domain to
+    /// exactly one value.
+    pub(crate) fn domain(&self) -> Option<&str> {
+        self.required(|field| matches!(field, Field::Domain))
+    }
+
+    /// The scope the selector requires, if it constrains the scope to
+    /// exactly one value.
+    pub(crate) fn scope(&self) -> Option<&str> {
+        self.required(|field| matches!(field, Field::Scope))
+    }
+
+    /// The kind the selector requires, if it constrains the kind to exactly
+    /// one value.
+    pub(crate) fn kind(&self) -> Option<&str> {
+        self.required(|field| matches!(field, Field::Kind))
+    }
+
+    /// The version the selector requires, if it constrains the version to
+    /// exactly one.
+    #[cfg(feature = "nats")]
+    pub(crate) fn version(&self) -> Option<u32> {
+        self.terms.iter().find_map(|term| match term {
+            Term::Version {
+                cmp: Cmp::Eq,
+                version,
+            } => Some(*version),
+            _ => None,
+        })
+    }
+
+    fn required(&self, wanted: impl Fn(&Field) -> bool) -> Option<&str> {
+        self.terms.iter().find_map(|term| match term {
+            Term::Text {
+                field,
+                op: Op::Eq(pattern),
+            } if wanted(field) && !pattern.is_glob() => Some(pattern.0.as_str()),
+            Term::Text {
+                field,
+                op: Op::Is(value),
+            } if wanted(field) => Some(value.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl Term {
+    fn matches(&self, manifest: &Manifest) -> bool {
+        match self {
+            Term::Text { field, op } => {
+                let value = match field {
+                    Field::Domain => Some(&manifest.domain),
+                    Field::Scope => Some(&manifest.scope),
+                    Field::Kind => Some(&manifest.kind),
+                    Field::Origin => Some(&manifest.origin),
+                    Field::Label(key) => manifest.labels.get(key),
+                };
+                let any = |patterns: &[Pattern]| {
+                    value.is_some_and(|value| patterns.iter().any(|p| p.matches(value)))
+                };
+                match op {
+                    Op::Eq(pattern) => any(std::slice::from_ref(pattern)),
+                    Op::Is(expected) => value == Some(expected),
+                    Op::Ne(pattern) => !any(std::slice::from_ref(pattern)),
+                    Op::In(patterns) => any(patterns),
+                    Op::NotIn(patterns) => !any(patterns),
+                }
+            }
+            Term::Version { cmp, version } => {
+                let actual = manifest.version;
+                match cmp {
+                    Cmp::Eq => actual == *version,
+                    Cmp::Ne => actual != *version,
+                    Cmp::Lt => actual < *version,
+                    Cmp::Le => actual <= *version,
+                    Cmp::Gt => actual > *version,
+                    Cmp::Ge => actual >= *version,
+                }
+            }
+            Term::Exists { key, exists } => manifest.labels.contains_key(key) == *exists,
+        }
+    }
+}
+
+impl Field {
+    fn key(&self) -> &str {
+        match self {
+            Field::Domain => "domain",
+            Field::Scope => "scope",
+            Field::Kind => "kind",
+            Field::Origin => "origin",
+            Field::Label(key) => key,
+        }
+    }
+}
+
+impl Pattern {
+    fn is_glob(&self) -> bool {
+        self.0.contains(['*', '?'])
+    }
+
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        if !self.is_glob() {
+            return self.0 == value;
+        }
+        // Backtracks only to the most recent `*`, which suffices because a
+        // later `*` can absorb anything an earlier one could.
+        let pattern: Vec<char> = self.0.chars().collect();
+        let value: Vec<char> = value.chars().collect();
+        let (mut p, mut v) = (0, 0);
+        let mut star = None;
+        while v < value.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    star = Some((p, v));
+                    p += 1;
+                }
+                Some(&c) if c == '?' || c == value[v] => {
+                    p += 1;
+                    v += 1;
+                }
+                _ => match star {
+                    Some((star_p, star_v)) => {
+                        star = Some((star_p, star_v + 1));
+                        p = star_p + 1;
+                        v = star_v + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.terms.is_empty() {
+            return f.write_str("*");
+        }
+        for (i, term) in self.terms.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", term)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter<'_>, patterns: &[Pattern]| {
+            let values: Vec<_> = patterns.iter().map(|p| p.0.as_str()).collect();
+            write!(f, "({})", values.join(", "))
+        };
+        match self {
+            Term::Text { field, op } => match op {
+                Op::Eq(pattern) => write!(f, "{}={}", field.key(), pattern.0),
+                Op::Is(value) => {
+                    write!(f, "{}=\"", field.key())?;
+                    for c in value.chars() {
+                        if c == '"' || c == '\\' {
+                            f.write_char('\\')?;
+                        }
+                        f.write_char(c)?;
+                    }
+                    f.write_char('"')
+                }
+                Op::Ne(pattern) => write!(f, "{}!={}", field.key(), pattern.0),
+                Op::In(patterns) => {
+                    write!(f, "{} in ", field.key())?;
+                    list(f, patterns)
+                }
+                Op::NotIn(patterns) => {
+                    write!(f, "{} notin ", field.key())?;
+                    list(f, patterns)
+                }
+            },
+            Term::Version { cmp, version } => {
+                let op = match cmp {
+                    Cmp::Eq => "=",
+                    Cmp::Ne => "!=",
+                    Cmp::Lt => "<",
+                    Cmp::Le => "<=",
+                    Cmp::Gt => ">",
+                    Cmp::Ge => ">=",
+                };
+                write!(f, "version{}{}", op, version)
+            }
+            Term::Exists { key, exists: true } => f.write_str(key),
+            Term::Exists { key, exists: false } => write!(f, "!{}", key),
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s == "*" {
+            return Ok(Selector::any());
+        }
+
+        let mut terms = Vec::new();
+        for part in split_terms(s)? {
+            terms.push(parse_term(part.trim())?);
+        }
+        Ok(Selector { terms })
+    }
+}
+
+/// Splits a selector at the commas that are not within a value list or a
+/// quoted value.
+fn split_terms(s: &str) -> Result<Vec<&str>, ParseError> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let (mut quoted, mut escaped) = (false, false);
+    for (i, c) in s.char_indices() {
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = true,
+            '(' if depth == 0 => depth = 1,
+            '(' => return Err(ParseError::new(format!("nested `(` in `{}`", s))),
+            ')' if depth == 1 => depth = 0,
+            ')' => return Err(ParseError::new(format!("unmatched `)` in `{}`", s))),
+            ',' if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(ParseError::new(format!("unclosed `\"` in `{}`", s)));
+    }
+    if depth > 0 {
+        return Err(ParseError::new(format!("unclosed `(` in `{}`", s)));
+    }
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+fn parse_term(part: &str) -> Result<Term, ParseError> {
+    if let Some(key) = part.strip_prefix('!') {
+        let key = key.trim();
+        if !is_key(key) {
+            return Err(ParseError::new(format!("invalid label name in `{}`", part)));
+        }
+        return Ok(Term::Exists {
+            key: key.to_string(),
+            exists: false,
+        });
+    }
+
+    let end = part
+        .find(|c: char| c.is_whitespace() || "=!<>(),".contains(c))
+        .unwrap_or(part.len());
+    let (key, rest) = (&part[..end], part[end..].trim_start());
+    if key.is_empty() {
+        return Err(ParseError::new(format!("missing field name in `{}`", part)));
+    }
+    if rest.is_empty() {
+        return match key {
+            "domain" | "scope" | "kind" | "version" | "origin" => {
+                Err(ParseError::new(format!("expected a value for `{}`", key)))
+            }
+            _ => Ok(Term::Exists {
+                key: key.to_string(),
+                exists: true,
+            }),
+        };
+    }
+
+    let operators = [
+        ("==", "="),
+        ("!=", "!="),
+        (">=", ">="),
+        ("<=", "<="),
+        ("=", "="),
+        (">", ">"),
+        ("<", "<"),
+    ];
+    if let Some((written, op)) = operators
+        .iter()
+        .find(|(written, _)| rest.starts_with(written))
+    {
+        let value = rest[written.len()..].trim();
+        return parse_comparison(key, op, value, part);
+    }
+
+    let (negated, list) = if let Some(list) = strip_word(rest, "notin") {
+        (true, list)
+    } else if let Some(list) = strip_word(rest, "in") {
+        (false, list)
+    } else {
+        return Err(ParseError::new(format!(
+            "expected `field=value`, found `{}`",
+            part
+        )));
+    };
+    let values = list
+        .strip_prefix('(')
+        .and_then(|list| list.strip_suffix(')'))
+        .ok_or_else(|| ParseError::new(format!("expected `(values)` in `{}`", part)))?;
+    let patterns: Vec<_> = values
+        .split(',')
+        .map(|value| Pattern(value.trim().to_string()))
+        .filter(|pattern| !pattern.0.is_empty())
+        .collect();
+    if patterns.is_empty() {
+        return Err(ParseError::new(format!("empty value list in `{}`", part)));
+    }
+    if key == "version" {
+        return Err(ParseError::new(format!(
+            "versions take comparisons, not value lists, in `{}`",
+            part
+        )));
+    }
+    let op = if negated {
+        Op::NotIn(patterns)
+    } else {
+        Op::In(patterns)
+    };
+    Ok(Term::Text {
+        field: field(key),
+        op,
+    })
+}
+
+fn parse_comparison(key: &str, op: &str, value: &str, part: &str) -> Result<Term, ParseError> {
+    if key == "version" {
+        let version = value
+            .parse()
+            .map_err(|_| ParseError::new(format!("invalid version `{}`", value)))?;
+        let cmp = match op {
+            "=" => Cmp::Eq,
+            "!=" => Cmp::Ne,
+            "<" => Cmp::Lt,
+            "<=" => Cmp::Le,
+            ">" => Cmp::Gt,
+            _ => Cmp::Ge,
+        };
+        return Ok(Term::Version { cmp, version });
+    }
+    if let Some(quoted) = value.strip_prefix('"') {
+        if op != "=" {
+            return Err(ParseError::new(format!(
+                "quoted values only follow `=`, in `{}`",
+                part
+            )));
+        }
+        return Ok(Term::Text {
+            field: field(key),
+            op: Op::Is(unquote(quoted, part)?),
+        });
+    }
+    let pattern = Pattern(value.to_string());
+    let op = match op {
+        "=" => Op::Eq(pattern),
+        "!=" => Op::Ne(pattern),
+        _ => {
+            return Err(ParseError::new(format!(
+                "only versions compare with `{}`, in `{}`",
+                op, part
+            )))
+        }
+    };
+    Ok(Term::Text {
+        field: field(key),
+        op,
+    })
+}
+
+/// Takes the escapes out of a quoted value, less its opening quote.
+fn unquote(quoted: &str, part: &str) -> Result<String, ParseError> {
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) => value.push(c),
+                None => break,
+            },
+            '"' if chars.as_str().is_empty() => return Ok(value),
+            '"' => break,
+            c => value.push(c),
+        }
+    }
+    Err(ParseError::new(format!(
+        "expected a value quoted whole in `{}`",
+        part
+    )))
+}
+
+fn field(key: &str) -> Field {
+    match key {
+        "domain" => Field::Domain,
+        "scope" => Field::Scope,
+        "kind" => Field::Kind,
+        "origin" => Field::Origin,
+        label => Field::Label(label.to_string()),
+    }
+}
+
+fn is_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.contains(|c: char| c.is_whitespace() || "=!<>(),".contains(c))
+        && !matches!(key, "domain" | "scope" | "kind" | "version" | "origin")
+}
+
+/// Strips a leading keyword that is followed by a space or `(`.
+fn strip_word<'a>(s: &'a str, word: &str) -> Option<&'a str> {
+    let rest = s.strip_prefix(word)?;
+    if rest.starts_with(|c: char| c.is_whitespace() || c == '(') {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+impl Serialize for Selector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Selector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An error encountered while parsing a [`Selector`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    message: String,
+}
+
+impl ParseError {
+    fn new(message: String) -> Self {
+        ParseError { message }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid selector: {}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn matches_fields_and_labels() {
+        let cpu = fixtures::cpu_manifest();
+        let netstat = fixtures::netstat_manifest();
+
+        let selector: Selector = "domain=example.org, kind=cpu, environment=production"
+            .parse()
+            .unwrap();
+        assert!(selector.matches(&cpu));
+        assert!(!selector.matches(&netstat));
+
+        let selector: Selector = "version=2".parse().unwrap();
+        assert!(!selector.matches(&cpu));
+
+        assert!(Selector::any().matches(&cpu));
+        assert!("*".parse::<Selector>().unwrap().matches(&netstat));
+    }
+
+    #[test]
+    fn matches_globs_sets_and_ranges() {
+        let cpu = fixtures::cpu_manifest();
+        let netstat = fixtures::netstat_manifest();
+        let matches = |s: &str| {
+            let selector: Selector = s.parse().unwrap();
+            (selector.matches(&cpu), selector.matches(&netstat))
+        };
+
+        assert_eq!(matches("scope=metrics/*, kind in (cpu,mem)"), (true, false));
+        assert_eq!(matches("kind notin (cpu, mem)"), (false, true));
+        assert_eq!(matches("domain=*.org, kind=?pu"), (true, false));
+        assert_eq!(matches("scope=*/host/*"), (false, false));
+        assert_eq!(matches("version>=1, version<2"), (true, true));
+        assert_eq!(matches("version>1"), (false, false));
+        assert_eq!(matches("version!=1"), (false, false));
+        assert_eq!(matches("kind!=cpu"), (false, true));
+
+        // Labels a manifest lacks match no value.
+        assert_eq!(matches("datacenter"), (true, false));
+        assert_eq!(matches("!datacenter"), (false, true));
+        assert_eq!(matches("datacenter!=eu-west"), (true, true));
+        assert_eq!(matches("datacenter notin (us-east)"), (false, true));
+        assert_eq!(
+            matches("datacenter in (us-*), environment=prod*"),
+            (true, false)
+        );
+    }
+
+    #[test]
+    fn globs() {
+        let glob = |p: &str, v: &str| Pattern(p.to_string()).matches(v);
+        assert!(glob("metrics/*", "metrics/host"));
+        assert!(glob("metrics/*", "metrics/"));
+        assert!(!glob("metrics/*", "metric"));
+        assert!(glob("*a*b*", "xxaxxbx"));
+        assert!(!glob("*a*b", "xxaxxbx"));
+        assert!(glob("a?c", "abc"));
+        assert!(!glob("a?c", "ac"));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let selector: Selector = " scope = metrics/host ,datacenter=us-east".parse().unwrap();
+        assert_eq!(
+            selector.to_string(),
+            "scope=metrics/host, datacenter=us-east"
+        );
+        assert_eq!(selector.to_string().parse::<Selector>().unwrap(), selector);
+
+        let selector: Selector = "kind in(cpu ,mem),version>=2, !debug, team, env==prod"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            selector.to_string(),
+            "kind in (cpu, mem), version>=2, !debug, team, env=prod"
+        );
+        assert_eq!(selector.to_string().parse::<Selector>().unwrap(), selector);
+    }
+
+    #[test]
+    fn requires_only_exact_values() {
+        let selector: Selector = "domain=example.org, scope=metrics/*, kind in (cpu)"
+            .parse()
+            .unwrap();
+        assert_eq!(selector.domain(), Some("example.org"));
+        assert_eq!(selector.scope(), None);
+        assert_eq!(selector.kind(), None);
+    }
+
+    #[test]
+    fn matches_exact_values_literally() {
+        let cpu = fixtures::cpu_manifest();
+        let exact = |kind: &str| {
+            Selector::exact(Some("example.org".into()), None, Some(kind.into()), Some(1))
+        };
+        assert!(exact("cpu").matches(&cpu));
+        assert!(!exact("c?u").matches(&cpu));
+        assert!(!exact("*").matches(&cpu));
+        assert_eq!(exact("cpu, version=2").kind(), Some("cpu, version=2"));
+        assert_eq!(
+            exact("cpu").to_string(),
+            "domain=\"example.org\", kind=\"cpu\", version=1"
+        );
+    }
+
+    #[test]
+    fn exact_values_round_trip() {
+        for kind in [
+            "c*u",
+            "c?u",
+            "cpu, version=2",
+            "(cpu)",
+            "say \"cpu\"",
+            "C:\\cpu\\",
+            "",
+        ] {
+            let selector = Selector::exact(None, Some("metrics/*".into()), Some(kind.into()), None);
+            let parsed: Selector = selector.to_string().parse().unwrap();
+            assert_eq!(parsed, selector, "{}", selector);
+            assert_eq!(parsed.kind(), Some(kind));
+        }
+
+        let selector: Selector = r#"kind="c*u", domain=*.org"#.parse().unwrap();
+        let mut manifest = fixtures::cpu_manifest();
+        assert!(!selector.matches(&manifest));
+        manifest.kind = "c*u".into();
+        assert!(selector.matches(&manifest));
+    }
+
+    #[test]
+    fn rejects_malformed_terms() {
+        assert!("=cpu".parse::<Selector>().is_err());
+        assert!("kind".parse::<Selector>().is_err());
+        assert!("version=one".parse::<Selector>().is_err());
+        assert!("version in (1, 2)".parse::<Selector>().is_err());
+        assert!("kind>cpu".parse::<Selector>().is_err());
+        assert!("kind in cpu".parse::<Selector>().is_err());
+        assert!("kind in ()".parse::<Selector>().is_err());
+        assert!("kind in (cpu".parse::<Selector>().is_err());
+        assert!("kind in ((cpu))".parse::<Selector>().is_err());
+        assert!("kind ~ cpu".parse::<Selector>().is_err());
+        assert!("!kind".parse::<Selector>().is_err());
+        assert!(r#"kind="cpu"#.parse::<Selector>().is_err());
+        assert!(r#"kind="cpu"u"#.parse::<Selector>().is_err());
+        assert!(r#"kind!="cpu""#.parse::<Selector>().is_err());
+    }
+}
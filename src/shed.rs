@@ -0,0 +1,287 @@
+//! Shedding low-priority packets when a pipeline is overloaded.
+//!
+//! A [`Shedder`] holds [`Rule`]s, each naming the packets it covers with a
+//! [`Selector`], the [`Load`] at which they are shed, and whether they are
+//! then dropped or spooled. Packets matching no rule are never shed, so
+//! the packets that matter most need no rule at all. Giving less important
+//! packets lower thresholds sheds them first, while the queues still have
+//! room for the rest:
+//!
+//! ```
+//! use intermodal::shed::{Action, Rule, Shedder};
+//!
+//! let shedder = Shedder::new()
+//!     .rule(Rule::new("debug", "kind=debug".parse()?, Action::Drop).queue_depth(1000))
+//!     .rule(Rule::new("bulk", "priority=low".parse()?, Action::Drop).queue_depth(5000))
+//!     .rule(
+//!         Rule::new("metrics", "scope=metrics/*".parse()?, Action::Drop)
+//!             .queue_depth(20_000)
+//!             .memory(2 << 30),
+//!     );
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The first rule matching a packet decides its fate. A rule sheds once any
+//! of its thresholds is reached. Memory is measured by a gauge the program
+//! supplies with [`Shedder::memory`]; without one, memory thresholds are
+//! never reached. A [`Pipeline`](crate::pipeline::Pipeline) measures the
+//! queue depth as the packets queued across its dispatcher's lanes.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::spool::Spool;
+use crate::{Coordinates, Manifest, RawPacket, Selector};
+
+/// How loaded a pipeline is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Load {
+    /// The number of packets waiting to be handled.
+    pub queue_depth: usize,
+    /// The memory in use, in bytes.
+    pub memory: u64,
+}
+
+/// What to do with a shed packet.
+#[derive(Clone)]
+pub enum Action {
+    Drop,
+    /// Set the packet aside, to replay once the load has passed.
+    Spool(Arc<dyn Spool>),
+}
+
+impl fmt::Debug for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Drop => f.write_str("Drop"),
+            Action::Spool(_) => f.write_str("Spool(..)"),
+        }
+    }
+}
+
+/// Which packets to shed, and when.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub selector: Selector,
+    /// Shed once this many packets are waiting.
+    pub queue_depth: Option<usize>,
+    /// Shed once this many bytes of memory are in use.
+    pub memory: Option<u64>,
+    pub action: Action,
+}
+
+impl Rule {
+    /// A rule with no thresholds yet, which never sheds.
+    pub fn new<N: Into<String>>(name: N, selector: Selector, action: Action) -> Self {
+        Rule {
+            name: name.into(),
+            selector,
+            queue_depth: None,
+            memory: None,
+            action,
+        }
+    }
+
+    pub fn queue_depth(mut self, threshold: usize) -> Self {
+        self.queue_depth = Some(threshold);
+        self
+    }
+
+    pub fn memory(mut self, threshold: u64) -> Self {
+        self.memory = Some(threshold);
+        self
+    }
+
+    /// Whether the load reaches any of the rule's thresholds.
+    pub fn is_reached(&self, load: Load) -> bool {
+        self.queue_depth
+            .is_some_and(|threshold| load.queue_depth >= threshold)
+            || self
+                .memory
+                .is_some_and(|threshold| load.memory >= threshold)
+    }
+}
+
+/// What one rule has shed of one set of coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shed {
+    pub rule: String,
+    pub coordinates: Coordinates,
+    pub dropped: u64,
+    pub spooled: u64,
+}
+
+type Gauge = Box<dyn Fn() -> u64 + Send + Sync>;
+
+/// Decides which packets to shed under load, and counts what it shed.
+#[derive(Default)]
+pub struct Shedder {
+    rules: Vec<Rule>,
+    memory: Option<Gauge>,
+    shed: Mutex<BTreeMap<(String, Coordinates), Shed>>,
+}
+
+impl fmt::Debug for Shedder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shedder")
+            .field("rules", &self.rules)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Shedder {
+    pub fn new() -> Self {
+        Shedder::default()
+    }
+
+    /// Adds a rule, consulted after those already added.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Measures memory in use with `gauge`, which returns bytes.
+    pub fn memory<F>(mut self, gauge: F) -> Self
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        self.memory = Some(Box::new(gauge));
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The load with the given queue depth, and memory as measured now.
+    pub fn load(&self, queue_depth: usize) -> Load {
+        Load {
+            queue_depth,
+            memory: self.memory.as_ref().map_or(0, |gauge| gauge()),
+        }
+    }
+
+    /// The rule that sheds packets with this manifest under `load`, if any.
+    pub fn check(&self, manifest: &Manifest, load: Load) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.selector.matches(manifest))
+            .filter(|rule| rule.is_reached(load))
+    }
+
+    /// Sheds a packet by a rule [`check`](Shedder::check) returned: drops or
+    /// spools it, and counts it.
+    pub fn shed(&self, rule: &Rule, packet: &RawPacket) -> io::Result<()> {
+        if let Action::Spool(spool) = &rule.action {
+            spool.spool(packet)?;
+        }
+        let coordinates = packet.manifest.coordinates();
+        let mut shed = self.shed.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = shed
+            .entry((rule.name.clone(), coordinates.clone()))
+            .or_insert_with(|| Shed {
+                rule: rule.name.clone(),
+                coordinates,
+                dropped: 0,
+                spooled: 0,
+            });
+        match rule.action {
+            Action::Drop => entry.dropped += 1,
+            Action::Spool(_) => entry.spooled += 1,
+        }
+        Ok(())
+    }
+
+    /// Checks a packet against the rules, returning it unless it was shed.
+    pub fn admit(&self, packet: RawPacket, load: Load) -> io::Result<Option<RawPacket>> {
+        match self.check(&packet.manifest, load) {
+            Some(rule) => self.shed(rule, &packet).map(|()| None),
+            None => Ok(Some(packet)),
+        }
+    }
+
+    /// Everything shed so far, by rule name and then coordinates.
+    pub fn report(&self) -> Vec<Shed> {
+        let shed = self.shed.lock().unwrap_or_else(|e| e.into_inner());
+        shed.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::fixtures;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<RawPacket>>);
+
+    impl Spool for Collect {
+        fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+            self.0.lock().unwrap().push(packet.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sheds_by_priority() {
+        let spool = Arc::new(Collect::default());
+        let memory = Arc::new(AtomicU64::new(0));
+        let shedder = Shedder::new()
+            .rule(
+                Rule::new("netstat", "kind=netstat".parse().unwrap(), Action::Drop).queue_depth(10),
+            )
+            .rule(
+                Rule::new(
+                    "metrics",
+                    "scope=metrics/*".parse().unwrap(),
+                    Action::Spool(spool.clone()),
+                )
+                .queue_depth(100)
+                .memory(1000),
+            )
+            .memory({
+                let memory = Arc::clone(&memory);
+                move || memory.load(Ordering::Relaxed)
+            });
+
+        let (cpu, netstat) = (fixtures::cpu_raw(), fixtures::netstat_raw());
+        let load = shedder.load(9);
+        assert!(shedder.admit(netstat.clone(), load).unwrap().is_some());
+        assert!(shedder.admit(cpu.clone(), load).unwrap().is_some());
+
+        let load = shedder.load(10);
+        assert!(shedder.admit(netstat.clone(), load).unwrap().is_none());
+        assert!(shedder.admit(cpu.clone(), load).unwrap().is_some());
+
+        memory.store(1000, Ordering::Relaxed);
+        let load = shedder.load(0);
+        assert_eq!(load.memory, 1000);
+        // Netstat is governed by its own rule, whose queue has room.
+        assert!(shedder.admit(netstat.clone(), load).unwrap().is_some());
+        assert!(shedder.admit(cpu.clone(), load).unwrap().is_none());
+        assert_eq!(*spool.0.lock().unwrap(), vec![cpu.clone()]);
+
+        assert_eq!(
+            shedder.report(),
+            vec![
+                Shed {
+                    rule: "metrics".to_string(),
+                    coordinates: cpu.manifest.coordinates(),
+                    dropped: 0,
+                    spooled: 1,
+                },
+                Shed {
+                    rule: "netstat".to_string(),
+                    coordinates: netstat.manifest.coordinates(),
+                    dropped: 1,
+                    spooled: 0,
+                },
+            ]
+        );
+    }
+}
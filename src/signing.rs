@@ -0,0 +1,264 @@
+//! Detached signatures over envelopes.
+//!
+//! A signed packet carries a [`Signature`] alongside its manifest and
+//! content, made over their [canonical encoding](crate::canonical), so that
+//! it survives being re-encoded, in any format, on the way. Anything that
+//! changes the manifest or the content, labels included, makes the
+//! signature fail to verify:
+//!
+//! ```
+//! # #[cfg(feature = "ed25519")] {
+//! use ed25519_dalek::SigningKey;
+//! # let mut packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "uptime", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": 86400
+//! # })).unwrap();
+//!
+//! let key = SigningKey::from_bytes(&[7; 32]);
+//! packet.sign(&key).unwrap();
+//! assert!(packet.verify(&key.verifying_key()).is_ok());
+//!
+//! packet.content = serde_json::json!(0);
+//! assert!(packet.verify(&key.verifying_key()).is_err());
+//! # }
+//! ```
+//!
+//! The signature is carried only by encodings of the whole packet. The
+//! [framing](crate::framing) format and decoding content into another type
+//! with [`Packet::map`] drop it, so verify before either.
+//!
+//! Ed25519 is available with the `ed25519` feature. [`Signature`] is not
+//! feature-gated, so that packets signed elsewhere decode, and pass through,
+//! without it.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::keys;
+
+#[cfg(feature = "ed25519")]
+use crate::keys::{KeyProvider, PublicKeyProvider};
+#[cfg(feature = "ed25519")]
+use crate::{hex, Packet};
+
+/// The [`Signature::algorithm`] of Ed25519 signatures.
+pub const ED25519: &str = "ed25519";
+
+/// A detached signature over a packet's manifest and content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Signature {
+    pub algorithm: String,
+    /// Names the key the packet was signed with, for a verifier holding
+    /// several. It is a hint only, and is not covered by the signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// The signature, hex-encoded.
+    pub value: String,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The packet has no signature to verify.
+    Unsigned,
+    /// The signature was made with an algorithm other than the key's.
+    Algorithm(String),
+    /// The signature is not well-formed for its algorithm.
+    Malformed(String),
+    /// The signature does not match the packet and key.
+    Invalid,
+    /// The packet could not be encoded to sign or verify it.
+    Encode(String),
+    /// The key could not be had from its provider.
+    Key(keys::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unsigned => write!(f, "packet is not signed"),
+            Error::Algorithm(algorithm) => {
+                write!(f, "unsupported signature algorithm `{}`", algorithm)
+            }
+            Error::Malformed(message) => write!(f, "malformed signature: {}", message),
+            Error::Invalid => write!(f, "signature does not match"),
+            Error::Encode(message) => write!(f, "cannot encode packet to sign: {}", message),
+            Error::Key(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Key(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<keys::Error> for Error {
+    fn from(e: keys::Error) -> Self {
+        Error::Key(e)
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl<T: Serialize> Packet<T> {
+    /// The bytes a signature covers.
+    fn signed_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.canonical_bytes()
+            .map_err(|e| Error::Encode(e.to_string()))
+    }
+
+    /// Signs the packet with an Ed25519 key, replacing any signature it had.
+    pub fn sign(&mut self, key: &ed25519_dalek::SigningKey) -> Result<(), Error> {
+        use ed25519_dalek::Signer;
+
+        let signature = key.sign(&self.signed_bytes()?);
+        self.signature = Some(Signature {
+            algorithm: ED25519.to_string(),
+            key_id: None,
+            value: hex::encode(&signature.to_bytes()),
+        });
+        Ok(())
+    }
+
+    /// Checks the packet's signature against an Ed25519 public key.
+    pub fn verify(&self, key: &ed25519_dalek::VerifyingKey) -> Result<(), Error> {
+        let signature = self.signature.as_ref().ok_or(Error::Unsigned)?;
+        if signature.algorithm != ED25519 {
+            return Err(Error::Algorithm(signature.algorithm.clone()));
+        }
+        let bytes = hex::decode(&signature.value)
+            .ok_or_else(|| Error::Malformed("not hex-encoded".to_string()))?;
+        let signature = ed25519_dalek::Signature::from_slice(&bytes)
+            .map_err(|e| Error::Malformed(e.to_string()))?;
+        key.verify_strict(&self.signed_bytes()?, &signature)
+            .map_err(|_| Error::Invalid)
+    }
+
+    /// Signs the packet with the provider's current key, recording the
+    /// key's id in the signature.
+    pub fn sign_with<P: KeyProvider + ?Sized>(&mut self, keys: &P) -> Result<(), Error> {
+        let key = keys.current()?;
+        self.sign(&key.signing_key()?)?;
+        if let Some(signature) = &mut self.signature {
+            signature.key_id = Some(key.id);
+        }
+        Ok(())
+    }
+
+    /// Checks the packet's signature against the public key whose id it
+    /// records, from the provider.
+    pub fn verify_with<P: PublicKeyProvider + ?Sized>(&self, keys: &P) -> Result<(), Error> {
+        let signature = self.signature.as_ref().ok_or(Error::Unsigned)?;
+        let id = signature
+            .key_id
+            .as_deref()
+            .ok_or_else(|| Error::Malformed("no key id".to_string()))?;
+        let key = keys
+            .verifying_key(id)?
+            .ok_or_else(|| keys::Error::Unknown(id.to_string()))?;
+        self.verify(&key)
+    }
+}
+
+#[cfg(all(test, feature = "ed25519"))]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn signs_and_verifies() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        let mut packet = fixtures::cpu_raw();
+        assert!(matches!(
+            packet.verify(&key.verifying_key()),
+            Err(Error::Unsigned)
+        ));
+
+        packet.sign(&key).unwrap();
+        packet.verify(&key.verifying_key()).unwrap();
+        assert!(matches!(
+            packet.verify(&other.verifying_key()),
+            Err(Error::Invalid)
+        ));
+
+        // The signature survives re-encoding, whatever the key order.
+        let json = serde_json::to_string(&packet).unwrap();
+        let decoded: crate::RawPacket = serde_json::from_str(&json).unwrap();
+        decoded.verify(&key.verifying_key()).unwrap();
+
+        let mut tampered = packet.clone();
+        tampered
+            .manifest
+            .labels
+            .insert("environment".to_string(), "staging".to_string());
+        assert!(matches!(
+            tampered.verify(&key.verifying_key()),
+            Err(Error::Invalid)
+        ));
+
+        let mut tampered = packet;
+        tampered.signature.as_mut().unwrap().value.pop();
+        assert!(matches!(
+            tampered.verify(&key.verifying_key()),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn signs_with_providers() {
+        use crate::keys::{KeyEntry, KeySet};
+
+        let keys = KeySet::new()
+            .insert(KeyEntry::new("old", vec![1; 32]))
+            .insert(KeyEntry::new("new", vec![2; 32]));
+        let mut packet = fixtures::cpu_raw();
+        packet.sign_with(&keys).unwrap();
+        assert_eq!(
+            packet.signature.as_ref().unwrap().key_id.as_deref(),
+            Some("new")
+        );
+        packet.verify_with(&keys).unwrap();
+        let entry = keys.key("new").unwrap().unwrap();
+        packet
+            .verify(&entry.signing_key().unwrap().verifying_key())
+            .unwrap();
+        // The material itself is not the signing key.
+        assert!(packet
+            .verify(&SigningKey::from_bytes(&[2; 32]).verifying_key())
+            .is_err());
+
+        let rotated = KeySet::new().insert(KeyEntry::new("newer", vec![3; 32]));
+        assert!(matches!(
+            packet.verify_with(&rotated),
+            Err(Error::Key(keys::Error::Unknown(_)))
+        ));
+    }
+
+    #[test]
+    fn verifies_with_public_keys_only() {
+        use crate::keys::{KeyEntry, KeySet, PublicKeySet};
+
+        let signer = KeySet::new().insert(KeyEntry::new("k1", vec![1; 32]));
+        let verifier =
+            PublicKeySet::from_json(signer.public_keys().unwrap().to_json().as_bytes()).unwrap();
+        let mut packet = fixtures::cpu_raw();
+        packet.sign_with(&signer).unwrap();
+        packet.verify_with(&verifier).unwrap();
+
+        packet.manifest.kind = "mem".to_string();
+        assert!(matches!(packet.verify_with(&verifier), Err(Error::Invalid)));
+        assert!(matches!(
+            packet.verify_with(&PublicKeySet::new()),
+            Err(Error::Key(keys::Error::Unknown(_)))
+        ));
+    }
+}
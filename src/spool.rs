@@ -0,0 +1,91 @@
+//! Durable holding areas for packets that cannot be delivered yet.
+//!
+//! A [`Spool`] takes packets that would otherwise be dropped, such as those
+//! that overflow a busy handler's queue, so they can be replayed later.
+//! [`FileSpool`] appends them to a file as newline-delimited JSON, the format
+//! [`Rewriter::run`](crate::rewrite::Rewriter::run) reads.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::RawPacket;
+
+/// Somewhere to set packets aside.
+pub trait Spool: Send + Sync {
+    fn spool(&self, packet: &RawPacket) -> io::Result<()>;
+
+    /// Makes everything spooled so far durable.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Spools packets to a file, one JSON envelope per line.
+#[derive(Debug)]
+pub struct FileSpool {
+    path: PathBuf,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl FileSpool {
+    /// Opens `path` for appending, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSpool {
+            path: path.to_path_buf(),
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Spool for FileSpool {
+    fn spool(&self, packet: &RawPacket) -> io::Result<()> {
+        let mut line = serde_json::to_vec(packet)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&line)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.flush()?;
+        file.get_ref().sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn appends_lines() {
+        let path = std::env::temp_dir().join(format!("intermodal-spool-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let spool = FileSpool::open(&path).unwrap();
+        spool.spool(&fixtures::cpu_raw()).unwrap();
+        spool.spool(&fixtures::netstat_raw()).unwrap();
+        spool.flush().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let kinds: Vec<String> = text
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<RawPacket>(line)
+                    .unwrap()
+                    .manifest
+                    .kind
+            })
+            .collect();
+        assert_eq!(kinds, vec!["cpu", "netstat"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
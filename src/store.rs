@@ -0,0 +1,457 @@
+//! Storing envelopes by the digest of what they hold.
+//!
+//! A [`Store`] keeps envelopes keyed by their
+//! [content digest](crate::Packet::content_digest), so that storing a
+//! replayed envelope again is harmless, and finds them again by digest or
+//! by [`Selector`]:
+//!
+//! ```no_run
+//! use intermodal::store::{FsStore, Store};
+//! # let packet: intermodal::RawPacket = unimplemented!();
+//!
+//! let store = FsStore::open("/var/lib/intermodal/store")?;
+//! let digest = store.put(&packet)?;
+//! assert_eq!(store.get_by_digest(&digest)?, Some(packet));
+//!
+//! for digest in store.list_by_selector(&"kind=cpu, environment=production".parse()?)? {
+//!     // ...
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! [`FsStore`] keeps each envelope as a JSON file at
+//! `<root>/sha256/<first two hex digits>/<hex digest>.json`, the layout of
+//! [`FsBlobStore`](crate::blob::FsBlobStore), and appends each envelope's
+//! digest and manifest to `<root>/index.ndjson`. The index is read into
+//! memory when the store is opened, grouped by coordinates, so that listing
+//! by a selector naming a domain, scope or kind reads only the manifests
+//! with those coordinates, and no envelopes at all. A store directory must
+//! be written to by one process at a time.
+//!
+//! The index also answers what was known at a point in time.
+//! [`FsStore::as_of`] gives a view of the store at that time, whose
+//! [`latest`](AsOf::latest) envelopes are the most recent of each origin
+//! and kind, as a job reconstructing state from the store wants them:
+//!
+//! ```no_run
+//! use intermodal::store::FsStore;
+//!
+//! let store = FsStore::open("/var/lib/intermodal/store")?;
+//! let midnight = "2020-06-02T00:00:00Z".parse()?;
+//! for packet in store.as_of(midnight).latest(&"scope=metrics/host".parse()?)? {
+//!     // ...
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Available with the `blob` feature.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::blob::Digest;
+use crate::stream::{NdjsonReader, Recovery};
+use crate::{Coordinates, Manifest, RawPacket, Selector};
+
+/// Envelopes kept by content digest.
+pub trait Store: Send + Sync {
+    /// Stores the packet, returning its content digest. Storing an envelope
+    /// with the same manifest and content again is harmless, and keeps the
+    /// first.
+    fn put(&self, packet: &RawPacket) -> Result<Digest, Error>;
+
+    /// The envelope with the given digest, if one is stored.
+    fn get_by_digest(&self, digest: &Digest) -> Result<Option<RawPacket>, Error>;
+
+    /// The digests of the stored envelopes whose manifests match the
+    /// selector, in the order they were stored.
+    fn list_by_selector(&self, selector: &Selector) -> Result<Vec<Digest>, Error>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// An envelope could not be encoded, or a stored one decoded.
+    Json(serde_json::Error),
+    /// A stored envelope does not match its digest.
+    Corrupt {
+        expected: Digest,
+        actual: Digest,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "stored envelope: {}", e),
+            Error::Corrupt { expected, actual } => write!(
+                f,
+                "envelope {} is corrupt: its digest is {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Corrupt { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// A line of the index.
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    digest: Digest,
+    manifest: Manifest,
+}
+
+/// A manifest in the index, with its digest and its position there.
+type Entry = (usize, Digest, Manifest);
+
+#[derive(Debug)]
+struct Index {
+    file: File,
+    digests: HashSet<Digest>,
+    /// Manifests by coordinates, each with its digest and its position in
+    /// the index, which orders listings.
+    by_coordinates: BTreeMap<Coordinates, Vec<Entry>>,
+    len: usize,
+}
+
+impl Index {
+    fn insert(&mut self, digest: Digest, manifest: Manifest) {
+        self.digests.insert(digest.clone());
+        self.by_coordinates
+            .entry(manifest.coordinates())
+            .or_default()
+            .push((self.len, digest, manifest));
+        self.len += 1;
+    }
+
+    /// The entries whose manifests match the selector, in no particular
+    /// order.
+    fn matching<'a>(&'a self, selector: &'a Selector) -> impl Iterator<Item = &'a Entry> {
+        let (domain, scope, kind) = (selector.domain(), selector.scope(), selector.kind());
+        self.by_coordinates
+            .iter()
+            .filter(move |(coordinates, _)| {
+                domain.is_none_or(|domain| coordinates.domain == domain)
+                    && scope.is_none_or(|scope| coordinates.scope == scope)
+                    && kind.is_none_or(|kind| coordinates.kind == kind)
+            })
+            .flat_map(|(_, entries)| entries)
+            .filter(move |(_, _, manifest)| selector.matches(manifest))
+    }
+}
+
+/// Envelopes kept as files beneath a directory.
+#[derive(Debug)]
+pub struct FsStore {
+    root: PathBuf,
+    index: Mutex<Index>,
+}
+
+impl FsStore {
+    /// Opens the store rooted at `root`, creating it if need be, and reads
+    /// its index. A malformed index line, such as one cut short by a
+    /// crash, is skipped; its envelope is indexed again when it is next
+    /// put.
+    pub fn open<P: Into<PathBuf>>(root: P) -> Result<Self, Error> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        let path = root.join("index.ndjson");
+        let mut index = Index {
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            digests: HashSet::new(),
+            by_coordinates: BTreeMap::new(),
+            len: 0,
+        };
+        let file = File::open(&path)?;
+        let entries = NdjsonReader::<_, IndexEntry>::new(&file).recovery(Recovery::Skip);
+        for entry in entries {
+            match entry {
+                Ok(entry) => index.insert(entry.digest, entry.manifest),
+                Err(crate::stream::Error::Io(e)) => return Err(e.into()),
+                // Undecodable lines are skipped rather than yielded.
+                Err(_) => {}
+            }
+        }
+        if !ends_with_newline(&path)? {
+            // A line cut short has no newline; start the next on its own.
+            index.file.write_all(b"\n")?;
+        }
+        Ok(FsStore {
+            root,
+            index: Mutex::new(index),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// A view of the store as it was at `time`, holding the envelopes
+    /// created by then.
+    pub fn as_of(&self, time: DateTime<Utc>) -> AsOf<'_> {
+        AsOf { store: self, time }
+    }
+
+    fn path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join("sha256")
+            .join(&digest.hex()[..2])
+            .join(format!("{}.json", digest.hex()))
+    }
+}
+
+impl Store for FsStore {
+    fn put(&self, packet: &RawPacket) -> Result<Digest, Error> {
+        let digest = packet.content_digest()?;
+        let mut index = self.index.lock().unwrap_or_else(|e| e.into_inner());
+        if index.digests.contains(&digest) {
+            return Ok(digest);
+        }
+
+        let path = self.path(&digest);
+        if !path.exists() {
+            let dir = path.parent().expect("envelope paths have a parent");
+            fs::create_dir_all(dir)?;
+            // Write beside the final path and rename into place, so that
+            // readers never observe a partially written envelope.
+            let partial = dir.join(format!(".{}.{}", digest.hex(), std::process::id()));
+            fs::write(&partial, serde_json::to_vec(packet)?)?;
+            fs::rename(&partial, &path)?;
+        }
+        let entry = IndexEntry {
+            digest,
+            manifest: packet.manifest.clone(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        index.file.write_all(&line)?;
+        index.insert(entry.digest.clone(), entry.manifest);
+        Ok(entry.digest)
+    }
+
+    fn get_by_digest(&self, digest: &Digest) -> Result<Option<RawPacket>, Error> {
+        let bytes = match fs::read(self.path(digest)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let packet: RawPacket = serde_json::from_slice(&bytes)?;
+        let actual = packet.content_digest()?;
+        if actual != *digest {
+            return Err(Error::Corrupt {
+                expected: digest.clone(),
+                actual,
+            });
+        }
+        Ok(Some(packet))
+    }
+
+    fn list_by_selector(&self, selector: &Selector) -> Result<Vec<Digest>, Error> {
+        let index = self.index.lock().unwrap_or_else(|e| e.into_inner());
+        let mut found: Vec<_> = index
+            .matching(selector)
+            .map(|(position, digest, _)| (*position, digest.clone()))
+            .collect();
+        found.sort_unstable_by_key(|(position, _)| *position);
+        Ok(found.into_iter().map(|(_, digest)| digest).collect())
+    }
+}
+
+/// An [`FsStore`] as it was at a point in time, from [`FsStore::as_of`].
+#[derive(Debug, Clone, Copy)]
+pub struct AsOf<'a> {
+    store: &'a FsStore,
+    time: DateTime<Utc>,
+}
+
+impl AsOf<'_> {
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    /// The digests of the most recent envelopes matching the selector for
+    /// each origin and kind, ordered by origin and then coordinates.
+    ///
+    /// An envelope is a candidate if it was created by the view's time. A
+    /// kind is its domain, scope and kind name, whatever its version, and
+    /// of candidates created at the same moment the one stored last is the
+    /// most recent. Where the most recent had expired by the view's time,
+    /// its origin and kind are left out, as they then held nothing.
+    pub fn latest_digests(&self, selector: &Selector) -> Result<Vec<Digest>, Error> {
+        let index = self.store.index.lock().unwrap_or_else(|e| e.into_inner());
+        let mut latest: BTreeMap<(&str, &str, &str, &str), &Entry> = BTreeMap::new();
+        for entry in index.matching(selector) {
+            let manifest = &entry.2;
+            if manifest.ctime > self.time {
+                continue;
+            }
+            let key = (
+                manifest.origin.as_str(),
+                manifest.domain.as_str(),
+                manifest.scope.as_str(),
+                manifest.kind.as_str(),
+            );
+            let recent = latest.entry(key).or_insert(entry);
+            if (manifest.ctime, entry.0) > (recent.2.ctime, recent.0) {
+                *recent = entry;
+            }
+        }
+        Ok(latest
+            .into_values()
+            .filter(|(_, _, manifest)| manifest.expires.is_none_or(|expires| expires > self.time))
+            .map(|(_, digest, _)| digest.clone())
+            .collect())
+    }
+
+    /// The envelopes of [`latest_digests`](AsOf::latest_digests).
+    pub fn latest(&self, selector: &Selector) -> Result<Vec<RawPacket>, Error> {
+        let mut latest = Vec::new();
+        for digest in self.latest_digests(selector)? {
+            // Envelopes are written before they are indexed, so one that is
+            // indexed but absent was removed since.
+            if let Some(packet) = self.store.get_by_digest(&digest)? {
+                latest.push(packet);
+            }
+        }
+        Ok(latest)
+    }
+}
+
+fn ends_with_newline(path: &Path) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(true);
+    }
+    let mut last = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn stores_and_lists() {
+        let root = std::env::temp_dir().join(format!("intermodal-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (cpu, netstat) = (fixtures::cpu_raw(), fixtures::netstat_raw());
+
+        let store = FsStore::open(&root).unwrap();
+        let cpu_digest = store.put(&cpu).unwrap();
+        let netstat_digest = store.put(&netstat).unwrap();
+        assert_eq!(store.put(&cpu).unwrap(), cpu_digest);
+        assert_eq!(store.get_by_digest(&cpu_digest).unwrap(), Some(cpu.clone()));
+        drop(store);
+
+        // A line cut short by a crash is skipped, and does not run into the
+        // next.
+        let index = root.join("index.ndjson");
+        let mut file = OpenOptions::new().append(true).open(&index).unwrap();
+        file.write_all(br#"{"digest":"sha"#).unwrap();
+        drop(file);
+
+        let store = FsStore::open(&root).unwrap();
+        let all = store.list_by_selector(&"*".parse().unwrap()).unwrap();
+        assert_eq!(all, [cpu_digest.clone(), netstat_digest.clone()]);
+        let kind = store
+            .list_by_selector(&"kind=netstat".parse().unwrap())
+            .unwrap();
+        assert_eq!(kind, vec![netstat_digest.clone()]);
+        let label = store
+            .list_by_selector(&"datacenter=us-east".parse().unwrap())
+            .unwrap();
+        assert_eq!(label, vec![cpu_digest.clone()]);
+
+        let mut later = cpu.clone();
+        later.manifest.ctime += chrono::Duration::seconds(1);
+        let later_digest = store.put(&later).unwrap();
+        drop(store);
+        let store = FsStore::open(&root).unwrap();
+        let kind = store
+            .list_by_selector(&"kind=cpu".parse().unwrap())
+            .unwrap();
+        assert_eq!(kind, [cpu_digest.clone(), later_digest]);
+
+        fs::write(
+            store.path(&cpu_digest),
+            serde_json::to_vec(&netstat).unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(
+            store.get_by_digest(&cpu_digest),
+            Err(Error::Corrupt { .. })
+        ));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reconstructs_state_as_of() {
+        let root = std::env::temp_dir().join(format!("intermodal-as-of-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = FsStore::open(&root).unwrap();
+        let (cpu, netstat) = (fixtures::cpu_raw(), fixtures::netstat_raw());
+        let start = cpu.manifest.ctime;
+        let mut later = cpu.clone();
+        later.manifest.ctime += chrono::Duration::seconds(60);
+        later.content["idle"] = 50.0.into();
+        let mut elsewhere = cpu.clone();
+        elsewhere.manifest.origin = "host02.example.org".to_string();
+        elsewhere.manifest.ctime += chrono::Duration::seconds(30);
+        elsewhere.manifest.expires = Some(elsewhere.manifest.ctime + chrono::Duration::seconds(15));
+        for packet in [&later, &cpu, &netstat, &elsewhere] {
+            store.put(packet).unwrap();
+        }
+
+        let all = "*".parse().unwrap();
+        let before = store.as_of(start - chrono::Duration::seconds(1));
+        assert!(before.latest(&all).unwrap().is_empty());
+        let latest = store
+            .as_of(start + chrono::Duration::seconds(30))
+            .latest(&all)
+            .unwrap();
+        assert_eq!(latest, [cpu.clone(), netstat.clone(), elsewhere]);
+        let latest = store
+            .as_of(start + chrono::Duration::seconds(60))
+            .latest(&all)
+            .unwrap();
+        assert_eq!(latest, [later, netstat]);
+        let cpu_only = store
+            .as_of(start)
+            .latest(&"kind=cpu".parse().unwrap())
+            .unwrap();
+        assert_eq!(cpu_only, [cpu]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
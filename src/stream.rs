@@ -0,0 +1,274 @@
+//! Reading and writing envelope logs, one JSON envelope per line.
+//!
+//! [`NdjsonReader`] decodes envelopes from any [`Read`] as it iterates, so
+//! a log is never held in memory whole. It yields [`Header`]s or
+//! [`Packet`]s, whichever it is asked for:
+//!
+//! ```
+//! use intermodal::stream::{NdjsonReader, Recovery};
+//! use intermodal::Header;
+//!
+//! let log = br#"{"manifest":{"domain":"example.org","scope":"metrics/host","kind":"uptime","version":1,"ctime":"2020-06-01T12:00:00Z","origin":"host01"},"content":86400}
+//! not an envelope
+//! "#;
+//! let mut reader = NdjsonReader::<_, Header>::new(&log[..]).recovery(Recovery::Skip);
+//! let kinds: Vec<_> = reader.by_ref().map(|h| h.unwrap().manifest.kind).collect();
+//! assert_eq!(kinds, ["uptime"]);
+//! assert_eq!(reader.skipped(), 1);
+//! ```
+//!
+//! [`NdjsonWriter`] writes envelopes in the same layout, which is also the
+//! layout of a [`FileSpool`](crate::spool::FileSpool).
+//!
+//! [`Header`]: crate::Header
+//! [`Packet`]: crate::Packet
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::RawPacket;
+
+/// What a reader does with a line that cannot be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Yield the error and stop.
+    Abort,
+    /// Count the line and carry on with the next one.
+    Skip,
+}
+
+/// Decodes envelopes from newline-delimited JSON, one per line.
+///
+/// Blank lines are ignored. Errors reading the underlying reader always end
+/// the iteration, as does an undecodable line unless the reader skips them.
+pub struct NdjsonReader<R, T = RawPacket> {
+    reader: BufReader<R>,
+    recovery: Recovery,
+    line: Vec<u8>,
+    lineno: u64,
+    skipped: u64,
+    done: bool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<R: Read, T: DeserializeOwned> NdjsonReader<R, T> {
+    /// Reads from `reader`, aborting at the first undecodable line.
+    pub fn new(reader: R) -> Self {
+        NdjsonReader {
+            reader: BufReader::new(reader),
+            recovery: Recovery::Abort,
+            line: Vec::new(),
+            lineno: 0,
+            skipped: 0,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn recovery(mut self, recovery: Recovery) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// The number of the last line read, counting from 1.
+    pub fn line(&self) -> u64 {
+        self.lineno
+    }
+
+    /// The number of undecodable lines skipped so far.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for NdjsonReader<R, T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.line.clear();
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    self.lineno += 1;
+                    let line = self.line.trim_ascii();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_slice(line) {
+                        Ok(envelope) => return Some(Ok(envelope)),
+                        Err(_) if self.recovery == Recovery::Skip => self.skipped += 1,
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(Error::Decode {
+                                line: self.lineno,
+                                message: e.to_string(),
+                            }));
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(Error::Io(e)));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<R, T> fmt::Debug for NdjsonReader<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NdjsonReader")
+            .field("recovery", &self.recovery)
+            .field("line", &self.lineno)
+            .field("skipped", &self.skipped)
+            .finish()
+    }
+}
+
+/// Encodes envelopes as newline-delimited JSON, one per line.
+///
+/// Output is buffered; call [`flush`](NdjsonWriter::flush), or
+/// [`into_inner`](NdjsonWriter::into_inner), when done.
+#[derive(Debug)]
+pub struct NdjsonWriter<W: Write> {
+    writer: BufWriter<W>,
+    written: u64,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(writer: W) -> Self {
+        NdjsonWriter {
+            writer: BufWriter::new(writer),
+            written: 0,
+        }
+    }
+
+    /// Writes an envelope, such as a [`Packet`](crate::Packet) or a
+    /// [`Header`](crate::Header), as a line.
+    pub fn write<S: Serialize + ?Sized>(&mut self, envelope: &S) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(envelope).map_err(|e| Error::Encode(e.to_string()))?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// The number of envelopes written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().map_err(Error::Io)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    /// Flushes the writer and returns the underlying one.
+    pub fn into_inner(self) -> Result<W, Error> {
+        self.writer
+            .into_inner()
+            .map_err(|e| Error::Io(e.into_error()))
+    }
+}
+
+/// An error reading or writing an envelope log.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A line is not an envelope of the expected type.
+    Decode {
+        line: u64,
+        message: String,
+    },
+    /// An envelope could not be encoded as JSON.
+    Encode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Decode { line, message } => write!(f, "line {}: {}", line, message),
+            Error::Encode(message) => write!(f, "cannot encode envelope: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+    use crate::{Header, Packet};
+
+    fn log() -> Vec<u8> {
+        let mut writer = NdjsonWriter::new(Vec::new());
+        writer.write(&fixtures::cpu_raw()).unwrap();
+        writer.write(&fixtures::netstat_raw()).unwrap();
+        assert_eq!(writer.written(), 2);
+        let mut log = writer.into_inner().unwrap();
+        log.extend_from_slice(b"\n{\"manifest\": 7}\n");
+        log.extend_from_slice(
+            serde_json::to_string(&fixtures::cpu_raw())
+                .unwrap()
+                .as_bytes(),
+        );
+        log
+    }
+
+    #[test]
+    fn aborts_by_default() {
+        let packets: Vec<_> = NdjsonReader::<_, RawPacket>::new(&log()[..]).collect();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[1].as_ref().unwrap(), &fixtures::netstat_raw());
+        match &packets[2] {
+            Err(Error::Decode { line: 4, .. }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn skips_and_reads_headers() {
+        let log = log();
+        let mut reader = NdjsonReader::<_, Header>::new(&log[..]).recovery(Recovery::Skip);
+        let kinds: Vec<_> = reader
+            .by_ref()
+            .map(|header| header.unwrap().manifest.kind)
+            .collect();
+        assert_eq!(kinds, ["cpu", "netstat", "cpu"]);
+        assert_eq!((reader.skipped(), reader.line()), (1, 5));
+
+        // Typed content that does not fit is undecodable too.
+        let mut reader = NdjsonReader::<_, Packet<Cpu>>::new(&log[..]).recovery(Recovery::Skip);
+        assert_eq!(reader.by_ref().filter_map(Result::ok).count(), 2);
+        assert_eq!(reader.skipped(), 2);
+    }
+}
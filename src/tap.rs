@@ -0,0 +1,349 @@
+//! Mirroring live traffic for debugging.
+//!
+//! A [`Tap`] copies the packets passing a point in a pipeline to a
+//! secondary [`TapSink`], such as a socket someone is listening on, a file,
+//! or a server-sent events stream, so that production traffic can be
+//! watched without being disturbed. A [`Selector`] picks the packets worth
+//! copying, and a sample rate thins them out:
+//!
+//! ```no_run
+//! use std::net::TcpStream;
+//! use intermodal::tap::{Ndjson, Tap};
+//! # let packet: intermodal::RawPacket = unimplemented!();
+//!
+//! let tap = Tap::new(Ndjson::new(TcpStream::connect("debug01:9400")?))
+//!     .selector("kind=netstat, environment=production".parse()?)
+//!     .sample_rate(0.01);
+//! tap.observe(&packet);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! A tap never holds up the traffic it copies. Copies are written by a
+//! thread of the tap's own, through a bounded queue; when the sink falls
+//! behind and the queue fills, further copies are dropped, and when the
+//! sink fails, the copy is lost. [`Tap::stats`] counts both.
+//!
+//! A tap is a [`Transform`] that passes every packet on unchanged, and a
+//! [`Pipeline`](crate::pipeline::Pipeline) can be given taps to copy what
+//! it is sent.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::transform::{self, Transform};
+use crate::{RawPacket, Selector};
+
+/// How many copies wait for the sink by default before more are dropped.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Where a [`Tap`] writes its copies.
+pub trait TapSink: Send {
+    fn write(&mut self, packet: &RawPacket) -> io::Result<()>;
+
+    /// Called whenever the tap's queue empties.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F> TapSink for F
+where
+    F: FnMut(&RawPacket) -> io::Result<()> + Send,
+{
+    fn write(&mut self, packet: &RawPacket) -> io::Result<()> {
+        self(packet)
+    }
+}
+
+/// Writes copies as newline-delimited JSON, as to a file or socket.
+#[derive(Debug)]
+pub struct Ndjson<W>(W);
+
+impl<W: Write + Send> Ndjson<W> {
+    pub fn new(writer: W) -> Self {
+        Ndjson(writer)
+    }
+}
+
+impl<W: Write + Send> TapSink for Ndjson<W> {
+    fn write(&mut self, packet: &RawPacket) -> io::Result<()> {
+        let mut line = serde_json::to_vec(packet)?;
+        line.push(b'\n');
+        self.0.write_all(&line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Writes copies as server-sent events, one `packet` event each holding
+/// the JSON envelope, to a writer that is the body of an event stream
+/// response.
+#[derive(Debug)]
+pub struct Sse<W>(W);
+
+impl<W: Write + Send> Sse<W> {
+    pub fn new(writer: W) -> Self {
+        Sse(writer)
+    }
+}
+
+impl<W: Write + Send> TapSink for Sse<W> {
+    fn write(&mut self, packet: &RawPacket) -> io::Result<()> {
+        // Compact JSON holds no newlines, so the envelope fits one data
+        // line.
+        let mut event = b"event: packet\ndata: ".to_vec();
+        serde_json::to_writer(&mut event, packet)?;
+        event.extend_from_slice(b"\n\n");
+        self.0.write_all(&event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// What a [`Tap`] has done so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TapStats {
+    /// Packets the selector matched.
+    pub matched: u64,
+    /// Copies queued for the sink.
+    pub sampled: u64,
+    /// Copies dropped because the queue was full.
+    pub dropped: u64,
+    /// Copies the sink failed to write.
+    pub failed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    matched: AtomicU64,
+    sampled: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Copies a sample of the packets it observes to a sink.
+pub struct Tap {
+    selector: Selector,
+    rate: f64,
+    /// The fraction of a copy owed, so that a rate of 0.25 copies exactly
+    /// every fourth matching packet.
+    credit: Mutex<f64>,
+    sender: SyncSender<RawPacket>,
+    counters: Arc<Counters>,
+}
+
+impl fmt::Debug for Tap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tap")
+            .field("selector", &self.selector)
+            .field("rate", &self.rate)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Tap {
+    /// Copies every packet to `sink`, through a queue of
+    /// [`DEFAULT_CAPACITY`] copies.
+    pub fn new<S: TapSink + 'static>(sink: S) -> Self {
+        Tap::with_capacity(sink, DEFAULT_CAPACITY)
+    }
+
+    /// Copies every packet to `sink`, through a queue of `capacity` copies.
+    pub fn with_capacity<S: TapSink + 'static>(mut sink: S, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<RawPacket>(capacity);
+        let counters = Arc::new(Counters::default());
+        let written = Arc::clone(&counters);
+        thread::spawn(move || {
+            // The thread ends once the tap is dropped and its queue drained.
+            while let Ok(packet) = receiver.recv() {
+                let mut next = Some(packet);
+                while let Some(packet) = next {
+                    if sink.write(&packet).is_err() {
+                        written.failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    next = receiver.try_recv().ok();
+                }
+                let _ = sink.flush();
+            }
+        });
+        Tap {
+            selector: Selector::any(),
+            rate: 1.0,
+            credit: Mutex::new(0.0),
+            sender,
+            counters,
+        }
+    }
+
+    /// Copies only packets matching `selector`.
+    pub fn selector(mut self, selector: Selector) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Copies this fraction of the matching packets, spread evenly among
+    /// them. Rates are clamped to between 0 and 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is NaN.
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        assert!(!rate.is_nan(), "sample rates must be numbers");
+        self.rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Queues a copy of the packet for the sink, if it is selected and
+    /// sampled and the queue has room. Returns whether it was queued.
+    pub fn observe(&self, packet: &RawPacket) -> bool {
+        if !self.selector.matches(&packet.manifest) {
+            return false;
+        }
+        self.counters.matched.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut credit = self.credit.lock().unwrap_or_else(|e| e.into_inner());
+            *credit += self.rate;
+            if *credit < 1.0 {
+                return false;
+            }
+            *credit -= 1.0;
+        }
+        match self.sender.try_send(packet.clone()) {
+            Ok(()) => {
+                self.counters.sampled.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    pub fn stats(&self) -> TapStats {
+        TapStats {
+            matched: self.counters.matched.load(Ordering::Relaxed),
+            sampled: self.counters.sampled.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Transform for Tap {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.observe(&packet);
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    /// A sink sending what it is given to a channel.
+    fn channel() -> (impl TapSink, Receiver<RawPacket>) {
+        let (sender, receiver) = mpsc::channel();
+        let sink = move |packet: &RawPacket| sender.send(packet.clone()).map_err(io::Error::other);
+        (sink, receiver)
+    }
+
+    #[test]
+    fn copies_selected_samples() {
+        let (sink, copies) = channel();
+        let tap = Tap::new(sink)
+            .selector("kind=cpu".parse().unwrap())
+            .sample_rate(0.25);
+        for _ in 0..8 {
+            let packet = tap.apply(fixtures::cpu_raw()).unwrap().unwrap();
+            assert_eq!(packet, fixtures::cpu_raw());
+            tap.apply(fixtures::netstat_raw()).unwrap();
+        }
+        for _ in 0..2 {
+            let copy = copies.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(copy.manifest.kind, "cpu");
+        }
+        assert_eq!(
+            tap.stats(),
+            TapStats {
+                matched: 8,
+                sampled: 2,
+                dropped: 0,
+                failed: 0
+            }
+        );
+        drop(tap);
+        assert!(copies.recv_timeout(Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn clamps_sample_rates() {
+        let (sink, _copies) = channel();
+        let tap = Tap::new(sink).sample_rate(f64::INFINITY);
+        assert_eq!(tap.rate, 1.0);
+        let tap = tap.sample_rate(-0.5);
+        assert_eq!(tap.rate, 0.0);
+        tap.observe(&fixtures::cpu_raw());
+        assert_eq!(tap.stats().sampled, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample rates must be numbers")]
+    fn rejects_nan_sample_rates() {
+        let (sink, _copies) = channel();
+        let _ = Tap::new(sink).sample_rate(f64::NAN);
+    }
+
+    #[test]
+    fn drops_copies_rather_than_wait() {
+        let (release, blocked) = mpsc::channel::<()>();
+        let sink = move |_: &RawPacket| {
+            let _ = blocked.recv();
+            Err(io::Error::other("listener went away"))
+        };
+        let tap = Tap::with_capacity(sink, 1);
+        for _ in 0..10 {
+            tap.observe(&fixtures::cpu_raw());
+        }
+        let stats = tap.stats();
+        assert_eq!(stats.matched, 10);
+        // One copy is being written and one is queued, at most.
+        assert!(stats.sampled <= 2, "{:?}", stats);
+        assert_eq!(stats.sampled + stats.dropped, 10);
+
+        drop(release);
+        while tap.stats().failed < stats.sampled {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn writes_ndjson_and_events() {
+        let mut ndjson = Ndjson::new(Vec::new());
+        let mut sse = Sse::new(Vec::new());
+        let packet = fixtures::cpu_raw();
+        ndjson.write(&packet).unwrap();
+        sse.write(&packet).unwrap();
+
+        let line = String::from_utf8(ndjson.0).unwrap();
+        assert!(line.ends_with("}\n"));
+        let decoded: RawPacket = serde_json::from_str(&line).unwrap();
+        assert_eq!(decoded, packet);
+
+        let event = String::from_utf8(sse.0).unwrap();
+        assert!(event.starts_with("event: packet\ndata: {\"manifest\""));
+        assert!(event.ends_with("}\n\n"));
+    }
+}
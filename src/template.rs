@@ -0,0 +1,431 @@
+//! Names derived from manifests, such as topics, subjects and paths.
+//!
+//! Transports name their channels differently: Kafka topics, NATS
+//! subjects, MQTT topics, object store paths. A [`Template`] describes the
+//! name once, in terms of manifest fields, so every adapter derives the same
+//! name for the same content:
+//!
+//! ```text
+//! {domain}.{scope|dots}.{kind}.v{version}
+//! ```
+//!
+//! A placeholder names a manifest field, `domain`, `scope`, `kind`,
+//! `version` or `origin`, or a label as `label.<key>`. It may be followed by
+//! filters, applied in order:
+//!
+//! - `dots`, `dashes`, `underscores` replace the `/` in a path with `.`,
+//!   `-` or `_`
+//! - `lower` and `upper` change the case of the value
+//!
+//! `{{` and `}}` stand for literal braces.
+//!
+//! Templates also work in reverse: [`Template::parse`] recovers the fields
+//! from a name.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Coordinates, Manifest};
+
+/// A pattern for names derived from manifests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Placeholder { field: Field, filters: Vec<Filter> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Domain,
+    Scope,
+    Kind,
+    Version,
+    Origin,
+    Label(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Filter {
+    Dots,
+    Dashes,
+    Underscores,
+    Lower,
+    Upper,
+}
+
+impl Filter {
+    fn apply(self, value: &str) -> String {
+        match self {
+            Filter::Dots => value.replace('/', "."),
+            Filter::Dashes => value.replace('/', "-"),
+            Filter::Underscores => value.replace('/', "_"),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Upper => value.to_uppercase(),
+        }
+    }
+
+    /// Undoes the filter as far as possible. Case changes cannot be undone,
+    /// so they are left as they are.
+    fn reverse(self, value: &str) -> String {
+        match self {
+            Filter::Dots => value.replace('.', "/"),
+            Filter::Dashes => value.replace('-', "/"),
+            Filter::Underscores => value.replace('_', "/"),
+            Filter::Lower | Filter::Upper => value.to_string(),
+        }
+    }
+}
+
+/// The fields recovered from a name by [`Template::parse`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captures {
+    pub domain: Option<String>,
+    pub scope: Option<String>,
+    pub kind: Option<String>,
+    pub version: Option<u32>,
+    pub origin: Option<String>,
+    pub labels: HashMap<String, String>,
+}
+
+impl Captures {
+    /// The captured coordinates, if the template names all four fields.
+    pub fn coordinates(&self) -> Option<Coordinates> {
+        Some(Coordinates::new(
+            self.domain.clone()?,
+            self.scope.clone()?,
+            self.kind.clone()?,
+            self.version?,
+        ))
+    }
+}
+
+impl Template {
+    /// Renders the name for a manifest.
+    pub fn render(&self, manifest: &Manifest) -> Result<String, Error> {
+        self.render_with(|field| match field {
+            Field::Domain => Some(manifest.domain.clone()),
+            Field::Scope => Some(manifest.scope.clone()),
+            Field::Kind => Some(manifest.kind.clone()),
+            Field::Version => Some(manifest.version.to_string()),
+            Field::Origin => Some(manifest.origin.clone()),
+            Field::Label(key) => manifest.labels.get(key).cloned(),
+        })
+    }
+
+    /// Renders the name for content with the given coordinates. Fails if the
+    /// template refers to the origin or to labels.
+    pub fn render_coordinates(&self, coordinates: &Coordinates) -> Result<String, Error> {
+        self.render_with(|field| match field {
+            Field::Domain => Some(coordinates.domain.clone()),
+            Field::Scope => Some(coordinates.scope.clone()),
+            Field::Kind => Some(coordinates.kind.clone()),
+            Field::Version => Some(coordinates.version.to_string()),
+            Field::Origin | Field::Label(_) => None,
+        })
+    }
+
+    /// Whether `name` is the name of content with the given coordinates.
+    pub fn matches(&self, name: &str, coordinates: &Coordinates) -> bool {
+        self.render_coordinates(coordinates)
+            .is_ok_and(|rendered| rendered == name)
+    }
+
+    /// Recovers the fields that rendered `name`.
+    ///
+    /// Fails if the name does not fit the template, or if it fits in more
+    /// than one way. That happens when a placeholder's value may contain the
+    /// literal text that follows it, as a dotted domain followed by `.`
+    /// does; [`matches`](Template::matches) answers the question for known
+    /// coordinates regardless.
+    pub fn parse(&self, name: &str) -> Result<Captures, Error> {
+        let mut found = Vec::new();
+        let mut values = Vec::new();
+        self.search(name, 0, &mut values, &mut found);
+        match found.len() {
+            0 => Err(Error::new(format!("`{}` does not match `{}`", name, self))),
+            1 => {
+                let mut captures = Captures::default();
+                for (field, value) in found.remove(0) {
+                    match field {
+                        Field::Domain => captures.domain = Some(value),
+                        Field::Scope => captures.scope = Some(value),
+                        Field::Kind => captures.kind = Some(value),
+                        Field::Version => captures.version = value.parse().ok(),
+                        Field::Origin => captures.origin = Some(value),
+                        Field::Label(key) => {
+                            captures.labels.insert(key, value);
+                        }
+                    }
+                }
+                Ok(captures)
+            }
+            _ => Err(Error::new(format!(
+                "`{}` matches `{}` in more than one way",
+                name, self
+            ))),
+        }
+    }
+
+    fn render_with(&self, value: impl Fn(&Field) -> Option<String>) -> Result<String, Error> {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Placeholder { field, filters } => {
+                    let mut v = value(field).ok_or_else(|| {
+                        Error::new(format!("no value for `{}` in `{}`", field, self))
+                    })?;
+                    for filter in filters {
+                        v = filter.apply(&v);
+                    }
+                    out.push_str(&v);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Finds up to two ways of splitting `rest` among the segments from
+    /// `index` on, which is enough to tell a unique match from an ambiguous one.
+    fn search(
+        &self,
+        rest: &str,
+        index: usize,
+        values: &mut Vec<(Field, String)>,
+        found: &mut Vec<Vec<(Field, String)>>,
+    ) {
+        if found.len() > 1 {
+            return;
+        }
+        let segment = match self.segments.get(index) {
+            Some(segment) => segment,
+            None => {
+                if rest.is_empty() {
+                    found.push(values.clone());
+                }
+                return;
+            }
+        };
+        match segment {
+            Segment::Literal(text) => {
+                if let Some(rest) = rest.strip_prefix(text.as_str()) {
+                    self.search(rest, index + 1, values, found);
+                }
+            }
+            Segment::Placeholder { field, filters } => {
+                for (end, _) in rest.char_indices().skip(1).chain(Some((rest.len(), ' '))) {
+                    let raw = &rest[..end];
+                    if *field == Field::Version && !raw.bytes().all(|b| b.is_ascii_digit()) {
+                        break;
+                    }
+                    let value = filters
+                        .iter()
+                        .rev()
+                        .fold(raw.to_string(), |v, filter| filter.reverse(&v));
+                    values.push((field.clone(), value));
+                    self.search(&rest[end..], index + 1, values, found);
+                    values.pop();
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Domain => f.write_str("domain"),
+            Field::Scope => f.write_str("scope"),
+            Field::Kind => f.write_str("kind"),
+            Field::Version => f.write_str("version"),
+            Field::Origin => f.write_str("origin"),
+            Field::Label(key) => write!(f, "label.{}", key),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inner.push(c),
+                            None => return Err(Error::new(format!("unclosed `{{` in `{}`", s))),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    if let Some(Segment::Placeholder { .. }) = segments.last() {
+                        return Err(Error::new(format!(
+                            "adjacent placeholders cannot be told apart in `{}`",
+                            s
+                        )));
+                    }
+                    segments.push(placeholder(&inner)?);
+                }
+                '}' => return Err(Error::new(format!("unmatched `}}` in `{}`", s))),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template {
+            source: s.to_string(),
+            segments,
+        })
+    }
+}
+
+fn placeholder(inner: &str) -> Result<Segment, Error> {
+    let mut parts = inner.split('|').map(str::trim);
+    let field = match parts.next().unwrap_or_default() {
+        "domain" => Field::Domain,
+        "scope" => Field::Scope,
+        "kind" => Field::Kind,
+        "version" => Field::Version,
+        "origin" => Field::Origin,
+        other => match other.strip_prefix("label.") {
+            Some(key) if !key.is_empty() => Field::Label(key.to_string()),
+            _ => return Err(Error::new(format!("unknown field `{}`", other))),
+        },
+    };
+    let filters = parts
+        .map(|name| match name {
+            "dots" => Ok(Filter::Dots),
+            "dashes" => Ok(Filter::Dashes),
+            "underscores" => Ok(Filter::Underscores),
+            "lower" => Ok(Filter::Lower),
+            "upper" => Ok(Filter::Upper),
+            other => Err(Error::new(format!("unknown filter `{}`", other))),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Segment::Placeholder { field, filters })
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Template {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An error parsing, rendering or reverse-matching a [`Template`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new(message: String) -> Self {
+        Error { message }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "template: {}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn renders() {
+        let cpu = fixtures::cpu_manifest();
+        let template: Template = "{domain}.{scope|dots}.{kind}.v{version}".parse().unwrap();
+        assert_eq!(
+            template.render(&cpu).unwrap(),
+            "example.org.metrics.host.cpu.v1"
+        );
+
+        let template: Template = "{{{label.environment|upper}}}/{scope|dashes}"
+            .parse()
+            .unwrap();
+        assert_eq!(template.render(&cpu).unwrap(), "{PRODUCTION}/metrics-host");
+        assert!(template.render_coordinates(&cpu.coordinates()).is_err());
+        assert!("{label.missing}"
+            .parse::<Template>()
+            .unwrap()
+            .render(&cpu)
+            .is_err());
+    }
+
+    #[test]
+    fn reverse_matches() {
+        let cpu = fixtures::cpu_manifest().coordinates();
+        let template: Template = "{scope|underscores}/{kind}/v{version}".parse().unwrap();
+        let captures = template.parse("metrics_host/cpu/v1").unwrap();
+        assert_eq!(captures.scope.as_deref(), Some("metrics/host"));
+        assert_eq!(captures.kind.as_deref(), Some("cpu"));
+        assert_eq!(captures.version, Some(1));
+        assert!(template.parse("metrics_host/cpu/vx").is_err());
+
+        let template: Template = "{domain}/{scope}/{kind}/v{version}".parse().unwrap();
+        assert!(template.parse("example.org/metrics/host/cpu/v1").is_err());
+        assert!(template.matches("example.org/metrics/host/cpu/v1", &cpu));
+
+        let template: Template = "{domain}:{scope}:{kind}:{version}".parse().unwrap();
+        let name = template.render_coordinates(&cpu).unwrap();
+        assert_eq!(template.parse(&name).unwrap().coordinates(), Some(cpu));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for s in &[
+            "{domain",
+            "domain}",
+            "{color}",
+            "{scope|sideways}",
+            "{domain}{kind}",
+        ] {
+            assert!(s.parse::<Template>().is_err(), "{}", s);
+        }
+        let template: Template = "a{{b}}".parse().unwrap();
+        assert_eq!(template.to_string(), "a{{b}}");
+    }
+}
@@ -0,0 +1,13 @@
+//! Helpers for testing code that consumes envelopes.
+//!
+//! [`corpus`] holds example envelopes and [`fixture`] builds envelopes with
+//! realistic defaults, [`arbitrary`] generates valid and adversarial ones
+//! for property tests, and [`golden`] snapshots their encodings.
+//!
+//! These are meant for downstream crates' tests, and are available with the
+//! `test-util` feature.
+
+pub mod arbitrary;
+pub mod corpus;
+pub mod fixture;
+pub mod golden;
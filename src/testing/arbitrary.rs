@@ -0,0 +1,245 @@
+//! [Proptest](proptest) strategies generating envelopes.
+//!
+//! [`Manifest`] and [`RawPacket`] implement [`Arbitrary`], generating
+//! manifests that pass [`Manifest::validate`] around content of any JSON
+//! shape. Handlers can be run against as many of them as the property test
+//! asks for:
+//!
+//! ```
+//! use intermodal::testing::arbitrary;
+//! use intermodal::{Format, Manifest, RawPacket};
+//! use proptest::prelude::*;
+//!
+//! proptest!(|(manifest in any::<Manifest>(), packet in arbitrary::packet())| {
+//!     prop_assert!(manifest.validate().is_ok());
+//!     let bytes = packet.to_bytes(Format::Json).unwrap();
+//!     prop_assert_eq!(RawPacket::from_bytes(&bytes, Format::Json).unwrap(), packet);
+//! });
+//! ```
+//!
+//! [`adversarial_manifest`] generates manifests that decode but break the
+//! rules a producer should follow: empty and oversized fields, control
+//! characters, versions of 0, expiry before creation. [`mangled`] generates
+//! the encodings of envelopes truncated, with flipped bits or with garbage
+//! spliced in, for decoders to reject without panicking.
+
+use std::fmt;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use proptest::arbitrary::Arbitrary;
+use proptest::collection::{btree_map, hash_map, vec};
+use proptest::option;
+use proptest::prelude::*;
+use proptest::sample::Index;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::trace::TraceContext;
+use crate::{Format, Manifest, Packet, RawPacket};
+
+impl Arbitrary for Manifest {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Manifest>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        manifest().boxed()
+    }
+}
+
+impl Arbitrary for Packet<Value> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<RawPacket>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        packet().boxed()
+    }
+}
+
+/// Manifests that pass [`Manifest::validate`].
+pub fn manifest() -> impl Strategy<Value = Manifest> {
+    let coordinates = (
+        "[a-z0-9]([a-z0-9-]{0,8}[a-z0-9])?(\\.[a-z0-9]([a-z0-9-]{0,8}[a-z0-9])?){0,3}",
+        "[a-z0-9_-][a-z0-9_.-]{0,8}(/[a-z0-9_-][a-z0-9_.-]{0,8}){0,2}",
+        "[a-z][a-z0-9_-]{0,15}",
+        1u32..10,
+    );
+    let instance = (
+        ctime(),
+        option::of(0i64..1_000_000),
+        "[a-z][a-z0-9-]{0,10}(\\.[a-z0-9]{1,8}){0,2}",
+        hash_map("[a-z0-9][a-z0-9_.-]{0,15}", "[ -~]{0,32}", 0..4),
+        option::of(trace()),
+        option::of("[a-z0-9-]{1,16}"),
+        option::of("[a-z][a-z0-9.]{0,20}"),
+    );
+    (coordinates, instance).prop_map(
+        |(
+            (domain, scope, kind, version),
+            (ctime, ttl, origin, labels, trace, correlation_id, reply_to),
+        )| Manifest {
+            domain,
+            scope,
+            kind,
+            version,
+            ctime,
+            expires: ttl.map(|ttl| ctime + Duration::seconds(ttl)),
+            origin,
+            labels,
+            trace,
+            correlation_id,
+            reply_to,
+        },
+    )
+}
+
+/// Times between 2000 and 2100, to the millisecond.
+fn ctime() -> impl Strategy<Value = DateTime<Utc>> {
+    (946_684_800_000i64..4_102_444_800_000).prop_map(|millis| {
+        Utc.timestamp_millis_opt(millis)
+            .single()
+            .expect("times in range are valid")
+    })
+}
+
+fn trace() -> impl Strategy<Value = TraceContext> {
+    (any::<[u8; 16]>(), any::<[u8; 8]>(), any::<bool>())
+        .prop_map(|(trace_id, span_id, sampled)| TraceContext::new(trace_id, span_id, sampled))
+        .prop_filter("trace and span ids are non-zero", TraceContext::is_valid)
+}
+
+/// JSON values of any shape, nested a few levels deep.
+pub fn json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<f64>()
+            .prop_filter("JSON numbers are finite", |f| f.is_finite())
+            .prop_map(Value::from),
+        "\\PC{0,16}".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(3, 32, 4, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..4).prop_map(Value::from),
+            btree_map("[a-z_]{1,8}", inner, 0..4)
+                .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+/// Packets with [arbitrary](manifest) manifests and [`json`] content.
+pub fn packet() -> impl Strategy<Value = RawPacket> {
+    packet_with(json())
+}
+
+/// Packets with [arbitrary](manifest) manifests and content from `content`.
+pub fn packet_with<T, S>(content: S) -> impl Strategy<Value = Packet<T>>
+where
+    T: fmt::Debug,
+    S: Strategy<Value = T>,
+{
+    (manifest(), content).prop_map(|(manifest, content)| Packet::new(manifest, content))
+}
+
+/// Manifests that decode, but need not pass [`Manifest::validate`].
+pub fn adversarial_manifest() -> impl Strategy<Value = Manifest> {
+    let coordinates = (
+        hostile_string(),
+        hostile_string(),
+        hostile_string(),
+        prop_oneof![Just(0), Just(u32::MAX), any::<u32>()],
+    );
+    let instance = (
+        prop_oneof![
+            Just(Utc.timestamp_opt(0, 0).unwrap()),
+            Just(Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap()),
+            ctime(),
+        ],
+        option::of(-1_000_000i64..1_000_000),
+        hostile_string(),
+        hash_map(hostile_string(), hostile_string(), 0..16),
+        option::of(hostile_string()),
+    );
+    (coordinates, instance).prop_map(
+        |((domain, scope, kind, version), (ctime, ttl, origin, labels, correlation_id))| Manifest {
+            domain,
+            scope,
+            kind,
+            version,
+            ctime,
+            expires: ttl.map(|ttl| ctime + Duration::seconds(ttl)),
+            origin,
+            labels,
+            trace: None,
+            correlation_id,
+            reply_to: None,
+        },
+    )
+}
+
+/// Strings a well-behaved producer would not emit.
+fn hostile_string() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just(String::new()),
+        "[\\x00-\\x1f\\x7f]{1,4}",
+        "[=,/.%\\\\\" ]{1,8}",
+        "\\PC{1,16}",
+        "a{256,300}",
+    ]
+}
+
+/// The encodings of [arbitrary](packet) packets in `format`, damaged:
+/// truncated, with a bit flipped, or with garbage spliced in.
+pub fn mangled(format: Format) -> impl Strategy<Value = Vec<u8>> {
+    (packet(), 0..3, any::<Index>(), vec(any::<u8>(), 1..8)).prop_map(
+        move |(packet, damage, at, garbage)| {
+            let mut bytes = encode(&packet, format);
+            if bytes.is_empty() {
+                return garbage;
+            }
+            let i = at.index(bytes.len());
+            match damage {
+                0 => bytes.truncate(i),
+                1 => bytes[i] ^= 1 << (garbage[0] % 8),
+                _ => {
+                    bytes.splice(i..i, garbage);
+                }
+            }
+            bytes
+        },
+    )
+}
+
+/// Encodes the packet, or nothing if the format cannot hold its content.
+fn encode<T: Serialize>(packet: &Packet<T>, format: Format) -> Vec<u8> {
+    packet.to_bytes(format).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn manifests_are_valid(packet in packet_with("[a-z]{1,8}")) {
+            prop_assert!(packet.manifest.validate().is_ok());
+            for &format in Format::all() {
+                let bytes = packet.to_bytes(format).unwrap();
+                let decoded = RawPacket::from_bytes(&bytes, format).unwrap();
+                prop_assert_eq!(decoded.manifest, packet.manifest.clone());
+            }
+        }
+
+        #[test]
+        fn adversarial_manifests_round_trip(manifest in adversarial_manifest()) {
+            let packet = Packet::new(manifest, Value::Null);
+            let bytes = packet.to_bytes(Format::Json).unwrap();
+            prop_assert_eq!(RawPacket::from_bytes(&bytes, Format::Json).unwrap(), packet);
+        }
+
+        #[test]
+        fn mangled_envelopes_do_not_panic(bytes in mangled(Format::Json)) {
+            let _ = RawPacket::from_bytes(&bytes, Format::Json);
+        }
+    }
+}
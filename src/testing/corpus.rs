@@ -0,0 +1,148 @@
+//! The canonical example envelopes bundled with the crate.
+//!
+//! These are the envelopes intermodal's own tests run against. Downstream
+//! crates can run their handlers against the same corpus, picking entries
+//! out by name or coordinates and encoding them in any supported format:
+//!
+//! ```
+//! use intermodal::testing::corpus::{self, Format};
+//! use intermodal::Coordinates;
+//!
+//! let cpu = Coordinates::new("example.org", "metrics/host", "cpu", 1);
+//! for entry in corpus::by_coordinates(&cpu) {
+//!     for format in Format::all() {
+//!         let bytes = entry.encode(*format).unwrap();
+//!         assert!(!bytes.is_empty());
+//!     }
+//! }
+//! ```
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+
+pub use crate::Format;
+use crate::{Coordinates, Header, Packet, RawPacket};
+
+const ENTRIES: &[Entry] = &[
+    Entry {
+        name: "cpu",
+        json: include_str!("../../fixtures/cpu.json"),
+    },
+    Entry {
+        name: "netstat",
+        json: include_str!("../../fixtures/netstat.json"),
+    },
+];
+
+/// One envelope in the corpus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    /// A short name for the envelope, unique within the corpus.
+    pub name: &'static str,
+    json: &'static str,
+}
+
+/// Every envelope in the corpus.
+pub fn entries() -> &'static [Entry] {
+    ENTRIES
+}
+
+/// Returns the envelope with the given name.
+pub fn get(name: &str) -> Option<Entry> {
+    ENTRIES.iter().find(|entry| entry.name == name).copied()
+}
+
+/// Returns the envelopes whose content has the given coordinates.
+pub fn by_coordinates(coordinates: &Coordinates) -> impl Iterator<Item = Entry> + '_ {
+    ENTRIES
+        .iter()
+        .filter(move |entry| entry.coordinates() == *coordinates)
+        .copied()
+}
+
+impl Entry {
+    /// The envelope as it is stored, in JSON.
+    pub fn json(&self) -> &'static str {
+        self.json
+    }
+
+    pub fn header(&self) -> Header {
+        serde_json::from_str(self.json).expect("corpus envelopes are valid")
+    }
+
+    pub fn coordinates(&self) -> Coordinates {
+        self.header().manifest.coordinates()
+    }
+
+    pub fn raw(&self) -> RawPacket {
+        serde_json::from_str(self.json).expect("corpus envelopes are valid")
+    }
+
+    /// Decodes the envelope with content of a concrete type.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<Packet<T>, serde_json::Error> {
+        serde_json::from_str(self.json)
+    }
+
+    /// Encodes the envelope in the given format.
+    pub fn encode(&self, format: Format) -> Result<Vec<u8>, Error> {
+        if format == Format::Json {
+            return Ok(self.json.as_bytes().to_vec());
+        }
+        self.raw()
+            .to_bytes(format)
+            .map_err(|e| Error::new(self, format, e))
+    }
+}
+
+/// An error encoding a corpus envelope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new<E: fmt::Display>(entry: &Entry, format: Format, e: E) -> Self {
+        Error {
+            message: format!("encoding `{}` as {}: {}", entry.name, format, e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Netstat};
+
+    #[test]
+    fn enumerates_fixtures() {
+        let names: Vec<_> = entries().iter().map(|entry| entry.name).collect();
+        assert_eq!(names, vec!["cpu", "netstat"]);
+
+        let netstat = fixtures::netstat_manifest().coordinates();
+        let found: Vec<_> = by_coordinates(&netstat).collect();
+        assert_eq!(found, vec![get("netstat").unwrap()]);
+        assert_eq!(found[0].raw(), fixtures::netstat_raw());
+        let packet = found[0].decode::<Netstat>().unwrap();
+        assert_eq!(packet.content.connections.len(), 2);
+        assert!(get("memory").is_none());
+    }
+
+    #[test]
+    fn encodes_every_format() {
+        for entry in entries() {
+            for format in Format::all() {
+                let bytes = entry.encode(*format).unwrap();
+                assert!(!bytes.is_empty(), "{} as {:?}", entry.name, format);
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Envelopes with realistic defaults, for tests that care about a few
+//! fields.
+//!
+//! [`manifest`] starts a [`ManifestBuilder`] with every field a producer
+//! would fill in already set, to the values of the corpus's `cpu` envelope,
+//! so a test sets only the fields it is about:
+//!
+//! ```
+//! use intermodal::testing::fixture;
+//!
+//! let packet = fixture::packet(serde_json::json!({ "idle": 83.25 }));
+//! assert_eq!(packet.manifest.origin, fixture::ORIGIN);
+//!
+//! let memory = fixture::manifest().kind("memory").label("rack", "r12").build().unwrap();
+//! assert_eq!(memory.scope, "metrics/host");
+//! assert_eq!(memory.labels.len(), 3);
+//! ```
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::{Manifest, ManifestBuilder, Packet};
+
+pub const DOMAIN: &str = "example.org";
+pub const SCOPE: &str = "metrics/host";
+pub const KIND: &str = "cpu";
+pub const ORIGIN: &str = "host01.example.org";
+
+/// The creation time of fixture envelopes, 2020-06-01T12:00:00Z.
+pub fn ctime() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2020, 6, 1, 12, 0, 0).unwrap()
+}
+
+/// A builder with the coordinates, creation time, origin and labels of the
+/// corpus's `cpu` envelope.
+pub fn manifest() -> ManifestBuilder {
+    Manifest::builder()
+        .domain(DOMAIN)
+        .scope(SCOPE)
+        .kind(KIND)
+        .version(1)
+        .ctime(ctime())
+        .origin(ORIGIN)
+        .label("environment", "production")
+        .label("datacenter", "us-east")
+}
+
+/// The content in an envelope with the [default](manifest) manifest.
+pub fn packet<T>(content: T) -> Packet<T> {
+    let manifest = manifest().build().expect("fixture manifests are complete");
+    Packet::new(manifest, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Cpu};
+
+    #[test]
+    fn matches_the_corpus() {
+        assert_eq!(manifest().build().unwrap(), fixtures::cpu_manifest());
+        let cpu = Cpu {
+            user: 12.5,
+            system: 4.25,
+            idle: 83.25,
+        };
+        assert_eq!(
+            serde_json::to_value(packet(cpu)).unwrap(),
+            serde_json::to_value(fixtures::cpu_raw()).unwrap()
+        );
+        assert!(manifest().build().unwrap().validate().is_ok());
+    }
+}
@@ -0,0 +1,266 @@
+//! Snapshot tests of envelopes' encoded bytes.
+//!
+//! A [`Golden`] encodes a packet in every format enabled in the build and
+//! compares each encoding with a snapshot checked in under a directory, as
+//! `<name>.<format>`, such as `cpu.json` or `cpu.msgpack`. A change to a
+//! type that alters what goes on the wire then fails the test with a diff:
+//!
+//! ```no_run
+//! use intermodal::testing::golden::Golden;
+//! # let packet: intermodal::RawPacket = unimplemented!();
+//!
+//! Golden::new("tests/golden").assert("cpu", &packet);
+//! ```
+//!
+//! When a change is intended, run the tests with `INTERMODAL_UPDATE_GOLDEN=1`
+//! to write the new encodings over the snapshots, and review and commit
+//! them. Missing snapshots are written in the same way.
+//!
+//! Snapshots of text formats are diffed line by line; JSON is pretty
+//! printed for the diff, though the snapshot holds it as encoded. Binary
+//! formats are diffed as hex dumps.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::{encode, Format, Packet};
+
+/// The environment variable that, when set to anything but `0` or the empty
+/// string, switches [`Golden::new`] to update mode.
+pub const UPDATE_VAR: &str = "INTERMODAL_UPDATE_GOLDEN";
+
+/// A directory of snapshots.
+#[derive(Debug, Clone)]
+pub struct Golden {
+    dir: PathBuf,
+    update: bool,
+}
+
+impl Golden {
+    /// Compares against the snapshots in `dir`, or updates them if
+    /// [`UPDATE_VAR`] is set.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        let update = std::env::var_os(UPDATE_VAR).is_some_and(|v| !v.is_empty() && v != "0");
+        Golden {
+            dir: dir.into(),
+            update,
+        }
+    }
+
+    /// Sets whether to update the snapshots rather than compare with them.
+    pub fn updating(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// The path of the snapshot of `name` in `format`.
+    pub fn path(&self, name: &str, format: Format) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, format))
+    }
+
+    /// Compares the packet's encodings with the snapshots of `name`, or
+    /// writes them in update mode or where snapshots are missing.
+    pub fn check<T: Serialize>(&self, name: &str, packet: &Packet<T>) -> Result<(), Error> {
+        let mut mismatches = Vec::new();
+        for &format in Format::all() {
+            let actual = packet.to_bytes(format).map_err(Error::Encode)?;
+            let path = self.path(name, format);
+            let expected = match fs::read(&path) {
+                Ok(expected) => Some(expected),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(Error::Io(path, e)),
+            };
+            match expected {
+                Some(expected) if expected == actual => {}
+                Some(expected) if !self.update => mismatches.push(Mismatch {
+                    diff: diff(&render(&expected, format), &render(&actual, format)),
+                    path,
+                }),
+                _ => write(&path, &actual)?,
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Mismatches(mismatches))
+        }
+    }
+
+    /// Like [`check`](Golden::check), but panics with the diffs on mismatch.
+    pub fn assert<T: Serialize>(&self, name: &str, packet: &Packet<T>) {
+        if let Err(e) = self.check(name, packet) {
+            panic!("{}", e);
+        }
+    }
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| Error::Io(dir.to_path_buf(), e))?;
+    }
+    fs::write(path, bytes).map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+/// Renders an encoding as lines to diff.
+fn render(bytes: &[u8], format: Format) -> Vec<String> {
+    let text = match format {
+        Format::Json => serde_json::from_slice::<serde_json::Value>(bytes)
+            .ok()
+            .and_then(|value| serde_json::to_string_pretty(&value).ok()),
+        #[cfg(feature = "cbor")]
+        Format::Cbor => None,
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => None,
+        #[allow(unreachable_patterns)]
+        _ => std::str::from_utf8(bytes).ok().map(str::to_string),
+    };
+    match text {
+        Some(text) => text.lines().map(str::to_string).collect(),
+        None => bytes
+            .chunks(16)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let hex: Vec<_> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("{:08x}  {}", i * 16, hex.join(" "))
+            })
+            .collect(),
+    }
+}
+
+/// A line diff of `expected` against `actual`, with unchanged lines prefixed
+/// by two spaces, removed ones by `- ` and added ones by `+ `.
+fn diff(expected: &[String], actual: &[String]) -> String {
+    // The lengths of the longest common subsequences of the suffixes.
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            out.push_str("  ");
+            out.push_str(&expected[i]);
+            i += 1;
+            j += 1;
+        } else if j < actual.len() && (i == expected.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out.push_str("+ ");
+            out.push_str(&actual[j]);
+            j += 1;
+        } else {
+            out.push_str("- ");
+            out.push_str(&expected[i]);
+            i += 1;
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// A snapshot that differs from the packet's encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    /// The diff from the snapshot to the encoding.
+    pub diff: String,
+}
+
+/// An error checking snapshots.
+#[derive(Debug)]
+pub enum Error {
+    /// A snapshot could not be read or written.
+    Io(PathBuf, io::Error),
+    Encode(encode::Error),
+    Mismatches(Vec<Mismatch>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Encode(e) => write!(f, "{}", e),
+            Error::Mismatches(mismatches) => {
+                for mismatch in mismatches {
+                    writeln!(
+                        f,
+                        "{} does not match the encoding:\n{}",
+                        mismatch.path.display(),
+                        mismatch.diff
+                    )?;
+                }
+                write!(f, "rerun with {}=1 to update the snapshots", UPDATE_VAR)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            Error::Encode(e) => Some(e),
+            Error::Mismatches(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn golden(name: &str) -> Golden {
+        let dir =
+            std::env::temp_dir().join(format!("intermodal-golden-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Golden::new(dir).updating(false)
+    }
+
+    #[test]
+    fn writes_missing_then_compares() {
+        let golden = golden("compare");
+        let packet = fixtures::cpu_raw();
+        golden.check("cpu", &packet).unwrap();
+        for &format in Format::all() {
+            assert!(golden.path("cpu", format).exists(), "{}", format);
+        }
+        golden.assert("cpu", &packet);
+
+        let mut changed = packet.clone();
+        changed.content["idle"] = serde_json::json!(80.0);
+        let mismatches = match golden.check("cpu", &changed) {
+            Err(Error::Mismatches(mismatches)) => mismatches,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(mismatches.len(), Format::all().len());
+        assert!(mismatches[0].diff.contains("-     \"idle\": 83.25,\n"));
+        assert!(mismatches[0].diff.contains("+     \"idle\": 80.0,\n"));
+
+        golden.clone().updating(true).assert("cpu", &changed);
+        golden.assert("cpu", &changed);
+    }
+
+    #[test]
+    fn diffs_lines() {
+        let lines = |s: &str| s.lines().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            diff(&lines("a\nb\nc\nd"), &lines("a\nc\nx\nd")),
+            "  a\n- b\n  c\n+ x\n  d\n"
+        );
+        assert_eq!(
+            render(&[0xde, 0xad, 0xbe, 0xef], Format::Json),
+            vec!["00000000  de ad be ef"]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Carrying W3C Trace Context in manifests.
+//!
+//! A manifest's optional [`trace`](crate::Manifest::trace) section holds a
+//! [`TraceContext`]: the `traceparent` and `tracestate` values of the
+//! [W3C Trace Context](https://www.w3.org/TR/trace-context/) standard, as
+//! HTTP carries them in headers. Each hop that handles an envelope can
+//! record its own span as a child of the sender's, so that the envelope's
+//! journey shows up as one trace:
+//!
+//! ```
+//! use intermodal::trace::TraceContext;
+//!
+//! let trace: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse()?;
+//! assert!(trace.sampled());
+//!
+//! let hop = trace.child([1, 2, 3, 4, 5, 6, 7, 8]);
+//! assert_eq!(hop.trace_id(), trace.trace_id());
+//! assert_eq!(hop.traceparent, "00-4bf92f3577b34da6a3ce929d0e0e4736-0102030405060708-01");
+//! # Ok::<(), intermodal::trace::Error>(())
+//! ```
+//!
+//! With the `opentelemetry` feature, [`inject`] and [`extract`] move trace
+//! context between manifests and OpenTelemetry contexts.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hex;
+
+/// The trace an envelope belongs to, and the span that sent it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TraceContext {
+    /// `version-traceid-parentid-flags`, in lowercase hex.
+    pub traceparent: String,
+    /// Vendor-specific trace state, passed along unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
+}
+
+/// A `traceparent` that is not one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    traceparent: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid traceparent `{}`", self.traceparent)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl TraceContext {
+    /// Trace context for a span, in version 00 of the format.
+    pub fn new(trace_id: [u8; 16], span_id: [u8; 8], sampled: bool) -> Self {
+        TraceContext {
+            traceparent: format!(
+                "00-{}-{}-{:02x}",
+                hex::encode(&trace_id),
+                hex::encode(&span_id),
+                u8::from(sampled)
+            ),
+            tracestate: None,
+        }
+    }
+
+    pub fn tracestate<S: Into<String>>(mut self, tracestate: S) -> Self {
+        self.tracestate = Some(tracestate.into());
+        self
+    }
+
+    /// Trace context for a child span of this one, in the same trace, with
+    /// the same flags and trace state.
+    pub fn child(&self, span_id: [u8; 8]) -> Self {
+        let flags = self.parts().map_or(0, |(_, _, flags)| flags);
+        let trace_id = self.trace_id().unwrap_or_default();
+        TraceContext {
+            traceparent: format!(
+                "00-{}-{}-{:02x}",
+                hex::encode(&trace_id),
+                hex::encode(&span_id),
+                flags
+            ),
+            tracestate: self.tracestate.clone(),
+        }
+    }
+
+    /// The trace id, if the `traceparent` is valid.
+    pub fn trace_id(&self) -> Option<[u8; 16]> {
+        self.parts().map(|(trace_id, _, _)| trace_id)
+    }
+
+    /// The id of the span that sent the envelope, if the `traceparent` is
+    /// valid.
+    pub fn span_id(&self) -> Option<[u8; 8]> {
+        self.parts().map(|(_, span_id, _)| span_id)
+    }
+
+    /// Whether the sender sampled the trace.
+    pub fn sampled(&self) -> bool {
+        self.parts().is_some_and(|(_, _, flags)| flags & 1 == 1)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.parts().is_some()
+    }
+
+    /// The trace id, span id and flags, if the `traceparent` is valid: four
+    /// fields of lowercase hex, of which the version is not `ff`, and
+    /// neither id is all zeros. Versions after 00 may append fields.
+    fn parts(&self) -> Option<([u8; 16], [u8; 8], u8)> {
+        let mut fields = self.traceparent.split('-');
+        let version = fields.next().filter(|v| v.len() == 2 && *v != "ff")?;
+        let trace_id = fields.next().filter(|id| id.len() == 32)?;
+        let span_id = fields.next().filter(|id| id.len() == 16)?;
+        let flags = fields.next().filter(|flags| flags.len() == 2)?;
+        if version == "00" && fields.next().is_some() {
+            return None;
+        }
+        let lowercase = [version, trace_id, span_id, flags]
+            .iter()
+            .all(|field| !field.bytes().any(|b| b.is_ascii_uppercase()));
+        if !lowercase {
+            return None;
+        }
+        hex::decode(version)?;
+        let mut trace = [0; 16];
+        trace.copy_from_slice(&hex::decode(trace_id)?);
+        let mut span = [0; 8];
+        span.copy_from_slice(&hex::decode(span_id)?);
+        let flags = hex::decode(flags)?[0];
+        if trace == [0; 16] || span == [0; 8] {
+            return None;
+        }
+        Some((trace, span, flags))
+    }
+}
+
+/// Parses a `traceparent`, without trace state.
+impl FromStr for TraceContext {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trace = TraceContext {
+            traceparent: s.to_string(),
+            tracestate: None,
+        };
+        if trace.is_valid() {
+            Ok(trace)
+        } else {
+            Err(Error {
+                traceparent: s.to_string(),
+            })
+        }
+    }
+}
+
+#[cfg(feature = "opentelemetry")]
+pub use self::otel::{extract, inject};
+
+#[cfg(feature = "opentelemetry")]
+mod otel {
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry::Context;
+
+    use super::TraceContext;
+    use crate::Manifest;
+
+    /// Records the span of `cx` in the manifest, replacing any trace
+    /// context it had. Leaves the manifest alone if `cx` has no valid span.
+    pub fn inject(manifest: &mut Manifest, cx: &Context) {
+        let span = cx.span();
+        let span = span.span_context();
+        if !span.is_valid() {
+            return;
+        }
+        let mut trace = TraceContext::new(
+            span.trace_id().to_bytes(),
+            span.span_id().to_bytes(),
+            span.is_sampled(),
+        );
+        let state = span.trace_state().header();
+        if !state.is_empty() {
+            trace = trace.tracestate(state);
+        }
+        manifest.trace = Some(trace);
+    }
+
+    /// The current context, with the manifest's span as its remote parent
+    /// if the manifest carries valid trace context.
+    pub fn extract(manifest: &Manifest) -> Context {
+        let cx = Context::current();
+        let trace = match &manifest.trace {
+            Some(trace) => trace,
+            None => return cx,
+        };
+        let (trace_id, span_id) = match (trace.trace_id(), trace.span_id()) {
+            (Some(trace_id), Some(span_id)) => (trace_id, span_id),
+            _ => return cx,
+        };
+        let state = trace
+            .tracestate
+            .as_deref()
+            .and_then(|state| state.parse().ok())
+            .unwrap_or_else(TraceState::default);
+        let flags = if trace.sampled() {
+            TraceFlags::SAMPLED
+        } else {
+            TraceFlags::NOT_SAMPLED
+        };
+        cx.with_remote_span_context(SpanContext::new(
+            TraceId::from_bytes(trace_id),
+            SpanId::from_bytes(span_id),
+            flags,
+            true,
+            state,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_traceparents() {
+        let trace: TraceContext = PARENT.parse().unwrap();
+        assert_eq!(
+            trace.span_id(),
+            Some([0, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7])
+        );
+        assert!(trace.sampled());
+        assert_eq!(
+            TraceContext::new(trace.trace_id().unwrap(), trace.span_id().unwrap(), true),
+            trace
+        );
+
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00",
+        ] {
+            assert!(invalid.parse::<TraceContext>().is_err(), "{}", invalid);
+        }
+        assert!("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00"
+            .parse::<TraceContext>()
+            .is_ok());
+    }
+
+    #[test]
+    fn travels_in_manifests() {
+        let mut manifest = fixtures::cpu_manifest();
+        let json = serde_json::to_value(&manifest).unwrap();
+        assert!(json.get("trace").is_none());
+
+        manifest.trace = Some(
+            PARENT
+                .parse::<TraceContext>()
+                .unwrap()
+                .tracestate("congo=t61rcWkgMzE"),
+        );
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert!(json.contains(r#""trace":{"traceparent":"00-4bf9"#));
+        let decoded: crate::Manifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, manifest);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn converts_to_and_from_opentelemetry() {
+        use opentelemetry::trace::TraceContextExt;
+
+        let mut manifest = fixtures::cpu_manifest();
+        manifest.trace = Some(
+            PARENT
+                .parse::<TraceContext>()
+                .unwrap()
+                .tracestate("congo=t61rcWkgMzE"),
+        );
+        let cx = extract(&manifest);
+        assert!(cx.span().span_context().is_remote());
+
+        let mut forwarded = fixtures::cpu_manifest();
+        inject(&mut forwarded, &cx);
+        assert_eq!(forwarded.trace, manifest.trace);
+
+        let mut untouched = fixtures::cpu_manifest();
+        inject(&mut untouched, &opentelemetry::Context::new());
+        assert_eq!(untouched.trace, None);
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::RawPacket;
+
+/// A processing step applied to packets in flight.
+///
+/// A transform may rewrite the packet it is given, or return `None` to drop
+/// it, which makes filters a special case of transforms.
+pub trait Transform: Send + Sync {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, Error>;
+}
+
+impl<F> Transform for F
+where
+    F: Fn(RawPacket) -> Result<Option<RawPacket>, Error> + Send + Sync,
+{
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, Error> {
+        self(packet)
+    }
+}
+
+/// Applies each transform in turn, stopping early if one drops the packet.
+pub fn apply_all(
+    transforms: &[Arc<dyn Transform>],
+    packet: RawPacket,
+) -> Result<Option<RawPacket>, Error> {
+    let mut packet = packet;
+    for transform in transforms {
+        packet = match transform.apply(packet)? {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(packet))
+}
+
+/// An error raised by a [`Transform`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transform failed: {}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
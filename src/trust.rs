@@ -0,0 +1,399 @@
+//! Checking inbound packets against a trust policy.
+//!
+//! A [`Policy`] is a [`Transform`] that decides, for each packet arriving
+//! from outside, whether it is trusted: packets matching a selector may be
+//! required to be [signed](crate::signing) by one of the public keys the
+//! policy's [`PublicKeyProvider`] holds, or to carry a digest of their content, and
+//! unsigned packets may be refused from origins not known to the policy.
+//! A signature a packet carries is always checked, required or not.
+//!
+//! ```no_run
+//! use intermodal::keys::PublicKeySet;
+//! use intermodal::trust::{Action, Policy};
+//!
+//! let policy = Policy::new(PublicKeySet::from_file("/etc/intermodal/partners.json")?)
+//!     .require_signature("domain=partner.example".parse()?)
+//!     .known_origins(["host01.example.org", "host02.example.org"])
+//!     .action(Action::Reject)
+//!     .audit(|audit| eprintln!("{}", audit));
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The policy needs only the partners' public keys, so trusting a partner
+//! never means holding what it signs with. With [`Policy::loader`], a
+//! [`Reloadable`](crate::reload::Reloadable) policy takes up partners' new
+//! keys as their file is edited, keeping its rules.
+//!
+//! Every decision is passed to the audit function, if there is one, as an
+//! [`Audit`], whose `Display` form is one log line. What happens to an
+//! untrusted packet depends on the [`Action`]; observing only, the default,
+//! lets a policy be rolled out by watching its audit log before it is
+//! enforced.
+//!
+//! Available with the `ed25519` feature. Requiring digests also takes the
+//! `blob` feature.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use crate::keys::{self, PublicKeyProvider, PublicKeySet};
+use crate::signing;
+use crate::transform::{self, Transform};
+use crate::{labels, Coordinates, RawPacket, Selector};
+
+/// The label holding the digest of a packet's content, as written by
+/// [`stamp_digest`].
+pub const DIGEST_LABEL: &str = labels::CONTENT_DIGEST;
+
+/// What to do with an untrusted packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Let it through, auditing it as untrusted.
+    Observe,
+    Drop,
+    /// Fail the transform with an error naming the violation.
+    Reject,
+}
+
+/// Why a packet is untrusted.
+#[derive(Debug)]
+pub enum Violation {
+    /// The packet must be signed, and is not.
+    Unsigned,
+    /// The packet is unsigned, and its origin is not known.
+    UnknownOrigin,
+    /// The packet's signature does not verify.
+    Signature(signing::Error),
+    /// The packet must carry a content digest, and does not.
+    MissingDigest,
+    /// The packet's content digest does not match its content.
+    Digest(String),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Unsigned => f.write_str("signature required"),
+            Violation::UnknownOrigin => f.write_str("unsigned from an unknown origin"),
+            Violation::Signature(e) => write!(f, "bad signature: {}", e),
+            Violation::MissingDigest => f.write_str("content digest required"),
+            Violation::Digest(message) => write!(f, "bad content digest: {}", message),
+        }
+    }
+}
+
+/// A decision of a [`Policy`].
+#[derive(Debug)]
+pub struct Audit {
+    pub coordinates: Coordinates,
+    pub origin: String,
+    /// The key the packet was signed with, if it was signed with one the
+    /// policy's provider holds.
+    pub key_id: Option<String>,
+    /// Why the packet is untrusted, if it is.
+    pub violation: Option<Violation>,
+    /// What was done with the packet.
+    pub action: Option<Action>,
+}
+
+impl fmt::Display for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.violation {
+            None => write!(f, "trusted {} from {}", self.coordinates, self.origin)?,
+            Some(violation) => write!(
+                f,
+                "untrusted {} from {}: {}",
+                self.coordinates, self.origin, violation
+            )?,
+        }
+        if let Some(key_id) = &self.key_id {
+            write!(f, ", signed with `{}`", key_id)?;
+        }
+        match self.action {
+            Some(Action::Observe) => f.write_str(", observed"),
+            Some(Action::Drop) => f.write_str(", dropped"),
+            Some(Action::Reject) => f.write_str(", rejected"),
+            None => Ok(()),
+        }
+    }
+}
+
+type AuditFn = Box<dyn Fn(&Audit) + Send + Sync>;
+
+/// The rules inbound packets are held to.
+pub struct Policy<P> {
+    keys: P,
+    signed: Vec<Selector>,
+    #[cfg(feature = "blob")]
+    digested: Vec<Selector>,
+    known_origins: Option<HashSet<String>>,
+    action: Action,
+    audit: Option<AuditFn>,
+}
+
+impl<P> fmt::Debug for Policy<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Policy")
+            .field("signed", &self.signed)
+            .field("known_origins", &self.known_origins)
+            .field("action", &self.action)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P: PublicKeyProvider> Policy<P> {
+    /// A policy verifying signatures with the public `keys`, requiring
+    /// nothing yet, and observing untrusted packets only.
+    pub fn new(keys: P) -> Self {
+        Policy {
+            keys,
+            signed: Vec::new(),
+            #[cfg(feature = "blob")]
+            digested: Vec::new(),
+            known_origins: None,
+            action: Action::Observe,
+            audit: None,
+        }
+    }
+
+    /// Requires packets matching `selector` to be signed.
+    pub fn require_signature(mut self, selector: Selector) -> Self {
+        self.signed.push(selector);
+        self
+    }
+
+    /// Requires packets matching `selector` to carry a content digest, in
+    /// the [`DIGEST_LABEL`] label.
+    #[cfg(feature = "blob")]
+    pub fn require_digest(mut self, selector: Selector) -> Self {
+        self.digested.push(selector);
+        self
+    }
+
+    /// Requires packets from origins other than these to be signed.
+    pub fn known_origins<I, S>(mut self, origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.known_origins = Some(origins.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Passes every decision to `audit`.
+    pub fn audit<F>(mut self, audit: F) -> Self
+    where
+        F: Fn(&Audit) + Send + Sync + 'static,
+    {
+        self.audit = Some(Box::new(audit));
+        self
+    }
+
+    /// Checks a packet against the policy, returning the key it was signed
+    /// with, if any.
+    pub fn check(&self, packet: &RawPacket) -> Result<Option<String>, Violation> {
+        let manifest = &packet.manifest;
+        let key_id = match &packet.signature {
+            Some(signature) => {
+                packet
+                    .verify_with(&self.keys)
+                    .map_err(Violation::Signature)?;
+                signature.key_id.clone()
+            }
+            None if self.signed.iter().any(|s| s.matches(manifest)) => {
+                return Err(Violation::Unsigned)
+            }
+            None if self
+                .known_origins
+                .as_ref()
+                .is_some_and(|known| !known.contains(&manifest.origin)) =>
+            {
+                return Err(Violation::UnknownOrigin)
+            }
+            None => None,
+        };
+        #[cfg(feature = "blob")]
+        if self.digested.iter().any(|s| s.matches(manifest)) {
+            let expected = manifest
+                .labels
+                .get(DIGEST_LABEL)
+                .ok_or(Violation::MissingDigest)?;
+            let actual = content_digest(packet).map_err(|e| Violation::Digest(e.to_string()))?;
+            if actual.to_string() != *expected {
+                return Err(Violation::Digest(format!("content has digest {}", actual)));
+            }
+        }
+        Ok(key_id)
+    }
+}
+
+impl Policy<PublicKeySet> {
+    /// Returns a loader that reads a public key file, suitable for
+    /// [`Reloadable::reload_from`] and [`reload::watch`](crate::reload),
+    /// and gives `build` a new policy verifying with its keys, to add the
+    /// policy's rules to.
+    ///
+    /// [`Reloadable::reload_from`]: crate::reload::Reloadable::reload_from
+    pub fn loader<F>(build: F) -> impl Fn(&Path) -> Result<Policy<PublicKeySet>, keys::Error>
+    where
+        F: Fn(Policy<PublicKeySet>) -> Policy<PublicKeySet>,
+    {
+        move |path| Ok(build(Policy::new(PublicKeySet::from_file(path)?)))
+    }
+}
+
+impl<P: PublicKeyProvider> Transform for Policy<P> {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        let (key_id, violation) = match self.check(&packet) {
+            Ok(key_id) => (key_id, None),
+            Err(violation) => (None, Some(violation)),
+        };
+        let action = violation.as_ref().map(|_| self.action);
+        let audit = Audit {
+            coordinates: packet.manifest.coordinates(),
+            origin: packet.manifest.origin.clone(),
+            key_id,
+            violation,
+            action,
+        };
+        if let Some(log) = &self.audit {
+            log(&audit);
+        }
+        match (audit.violation, action) {
+            (Some(violation), Some(Action::Reject)) => Err(transform::Error::new(format!(
+                "untrusted packet from `{}`: {}",
+                audit.origin, violation
+            ))),
+            (_, Some(Action::Drop)) => Ok(None),
+            _ => Ok(Some(packet)),
+        }
+    }
+}
+
+/// The digest of the canonical encoding of a packet's content alone.
+#[cfg(feature = "blob")]
+fn content_digest(packet: &RawPacket) -> Result<crate::blob::Digest, serde_json::Error> {
+    crate::canonical::to_vec(&packet.content).map(|bytes| crate::blob::Digest::of(&bytes))
+}
+
+/// Labels a packet with the digest of its content, as
+/// [`Policy::require_digest`] checks.
+#[cfg(feature = "blob")]
+pub fn stamp_digest(packet: &mut RawPacket) -> Result<(), serde_json::Error> {
+    let digest = content_digest(packet)?;
+    packet
+        .manifest
+        .labels
+        .insert(DIGEST_LABEL.to_string(), digest.to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::fixtures;
+    use crate::keys::{KeyEntry, KeySet, PublicKeySet};
+
+    /// The partner's keys, which only it holds.
+    fn keys() -> KeySet {
+        KeySet::new().insert(KeyEntry::new("k1", vec![1; 32]))
+    }
+
+    /// What the policy is given of them.
+    fn public() -> PublicKeySet {
+        keys().public_keys().unwrap()
+    }
+
+    #[test]
+    fn reloads_partners_keys() {
+        use crate::reload::Reloadable;
+
+        let path =
+            std::env::temp_dir().join(format!("intermodal-partners-{}.json", std::process::id()));
+        let load = Policy::loader(|policy| {
+            policy
+                .require_signature("kind=cpu".parse().unwrap())
+                .action(Action::Reject)
+        });
+        std::fs::write(&path, PublicKeySet::new().to_json()).unwrap();
+        let policy = Reloadable::new(load(&path).unwrap());
+
+        let mut cpu = fixtures::cpu_raw();
+        cpu.sign_with(&keys()).unwrap();
+        assert!(policy.load().apply(cpu.clone()).is_err());
+
+        std::fs::write(&path, public().to_json()).unwrap();
+        policy.reload_from(&path, &load).unwrap();
+        assert!(policy.load().apply(cpu.clone()).unwrap().is_some());
+
+        // A key file that does not parse leaves the policy as it was.
+        std::fs::write(&path, "{").unwrap();
+        assert!(matches!(
+            policy.reload_from(&path, &load),
+            Err(keys::Error::Parse(_))
+        ));
+        assert!(policy.load().apply(cpu.clone()).unwrap().is_some());
+        assert!(policy.load().apply(fixtures::cpu_raw()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn enforces() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let policy = Policy::new(public())
+            .require_signature("kind=cpu".parse().unwrap())
+            .known_origins(["host01.example.org"])
+            .action(Action::Reject)
+            .audit({
+                let log = Arc::clone(&log);
+                move |audit| log.lock().unwrap().push(audit.to_string())
+            });
+
+        let mut cpu = fixtures::cpu_raw();
+        assert!(policy.apply(cpu.clone()).is_err());
+        cpu.sign_with(&keys()).unwrap();
+        assert!(policy.apply(cpu.clone()).unwrap().is_some());
+        cpu.manifest.origin = "elsewhere".to_string();
+        let error = policy.apply(cpu).unwrap_err();
+        assert!(error.to_string().contains("bad signature"), "{}", error);
+
+        let mut netstat = fixtures::netstat_raw();
+        assert!(policy.apply(netstat.clone()).unwrap().is_some());
+        netstat.manifest.origin = "elsewhere".to_string();
+        assert!(policy.apply(netstat).is_err());
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 5);
+        assert!(
+            log[0].starts_with("untrusted example.org/metrics/host/cpu@1 from ")
+                && log[0].ends_with(": signature required, rejected"),
+            "{}",
+            log[0]
+        );
+        assert!(log[1].ends_with(", signed with `k1`"), "{}", log[1]);
+        assert!(log[4].ends_with("unsigned from an unknown origin, rejected"));
+    }
+
+    #[cfg(feature = "blob")]
+    #[test]
+    fn requires_digests() {
+        let policy = Policy::new(public())
+            .require_digest("kind=cpu".parse().unwrap())
+            .action(Action::Drop);
+        let mut cpu = fixtures::cpu_raw();
+        assert!(matches!(policy.check(&cpu), Err(Violation::MissingDigest)));
+        assert!(policy.apply(cpu.clone()).unwrap().is_none());
+        stamp_digest(&mut cpu).unwrap();
+        assert!(policy.check(&cpu).is_ok());
+        cpu.content["user"] = 0.into();
+        assert!(matches!(policy.check(&cpu), Err(Violation::Digest(_))));
+    }
+}
@@ -0,0 +1,187 @@
+//! Type URLs for content types.
+//!
+//! Systems built around `google.protobuf.Any` identify message types by a
+//! type URL. Intermodal content types are given type URLs of the form
+//!
+//! ```text
+//! intermodal://example.org/metrics/host/cpu/v1
+//! ```
+//!
+//! that is, the domain, the scope (which may span several path segments),
+//! the kind, and the version prefixed with `v`.
+//!
+//! Protobuf-centric services usually already have type URLs of their own for
+//! the same messages, such as `type.googleapis.com/acme.metrics.Cpu`. A
+//! [`TypeUrlMap`] records those aliases so that an `Any` arriving with a
+//! foreign type URL can be resolved to coordinates, and coordinates can be
+//! published under the URL a foreign registry expects.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{Coordinates, Manifest};
+
+/// The scheme of intermodal type URLs.
+pub const SCHEME: &str = "intermodal://";
+
+impl Coordinates {
+    /// Returns the intermodal type URL for these coordinates.
+    pub fn type_url(&self) -> String {
+        format!(
+            "{}{}/{}/{}/v{}",
+            SCHEME, self.domain, self.scope, self.kind, self.version
+        )
+    }
+
+    /// Parses an intermodal type URL.
+    pub fn from_type_url(url: &str) -> Result<Self, Error> {
+        let path = url
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| Error::new(url, format!("expected the `{}` scheme", SCHEME)))?;
+        let mut segments: Vec<&str> = path.split('/').collect();
+        if segments.len() < 4 || segments.iter().any(|s| s.is_empty()) {
+            return Err(Error::new(url, "expected domain, scope, kind and version"));
+        }
+
+        let version = segments.pop().unwrap_or_default();
+        let version = version
+            .strip_prefix('v')
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| Error::new(url, format!("invalid version `{}`", version)))?;
+        let kind = segments.pop().unwrap_or_default();
+        let domain = segments.remove(0);
+        Ok(Coordinates::new(domain, segments.join("/"), kind, version))
+    }
+}
+
+impl Manifest {
+    /// Returns the intermodal type URL for the content.
+    pub fn type_url(&self) -> String {
+        self.coordinates().type_url()
+    }
+}
+
+/// Aliases between coordinates and foreign type URLs.
+#[derive(Debug, Clone, Default)]
+pub struct TypeUrlMap {
+    by_url: HashMap<String, Coordinates>,
+    by_coordinates: HashMap<Coordinates, String>,
+}
+
+impl TypeUrlMap {
+    pub fn new() -> Self {
+        TypeUrlMap::default()
+    }
+
+    /// Records that `url` names the content type at `coordinates`.
+    ///
+    /// A later alias for the same coordinates becomes the one returned by
+    /// [`type_url`](TypeUrlMap::type_url), while earlier aliases still
+    /// resolve.
+    pub fn insert<U: Into<String>>(&mut self, coordinates: Coordinates, url: U) {
+        let url = url.into();
+        self.by_url.insert(url.clone(), coordinates.clone());
+        self.by_coordinates.insert(coordinates, url);
+    }
+
+    /// Resolves a type URL, either an intermodal one or a recorded alias, to
+    /// coordinates.
+    pub fn resolve(&self, url: &str) -> Result<Coordinates, Error> {
+        if let Some(coordinates) = self.by_url.get(url) {
+            return Ok(coordinates.clone());
+        }
+        Coordinates::from_type_url(url)
+    }
+
+    /// Returns the type URL to publish for `coordinates`: the most recently
+    /// recorded alias if there is one, or else the intermodal type URL.
+    pub fn type_url(&self, coordinates: &Coordinates) -> String {
+        match self.by_coordinates.get(coordinates) {
+            Some(url) => url.clone(),
+            None => coordinates.type_url(),
+        }
+    }
+}
+
+/// An error parsing a type URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    url: String,
+    message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(url: &str, message: S) -> Self {
+        Error {
+            url: url.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid type URL `{}`: {}", self.url, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn round_trips() {
+        let manifest = fixtures::cpu_manifest();
+        assert_eq!(
+            manifest.type_url(),
+            "intermodal://example.org/metrics/host/cpu/v1"
+        );
+
+        let coordinates = Coordinates::new(
+            "example.org",
+            "metrics/applications/some-app",
+            "useractions",
+            2,
+        );
+        let url = coordinates.type_url();
+        assert_eq!(
+            url,
+            "intermodal://example.org/metrics/applications/some-app/useractions/v2"
+        );
+        assert_eq!(Coordinates::from_type_url(&url).unwrap(), coordinates);
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for url in &[
+            "type.googleapis.com/acme.Cpu",
+            "intermodal://example.org/cpu/v1",
+            "intermodal://example.org/metrics//cpu/v1",
+            "intermodal://example.org/metrics/cpu/1",
+            "intermodal://example.org/metrics/cpu/vx",
+        ] {
+            assert!(Coordinates::from_type_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let cpu = fixtures::cpu_manifest().coordinates();
+        let mut map = TypeUrlMap::new();
+        map.insert(cpu.clone(), "type.googleapis.com/acme.metrics.Cpu");
+
+        assert_eq!(
+            map.resolve("type.googleapis.com/acme.metrics.Cpu").unwrap(),
+            cpu
+        );
+        assert_eq!(map.resolve(&cpu.type_url()).unwrap(), cpu);
+        assert_eq!(map.type_url(&cpu), "type.googleapis.com/acme.metrics.Cpu");
+
+        let netstat = fixtures::netstat_manifest().coordinates();
+        assert_eq!(map.type_url(&netstat), netstat.type_url());
+        assert!(map.resolve("type.googleapis.com/acme.metrics.Mem").is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Checking that a manifest is well formed.
+//!
+//! Decoding accepts any strings in a manifest's fields, but producers should
+//! only emit manifests that pass [`Manifest::validate`]:
+//!
+//! - `domain` is a DNS name: dot-separated labels of ASCII letters, digits
+//!   and hyphens, each 1 to 63 long and neither starting nor ending with a
+//!   hyphen, with at most 253 characters in all;
+//! - `scope` is a slash-separated path of non-empty segments of ASCII
+//!   letters, digits, `-`, `_` and `.`, with no `.` or `..` segments;
+//! - `kind` is non-empty and lowercase;
+//! - `version` is at least 1;
+//! - label keys are names of 1 to [`MAX_LABEL_KEY_LEN`] ASCII letters,
+//!   digits, `-`, `_` and `.`, starting with a letter or digit, optionally
+//!   after a prefix that is a DNS name and a `/`, as in Kubernetes labels
+//!   such as `intermodal.io/compression`;
+//! - label values are at most [`MAX_LABEL_VALUE_LEN`] characters with no
+//!   control characters.
+//!
+//! Validation reports every violation at once, rather than stopping at the
+//! first.
+
+use std::fmt;
+
+use crate::Manifest;
+
+/// The longest a domain may be.
+pub const MAX_DOMAIN_LEN: usize = 253;
+
+/// The longest the name of a label key may be, not counting its prefix.
+pub const MAX_LABEL_KEY_LEN: usize = 63;
+
+/// The longest a label value may be, in characters.
+pub const MAX_LABEL_VALUE_LEN: usize = 255;
+
+impl Manifest {
+    /// Checks the manifest against the rules in the [`validation`] module.
+    ///
+    /// [`validation`]: crate::validation
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut violations = Vec::new();
+        if let Err(reason) = check_domain(&self.domain) {
+            violations.push(Violation::Domain {
+                domain: self.domain.clone(),
+                reason,
+            });
+        }
+        if let Err(reason) = check_scope(&self.scope) {
+            violations.push(Violation::Scope {
+                scope: self.scope.clone(),
+                reason,
+            });
+        }
+        if let Err(reason) = check_kind(&self.kind) {
+            violations.push(Violation::Kind {
+                kind: self.kind.clone(),
+                reason,
+            });
+        }
+        if self.version < 1 {
+            violations.push(Violation::Version(self.version));
+        }
+
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_unstable();
+        for (key, value) in labels {
+            if let Err(reason) = check_label_key(key) {
+                violations.push(Violation::LabelKey {
+                    key: key.clone(),
+                    reason,
+                });
+            }
+            if let Err(reason) = check_label_value(value) {
+                violations.push(Violation::LabelValue {
+                    key: key.clone(),
+                    reason,
+                });
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { violations })
+        }
+    }
+}
+
+fn check_domain(domain: &str) -> Result<(), &'static str> {
+    if domain.is_empty() {
+        return Err("is empty");
+    }
+    if domain.len() > MAX_DOMAIN_LEN {
+        return Err("is longer than 253 characters");
+    }
+    for label in domain.split('.') {
+        if label.is_empty() {
+            return Err("has an empty label");
+        }
+        if label.len() > 63 {
+            return Err("has a label longer than 63 characters");
+        }
+        if !label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        {
+            return Err("may only contain ASCII letters, digits, hyphens and dots");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err("has a label starting or ending with a hyphen");
+        }
+    }
+    Ok(())
+}
+
+fn check_scope(scope: &str) -> Result<(), &'static str> {
+    if scope.is_empty() {
+        return Err("is empty");
+    }
+    for segment in scope.split('/') {
+        if segment.is_empty() {
+            return Err("has an empty segment");
+        }
+        if segment == "." || segment == ".." {
+            return Err("has a `.` or `..` segment");
+        }
+        if !segment.bytes().all(is_name_byte) {
+            return Err(
+                "has a segment with characters other than ASCII letters, digits, `-`, `_` and `.`",
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_kind(kind: &str) -> Result<(), &'static str> {
+    if kind.is_empty() {
+        return Err("is empty");
+    }
+    if kind.chars().any(char::is_uppercase) {
+        return Err("is not lowercase");
+    }
+    Ok(())
+}
+
+fn check_label_key(key: &str) -> Result<(), &'static str> {
+    let name = match key.split_once('/') {
+        Some((prefix, name)) => {
+            if check_domain(prefix).is_err() {
+                return Err("has a prefix that is not a DNS name");
+            }
+            name
+        }
+        None => key,
+    };
+    if name.is_empty() {
+        return Err("is empty");
+    }
+    if name.len() > MAX_LABEL_KEY_LEN {
+        return Err("is longer than 63 characters");
+    }
+    if !name.bytes().all(is_name_byte) {
+        return Err("may only contain ASCII letters, digits, `-`, `_` and `.`, after one prefix");
+    }
+    if !name.as_bytes()[0].is_ascii_alphanumeric() {
+        return Err("does not start with a letter or digit");
+    }
+    Ok(())
+}
+
+fn check_label_value(value: &str) -> Result<(), &'static str> {
+    if value.chars().count() > MAX_LABEL_VALUE_LEN {
+        return Err("is longer than 255 characters");
+    }
+    if value.chars().any(char::is_control) {
+        return Err("contains control characters");
+    }
+    Ok(())
+}
+
+fn is_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.'
+}
+
+/// One way in which a manifest is malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    Domain {
+        domain: String,
+        reason: &'static str,
+    },
+    Scope {
+        scope: String,
+        reason: &'static str,
+    },
+    Kind {
+        kind: String,
+        reason: &'static str,
+    },
+    /// The version is zero.
+    Version(u32),
+    LabelKey {
+        key: String,
+        reason: &'static str,
+    },
+    /// The value of the label with the key is malformed.
+    LabelValue {
+        key: String,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Domain { domain, reason } => write!(f, "domain `{}` {}", domain, reason),
+            Violation::Scope { scope, reason } => write!(f, "scope `{}` {}", scope, reason),
+            Violation::Kind { kind, reason } => write!(f, "kind `{}` {}", kind, reason),
+            Violation::Version(version) => write!(f, "version {} is less than 1", version),
+            Violation::LabelKey { key, reason } => write!(f, "label key `{}` {}", key, reason),
+            Violation::LabelValue { key, reason } => {
+                write!(f, "value of label `{}` {}", key, reason)
+            }
+        }
+    }
+}
+
+/// The violations found in a manifest, in field order, with labels in key
+/// order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    violations: Vec<Violation>,
+}
+
+impl ValidationError {
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub fn into_violations(self) -> Vec<Violation> {
+        self.violations
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid manifest: ")?;
+        for (i, violation) in self.violations.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn fixtures_are_valid() {
+        fixtures::cpu_manifest().validate().unwrap();
+        fixtures::netstat_manifest().validate().unwrap();
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let mut manifest = fixtures::cpu_manifest();
+        manifest.domain = "-example..org".into();
+        manifest.scope = "metrics//host".into();
+        manifest.kind = "CPU".into();
+        manifest.version = 0;
+        manifest.labels.insert("_private".into(), "x".into());
+        manifest.labels.insert("note".into(), "a\nb".into());
+
+        let error = manifest.validate().unwrap_err();
+        let fields: Vec<_> = error
+            .violations()
+            .iter()
+            .map(|v| match v {
+                Violation::Domain { .. } => "domain",
+                Violation::Scope { .. } => "scope",
+                Violation::Kind { .. } => "kind",
+                Violation::Version(_) => "version",
+                Violation::LabelKey { .. } => "label key",
+                Violation::LabelValue { .. } => "label value",
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "domain",
+                "scope",
+                "kind",
+                "version",
+                "label key",
+                "label value"
+            ]
+        );
+        assert!(error
+            .to_string()
+            .starts_with("invalid manifest: domain `-example..org` has a label starting or ending with a hyphen; "));
+    }
+
+    #[test]
+    fn rules() {
+        assert!(check_domain("a-b.example.org").is_ok());
+        assert!(check_domain("localhost").is_ok());
+        assert!(check_domain("example-.org").is_err());
+        assert!(check_domain("exa_mple.org").is_err());
+        assert!(check_domain(&"a".repeat(64)).is_err());
+        assert!(check_scope("metrics/host.v2").is_ok());
+        assert!(check_scope("/metrics").is_err());
+        assert!(check_scope("metrics/../host").is_err());
+        assert!(check_kind("net-stat").is_ok());
+        assert!(check_kind("").is_err());
+        assert!(check_label_key("app.kubernetes.io").is_ok());
+        assert!(check_label_key(&"k".repeat(64)).is_err());
+        assert!(check_label_key("intermodal.io/compression").is_ok());
+        assert!(check_label_key(&format!("example.org/{}", "k".repeat(63))).is_ok());
+        assert!(check_label_key("example.org/").is_err());
+        assert!(check_label_key("/compression").is_err());
+        assert!(check_label_key("exa_mple.org/compression").is_err());
+        assert!(check_label_key("example.org/a/b").is_err());
+        assert!(check_label_value(&"é".repeat(255)).is_ok());
+        assert!(check_label_value(&"v".repeat(256)).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Deciding which versions of a content type are acceptable.
+//!
+//! A consumer built for one version of a content type may be able to handle
+//! others. A [`VersionPolicy`] says which: only its own version, any older
+//! one its version reads too, or any version at all. The
+//! [`Registry`](crate::registry::Registry) uses a policy to pick a handler
+//! for content of a version it has none for, and
+//! [`MemorySchemaRegistry::find_schema`](crate::schema::MemorySchemaRegistry::find_schema)
+//! to pick a schema:
+//!
+//! ```
+//! use intermodal::version_policy::{Compatibility, VersionPolicy};
+//! use intermodal::Coordinates;
+//!
+//! let supported = [
+//!     Coordinates::new("example.org", "metrics/host", "cpu", 2),
+//!     Coordinates::new("example.org", "metrics/host", "cpu", 4),
+//! ];
+//! let v3 = Coordinates::new("example.org", "metrics/host", "cpu", 3);
+//! assert_eq!(VersionPolicy::Exact.select(&supported, &v3), None);
+//! assert_eq!(VersionPolicy::Backward.select(&supported, &v3), Some(&supported[1]));
+//!
+//! assert_eq!(Compatibility::between(&v3, &supported[0]), Compatibility::Older);
+//! ```
+//!
+//! [`Manifest::compat`] compares two manifests the same way.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Coordinates, Manifest};
+
+/// How the type of one manifest's content relates to another's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compatibility {
+    /// The same type, in the same version.
+    Same,
+    /// The same type, in a newer version.
+    Newer,
+    /// The same type, in an older version.
+    Older,
+    /// A different type: the domain, scope or kind differs.
+    Different,
+}
+
+impl Compatibility {
+    /// How the type at `other` relates to the type at `base`.
+    pub fn between(base: &Coordinates, other: &Coordinates) -> Self {
+        if (&base.domain, &base.scope, &base.kind) != (&other.domain, &other.scope, &other.kind) {
+            return Compatibility::Different;
+        }
+        match other.version.cmp(&base.version) {
+            std::cmp::Ordering::Equal => Compatibility::Same,
+            std::cmp::Ordering::Greater => Compatibility::Newer,
+            std::cmp::Ordering::Less => Compatibility::Older,
+        }
+    }
+
+    /// Whether the two are versions of one type.
+    pub fn is_same_type(self) -> bool {
+        self != Compatibility::Different
+    }
+}
+
+impl Manifest {
+    /// How the type of `other`'s content relates to this manifest's: the
+    /// same, a newer or older version of it, or a different type.
+    pub fn compat(&self, other: &Manifest) -> Compatibility {
+        Compatibility::between(&self.coordinates(), &other.coordinates())
+    }
+}
+
+/// Which versions of a content type a consumer of one version accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionPolicy {
+    /// Only the consumer's own version.
+    #[default]
+    Exact,
+    /// The consumer's version and older ones, which a version must go on
+    /// reading when its schema evolves backward-compatibly.
+    Backward,
+    /// Any version.
+    Any,
+}
+
+impl VersionPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            VersionPolicy::Exact => "exact",
+            VersionPolicy::Backward => "backward",
+            VersionPolicy::Any => "any",
+        }
+    }
+
+    /// Whether a consumer of version `supported` accepts content of
+    /// `version`.
+    pub fn accepts(self, supported: u32, version: u32) -> bool {
+        match self {
+            VersionPolicy::Exact => version == supported,
+            VersionPolicy::Backward => version <= supported,
+            VersionPolicy::Any => true,
+        }
+    }
+
+    /// Whether a consumer of the type at `supported` accepts the manifest's
+    /// content, which must be of the same type.
+    pub fn accepts_manifest(self, supported: &Coordinates, manifest: &Manifest) -> bool {
+        supported.domain == manifest.domain
+            && supported.scope == manifest.scope
+            && supported.kind == manifest.kind
+            && self.accepts(supported.version, manifest.version)
+    }
+
+    /// Picks the consumer for content at `wanted` among those supporting
+    /// the `supported` coordinates: one of the same version if there is
+    /// one; else, of those the policy lets accept it, the oldest version
+    /// newer than the content, or failing that the newest older one.
+    pub fn select<'a, I>(self, supported: I, wanted: &Coordinates) -> Option<&'a Coordinates>
+    where
+        I: IntoIterator<Item = &'a Coordinates>,
+    {
+        let mut newer: Option<&Coordinates> = None;
+        let mut older: Option<&Coordinates> = None;
+        for candidate in supported {
+            let compat = Compatibility::between(wanted, candidate);
+            if !compat.is_same_type() || !self.accepts(candidate.version, wanted.version) {
+                continue;
+            }
+            match compat {
+                Compatibility::Same => return Some(candidate),
+                Compatibility::Newer => {
+                    if newer.is_none_or(|n| candidate.version < n.version) {
+                        newer = Some(candidate);
+                    }
+                }
+                Compatibility::Older => {
+                    if older.is_none_or(|o| candidate.version > o.version) {
+                        older = Some(candidate);
+                    }
+                }
+                Compatibility::Different => {}
+            }
+        }
+        newer.or(older)
+    }
+}
+
+impl fmt::Display for VersionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for VersionPolicy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(VersionPolicy::Exact),
+            "backward" => Ok(VersionPolicy::Backward),
+            "any" => Ok(VersionPolicy::Any),
+            other => Err(ParseError(other.to_string())),
+        }
+    }
+}
+
+/// A version policy name that is not one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown version policy `{}`", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn cpu(version: u32) -> Coordinates {
+        Coordinates::new("example.org", "metrics/host", "cpu", version)
+    }
+
+    #[test]
+    fn compares_manifests() {
+        let cpu = fixtures::cpu_manifest();
+        let mut newer = cpu.clone();
+        newer.version += 1;
+        assert_eq!(cpu.compat(&cpu), Compatibility::Same);
+        assert_eq!(cpu.compat(&newer), Compatibility::Newer);
+        assert_eq!(newer.compat(&cpu), Compatibility::Older);
+        assert_eq!(
+            cpu.compat(&fixtures::netstat_manifest()),
+            Compatibility::Different
+        );
+    }
+
+    #[test]
+    fn selects_by_policy() {
+        let supported = [cpu(1), cpu(3), cpu(5), cpu(7)];
+        let pick = |policy: VersionPolicy, version| {
+            policy
+                .select(&supported, &cpu(version))
+                .map(|coordinates| coordinates.version)
+        };
+        assert_eq!(pick(VersionPolicy::Exact, 3), Some(3));
+        assert_eq!(pick(VersionPolicy::Exact, 4), None);
+        assert_eq!(pick(VersionPolicy::Backward, 4), Some(5));
+        assert_eq!(pick(VersionPolicy::Backward, 8), None);
+        assert_eq!(pick(VersionPolicy::Any, 8), Some(7));
+        assert_eq!(pick(VersionPolicy::Any, 0), Some(1));
+
+        let mut other = cpu(3);
+        other.kind = "memory".to_string();
+        assert_eq!(VersionPolicy::Any.select(&supported, &other), None);
+
+        let manifest = fixtures::cpu_manifest();
+        assert!(VersionPolicy::Backward.accepts_manifest(&cpu(2), &manifest));
+        assert!(!VersionPolicy::Exact.accepts_manifest(&cpu(2), &manifest));
+        assert_eq!("backward".parse(), Ok(VersionPolicy::Backward));
+        assert!("loose".parse::<VersionPolicy>().is_err());
+    }
+}
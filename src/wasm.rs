@@ -0,0 +1,281 @@
+//! Transforms implemented as WebAssembly modules.
+//!
+//! A plugin is a WebAssembly module, in binary or text form, that exports:
+//!
+//! * `memory`, its linear memory;
+//! * `intermodal_alloc(len: i32) -> i32`, which reserves `len` bytes of memory
+//!   and returns their offset;
+//! * `intermodal_transform(ptr: i32, len: i32) -> i64`, which is handed the
+//!   JSON encoding of a [`RawPacket`] at `ptr..ptr + len`.
+//!
+//! `intermodal_transform` returns `-1` to drop the packet. Otherwise the high
+//! 32 bits of its result are the offset, and the low 32 bits the length, of
+//! the JSON encoding of the packet to pass on. Any other negative result is
+//! treated as an error code.
+//!
+//! Every call runs in a fresh instance, so plugins cannot carry state from one
+//! packet to the next, and is bounded by the fuel and memory [`Limits`] of the
+//! transform. Plugins may not import anything from the host.
+//!
+//! Calls run on a thread of their own, with a stack of
+//! [`stack_bytes`](Limits::stack_bytes), since how much stack the interpreter
+//! needs depends on the build: an unoptimized one can need more than a
+//! default thread has just to trap out of a plugin that has run out of fuel.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::thread;
+
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::transform::{self, Transform};
+use crate::RawPacket;
+
+const MEMORY: &str = "memory";
+const ALLOC: &str = "intermodal_alloc";
+const TRANSFORM: &str = "intermodal_transform";
+
+/// Resources a plugin may consume while handling a single packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Units of fuel, roughly one per instruction executed.
+    pub fuel: u64,
+    /// The largest size, in bytes, the plugin's memory may grow to.
+    pub memory_bytes: usize,
+    /// The largest encoded packet the plugin may return, in bytes.
+    pub output_bytes: usize,
+    /// The size of the stack of the thread the plugin runs on.
+    pub stack_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: 50_000_000,
+            memory_bytes: 64 << 20,
+            output_bytes: 16 << 20,
+            stack_bytes: 8 << 20,
+        }
+    }
+}
+
+/// A [`Transform`] backed by a WebAssembly plugin.
+pub struct WasmTransform {
+    engine: Engine,
+    module: Module,
+    limits: Limits,
+}
+
+impl WasmTransform {
+    /// Compiles a plugin with the default limits.
+    pub fn new(wasm: impl AsRef<[u8]>) -> Result<Self, Error> {
+        WasmTransform::with_limits(wasm, Limits::default())
+    }
+
+    /// Compiles a plugin, checking that it provides the expected exports.
+    pub fn with_limits(wasm: impl AsRef<[u8]>, limits: Limits) -> Result<Self, Error> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|e| Error::new(e.to_string()))?;
+
+        if module.imports().next().is_some() {
+            return Err(Error::new("plugins may not import from the host"));
+        }
+        for name in &[MEMORY, ALLOC, TRANSFORM] {
+            if module.get_export(name).is_none() {
+                return Err(Error::new(format!("missing export `{}`", name)));
+            }
+        }
+
+        Ok(WasmTransform {
+            engine,
+            module,
+            limits,
+        })
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Calls the plugin on a thread with the configured stack.
+    fn call(&self, input: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        thread::scope(|scope| {
+            thread::Builder::new()
+                .name("intermodal-wasm".to_string())
+                .stack_size(self.limits.stack_bytes)
+                .spawn_scoped(scope, || self.run(input))
+                .map_err(|e| Error::new(format!("spawning plugin thread: {}", e)))?
+                .join()
+                .map_err(|_| Error::new("plugin thread panicked"))?
+        })
+    }
+
+    fn run(&self, input: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let store_limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.memory_bytes)
+            .instances(1)
+            .build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, store_limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.limits.fuel).map_err(trap)?;
+
+        let linker = Linker::new(&self.engine);
+        let instance = linker
+            .instantiate_and_start(&mut store, &self.module)
+            .map_err(trap)?;
+        let memory = instance
+            .get_memory(&store, MEMORY)
+            .ok_or_else(|| Error::new("`memory` is not a memory"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, ALLOC)
+            .map_err(trap)?;
+        let transform = instance
+            .get_typed_func::<(i32, i32), i64>(&store, TRANSFORM)
+            .map_err(trap)?;
+
+        let len = i32::try_from(input.len()).map_err(|_| Error::new("packet too large"))?;
+        let ptr = alloc.call(&mut store, len).map_err(trap)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .map_err(|e| Error::new(format!("writing input: {}", e)))?;
+
+        let result = transform.call(&mut store, (ptr, len)).map_err(trap)?;
+        if result == -1 {
+            return Ok(None);
+        }
+        if result < 0 {
+            return Err(Error::new(format!("plugin returned error code {}", result)));
+        }
+
+        let out_ptr = (result >> 32) as u32 as usize;
+        let out_len = (result & 0xffff_ffff) as usize;
+        if out_len > self.limits.output_bytes {
+            return Err(Error::new(format!(
+                "output of {} bytes exceeds the limit of {}",
+                out_len, self.limits.output_bytes
+            )));
+        }
+        let mut output = vec![0; out_len];
+        memory
+            .read(&store, out_ptr, &mut output)
+            .map_err(|e| Error::new(format!("reading output: {}", e)))?;
+        Ok(Some(output))
+    }
+}
+
+impl Transform for WasmTransform {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        let input =
+            serde_json::to_vec(&packet).map_err(|e| transform::Error::new(e.to_string()))?;
+        match self.call(&input) {
+            Ok(Some(output)) => serde_json::from_slice(&output).map(Some).map_err(|e| {
+                transform::Error::new(format!("plugin returned an invalid packet: {}", e))
+            }),
+            Ok(None) => Ok(None),
+            Err(e) => Err(transform::Error::new(e.to_string())),
+        }
+    }
+}
+
+impl fmt::Debug for WasmTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmTransform")
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+fn trap(e: wasmi::Error) -> Error {
+    Error::new(e.to_string())
+}
+
+/// An error loading or running a WebAssembly plugin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(message: S) -> Self {
+        Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "wasm plugin: {}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    // A bump allocator shared by the test plugins.
+    const PRELUDE: &str = r#"
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (func (export "intermodal_alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (drop (memory.grow (i32.add (i32.shr_u (local.get $len) (i32.const 16)) (i32.const 1))))
+            (local.get $ptr))
+    "#;
+
+    fn plugin(body: &str) -> String {
+        format!("(module {} {})", PRELUDE, body)
+    }
+
+    #[test]
+    fn identity() {
+        let wasm = plugin(
+            r#"(func (export "intermodal_transform") (param $ptr i32) (param $len i32) (result i64)
+                (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len))))"#,
+        );
+        let transform = WasmTransform::new(wasm).unwrap();
+        let packet = transform.apply(fixtures::cpu_raw()).unwrap().unwrap();
+        assert_eq!(packet.manifest.kind, "cpu");
+        assert_eq!(packet.content["idle"], 83.25);
+    }
+
+    #[test]
+    fn drop_packet() {
+        let wasm = plugin(
+            r#"(func (export "intermodal_transform") (param i32 i32) (result i64)
+                (i64.const -1))"#,
+        );
+        let transform = WasmTransform::new(wasm).unwrap();
+        assert!(transform.apply(fixtures::cpu_raw()).unwrap().is_none());
+    }
+
+    #[test]
+    fn fuel_exhaustion() {
+        let wasm = plugin(
+            r#"(func (export "intermodal_transform") (param i32 i32) (result i64)
+                (loop $forever (br $forever))
+                (i64.const -1))"#,
+        );
+        let limits = Limits {
+            fuel: 10_000,
+            ..Limits::default()
+        };
+        let transform = WasmTransform::with_limits(wasm, limits).unwrap();
+        assert!(transform.apply(fixtures::cpu_raw()).is_err());
+    }
+
+    #[test]
+    fn missing_exports() {
+        let err = WasmTransform::new("(module (memory (export \"memory\") 1))").unwrap_err();
+        assert!(err.to_string().contains("intermodal_alloc"));
+    }
+}
@@ -0,0 +1,304 @@
+//! Announcing how far a stream of content has been forwarded.
+//!
+//! A watermark is a built-in kind of packet saying "all content of kind K
+//! created up to time T has been forwarded". A producer's [`Emitter`]
+//! watches what it forwards and makes watermarks to send after it; a
+//! consumer's [`Tracker`] collects them, so that a windowed aggregation
+//! knows when no more content will arrive for a window and it is safe to
+//! close:
+//!
+//! ```
+//! use chrono::{Duration, TimeZone, Utc};
+//! use intermodal::watermark::{Emitter, Tracker};
+//! # let packet: intermodal::RawPacket = serde_json::from_value(serde_json::json!({
+//! #     "manifest": { "domain": "example.org", "scope": "metrics/host",
+//! #                   "kind": "uptime", "version": 1,
+//! #                   "ctime": "2020-06-01T12:00:00Z", "origin": "host01" },
+//! #     "content": 86400
+//! # })).unwrap();
+//!
+//! let emitter = Emitter::new("collector@edge-07").lateness(Duration::seconds(30));
+//! emitter.observe(&packet.manifest);
+//! let watermarks = emitter.emit();
+//!
+//! let tracker = Tracker::new();
+//! for watermark in &watermarks {
+//!     tracker.observe(watermark);
+//! }
+//! let closed = Utc.with_ymd_and_hms(2020, 6, 1, 11, 59, 0).unwrap();
+//! assert!(tracker.is_complete(&packet.manifest, closed));
+//! ```
+//!
+//! A producer's watermark trails the newest content it has forwarded by the
+//! [lateness](Emitter::lateness) it allows for content arriving out of
+//! order. A consumer fed by several producers hears a watermark for a kind
+//! from each; its watermark for the kind is the earliest among them.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::transform::{self, Transform};
+use crate::{ContentType, Manifest, Packet, RawPacket};
+
+/// The content of a watermark packet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watermark {
+    pub domain: String,
+    pub scope: String,
+    pub kind: String,
+    /// All content of the kind created up to this time has been forwarded.
+    pub up_to: DateTime<Utc>,
+}
+
+impl ContentType for Watermark {
+    const DOMAIN: &'static str = "intermodal";
+    const SCOPE: &'static str = "control";
+    const KIND: &'static str = "watermark";
+    const VERSION: u32 = 1;
+}
+
+impl Watermark {
+    /// A watermark for the kind of the manifest's content.
+    pub fn for_kind(manifest: &Manifest, up_to: DateTime<Utc>) -> Self {
+        Watermark {
+            domain: manifest.domain.clone(),
+            scope: manifest.scope.clone(),
+            kind: manifest.kind.clone(),
+            up_to,
+        }
+    }
+
+    /// Whether the watermark says content with the manifest has been
+    /// forwarded.
+    pub fn covers(&self, manifest: &Manifest) -> bool {
+        self.key() == key(manifest) && manifest.ctime <= self.up_to
+    }
+
+    fn key(&self) -> Key {
+        (self.domain.clone(), self.scope.clone(), self.kind.clone())
+    }
+}
+
+/// Whether the manifest is that of a watermark.
+pub fn is_watermark(manifest: &Manifest) -> bool {
+    manifest.domain == Watermark::DOMAIN
+        && manifest.scope == Watermark::SCOPE
+        && manifest.kind == Watermark::KIND
+}
+
+/// A kind of content: its domain, scope and kind, of any version.
+type Key = (String, String, String);
+
+fn key(manifest: &Manifest) -> Key {
+    (
+        manifest.domain.clone(),
+        manifest.scope.clone(),
+        manifest.kind.clone(),
+    )
+}
+
+#[derive(Debug)]
+struct Progress {
+    newest: DateTime<Utc>,
+    emitted: Option<DateTime<Utc>>,
+}
+
+/// Makes watermarks for the content a producer forwards.
+#[derive(Debug)]
+pub struct Emitter {
+    origin: String,
+    lateness: Duration,
+    kinds: Mutex<BTreeMap<Key, Progress>>,
+}
+
+impl Emitter {
+    /// An emitter whose watermarks give `origin` as theirs, allowing no
+    /// lateness.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `origin` is empty.
+    pub fn new<S: Into<String>>(origin: S) -> Self {
+        let origin = origin.into();
+        assert!(!origin.is_empty(), "watermarks need an origin");
+        Emitter {
+            origin,
+            lateness: Duration::zero(),
+            kinds: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Holds watermarks this far behind the newest content forwarded, for
+    /// content that is forwarded out of order by up to as much.
+    pub fn lateness(mut self, lateness: Duration) -> Self {
+        self.lateness = lateness;
+        self
+    }
+
+    /// Records that content with the manifest has been forwarded.
+    /// Watermarks are never recorded.
+    pub fn observe(&self, manifest: &Manifest) {
+        if is_watermark(manifest) {
+            return;
+        }
+        let mut kinds = self.kinds.lock().unwrap_or_else(|e| e.into_inner());
+        let progress = kinds.entry(key(manifest)).or_insert(Progress {
+            newest: manifest.ctime,
+            emitted: None,
+        });
+        progress.newest = progress.newest.max(manifest.ctime);
+    }
+
+    /// Makes a watermark for each kind whose watermark has advanced since
+    /// it was last emitted, ready to be forwarded after the content.
+    pub fn emit(&self) -> Vec<RawPacket> {
+        let mut kinds = self.kinds.lock().unwrap_or_else(|e| e.into_inner());
+        let mut watermarks = Vec::new();
+        for ((domain, scope, kind), progress) in kinds.iter_mut() {
+            let up_to = progress.newest - self.lateness;
+            if progress.emitted.is_some_and(|emitted| up_to <= emitted) {
+                continue;
+            }
+            progress.emitted = Some(up_to);
+            let watermark = Watermark {
+                domain: domain.clone(),
+                scope: scope.clone(),
+                kind: kind.clone(),
+                up_to,
+            };
+            watermarks.push(self.packet(watermark));
+        }
+        watermarks
+    }
+
+    fn packet(&self, watermark: Watermark) -> RawPacket {
+        let manifest = Manifest::builder()
+            .domain(Watermark::DOMAIN)
+            .scope(Watermark::SCOPE)
+            .kind(Watermark::KIND)
+            .version(Watermark::VERSION)
+            .origin(self.origin.as_str())
+            .build()
+            .expect("watermark manifests are complete");
+        let content = serde_json::to_value(watermark).expect("watermarks encode as JSON");
+        Packet::new(manifest, content)
+    }
+}
+
+impl Transform for Emitter {
+    fn apply(&self, packet: RawPacket) -> Result<Option<RawPacket>, transform::Error> {
+        self.observe(&packet.manifest);
+        Ok(Some(packet))
+    }
+}
+
+/// Collects the watermarks a consumer receives.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    /// The latest watermark for each kind, from each origin.
+    kinds: Mutex<BTreeMap<Key, BTreeMap<String, DateTime<Utc>>>>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker::default()
+    }
+
+    /// Records the packet's watermark, if it is one. Returns whether it
+    /// was. A watermark earlier than one already heard from its origin
+    /// leaves that one in place.
+    pub fn observe(&self, packet: &RawPacket) -> bool {
+        if !is_watermark(&packet.manifest) {
+            return false;
+        }
+        let watermark: Watermark = match serde_json::from_value(packet.content.clone()) {
+            Ok(watermark) => watermark,
+            Err(_) => return false,
+        };
+        let mut kinds = self.kinds.lock().unwrap_or_else(|e| e.into_inner());
+        let up_to = kinds
+            .entry(watermark.key())
+            .or_default()
+            .entry(packet.manifest.origin.clone())
+            .or_insert(watermark.up_to);
+        *up_to = (*up_to).max(watermark.up_to);
+        true
+    }
+
+    /// The watermark for the kind of the manifest's content: the earliest
+    /// among those heard from each origin, if any have been.
+    pub fn watermark(&self, manifest: &Manifest) -> Option<DateTime<Utc>> {
+        let kinds = self.kinds.lock().unwrap_or_else(|e| e.into_inner());
+        kinds.get(&key(manifest))?.values().min().copied()
+    }
+
+    /// Whether all content of the manifest's kind created up to `end` has
+    /// been forwarded, so that a window ending then can be closed.
+    pub fn is_complete(&self, manifest: &Manifest, end: DateTime<Utc>) -> bool {
+        self.watermark(manifest).is_some_and(|up_to| end <= up_to)
+    }
+
+    /// How far the watermark for the manifest's kind trails `now`.
+    pub fn lag(&self, manifest: &Manifest, now: DateTime<Utc>) -> Option<Duration> {
+        self.watermark(manifest).map(|up_to| now - up_to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn emits_advancing_watermarks() {
+        let emitter = Emitter::new("collector").lateness(Duration::seconds(10));
+        let cpu = fixtures::cpu_raw();
+        emitter.apply(cpu.clone()).unwrap();
+        emitter.observe(&fixtures::netstat_manifest());
+
+        let watermarks = emitter.emit();
+        assert_eq!(watermarks.len(), 2);
+        assert!(is_watermark(&watermarks[0].manifest));
+        assert_eq!(watermarks[0].manifest.origin, "collector");
+        let watermark: Watermark = serde_json::from_value(watermarks[0].content.clone()).unwrap();
+        assert_eq!(watermark.kind, "cpu");
+        assert_eq!(watermark.up_to, cpu.manifest.ctime - Duration::seconds(10));
+        assert!(!watermark.covers(&cpu.manifest));
+
+        assert!(emitter.emit().is_empty());
+        let mut later = cpu.manifest.clone();
+        later.ctime += Duration::seconds(60);
+        emitter.observe(&later);
+        emitter.observe(&watermarks[1].manifest);
+        let watermarks = emitter.emit();
+        assert_eq!(watermarks.len(), 1);
+        let watermark: Watermark = serde_json::from_value(watermarks[0].content.clone()).unwrap();
+        assert!(watermark.covers(&cpu.manifest));
+    }
+
+    #[test]
+    fn tracks_the_earliest_origin() {
+        let cpu = fixtures::cpu_manifest();
+        let edge = Emitter::new("edge-01");
+        let core = Emitter::new("core-01");
+        edge.observe(&cpu);
+        let mut earlier = cpu.clone();
+        earlier.ctime = cpu.ctime - Duration::minutes(5);
+        core.observe(&earlier);
+
+        let tracker = Tracker::new();
+        assert!(!tracker.observe(&fixtures::cpu_raw()));
+        assert_eq!(tracker.watermark(&cpu), None);
+        for watermark in edge.emit().iter().chain(&core.emit()) {
+            assert!(tracker.observe(watermark));
+        }
+        assert_eq!(tracker.watermark(&cpu), Some(earlier.ctime));
+        assert!(tracker.is_complete(&cpu, earlier.ctime));
+        assert!(!tracker.is_complete(&cpu, cpu.ctime));
+        assert_eq!(tracker.lag(&cpu, cpu.ctime), Some(Duration::minutes(5)));
+        assert_eq!(tracker.watermark(&fixtures::netstat_manifest()), None);
+    }
+}
@@ -0,0 +1,494 @@
+//! XML encoding of envelopes.
+//!
+//! Envelopes are encoded as an `envelope` element holding a `manifest`
+//! element and, for packets, a `content` element:
+//!
+//! ```xml
+//! <envelope>
+//!   <manifest>
+//!     <domain>example.org</domain>
+//!     <scope>metrics/host</scope>
+//!     <kind>cpu</kind>
+//!     <version>1</version>
+//!     <ctime>2020-06-01T12:00:00Z</ctime>
+//!     <origin>host01.example.org</origin>
+//!     <labels>
+//!       <label key="environment">production</label>
+//!     </labels>
+//!   </manifest>
+//!   <content>
+//!     <object>
+//!       <number name="user">12.5</number>
+//!       <number name="idle">83.25</number>
+//!     </object>
+//!   </content>
+//! </envelope>
+//! ```
+//!
+//! Each manifest field is an element of the same name. Scalar fields hold
+//! their value as text; `labels` holds one `label` element per label, keyed
+//! by its `key` attribute; fields with structured values hold a single typed
+//! value, as described next.
+//!
+//! Because content may take any shape, `content` holds exactly one typed
+//! value: `object`, `array`, `string`, `number`, `boolean` (`true` or
+//! `false`), or an empty `null`. The members of an `object` are typed values
+//! carrying a `name` attribute; the items of an `array` are typed values in
+//! order. This keeps arbitrary content, including keys that are not valid
+//! XML names, lossless.
+//!
+//! Unknown manifest fields and elements outside this layout are ignored when
+//! decoding, as with the other encodings.
+
+use std::fmt;
+
+use quick_xml::events::{BytesRef, Event};
+use quick_xml::{Reader, XmlVersion};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+const FIELD_ORDER: &[&str] = &[
+    "domain", "scope", "kind", "version", "ctime", "origin", "labels",
+];
+
+/// Serializes an envelope, either a [`Packet`](crate::Packet) or a
+/// [`Header`](crate::Header), as XML.
+pub fn to_string<S: Serialize>(envelope: &S) -> Result<String, Error> {
+    let value = serde_json::to_value(envelope).map_err(|e| Error::new(e.to_string()))?;
+    let manifest = value
+        .get("manifest")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::new("envelope has no manifest"))?;
+
+    // Known fields keep the order documented above; any others follow.
+    let known = FIELD_ORDER
+        .iter()
+        .filter_map(|name| manifest.get_key_value(*name));
+    let others = manifest
+        .iter()
+        .filter(|(name, _)| !FIELD_ORDER.contains(&name.as_str()));
+
+    let mut out = String::from("<envelope><manifest>");
+    for (name, field) in known.chain(others) {
+        match (name.as_str(), field) {
+            ("labels", Value::Object(labels)) => {
+                out.push_str("<labels>");
+                for (key, value) in labels {
+                    out.push_str("<label key=\"");
+                    escape(&mut out, key);
+                    out.push_str("\">");
+                    escape(&mut out, value.as_str().unwrap_or_default());
+                    out.push_str("</label>");
+                }
+                out.push_str("</labels>");
+            }
+            (_, Value::Null) => {}
+            (_, Value::Object(_)) | (_, Value::Array(_)) => {
+                out.push('<');
+                out.push_str(name);
+                out.push('>');
+                write_value(&mut out, None, field);
+                out.push_str("</");
+                out.push_str(name);
+                out.push('>');
+            }
+            (_, scalar) => {
+                out.push('<');
+                out.push_str(name);
+                out.push('>');
+                match scalar {
+                    Value::String(s) => escape(&mut out, s),
+                    other => out.push_str(&other.to_string()),
+                }
+                out.push_str("</");
+                out.push_str(name);
+                out.push('>');
+            }
+        }
+    }
+    out.push_str("</manifest>");
+    if let Some(content) = value.get("content") {
+        out.push_str("<content>");
+        write_value(&mut out, None, content);
+        out.push_str("</content>");
+    }
+    out.push_str("</envelope>");
+    Ok(out)
+}
+
+/// Deserializes an XML envelope into a [`Packet`](crate::Packet) or, ignoring
+/// any content, a [`Header`](crate::Header).
+pub fn from_str<D: DeserializeOwned>(s: &str) -> Result<D, Error> {
+    let root = parse(s)?;
+    if root.name != "envelope" {
+        return Err(Error::new(format!(
+            "expected `envelope`, found `{}`",
+            root.name
+        )));
+    }
+
+    let mut envelope = Map::new();
+    if let Some(manifest) = root.child("manifest") {
+        let mut fields = Map::new();
+        for field in manifest.elements() {
+            let value = match field.name.as_str() {
+                "labels" => {
+                    let mut labels = Map::new();
+                    for label in field.elements().filter(|e| e.name == "label") {
+                        let key = label
+                            .attr("key")
+                            .ok_or_else(|| Error::new("`label` has no `key`"))?;
+                        labels.insert(key.to_string(), Value::String(label.text()));
+                    }
+                    Value::Object(labels)
+                }
+                "version" => {
+                    let text = field.text();
+                    let version: u64 = text
+                        .trim()
+                        .parse()
+                        .map_err(|_| Error::new(format!("invalid version `{}`", text)))?;
+                    Value::from(version)
+                }
+                _ => match field.elements().next() {
+                    Some(typed) => read_value(typed)?,
+                    None => Value::String(field.text()),
+                },
+            };
+            fields.insert(field.name.clone(), value);
+        }
+        envelope.insert("manifest".into(), Value::Object(fields));
+    }
+    if let Some(content) = root.child("content") {
+        let value = match content.elements().next() {
+            Some(typed) => read_value(typed)?,
+            None => Value::Null,
+        };
+        envelope.insert("content".into(), value);
+    }
+
+    serde_json::from_value(Value::Object(envelope)).map_err(|e| Error::new(e.to_string()))
+}
+
+fn write_value(out: &mut String, name: Option<&str>, value: &Value) {
+    let tag = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    };
+    out.push('<');
+    out.push_str(tag);
+    if let Some(name) = name {
+        out.push_str(" name=\"");
+        escape(out, name);
+        out.push('"');
+    }
+    if let Value::Null = value {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    match value {
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::String(s) => escape(out, s),
+        Value::Array(items) => {
+            for item in items {
+                write_value(out, None, item);
+            }
+        }
+        Value::Object(members) => {
+            for (name, member) in members {
+                write_value(out, Some(name), member);
+            }
+        }
+        Value::Null => unreachable!(),
+    }
+    out.push_str("</");
+    out.push_str(tag);
+    out.push('>');
+}
+
+fn read_value(element: &Element) -> Result<Value, Error> {
+    Ok(match element.name.as_str() {
+        "null" => Value::Null,
+        "boolean" => match element.text().trim() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            other => return Err(Error::new(format!("invalid boolean `{}`", other))),
+        },
+        "number" => {
+            let text = element.text();
+            let number: Number = serde_json::from_str(text.trim())
+                .map_err(|_| Error::new(format!("invalid number `{}`", text)))?;
+            Value::Number(number)
+        }
+        "string" => Value::String(element.text()),
+        "array" => Value::Array(
+            element
+                .elements()
+                .map(read_value)
+                .collect::<Result<_, _>>()?,
+        ),
+        "object" => {
+            let mut members = Map::new();
+            for member in element.elements() {
+                let name = member.attr("name").ok_or_else(|| {
+                    Error::new(format!("object member `{}` has no `name`", member.name))
+                })?;
+                members.insert(name.to_string(), read_value(member)?);
+            }
+            Value::Object(members)
+        }
+        other => return Err(Error::new(format!("unknown value type `{}`", other))),
+    })
+}
+
+fn escape(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(e) => Some(e),
+            Node::Text(_) => None,
+        })
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|e| e.name == name)
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            if let Node::Text(t) = node {
+                text.push_str(t);
+            }
+        }
+        text
+    }
+
+    fn push_text(&mut self, s: &str) {
+        match self.children.last_mut() {
+            Some(Node::Text(t)) => t.push_str(s),
+            _ => self.children.push(Node::Text(s.to_string())),
+        }
+    }
+}
+
+fn parse(s: &str) -> Result<Element, Error> {
+    let mut reader = Reader::from_str(s);
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+
+    loop {
+        let event = reader.read_event().map_err(|e| Error::new(e.to_string()))?;
+        match event {
+            Event::Start(start) | Event::Empty(start) if root.is_some() => {
+                return Err(Error::new(format!(
+                    "unexpected element `{}` after the document",
+                    start.name().as_ref()
+                )));
+            }
+            Event::Start(start) => stack.push(element(&start)?),
+            Event::Empty(start) => {
+                let element = element(&start)?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(Node::Element(element)),
+                    None => root = Some(element),
+                }
+            }
+            Event::End(_) => {
+                let element = stack
+                    .pop()
+                    .ok_or_else(|| Error::new("unbalanced end tag"))?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(Node::Element(element)),
+                    None => root = Some(element),
+                }
+            }
+            Event::Text(text) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.push_text(&text.xml10_content());
+                }
+            }
+            Event::CData(cdata) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.push_text(&cdata.xml10_content());
+                }
+            }
+            Event::GeneralRef(reference) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.push_text(&resolve(&reference)?);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if !stack.is_empty() {
+        return Err(Error::new("unexpected end of document"));
+    }
+    root.ok_or_else(|| Error::new("empty document"))
+}
+
+fn element(start: &quick_xml::events::BytesStart<'_>) -> Result<Element, Error> {
+    let mut attrs = Vec::new();
+    for attr in start.attributes() {
+        let attr = attr.map_err(|e| Error::new(e.to_string()))?;
+        let value = attr
+            .normalized_value(XmlVersion::Implicit1_0)
+            .map_err(|e| Error::new(e.to_string()))?;
+        attrs.push((attr.key.as_ref().to_string(), value.into_owned()));
+    }
+    Ok(Element {
+        name: start.name().as_ref().to_string(),
+        attrs,
+        children: Vec::new(),
+    })
+}
+
+fn resolve(reference: &BytesRef<'_>) -> Result<String, Error> {
+    if let Some(c) = reference
+        .resolve_char_ref()
+        .map_err(|e| Error::new(e.to_string()))?
+    {
+        return Ok(c.to_string());
+    }
+    let name = reference.xml10_content();
+    let c = match name.as_ref() {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        other => return Err(Error::new(format!("unknown entity `&{};`", other))),
+    };
+    Ok(c.to_string())
+}
+
+/// An error encoding or decoding XML.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(message: S) -> Self {
+        Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "xml: {}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, Netstat};
+    use crate::{Header, Packet, RawPacket};
+
+    #[test]
+    fn round_trips() {
+        let packet: Packet<Netstat> = serde_json::from_str(fixtures::NETSTAT_JSON).unwrap();
+        let xml = to_string(&packet).unwrap();
+        assert!(xml.starts_with("<envelope><manifest><domain>example.org</domain>"));
+        assert!(xml.contains("<label key=\"environment\">production</label>"));
+
+        let decoded: Packet<Netstat> = from_str(&xml).unwrap();
+        assert_eq!(decoded.content, packet.content);
+        assert_eq!(decoded.manifest.ctime, packet.manifest.ctime);
+        assert_eq!(decoded.manifest.labels, packet.manifest.labels);
+
+        let header: Header = from_str(&xml).unwrap();
+        assert_eq!(header.manifest.kind, "netstat");
+    }
+
+    #[test]
+    fn lossless_content() {
+        let mut packet = fixtures::cpu_raw();
+        packet.content = serde_json::json!({
+            "not an xml name": [1, -2.5, true, null, "a < b & \"c\""],
+            "nested": { "empty": {}, "list": [] }
+        });
+        let decoded: RawPacket = from_str(&to_string(&packet).unwrap()).unwrap();
+        assert_eq!(decoded.content, packet.content);
+    }
+
+    #[test]
+    fn parses_formatted_documents() {
+        let xml = r#"<?xml version="1.0"?>
+            <envelope>
+              <!-- produced by a partner system -->
+              <manifest>
+                <domain>example.org</domain>
+                <scope>metrics/host</scope>
+                <kind>cpu</kind>
+                <version> 1 </version>
+                <ctime>2020-06-01T12:00:00Z</ctime>
+                <origin>host&#48;1 &amp; co</origin>
+                <labels><label key="a&amp;b">x</label></labels>
+                <unknown>ignored</unknown>
+              </manifest>
+              <content><array><number>1</number><null/></array></content>
+            </envelope>"#;
+        let packet: RawPacket = from_str(xml).unwrap();
+        assert_eq!(packet.manifest.version, 1);
+        assert_eq!(packet.manifest.origin, "host01 & co");
+        assert_eq!(packet.manifest.labels["a&b"], "x");
+        assert_eq!(packet.content, serde_json::json!([1, null]));
+    }
+
+    #[test]
+    fn rejects_bad_documents() {
+        assert!(from_str::<Header>("<packet/>").is_err());
+        assert!(from_str::<Header>("<envelope><manifest>").is_err());
+        assert!(from_str::<RawPacket>(
+            "<envelope><manifest/><content><bogus/></content></envelope>"
+        )
+        .is_err());
+    }
+}